@@ -0,0 +1,40 @@
+use uuid::Uuid;
+
+use crate::context::{account_context, plan_context};
+
+/// Configuration for API key generation/validation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyConfig {
+    /// Prefix for tokens (e.g., "lb" -> "lb_v1_...")
+    pub prefix: String,
+    /// Optional context ID to include in hash (organization_id, tenant_id, etc.)
+    pub context_id: Option<Uuid>,
+}
+
+impl Default for ApiKeyConfig {
+    fn default() -> Self {
+        Self {
+            prefix: "key".to_string(),
+            context_id: None,
+        }
+    }
+}
+
+impl ApiKeyConfig {
+    /// Configuration binding keys to a numeric account via [`account_context`].
+    pub fn for_account(prefix: impl Into<String>, account_id: i64) -> Self {
+        Self {
+            prefix: prefix.into(),
+            context_id: Some(account_context(account_id)),
+        }
+    }
+
+    /// Configuration binding keys to a numeric account and its current plan via
+    /// [`plan_context`].
+    pub fn for_account_plan(prefix: impl Into<String>, account_id: i64, plan_id: i64) -> Self {
+        Self {
+            prefix: prefix.into(),
+            context_id: Some(plan_context(account_id, plan_id)),
+        }
+    }
+}
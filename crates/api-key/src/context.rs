@@ -0,0 +1,45 @@
+use uuid::Uuid;
+
+/// Namespace for account context UUIDs. Never change it: every stored hash depends on it.
+pub const ACCOUNT_CONTEXT_NAMESPACE: Uuid =
+    Uuid::from_u128(0x6c62_6163_636f_756e_7400_0000_0000_0001);
+
+/// Stable context UUID for an account (UUIDv5 of the decimal account id).
+///
+/// Hashes computed with this context only verify for the same account, so a stored hash
+/// copied to a key row of another account no longer validates.
+pub fn account_context(account_id: i64) -> Uuid {
+    Uuid::new_v5(
+        &ACCOUNT_CONTEXT_NAMESPACE,
+        account_id.to_string().as_bytes(),
+    )
+}
+
+/// Stable context UUID for an account on a plan (UUIDv5 of the decimal plan id, in the
+/// namespace of the account's context).
+///
+/// Hashes computed with this context stop verifying once the account moves to another plan,
+/// so an upgrade or downgrade requires issuing the keys again.
+pub fn plan_context(account_id: i64, plan_id: i64) -> Uuid {
+    Uuid::new_v5(&account_context(account_id), plan_id.to_string().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_context_is_stable_and_distinct() {
+        assert_eq!(account_context(42), account_context(42));
+        assert_ne!(account_context(42), account_context(43));
+        assert_eq!(account_context(42).get_version_num(), 5);
+    }
+
+    #[test]
+    fn plan_context_is_distinct_per_account_and_plan() {
+        assert_eq!(plan_context(42, 1), plan_context(42, 1));
+        assert_ne!(plan_context(42, 1), plan_context(42, 2));
+        assert_ne!(plan_context(42, 1), plan_context(43, 1));
+        assert_ne!(plan_context(42, 1), account_context(42));
+    }
+}
@@ -0,0 +1,12 @@
+use uuid::Uuid;
+
+/// Data to store in database for an API key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyData {
+    /// Unique identifier (UUIDv7, extracted from token)
+    pub id: Uuid,
+    /// Hash of the secret (512 bits)
+    pub secret_hash: [u8; 64],
+    /// Algorithm version used
+    pub version: i16,
+}
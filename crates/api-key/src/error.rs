@@ -0,0 +1,17 @@
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ApiKeyError {
+    #[error("Invalid token format")]
+    InvalidFormat,
+
+    #[error("Invalid prefix: expected '{expected}', got '{got}'")]
+    InvalidPrefix { expected: String, got: String },
+
+    #[error("Unsupported version: {0}")]
+    UnsupportedVersion(i16),
+
+    #[error("Invalid base32 encoding")]
+    InvalidEncoding,
+
+    #[error("Invalid UUID")]
+    InvalidUuid,
+}
@@ -0,0 +1,31 @@
+use sha3::{Digest, Sha3_512};
+use uuid::Uuid;
+
+use crate::parse::ParsedToken;
+
+/// Hash a secret bound to its key id, version and context.
+pub(crate) fn hash_api_key(
+    api_key_id: Uuid,
+    version: i16,
+    context_id: Uuid,
+    secret: &[u8; 32],
+) -> [u8; 64] {
+    let mut hasher = Sha3_512::new();
+
+    hasher.update(api_key_id.as_bytes()); // Prevents ID swapping
+    hasher.update(version.to_le_bytes()); // Prevents algorithm confusion
+    hasher.update(context_id.as_bytes()); // Prevents context swapping
+    hasher.update(secret); // The actual secret (last!)
+
+    hasher.finalize().into()
+}
+
+/// Compute hash for a parsed token (for manual comparison)
+pub fn compute_hash(parsed: &ParsedToken, context_id: Option<Uuid>) -> [u8; 64] {
+    hash_api_key(
+        parsed.id,
+        parsed.version,
+        context_id.unwrap_or_else(Uuid::nil),
+        &parsed.secret,
+    )
+}
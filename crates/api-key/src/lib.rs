@@ -0,0 +1,26 @@
+//! Cryptographically-secure API keys.
+//!
+//! Tokens have the form `<prefix>_v1_<base32(uuidv7 || secret)>`. The UUID is used to look the
+//! key up; only a context-bound SHA3-512 hash of the secret is stored. See
+//! `docs/research/secure_api_key.md` for the design.
+
+mod config;
+mod context;
+mod data;
+mod error;
+mod hash;
+mod parse;
+mod token;
+mod verify;
+
+pub use config::ApiKeyConfig;
+pub use context::{ACCOUNT_CONTEXT_NAMESPACE, account_context, plan_context};
+pub use data::ApiKeyData;
+pub use error::ApiKeyError;
+pub use hash::compute_hash;
+pub use parse::{ParsedToken, parse};
+pub use token::{ApiKeyToken, fingerprint, generate, generate_with_data};
+pub use verify::{verify, verify_hash};
+
+/// Current token format version.
+pub const VERSION: i16 = 1;
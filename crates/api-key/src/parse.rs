@@ -0,0 +1,76 @@
+use data_encoding::BASE32_NOPAD;
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use crate::VERSION;
+use crate::error::ApiKeyError;
+use crate::token::fingerprint;
+
+/// Parsed components from a token string
+pub struct ParsedToken {
+    pub(crate) prefix: String,
+    pub(crate) id: Uuid,
+    pub(crate) version: i16,
+    pub(crate) secret: [u8; 32],
+}
+
+impl ParsedToken {
+    /// Key id, for database lookup.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Token format version.
+    pub fn version(&self) -> i16 {
+        self.version
+    }
+
+    /// Short, non-secret identifier for this token. See [`crate::fingerprint`].
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.prefix, self.id)
+    }
+}
+
+impl Drop for ParsedToken {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+/// Parse a token string into components (for database lookup)
+pub fn parse(token: &str, expected_prefix: &str) -> Result<ParsedToken, ApiKeyError> {
+    let (prefix, rest) = token.rsplit_once('_').ok_or(ApiKeyError::InvalidFormat)?;
+    let (prefix, version) = prefix.rsplit_once('_').ok_or(ApiKeyError::InvalidFormat)?;
+    if prefix != expected_prefix {
+        return Err(ApiKeyError::InvalidPrefix {
+            expected: expected_prefix.to_string(),
+            got: prefix.to_string(),
+        });
+    }
+    let version: i16 = version
+        .strip_prefix('v')
+        .and_then(|v| v.parse().ok())
+        .ok_or(ApiKeyError::InvalidFormat)?;
+    if version != VERSION {
+        return Err(ApiKeyError::UnsupportedVersion(version));
+    }
+
+    let mut bytes = BASE32_NOPAD
+        .decode(rest.to_ascii_uppercase().as_bytes())
+        .map_err(|_| ApiKeyError::InvalidEncoding)?;
+    if bytes.len() != 48 {
+        bytes.zeroize();
+        return Err(ApiKeyError::InvalidFormat);
+    }
+    let id = Uuid::from_slice(&bytes[..16]).map_err(|_| ApiKeyError::InvalidUuid)?;
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&bytes[16..]);
+    bytes.zeroize();
+
+    Ok(ParsedToken {
+        prefix: prefix.to_string(),
+        id,
+        version,
+        secret,
+    })
+}
@@ -0,0 +1,80 @@
+use data_encoding::BASE32_NOPAD;
+use rand::RngCore;
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use crate::VERSION;
+use crate::config::ApiKeyConfig;
+use crate::data::ApiKeyData;
+use crate::hash::hash_api_key;
+
+/// The API key token given to end users
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyToken {
+    /// The full token string (prefix + version + encoded data)
+    pub token: String,
+    /// Extracted UUIDv7 (for database storage/lookup)
+    pub id: Uuid,
+}
+
+impl ApiKeyToken {
+    /// Short, non-secret identifier for this token. See [`crate::fingerprint`].
+    pub fn fingerprint(&self) -> String {
+        let prefix = self
+            .token
+            .rsplitn(3, '_')
+            .nth(2)
+            .unwrap_or(self.token.as_str());
+        fingerprint(prefix, self.id)
+    }
+}
+
+/// Canonical short fingerprint of a key: `<prefix>_<first 8 chars of base32(id)>`.
+///
+/// The 8 characters are also the start of the token body, so a fingerprint can be matched
+/// against a token by eye. It only covers the leading (timestamp) bits of the UUIDv7, so keys
+/// minted within the same ~250ms can share a fingerprint; use the id when uniqueness matters.
+pub fn fingerprint(prefix: &str, id: Uuid) -> String {
+    let encoded = BASE32_NOPAD.encode(id.as_bytes()).to_ascii_lowercase();
+    format!("{prefix}_{}", &encoded[..8])
+}
+
+/// Generate a new API key
+pub fn generate(config: &ApiKeyConfig) -> ApiKeyToken {
+    generate_with_data(config).0
+}
+
+/// Generate and return both token and storage-ready data
+pub fn generate_with_data(config: &ApiKeyConfig) -> (ApiKeyToken, ApiKeyData) {
+    let id = Uuid::now_v7();
+    let mut secret = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+
+    let mut bytes = Vec::with_capacity(48);
+    bytes.extend_from_slice(id.as_bytes());
+    bytes.extend_from_slice(&secret);
+    let token = format!(
+        "{}_v{}_{}",
+        config.prefix,
+        VERSION,
+        BASE32_NOPAD.encode(&bytes).to_ascii_lowercase()
+    );
+    bytes.zeroize();
+
+    let secret_hash = hash_api_key(
+        id,
+        VERSION,
+        config.context_id.unwrap_or_else(Uuid::nil),
+        &secret,
+    );
+    secret.zeroize();
+
+    (
+        ApiKeyToken { token, id },
+        ApiKeyData {
+            id,
+            secret_hash,
+            version: VERSION,
+        },
+    )
+}
@@ -0,0 +1,107 @@
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::config::ApiKeyConfig;
+use crate::data::ApiKeyData;
+use crate::error::ApiKeyError;
+use crate::hash::compute_hash;
+use crate::parse::{ParsedToken, parse};
+
+/// Verify a token against stored hash
+/// Returns true if valid, false if invalid
+pub fn verify(
+    token: &str,
+    stored: &ApiKeyData,
+    config: &ApiKeyConfig,
+) -> Result<bool, ApiKeyError> {
+    let parsed = parse(token, &config.prefix)?;
+    if parsed.id != stored.id || parsed.version != stored.version {
+        return Ok(false);
+    }
+    Ok(verify_hash(&parsed, &stored.secret_hash, config.context_id))
+}
+
+/// Check an already parsed token against a stored hash in constant time.
+pub fn verify_hash(parsed: &ParsedToken, stored_hash: &[u8; 64], context_id: Option<Uuid>) -> bool {
+    compute_hash(parsed, context_id).ct_eq(stored_hash).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ApiKeyError, generate_with_data};
+
+    #[test]
+    fn generated_token_verifies() {
+        let config = ApiKeyConfig::for_account("lb", 7);
+        let (token, data) = generate_with_data(&config);
+
+        assert!(token.token.starts_with("lb_v1_"));
+        assert_eq!(parse(&token.token, "lb").unwrap().id(), token.id);
+        assert!(verify(&token.token, &data, &config).unwrap());
+    }
+
+    #[test]
+    fn fingerprint_matches_token_start() {
+        let (token, _) = generate_with_data(&ApiKeyConfig::for_account("lb", 7));
+        let fingerprint = token.fingerprint();
+
+        assert_eq!(fingerprint.len(), "lb_".len() + 8);
+        assert_eq!(&fingerprint[3..], &token.token[6..14]);
+        assert_eq!(
+            parse(&token.token, "lb").unwrap().fingerprint(),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn hash_is_bound_to_account_context() {
+        let (token, data) = generate_with_data(&ApiKeyConfig::for_account("lb", 7));
+
+        // The same hash stored under another account does not verify
+        let other = ApiKeyConfig::for_account("lb", 8);
+        assert!(!verify(&token.token, &data, &other).unwrap());
+    }
+
+    #[test]
+    fn hash_is_bound_to_plan_context() {
+        let (token, data) = generate_with_data(&ApiKeyConfig::for_account_plan("lb", 7, 1));
+        assert!(
+            verify(
+                &token.token,
+                &data,
+                &ApiKeyConfig::for_account_plan("lb", 7, 1)
+            )
+            .unwrap()
+        );
+
+        // Once the account changes plans, or when checked as an account-bound key
+        let moved = ApiKeyConfig::for_account_plan("lb", 7, 2);
+        assert!(!verify(&token.token, &data, &moved).unwrap());
+        let account = ApiKeyConfig::for_account("lb", 7);
+        assert!(!verify(&token.token, &data, &account).unwrap());
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        assert_eq!(
+            parse("sk_v1_abc", "lb").err(),
+            Some(ApiKeyError::InvalidPrefix {
+                expected: "lb".to_string(),
+                got: "sk".to_string()
+            })
+        );
+        assert_eq!(
+            parse("lb_v2_abc", "lb").err(),
+            Some(ApiKeyError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            parse("lb_v1_!!", "lb").err(),
+            Some(ApiKeyError::InvalidEncoding)
+        );
+        assert_eq!(
+            parse("nounderscores", "lb").err(),
+            Some(ApiKeyError::InvalidFormat)
+        );
+    }
+}
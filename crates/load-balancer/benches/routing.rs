@@ -0,0 +1,37 @@
+//! Per-request routing cost with 1k services.
+//!
+//! Run with `cargo bench --bench routing`.
+
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use load_balancer::configuration::Config;
+
+const ROUTES: usize = 1000;
+
+/// Services under `/api/v1/<team>/<name>`, so prefixes share long common heads.
+fn config() -> Config {
+    let mut yaml = String::from("services:\n");
+    for i in 0..ROUTES {
+        yaml.push_str(&format!("  svc{i:04}: /api/v1/team{}/svc{i:04}\n", i % 10));
+    }
+    yaml.push_str("  root: /\nbackends: []\n");
+    let mut config: Config = serde_yaml::from_str(&yaml).expect("bench config");
+    config.compile_routes();
+    config
+}
+
+fn routing(c: &mut Criterion) {
+    let config = config();
+    assert_eq!(config.routes.len(), ROUTES + 1);
+
+    let mut group = c.benchmark_group("routing_1k");
+    group.bench_function("hit", |b| {
+        b.iter(|| config.route(black_box("/api/v1/team7/svc0997/geocode?q=berlin")))
+    });
+    group.bench_function("fallthrough_to_root", |b| {
+        b.iter(|| config.route(black_box("/api/v1/team7/unknown/geocode")))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, routing);
+criterion_main!(benches);
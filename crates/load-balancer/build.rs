@@ -0,0 +1,47 @@
+//! Generates the gRPC services of the control plane ([`load_balancer::grpc`]) and of xDS
+//! ([`load_balancer::xds`]). Their messages are defined in Rust, so no `protoc` is needed:
+//! `proto/control.proto` describes those of the control plane, and the xDS ones are a
+//! subset of Envoy's v3 API.
+
+use tonic_build::manual::{Builder, Method, Service};
+
+fn method(name: &str, route_name: &str, input: &str, output: &str) -> Method {
+    Method::builder()
+        .name(name)
+        .route_name(route_name)
+        .input_type(format!("crate::{input}"))
+        .output_type(format!("crate::{output}"))
+        .codec_path("tonic_prost::ProstCodec")
+        .build()
+}
+
+fn main() {
+    let control_plane = Service::builder()
+        .name("ControlPlane")
+        .package("lb.control.v1")
+        .method(method("push", "Push", "grpc::PushRequest", "grpc::Ack"))
+        .method(method(
+            "version",
+            "Version",
+            "grpc::VersionRequest",
+            "grpc::VersionReply",
+        ))
+        .build();
+    let ads = Service::builder()
+        .name("AggregatedDiscoveryService")
+        .package("envoy.service.discovery.v3")
+        .method(
+            Method::builder()
+                .name("stream_aggregated_resources")
+                .route_name("StreamAggregatedResources")
+                .input_type("crate::xds::proto::DiscoveryRequest")
+                .output_type("crate::xds::proto::DiscoveryResponse")
+                .codec_path("tonic_prost::ProstCodec")
+                .client_streaming()
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().compile(&[control_plane, ads]);
+    println!("cargo:rerun-if-changed=build.rs");
+}
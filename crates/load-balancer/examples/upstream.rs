@@ -29,7 +29,7 @@ async fn main() {
         .await
         .unwrap();
     let app = Router::new().route("/{*key}", routing::get(handler));
-    let _ = axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app).await.unwrap();
 }
 
 #[derive(Deserialize, Serialize)]
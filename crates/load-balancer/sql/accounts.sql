@@ -5,6 +5,12 @@ CREATE TABLE Plans (
     monthly_quota INTEGER NOT NULL,
     rps_limit INTEGER NOT NULL,
     price_per_1k_req REAL NOT NULL,
+    -- Burst credits: unused quota accrues (scaled by accrual_rate) up to burst_cap.
+    -- A burst_cap of 0 disables bursting for the plan.
+    burst_cap INTEGER NOT NULL DEFAULT 0,
+    accrual_rate REAL NOT NULL DEFAULT 0,
+    -- Admission priority under overload (Enterprise > Pro > Free); higher is admitted first.
+    priority INTEGER NOT NULL DEFAULT 0,
     updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
 );
 
@@ -14,6 +20,9 @@ CREATE TABLE Accounts (
     email TEXT UNIQUE NOT NULL,
     plan_id INTEGER NOT NULL,
     billing_status TEXT NOT NULL,
+    -- Data residency region (e.g. 'eu'): usage of the account is written to the usage
+    -- directory of the region when the LB has one. NULL for no commitment.
+    residency TEXT,
     updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY (plan_id) REFERENCES Plans(plan_id)
 );
@@ -23,8 +32,27 @@ CREATE TABLE APIKeys (
     api_key_id INTEGER PRIMARY KEY AUTOINCREMENT,
     api_key CHAR(36) UNIQUE NOT NULL,
     account_id INTEGER NOT NULL,
+    -- Version 0: hex SHA-256 of the raw key. Version 1: hex SHA3-512 of an `lb_v1_` token
+    -- secret, bound to the owning account (see the api-key crate).
     api_key_hash TEXT UNIQUE NOT NULL,
+    version SMALLINT NOT NULL DEFAULT 0,
     is_active BOOLEAN NOT NULL DEFAULT 1,
+    -- Comma-separated scopes granted to the key, e.g. 'read,write'.
+    scopes TEXT NOT NULL DEFAULT '',
+    -- Whether a version 1 hash is bound to the account's plan as well, so the key stops
+    -- verifying when the account changes plans.
+    plan_bound BOOLEAN NOT NULL DEFAULT 0,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (account_id) REFERENCES Accounts(account_id)
+);
+
+-- Accounts served by dedicated backends: requests of the account go to the backends of
+-- `pool` (the `pool` of backends in the backend config) for services that have any.
+CREATE TABLE AccountRouting (
+    account_id INTEGER PRIMARY KEY,
+    pool TEXT NOT NULL,
     updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY (account_id) REFERENCES Accounts(account_id)
 );
@@ -77,3 +105,16 @@ END;
 CREATE TRIGGER trg_apikeys_delete AFTER DELETE ON APIKeys BEGIN
     INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', OLD.api_key_id, 'DELETE');
 END;
+
+-- --- ACCOUNTROUTING TRIGGERS ---
+CREATE TRIGGER trg_accountrouting_insert AFTER INSERT ON AccountRouting BEGIN
+    INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('AccountRouting', NEW.account_id, 'INSERT');
+END;
+
+CREATE TRIGGER trg_accountrouting_update AFTER UPDATE ON AccountRouting BEGIN
+    INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('AccountRouting', NEW.account_id, 'UPDATE');
+END;
+
+CREATE TRIGGER trg_accountrouting_delete AFTER DELETE ON AccountRouting BEGIN
+    INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('AccountRouting', OLD.account_id, 'DELETE');
+END;
@@ -2,8 +2,24 @@ CREATE TABLE Usage (
     account_id INTEGER NOT NULL,
     api_key CHAR(36) NOT NULL,
     plan_id INTEGER NOT NULL,
+    -- Matched service and its backend variant; empty when the request was not routed.
+    service TEXT NOT NULL DEFAULT '',
+    backend TEXT NOT NULL DEFAULT '',
+    -- Static labels of the service as key=value,...; not part of the key.
+    labels TEXT NOT NULL DEFAULT '',
+    -- Id of the LB node that served the requests; not part of the key.
+    node TEXT NOT NULL DEFAULT '',
     date_time DATETIME NOT NULL,
     total_requests INTEGER NOT NULL DEFAULT 0,
     total_data_mb REAL NOT NULL DEFAULT 0,
-    PRIMARY KEY (account_id, api_key, plan_id, date_time)
+    aborted_requests INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, api_key, plan_id, service, backend, date_time)
+);
+
+-- Last use of each key seen during the hour; later files supersede earlier ones.
+CREATE TABLE KeyActivity (
+    api_key CHAR(36) PRIMARY KEY,
+    account_id INTEGER NOT NULL,
+    last_used_at DATETIME NOT NULL,
+    client_ip TEXT
 );
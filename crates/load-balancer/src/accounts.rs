@@ -3,17 +3,33 @@
 //! Loads Plans, Accounts, and API Keys from SQLite and provides rate limiting
 //! based on the account's plan settings.
 
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use pingora::services::background::BackgroundService;
-use rusqlite::{Connection, OpenFlags};
+use rusqlite::{Connection, OptionalExtension, Row, params_from_iter};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use crate::alert::AlertSink;
+use crate::degradation::{Degradation, Dependency};
+use crate::readiness::{Phase, Readiness};
+use crate::sqlite;
+use crate::sync::{MutexExt, RwLockExt};
+
+/// Prefix of versioned API key tokens (`lb_v1_...`).
+pub const API_KEY_PREFIX: &str = "lb";
+
+/// Fingerprint reported for keys that are neither known nor well-formed tokens.
+pub const UNKNOWN_KEY_FINGERPRINT: &str = "unknown";
+
 // ============================================================================
 // Rate Limit Trait and Structs
 // ============================================================================
@@ -22,6 +38,49 @@ use uuid::Uuid;
 pub struct Limit {
     pub quota: isize,
     pub per_seconds: u64,
+    /// Burst credit policy, if the plan allows bursting above `quota`.
+    pub burst: Option<BurstPolicy>,
+    /// Where the limit came from.
+    pub source: LimitSource,
+}
+
+/// Origin of a key's limit, reported in debug headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitSource {
+    /// The plan of the key's account.
+    Plan,
+    /// Static fallback limits applied while the accounts DB is unavailable.
+    Override,
+    /// The default limit for unknown keys.
+    Default,
+    /// Per client IP limit of the internal listener or an `auth: none` service.
+    Ip,
+    /// The default limit of the key's tenant.
+    Tenant,
+    /// The limit of a request tag, counted per key and tag.
+    Tag,
+}
+
+impl LimitSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitSource::Plan => "plan",
+            LimitSource::Override => "override",
+            LimitSource::Default => "default",
+            LimitSource::Ip => "ip",
+            LimitSource::Tenant => "tenant",
+            LimitSource::Tag => "tag",
+        }
+    }
+}
+
+/// How unused quota turns into burst credits for a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BurstPolicy {
+    /// Maximum number of credits a key can hold.
+    pub cap: u32,
+    /// Credits earned per unit of unused quota.
+    pub accrual_rate: f64,
 }
 
 /// Provide rate limit settings for a given API key.
@@ -29,18 +88,52 @@ pub trait Ratelimit {
     fn limit_for_key(&self, api_key: &str) -> Limit;
 }
 
+/// Provide rate limit settings for a given API key, possibly over the network (a shared
+/// store, a quota service) without blocking the proxy's worker threads.
+#[async_trait]
+pub trait AsyncRatelimit: Send + Sync {
+    async fn limit_for_key(&self, api_key: &str) -> Limit;
+}
+
+/// A [`Ratelimit`] answering from memory, used as an [`AsyncRatelimit`].
+pub struct SyncRatelimit<R>(pub Arc<R>);
+
+#[async_trait]
+impl<R: Ratelimit + Send + Sync> AsyncRatelimit for SyncRatelimit<R> {
+    async fn limit_for_key(&self, api_key: &str) -> Limit {
+        self.0.limit_for_key(api_key)
+    }
+}
+
 // ============================================================================
 // Data Structs
 // ============================================================================
 
 /// Represents a pricing tier with rate limits and quotas.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Plan {
     pub plan_id: i64,
     pub name: String,
     pub monthly_quota: i32,
     pub rps_limit: i32,
     pub price_per_1k_req: f64,
+    /// Maximum burst credits a key can accrue (0 disables bursting).
+    pub burst_cap: i32,
+    /// Burst credits earned per unit of unused quota.
+    pub accrual_rate: f64,
+    /// Admission priority under overload; higher plans are admitted first.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl Plan {
+    /// Burst policy for this plan, or `None` when bursting is disabled.
+    pub fn burst_policy(&self) -> Option<BurstPolicy> {
+        (self.burst_cap > 0 && self.accrual_rate > 0.0).then_some(BurstPolicy {
+            cap: self.burst_cap as u32,
+            accrual_rate: self.accrual_rate,
+        })
+    }
 }
 
 /// Represents an account that owns subscriptions.
@@ -50,16 +143,59 @@ pub struct Account {
     pub email: String,
     pub plan_id: i64,
     pub billing_status: String,
+    /// Data residency region of the account's usage records, if it has a commitment.
+    pub residency: Option<String>,
+}
+
+/// Routes an account to the backends of a dedicated pool.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountRouting {
+    pub account_id: i64,
+    pub pool: String,
 }
 
 /// Represents an API key belonging to an account.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiKey {
     pub api_key_id: i64,
     pub api_key: Uuid,
     pub account_id: i64,
     pub api_key_hash: String,
+    /// Key format: 0 for legacy SHA-256 keys, 1 for context-bound tokens.
+    pub version: i16,
     pub is_active: bool,
+    /// Creation time as stored by SQLite (`YYYY-MM-DD HH:MM:SS`, UTC).
+    pub created_at: Option<String>,
+    /// Last time the key was seen, if it has been used.
+    pub last_used_at: Option<String>,
+    /// Scopes granted to the key.
+    pub scopes: Vec<String>,
+    /// Whether the token's hash is bound to the account's plan as well, so that the key
+    /// stops verifying when the account changes plans.
+    #[serde(default)]
+    pub plan_bound: bool,
+}
+
+/// Self-service metadata about an API key and the account that owns it.
+#[derive(Debug, Clone)]
+pub struct KeyMetadata {
+    pub api_key: Uuid,
+    pub fingerprint: String,
+    pub account_id: i64,
+    pub email: String,
+    pub plan_name: Option<String>,
+    pub created_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+/// An account with its plan and active keys, for operator tooling.
+#[derive(Debug, Clone)]
+pub struct AccountSummary {
+    pub account_id: i64,
+    pub email: String,
+    pub plan: Option<Plan>,
+    pub keys: Vec<KeyMetadata>,
 }
 
 /// Represents a change log entry from the database.
@@ -71,12 +207,47 @@ pub struct ChangeLogEntry {
     pub operation: String,
 }
 
+/// A key or plan change pushed by the control plane (see [`crate::control`]).
+#[derive(Debug, Clone)]
+pub enum PushedChange {
+    /// Stop accepting the key `api_key_id`.
+    RevokeKey { api_key_id: i64 },
+    /// Add a plan or change its limits.
+    Plan(Plan),
+    /// Move an account to another plan.
+    AccountPlan { account_id: i64, plan_id: i64 },
+}
+
+impl PushedChange {
+    /// Table, id column and id of the row the change is to.
+    fn row(&self) -> (&'static str, &'static str, i64) {
+        match self {
+            PushedChange::RevokeKey { api_key_id } => ("APIKeys", "api_key_id", *api_key_id),
+            PushedChange::Plan(plan) => ("Plans", "plan_id", plan.plan_id),
+            PushedChange::AccountPlan { account_id, .. } => ("Accounts", "account_id", *account_id),
+        }
+    }
+}
+
+/// A [`PushedChange`] with the control plane's version of it and when it was applied.
+///
+/// The LB does not write pushes to the accounts DB, which the control plane owns. Loads of
+/// the DB apply them again over the rows they read, until the row's `updated_at` is later
+/// than `pushed_at`: the DB then has the change, or a newer one.
+#[derive(Debug, Clone)]
+pub struct Pushed {
+    pub version: u64,
+    /// Unix time the change was applied.
+    pub pushed_at: i64,
+    pub change: PushedChange,
+}
+
 // ============================================================================
 // Account Store
 // ============================================================================
 
 /// Thread-safe in-memory store for account data with delta loading support.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct AccountStore {
     /// API key hash -> Account ID
     api_key_to_account: HashMap<String, i64>,
@@ -84,12 +255,28 @@ pub struct AccountStore {
     api_key_to_key_id: HashMap<String, (i64, Uuid)>,
     /// api_key_id -> API key hash (for reverse lookup during deletes)
     api_key_id_to_hash: HashMap<i64, String>,
+    /// API key hash -> full key record for self-service metadata
+    api_key_details: HashMap<String, ApiKey>,
+    /// Key UUID -> API key hash, for looking up versioned tokens
+    api_key_uuid_to_hash: HashMap<Uuid, String>,
+    /// Key fingerprint -> API key hash, for mapping metrics back to keys
+    api_key_fingerprint_to_hash: HashMap<String, String>,
     /// Account ID -> Plan ID
     account_to_plan: HashMap<i64, i64>,
+    /// Account ID -> email
+    account_emails: HashMap<i64, String>,
     /// Plan ID -> Plan
     plans: HashMap<i64, Plan>,
+    /// Account ID -> dedicated backend pool
+    account_pools: HashMap<i64, String>,
+    /// Account ID -> data residency region
+    account_residency: HashMap<i64, String>,
     /// Track max change_id for ChangeLog-based delta loading
     max_change_id: i64,
+    /// Prefix of the versioned tokens issued for this store; [`API_KEY_PREFIX`] when unset
+    token_prefix: Option<String>,
+    /// Control-plane changes applied over the loaded data, in version order
+    pushed: Vec<Pushed>,
 }
 
 impl AccountStore {
@@ -98,6 +285,17 @@ impl AccountStore {
         Self::default()
     }
 
+    /// Use `prefix` for versioned tokens and fingerprints. Set before adding keys.
+    pub fn with_token_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.token_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Prefix of the versioned tokens issued for this store.
+    pub fn token_prefix(&self) -> &str {
+        self.token_prefix.as_deref().unwrap_or(API_KEY_PREFIX)
+    }
+
     /// Lookup the plan for a given API key hash.
     pub fn get_plan_for_key(&self, api_key_hash: &str) -> Option<&Plan> {
         let account_id = self.api_key_to_account.get(api_key_hash)?;
@@ -105,6 +303,12 @@ impl AccountStore {
         self.plans.get(plan_id)
     }
 
+    /// Dedicated backend pool of the account owning a key, if the account has one.
+    pub fn get_pool_for_key(&self, api_key_hash: &str) -> Option<&str> {
+        let account_id = self.api_key_to_account.get(api_key_hash)?;
+        self.account_pools.get(account_id).map(String::as_str)
+    }
+
     /// Get full context for a key: (account_id, api_key, plan_id).
     /// Used for usage tracking.
     pub fn get_key_context(&self, api_key_hash: &str) -> Option<(i64, Uuid, i64)> {
@@ -114,6 +318,113 @@ impl AccountStore {
         Some((account_id, *api_key, plan_id))
     }
 
+    /// Map a raw key as presented by a client to the hash it is stored under.
+    ///
+    /// Versioned tokens (`lb_v1_...` or the store's prefix) are looked up by their embedded UUID and verified against the stored
+    /// hash using the owning account's context, so a hash copied onto a key row of another
+    /// account does not verify. Plan-bound keys are verified with the context of the
+    /// account's current plan, so they stop resolving when it changes plans. Anything else
+    /// is treated as a legacy key and hashed with SHA-256. A token that fails verification
+    /// resolves to a hash that matches no key.
+    pub fn resolve_key(&self, api_key: &str) -> String {
+        if let Ok(parsed) = api_key::parse(api_key, self.token_prefix())
+            && let Some(hash) = self.api_key_uuid_to_hash.get(&parsed.id())
+            && let Some(key) = self.api_key_details.get(hash)
+            && key.version == parsed.version()
+            && let Some(context) = self.key_hash_context(key)
+            && let Ok(stored) = hex::decode(&key.api_key_hash)
+            && let Ok(stored) = <[u8; 64]>::try_from(stored.as_slice())
+            && api_key::verify_hash(&parsed, &stored, Some(context))
+        {
+            return key.api_key_hash.clone();
+        }
+        hash_api_key(api_key)
+    }
+
+    /// Context the token of `key` was hashed with: its account's, or for plan-bound keys
+    /// that of the account's current plan; `None` when the account has no plan.
+    fn key_hash_context(&self, key: &ApiKey) -> Option<Uuid> {
+        if !key.plan_bound {
+            return Some(api_key::account_context(key.account_id));
+        }
+        let plan_id = self.account_to_plan.get(&key.account_id)?;
+        Some(api_key::plan_context(key.account_id, *plan_id))
+    }
+
+    /// Fingerprint identifying a raw key in logs, metrics and admin output.
+    ///
+    /// Known keys are fingerprinted from their UUID, so legacy keys get one too. Unknown
+    /// tokens are fingerprinted from their embedded UUID; other unknown keys share
+    /// [`UNKNOWN_KEY_FINGERPRINT`].
+    pub fn fingerprint(&self, api_key: &str) -> String {
+        if let Some(key) = self.api_key_details.get(&self.resolve_key(api_key)) {
+            return api_key::fingerprint(self.token_prefix(), key.api_key);
+        }
+        match api_key::parse(api_key, self.token_prefix()) {
+            Ok(parsed) => parsed.fingerprint(),
+            Err(_) => UNKNOWN_KEY_FINGERPRINT.to_string(),
+        }
+    }
+
+    /// Hash of the active key with the given fingerprint.
+    pub fn hash_for_fingerprint(&self, fingerprint: &str) -> Option<&str> {
+        self.api_key_fingerprint_to_hash
+            .get(fingerprint)
+            .map(String::as_str)
+    }
+
+    /// Metadata for an active key, for the self-service endpoint.
+    pub fn key_metadata(&self, api_key_hash: &str) -> Option<KeyMetadata> {
+        let key = self.api_key_details.get(api_key_hash)?;
+        let email = self.account_emails.get(&key.account_id)?;
+        Some(KeyMetadata {
+            api_key: key.api_key,
+            fingerprint: api_key::fingerprint(self.token_prefix(), key.api_key),
+            account_id: key.account_id,
+            email: email.clone(),
+            plan_name: self.get_plan_for_key(api_key_hash).map(|p| p.name.clone()),
+            created_at: key.created_at.clone(),
+            last_used_at: key.last_used_at.clone(),
+            scopes: key.scopes.clone(),
+        })
+    }
+
+    /// An account with its plan and active keys, if the account is loaded.
+    pub fn account_summary(&self, account_id: i64) -> Option<AccountSummary> {
+        let email = self.account_emails.get(&account_id)?;
+        let mut keys: Vec<KeyMetadata> = self
+            .api_key_details
+            .iter()
+            .filter(|(_, key)| key.account_id == account_id)
+            .filter_map(|(hash, _)| self.key_metadata(hash))
+            .collect();
+        keys.sort_by_key(|key| key.api_key);
+        Some(AccountSummary {
+            account_id,
+            email: email.clone(),
+            plan: self
+                .account_to_plan
+                .get(&account_id)
+                .and_then(|plan_id| self.plans.get(plan_id))
+                .cloned(),
+            keys,
+        })
+    }
+
+    /// Data residency region of an account, if it has one.
+    pub fn residency(&self, account_id: i64) -> Option<&str> {
+        self.account_residency.get(&account_id).map(String::as_str)
+    }
+
+    /// Number of (plans, accounts, active API keys) held in memory.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (
+            self.plans.len(),
+            self.account_to_plan.len(),
+            self.api_key_to_account.len(),
+        )
+    }
+
     /// Get max change_id for ChangeLog-based delta loading.
     pub fn max_change_id(&self) -> i64 {
         self.max_change_id
@@ -124,6 +435,11 @@ impl AccountStore {
         self.max_change_id = change_id;
     }
 
+    /// Plan by ID.
+    pub fn plan(&self, plan_id: i64) -> Option<&Plan> {
+        self.plans.get(&plan_id)
+    }
+
     /// Insert or update a plan.
     pub fn upsert_plan(&mut self, plan: Plan) {
         self.plans.insert(plan.plan_id, plan);
@@ -138,11 +454,29 @@ impl AccountStore {
     pub fn upsert_account(&mut self, account: Account) {
         self.account_to_plan
             .insert(account.account_id, account.plan_id);
+        self.account_emails
+            .insert(account.account_id, account.email);
+        match account.residency {
+            Some(region) => self.account_residency.insert(account.account_id, region),
+            None => self.account_residency.remove(&account.account_id),
+        };
     }
 
     /// Delete an account by ID.
     pub fn delete_account(&mut self, account_id: i64) {
         self.account_to_plan.remove(&account_id);
+        self.account_emails.remove(&account_id);
+        self.account_residency.remove(&account_id);
+    }
+
+    /// Route an account to a dedicated backend pool.
+    pub fn upsert_account_routing(&mut self, routing: AccountRouting) {
+        self.account_pools.insert(routing.account_id, routing.pool);
+    }
+
+    /// Route an account back to the shared backends.
+    pub fn delete_account_routing(&mut self, account_id: i64) {
+        self.account_pools.remove(&account_id);
     }
 
     /// Insert or update an API key.
@@ -151,6 +485,10 @@ impl AccountStore {
         if let Some(old_hash) = self.api_key_id_to_hash.get(&api_key.api_key_id) {
             self.api_key_to_account.remove(old_hash);
             self.api_key_to_key_id.remove(old_hash);
+            self.api_key_details.remove(old_hash);
+            self.api_key_uuid_to_hash.retain(|_, hash| hash != old_hash);
+            self.api_key_fingerprint_to_hash
+                .retain(|_, hash| hash != old_hash);
         }
 
         if api_key.is_active {
@@ -161,19 +499,215 @@ impl AccountStore {
                 (api_key.api_key_id, api_key.api_key),
             );
             self.api_key_id_to_hash
-                .insert(api_key.api_key_id, api_key.api_key_hash);
+                .insert(api_key.api_key_id, api_key.api_key_hash.clone());
+            self.api_key_uuid_to_hash
+                .insert(api_key.api_key, api_key.api_key_hash.clone());
+            self.api_key_fingerprint_to_hash.insert(
+                api_key::fingerprint(self.token_prefix(), api_key.api_key),
+                api_key.api_key_hash.clone(),
+            );
+            self.api_key_details
+                .insert(api_key.api_key_hash.clone(), api_key);
         } else {
             // Inactive key: remove from lookup maps but keep reverse lookup
             self.api_key_id_to_hash.remove(&api_key.api_key_id);
         }
     }
 
+    /// Id of the active key with the given fingerprint.
+    pub fn api_key_id_for_fingerprint(&self, fingerprint: &str) -> Option<i64> {
+        self.hash_for_fingerprint(fingerprint)
+            .and_then(|hash| self.api_key_details.get(hash))
+            .map(|key| key.api_key_id)
+    }
+
+    /// Stop accepting the active key with the given fingerprint; false if there is none.
+    pub fn revoke_fingerprint(&mut self, fingerprint: &str) -> bool {
+        let Some(api_key_id) = self.api_key_id_for_fingerprint(fingerprint) else {
+            return false;
+        };
+        self.delete_api_key(api_key_id);
+        true
+    }
+
+    /// Move an account to another plan; false for an unknown account.
+    pub fn set_account_plan(&mut self, account_id: i64, plan_id: i64) -> bool {
+        match self.account_to_plan.get_mut(&account_id) {
+            Some(current) => {
+                *current = plan_id;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the account is known.
+    pub fn has_account(&self, account_id: i64) -> bool {
+        self.account_to_plan.contains_key(&account_id)
+    }
+
+    /// Apply a change pushed by the control plane, and again after each load of the DB
+    /// until the DB has a newer change of the row. Fails for an unknown account or plan.
+    pub fn apply_pushed(&mut self, pushed: Pushed) -> Result<(), String> {
+        match &pushed.change {
+            PushedChange::RevokeKey { api_key_id } => self.delete_api_key(*api_key_id),
+            PushedChange::Plan(plan) => self.upsert_plan(plan.clone()),
+            PushedChange::AccountPlan {
+                account_id,
+                plan_id,
+            } => {
+                if self.plan(*plan_id).is_none() {
+                    return Err(format!("unknown plan {plan_id}"));
+                }
+                if !self.set_account_plan(*account_id, *plan_id) {
+                    return Err(format!("unknown account {account_id}"));
+                }
+            }
+        }
+        let row = pushed.change.row();
+        self.pushed.retain(|p| p.change.row() != row);
+        self.pushed.push(pushed);
+        Ok(())
+    }
+
+    /// Control-plane changes applied over the loaded data, in version order.
+    pub fn pushed(&self) -> &[Pushed] {
+        &self.pushed
+    }
+
+    /// Version of the last control-plane change applied.
+    fn last_pushed_version(&self) -> Option<u64> {
+        self.pushed.last().map(|p| p.version)
+    }
+
+    /// Apply the changes of `current` pushed after `version` to this store, which replaces
+    /// it, so pushes made while it was loaded are not lost.
+    fn carry_pushes_after(&mut self, current: &AccountStore, version: Option<u64>) {
+        for pushed in current.pushed.iter().filter(|p| Some(p.version) > version) {
+            if let Err(e) = self.apply_pushed(pushed.clone()) {
+                log::info!("Dropped control plane push {}: {e}", pushed.version);
+            }
+        }
+    }
+
     /// Delete an API key by api_key_id.
     pub fn delete_api_key(&mut self, api_key_id: i64) {
         if let Some(hash) = self.api_key_id_to_hash.remove(&api_key_id) {
             self.api_key_to_account.remove(&hash);
             self.api_key_to_key_id.remove(&hash);
+            if let Some(key) = self.api_key_details.remove(&hash) {
+                self.api_key_uuid_to_hash.remove(&key.api_key);
+                self.api_key_fingerprint_to_hash
+                    .remove(&api_key::fingerprint(self.token_prefix(), key.api_key));
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Snapshots
+// ============================================================================
+
+/// Snapshot file format version, bumped on incompatible changes.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The account fields the store keeps.
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotAccount {
+    account_id: i64,
+    email: String,
+    plan_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    residency: Option<String>,
+}
+
+/// On-disk form of an [`AccountStore`].
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountSnapshot {
+    version: u32,
+    max_change_id: i64,
+    token_prefix: String,
+    plans: Vec<Plan>,
+    accounts: Vec<SnapshotAccount>,
+    api_keys: Vec<ApiKey>,
+    #[serde(default)]
+    account_routing: Vec<AccountRouting>,
+}
+
+impl AccountStore {
+    /// Write the store to `path` as a compact JSON snapshot.
+    ///
+    /// The file is written next to `path` and renamed into place, so a crash never leaves a
+    /// truncated snapshot behind.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        let snapshot = AccountSnapshot {
+            version: SNAPSHOT_VERSION,
+            max_change_id: self.max_change_id,
+            token_prefix: self.token_prefix().to_string(),
+            plans: self.plans.values().cloned().collect(),
+            accounts: self
+                .account_to_plan
+                .iter()
+                .map(|(account_id, plan_id)| SnapshotAccount {
+                    account_id: *account_id,
+                    email: self
+                        .account_emails
+                        .get(account_id)
+                        .cloned()
+                        .unwrap_or_default(),
+                    plan_id: *plan_id,
+                    residency: self.account_residency.get(account_id).cloned(),
+                })
+                .collect(),
+            api_keys: self.api_key_details.values().cloned().collect(),
+            account_routing: self
+                .account_pools
+                .iter()
+                .map(|(account_id, pool)| AccountRouting {
+                    account_id: *account_id,
+                    pool: pool.clone(),
+                })
+                .collect(),
+        };
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        std::fs::write(&tmp, serde_json::to_vec(&snapshot)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Read a store written by [`AccountStore::save_snapshot`].
+    pub fn load_snapshot(path: &Path) -> io::Result<Self> {
+        let snapshot: AccountSnapshot = serde_json::from_slice(&std::fs::read(path)?)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported snapshot version {}", snapshot.version),
+            ));
+        }
+
+        let mut store = AccountStore::new().with_token_prefix(snapshot.token_prefix);
+        for plan in snapshot.plans {
+            store.upsert_plan(plan);
+        }
+        for account in snapshot.accounts {
+            store.upsert_account(Account {
+                account_id: account.account_id,
+                email: account.email,
+                plan_id: account.plan_id,
+                billing_status: String::new(),
+                residency: account.residency,
+            });
+        }
+        for api_key in snapshot.api_keys {
+            store.upsert_api_key(api_key);
+        }
+        for routing in snapshot.account_routing {
+            store.upsert_account_routing(routing);
         }
+        store.set_max_change_id(snapshot.max_change_id);
+        Ok(store)
     }
 }
 
@@ -181,9 +715,262 @@ impl AccountStore {
 // Account Loader
 // ============================================================================
 
+/// Columns selected for a [`Plan`], in the order read by [`plan_from_row`].
+const PLAN_COLUMNS: &str =
+    "plan_id, name, monthly_quota, rps_limit, price_per_1k_req, burst_cap, accrual_rate, priority";
+/// Columns selected for an [`Account`], in the order read by [`account_from_row`].
+const ACCOUNT_COLUMNS: &str = "account_id, email, plan_id, billing_status, residency";
+/// Columns selected for an [`AccountRouting`], in the order read by [`account_routing_from_row`].
+const ACCOUNT_ROUTING_COLUMNS: &str = "account_id, pool";
+/// Columns selected for an [`ApiKey`], in the order read by [`api_key_from_row`].
+const API_KEY_COLUMNS: &str = "api_key_id, api_key, account_id, api_key_hash, is_active, created_at, last_used_at, scopes, version, plan_bound";
+
+/// A change to the accounts DB since its first schema.
+enum Migration {
+    /// Add `column` to `table`, with the SQL `definition`.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+    /// Create `table` and its ChangeLog triggers with the SQL `schema`.
+    CreateTable {
+        table: &'static str,
+        schema: &'static str,
+    },
+}
+
+/// Changes to the accounts DB since its first schema, in the order they were made.
+/// [`migrate_schema`] applies the ones a database lacks.
+const MIGRATIONS: &[Migration] = &[
+    // Burst credits
+    Migration::AddColumn {
+        table: "Plans",
+        column: "burst_cap",
+        definition: "INTEGER NOT NULL DEFAULT 0",
+    },
+    Migration::AddColumn {
+        table: "Plans",
+        column: "accrual_rate",
+        definition: "REAL NOT NULL DEFAULT 0",
+    },
+    // Key metadata. Added columns cannot default to `CURRENT_TIMESTAMP`, so `created_at`
+    // is left empty on keys created before it.
+    Migration::AddColumn {
+        table: "APIKeys",
+        column: "scopes",
+        definition: "TEXT NOT NULL DEFAULT ''",
+    },
+    Migration::AddColumn {
+        table: "APIKeys",
+        column: "created_at",
+        definition: "TIMESTAMP",
+    },
+    Migration::AddColumn {
+        table: "APIKeys",
+        column: "last_used_at",
+        definition: "TIMESTAMP",
+    },
+    // Versioned key hashes; existing keys keep the unversioned SHA-256 hash
+    Migration::AddColumn {
+        table: "APIKeys",
+        column: "version",
+        definition: "SMALLINT NOT NULL DEFAULT 0",
+    },
+    // Admission priority under overload
+    Migration::AddColumn {
+        table: "Plans",
+        column: "priority",
+        definition: "INTEGER NOT NULL DEFAULT 0",
+    },
+    // Dedicated backend pools
+    Migration::CreateTable {
+        table: "AccountRouting",
+        schema: ACCOUNT_ROUTING_SCHEMA,
+    },
+    // Data residency of usage
+    Migration::AddColumn {
+        table: "Accounts",
+        column: "residency",
+        definition: "TEXT",
+    },
+    // Keys bound to the account's plan
+    Migration::AddColumn {
+        table: "APIKeys",
+        column: "plan_bound",
+        definition: "BOOLEAN NOT NULL DEFAULT 0",
+    },
+];
+
+/// `AccountRouting` and its ChangeLog triggers.
+const ACCOUNT_ROUTING_SCHEMA: &str = r#"
+CREATE TABLE AccountRouting (
+    account_id INTEGER PRIMARY KEY,
+    pool TEXT NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (account_id) REFERENCES Accounts(account_id)
+);
+CREATE TRIGGER trg_accountrouting_insert AFTER INSERT ON AccountRouting BEGIN
+    INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('AccountRouting', NEW.account_id, 'INSERT');
+END;
+CREATE TRIGGER trg_accountrouting_update AFTER UPDATE ON AccountRouting BEGIN
+    INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('AccountRouting', NEW.account_id, 'UPDATE');
+END;
+CREATE TRIGGER trg_accountrouting_delete AFTER DELETE ON AccountRouting BEGIN
+    INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('AccountRouting', OLD.account_id, 'DELETE');
+END;
+"#;
+
+impl Migration {
+    fn is_applied(&self, conn: &Connection) -> Result<bool, rusqlite::Error> {
+        match self {
+            Migration::AddColumn { table, column, .. } => conn.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+                [table, column],
+                |row| row.get(0),
+            ),
+            Migration::CreateTable { table, .. } => conn.query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                [table],
+                |row| row.get(0),
+            ),
+        }
+    }
+
+    fn apply(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        match self {
+            Migration::AddColumn {
+                table,
+                column,
+                definition,
+            } => conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            )),
+            Migration::CreateTable { schema, .. } => conn.execute_batch(schema),
+        }
+    }
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Migration::AddColumn { table, column, .. } => write!(f, "column {table}.{column}"),
+            Migration::CreateTable { table, .. } => write!(f, "table {table}"),
+        }
+    }
+}
+
+/// Number of ids bound per batched fetch. Short batches are padded by repeating an id so the
+/// statement text, and therefore the cached statement, is always the same.
+const FETCH_BATCH: usize = 100;
+
+fn plan_from_row(row: &Row) -> Result<Plan, rusqlite::Error> {
+    Ok(Plan {
+        plan_id: row.get(0)?,
+        name: row.get(1)?,
+        monthly_quota: row.get(2)?,
+        rps_limit: row.get(3)?,
+        price_per_1k_req: row.get(4)?,
+        burst_cap: row.get(5)?,
+        accrual_rate: row.get(6)?,
+        priority: row.get(7)?,
+    })
+}
+
+fn account_from_row(row: &Row) -> Result<Account, rusqlite::Error> {
+    Ok(Account {
+        account_id: row.get(0)?,
+        email: row.get(1)?,
+        plan_id: row.get(2)?,
+        billing_status: row.get(3)?,
+        residency: row.get(4)?,
+    })
+}
+
+fn account_routing_from_row(row: &Row) -> Result<AccountRouting, rusqlite::Error> {
+    Ok(AccountRouting {
+        account_id: row.get(0)?,
+        pool: row.get(1)?,
+    })
+}
+
+fn api_key_from_row(row: &Row) -> Result<ApiKey, rusqlite::Error> {
+    let api_key_str: String = row.get(1)?;
+    let api_key = Uuid::parse_str(&api_key_str).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    Ok(ApiKey {
+        api_key_id: row.get(0)?,
+        api_key,
+        account_id: row.get(2)?,
+        api_key_hash: row.get(3)?,
+        is_active: row.get(4)?,
+        created_at: row.get(5)?,
+        last_used_at: row.get(6)?,
+        scopes: parse_scopes(&row.get::<_, String>(7)?),
+        version: row.get(8)?,
+        plan_bound: row.get(9)?,
+    })
+}
+
+/// Fetch the rows of `table` whose `id_column` is in `ids`, [`FETCH_BATCH`] ids per query.
+fn fetch_batched<T>(
+    conn: &Connection,
+    table: &str,
+    columns: &str,
+    id_column: &str,
+    ids: &[i64],
+    from_row: fn(&Row) -> Result<T, rusqlite::Error>,
+) -> Result<Vec<T>, rusqlite::Error> {
+    let placeholders = vec!["?"; FETCH_BATCH].join(", ");
+    let sql = format!("SELECT {columns} FROM {table} WHERE {id_column} IN ({placeholders})");
+    let mut stmt = conn.prepare_cached(&sql)?;
+
+    let mut records = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(FETCH_BATCH) {
+        let padded = (0..FETCH_BATCH).map(|i| chunk[i.min(chunk.len() - 1)]);
+        for record in stmt.query_map(params_from_iter(padded), from_row)? {
+            records.push(record?);
+        }
+    }
+    Ok(records)
+}
+
+/// Final operation for each changed record of one table, in record id order.
+#[derive(Debug, Default)]
+struct TableChanges {
+    /// record_id -> whether the record was inserted (as opposed to updated)
+    upserts: BTreeMap<i64, bool>,
+    deletes: Vec<i64>,
+}
+
+impl TableChanges {
+    /// Record a ChangeLog operation; later operations on the same record win.
+    fn push(&mut self, record_id: i64, operation: &str) {
+        if operation == "DELETE" {
+            self.upserts.remove(&record_id);
+            self.deletes.push(record_id);
+        } else {
+            self.deletes.retain(|id| *id != record_id);
+            // An INSERT followed by UPDATEs is still an insert
+            let inserted = operation == "INSERT";
+            *self.upserts.entry(record_id).or_insert(inserted) |= inserted;
+        }
+    }
+
+    fn upsert_ids(&self) -> Vec<i64> {
+        self.upserts.keys().copied().collect()
+    }
+}
+
 /// Loads account data from SQLite database.
+///
+/// The read-only connection is kept open between loads so prepared statements are reused.
+/// It is dropped after any error and reopened on the next load.
 pub struct AccountLoader {
     db_path: String,
+    conn: Mutex<Option<Connection>>,
+    token_prefix: Option<String>,
 }
 
 impl AccountLoader {
@@ -191,266 +978,422 @@ impl AccountLoader {
     pub fn new<P: AsRef<Path>>(db_path: P) -> Self {
         Self {
             db_path: db_path.as_ref().to_string_lossy().into_owned(),
+            conn: Mutex::new(None),
+            token_prefix: None,
         }
     }
 
-    /// Open a read-only connection to the database.
-    fn open_connection(&self) -> Result<Connection, rusqlite::Error> {
-        Connection::open_with_flags(&self.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    /// Build stores whose versioned tokens use `prefix`.
+    pub fn with_token_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.token_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Look up one active key by `column` (`api_key` or `api_key_hash`) together with its
+    /// account and plan.
+    fn fetch_key(
+        &self,
+        column: &str,
+        value: &str,
+    ) -> Result<Option<(ApiKey, Account, Option<Plan>)>, rusqlite::Error> {
+        self.with_connection(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let key = tx
+                .prepare_cached(&format!(
+                    "SELECT {API_KEY_COLUMNS} FROM APIKeys WHERE {column} = ? AND is_active = 1"
+                ))?
+                .query_map([value], api_key_from_row)?
+                .next()
+                .transpose()?;
+            let Some(key) = key else {
+                return Ok(None);
+            };
+            let Some(account) = fetch_batched(
+                &tx,
+                "Accounts",
+                ACCOUNT_COLUMNS,
+                "account_id",
+                &[key.account_id],
+                account_from_row,
+            )?
+            .pop() else {
+                return Ok(None);
+            };
+            let plan = fetch_batched(
+                &tx,
+                "Plans",
+                PLAN_COLUMNS,
+                "plan_id",
+                &[account.plan_id],
+                plan_from_row,
+            )?
+            .pop();
+            Ok(Some((key, account, plan)))
+        })
+    }
+
+    /// Run `f` on the persistent connection, opening it if needed.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, rusqlite::Error>,
+    ) -> Result<T, rusqlite::Error> {
+        let mut guard = self.conn.lock_or_recover();
+        let conn = match guard.take() {
+            Some(conn) => conn,
+            None => {
+                let conn = sqlite::open_read_only(&self.db_path)?;
+                // Loads fail on the missing columns; the LB does not change the schema
+                for migration in pending_migrations(&conn)? {
+                    log::error!(
+                        "The accounts DB {} has no {migration}, run `lb accounts migrate`",
+                        self.db_path
+                    );
+                }
+                conn
+            }
+        };
+        let result = f(&conn);
+        if result.is_ok() {
+            *guard = Some(conn);
+        }
+        result
     }
 
     /// Perform initial full load of all data.
     pub fn load_initial(&self) -> Result<AccountStore, rusqlite::Error> {
-        let conn = self.open_connection()?;
-        let mut store = AccountStore::new();
+        self.with_connection(|conn| {
+            // Read all tables from one snapshot
+            let tx = conn.unchecked_transaction()?;
+            let conn: &Connection = &tx;
+            let mut store = AccountStore::new();
+            if let Some(prefix) = &self.token_prefix {
+                store = store.with_token_prefix(prefix.clone());
+            }
 
-        // Load all plans
-        let mut stmt = conn.prepare(
-            "SELECT plan_id, name, monthly_quota, rps_limit, price_per_1k_req FROM Plans",
-        )?;
-        let plans = stmt.query_map([], |row| {
-            Ok(Plan {
-                plan_id: row.get(0)?,
-                name: row.get(1)?,
-                monthly_quota: row.get(2)?,
-                rps_limit: row.get(3)?,
-                price_per_1k_req: row.get(4)?,
-            })
-        })?;
-        for plan in plans {
-            store.upsert_plan(plan?);
-        }
-
-        // Load all accounts
-        let mut stmt =
-            conn.prepare("SELECT account_id, email, plan_id, billing_status FROM Accounts")?;
-        let accounts = stmt.query_map([], |row| {
-            Ok(Account {
-                account_id: row.get(0)?,
-                email: row.get(1)?,
-                plan_id: row.get(2)?,
-                billing_status: row.get(3)?,
-            })
-        })?;
-        for account in accounts {
-            store.upsert_account(account?);
-        }
-
-        // Load all API keys
-        let mut stmt = conn.prepare(
-            "SELECT api_key_id, api_key, account_id, api_key_hash, is_active FROM APIKeys",
-        )?;
-        let keys = stmt.query_map([], |row| {
-            let api_key_id: i64 = row.get(0)?;
-            let api_key_str: String = row.get(1)?;
-            let api_key = Uuid::parse_str(&api_key_str).map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    0,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?;
-            Ok(ApiKey {
-                api_key_id,
-                api_key,
-                account_id: row.get(2)?,
-                api_key_hash: row.get(3)?,
-                is_active: row.get(4)?,
-            })
-        })?;
-        for key in keys {
-            store.upsert_api_key(key?);
-        }
+            let mut stmt = conn.prepare_cached(&format!("SELECT {PLAN_COLUMNS} FROM Plans"))?;
+            for plan in stmt.query_map([], plan_from_row)? {
+                store.upsert_plan(plan?);
+            }
 
-        // Get the max change_id for delta loading
-        let max_change_id: i64 = conn
-            .query_row(
-                "SELECT COALESCE(MAX(change_id), 0) FROM ChangeLog",
-                [],
-                |row| row.get(0),
-            )
-            .unwrap_or(0);
-        store.set_max_change_id(max_change_id);
+            let mut stmt =
+                conn.prepare_cached(&format!("SELECT {ACCOUNT_COLUMNS} FROM Accounts"))?;
+            for account in stmt.query_map([], account_from_row)? {
+                store.upsert_account(account?);
+            }
 
-        log::info!(
-            "Loaded {} plans, {} accounts, {} API keys",
-            store.plans.len(),
-            store.account_to_plan.len(),
-            store.api_key_to_account.len()
-        );
+            let mut stmt =
+                conn.prepare_cached(&format!("SELECT {API_KEY_COLUMNS} FROM APIKeys"))?;
+            for key in stmt.query_map([], api_key_from_row)? {
+                store.upsert_api_key(key?);
+            }
 
-        Ok(store)
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {ACCOUNT_ROUTING_COLUMNS} FROM AccountRouting"
+            ))?;
+            for routing in stmt.query_map([], account_routing_from_row)? {
+                store.upsert_account_routing(routing?);
+            }
+
+            // Get the max change_id for delta loading
+            let max_change_id: i64 = conn
+                .prepare_cached("SELECT COALESCE(MAX(change_id), 0) FROM ChangeLog")?
+                .query_row([], |row| row.get(0))
+                .unwrap_or(0);
+            store.set_max_change_id(max_change_id);
+
+            log::info!(
+                "Loaded {} plans, {} accounts, {} API keys",
+                store.plans.len(),
+                store.account_to_plan.len(),
+                store.api_key_to_account.len()
+            );
+
+            Ok(store)
+        })
     }
 
     /// Perform delta load of changes since last load using ChangeLog table.
+    ///
+    /// The ChangeLog and the changed records are read in one transaction, so they come from
+    /// a single consistent snapshot of the database.
     pub fn load_delta(&self, store: &mut AccountStore) -> Result<(), rusqlite::Error> {
-        let conn = self.open_connection()?;
-
-        let last_change_id = store.max_change_id();
-
-        // Query ChangeLog for new entries
-        let mut stmt = conn.prepare(
-            "SELECT change_id, table_name, record_id, operation FROM ChangeLog WHERE change_id > ? ORDER BY change_id"
-        )?;
-        let entries = stmt.query_map([last_change_id], |row| {
-            Ok(ChangeLogEntry {
-                change_id: row.get(0)?,
-                table_name: row.get(1)?,
-                record_id: row.get(2)?,
-                operation: row.get(3)?,
-            })
-        })?;
+        self.with_connection(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let entries = changes_since(&tx, store.max_change_id())?;
+            apply_changes(&tx, store, entries)
+        })
+    }
 
-        let mut inserts = 0;
-        let mut updates = 0;
-        let mut deletes = 0;
-        let mut max_processed_id = last_change_id;
+    /// Delta load into a copy of `store` and swap it in, so readers never see a partially
+    /// applied delta and the write lock is only held for the swap.
+    ///
+    /// Returns whether the store was replaced. Nothing is cloned when there are no changes,
+    /// and the copy is discarded if `store` was replaced concurrently (e.g. by a full reload).
+    pub fn refresh(&self, store: &RwLock<AccountStore>) -> Result<bool, rusqlite::Error> {
+        self.with_connection(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let last_change_id = store.read_or_recover().max_change_id();
+            let entries = changes_since(&tx, last_change_id)?;
+            if entries.is_empty() {
+                return Ok(false);
+            }
 
-        for entry_result in entries {
-            let entry = entry_result?;
-            max_processed_id = entry.change_id;
+            let mut next = store.read_or_recover().clone();
+            if next.max_change_id() != last_change_id {
+                return Ok(false);
+            }
+            let carried = next.last_pushed_version();
+            apply_changes(&tx, &mut next, entries)?;
 
-            match (entry.table_name.as_str(), entry.operation.as_str()) {
-                ("Plans", "DELETE") => {
-                    store.delete_plan(entry.record_id);
-                    deletes += 1;
-                }
-                ("Plans", _) => {
-                    // INSERT or UPDATE: fetch and upsert
-                    if let Some(plan) = self.fetch_plan(&conn, entry.record_id)? {
-                        store.upsert_plan(plan);
-                        if entry.operation == "INSERT" {
-                            inserts += 1;
-                        } else {
-                            updates += 1;
-                        }
-                    }
-                }
-                ("Accounts", "DELETE") => {
-                    store.delete_account(entry.record_id);
-                    deletes += 1;
-                }
-                ("Accounts", _) => {
-                    if let Some(account) = self.fetch_account(&conn, entry.record_id)? {
-                        store.upsert_account(account);
-                        if entry.operation == "INSERT" {
-                            inserts += 1;
-                        } else {
-                            updates += 1;
-                        }
-                    }
-                }
-                ("APIKeys", "DELETE") => {
-                    store.delete_api_key(entry.record_id);
-                    deletes += 1;
-                }
-                ("APIKeys", _) => {
-                    if let Some(api_key) = self.fetch_api_key(&conn, entry.record_id)? {
-                        store.upsert_api_key(api_key);
-                        if entry.operation == "INSERT" {
-                            inserts += 1;
-                        } else {
-                            updates += 1;
-                        }
-                    }
-                }
-                _ => {
-                    log::warn!(
-                        "Unknown table in ChangeLog: {} (change_id={})",
-                        entry.table_name,
-                        entry.change_id
-                    );
-                }
+            let mut current = store.write_or_recover();
+            if current.max_change_id() != last_change_id {
+                log::info!("Account store replaced during delta load, discarding delta");
+                return Ok(false);
             }
+            next.carry_pushes_after(&current, carried);
+            *current = next;
+            Ok(true)
+        })
+    }
+
+    /// Full load into a new store and swap it in for `store`, keeping the control-plane
+    /// changes the DB does not have yet.
+    pub fn reload(&self, store: &RwLock<AccountStore>) -> Result<(), rusqlite::Error> {
+        let mut next = self.load_initial()?;
+        let pushed = store.read_or_recover().pushed.clone();
+        let carried = pushed.last().map(|p| p.version);
+        self.with_connection(|conn| reapply_pushed(conn, &mut next, pushed))?;
+
+        let mut current = store.write_or_recover();
+        next.carry_pushes_after(&current, carried);
+        *current = next;
+        Ok(())
+    }
+}
+
+/// ChangeLog entries after `last_change_id`, in order.
+fn changes_since(
+    conn: &Connection,
+    last_change_id: i64,
+) -> Result<Vec<ChangeLogEntry>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT change_id, table_name, record_id, operation FROM ChangeLog WHERE change_id > ? ORDER BY change_id",
+    )?;
+    stmt.query_map([last_change_id], |row| {
+        Ok(ChangeLogEntry {
+            change_id: row.get(0)?,
+            table_name: row.get(1)?,
+            record_id: row.get(2)?,
+            operation: row.get(3)?,
+        })
+    })?
+    .collect()
+}
+
+/// Bring the accounts DB at `path` up to the current schema with the [`MIGRATIONS`] it
+/// lacks, returning how many were applied. Running it again changes nothing.
+///
+/// The LB only reads the accounts DB, so this is run by `lb accounts migrate` (or the
+/// control plane) before an LB loads a database created with an older schema.
+pub fn migrate_schema(path: &Path) -> Result<usize, rusqlite::Error> {
+    let mut conn = sqlite::open_existing(path)?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let pending = pending_migrations(&tx)?;
+    for migration in &pending {
+        log::info!("Adding {migration} to the accounts DB");
+        migration.apply(&tx)?;
+    }
+    tx.commit()?;
+    Ok(pending.len())
+}
+
+/// The [`MIGRATIONS`] the accounts DB behind `conn` lacks.
+fn pending_migrations(conn: &Connection) -> Result<Vec<&'static Migration>, rusqlite::Error> {
+    let mut pending = Vec::new();
+    for migration in MIGRATIONS {
+        if !migration.is_applied(conn)? {
+            pending.push(migration);
         }
+    }
+    Ok(pending)
+}
+
+/// Delete ChangeLog entries that occurred before `before` (Unix seconds) from the accounts DB
+/// at `path`, returning how many were deleted.
+///
+/// Nodes read the ChangeLog every few seconds, so entries older than any node's refresh
+/// interval are no longer needed; a node that falls further behind does a full load when
+/// it restarts.
+pub fn compact_changelog(path: &Path, before: i64) -> Result<usize, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(sqlite::BUSY_TIMEOUT)?;
+    conn.execute(
+        "DELETE FROM ChangeLog WHERE occurred_at < datetime(?1, 'unixepoch')",
+        [before],
+    )
+}
 
-        if max_processed_id > last_change_id {
-            store.set_max_change_id(max_processed_id);
+/// Apply `pushed` to `store` again after a load, except the changes whose row the DB
+/// changed since they were pushed. Changes that no longer apply are dropped.
+fn reapply_pushed(
+    conn: &Connection,
+    store: &mut AccountStore,
+    pushed: Vec<Pushed>,
+) -> Result<(), rusqlite::Error> {
+    for pushed in pushed {
+        let (table, id_column, id) = pushed.change.row();
+        let updated_at: Option<Option<i64>> = conn
+            .prepare_cached(&format!(
+                "SELECT CAST(strftime('%s', updated_at) AS INTEGER) FROM {table} \
+                 WHERE {id_column} = ?1"
+            ))?
+            .query_row([id], |row| row.get(0))
+            .optional()?;
+        // A deleted key stays gone without the push
+        if updated_at.is_none() && matches!(pushed.change, PushedChange::RevokeKey { .. }) {
+            continue;
+        }
+        if updated_at
+            .flatten()
+            .is_some_and(|updated_at| updated_at > pushed.pushed_at)
+        {
             log::info!(
-                "Delta loaded {} inserts, {} updates, {} deletes (change_id: {} -> {})",
-                inserts,
-                updates,
-                deletes,
-                last_change_id,
-                max_processed_id
+                "Control plane push {} superseded by the accounts DB",
+                pushed.version
             );
+            continue;
+        }
+        let version = pushed.version;
+        if let Err(e) = store.apply_pushed(pushed) {
+            log::info!("Dropped control plane push {version}: {e}");
         }
-
-        Ok(())
     }
+    Ok(())
+}
 
-    /// Fetch a single plan by ID.
-    fn fetch_plan(&self, conn: &Connection, plan_id: i64) -> Result<Option<Plan>, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "SELECT plan_id, name, monthly_quota, rps_limit, price_per_1k_req FROM Plans WHERE plan_id = ?"
-        )?;
-        let mut rows = stmt.query([plan_id])?;
-        if let Some(row) = rows.next()? {
-            Ok(Some(Plan {
-                plan_id: row.get(0)?,
-                name: row.get(1)?,
-                monthly_quota: row.get(2)?,
-                rps_limit: row.get(3)?,
-                price_per_1k_req: row.get(4)?,
-            }))
-        } else {
-            Ok(None)
+/// Apply ChangeLog `entries` to `store`, fetching changed records through `conn`.
+///
+/// Changes are collapsed to the last operation per record and changed records are fetched
+/// in batches per table.
+fn apply_changes(
+    conn: &Connection,
+    store: &mut AccountStore,
+    entries: Vec<ChangeLogEntry>,
+) -> Result<(), rusqlite::Error> {
+    let last_change_id = store.max_change_id();
+
+    let mut plans = TableChanges::default();
+    let mut accounts = TableChanges::default();
+    let mut keys = TableChanges::default();
+    let mut routing = TableChanges::default();
+    let mut max_processed_id = last_change_id;
+
+    for entry in entries {
+        max_processed_id = entry.change_id;
+
+        match entry.table_name.as_str() {
+            "Plans" => plans.push(entry.record_id, &entry.operation),
+            "Accounts" => accounts.push(entry.record_id, &entry.operation),
+            "APIKeys" => keys.push(entry.record_id, &entry.operation),
+            "AccountRouting" => routing.push(entry.record_id, &entry.operation),
+            _ => {
+                log::warn!(
+                    "Unknown table in ChangeLog: {} (change_id={})",
+                    entry.table_name,
+                    entry.change_id
+                );
+            }
         }
     }
 
-    /// Fetch a single account by ID.
-    fn fetch_account(
-        &self,
-        conn: &Connection,
-        account_id: i64,
-    ) -> Result<Option<Account>, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "SELECT account_id, email, plan_id, billing_status FROM Accounts WHERE account_id = ?",
-        )?;
-        let mut rows = stmt.query([account_id])?;
-        if let Some(row) = rows.next()? {
-            Ok(Some(Account {
-                account_id: row.get(0)?,
-                email: row.get(1)?,
-                plan_id: row.get(2)?,
-                billing_status: row.get(3)?,
-            }))
+    let mut inserts = 0;
+    let mut updates = 0;
+    let deletes =
+        plans.deletes.len() + accounts.deletes.len() + keys.deletes.len() + routing.deletes.len();
+    let mut count = |inserted: bool| {
+        if inserted {
+            inserts += 1;
         } else {
-            Ok(None)
+            updates += 1;
         }
+    };
+
+    for plan_id in &plans.deletes {
+        store.delete_plan(*plan_id);
+    }
+    for plan in fetch_batched(
+        conn,
+        "Plans",
+        PLAN_COLUMNS,
+        "plan_id",
+        &plans.upsert_ids(),
+        plan_from_row,
+    )? {
+        count(plans.upserts[&plan.plan_id]);
+        store.upsert_plan(plan);
     }
 
-    /// Fetch a single API key by api_key_id.
-    fn fetch_api_key(
-        &self,
-        conn: &Connection,
-        api_key_id: i64,
-    ) -> Result<Option<ApiKey>, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "SELECT api_key_id, api_key, account_id, api_key_hash, is_active FROM APIKeys WHERE api_key_id = ?",
-        )?;
-        let mut rows = stmt.query([api_key_id])?;
-        if let Some(row) = rows.next()? {
-            let api_key_id: i64 = row.get(0)?;
-            let api_key_str: String = row.get(1)?;
-            let api_key = Uuid::parse_str(&api_key_str).map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    0,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?;
-            Ok(Some(ApiKey {
-                api_key_id,
-                api_key,
-                account_id: row.get(2)?,
-                api_key_hash: row.get(3)?,
-                is_active: row.get(4)?,
-            }))
-        } else {
-            Ok(None)
-        }
+    for account_id in &accounts.deletes {
+        store.delete_account(*account_id);
+    }
+    for account in fetch_batched(
+        conn,
+        "Accounts",
+        ACCOUNT_COLUMNS,
+        "account_id",
+        &accounts.upsert_ids(),
+        account_from_row,
+    )? {
+        count(accounts.upserts[&account.account_id]);
+        store.upsert_account(account);
+    }
+
+    for api_key_id in &keys.deletes {
+        store.delete_api_key(*api_key_id);
+    }
+    for api_key in fetch_batched(
+        conn,
+        "APIKeys",
+        API_KEY_COLUMNS,
+        "api_key_id",
+        &keys.upsert_ids(),
+        api_key_from_row,
+    )? {
+        count(keys.upserts[&api_key.api_key_id]);
+        store.upsert_api_key(api_key);
+    }
+
+    for account_id in &routing.deletes {
+        store.delete_account_routing(*account_id);
+    }
+    for account_routing in fetch_batched(
+        conn,
+        "AccountRouting",
+        ACCOUNT_ROUTING_COLUMNS,
+        "account_id",
+        &routing.upsert_ids(),
+        account_routing_from_row,
+    )? {
+        count(routing.upserts[&account_routing.account_id]);
+        store.upsert_account_routing(account_routing);
     }
+
+    let pushed = std::mem::take(&mut store.pushed);
+    reapply_pushed(conn, store, pushed)?;
+
+    if max_processed_id > last_change_id {
+        store.set_max_change_id(max_processed_id);
+        log::info!(
+            "Delta loaded {} inserts, {} updates, {} deletes (change_id: {} -> {})",
+            inserts,
+            updates,
+            deletes,
+            last_change_id,
+            max_processed_id
+        );
+    }
+
+    Ok(())
 }
 
 // ============================================================================
@@ -461,12 +1404,78 @@ impl AccountLoader {
 pub struct AccountDataService {
     loader: AccountLoader,
     store: Arc<RwLock<AccountStore>>,
+    alerts: Arc<AlertSink>,
+    /// Where to write a snapshot of the store on shutdown.
+    snapshot: Option<PathBuf>,
+    /// The store came from a snapshot or degraded start and needs a full load once the DB
+    /// is reachable. Shared with the limiter.
+    stale: Arc<AtomicBool>,
+    /// Marked once a full load succeeds after a degraded start.
+    readiness: Option<Arc<Readiness>>,
+    /// Where failing loads mark the accounts DB as degraded.
+    degradation: Option<Arc<Degradation>>,
 }
 
-impl AccountDataService {
-    /// Create a new background service.
-    pub fn new(loader: AccountLoader, store: Arc<RwLock<AccountStore>>) -> Self {
-        Self { loader, store }
+impl AccountDataService {
+    /// Create a new background service.
+    pub fn new(loader: AccountLoader, store: Arc<RwLock<AccountStore>>) -> Self {
+        Self {
+            loader,
+            store,
+            alerts: Arc::new(AlertSink::default()),
+            snapshot: None,
+            stale: Arc::new(AtomicBool::new(false)),
+            readiness: None,
+            degradation: None,
+        }
+    }
+
+    /// Complete the accounts startup phase once a full load succeeds.
+    pub fn with_readiness(mut self, readiness: Arc<Readiness>) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    /// Report load failures to `alerts`.
+    pub fn with_alerts(mut self, alerts: Arc<AlertSink>) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    /// Track whether the accounts DB loads in `degradation`.
+    pub fn with_degradation(mut self, degradation: Arc<Degradation>) -> Self {
+        self.degradation = Some(degradation);
+        self
+    }
+
+    /// Write a snapshot of the store to `path` on shutdown.
+    pub fn with_snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot = Some(path.into());
+        self
+    }
+
+    /// Write the current store to the configured snapshot path, if any.
+    fn save_snapshot(&self) {
+        if let Some(path) = &self.snapshot
+            && let Err(e) = self.store.read_or_recover().save_snapshot(path)
+        {
+            self.alerts.warning(
+                "accounts",
+                format!("Failed to write account snapshot {path:?}: {e}"),
+            );
+        }
+    }
+
+    /// Replace snapshot data with a full load from the DB.
+    fn load_full(&self) -> Result<(), rusqlite::Error> {
+        self.loader.reload(&self.store)?;
+        self.stale.store(false, Ordering::Relaxed);
+        log::info!("Accounts DB reachable again, replaced snapshot or fallback data");
+        if let Some(readiness) = &self.readiness {
+            readiness.complete(Phase::Accounts);
+        }
+        self.save_snapshot();
+        Ok(())
     }
 }
 
@@ -482,6 +1491,10 @@ impl BackgroundService for AccountDataService {
             // Wait for 30 seconds or shutdown
             tokio::select! {
                 _ = shutdown.changed() => {
+                    // Snapshot data is no newer than the file it came from
+                    if !self.stale.load(Ordering::Relaxed) {
+                        self.save_snapshot();
+                    }
                     return;
                 }
                 _ = tokio::time::sleep(Duration::from_secs(30)) => {
@@ -489,10 +1502,25 @@ impl BackgroundService for AccountDataService {
                 }
             }
 
-            // Perform delta load
-            let mut store = self.store.write().unwrap();
-            if let Err(e) = self.loader.load_delta(&mut store) {
-                log::error!("Failed to load account data: {}", e);
+            // Perform delta load, or a full load while serving from a snapshot
+            let result = if self.stale.load(Ordering::Relaxed) {
+                self.load_full()
+            } else {
+                self.loader.refresh(&self.store).map(|_| ())
+            };
+            match result {
+                Ok(()) => {
+                    if let Some(degradation) = &self.degradation {
+                        degradation.recover(Dependency::AccountsDb);
+                    }
+                }
+                Err(e) => {
+                    self.alerts
+                        .critical("accounts", format!("Failed to load account data: {e}"));
+                    if let Some(degradation) = &self.degradation {
+                        degradation.enter(Dependency::AccountsDb, e.to_string());
+                    }
+                }
             }
         }
     }
@@ -514,15 +1542,165 @@ pub fn hash_api_key(api_key: &str) -> String {
     hex::encode(result)
 }
 
+/// Split a comma-separated scope list, ignoring blanks.
+pub fn parse_scopes(scopes: &str) -> Vec<String> {
+    scopes
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Mask an email for display, keeping the first character and the domain.
+pub fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let first: String = local.chars().take(1).collect();
+            format!("{first}***@{domain}")
+        }
+        None => "***".to_string(),
+    }
+}
+
+/// Read a key hash allowlist: one hex hash per line, blank lines and `#` comments ignored.
+pub fn load_allowlist(path: &Path) -> io::Result<HashSet<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
+
+/// Static limits for keys without account data while the accounts DB is unavailable.
+#[derive(Debug, Clone)]
+pub struct FallbackLimits {
+    pub rps_limit: isize,
+    /// Key hashes that get `rps_limit`; every key does when `None`.
+    pub allowlist: Option<HashSet<String>>,
+}
+
+impl FallbackLimits {
+    fn allows(&self, api_key_hash: &str) -> bool {
+        self.allowlist
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.contains(api_key_hash))
+    }
+}
+
+/// Default of [`ReadThrough::with_max_lookups_per_sec`].
+pub const DEFAULT_READ_THROUGH_LOOKUPS_PER_SEC: u32 = 100;
+
+/// Synchronous DB lookups for keys missing from memory, so keys provisioned since the last
+/// refresh work immediately.
+///
+/// Only versioned tokens are looked up, by the id they carry; anything else, legacy keys
+/// included, waits for the next refresh. Misses are remembered for `negative_ttl` in a
+/// cache of at most `max_negative` entries, and at most `max_lookups_per_sec` queries run
+/// per second across all keys of the DB, so a stream of unknown tokens cannot tie up the DB or the
+/// request path.
+pub struct ReadThrough {
+    loader: AccountLoader,
+    negative_ttl: Duration,
+    max_negative: usize,
+    /// SHA-256 of the raw key -> when the miss expires
+    negative: Mutex<HashMap<String, Instant>>,
+    max_lookups_per_sec: u32,
+    /// Start of the current second and the lookups made in it
+    lookups: Mutex<(Instant, u32)>,
+}
+
+impl ReadThrough {
+    /// Read through to the accounts DB at `db_path`, on a connection of its own.
+    pub fn new<P: AsRef<Path>>(db_path: P, negative_ttl: Duration, max_negative: usize) -> Self {
+        Self {
+            loader: AccountLoader::new(db_path),
+            negative_ttl,
+            max_negative,
+            negative: Mutex::new(HashMap::new()),
+            max_lookups_per_sec: DEFAULT_READ_THROUGH_LOOKUPS_PER_SEC,
+            lookups: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Run at most `max` lookups per second; keys beyond that wait for the next refresh.
+    pub fn with_max_lookups_per_sec(mut self, max: u32) -> Self {
+        self.max_lookups_per_sec = max;
+        self
+    }
+
+    /// Take a lookup from this second's budget, if any is left.
+    fn take_lookup(&self, now: Instant) -> bool {
+        let mut lookups = self.lookups.lock_or_recover();
+        if now.duration_since(lookups.0) >= Duration::from_secs(1) {
+            *lookups = (now, 0);
+        }
+        if lookups.1 >= self.max_lookups_per_sec {
+            return false;
+        }
+        lookups.1 += 1;
+        true
+    }
+
+    /// Whether `key` missed recently.
+    fn recently_missed(&self, key: &str, now: Instant) -> bool {
+        self.negative
+            .lock_or_recover()
+            .get(key)
+            .is_some_and(|expires| *expires > now)
+    }
+
+    fn remember_miss(&self, key: String, now: Instant) {
+        let mut negative = self.negative.lock_or_recover();
+        if negative.len() >= self.max_negative {
+            negative.retain(|_, expires| *expires > now);
+            if negative.len() >= self.max_negative {
+                negative.clear();
+            }
+        }
+        negative.insert(key, now + self.negative_ttl);
+    }
+}
+
 /// Rate limiter that uses account data from SQLite.
 pub struct AccountRatelimit {
     store: Arc<RwLock<AccountStore>>,
+    /// Limits for unknown keys while degraded.
+    fallback: Option<FallbackLimits>,
+    /// Set while the store holds snapshot data or nothing at all; cleared by the refresh
+    /// service once a full load succeeds.
+    degraded: Arc<AtomicBool>,
+    /// Independent account populations, selected by token prefix.
+    partitions: Vec<(String, AccountRatelimit)>,
+    /// DB lookups for keys missing from the store, when enabled.
+    read_through: Option<ReadThrough>,
 }
 
 impl AccountRatelimit {
     /// Create a new rate limiter with the given store.
     pub fn new(store: Arc<RwLock<AccountStore>>) -> Self {
-        Self { store }
+        Self {
+            store,
+            fallback: None,
+            degraded: Arc::new(AtomicBool::new(false)),
+            partitions: Vec::new(),
+            read_through: None,
+        }
+    }
+
+    /// Pair a limiter with its refresh service, sharing the degraded flag.
+    fn with_service(
+        loader: AccountLoader,
+        store: AccountStore,
+        degraded: bool,
+    ) -> (Self, AccountDataService) {
+        let store = Arc::new(RwLock::new(store));
+        let mut service = AccountDataService::new(loader, store.clone());
+        let limiter = Self::new(store);
+        limiter.degraded.store(degraded, Ordering::Relaxed);
+        service.stale = limiter.degraded.clone();
+        (limiter, service)
     }
 
     /// Create and initialize a rate limiter from a database path.
@@ -531,32 +1709,259 @@ impl AccountRatelimit {
         db_path: P,
     ) -> Result<(Self, AccountDataService), rusqlite::Error> {
         let loader = AccountLoader::new(&db_path);
-        let store = Arc::new(RwLock::new(loader.load_initial()?));
-        let service = AccountDataService::new(AccountLoader::new(&db_path), store.clone());
-        Ok((Self::new(store), service))
+        let store = loader.load_initial()?;
+        Ok(Self::with_service(loader, store, false))
+    }
+
+    /// Like [`AccountRatelimit::from_db`], but falls back to the snapshot at `snapshot` when
+    /// the database cannot be loaded, so traffic is served with slightly stale limits during
+    /// a control-plane outage. The returned service writes the snapshot on shutdown and
+    /// replaces snapshot data with a full load once the database is reachable.
+    pub fn from_db_with_snapshot<P: AsRef<Path>>(
+        db_path: P,
+        snapshot: &Path,
+    ) -> Result<(Self, AccountDataService), rusqlite::Error> {
+        let loader = AccountLoader::new(&db_path);
+        let (store, stale) = match loader.load_initial() {
+            Ok(store) => {
+                // Keep a snapshot even if the process never shuts down cleanly
+                if let Err(e) = store.save_snapshot(snapshot) {
+                    log::warn!("Failed to write account snapshot {snapshot:?}: {e}");
+                }
+                (store, false)
+            }
+            Err(e) => match AccountStore::load_snapshot(snapshot) {
+                Ok(store) => {
+                    log::warn!(
+                        "Failed to load accounts DB ({e}), serving from snapshot {snapshot:?}"
+                    );
+                    (store, true)
+                }
+                Err(snapshot_err) => {
+                    log::error!("Failed to read account snapshot {snapshot:?}: {snapshot_err}");
+                    return Err(e);
+                }
+            },
+        };
+        let (limiter, service) = Self::with_service(loader, store, stale);
+        Ok((limiter, service.with_snapshot(snapshot)))
+    }
+
+    /// Limiter for a separate accounts DB whose versioned tokens use `token_prefix`, to be
+    /// attached to the primary limiter with [`AccountRatelimit::with_partition`].
+    pub fn partition<P: AsRef<Path>>(
+        db_path: P,
+        token_prefix: &str,
+    ) -> Result<(Self, AccountDataService), rusqlite::Error> {
+        let loader = AccountLoader::new(&db_path).with_token_prefix(token_prefix);
+        let store = loader.load_initial()?;
+        Ok(Self::with_service(loader, store, false))
+    }
+
+    /// Serve keys and fingerprints starting with `<prefix>_` from `partition`.
+    ///
+    /// Everything else, including legacy keys, which carry no prefix, is served by this
+    /// limiter.
+    pub fn with_partition(
+        mut self,
+        prefix: impl Into<String>,
+        partition: AccountRatelimit,
+    ) -> Self {
+        self.partitions.push((prefix.into(), partition));
+        self
+    }
+
+    /// Look keys missing from the store up in the DB. See [`ReadThrough`].
+    pub fn with_read_through(mut self, read_through: ReadThrough) -> Self {
+        self.read_through = Some(read_through);
+        self
+    }
+
+    /// Make sure a key provisioned since the last refresh is in the store.
+    ///
+    /// Does nothing unless read-through is enabled for the key's partition or the key is
+    /// already known. Returns whether the key was added.
+    pub fn read_through(&self, api_key: &str) -> bool {
+        let limiter = self.route(api_key);
+        let Some(read_through) = &limiter.read_through else {
+            return false;
+        };
+        let (known, prefix) = {
+            let store = limiter.store.read_or_recover();
+            let known = store
+                .api_key_details
+                .contains_key(&store.resolve_key(api_key));
+            (known, store.token_prefix().to_string())
+        };
+        let now = Instant::now();
+        let miss_key = hash_api_key(api_key);
+        if known || read_through.recently_missed(&miss_key, now) {
+            return false;
+        }
+
+        let Ok(parsed) = api_key::parse(api_key, &prefix) else {
+            return false;
+        };
+        if !read_through.take_lookup(now) {
+            log::debug!("Read-through lookup budget exhausted, skipping the lookup");
+            return false;
+        }
+
+        let lookup = read_through
+            .loader
+            .fetch_key("api_key", &parsed.id().to_string());
+        let found = match lookup {
+            Ok(Some((key, account, plan))) => {
+                let mut store = limiter.store.write_or_recover();
+                if let Some(plan) = plan {
+                    store.upsert_plan(plan);
+                }
+                store.upsert_account(account);
+                store.upsert_api_key(key);
+                // A token whose secret does not match is still unknown
+                store
+                    .api_key_details
+                    .contains_key(&store.resolve_key(api_key))
+            }
+            Ok(None) => false,
+            Err(e) => {
+                log::warn!("Read-through key lookup failed: {e}");
+                false
+            }
+        };
+        if !found {
+            read_through.remember_miss(miss_key, now);
+        }
+        found
+    }
+
+    /// Limiter responsible for a raw key or fingerprint.
+    fn route(&self, key: &str) -> &AccountRatelimit {
+        self.partitions
+            .iter()
+            .find(|(prefix, _)| {
+                key.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with('_'))
+            })
+            .map_or(self, |(_, partition)| partition)
+    }
+
+    /// Start without account data. Every key is unknown until the returned service manages a
+    /// full load of `db_path`; combine with [`AccountRatelimit::with_fallback`].
+    pub fn degraded<P: AsRef<Path>>(db_path: P) -> (Self, AccountDataService) {
+        Self::with_service(AccountLoader::new(&db_path), AccountStore::new(), true)
+    }
+
+    /// Limits for keys without account data while degraded.
+    pub fn with_fallback(mut self, fallback: FallbackLimits) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Whether limits currently come from a snapshot or the fallback rather than the DB.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Shared store backing this limiter, for full reloads.
+    pub fn store(&self) -> Arc<RwLock<AccountStore>> {
+        self.store.clone()
+    }
+
+    /// Stores of the partitions, by token prefix.
+    pub fn partition_stores(&self) -> Vec<(String, Arc<RwLock<AccountStore>>)> {
+        self.partitions
+            .iter()
+            .map(|(prefix, partition)| (prefix.clone(), partition.store()))
+            .collect()
+    }
+
+    /// Hash under which a raw API key is stored. See [`AccountStore::resolve_key`].
+    pub fn key_hash(&self, api_key: &str) -> String {
+        self.route(api_key)
+            .store
+            .read_or_recover()
+            .resolve_key(api_key)
+    }
+
+    /// Usage context for a raw API key: (account_id, api_key_id, plan_id).
+    pub fn key_context(&self, api_key: &str) -> Option<(i64, Uuid, i64)> {
+        let store = self.route(api_key).store.read_or_recover();
+        store.get_key_context(&store.resolve_key(api_key))
     }
 
     /// Get the full context for a given API key hash: (account_id, api_key_id, plan_id).
     /// Used for usage tracking.
     pub fn get_key_context(&self, api_key_hash: &str) -> Option<(i64, Uuid, i64)> {
-        let store = self.store.read().unwrap();
+        let store = self.store.read_or_recover();
         store.get_key_context(api_key_hash)
     }
+
+    /// Get the plan for a raw API key, if the key is known.
+    pub fn plan_for_key(&self, api_key: &str) -> Option<Plan> {
+        let store = self.route(api_key).store.read_or_recover();
+        let api_key_hash = store.resolve_key(api_key);
+        store.get_plan_for_key(&api_key_hash).cloned()
+    }
+
+    /// Dedicated backend pool for a raw API key, if its account has one.
+    pub fn pool_for_key(&self, api_key: &str) -> Option<String> {
+        let store = self.route(api_key).store.read_or_recover();
+        let api_key_hash = store.resolve_key(api_key);
+        store.get_pool_for_key(&api_key_hash).map(str::to_string)
+    }
+
+    /// Fingerprint of a raw API key. See [`AccountStore::fingerprint`].
+    pub fn fingerprint(&self, api_key: &str) -> String {
+        self.route(api_key)
+            .store
+            .read_or_recover()
+            .fingerprint(api_key)
+    }
+
+    /// Self-service metadata for the active key with the given fingerprint.
+    pub fn metadata_for_fingerprint(&self, fingerprint: &str) -> Option<KeyMetadata> {
+        let store = self.route(fingerprint).store.read_or_recover();
+        store.key_metadata(store.hash_for_fingerprint(fingerprint)?)
+    }
+
+    /// Get self-service metadata for a raw API key, if the key is known and active.
+    pub fn key_metadata(&self, api_key: &str) -> Option<KeyMetadata> {
+        let store = self.route(api_key).store.read_or_recover();
+        let api_key_hash = store.resolve_key(api_key);
+        store.key_metadata(&api_key_hash)
+    }
 }
 
 impl Ratelimit for AccountRatelimit {
     fn limit_for_key(&self, api_key: &str) -> Limit {
-        let api_key_hash = hash_api_key(api_key);
-        let store = self.store.read().unwrap();
+        let partition = self.route(api_key);
+        if !std::ptr::eq(partition, self) {
+            return partition.limit_for_key(api_key);
+        }
+        let store = self.store.read_or_recover();
+        let api_key_hash = store.resolve_key(api_key);
 
         match store.get_plan_for_key(&api_key_hash) {
             Some(plan) => Limit {
                 quota: plan.rps_limit as isize,
                 per_seconds: DEFAULT_WINDOW_SECS,
+                burst: plan.burst_policy(),
+                source: LimitSource::Plan,
             },
-            None => Limit {
-                quota: DEFAULT_RPS_LIMIT,
-                per_seconds: DEFAULT_WINDOW_SECS,
+            None => match &self.fallback {
+                Some(fallback) if self.is_degraded() && fallback.allows(&api_key_hash) => Limit {
+                    quota: fallback.rps_limit,
+                    per_seconds: DEFAULT_WINDOW_SECS,
+                    burst: None,
+                    source: LimitSource::Override,
+                },
+                _ => Limit {
+                    quota: DEFAULT_RPS_LIMIT,
+                    per_seconds: DEFAULT_WINDOW_SECS,
+                    burst: None,
+                    source: LimitSource::Default,
+                },
             },
         }
     }
@@ -573,76 +1978,13 @@ mod tests {
 
     fn create_test_db() -> NamedTempFile {
         let file = NamedTempFile::new().unwrap();
-        let conn = Connection::open(file.path()).unwrap();
+        let conn = sqlite::open_wal(file.path()).unwrap();
 
+        conn.execute_batch(include_str!("../sql/accounts.sql"))
+            .unwrap();
         conn.execute_batch(
             r#"
-            CREATE TABLE Plans (
-                plan_id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL,
-                monthly_quota INTEGER NOT NULL,
-                rps_limit INTEGER NOT NULL,
-                price_per_1k_req REAL NOT NULL,
-                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            );
-            CREATE TABLE Accounts (
-                account_id INTEGER PRIMARY KEY AUTOINCREMENT,
-                email TEXT UNIQUE NOT NULL,
-                plan_id INTEGER NOT NULL,
-                billing_status TEXT NOT NULL,
-                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (plan_id) REFERENCES Plans(plan_id)
-            );
-            CREATE TABLE APIKeys (
-                api_key_id INTEGER PRIMARY KEY AUTOINCREMENT,
-                api_key CHAR(36) UNIQUE NOT NULL,
-                account_id INTEGER NOT NULL,
-                api_key_hash TEXT UNIQUE NOT NULL,
-                is_active BOOLEAN NOT NULL DEFAULT 1,
-                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (account_id) REFERENCES Accounts(account_id)
-            );
-            CREATE TABLE ChangeLog (
-                change_id INTEGER PRIMARY KEY AUTOINCREMENT,
-                table_name TEXT NOT NULL,
-                record_id INTEGER NOT NULL,
-                operation TEXT NOT NULL,
-                occurred_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            );
-
-            -- Plans triggers
-            CREATE TRIGGER trg_plans_insert AFTER INSERT ON Plans BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Plans', NEW.plan_id, 'INSERT');
-            END;
-            CREATE TRIGGER trg_plans_update AFTER UPDATE ON Plans BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Plans', NEW.plan_id, 'UPDATE');
-            END;
-            CREATE TRIGGER trg_plans_delete AFTER DELETE ON Plans BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Plans', OLD.plan_id, 'DELETE');
-            END;
-
-            -- Accounts triggers
-            CREATE TRIGGER trg_accounts_insert AFTER INSERT ON Accounts BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Accounts', NEW.account_id, 'INSERT');
-            END;
-            CREATE TRIGGER trg_accounts_update AFTER UPDATE ON Accounts BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Accounts', NEW.account_id, 'UPDATE');
-            END;
-            CREATE TRIGGER trg_accounts_delete AFTER DELETE ON Accounts BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Accounts', OLD.account_id, 'DELETE');
-            END;
-
-            -- APIKeys triggers
-            CREATE TRIGGER trg_apikeys_insert AFTER INSERT ON APIKeys BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', NEW.api_key_id, 'INSERT');
-            END;
-            CREATE TRIGGER trg_apikeys_update AFTER UPDATE ON APIKeys BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', NEW.api_key_id, 'UPDATE');
-            END;
-            CREATE TRIGGER trg_apikeys_delete AFTER DELETE ON APIKeys BEGIN
-                INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', OLD.api_key_id, 'DELETE');
-            END;
-
+            BEGIN;
             INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req)
             VALUES ('Free', 1000, 5, 0.0);
             INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req)
@@ -655,10 +1997,11 @@ mod tests {
 
             INSERT INTO APIKeys (api_key, account_id, api_key_hash, is_active)
             VALUES ('00000000-0000-0000-0000-000000000001', 1, 'hash_free_key', 1);
-            INSERT INTO APIKeys (api_key, account_id, api_key_hash, is_active)
-            VALUES ('00000000-0000-0000-0000-000000000002', 2, 'hash_pro_key', 1);
+            INSERT INTO APIKeys (api_key, account_id, api_key_hash, is_active, scopes)
+            VALUES ('00000000-0000-0000-0000-000000000002', 2, 'hash_pro_key', 1, 'read, write');
             INSERT INTO APIKeys (api_key, account_id, api_key_hash, is_active)
             VALUES ('00000000-0000-0000-0000-000000000003', 1, 'hash_inactive_key', 0);
+            COMMIT;
             "#,
         )
         .unwrap();
@@ -666,6 +2009,52 @@ mod tests {
         file
     }
 
+    #[test]
+    fn test_baseline_schema_is_migrated() {
+        let file = NamedTempFile::new().unwrap();
+        let conn = sqlite::open_wal(file.path()).unwrap();
+        conn.execute_batch(include_str!("../test_data/accounts-baseline.sql"))
+            .unwrap();
+        conn.execute_batch(
+            r#"
+            INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req)
+            VALUES ('Free', 1000, 5, 0.0);
+            INSERT INTO Accounts (email, plan_id, billing_status)
+            VALUES ('free@example.com', 1, 'active');
+            INSERT INTO APIKeys (api_key, account_id, api_key_hash, is_active)
+            VALUES ('00000000-0000-0000-0000-000000000001', 1, 'hash_free_key', 1);
+            "#,
+        )
+        .unwrap();
+        drop(conn);
+
+        // Loading leaves the schema alone
+        let loader = AccountLoader::new(file.path());
+        assert!(loader.load_initial().is_err());
+        let conn = Connection::open(file.path()).unwrap();
+        assert_eq!(pending_migrations(&conn).unwrap().len(), MIGRATIONS.len());
+
+        assert_eq!(migrate_schema(file.path()).unwrap(), MIGRATIONS.len());
+        assert!(pending_migrations(&conn).unwrap().is_empty());
+        let mut store = loader.load_initial().unwrap();
+        let plan = store.get_plan_for_key("hash_free_key").unwrap();
+        assert_eq!((plan.burst_cap, plan.priority), (0, 0));
+        assert_eq!(store.residency(1), None);
+        let metadata = store.key_metadata("hash_free_key").unwrap();
+        assert!(metadata.scopes.is_empty());
+        assert_eq!(metadata.created_at, None);
+
+        // Migrating again changes nothing, and AccountRouting changes reach the store
+        assert_eq!(migrate_schema(file.path()).unwrap(), 0);
+        conn.execute(
+            "INSERT INTO AccountRouting (account_id, pool) VALUES (1, 'dedicated')",
+            [],
+        )
+        .unwrap();
+        loader.load_delta(&mut store).unwrap();
+        assert_eq!(store.get_pool_for_key("hash_free_key"), Some("dedicated"));
+    }
+
     #[test]
     fn test_account_store_lookup() {
         let mut store = AccountStore::new();
@@ -676,6 +2065,9 @@ mod tests {
             monthly_quota: 1000,
             rps_limit: 5,
             price_per_1k_req: 0.0,
+            burst_cap: 0,
+            accrual_rate: 0.0,
+            priority: 0,
         });
 
         store.upsert_account(Account {
@@ -683,6 +2075,7 @@ mod tests {
             email: "test@example.com".to_string(),
             plan_id: 1,
             billing_status: "active".to_string(),
+            residency: None,
         });
 
         store.upsert_api_key(ApiKey {
@@ -690,7 +2083,12 @@ mod tests {
             api_key: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
             account_id: 1,
             api_key_hash: "test_hash".to_string(),
+            version: 0,
             is_active: true,
+            created_at: None,
+            last_used_at: None,
+            scopes: Vec::new(),
+            plan_bound: false,
         });
 
         let plan = store.get_plan_for_key("test_hash").unwrap();
@@ -708,6 +2106,9 @@ mod tests {
             monthly_quota: 1000,
             rps_limit: 5,
             price_per_1k_req: 0.0,
+            burst_cap: 0,
+            accrual_rate: 0.0,
+            priority: 0,
         });
 
         store.upsert_account(Account {
@@ -715,6 +2116,7 @@ mod tests {
             email: "test@example.com".to_string(),
             plan_id: 1,
             billing_status: "active".to_string(),
+            residency: None,
         });
 
         store.upsert_api_key(ApiKey {
@@ -722,7 +2124,12 @@ mod tests {
             api_key: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
             account_id: 1,
             api_key_hash: "inactive_hash".to_string(),
+            version: 0,
             is_active: false,
+            created_at: None,
+            last_used_at: None,
+            scopes: Vec::new(),
+            plan_bound: false,
         });
 
         assert!(store.get_plan_for_key("inactive_hash").is_none());
@@ -748,6 +2155,27 @@ mod tests {
         assert_eq!(pro_plan.rps_limit, 100);
     }
 
+    #[test]
+    fn test_key_metadata() {
+        let db = create_test_db();
+        let store = AccountLoader::new(db.path()).load_initial().unwrap();
+
+        let meta = store.key_metadata("hash_pro_key").unwrap();
+        assert_eq!(meta.email, "pro@example.com");
+        assert_eq!(meta.plan_name.as_deref(), Some("Pro"));
+        assert!(meta.created_at.is_some());
+        assert!(meta.last_used_at.is_none());
+        assert_eq!(meta.scopes, vec!["read", "write"]);
+
+        assert!(store.key_metadata("hash_inactive_key").is_none());
+    }
+
+    #[test]
+    fn test_mask_email() {
+        assert_eq!(mask_email("alice@example.com"), "a***@example.com");
+        assert_eq!(mask_email("not-an-email"), "***");
+    }
+
     #[test]
     fn test_delta_loading() {
         let db = create_test_db();
@@ -784,6 +2212,337 @@ mod tests {
         assert_eq!(enterprise_plan.rps_limit, 1000);
     }
 
+    #[test]
+    fn test_account_routing_is_loaded_and_refreshed() {
+        let db = create_test_db();
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute(
+            "INSERT INTO AccountRouting (account_id, pool) VALUES (2, 'acme')",
+            [],
+        )
+        .unwrap();
+        let loader = AccountLoader::new(db.path());
+        let mut store = loader.load_initial().unwrap();
+        assert_eq!(store.get_pool_for_key("hash_pro_key"), Some("acme"));
+        assert_eq!(store.get_pool_for_key("hash_free_key"), None);
+
+        conn.execute_batch(
+            r#"
+            UPDATE AccountRouting SET pool = 'acme-eu' WHERE account_id = 2;
+            INSERT INTO AccountRouting (account_id, pool) VALUES (1, 'trial');
+            DELETE FROM AccountRouting WHERE account_id = 1;
+            "#,
+        )
+        .unwrap();
+        loader.load_delta(&mut store).unwrap();
+        assert_eq!(store.get_pool_for_key("hash_pro_key"), Some("acme-eu"));
+        assert_eq!(store.get_pool_for_key("hash_free_key"), None);
+
+        let snapshot = NamedTempFile::new().unwrap();
+        store.save_snapshot(snapshot.path()).unwrap();
+        let restored = AccountStore::load_snapshot(snapshot.path()).unwrap();
+        assert_eq!(restored.get_pool_for_key("hash_pro_key"), Some("acme-eu"));
+    }
+
+    #[test]
+    fn test_account_residency_is_loaded_and_refreshed() {
+        let db = create_test_db();
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute(
+            "UPDATE Accounts SET residency = 'eu' WHERE account_id = 2",
+            [],
+        )
+        .unwrap();
+        let loader = AccountLoader::new(db.path());
+        let mut store = loader.load_initial().unwrap();
+        assert_eq!(store.residency(2), Some("eu"));
+        assert_eq!(store.residency(1), None);
+
+        conn.execute_batch(
+            "UPDATE Accounts SET residency = 'us' WHERE account_id = 1;
+             UPDATE Accounts SET residency = NULL WHERE account_id = 2;",
+        )
+        .unwrap();
+        loader.load_delta(&mut store).unwrap();
+        assert_eq!(store.residency(1), Some("us"));
+        assert_eq!(store.residency(2), None);
+
+        let snapshot = NamedTempFile::new().unwrap();
+        store.save_snapshot(snapshot.path()).unwrap();
+        let restored = AccountStore::load_snapshot(snapshot.path()).unwrap();
+        assert_eq!(restored.residency(1), Some("us"));
+    }
+
+    #[test]
+    fn test_delta_loading_batches_and_collapses_changes() {
+        let db = create_test_db();
+        let loader = AccountLoader::new(db.path());
+        let mut store = loader.load_initial().unwrap();
+
+        // More keys than fit in one batch, plus a key created and deleted within the delta
+        let conn = Connection::open(db.path()).unwrap();
+        for i in 0..(FETCH_BATCH + 50) {
+            conn.execute(
+                "INSERT INTO APIKeys (api_key, account_id, api_key_hash) VALUES (?, 1, ?)",
+                rusqlite::params![
+                    Uuid::from_u128(1000 + i as u128).to_string(),
+                    format!("bulk_{i}")
+                ],
+            )
+            .unwrap();
+        }
+        conn.execute_batch(
+            r#"
+            UPDATE APIKeys SET scopes = 'read' WHERE api_key_hash = 'bulk_0';
+            DELETE FROM APIKeys WHERE api_key_hash = 'bulk_1';
+            UPDATE Plans SET rps_limit = 7 WHERE plan_id = 1;
+            "#,
+        )
+        .unwrap();
+
+        loader.load_delta(&mut store).unwrap();
+
+        // 2 initial active keys + bulk keys minus the deleted one
+        assert_eq!(store.api_key_to_account.len(), 2 + FETCH_BATCH + 50 - 1);
+        assert!(store.get_plan_for_key("bulk_1").is_none());
+        assert_eq!(store.get_plan_for_key("bulk_0").unwrap().rps_limit, 7);
+        assert_eq!(store.api_key_details["bulk_0"].scopes, vec!["read"]);
+
+        // The connection is reused for the next delta
+        assert!(loader.conn.lock().unwrap().is_some());
+        loader.load_delta(&mut store).unwrap();
+    }
+
+    #[test]
+    fn test_refresh_swaps_in_updated_store() {
+        let db = create_test_db();
+        let loader = AccountLoader::new(db.path());
+        let store = RwLock::new(loader.load_initial().unwrap());
+
+        // No changes: nothing to swap
+        assert!(!loader.refresh(&store).unwrap());
+
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute("UPDATE Plans SET rps_limit = 9 WHERE plan_id = 2", [])
+            .unwrap();
+        assert!(loader.refresh(&store).unwrap());
+        let current = store.read().unwrap();
+        assert_eq!(
+            current.get_plan_for_key("hash_pro_key").unwrap().rps_limit,
+            9
+        );
+        assert_eq!(current.max_change_id(), 8);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let db = create_test_db();
+        let store = AccountLoader::new(db.path()).load_initial().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("accounts.snapshot");
+
+        store.save_snapshot(&path).unwrap();
+        let restored = AccountStore::load_snapshot(&path).unwrap();
+
+        assert_eq!(restored.counts(), store.counts());
+        assert_eq!(restored.max_change_id(), store.max_change_id());
+        assert_eq!(
+            restored.get_plan_for_key("hash_pro_key").unwrap().name,
+            "Pro"
+        );
+        let meta = restored.key_metadata("hash_pro_key").unwrap();
+        assert_eq!(meta.email, "pro@example.com");
+        assert_eq!(meta.scopes, vec!["read", "write"]);
+    }
+
+    #[test]
+    fn test_snapshot_used_when_db_unavailable() {
+        let db = create_test_db();
+        let dir = tempfile::TempDir::new().unwrap();
+        let snapshot = dir.path().join("accounts.snapshot");
+
+        // A successful load writes the snapshot
+        let (limiter, _) = AccountRatelimit::from_db_with_snapshot(db.path(), &snapshot).unwrap();
+        assert_eq!(limiter.get_key_context("hash_pro_key").unwrap().0, 2);
+        assert!(snapshot.exists());
+
+        let missing = dir.path().join("missing.db");
+        let (limiter, service) =
+            AccountRatelimit::from_db_with_snapshot(&missing, &snapshot).unwrap();
+        assert_eq!(limiter.get_key_context("hash_pro_key").unwrap().0, 2);
+        assert!(service.stale.load(Ordering::Relaxed));
+
+        // Without a snapshot the DB error is returned
+        std::fs::remove_file(&snapshot).unwrap();
+        assert!(AccountRatelimit::from_db_with_snapshot(&missing, &snapshot).is_err());
+    }
+
+    #[test]
+    fn test_degraded_mode_uses_fallback_limits() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let allowlist = dir.path().join("allowlist");
+        std::fs::write(
+            &allowlist,
+            format!(
+                "# known keys\n\n{}\n",
+                hash_api_key("allowed").to_uppercase()
+            ),
+        )
+        .unwrap();
+
+        let (limiter, service) = AccountRatelimit::degraded(dir.path().join("missing.db"));
+        let limiter = limiter.with_fallback(FallbackLimits {
+            rps_limit: 20,
+            allowlist: Some(load_allowlist(&allowlist).unwrap()),
+        });
+        assert!(limiter.is_degraded());
+        assert_eq!(limiter.limit_for_key("allowed").quota, 20);
+        assert_eq!(
+            limiter.limit_for_key("allowed").source,
+            LimitSource::Override
+        );
+        assert_eq!(limiter.limit_for_key("other").quota, DEFAULT_RPS_LIMIT);
+        assert_eq!(limiter.limit_for_key("other").source, LimitSource::Default);
+
+        // Once the DB loads the fallback no longer applies
+        let db = create_test_db();
+        *service.loader.conn.lock().unwrap() = Some(Connection::open(db.path()).unwrap());
+        service.load_full().unwrap();
+        assert!(!limiter.is_degraded());
+        assert_eq!(limiter.limit_for_key("allowed").quota, DEFAULT_RPS_LIMIT);
+    }
+
+    #[test]
+    fn test_partitions_are_selected_by_token_prefix() {
+        let primary_db = create_test_db();
+        let eu_db = create_test_db();
+        let (eu_token, eu_data) =
+            api_key::generate_with_data(&api_key::ApiKeyConfig::for_account("eu", 1));
+        Connection::open(eu_db.path())
+            .unwrap()
+            .execute(
+                "INSERT INTO APIKeys (api_key, account_id, api_key_hash, version) VALUES (?, 1, ?, 1)",
+                rusqlite::params![eu_data.id.to_string(), hex::encode(eu_data.secret_hash)],
+            )
+            .unwrap();
+
+        let (primary, _) = AccountRatelimit::from_db(primary_db.path()).unwrap();
+        let (eu, _) = AccountRatelimit::partition(eu_db.path(), "eu").unwrap();
+        let limiter = primary.with_partition("eu", eu);
+
+        // The EU token resolves in the EU store only
+        assert_eq!(limiter.limit_for_key(&eu_token.token).quota, 5);
+        assert_eq!(limiter.key_context(&eu_token.token).unwrap().1, eu_token.id);
+        assert_eq!(limiter.fingerprint(&eu_token.token), eu_token.fingerprint());
+        assert!(
+            limiter
+                .metadata_for_fingerprint(&eu_token.fingerprint())
+                .is_some()
+        );
+        assert!(
+            limiter
+                .store()
+                .read()
+                .unwrap()
+                .key_metadata(&hex::encode(eu_data.secret_hash))
+                .is_none()
+        );
+
+        // Keys without a partition prefix go to the primary store
+        assert!(limiter.key_context("legacy-key").is_none());
+        assert_eq!(
+            limiter
+                .route("europe_key")
+                .store
+                .read()
+                .unwrap()
+                .token_prefix(),
+            API_KEY_PREFIX
+        );
+    }
+
+    #[test]
+    fn test_read_through_finds_new_keys() {
+        let db = create_test_db();
+        let (limiter, _) = AccountRatelimit::from_db(db.path()).unwrap();
+        let limiter =
+            limiter.with_read_through(ReadThrough::new(db.path(), Duration::from_secs(60), 2));
+
+        let insert = |account_id: i64| {
+            let (token, data) = api_key::generate_with_data(&api_key::ApiKeyConfig::for_account(
+                API_KEY_PREFIX,
+                account_id,
+            ));
+            Connection::open(db.path())
+                .unwrap()
+                .execute(
+                    "INSERT INTO APIKeys (api_key, account_id, api_key_hash, version) VALUES (?, ?, ?, 1)",
+                    rusqlite::params![data.id.to_string(), account_id, hex::encode(data.secret_hash)],
+                )
+                .unwrap();
+            token.token
+        };
+        let fresh = insert(2);
+
+        assert_eq!(limiter.limit_for_key(&fresh).quota, DEFAULT_RPS_LIMIT);
+        assert!(limiter.read_through(&fresh));
+        assert_eq!(limiter.limit_for_key(&fresh).quota, 100);
+        // Already known: no lookup
+        assert!(!limiter.read_through(&fresh));
+
+        // Misses are cached
+        let (missing, _) =
+            api_key::generate_with_data(&api_key::ApiKeyConfig::for_account(API_KEY_PREFIX, 2));
+        assert!(!limiter.read_through(&missing.token));
+        let read_through = limiter.read_through.as_ref().unwrap();
+        assert!(read_through.recently_missed(&hash_api_key(&missing.token), Instant::now()));
+        // The negative cache stays bounded
+        for _ in 0..2 {
+            let (missing, _) =
+                api_key::generate_with_data(&api_key::ApiKeyConfig::for_account(API_KEY_PREFIX, 2));
+            assert!(!limiter.read_through(&missing.token));
+        }
+        assert!(read_through.negative.lock().unwrap().len() <= 2);
+
+        // Keys that are not versioned tokens are never looked up
+        Connection::open(db.path())
+            .unwrap()
+            .execute(
+                "INSERT INTO APIKeys (api_key, account_id, api_key_hash) VALUES (?, 2, ?)",
+                rusqlite::params![
+                    "00000000-0000-0000-0000-000000000009",
+                    hash_api_key("legacy-key")
+                ],
+            )
+            .unwrap();
+        assert!(!limiter.read_through("legacy-key"));
+        assert!(!read_through.recently_missed(&hash_api_key("legacy-key"), Instant::now()));
+    }
+
+    #[test]
+    fn test_read_through_lookups_are_budgeted() {
+        let db = create_test_db();
+        let (limiter, _) = AccountRatelimit::from_db(db.path()).unwrap();
+        let limiter = limiter.with_read_through(
+            ReadThrough::new(db.path(), Duration::from_secs(60), 100).with_max_lookups_per_sec(2),
+        );
+        let token = || {
+            api_key::generate_with_data(&api_key::ApiKeyConfig::for_account(API_KEY_PREFIX, 2))
+                .0
+                .token
+        };
+
+        for _ in 0..3 {
+            assert!(!limiter.read_through(&token()));
+        }
+        // Two lookups missed; the third key was skipped without being remembered
+        let read_through = limiter.read_through.as_ref().unwrap();
+        assert_eq!(read_through.negative.lock().unwrap().len(), 2);
+
+        // The budget is refilled the next second
+        assert!(read_through.take_lookup(Instant::now() + Duration::from_secs(1)));
+    }
+
     #[test]
     fn test_account_ratelimit_known_key() {
         let db = create_test_db();
@@ -810,6 +2569,56 @@ mod tests {
         assert_eq!(limit.per_seconds, DEFAULT_WINDOW_SECS);
     }
 
+    #[test]
+    fn test_versioned_key_is_bound_to_account() {
+        let db = create_test_db();
+        let (token, data) =
+            api_key::generate_with_data(&api_key::ApiKeyConfig::for_account(API_KEY_PREFIX, 2));
+        Connection::open(db.path())
+            .unwrap()
+            .execute(
+                "INSERT INTO APIKeys (api_key, account_id, api_key_hash, version) VALUES (?, 2, ?, 1)",
+                rusqlite::params![data.id.to_string(), hex::encode(data.secret_hash)],
+            )
+            .unwrap();
+        let store = Arc::new(RwLock::new(
+            AccountLoader::new(db.path()).load_initial().unwrap(),
+        ));
+        let limiter = AccountRatelimit::new(store.clone());
+
+        assert_eq!(limiter.limit_for_key(&token.token).quota, 100);
+        assert_eq!(
+            limiter.limit_for_key(&token.token).source,
+            LimitSource::Plan
+        );
+        assert_eq!(
+            limiter.key_hash(&token.token),
+            hex::encode(data.secret_hash)
+        );
+        assert_eq!(limiter.fingerprint(&token.token), token.fingerprint());
+        assert_eq!(
+            limiter
+                .metadata_for_fingerprint(&token.fingerprint())
+                .unwrap()
+                .api_key,
+            token.id
+        );
+        assert_eq!(limiter.fingerprint("no-such-key"), UNKNOWN_KEY_FINGERPRINT);
+
+        // The same hash moved onto a row owned by another account no longer verifies
+        let mut key = store
+            .read()
+            .unwrap()
+            .api_key_details
+            .get(&hex::encode(data.secret_hash))
+            .cloned()
+            .unwrap();
+        key.account_id = 1;
+        store.write().unwrap().upsert_api_key(key);
+        assert_eq!(limiter.limit_for_key(&token.token).quota, DEFAULT_RPS_LIMIT);
+        assert!(limiter.plan_for_key(&token.token).is_none());
+    }
+
     #[test]
     fn test_hash_api_key() {
         let hash1 = hash_api_key("test-key-123");
@@ -0,0 +1,976 @@
+//! Admin HTTP listener.
+//!
+//! A separate listener (never the public one) for operational endpoints. When a token is
+//! configured every request except the readiness probe must carry
+//! `Authorization: Bearer <token>`.
+//!
+//! - `GET /readyz`: 200 once startup has completed, 503 with the pending phases before;
+//!   both list the degraded dependencies (see [`crate::degradation`]).
+//! - `POST /admin/reload`: full reload of runtime state (see [`crate::reload`]).
+//! - `PUT /admin/log-level?level=<filter>`: change the application log filter.
+//! - `GET /admin/usage/files`: manifests of the closed hourly usage files.
+//! - `DELETE /admin/usage/accounts/<account_id>`: delete all usage data of the account (see
+//!   [`crate::retention`]).
+//! - `GET /admin/top?window=<minutes>&by=<requests|rate_limited|bytes>&n=<count>`: heaviest
+//!   keys and accounts (see [`crate::top`]).
+//! - `GET /admin/openapi/rejections`: requests rejected by service OpenAPI specs, by
+//!   operation (see [`crate::openapi`]).
+//! - `POST /admin/trace?key=<fingerprint>&n=<count>`: trace the key's next requests;
+//!   `GET` returns the captured traces and `DELETE` stops tracing (see [`crate::trace`]).
+//! - `GET /admin/config/history`: applied backend config versions, newest first;
+//!   `POST /admin/config/history/<version>` re-applies one (see [`crate::history`]).
+//! - `POST /admin/control/push`: apply a versioned change pushed by a control plane, and
+//!   `GET /admin/control` the last version applied (see [`crate::control`]).
+//! - `GET /metrics`: counters and size histograms in the Prometheus text format.
+//! - `GET /info`: node id, version, build hash, backend config version and uptime.
+//!
+//! Requests are limited to `requests_per_minute` per credential: the token, or the client
+//! IP for requests without it (including those with a wrong token). Requests beyond the
+//! limit get 429. Every request but the readiness probe is written to the audit log with
+//! its credential, client IP and status, and every authorized action with what it did.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use http::Response;
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+
+use crate::accounts::AccountRatelimit;
+use crate::configuration::{AdminConfig, Config};
+use crate::control::{AckStatus, ControlPlane, PushRequest};
+use crate::degradation::Degradation;
+use crate::history::ConfigHistory;
+use crate::lb::rate_for_window;
+use crate::logging::{AUDIT_TARGET, LogHandle};
+use crate::metric::Metrics;
+use crate::openapi::{REJECTIONS_COUNTER, rejection_report};
+use crate::readiness::Readiness;
+use crate::reload::RuntimeReloader;
+use crate::retention::{AccountDeletion, delete_account};
+use crate::sync::RwLockExt;
+use crate::top::{Ranking, top_consumers};
+use crate::trace::TraceCapture;
+use crate::usage::{UsageTracker, closed_files};
+
+pub const RELOAD_PATH: &str = "/admin/reload";
+pub const LOG_LEVEL_PATH: &str = "/admin/log-level";
+pub const READYZ_PATH: &str = "/readyz";
+pub const USAGE_FILES_PATH: &str = "/admin/usage/files";
+/// Prefix of the path deleting an account's usage data, followed by the account id.
+pub const USAGE_ACCOUNTS_PATH: &str = "/admin/usage/accounts/";
+pub const TOP_PATH: &str = "/admin/top";
+pub const OPENAPI_REJECTIONS_PATH: &str = "/admin/openapi/rejections";
+pub const TRACE_PATH: &str = "/admin/trace";
+pub const CONFIG_HISTORY_PATH: &str = "/admin/config/history";
+pub const CONTROL_PATH: &str = "/admin/control";
+pub const CONTROL_PUSH_PATH: &str = "/admin/control/push";
+/// Largest control-plane push accepted, in bytes.
+pub const MAX_PUSH_BYTES: usize = 4 << 20;
+pub const METRICS_PATH: &str = "/metrics";
+pub const INFO_PATH: &str = "/info";
+
+/// Commit the binary was built from, set by the build through `LB_BUILD_HASH`.
+pub const BUILD_HASH: &str = match option_env!("LB_BUILD_HASH") {
+    Some(hash) => hash,
+    None => "unknown",
+};
+
+/// Request handler for the admin listener.
+pub struct AdminApp {
+    token: Option<String>,
+    /// Requests per minute allowed per credential; 0 for no limit.
+    requests_per_minute: isize,
+    reloader: Option<Arc<RuntimeReloader>>,
+    log_handle: Option<LogHandle>,
+    readiness: Option<Arc<Readiness>>,
+    degradation: Option<Arc<Degradation>>,
+    usage_dir: Option<PathBuf>,
+    /// Tracker whose unwritten records account deletions drop, and whether they shred.
+    usage_deletion: Option<(Arc<UsageTracker>, bool)>,
+    top: Option<(Arc<Metrics>, Arc<AccountRatelimit>)>,
+    openapi_rejections: Option<Arc<Metrics>>,
+    traces: Option<Arc<TraceCapture>>,
+    metrics: Option<Arc<Metrics>>,
+    node_id: Option<String>,
+    config: Option<Arc<RwLock<Config>>>,
+    config_history: Option<Arc<ConfigHistory>>,
+    control: Option<Arc<ControlPlane>>,
+    started: Instant,
+}
+
+impl AdminApp {
+    pub fn new(config: &AdminConfig) -> Self {
+        Self {
+            token: config.token.clone(),
+            requests_per_minute: config.requests_per_minute,
+            reloader: None,
+            log_handle: None,
+            readiness: None,
+            degradation: None,
+            usage_dir: None,
+            usage_deletion: None,
+            top: None,
+            openapi_rejections: None,
+            traces: None,
+            metrics: None,
+            node_id: None,
+            config: None,
+            config_history: None,
+            control: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Name this node in `GET /info` and in the labels of `GET /metrics`.
+    pub fn with_node_id(mut self, node_id: impl Into<String>) -> Self {
+        self.node_id = Some(node_id.into());
+        self
+    }
+
+    /// Report the version of `config` in `GET /info`.
+    pub fn with_config(mut self, config: Arc<RwLock<Config>>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Serve `/admin/config/history` from `history`, re-applying versions through the
+    /// reloader.
+    pub fn with_config_history(mut self, history: Arc<ConfigHistory>) -> Self {
+        self.config_history = Some(history);
+        self
+    }
+
+    /// Apply pushes to `/admin/control/push` through `control`.
+    pub fn with_control_plane(mut self, control: Arc<ControlPlane>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Serve `GET /metrics` from `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Serve `GET /admin/top` from `metrics`, resolving accounts through `limiter`.
+    pub fn with_top_consumers(
+        mut self,
+        metrics: Arc<Metrics>,
+        limiter: Arc<AccountRatelimit>,
+    ) -> Self {
+        self.top = Some((metrics, limiter));
+        self
+    }
+
+    /// Serve `GET /admin/openapi/rejections` from `metrics`.
+    pub fn with_openapi_rejections(mut self, metrics: Arc<Metrics>) -> Self {
+        self.openapi_rejections = Some(metrics);
+        self
+    }
+
+    /// Serve `/admin/trace` from `traces`.
+    pub fn with_trace_capture(mut self, traces: Arc<TraceCapture>) -> Self {
+        self.traces = Some(traces);
+        self
+    }
+
+    /// Serve `GET /admin/usage/files` from the manifests in `usage_dir`.
+    pub fn with_usage_dir(mut self, usage_dir: impl Into<PathBuf>) -> Self {
+        self.usage_dir = Some(usage_dir.into());
+        self
+    }
+
+    /// Serve `DELETE /admin/usage/accounts/<account_id>` from the files in the usage
+    /// directory and the records of `tracker`, zeroing deleted data when `shred` is set.
+    pub fn with_usage_deletion(mut self, tracker: Arc<UsageTracker>, shred: bool) -> Self {
+        self.usage_deletion = Some((tracker, shred));
+        self
+    }
+
+    /// Serve `GET /readyz` from `readiness`.
+    pub fn with_readiness(mut self, readiness: Arc<Readiness>) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    /// List degraded dependencies in `GET /readyz`, which fails while they block readiness.
+    pub fn with_degradation(mut self, degradation: Arc<Degradation>) -> Self {
+        self.degradation = Some(degradation);
+        self
+    }
+
+    /// Serve `PUT /admin/log-level` through `handle`.
+    pub fn with_log_handle(mut self, handle: LogHandle) -> Self {
+        self.log_handle = Some(handle);
+        self
+    }
+
+    /// Serve `POST /admin/reload` through `reloader`.
+    pub fn with_reloader(mut self, reloader: Arc<RuntimeReloader>) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        match &self.token {
+            None => true,
+            Some(token) => authorization
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .is_some_and(|v| v == token),
+        }
+    }
+
+    /// Who a request is limited and audited as: the token when it carries the configured
+    /// one, its client IP otherwise.
+    fn credential(&self, authorization: Option<&str>, client: Option<IpAddr>) -> String {
+        if self.token.is_some() && self.is_authorized(authorization) {
+            return "token".to_string();
+        }
+        client.map_or_else(|| "ip:-".to_string(), |ip| format!("ip:{ip}"))
+    }
+
+    /// Count a request of `credential`, returning whether it is within the limit.
+    fn admit(&self, credential: &str) -> bool {
+        if self.requests_per_minute <= 0 {
+            return true;
+        }
+        let rate = rate_for_window(60);
+        rate.observe(&format!("admin:{credential}"), 1) <= self.requests_per_minute
+    }
+
+    /// Serve a request from `client`, with `body`: limit and audit it, then route it.
+    pub fn serve(
+        &self,
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        authorization: Option<&str>,
+        body: &[u8],
+        client: Option<IpAddr>,
+    ) -> Response<Vec<u8>> {
+        if let ("GET", READYZ_PATH) = (method, path) {
+            let (status, body) = self.handle(method, path, query, authorization);
+            return json_response(status, &body);
+        }
+        let credential = self.credential(authorization, client);
+        let response = if !self.admit(&credential) {
+            json_response(429, &serde_json::json!({ "error": "too many requests" }))
+        } else if let Some(response) = self.prometheus(method, path, authorization) {
+            response
+        } else if (method, path) == ("POST", CONTROL_PUSH_PATH) && self.is_authorized(authorization)
+        {
+            let (status, body) = self.push(body);
+            json_response(status, &body)
+        } else {
+            let (status, body) = self.handle(method, path, query, authorization);
+            json_response(status, &body)
+        };
+        let status = response.status().as_u16();
+        let client = client.map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        if matches!(status, 401 | 429) {
+            log::warn!(target: AUDIT_TARGET, "admin request {method} {path} by {credential} from {client} rejected with {status}");
+        } else {
+            log::info!(target: AUDIT_TARGET, "admin request {method} {path} by {credential} from {client}: {status}");
+        }
+        response
+    }
+
+    /// Apply the control-plane push in `body`.
+    fn push(&self, body: &[u8]) -> (u16, serde_json::Value) {
+        let Some(control) = &self.control else {
+            return (404, serde_json::json!({ "error": "not found" }));
+        };
+        let request: PushRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return (400, serde_json::json!({ "error": e.to_string() })),
+        };
+        let version = request.version;
+        let kind = serde_json::to_value(&request.push)
+            .ok()
+            .and_then(|push| push["kind"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        let ack = control.push(request);
+        log::info!(target: AUDIT_TARGET, "control plane push {version} ({kind}): {:?}", ack.status);
+        let status = match ack.status {
+            AckStatus::Applied | AckStatus::Duplicate => 200,
+            AckStatus::Rejected => 422,
+        };
+        (status, serde_json::json!(ack))
+    }
+
+    /// The Prometheus exposition for `GET /metrics`; `None` for other requests and when
+    /// no metrics are served.
+    pub fn prometheus(
+        &self,
+        method: &str,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> Option<Response<Vec<u8>>> {
+        let metrics = self.metrics.as_ref()?;
+        if (method, path) != ("GET", METRICS_PATH) || !self.is_authorized(authorization) {
+            return None;
+        }
+        let body = metrics
+            .render_prometheus(self.node_id.as_deref())
+            .into_bytes();
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .header("Content-Length", body.len())
+            .body(body)
+            .expect("valid admin response");
+        Some(response)
+    }
+
+    /// Route a request to its handler.
+    pub fn handle(
+        &self,
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        authorization: Option<&str>,
+    ) -> (u16, serde_json::Value) {
+        // Probes carry no credentials and readiness reveals nothing sensitive
+        if let ("GET", READYZ_PATH) = (method, path) {
+            let Some(readiness) = &self.readiness else {
+                return (404, serde_json::json!({ "error": "not found" }));
+            };
+            let blocked = self
+                .degradation
+                .as_ref()
+                .is_some_and(|degradation| degradation.blocks_readiness());
+            let ready = readiness.is_ready() && !blocked;
+            let mut body = serde_json::json!({ "ready": ready });
+            if !readiness.is_ready() {
+                body["pending"] = serde_json::json!(readiness.pending());
+            }
+            if let Some(degradation) = &self.degradation {
+                body["degraded"] = serde_json::json!(degradation.degraded());
+            }
+            return (if ready { 200 } else { 503 }, body);
+        }
+        if !self.is_authorized(authorization) {
+            return (401, serde_json::json!({ "error": "unauthorized" }));
+        }
+        match (method, path) {
+            ("POST", RELOAD_PATH) => match &self.reloader {
+                Some(reloader) => {
+                    log::info!(target: AUDIT_TARGET, "reload requested via admin API");
+                    let summary = reloader.reload_all();
+                    let status = if summary.is_ok() { 200 } else { 500 };
+                    (
+                        status,
+                        serde_json::json!({ "summary": summary.to_string() }),
+                    )
+                }
+                None => (404, serde_json::json!({ "error": "not found" })),
+            },
+            ("PUT", LOG_LEVEL_PATH) => {
+                let Some(handle) = &self.log_handle else {
+                    return (404, serde_json::json!({ "error": "not found" }));
+                };
+                let level = query_param(query, "level");
+                match level.map(|level| (level, handle.set_level(level))) {
+                    Some((level, Ok(()))) => (200, serde_json::json!({ "level": level })),
+                    Some((_, Err(e))) => (400, serde_json::json!({ "error": e })),
+                    None => (400, serde_json::json!({ "error": "missing level" })),
+                }
+            }
+            ("GET", TOP_PATH) => {
+                let Some((metrics, limiter)) = &self.top else {
+                    return (404, serde_json::json!({ "error": "not found" }));
+                };
+                let window = query_param(query, "window").map_or(Ok(5), str::parse::<u64>);
+                let by = query_param(query, "by").map_or(Ok(Ranking::Requests), str::parse);
+                let n = query_param(query, "n").map_or(Ok(10), str::parse::<usize>);
+                match (window, by, n) {
+                    (Ok(window @ 1..=1440), Ok(by), Ok(n)) => {
+                        let top = top_consumers(metrics, limiter, window, by, n);
+                        (200, serde_json::json!(top))
+                    }
+                    (_, Err(e), _) => (400, serde_json::json!({ "error": e })),
+                    _ => (400, serde_json::json!({ "error": "invalid window or n" })),
+                }
+            }
+            ("GET", OPENAPI_REJECTIONS_PATH) => match &self.openapi_rejections {
+                Some(metrics) => {
+                    let (operations, other) =
+                        rejection_report(metrics.labeled_counter(REJECTIONS_COUNTER));
+                    (
+                        200,
+                        serde_json::json!({ "operations": operations, "other": other }),
+                    )
+                }
+                None => (404, serde_json::json!({ "error": "not found" })),
+            },
+            (_, TRACE_PATH) => {
+                let Some(traces) = &self.traces else {
+                    return (404, serde_json::json!({ "error": "not found" }));
+                };
+                let Some(key) = query_param(query, "key") else {
+                    return (400, serde_json::json!({ "error": "missing key" }));
+                };
+                match method {
+                    "POST" => match query_param(query, "n").map_or(Ok(10), str::parse::<usize>) {
+                        Ok(n) => match traces.arm(key, n) {
+                            Some(armed) => {
+                                log::info!(target: AUDIT_TARGET, "tracing next {armed} requests of key {key}");
+                                (200, serde_json::json!({ "key": key, "remaining": armed }))
+                            }
+                            None => (503, serde_json::json!({ "error": "too many keys traced" })),
+                        },
+                        Err(_) => (400, serde_json::json!({ "error": "invalid n" })),
+                    },
+                    "GET" => {
+                        let (remaining, captured) = traces.traces(key);
+                        (
+                            200,
+                            serde_json::json!({
+                                "key": key,
+                                "remaining": remaining,
+                                "traces": captured,
+                            }),
+                        )
+                    }
+                    "DELETE" => {
+                        log::info!(target: AUDIT_TARGET, "stopped tracing key {key}");
+                        let status = if traces.clear(key) { 200 } else { 404 };
+                        (status, serde_json::json!({ "key": key }))
+                    }
+                    _ => (404, serde_json::json!({ "error": "not found" })),
+                }
+            }
+            ("GET", INFO_PATH) => (
+                200,
+                serde_json::json!({
+                    "node": self.node_id,
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "build": BUILD_HASH,
+                    "config_version": self
+                        .config
+                        .as_ref()
+                        .map(|config| config.read_or_recover().version.clone()),
+                    "uptime_secs": self.started.elapsed().as_secs(),
+                }),
+            ),
+            ("GET", CONTROL_PATH) => match &self.control {
+                Some(control) => (200, serde_json::json!({ "version": control.version() })),
+                None => (404, serde_json::json!({ "error": "not found" })),
+            },
+            ("GET", CONFIG_HISTORY_PATH) => match &self.config_history {
+                Some(history) => match history.list() {
+                    Ok(versions) => (200, serde_json::json!({ "versions": versions })),
+                    Err(e) => (500, serde_json::json!({ "error": e.to_string() })),
+                },
+                None => (404, serde_json::json!({ "error": "not found" })),
+            },
+            ("POST", path) if path.starts_with(CONFIG_HISTORY_PATH) => {
+                let (Some(history), Some(reloader)) = (&self.config_history, &self.reloader) else {
+                    return (404, serde_json::json!({ "error": "not found" }));
+                };
+                let Some(version) = path[CONFIG_HISTORY_PATH.len()..].strip_prefix('/') else {
+                    return (404, serde_json::json!({ "error": "not found" }));
+                };
+                let source = match history.source(version) {
+                    Ok(Some(source)) => source,
+                    Ok(None) => {
+                        return (
+                            404,
+                            serde_json::json!({ "error": "unknown config version" }),
+                        );
+                    }
+                    Err(e) => return (500, serde_json::json!({ "error": e.to_string() })),
+                };
+                log::info!(target: AUDIT_TARGET, "re-applying backend config version {version} via admin API");
+                match reloader.apply_config(&source) {
+                    Ok(((s0, b0), (s1, b1))) => (
+                        200,
+                        serde_json::json!({
+                            "version": version,
+                            "summary": format!("services {s0} -> {s1}, backends {b0} -> {b1}"),
+                        }),
+                    ),
+                    Err(e) => (500, serde_json::json!({ "error": e })),
+                }
+            }
+            ("DELETE", path) if path.starts_with(USAGE_ACCOUNTS_PATH) => {
+                let (Some(dir), Some((tracker, shred))) = (&self.usage_dir, &self.usage_deletion)
+                else {
+                    return (404, serde_json::json!({ "error": "not found" }));
+                };
+                let Ok(account_id) = path[USAGE_ACCOUNTS_PATH.len()..].parse::<i64>() else {
+                    return (400, serde_json::json!({ "error": "invalid account id" }));
+                };
+                tracker.forget_account(account_id);
+                // The account may have moved between residency regions, so clear them all
+                let deleted = std::iter::once(dir.clone())
+                    .chain(tracker.residency_dirs())
+                    .try_fold(AccountDeletion::default(), |mut total, dir| {
+                        total.merge(delete_account(&dir, account_id, *shred)?);
+                        Ok::<_, std::io::Error>(total)
+                    });
+                match deleted {
+                    Ok(deletion) => {
+                        log::info!(
+                            target: AUDIT_TARGET,
+                            "deleted usage data of account {account_id} from {} files",
+                            deletion.files.len()
+                        );
+                        (
+                            200,
+                            serde_json::json!({ "account_id": account_id, "deleted": deletion }),
+                        )
+                    }
+                    Err(e) => (500, serde_json::json!({ "error": e.to_string() })),
+                }
+            }
+            ("GET", USAGE_FILES_PATH) => match &self.usage_dir {
+                Some(dir) => match closed_files(dir) {
+                    Ok(files) => (200, serde_json::json!({ "files": files })),
+                    Err(e) => (500, serde_json::json!({ "error": e.to_string() })),
+                },
+                None => (404, serde_json::json!({ "error": "not found" })),
+            },
+            _ => (404, serde_json::json!({ "error": "not found" })),
+        }
+    }
+}
+
+/// Value of `name` in a query string.
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Build a JSON response.
+pub fn json_response(status: u16, body: &serde_json::Value) -> Response<Vec<u8>> {
+    let body = body.to_string().into_bytes();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len())
+        .body(body)
+        .expect("valid admin response")
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let client = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        // Only pushes carry a body worth reading
+        let mut body = Vec::new();
+        if session.req_header().uri.path() == CONTROL_PUSH_PATH {
+            while let Ok(Some(chunk)) = session.read_request_body().await {
+                body.extend_from_slice(&chunk);
+                if body.len() > MAX_PUSH_BYTES {
+                    let error = serde_json::json!({ "error": "push too large" });
+                    return json_response(413, &error);
+                }
+            }
+        }
+        let req = session.req_header();
+        let authorization = req
+            .headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        self.serve(
+            req.method.as_str(),
+            req.uri.path(),
+            req.uri.query(),
+            authorization,
+            &body,
+            client,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_required_when_configured() {
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: Some("secret".to_string()),
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        });
+        assert_eq!(app.handle("POST", RELOAD_PATH, None, None).0, 401);
+        assert_eq!(
+            app.handle("POST", RELOAD_PATH, None, Some("Bearer wrong"))
+                .0,
+            401
+        );
+        // Authorized, but no reloader is attached
+        assert_eq!(
+            app.handle("POST", RELOAD_PATH, None, Some("Bearer secret"))
+                .0,
+            404
+        );
+    }
+
+    #[test]
+    fn requests_are_limited_per_credential() {
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: Some("secret".to_string()),
+            requests_per_minute: 3,
+            control_plane: false,
+            control_plane_grpc: None,
+        });
+        let client: Option<IpAddr> = Some("192.0.2.7".parse().unwrap());
+        let other: Option<IpAddr> = Some("192.0.2.8".parse().unwrap());
+        let status = |authorization: Option<&str>, client: Option<IpAddr>| {
+            app.serve("GET", TOP_PATH, None, authorization, b"", client)
+                .status()
+                .as_u16()
+        };
+
+        // Guessing tokens counts against the client IP
+        for _ in 0..3 {
+            assert_eq!(status(Some("Bearer wrong"), client), 401);
+        }
+        assert_eq!(status(Some("Bearer wrong"), client), 429);
+        assert_eq!(status(None, other), 401);
+
+        // The token has its own budget, wherever it is used from
+        for _ in 0..3 {
+            assert_eq!(status(Some("Bearer secret"), client), 404);
+        }
+        assert_eq!(status(Some("Bearer secret"), other), 429);
+
+        // Probes are never limited
+        assert_eq!(
+            app.serve("GET", READYZ_PATH, None, None, b"", client)
+                .status(),
+            404
+        );
+    }
+
+    #[test]
+    fn control_plane_pushes_are_acked_by_version() {
+        use crate::accounts::AccountStore;
+
+        let store = Arc::new(RwLock::new(AccountStore::new()));
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: Some("secret".to_string()),
+            requests_per_minute: 0,
+            control_plane: true,
+            control_plane_grpc: None,
+        })
+        .with_control_plane(Arc::new(ControlPlane::new(store.clone())));
+        let push = |body: &str, authorization: Option<&str>| {
+            let response = app.serve(
+                "POST",
+                CONTROL_PUSH_PATH,
+                None,
+                authorization,
+                body.as_bytes(),
+                None,
+            );
+            let status = response.status().as_u16();
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            (status, body)
+        };
+
+        let plan = r#"{ "version": 4, "kind": "plan", "plan": { "plan_id": 2, "name": "pro",
+            "monthly_quota": 100000, "rps_limit": 50, "price_per_1k_req": 0.5, "burst_cap": 0,
+            "accrual_rate": 0.0 } }"#;
+        assert_eq!(push(plan, None).0, 401);
+        let (status, ack) = push(plan, Some("Bearer secret"));
+        assert_eq!(status, 200);
+        assert_eq!(
+            ack,
+            serde_json::json!({ "version": 4, "status": "applied" })
+        );
+        assert_eq!(store.read().unwrap().plan(2).unwrap().rps_limit, 50);
+        assert_eq!(push(plan, Some("Bearer secret")).1["status"], "duplicate");
+
+        let unknown = r#"{ "version": 5, "kind": "account_plan", "account_id": 9, "plan_id": 2 }"#;
+        let (status, ack) = push(unknown, Some("Bearer secret"));
+        assert_eq!((status, ack["status"].as_str()), (422, Some("rejected")));
+        assert_eq!(push("{}", Some("Bearer secret")).0, 400);
+
+        let (status, body) = app.handle("GET", CONTROL_PATH, None, Some("Bearer secret"));
+        assert_eq!((status, body["version"].as_u64()), (200, Some(4)));
+    }
+
+    #[test]
+    fn readyz_reports_pending_phases_without_auth() {
+        use crate::readiness::Phase;
+
+        let readiness = Arc::new(Readiness::new());
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: Some("secret".to_string()),
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_readiness(readiness.clone());
+
+        readiness.complete(Phase::BackendConfig);
+        let (status, body) = app.handle("GET", READYZ_PATH, None, None);
+        assert_eq!(status, 503);
+        assert_eq!(body["pending"], serde_json::json!(["accounts"]));
+
+        readiness.complete(Phase::Accounts);
+        assert_eq!(app.handle("GET", READYZ_PATH, None, None).0, 200);
+    }
+
+    #[test]
+    fn readyz_lists_degraded_dependencies() {
+        use crate::degradation::{AccountsDbPolicy, DegradationConfig, Dependency};
+        use crate::readiness::Phase;
+
+        let readiness = Arc::new(Readiness::new());
+        readiness.complete(Phase::BackendConfig);
+        readiness.complete(Phase::Accounts);
+        let degradation = Arc::new(Degradation::new(DegradationConfig {
+            accounts_db: AccountsDbPolicy::Unready,
+            ..Default::default()
+        }));
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: None,
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_readiness(readiness)
+        .with_degradation(degradation.clone());
+
+        degradation.enter(Dependency::UsageDir, "disk full");
+        let (status, body) = app.handle("GET", READYZ_PATH, None, None);
+        assert_eq!(status, 200);
+        assert_eq!(body["degraded"]["usage_dir"], "disk full");
+
+        degradation.enter(Dependency::AccountsDb, "database is locked");
+        let (status, body) = app.handle("GET", READYZ_PATH, None, None);
+        assert_eq!(status, 503);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["degraded"]["accounts_db"], "database is locked");
+    }
+
+    #[test]
+    fn top_validates_query() {
+        use crate::accounts::AccountStore;
+        use std::sync::RwLock;
+
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: None,
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_top_consumers(
+            Arc::new(Metrics::new()),
+            Arc::new(AccountRatelimit::new(Arc::new(RwLock::new(
+                AccountStore::new(),
+            )))),
+        );
+
+        let (status, body) = app.handle("GET", TOP_PATH, Some("window=60&by=bytes&n=3"), None);
+        assert_eq!(status, 200);
+        assert_eq!(body["window_minutes"], 60);
+        assert_eq!(body["by"], "bytes");
+        assert_eq!(app.handle("GET", TOP_PATH, Some("by=latency"), None).0, 400);
+        assert_eq!(app.handle("GET", TOP_PATH, Some("window=0"), None).0, 400);
+    }
+
+    #[test]
+    fn trace_is_armed_read_and_cleared() {
+        let traces = Arc::new(TraceCapture::new());
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: None,
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_trace_capture(traces.clone());
+
+        assert_eq!(app.handle("POST", TRACE_PATH, None, None).0, 400);
+        assert_eq!(
+            app.handle("POST", TRACE_PATH, Some("key=fp&n=x"), None).0,
+            400
+        );
+        let (status, body) = app.handle("POST", TRACE_PATH, Some("key=fp&n=3"), None);
+        assert_eq!(status, 200);
+        assert_eq!(body["remaining"], 3);
+        assert!(traces.claim("fp"));
+
+        let (status, body) = app.handle("GET", TRACE_PATH, Some("key=fp"), None);
+        assert_eq!(status, 200);
+        assert_eq!(body["remaining"], 2);
+        assert_eq!(body["traces"], serde_json::json!([]));
+
+        assert_eq!(
+            app.handle("DELETE", TRACE_PATH, Some("key=fp"), None).0,
+            200
+        );
+        assert!(!traces.claim("fp"));
+        assert_eq!(
+            app.handle("DELETE", TRACE_PATH, Some("key=fp"), None).0,
+            404
+        );
+    }
+
+    #[test]
+    fn metrics_are_exported_when_authorized() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe("response_body_bytes", "geocode", 10);
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: Some("secret".to_string()),
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_metrics(metrics);
+
+        let response = app
+            .prometheus("GET", METRICS_PATH, Some("Bearer secret"))
+            .unwrap();
+        let body = String::from_utf8(response.into_body()).unwrap();
+        assert!(body.contains("lb_response_body_bytes_count{service=\"geocode\"} 1"));
+        // Unauthorized requests get the JSON 401 of `handle`
+        assert!(app.prometheus("GET", METRICS_PATH, None).is_none());
+        assert_eq!(app.handle("GET", METRICS_PATH, None, None).0, 401);
+    }
+
+    #[test]
+    fn info_reports_node_and_config_version() {
+        let mut config: Config = serde_yaml::from_str("services: {}\nbackends: []\n").unwrap();
+        config.version = "0123456789ab".to_string();
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: Some("secret".to_string()),
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_node_id("lb-1")
+        .with_config(Arc::new(RwLock::new(config)));
+
+        assert_eq!(app.handle("GET", INFO_PATH, None, None).0, 401);
+        let (status, body) = app.handle("GET", INFO_PATH, None, Some("Bearer secret"));
+        assert_eq!(status, 200);
+        assert_eq!(body["node"], "lb-1");
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["build"], BUILD_HASH);
+        assert_eq!(body["config_version"], "0123456789ab");
+        assert_eq!(body["uptime_secs"], 0);
+    }
+
+    #[test]
+    fn account_usage_is_deleted() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let tracker = Arc::new(crate::usage::UsageTracker::new());
+        tracker.touch_key(7, uuid::Uuid::nil(), None, 3700);
+        crate::usage::UsageWriter::new(tracker.clone(), dir.path())
+            .flush_all()
+            .unwrap();
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: None,
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_usage_dir(dir.path());
+        let path = format!("{USAGE_ACCOUNTS_PATH}7");
+        assert_eq!(app.handle("DELETE", &path, None, None).0, 404);
+
+        let app = app.with_usage_deletion(tracker, false);
+        let (status, body) = app.handle("DELETE", &path, None, None);
+        assert_eq!(status, 200);
+        assert_eq!(body["deleted"]["activity_rows"], 1);
+        assert_eq!(body["deleted"]["files"][0], "usage-1970010101.db");
+        let (status, _) = app.handle("DELETE", &format!("{USAGE_ACCOUNTS_PATH}x"), None, None);
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn usage_files_lists_manifests() {
+        let dir = tempfile::TempDir::new().unwrap();
+        crate::usage::close_hour(dir.path(), 3600).unwrap();
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: None,
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_usage_dir(dir.path());
+
+        let (status, body) = app.handle("GET", USAGE_FILES_PATH, None, None);
+        assert_eq!(status, 200);
+        assert_eq!(body["files"][0]["file"], "usage-1970010101.db");
+        assert_eq!(body["files"][0]["sha256"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn config_history_is_listed_and_reapplied() {
+        use crate::accounts::AccountStore;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let backend = dir.path().join("backend.yaml");
+        let one = "services: {}\nbackends: []\n";
+        let two = "services:\n  tiles: /tiles\nbackends:\n  - service: tiles\n    \
+                   backend: { type: basic, ip: 127.0.0.1, port: 8000 }\n";
+        std::fs::write(&backend, one).unwrap();
+        let config = Arc::new(RwLock::new(Config::load(&backend).unwrap()));
+        let history = Arc::new(ConfigHistory::new(dir.path().join("history"), 10));
+        std::fs::create_dir(history.dir()).unwrap();
+        history.record(one, None, &config.read().unwrap()).unwrap();
+        let reloader = RuntimeReloader::new(
+            &backend,
+            config.clone(),
+            dir.path().join("accounts.db"),
+            Arc::new(RwLock::new(AccountStore::new())),
+        )
+        .with_history(history.clone());
+        let first = config.read().unwrap().version.clone();
+
+        std::fs::write(&backend, two).unwrap();
+        reloader.reload_all();
+        let app = AdminApp::new(&AdminConfig {
+            listen: "127.0.0.1:0".to_string(),
+            token: None,
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: None,
+        })
+        .with_reloader(Arc::new(reloader))
+        .with_config_history(history);
+        let (status, body) = app.handle("GET", CONFIG_HISTORY_PATH, None, None);
+        assert_eq!(status, 200);
+        assert_eq!(body["versions"][0]["diff"]["services_added"][0], "tiles");
+        assert_eq!(body["versions"][1]["version"], first.as_str());
+
+        let path = format!("{CONFIG_HISTORY_PATH}/{first}");
+        let (status, body) = app.handle("POST", &path, None, None);
+        assert_eq!(status, 200, "{body}");
+        assert_eq!(body["summary"], "services 1 -> 0, backends 1 -> 0");
+        assert_eq!(config.read().unwrap().version, first);
+        assert_eq!(std::fs::read_to_string(&backend).unwrap(), one);
+        let (_, body) = app.handle("GET", CONFIG_HISTORY_PATH, None, None);
+        assert_eq!(body["versions"][0]["seq"], 3);
+        let unknown = format!("{CONFIG_HISTORY_PATH}/000000000000");
+        assert_eq!(app.handle("POST", &unknown, None, None).0, 404);
+    }
+}
@@ -0,0 +1,358 @@
+//! Plan-priority admission under overload.
+//!
+//! With an `overload` section, at most `max_concurrent` proxied requests are in flight at
+//! once. Requests arriving beyond that wait in one queue per plan priority (the `priority`
+//! column of Plans, higher first) and freed slots go to the queues by weighted round robin:
+//! with weights 4, 2 and 1, Enterprise, Pro and Free waiters get 4, 2 and 1 of every 7
+//! slots, so paying customers keep working during a capacity incident while lower plans
+//! still progress. Requests that cannot be queued, or wait longer than `queue_timeout_ms`,
+//! get a `503` with `Retry-After`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::sync::MutexExt;
+
+/// Counter of requests that waited for a slot, labeled with their plan priority.
+pub const OVERLOAD_QUEUED_COUNTER: &str = "overload_queued";
+/// Counter of requests rejected while overloaded, labeled with their plan priority.
+pub const OVERLOAD_REJECTED_COUNTER: &str = "overload_rejected";
+
+/// Admission of proxied requests by plan priority.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OverloadConfig {
+    /// Requests in flight beyond which new requests are queued.
+    pub max_concurrent: usize,
+    /// Requests waiting across all queues beyond which new requests are rejected.
+    pub max_queued: usize,
+    /// Longest a request waits for a slot before it is rejected.
+    pub queue_timeout_ms: u64,
+    /// Share of freed slots given to each plan priority; `priority + 1` when not listed.
+    pub weights: HashMap<i32, u32>,
+    /// Seconds rejected clients are asked to wait before retrying.
+    pub retry_after_secs: u64,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 1024,
+            max_queued: 4096,
+            queue_timeout_ms: 1000,
+            weights: HashMap::new(),
+            retry_after_secs: 1,
+        }
+    }
+}
+
+impl OverloadConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent == 0 {
+            return Err("overload.max_concurrent must be at least 1".to_string());
+        }
+        if let Some((priority, _)) = self.weights.iter().find(|(_, weight)| **weight == 0) {
+            return Err(format!(
+                "overload.weights: priority {priority} must have a weight of at least 1"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Share of freed slots given to requests of `priority`.
+    pub fn weight(&self, priority: i32) -> u32 {
+        self.weights
+            .get(&priority)
+            .copied()
+            .unwrap_or_else(|| priority.max(0).saturating_add(1) as u32)
+    }
+}
+
+/// Why a request was not admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// All queues together hold `max_queued` requests.
+    QueueFull,
+    /// No slot was freed for the request within `queue_timeout_ms`.
+    TimedOut,
+}
+
+/// Slot of an admitted request, freed when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    admission: Arc<Mutex<State>>,
+    config: Arc<OverloadConfig>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.admission.lock_or_recover().release(&self.config);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: usize,
+    queued: usize,
+    next_waiter: u64,
+    /// Waiters per priority, oldest first.
+    queues: BTreeMap<i32, VecDeque<(u64, oneshot::Sender<()>)>>,
+    /// Smooth weighted round robin credit of each priority with waiters.
+    credits: BTreeMap<i32, i64>,
+}
+
+impl State {
+    /// Hand a freed slot to the next waiter, or give it back when none is left.
+    fn release(&mut self, config: &OverloadConfig) {
+        while let Some(priority) = self.next_priority(config) {
+            let queue = self
+                .queues
+                .get_mut(&priority)
+                .expect("picked a queued priority");
+            let (_, waiter) = queue.pop_front().expect("queues are never left empty");
+            if queue.is_empty() {
+                self.queues.remove(&priority);
+                self.credits.remove(&priority);
+            }
+            self.queued -= 1;
+            // The slot moves to the waiter as is; a waiter gone since keeps looking
+            if waiter.send(()).is_ok() {
+                return;
+            }
+        }
+        self.in_flight -= 1;
+    }
+
+    /// Priority whose oldest waiter gets the next slot.
+    fn next_priority(&mut self, config: &OverloadConfig) -> Option<i32> {
+        let total: i64 = self
+            .queues
+            .keys()
+            .map(|priority| i64::from(config.weight(*priority)))
+            .sum();
+        let mut best: Option<(i32, i64)> = None;
+        for priority in self.queues.keys().rev() {
+            let credit = self.credits.entry(*priority).or_default();
+            *credit += i64::from(config.weight(*priority));
+            if best.is_none_or(|(_, top)| *credit > top) {
+                best = Some((*priority, *credit));
+            }
+        }
+        let (priority, _) = best?;
+        *self.credits.get_mut(&priority)? -= total;
+        Some(priority)
+    }
+
+    /// Take the waiter out of its queue; false if it was handed a slot already.
+    fn withdraw(&mut self, priority: i32, id: u64) -> bool {
+        let Some(queue) = self.queues.get_mut(&priority) else {
+            return false;
+        };
+        let Some(position) = queue.iter().position(|(waiter, _)| *waiter == id) else {
+            return false;
+        };
+        queue.remove(position);
+        if queue.is_empty() {
+            self.queues.remove(&priority);
+            self.credits.remove(&priority);
+        }
+        self.queued -= 1;
+        true
+    }
+}
+
+/// Waiting request; gives back a slot handed to it after it stopped waiting.
+struct Waiter {
+    admission: Arc<Mutex<State>>,
+    config: Arc<OverloadConfig>,
+    priority: i32,
+    id: u64,
+    slot: oneshot::Receiver<()>,
+    admitted: bool,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        let mut state = self.admission.lock_or_recover();
+        // Slots are handed over under the lock, so the receiver is settled here
+        if !state.withdraw(self.priority, self.id) && self.slot.try_recv().is_ok() {
+            state.release(&self.config);
+        }
+    }
+}
+
+/// Concurrency limit admitting queued requests by plan priority.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    config: Arc<OverloadConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Admission {
+    pub fn new(config: OverloadConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    pub fn config(&self) -> &OverloadConfig {
+        &self.config
+    }
+
+    /// Requests in flight and waiting.
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock_or_recover();
+        (state.in_flight, state.queued)
+    }
+
+    fn permit(&self) -> Permit {
+        Permit {
+            admission: self.state.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    /// A slot if one is free and nobody is waiting for it.
+    pub fn try_admit(&self) -> Option<Permit> {
+        let mut state = self.state.lock_or_recover();
+        if state.in_flight >= self.config.max_concurrent || state.queued > 0 {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(self.permit())
+    }
+
+    /// Wait for a slot in the queue of `priority`.
+    pub async fn admit(&self, priority: i32) -> Result<Permit, Rejected> {
+        let mut waiter = {
+            let mut state = self.state.lock_or_recover();
+            if state.in_flight < self.config.max_concurrent && state.queued == 0 {
+                state.in_flight += 1;
+                return Ok(self.permit());
+            }
+            if state.queued >= self.config.max_queued {
+                return Err(Rejected::QueueFull);
+            }
+            let (tx, rx) = oneshot::channel();
+            let id = state.next_waiter;
+            state.next_waiter += 1;
+            state
+                .queues
+                .entry(priority)
+                .or_default()
+                .push_back((id, tx));
+            state.queued += 1;
+            Waiter {
+                admission: self.state.clone(),
+                config: self.config.clone(),
+                priority,
+                id,
+                slot: rx,
+                admitted: false,
+            }
+        };
+
+        let timeout = Duration::from_millis(self.config.queue_timeout_ms);
+        match tokio::time::timeout(timeout, &mut waiter.slot).await {
+            Ok(Ok(())) => {
+                waiter.admitted = true;
+                Ok(self.permit())
+            }
+            // Dropping the waiter withdraws it, or frees a slot handed over meanwhile
+            _ => Err(Rejected::TimedOut),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_concurrent: usize) -> Admission {
+        Admission::new(OverloadConfig {
+            max_concurrent,
+            max_queued: 100,
+            queue_timeout_ms: 5000,
+            weights: HashMap::from([(2, 4), (1, 2), (0, 1)]),
+            retry_after_secs: 1,
+        })
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let config = OverloadConfig {
+            weights: HashMap::from([(2, 4), (1, 2), (0, 1)]),
+            ..Default::default()
+        };
+        let mut state = State::default();
+        for priority in [0, 1, 2] {
+            state.queues.insert(priority, VecDeque::new());
+        }
+        let picks: Vec<i32> = (0..7)
+            .map(|_| state.next_priority(&config).unwrap())
+            .collect();
+        assert_eq!(picks.iter().filter(|p| **p == 2).count(), 4);
+        assert_eq!(picks.iter().filter(|p| **p == 1).count(), 2);
+        assert_eq!(picks.iter().filter(|p| **p == 0).count(), 1);
+        assert_eq!(picks[0], 2);
+        assert_eq!(config.weight(7), 8);
+    }
+
+    #[tokio::test]
+    async fn test_waiters_are_admitted_by_priority() {
+        let admission = admission(1);
+        let held = admission.try_admit().unwrap();
+        assert!(admission.try_admit().is_none());
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for priority in [0, 1, 2].repeat(7) {
+            let (queue, order) = (admission.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = queue.admit(priority).await.unwrap();
+                order.lock().unwrap().push(priority);
+            }));
+            // Queue in a known order
+            while admission.load().1 < tasks.len() {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let order = order.lock().unwrap();
+        let first = |priority| order[..7].iter().filter(|p| **p == priority).count();
+        assert_eq!((first(2), first(1), first(0)), (4, 2, 1));
+        assert_eq!(admission.load(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_rejections() {
+        let admission = Admission::new(OverloadConfig {
+            max_concurrent: 1,
+            max_queued: 1,
+            queue_timeout_ms: 20,
+            ..Default::default()
+        });
+        let _held = admission.admit(0).await.unwrap();
+        let waiting = {
+            let admission = admission.clone();
+            tokio::spawn(async move { admission.admit(5).await.map(|_| ()) })
+        };
+        while admission.load().1 == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(admission.admit(9).await.unwrap_err(), Rejected::QueueFull);
+        assert_eq!(waiting.await.unwrap(), Err(Rejected::TimedOut));
+        assert_eq!(admission.load(), (1, 0));
+    }
+}
@@ -0,0 +1,179 @@
+//! Operational alerting.
+//!
+//! Background services report failures (config reloads, account DB loads, usage flushes,
+//! traffic anomalies) to an [`AlertSink`]. Every alert is logged; when a webhook is configured
+//! it is also POSTed as JSON with a Slack-compatible `text` field. Identical alerts are
+//! suppressed for a configurable period so a persistent failure does not flood the channel.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+
+use crate::configuration::AlertConfig;
+use crate::sync::MutexExt;
+
+/// Alert severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+/// Webhook payload for a single alert.
+#[derive(Debug, Clone, Serialize)]
+struct AlertPayload<'a> {
+    /// Human-readable summary, shown by Slack-compatible receivers.
+    text: String,
+    source: &'a str,
+    severity: Severity,
+    message: &'a str,
+    /// Unix timestamp in seconds.
+    timestamp: u64,
+}
+
+/// Destination for operational alerts with duplicate suppression.
+#[derive(Debug)]
+pub struct AlertSink {
+    webhook_url: Option<String>,
+    dedupe_window: Duration,
+    client: reqwest::Client,
+    /// (source, message) -> last time it was sent.
+    last_sent: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl Default for AlertSink {
+    fn default() -> Self {
+        Self::new(&AlertConfig::default())
+    }
+}
+
+impl AlertSink {
+    /// Create a sink from alert settings.
+    pub fn new(config: &AlertConfig) -> Self {
+        Self {
+            webhook_url: config.webhook_url.clone(),
+            dedupe_window: Duration::from_secs(config.dedupe_secs),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap_or_default(),
+            last_sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Report a warning from `source`.
+    pub fn warning(&self, source: &str, message: impl Into<String>) -> bool {
+        self.notify(source, Severity::Warning, message.into())
+    }
+
+    /// Report a critical failure from `source`.
+    pub fn critical(&self, source: &str, message: impl Into<String>) -> bool {
+        self.notify(source, Severity::Critical, message.into())
+    }
+
+    /// Log an alert and deliver it to the webhook unless an identical alert was sent recently.
+    ///
+    /// Returns whether the alert was delivered (or would have been, without a webhook).
+    pub fn notify(&self, source: &str, severity: Severity, message: String) -> bool {
+        match severity {
+            Severity::Warning => log::warn!(target: "alert", "[{source}] {message}"),
+            Severity::Critical => log::error!(target: "alert", "[{source}] {message}"),
+        }
+
+        if !self.should_send(source, &message, Instant::now()) {
+            return false;
+        }
+
+        if let Some(url) = self.webhook_url.clone() {
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let payload = AlertPayload {
+                text: format!("[{source}] {message}"),
+                source,
+                severity,
+                message: &message,
+                timestamp,
+            };
+            let body = match serde_json::to_vec(&payload) {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("Failed to encode alert: {}", e);
+                    return false;
+                }
+            };
+
+            // Deliver in the background so callers never wait on the receiver
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    let request = self
+                        .client
+                        .post(url)
+                        .header("Content-Type", "application/json")
+                        .body(body);
+                    handle.spawn(async move {
+                        if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                            log::warn!("Failed to deliver alert webhook: {}", e);
+                        }
+                    });
+                }
+                Err(_) => log::warn!("No runtime available to deliver alert webhook"),
+            }
+        }
+        true
+    }
+
+    /// Record an attempt and decide whether it is outside the duplicate window.
+    fn should_send(&self, source: &str, message: &str, now: Instant) -> bool {
+        let mut last_sent = self.last_sent.lock_or_recover();
+        let window = self.dedupe_window;
+        last_sent.retain(|_, sent| now.duration_since(*sent) < window);
+
+        let key = (source.to_string(), message.to_string());
+        if last_sent.contains_key(&key) {
+            return false;
+        }
+        last_sent.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_alerts_are_suppressed_within_window() {
+        let sink = AlertSink::new(&AlertConfig {
+            webhook_url: None,
+            dedupe_secs: 60,
+        });
+        let now = Instant::now();
+
+        assert!(sink.should_send("usage", "flush failed", now));
+        assert!(!sink.should_send("usage", "flush failed", now + Duration::from_secs(30)));
+        // Different message or source is not a duplicate
+        assert!(sink.should_send("usage", "disk full", now + Duration::from_secs(30)));
+        assert!(sink.should_send("accounts", "flush failed", now + Duration::from_secs(30)));
+        // Once the window has passed the alert is sent again
+        assert!(sink.should_send("usage", "flush failed", now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn payload_is_slack_compatible() {
+        let payload = AlertPayload {
+            text: "[config] reload failed".to_string(),
+            source: "config",
+            severity: Severity::Critical,
+            message: "reload failed",
+            timestamp: 1,
+        };
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["text"], "[config] reload failed");
+        assert_eq!(json["severity"], "critical");
+    }
+}
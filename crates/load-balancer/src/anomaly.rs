@@ -0,0 +1,273 @@
+//! Per-key traffic anomaly detection.
+//!
+//! A background analyzer looks at the per-minute status counts in [`Metrics`] and flags keys
+//! whose traffic looks like it might come from a leaked credential: a sudden jump in request
+//! rate, a spike in error responses, or traffic from a key that had been dormant for a long
+//! time. Alerts are emitted as structured JSON log lines on the `anomaly` target and sent to
+//! the configured [`AlertSink`].
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+use serde::Serialize;
+
+use crate::accounts::AccountRatelimit;
+use crate::alert::AlertSink;
+use crate::configuration::AnomalyConfig;
+use crate::lb::MISSING_API_KEY;
+use crate::metric::{Metrics, MinuteCounts};
+use crate::sync::MutexExt;
+
+/// Kind of anomalous behaviour detected for a key.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// Requests in the last minute exceeded `factor` times the recent baseline.
+    RpsSpike { requests: u64, baseline: f64 },
+    /// The share of 5xx responses in the last minute crossed the threshold.
+    ErrorRateSpike { requests: u64, error_rate: f64 },
+    /// A key that had not been used for a long time is sending traffic again.
+    DormantKeyActive { last_used_at: String },
+}
+
+/// A single alert for an API key.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Anomaly {
+    /// Key fingerprint (never the raw key).
+    pub key_id: String,
+    /// Minute bucket the anomaly was observed in.
+    pub minute: u64,
+    #[serde(flatten)]
+    pub kind: AnomalyKind,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// Check one key's minute counts for rate and error spikes in `minute`.
+///
+/// The baseline is the average request count over the `baseline_minutes` minutes before
+/// `minute`. Keys with fewer than `min_requests` requests in `minute` are never flagged.
+pub fn detect_spikes(
+    counts: &MinuteCounts,
+    minute: u64,
+    config: &AnomalyConfig,
+) -> Vec<AnomalyKind> {
+    let total = |m: u64| -> u64 { counts.get(&m).map_or(0, |c| c.values().sum()) };
+    let requests = total(minute);
+    if requests < config.min_requests {
+        return Vec::new();
+    }
+
+    let mut found = Vec::new();
+    let window = config.baseline_minutes.max(1);
+    let baseline = (1..=window)
+        .map(|back| total(minute.saturating_sub(back)))
+        .sum::<u64>() as f64
+        / window as f64;
+    if baseline > 0.0 && requests as f64 >= baseline * config.rps_spike_factor {
+        found.push(AnomalyKind::RpsSpike { requests, baseline });
+    }
+
+    let errors: u64 = counts.get(&minute).map_or(0, |c| {
+        c.iter()
+            .filter(|(status, _)| **status >= 500)
+            .map(|(_, n)| *n)
+            .sum()
+    });
+    let error_rate = errors as f64 / requests as f64;
+    if error_rate >= config.error_rate_threshold {
+        found.push(AnomalyKind::ErrorRateSpike {
+            requests,
+            error_rate,
+        });
+    }
+    found
+}
+
+/// Whether a stored `last_used_at` (`YYYY-MM-DD HH:MM:SS`, UTC) is older than `idle`.
+pub fn is_dormant(last_used_at: &str, now: SystemTime, idle: Duration) -> bool {
+    let Ok(last) = chrono::NaiveDateTime::parse_from_str(last_used_at, "%Y-%m-%d %H:%M:%S") else {
+        return false;
+    };
+    let last =
+        SystemTime::UNIX_EPOCH + Duration::from_secs(last.and_utc().timestamp().max(0) as u64);
+    now.duration_since(last).is_ok_and(|elapsed| elapsed > idle)
+}
+
+/// Background service that periodically scans [`Metrics`] for anomalies.
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    metrics: Arc<Metrics>,
+    limiter: Arc<AccountRatelimit>,
+    /// (key, minute, kind) already reported, so each anomaly is alerted once.
+    reported: Mutex<HashSet<(String, u64, &'static str)>>,
+    alerts: Arc<AlertSink>,
+}
+
+impl AnomalyDetector {
+    pub fn new(
+        config: AnomalyConfig,
+        metrics: Arc<Metrics>,
+        limiter: Arc<AccountRatelimit>,
+    ) -> Self {
+        Self {
+            config,
+            metrics,
+            limiter,
+            reported: Mutex::new(HashSet::new()),
+            alerts: Arc::new(AlertSink::default()),
+        }
+    }
+
+    /// Deliver anomalies to `alerts` in addition to the `anomaly` log target.
+    pub fn with_alerts(mut self, alerts: Arc<AlertSink>) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    /// Analyze the last complete minute before `now` and return new anomalies.
+    pub fn scan(&self, now: SystemTime) -> Vec<Anomaly> {
+        let current = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+        let minute = current.saturating_sub(1);
+
+        let mut found = Vec::new();
+        // Metrics are keyed by key fingerprint
+        for key_id in self.metrics.keys() {
+            if key_id == MISSING_API_KEY {
+                continue;
+            }
+            let counts = self.metrics.snapshot(&key_id);
+            let meta = self.limiter.metadata_for_fingerprint(&key_id);
+
+            let mut kinds = detect_spikes(&counts, minute, &self.config);
+            if let Some(last_used_at) = meta.as_ref().and_then(|m| m.last_used_at.as_deref())
+                && counts.get(&minute).is_some_and(|c| !c.is_empty())
+                && is_dormant(last_used_at, now, self.config.idle_after())
+            {
+                kinds.push(AnomalyKind::DormantKeyActive {
+                    last_used_at: last_used_at.to_string(),
+                });
+            }
+
+            for kind in kinds {
+                // Dormant keys are reported once per key rather than once per minute
+                let dedupe = match kind {
+                    AnomalyKind::RpsSpike { .. } => (key_id.clone(), minute, "rps_spike"),
+                    AnomalyKind::ErrorRateSpike { .. } => (key_id.clone(), minute, "error_rate"),
+                    AnomalyKind::DormantKeyActive { .. } => (key_id.clone(), 0, "dormant"),
+                };
+                if self.reported.lock_or_recover().insert(dedupe) {
+                    found.push(Anomaly {
+                        key_id: key_id.clone(),
+                        minute,
+                        kind,
+                    });
+                }
+            }
+        }
+
+        // Forget per-minute entries once they can no longer repeat
+        self.reported
+            .lock_or_recover()
+            .retain(|(_, m, kind)| *kind == "dormant" || *m + 1 >= minute);
+
+        found
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AnomalyDetector {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        let interval = Duration::from_secs(self.config.interval_secs.max(1));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(interval) => {}
+            }
+
+            for anomaly in self.scan(SystemTime::now()) {
+                log::warn!(target: "anomaly", "{anomaly}");
+                self.alerts.warning("anomaly", anomaly.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn counts(per_minute: &[(u64, u16, u64)]) -> MinuteCounts {
+        let mut counts: MinuteCounts = HashMap::new();
+        for (minute, status, n) in per_minute {
+            *counts
+                .entry(*minute)
+                .or_default()
+                .entry(*status)
+                .or_insert(0) += n;
+        }
+        counts
+    }
+
+    #[test]
+    fn rate_spike_over_baseline_is_flagged() {
+        let config = AnomalyConfig {
+            min_requests: 10,
+            baseline_minutes: 2,
+            ..Default::default()
+        };
+        let spiky = counts(&[(8, 200, 10), (9, 200, 10), (10, 200, 150)]);
+        assert_eq!(
+            detect_spikes(&spiky, 10, &config),
+            vec![AnomalyKind::RpsSpike {
+                requests: 150,
+                baseline: 10.0
+            }]
+        );
+
+        let steady = counts(&[(8, 200, 100), (9, 200, 100), (10, 200, 150)]);
+        assert!(detect_spikes(&steady, 10, &config).is_empty());
+    }
+
+    #[test]
+    fn error_rate_spike_is_flagged() {
+        let config = AnomalyConfig {
+            min_requests: 10,
+            ..Default::default()
+        };
+        let errors = counts(&[(10, 200, 4), (10, 503, 6)]);
+        assert_eq!(
+            detect_spikes(&errors, 10, &config),
+            vec![AnomalyKind::ErrorRateSpike {
+                requests: 10,
+                error_rate: 0.6
+            }]
+        );
+        // Below the minimum volume nothing is reported
+        let quiet = counts(&[(10, 503, 3)]);
+        assert!(detect_spikes(&quiet, 10, &config).is_empty());
+    }
+
+    #[test]
+    fn dormant_keys_are_detected() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(200 * 86_400);
+        let idle = Duration::from_secs(90 * 86_400);
+        assert!(is_dormant("1970-01-02 00:00:00", now, idle));
+        assert!(!is_dormant("1970-07-01 00:00:00", now, idle));
+        assert!(!is_dormant("not a timestamp", now, idle));
+    }
+}
@@ -0,0 +1,255 @@
+//! Audit events of API key changes.
+//!
+//! Issuing and revoking keys (`lb keys generate` and `lb keys revoke`) publishes a
+//! structured event to the sink of the `key_audit` section, apart from the request and
+//! audit logs:
+//!
+//! ```yaml
+//! key_audit:
+//!   kind: kafka
+//!   rest_proxy: http://kafka-rest:8082
+//!   topic: lb.key-audit
+//! ```
+//!
+//! A `file` sink appends one JSON object per line, a `webhook` sink POSTs the event as JSON
+//! and a `kafka` sink produces it, keyed by fingerprint, through a Kafka REST proxy. Key
+//! tokens and hashes are never part of an event.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Content type of the Kafka REST proxy v2 API for JSON records.
+const KAFKA_JSON: &str = "application/vnd.kafka.json.v2+json";
+
+/// Where key audit events go.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum KeyAuditConfig {
+    /// JSON lines appended to a file.
+    File { path: String },
+    /// JSON POSTed to a URL.
+    Webhook { url: String },
+    /// Records produced to a topic through a Kafka REST proxy.
+    Kafka { rest_proxy: String, topic: String },
+}
+
+impl KeyAuditConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let empty = match self {
+            KeyAuditConfig::File { path } => path.is_empty(),
+            KeyAuditConfig::Webhook { url } => url.is_empty(),
+            KeyAuditConfig::Kafka { rest_proxy, topic } => {
+                rest_proxy.is_empty() || topic.is_empty()
+            }
+        };
+        if empty {
+            return Err("key_audit has an empty destination".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Change made to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAction {
+    Created,
+    Revoked,
+}
+
+/// One key change, as published.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct KeyAuditEvent {
+    pub action: KeyAction,
+    /// Who made the change.
+    pub actor: String,
+    pub account_id: i64,
+    pub key_id: Uuid,
+    pub fingerprint: String,
+    /// RFC 3339 time of the change.
+    pub timestamp: String,
+}
+
+impl KeyAuditEvent {
+    /// An event for a change made now.
+    pub fn now(
+        action: KeyAction,
+        actor: impl Into<String>,
+        account_id: i64,
+        key_id: Uuid,
+        fingerprint: impl Into<String>,
+    ) -> Self {
+        Self {
+            action,
+            actor: actor.into(),
+            account_id,
+            key_id,
+            fingerprint: fingerprint.into(),
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+}
+
+/// Publishes key audit events to the configured sink.
+#[derive(Debug)]
+pub struct KeyAudit {
+    sink: Sink,
+    client: reqwest::Client,
+}
+
+#[derive(Debug)]
+enum Sink {
+    File(PathBuf),
+    Webhook(String),
+    Kafka(String),
+}
+
+impl KeyAudit {
+    /// A publisher for `config`, with file paths relative to `base`.
+    pub fn new(config: &KeyAuditConfig, base: &Path) -> Self {
+        let sink = match config {
+            KeyAuditConfig::File { path } => Sink::File(base.join(path)),
+            KeyAuditConfig::Webhook { url } => Sink::Webhook(url.clone()),
+            KeyAuditConfig::Kafka { rest_proxy, topic } => Sink::Kafka(format!(
+                "{}/topics/{topic}",
+                rest_proxy.trim_end_matches('/')
+            )),
+        };
+        Self {
+            sink,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Publish `event`, returning once the sink has accepted it.
+    pub async fn publish(&self, event: &KeyAuditEvent) -> Result<(), String> {
+        match &self.sink {
+            Sink::File(path) => {
+                let mut line = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+                line.push(b'\n');
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(&line))
+                    .map_err(|e| format!("failed to append to {}: {e}", path.display()))
+            }
+            Sink::Webhook(url) => self
+                .client
+                .post(url)
+                .json(event)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Sink::Kafka(url) => {
+                let body = serde_json::json!({
+                    "records": [{ "key": event.fingerprint, "value": event }],
+                });
+                self.client
+                    .post(url)
+                    .header("Content-Type", KAFKA_JSON)
+                    .body(body.to_string())
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+
+    use super::*;
+
+    fn event() -> KeyAuditEvent {
+        KeyAuditEvent::now(KeyAction::Created, "ops", 7, Uuid::nil(), "lb_aaaaaaaa")
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_appends_json_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let audit = KeyAudit::new(
+            &KeyAuditConfig::File {
+                path: "keys.jsonl".to_string(),
+            },
+            dir.path(),
+        );
+        let created = event();
+        audit.publish(&created).await.unwrap();
+        let mut revoked = created.clone();
+        revoked.action = KeyAction::Revoked;
+        audit.publish(&revoked).await.unwrap();
+
+        let written = std::fs::read_to_string(dir.path().join("keys.jsonl")).unwrap();
+        let events: Vec<KeyAuditEvent> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events, vec![created, revoked]);
+    }
+
+    #[tokio::test]
+    async fn test_kafka_sink_produces_through_rest_proxy() {
+        let received: Arc<Mutex<Vec<(String, serde_json::Value)>>> = Arc::default();
+        let app = axum::Router::new().route(
+            "/topics/lb.key-audit",
+            post({
+                let received = received.clone();
+                move |headers: HeaderMap, body: String| async move {
+                    let content_type = headers["content-type"].to_str().unwrap().to_string();
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((content_type, serde_json::from_str(&body).unwrap()));
+                    "{}"
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let audit = KeyAudit::new(
+            &KeyAuditConfig::Kafka {
+                rest_proxy: format!("http://{addr}/"),
+                topic: "lb.key-audit".to_string(),
+            },
+            Path::new("."),
+        );
+        audit.publish(&event()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let (content_type, body) = &received[0];
+        assert_eq!(content_type, KAFKA_JSON);
+        assert_eq!(body["records"][0]["key"], "lb_aaaaaaaa");
+        assert_eq!(body["records"][0]["value"]["action"], "created");
+        assert_eq!(body["records"][0]["value"]["account_id"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_failures_are_reported() {
+        let audit = KeyAudit::new(
+            &KeyAuditConfig::Webhook {
+                url: "http://127.0.0.1:1/audit".to_string(),
+            },
+            Path::new("."),
+        );
+        assert!(audit.publish(&event()).await.is_err());
+    }
+}
@@ -0,0 +1,545 @@
+//! Monthly billing from hourly usage files.
+//!
+//! Joins a month of `usage-<YYYYMMDDHH>.db` files with the plans in the accounts DB and
+//! produces one line item per account, plan and service. Each plan's `monthly_quota` is
+//! included; requests beyond it are overage, charged at `price_per_1k_req`. Quota is used up
+//! in request order, so overage is attributed to the services called after it ran out.
+//!
+//! Run as `load-balancer billing compute --month 2024-06`. Line items are written to a
+//! `billing-YYYYMM.db` (table `LineItems`) or, with `--format csv`, a CSV file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::sqlite;
+use crate::usage::{UsageManifest, closed_files};
+
+#[derive(Debug)]
+pub enum BillingError {
+    InvalidMonth(String),
+    UnknownPlan(i64),
+    /// A usage file no longer matches the checksum in its manifest.
+    ChecksumMismatch(String),
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for BillingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BillingError::InvalidMonth(month) => {
+                write!(f, "Invalid month '{}', expected YYYY-MM", month)
+            }
+            BillingError::UnknownPlan(plan_id) => {
+                write!(
+                    f,
+                    "Usage references plan {} missing from the accounts DB",
+                    plan_id
+                )
+            }
+            BillingError::ChecksumMismatch(file) => {
+                write!(
+                    f,
+                    "Usage file {} does not match its manifest checksum",
+                    file
+                )
+            }
+            BillingError::Io(e) => write!(f, "{}", e),
+            BillingError::Sqlite(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BillingError {}
+
+impl From<std::io::Error> for BillingError {
+    fn from(e: std::io::Error) -> Self {
+        BillingError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for BillingError {
+    fn from(e: rusqlite::Error) -> Self {
+        BillingError::Sqlite(e)
+    }
+}
+
+/// A billing month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl Month {
+    /// Parse `YYYY-MM`.
+    pub fn parse(s: &str) -> Result<Self, BillingError> {
+        let date = chrono::NaiveDate::parse_from_str(&format!("{s}-01"), "%Y-%m-%d")
+            .map_err(|_| BillingError::InvalidMonth(s.to_string()))?;
+        Ok(Self {
+            year: chrono::Datelike::year(&date),
+            month: chrono::Datelike::month(&date),
+        })
+    }
+
+    /// `YYYYMM`, as in usage and billing file names.
+    pub fn stamp(&self) -> String {
+        format!("{:04}{:02}", self.year, self.month)
+    }
+
+    /// Default output file name, `billing-YYYYMM.db`.
+    pub fn billing_filename(&self) -> String {
+        format!("billing-{}.db", self.stamp())
+    }
+}
+
+/// Pricing terms of a plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanPrice {
+    pub name: String,
+    pub monthly_quota: u64,
+    pub price_per_1k_req: f64,
+}
+
+/// Requests of one account, plan and service within a minute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub account_id: i64,
+    pub plan_id: i64,
+    pub service: String,
+    /// `YYYY-MM-DD HH:MM:SS` in UTC, as stored in the usage files.
+    pub date_time: String,
+    pub requests: u64,
+    pub data_mb: f64,
+}
+
+/// One invoice line: an account's use of a service under a plan.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineItem {
+    pub account_id: i64,
+    pub plan_id: i64,
+    pub plan_name: String,
+    /// Empty for requests that were not routed to a service.
+    pub service: String,
+    pub requests: u64,
+    /// Requests covered by the plan's monthly quota.
+    pub included_requests: u64,
+    pub overage_requests: u64,
+    pub data_mb: f64,
+    pub price_per_1k_req: f64,
+    /// Overage charge, rounded to cents.
+    pub amount: f64,
+}
+
+/// Result of a billing run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BillingRun {
+    pub line_items: Vec<LineItem>,
+    /// Usage files read.
+    pub files: usize,
+    /// Usage files without a manifest, i.e. hours that were never closed.
+    pub unclosed_files: Vec<String>,
+}
+
+/// Plans from the accounts DB, keyed by plan id.
+pub fn load_plans(accounts_db: &Path) -> Result<HashMap<i64, PlanPrice>, BillingError> {
+    let conn = sqlite::open_read_only(accounts_db)?;
+    let mut stmt =
+        conn.prepare("SELECT plan_id, name, monthly_quota, price_per_1k_req FROM Plans")?;
+    let plans = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                PlanPrice {
+                    name: row.get(1)?,
+                    monthly_quota: row.get::<_, i64>(2)?.max(0) as u64,
+                    price_per_1k_req: row.get(3)?,
+                },
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(plans)
+}
+
+/// Usage rows of one hourly file. Files written before service attribution report an empty
+/// service.
+fn read_usage_file(path: &Path) -> Result<Vec<UsageRow>, rusqlite::Error> {
+    let conn = sqlite::open_read_only(path)?;
+    let has_service: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('Usage') WHERE name = 'service'",
+        [],
+        |row| row.get(0),
+    )?;
+    let service = if has_service { "service" } else { "''" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT account_id, plan_id, {service}, date_time, SUM(total_requests), SUM(total_data_mb) \
+         FROM Usage GROUP BY 1, 2, 3, 4"
+    ))?;
+    stmt.query_map([], |row| {
+        Ok(UsageRow {
+            account_id: row.get(0)?,
+            plan_id: row.get(1)?,
+            service: row.get(2)?,
+            date_time: row.get(3)?,
+            requests: row.get::<_, i64>(4)?.max(0) as u64,
+            data_mb: row.get::<_, Option<f64>>(5)?.unwrap_or(0.0),
+        })
+    })?
+    .collect()
+}
+
+/// Read every usage file of `month` in `usage_dir`.
+///
+/// Files with a manifest must still match its checksum; files without one are read but
+/// reported, since their hour may not be complete.
+pub fn read_month(
+    usage_dir: &Path,
+    month: Month,
+) -> Result<(Vec<UsageRow>, usize, Vec<String>), BillingError> {
+    let manifests: HashMap<String, UsageManifest> = closed_files(usage_dir)?
+        .into_iter()
+        .map(|m| (m.file.clone(), m))
+        .collect();
+    let prefix = format!("usage-{}", month.stamp());
+
+    let mut files: Vec<(String, PathBuf)> = std::fs::read_dir(usage_dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.to_string();
+            (name.starts_with(&prefix) && name.ends_with(".db")).then(|| (name, e.path()))
+        })
+        .collect();
+    files.sort();
+
+    let mut rows = Vec::new();
+    let mut unclosed = Vec::new();
+    for (name, path) in &files {
+        match manifests.get(name) {
+            Some(manifest) => {
+                let digest = hex::encode(Sha256::digest(std::fs::read(path)?));
+                if manifest.sha256.as_deref() != Some(digest.as_str()) {
+                    return Err(BillingError::ChecksumMismatch(name.clone()));
+                }
+            }
+            None => unclosed.push(name.clone()),
+        }
+        rows.extend(read_usage_file(path)?);
+    }
+    Ok((rows, files.len(), unclosed))
+}
+
+fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+/// Price usage rows into line items, ordered by account, plan and service.
+pub fn compute_line_items(
+    mut rows: Vec<UsageRow>,
+    plans: &HashMap<i64, PlanPrice>,
+) -> Result<Vec<LineItem>, BillingError> {
+    // Quota is used up in request order; services break ties within a minute
+    rows.sort_by(|a, b| {
+        (a.account_id, a.plan_id, &a.date_time, &a.service).cmp(&(
+            b.account_id,
+            b.plan_id,
+            &b.date_time,
+            &b.service,
+        ))
+    });
+
+    let mut items: BTreeMap<(i64, i64, String), LineItem> = BTreeMap::new();
+    let mut used: HashMap<(i64, i64), u64> = HashMap::new();
+    for row in rows {
+        let plan = plans
+            .get(&row.plan_id)
+            .ok_or(BillingError::UnknownPlan(row.plan_id))?;
+        let used = used.entry((row.account_id, row.plan_id)).or_default();
+        let included = row.requests.min(plan.monthly_quota.saturating_sub(*used));
+        *used += row.requests;
+
+        let item = items
+            .entry((row.account_id, row.plan_id, row.service.clone()))
+            .or_insert_with(|| LineItem {
+                account_id: row.account_id,
+                plan_id: row.plan_id,
+                plan_name: plan.name.clone(),
+                service: row.service,
+                requests: 0,
+                included_requests: 0,
+                overage_requests: 0,
+                data_mb: 0.0,
+                price_per_1k_req: plan.price_per_1k_req,
+                amount: 0.0,
+            });
+        item.requests += row.requests;
+        item.included_requests += included;
+        item.overage_requests += row.requests - included;
+        item.data_mb += row.data_mb;
+    }
+
+    Ok(items
+        .into_values()
+        .map(|mut item| {
+            item.amount =
+                round_cents(item.overage_requests as f64 * item.price_per_1k_req / 1000.0);
+            item
+        })
+        .collect())
+}
+
+/// Compute line items for `month`.
+pub fn compute(
+    usage_dir: &Path,
+    accounts_db: &Path,
+    month: Month,
+) -> Result<BillingRun, BillingError> {
+    let plans = load_plans(accounts_db)?;
+    let (rows, files, unclosed_files) = read_month(usage_dir, month)?;
+    Ok(BillingRun {
+        line_items: compute_line_items(rows, &plans)?,
+        files,
+        unclosed_files,
+    })
+}
+
+/// Write line items to the `LineItems` table of `path`, replacing a previous run.
+pub fn write_db(path: &Path, items: &[LineItem]) -> Result<(), BillingError> {
+    let mut conn = sqlite::open_wal(path)?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        r#"
+        DROP TABLE IF EXISTS LineItems;
+        CREATE TABLE LineItems (
+            account_id INTEGER NOT NULL,
+            plan_id INTEGER NOT NULL,
+            plan_name TEXT NOT NULL,
+            service TEXT NOT NULL,
+            requests INTEGER NOT NULL,
+            included_requests INTEGER NOT NULL,
+            overage_requests INTEGER NOT NULL,
+            data_mb REAL NOT NULL,
+            price_per_1k_req REAL NOT NULL,
+            amount REAL NOT NULL,
+            PRIMARY KEY (account_id, plan_id, service)
+        );
+        "#,
+    )?;
+    let mut stmt =
+        tx.prepare("INSERT INTO LineItems VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)")?;
+    for item in items {
+        stmt.execute(rusqlite::params![
+            item.account_id,
+            item.plan_id,
+            item.plan_name,
+            item.service,
+            item.requests as i64,
+            item.included_requests as i64,
+            item.overage_requests as i64,
+            item.data_mb,
+            item.price_per_1k_req,
+            item.amount,
+        ])?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(())
+}
+
+/// Quote a CSV field if needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Write line items as CSV with a header row.
+pub fn write_csv(path: &Path, items: &[LineItem]) -> Result<(), BillingError> {
+    let mut out = String::from(
+        "account_id,plan_id,plan_name,service,requests,included_requests,overage_requests,\
+         data_mb,price_per_1k_req,amount\n",
+    );
+    for item in items {
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{},{:.6},{},{:.2}\n",
+            item.account_id,
+            item.plan_id,
+            csv_field(&item.plan_name),
+            csv_field(&item.service),
+            item.requests,
+            item.included_requests,
+            item.overage_requests,
+            item.data_mb,
+            item.price_per_1k_req,
+            item.amount,
+        ));
+    }
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(account_id: i64, service: &str, date_time: &str, requests: u64) -> UsageRow {
+        UsageRow {
+            account_id,
+            plan_id: 1,
+            service: service.to_string(),
+            date_time: date_time.to_string(),
+            requests,
+            data_mb: 0.5,
+        }
+    }
+
+    fn plans() -> HashMap<i64, PlanPrice> {
+        HashMap::from([(
+            1,
+            PlanPrice {
+                name: "Starter".to_string(),
+                monthly_quota: 100,
+                price_per_1k_req: 2.0,
+            },
+        )])
+    }
+
+    #[test]
+    fn test_month_parsing() {
+        let month = Month::parse("2024-06").unwrap();
+        assert_eq!(month.stamp(), "202406");
+        assert_eq!(month.billing_filename(), "billing-202406.db");
+        assert!(Month::parse("2024-13").is_err());
+        assert!(Month::parse("june").is_err());
+    }
+
+    #[test]
+    fn test_overage_goes_to_services_used_after_quota() {
+        let rows = vec![
+            row(1, "routing", "2024-06-02 00:00:00", 1500),
+            row(1, "geocode", "2024-06-01 00:00:00", 80),
+            row(1, "geocode", "2024-06-03 00:00:00", 500),
+            row(2, "geocode", "2024-06-01 00:00:00", 10),
+        ];
+        let items = compute_line_items(rows, &plans()).unwrap();
+
+        let summary: Vec<(i64, &str, u64, u64, f64)> = items
+            .iter()
+            .map(|i| {
+                (
+                    i.account_id,
+                    i.service.as_str(),
+                    i.included_requests,
+                    i.overage_requests,
+                    i.amount,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, "geocode", 80, 500, 1.0),
+                (1, "routing", 20, 1480, 2.96),
+                (2, "geocode", 10, 0, 0.0),
+            ]
+        );
+        assert_eq!(items[0].requests, 580);
+        assert_eq!(items[0].data_mb, 1.0);
+    }
+
+    #[test]
+    fn test_unknown_plan_is_an_error() {
+        let mut usage = row(1, "geocode", "2024-06-01 00:00:00", 1);
+        usage.plan_id = 9;
+        assert!(matches!(
+            compute_line_items(vec![usage], &plans()),
+            Err(BillingError::UnknownPlan(9))
+        ));
+    }
+
+    #[test]
+    fn test_compute_verifies_closed_files() {
+        use crate::usage::{UsageRoute, UsageTracker, UsageWriter, close_hour};
+        use std::sync::Arc;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let accounts_db = dir.path().join("accounts.db");
+        let conn = sqlite::open_wal(&accounts_db).unwrap();
+        conn.execute_batch(include_str!("../sql/accounts.sql"))
+            .unwrap();
+        conn.execute(
+            "INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req) \
+             VALUES ('Starter', 1, 5, 10.0)",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        let tracker = Arc::new(UsageTracker::new());
+        let writer = UsageWriter::new(tracker.clone(), dir.path());
+        let route = UsageRoute {
+            service: "geocode".to_string(),
+            backend: "basic".to_string(),
+            ..Default::default()
+        };
+        // 2024-06-01 00:00 and 01:00 UTC; only the first hour is closed
+        let june = 1_717_200_000;
+        tracker.record(1, uuid::Uuid::nil(), 1, &route, 0, june);
+        tracker.record(1, uuid::Uuid::nil(), 1, &route, 0, june + 3600);
+        writer.flush_all().unwrap();
+        close_hour(dir.path(), june).unwrap();
+
+        let month = Month::parse("2024-06").unwrap();
+        let run = compute(dir.path(), &accounts_db, month).unwrap();
+        assert_eq!(run.files, 2);
+        assert_eq!(run.unclosed_files, vec!["usage-2024060101.db".to_string()]);
+        assert_eq!(run.line_items.len(), 1);
+        assert_eq!(run.line_items[0].overage_requests, 1);
+        assert_eq!(run.line_items[0].amount, 0.01);
+
+        let output = dir.path().join(month.billing_filename());
+        write_db(&output, &run.line_items).unwrap();
+        write_db(&output, &run.line_items).unwrap();
+        let rows: i64 = sqlite::open_read_only(&output)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM LineItems", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+
+        // A closed file changed behind the writer's back is refused
+        rusqlite::Connection::open(dir.path().join("usage-2024060100.db"))
+            .unwrap()
+            .execute("DELETE FROM Usage", [])
+            .unwrap();
+        assert!(matches!(
+            compute(dir.path(), &accounts_db, month),
+            Err(BillingError::ChecksumMismatch(file)) if file == "usage-2024060100.db"
+        ));
+    }
+
+    #[test]
+    fn test_csv_quotes_fields() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("billing.csv");
+        let mut items = compute_line_items(
+            vec![row(1, "geocode", "2024-06-01 00:00:00", 150)],
+            &plans(),
+        )
+        .unwrap();
+        items[0].plan_name = "Starter, annual".to_string();
+        write_csv(&path, &items).unwrap();
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            "1,1,\"Starter, annual\",geocode,150,100,50,0.500000,2,0.10"
+        );
+    }
+}
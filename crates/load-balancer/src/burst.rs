@@ -0,0 +1,170 @@
+//! Burst credits accrued from unused quota.
+//!
+//! For plans with a [`BurstPolicy`], every fixed window in which a key uses less than its
+//! quota earns `unused * accrual_rate` credits, capped at `cap`. Once the key exceeds its
+//! quota in a window, each extra request spends one credit instead of being rejected.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{BurstPolicy, Limit};
+use crate::sync::MutexExt;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub(crate) struct BurstBucket {
+    /// Index of the window `used` belongs to (`now_secs / per_seconds`).
+    window: u64,
+    /// Requests seen in the current window.
+    used: isize,
+    /// Accrued burst credits.
+    credits: f64,
+}
+
+/// Per-key burst credit balances.
+#[derive(Debug, Default)]
+pub struct BurstCredits {
+    buckets: Mutex<HashMap<String, BurstBucket>>,
+}
+
+impl BurstCredits {
+    /// Create an empty credit store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for one request from `key` and decide whether it may proceed.
+    ///
+    /// Requests within the quota are always allowed (and still counted so that unused quota
+    /// can accrue); requests over the quota are allowed only while credits remain. Limits
+    /// without a burst policy are not handled here and always return `false`.
+    pub fn allow(&self, key: &str, limit: &Limit, now_secs: u64) -> bool {
+        let Some(policy) = limit.burst else {
+            return false;
+        };
+        let window = now_secs / limit.per_seconds.max(1);
+
+        let mut buckets = self.buckets.lock_or_recover();
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| BurstBucket {
+                window,
+                ..Default::default()
+            });
+
+        if window > bucket.window {
+            accrue(bucket, &policy, limit.quota, window);
+        }
+
+        bucket.used += 1;
+        if bucket.used <= limit.quota {
+            return true;
+        }
+        if bucket.credits >= 1.0 {
+            bucket.credits -= 1.0;
+            return true;
+        }
+        false
+    }
+
+    /// Requests already counted for `key` in the window containing `now_secs`.
+    pub fn used(&self, key: &str, limit: &Limit, now_secs: u64) -> isize {
+        let window = now_secs / limit.per_seconds.max(1);
+        self.buckets
+            .lock_or_recover()
+            .get(key)
+            .filter(|b| b.window == window)
+            .map_or(0, |b| b.used)
+    }
+
+    /// Every key's bucket, to be saved across restarts.
+    pub(crate) fn export(&self) -> Vec<(String, BurstBucket)> {
+        self.buckets
+            .lock_or_recover()
+            .iter()
+            .map(|(key, bucket)| (key.clone(), bucket.clone()))
+            .collect()
+    }
+
+    /// Put back a saved bucket, unless `key` has counted requests since.
+    pub(crate) fn restore(&self, key: &str, bucket: BurstBucket) {
+        self.buckets
+            .lock_or_recover()
+            .entry(key.to_string())
+            .or_insert(bucket);
+    }
+
+    /// Current credit balance for a key (for diagnostics and tests).
+    pub fn credits(&self, key: &str) -> f64 {
+        self.buckets
+            .lock_or_recover()
+            .get(key)
+            .map_or(0.0, |b| b.credits)
+    }
+}
+
+/// Close out the bucket's window and every idle window up to `window`.
+fn accrue(bucket: &mut BurstBucket, policy: &BurstPolicy, quota: isize, window: u64) {
+    let quota = quota.max(0) as f64;
+    let last_unused = (quota - bucket.used as f64).max(0.0);
+    let idle_windows = (window - bucket.window - 1) as f64;
+    let earned = (last_unused + idle_windows * quota) * policy.accrual_rate;
+
+    bucket.credits = (bucket.credits + earned).min(policy.cap as f64);
+    bucket.window = window;
+    bucket.used = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::LimitSource;
+
+    fn limit(quota: isize, cap: u32, accrual_rate: f64) -> Limit {
+        Limit {
+            quota,
+            per_seconds: 1,
+            burst: Some(BurstPolicy { cap, accrual_rate }),
+            source: LimitSource::Plan,
+        }
+    }
+
+    #[test]
+    fn idle_windows_accrue_credits_up_to_cap() {
+        let credits = BurstCredits::new();
+        let limit = limit(2, 3, 0.5);
+
+        // One request in second 0, then nothing until second 10.
+        assert!(credits.allow("k", &limit, 0));
+        assert!(credits.allow("k", &limit, 10));
+        // 1 unused in window 0 + 9 idle windows * 2 = 19 units * 0.5, capped at 3
+        assert_eq!(credits.credits("k"), 3.0);
+    }
+
+    #[test]
+    fn credits_are_spent_over_quota() {
+        let credits = BurstCredits::new();
+        let limit = limit(1, 2, 1.0);
+
+        assert!(credits.allow("k", &limit, 0));
+        // Second 5: window 0 had 0 unused, 4 idle windows earn 4, capped at 2.
+        assert!(credits.allow("k", &limit, 5));
+        assert!(credits.allow("k", &limit, 5));
+        assert!(credits.allow("k", &limit, 5));
+        assert!(!credits.allow("k", &limit, 5));
+        assert_eq!(credits.credits("k"), 0.0);
+    }
+
+    #[test]
+    fn limits_without_policy_are_not_handled() {
+        let credits = BurstCredits::new();
+        let limit = Limit {
+            quota: 1,
+            per_seconds: 1,
+            burst: None,
+            source: LimitSource::Default,
+        };
+        assert!(!credits.allow("k", &limit, 0));
+    }
+}
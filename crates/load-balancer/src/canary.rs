@@ -0,0 +1,98 @@
+//! Percentage-based traffic split between backend groups of a service, for canaries.
+//!
+//! Backends of a service naming a `group` serve the share of its API keys set by their
+//! `traffic_percent`, and the service's other backends serve the rest:
+//!
+//! ```yaml
+//! backends:
+//!   - service: geocode
+//!     backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+//!   - service: geocode
+//!     group: canary
+//!     traffic_percent: 5
+//!     backend: { type: basic, ip: 10.0.0.9, port: 8080 }
+//! ```
+//!
+//! Each key falls in a bucket by the hash of its fingerprint, so a key keeps its group from
+//! request to request, across nodes and reloads, and raising a group's percent only moves
+//! keys into it. Groups take the buckets in the order of their names. Requests without a
+//! key, and those of accounts with a dedicated pool, are not split.
+
+use std::sync::Arc;
+
+use crate::selection::hash;
+use crate::upstream::ServicePool;
+
+/// Buckets keys are split into; percents are precise to 1/100.
+const BUCKETS: u64 = 10_000;
+
+/// Backends of a service serving a share of its API keys.
+#[derive(Debug, Clone)]
+pub struct TrafficGroup {
+    pub name: String,
+    /// Share of the service's keys served, in percent.
+    pub traffic_percent: f64,
+    pub pool: Arc<ServicePool>,
+}
+
+/// The group of `groups`, in name order, serving the key with `fingerprint`; `None` for
+/// the service's other backends.
+pub fn group_for_key<'a>(
+    groups: &'a [TrafficGroup],
+    fingerprint: &str,
+) -> Option<&'a TrafficGroup> {
+    let bucket = (hash(fingerprint.as_bytes()) % BUCKETS) as f64 * 100.0 / BUCKETS as f64;
+    let mut end = 0.0;
+    groups.iter().find(|group| {
+        end += group.traffic_percent;
+        bucket < end
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::PoolSettings;
+
+    fn group(name: &str, traffic_percent: f64) -> TrafficGroup {
+        TrafficGroup {
+            name: name.to_string(),
+            traffic_percent,
+            pool: Arc::new(ServicePool::new(Vec::new(), PoolSettings::default(), None)),
+        }
+    }
+
+    fn share(groups: &[TrafficGroup], name: &str) -> usize {
+        (0..10_000)
+            .filter(|i| group_for_key(groups, &format!("key-{i}")).is_some_and(|g| g.name == name))
+            .count()
+    }
+
+    #[test]
+    fn test_keys_are_split_by_percent() {
+        let groups = vec![group("canary", 5.0), group("next", 20.0)];
+        let canary = share(&groups, "canary");
+        let next = share(&groups, "next");
+        assert!((400..600).contains(&canary), "{canary}");
+        assert!((1800..2200).contains(&next), "{next}");
+
+        // A key keeps its group
+        let picked = group_for_key(&groups, "key-1").map(|g| g.name.clone());
+        assert_eq!(
+            group_for_key(&groups, "key-1").map(|g| g.name.clone()),
+            picked
+        );
+
+        // Raising the canary's percent keeps the keys it had
+        let raised = vec![group("canary", 10.0)];
+        for i in 0..1000 {
+            let key = format!("key-{i}");
+            if group_for_key(&groups, &key).is_some_and(|g| g.name == "canary") {
+                assert!(group_for_key(&raised, &key).is_some());
+            }
+        }
+
+        assert_eq!(share(&[group("all", 100.0)], "all"), 10_000);
+        assert_eq!(group_for_key(&[], "key-1").map(|g| g.name.clone()), None);
+    }
+}
@@ -0,0 +1,62 @@
+//! Wall-clock time source.
+//!
+//! Minute, hour and month bucketing (metrics, usage files, burst windows) reads the time
+//! through [`Clock`] so tests can drive it across boundaries with a [`TestClock`].
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::sync::MutexExt;
+
+/// Source of the current wall-clock time.
+pub trait Clock: Send + Sync + Debug {
+    fn now(&self) -> SystemTime;
+
+    /// Current Unix timestamp in seconds.
+    fn unix_secs(&self) -> i64 {
+        self.now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs() as i64
+    }
+}
+
+/// The system clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to.
+#[derive(Debug)]
+pub struct TestClock {
+    now: Mutex<SystemTime>,
+}
+
+impl TestClock {
+    /// Start at `secs` after the Unix epoch.
+    pub fn at_unix(secs: u64) -> Self {
+        Self {
+            now: Mutex::new(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock_or_recover() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock_or_recover() += by;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock_or_recover()
+    }
+}
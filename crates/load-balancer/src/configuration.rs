@@ -1,221 +1,1947 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use async_trait::async_trait;
+use pingora::http::RequestHeader;
+use pingora::server::configuration::ServerConf;
 use pingora::services::background::BackgroundService;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::accounts::DEFAULT_READ_THROUGH_LOOKUPS_PER_SEC;
+use crate::admission::OverloadConfig;
+use crate::alert::AlertSink;
+use crate::audit::KeyAuditConfig;
+use crate::canary::TrafficGroup;
+use crate::degradation::DegradationConfig;
+use crate::egress::{EgressProxy, EgressProxyConfig};
+use crate::gossip::GossipConfig;
+use crate::hetzner::HetznerConfig;
+use crate::history::{ConfigHistory, ConfigHistoryConfig};
+use crate::leader::LeaderConfig;
+use crate::openapi::OpenApiSpec;
+use crate::privacy::PrivacyConfig;
+use crate::recorder::RecordingConfig;
+use crate::replay::ReplayProtectionConfig;
+use crate::residency::UsageResidencyConfig;
+use crate::retention::UsageRetentionConfig;
+use crate::routing::{Route, RoutePredicates, RoutingTable, path_regex};
+use crate::selector::{LabelSelector, SelectorError, valid_label};
+use crate::shedding::{LoadSheddingConfig, LoadSheddingError};
+use crate::static_cache::StaticCacheConfig;
+use crate::sync::RwLockExt;
+use crate::tagging::{TaggingConfig, TaggingError};
+use crate::tls::{UpstreamTls, UpstreamTlsConfig};
+use crate::upstream::{
+    ConnectionRecycling, DEFAULT_FAILOVER_THRESHOLD, DnsConfig, LocalityConfig,
+    PassiveHealthConfig, PoolMember, PoolSettings, RetryOn, RetryPolicy, ServicePool, Strategy,
+    UpstreamTimeouts, UpstreamsProvider, is_valid_host, provider_for_backend,
+};
+use crate::wasm_hooks::{HookConfig, WasmHook};
+use crate::xds::XdsConfig;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub backend: String,
     /// Path to the accounts SQLite database for rate limiting.
     pub accounts_db: String,
+    /// Account snapshot written on shutdown and used at startup when the accounts DB cannot
+    /// be loaded. Disabled when unset.
+    #[serde(default)]
+    pub accounts_snapshot: Option<String>,
+    /// File rate limit counters and burst credits are saved to on exit and restored from
+    /// at startup, so a restart does not reset quotas mid-window. Not kept when unset.
+    #[serde(default)]
+    pub limiter_state: Option<String>,
+    /// Degraded mode: start with static limits instead of failing when neither the accounts
+    /// DB nor a snapshot can be loaded. Disabled when unset.
+    #[serde(default)]
+    pub accounts_fallback: Option<FallbackConfig>,
+    /// Additional account stores, each with its own loader and refresh service.
+    #[serde(default)]
+    pub account_partitions: Vec<AccountPartitionConfig>,
+    /// API key populations told apart by token prefix, with their own limits and services.
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    /// Queue between the proxy and the aggregator applying metrics and usage records.
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    /// Pingora runtime settings: worker threads, daemonization and upgrade paths.
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    /// Read-through lookups for keys provisioned since the last refresh.
+    #[serde(default)]
+    pub accounts_read_through: ReadThroughConfig,
     /// Optional directory for hourly usage SQLite files.
     /// Files are named `usage-<YYYYMMDDHH>.db`.
     #[serde(default)]
     pub usage_dir: Option<String>,
+    /// How long usage files are kept and whether they are shredded; kept forever when
+    /// unset.
+    #[serde(default)]
+    pub usage_retention: Option<UsageRetentionConfig>,
+    /// Usage directories of data residency regions, for accounts with a `residency`.
+    #[serde(default)]
+    pub usage_residency: Option<UsageResidencyConfig>,
+    /// Downstream listener settings.
+    #[serde(default)]
+    pub listener: ListenerConfig,
+    /// Request timeout budget propagation.
+    #[serde(default)]
+    pub deadline: DeadlineConfig,
+    /// Routing and limit debug headers for support requests.
+    #[serde(default)]
+    pub debug_headers: DebugHeadersConfig,
+    /// Cache and topology headers added to every response.
+    #[serde(default)]
+    pub diagnostic_headers: DiagnosticHeadersConfig,
+    /// Per-key traffic anomaly detection.
+    #[serde(default)]
+    pub anomaly: AnomalyConfig,
+    /// Periodic log summary of the heaviest keys and accounts.
+    #[serde(default)]
+    pub top_consumers: TopConsumersConfig,
+    /// Operational alert delivery.
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    /// How the proxy keeps serving while the accounts DB, the usage directory or gossip
+    /// peers fail.
+    #[serde(default)]
+    pub degradation: DegradationConfig,
+    /// Sink of the audit events of issued and revoked API keys; none are published when
+    /// unset.
+    #[serde(default)]
+    pub key_audit: Option<KeyAuditConfig>,
+    /// Directory of applied backend config versions; none are kept when unset.
+    #[serde(default)]
+    pub config_history: Option<ConfigHistoryConfig>,
+    /// Log output, rotation and level.
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Admin listener; disabled when unset.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// Proxy listener for service-to-service traffic without API keys; disabled when unset.
+    #[serde(default)]
+    pub internal: Option<InternalListenerConfig>,
+    /// Admission of requests by plan priority beyond a concurrency limit; disabled when unset.
+    #[serde(default)]
+    pub overload: Option<OverloadConfig>,
+    /// Id of this LB node in access logs, metrics, usage records and `X-LB-Node`; the host
+    /// name when unset.
+    #[serde(default)]
+    pub node_id: Option<String>,
+    /// Rate limit counters shared with peer nodes; each node limits alone when unset.
+    #[serde(default)]
+    pub gossip: Option<GossipConfig>,
+    /// Election of the one node running singleton background jobs; every node skips
+    /// them when unset.
+    #[serde(default)]
+    pub leader: Option<LeaderConfig>,
+    /// De-identification of client IPs and request targets in logs and usage; recorded
+    /// as they are when unset.
+    #[serde(default)]
+    pub privacy: Option<PrivacyConfig>,
 }
 
-#[derive(Debug)]
-pub enum ConfigError {
-    UndefinedService(String),
-    UnusedService(String),
+impl ServerConfig {
+    /// Read and parse a server config file.
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<ServerConfig, String> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read server config: {e}"))?;
+        serde_yaml::from_str(&s).map_err(|e| format!("failed to parse server config: {e}"))
+    }
+
+    /// Account partitions, followed by the tenants that have their own accounts DB.
+    pub fn partitions(&self) -> Vec<AccountPartitionConfig> {
+        let tenants = self.tenants.iter().filter_map(|tenant| {
+            Some(AccountPartitionConfig {
+                name: tenant.name.clone(),
+                token_prefix: tenant.token_prefix.clone(),
+                accounts_db: tenant.accounts_db.clone()?,
+            })
+        });
+        self.account_partitions
+            .iter()
+            .cloned()
+            .chain(tenants)
+            .collect()
+    }
+
+    /// The configured node id, or the host name.
+    pub fn node_id(&self) -> String {
+        self.node_id
+            .clone()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(host_name)
+    }
 }
 
-impl fmt::Display for ConfigError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+/// Host name from `HOSTNAME` or `/etc/hostname`, and `localhost` without either.
+pub fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Time-based rotation period.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Index of the rotation period containing `secs`.
+    pub(crate) fn period(&self, secs: u64) -> u64 {
         match self {
-            ConfigError::UndefinedService(s) => {
-                write!(
-                    f,
-                    "Service '{}' referenced in backend but not defined in services",
-                    s
-                )
+            Rotation::Never => 0,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86_400,
+        }
+    }
+}
+
+/// Logging settings.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Application log filter (`info`, `load_balancer=debug,pingora=warn`, ...). `RUST_LOG`
+    /// takes precedence when set.
+    pub level: String,
+    /// Directory for log files; logs go to stderr when unset.
+    pub dir: Option<String>,
+    /// Time-based rotation.
+    pub rotation: Rotation,
+    /// Rotate once a file grows beyond this many megabytes.
+    pub max_size_mb: Option<u64>,
+    /// Rotated files kept per stream.
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            dir: None,
+            rotation: Rotation::Never,
+            max_size_mb: None,
+            max_files: 7,
+        }
+    }
+}
+
+/// Pingora runtime settings.
+///
+/// Unset settings keep pingora's own value, read from its top-level keys of the same file
+/// (`threads`, `daemon`, ...) or its default. `--daemon` on the command line enables
+/// daemonization whatever the config says.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// Worker threads of each service.
+    pub threads: Option<usize>,
+    /// Let idle worker threads of a service take tasks from busy ones.
+    pub work_stealing: Option<bool>,
+    /// Run in the background.
+    pub daemon: Option<bool>,
+    /// Socket the old and new process hand the listeners over through on `--upgrade`.
+    pub upgrade_sock: Option<String>,
+    /// PID file written when running in the background.
+    pub pid_file: Option<String>,
+}
+
+impl RuntimeConfig {
+    /// Check the settings before the server is created. The paths must be absolute, as a
+    /// daemon changes its working directory.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threads == Some(0) {
+            return Err("runtime.threads must be at least 1".to_string());
+        }
+        if cfg!(windows) && self.daemon == Some(true) {
+            return Err("runtime.daemon is not supported on Windows".to_string());
+        }
+        for (name, path) in [
+            ("upgrade_sock", &self.upgrade_sock),
+            ("pid_file", &self.pid_file),
+        ] {
+            let Some(path) = path else { continue };
+            let path = std::path::Path::new(path);
+            if !path.is_absolute() {
+                return Err(format!(
+                    "runtime.{name} must be an absolute path: {}",
+                    path.display()
+                ));
             }
-            ConfigError::UnusedService(s) => {
-                write!(f, "Service '{}' defined but has no backend", s)
+            if let Some(dir) = path.parent()
+                && !dir.is_dir()
+            {
+                return Err(format!(
+                    "runtime.{name}: directory {} does not exist",
+                    dir.display()
+                ));
             }
         }
+        Ok(())
+    }
+
+    /// Override pingora's settings with the ones set here.
+    pub fn apply(&self, conf: &mut ServerConf) {
+        if let Some(threads) = self.threads {
+            conf.threads = threads;
+        }
+        if let Some(work_stealing) = self.work_stealing {
+            conf.work_stealing = work_stealing;
+        }
+        if let Some(daemon) = self.daemon {
+            conf.daemon = daemon;
+        }
+        if let Some(upgrade_sock) = &self.upgrade_sock {
+            conf.upgrade_sock = upgrade_sock.clone();
+        }
+        if let Some(pid_file) = &self.pid_file {
+            conf.pid_file = pid_file.clone();
+        }
     }
 }
 
-impl std::error::Error for ConfigError {}
+/// Settings for the admin HTTP listener.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdminConfig {
+    /// Address to bind, e.g. `127.0.0.1:9090`.
+    pub listen: String,
+    /// Bearer token required on every admin request. Unauthenticated when unset.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Requests per minute allowed per credential: the token, or the client IP for
+    /// requests without it. 0 disables the limit.
+    #[serde(default = "default_admin_requests_per_minute")]
+    pub requests_per_minute: isize,
+    /// Accept control-plane pushes on `/admin/control/push` (see [`crate::control`]).
+    #[serde(default)]
+    pub control_plane: bool,
+    /// Address to accept control-plane pushes over gRPC on, e.g. `127.0.0.1:9091` (see
+    /// [`crate::grpc`]).
+    #[serde(default)]
+    pub control_plane_grpc: Option<String>,
+}
 
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub services: HashMap<String, String>,
-    pub backends: Vec<BackendConfig>,
+fn default_admin_requests_per_minute() -> isize {
+    600
 }
 
-impl Config {
-    pub fn validate(&self) -> Result<(), ConfigError> {
-        let mut used_services: HashSet<&String> = HashSet::new();
+/// Settings for the internal proxy listener.
+///
+/// It routes with the same backend config as the public listener but requires no API key;
+/// callers are rate limited per client IP instead. Bind it to an address only reachable from
+/// the internal network.
+///
+/// Connection, header and keepalive limits take the keys of [`ListenerConfig`] next to
+/// `listen`, and apply to this listener only:
+///
+/// ```yaml
+/// internal:
+///   listen: 10.0.0.5:8081
+///   rps_limit: 1000
+///   max_header_bytes: 16384
+///   keepalive_timeout_secs: 60
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InternalListenerConfig {
+    /// Address to bind, e.g. `10.0.0.5:8081`.
+    pub listen: String,
+    /// Requests per second allowed per client IP.
+    #[serde(default = "default_internal_rps_limit")]
+    pub rps_limit: isize,
+    /// Limits of the listener; its `listen` is unused, the address being `listen` above.
+    #[serde(flatten)]
+    pub limits: ListenerConfig,
+}
 
-        for backend in &self.backends {
-            if !self.services.contains_key(&backend.service) {
-                return Err(ConfigError::UndefinedService(backend.service.clone()));
-            }
-            used_services.insert(&backend.service);
+fn default_internal_rps_limit() -> isize {
+    1000
+}
+
+/// Where operational alerts are sent.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// Webhook receiving JSON alerts (Slack-compatible `text` field). Alerts are only logged
+    /// when unset.
+    pub webhook_url: Option<String>,
+    /// Identical alerts from the same source are sent at most once per this many seconds.
+    pub dedupe_secs: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: None,
+            dedupe_secs: 300,
         }
+    }
+}
 
-        for service in self.services.keys() {
-            if !used_services.contains(service) {
-                return Err(ConfigError::UnusedService(service.clone()));
-            }
+/// A separate accounts DB serving versioned tokens with its own prefix.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccountPartitionConfig {
+    /// Name used in logs and service names.
+    pub name: String,
+    /// Token prefix selecting this partition (`eu` for `eu_v1_...` tokens).
+    pub token_prefix: String,
+    /// Path to the partition's accounts SQLite database.
+    pub accounts_db: String,
+}
+
+/// Keys whose token starts with `<token_prefix>_`, such as a partner's keys.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TenantConfig {
+    /// Name used in logs and service names.
+    pub name: String,
+    /// Token prefix selecting this tenant (`partner` for `partner_v1_...` tokens).
+    pub token_prefix: String,
+    /// Accounts DB of the tenant, loaded as an account partition. Without one the tenant's
+    /// keys are served by the partition or main accounts DB matching its prefix.
+    #[serde(default)]
+    pub accounts_db: Option<String>,
+    /// Requests per second for tenant keys without a plan, instead of the global default.
+    #[serde(default)]
+    pub default_rps_limit: Option<isize>,
+    /// Services the tenant's keys may call; every service when empty.
+    #[serde(default)]
+    pub allowed_services: Vec<String>,
+}
+
+/// Synchronous accounts DB lookups for keys not yet loaded into memory.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReadThroughConfig {
+    /// Look unknown keys up in the DB before applying the default limit.
+    pub enabled: bool,
+    /// How long a key that was not found is not looked up again.
+    pub negative_ttl_secs: u64,
+    /// Maximum number of remembered misses.
+    pub max_negative_entries: usize,
+    /// Lookups per second across all keys of an accounts DB; keys beyond that wait for the
+    /// next refresh.
+    pub max_lookups_per_sec: u32,
+}
+
+impl Default for ReadThroughConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            negative_ttl_secs: 30,
+            max_negative_entries: 10_000,
+            max_lookups_per_sec: DEFAULT_READ_THROUGH_LOOKUPS_PER_SEC,
         }
+    }
+}
 
-        Ok(())
+/// Limits applied while the accounts DB is unavailable.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FallbackConfig {
+    /// Requests per second allowed for keys without account data.
+    pub rps_limit: isize,
+    /// File of key hashes (one hex hash per line) that get `rps_limit`. When unset every key
+    /// does; keys not listed get the restrictive default limit.
+    pub allowlist: Option<String>,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        Self {
+            rps_limit: 10,
+            allowlist: None,
+        }
     }
 }
 
-pub struct ConfigReloader {
-    pub path: String,
-    pub config: Arc<RwLock<Config>>,
+/// Settings for the periodic top consumers log summary.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TopConsumersConfig {
+    /// Log the summary.
+    pub enabled: bool,
+    /// How often to log, in seconds.
+    pub interval_secs: u64,
+    /// Number of keys and accounts listed per ranking.
+    pub n: usize,
 }
 
-#[async_trait]
-impl BackgroundService for ConfigReloader {
-    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
-        loop {
-            // Check for shutdown signal
-            if *shutdown.borrow() {
-                return;
-            }
-            // Wait for 5 seconds or shutdown
-            tokio::select! {
-                _ = shutdown.changed() => {
-                    return;
-                }
-                _ = tokio::time::sleep(Duration::from_secs(5)) => {
-                    // Continue to reload
-                }
-            }
+impl Default for TopConsumersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 300,
+            n: 10,
+        }
+    }
+}
 
-            match std::fs::read_to_string(&self.path) {
-                Ok(s) => match serde_yaml::from_str::<Config>(&s) {
-                    Ok(new_config) => {
-                        if let Err(e) = new_config.validate() {
-                            log::error!("Invalid backend config during reload: {}", e);
-                        } else {
-                            let mut w = self.config.write().unwrap();
-                            *w = new_config;
-                            log::info!("Backend config reloaded successfully");
-                        }
-                    }
-                    Err(e) => log::error!("Failed to parse backend config during reload: {}", e),
-                },
-                Err(e) => log::error!("Failed to read backend config during reload: {}", e),
-            }
+/// Settings for the per-key traffic anomaly analyzer.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    /// Run the analyzer.
+    pub enabled: bool,
+    /// How often to scan, in seconds.
+    pub interval_secs: u64,
+    /// Minimum requests in a minute before a key is analyzed.
+    pub min_requests: u64,
+    /// Number of preceding minutes averaged into the baseline rate.
+    pub baseline_minutes: u64,
+    /// Flag a key when its rate reaches this multiple of the baseline.
+    pub rps_spike_factor: f64,
+    /// Flag a key when this share of its responses are 5xx.
+    pub error_rate_threshold: f64,
+    /// Days without use after which new traffic from a key is flagged.
+    pub idle_days: u64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 60,
+            min_requests: 60,
+            baseline_minutes: 10,
+            rps_spike_factor: 10.0,
+            error_rate_threshold: 0.5,
+            idle_days: 90,
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct BackendConfig {
-    pub service: String,
-    pub backend: Backend,
+impl AnomalyConfig {
+    /// Idle period after which a key is considered dormant.
+    pub fn idle_after(&self) -> Duration {
+        Duration::from_secs(self.idle_days * 86_400)
+    }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
-pub enum Backend {
-    Hetzner {
-        labels: Vec<HashMap<String, String>>,
-        port: u16,
-    },
-    Basic {
-        ip: String,
-        port: u16,
-    },
+/// Settings for propagating the client's timeout budget to upstreams.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DeadlineConfig {
+    /// Honor and forward the budget header.
+    pub enabled: bool,
+    /// Header carrying the remaining budget in milliseconds.
+    pub header: String,
+    /// Time reserved for proxy overhead, subtracted before forwarding.
+    pub overhead_ms: u64,
+    /// Upper bound applied to client-supplied budgets.
+    pub max_ms: Option<u64>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json;
-    use serde_yaml;
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            header: "x-request-timeout".to_string(),
+            overhead_ms: 5,
+            max_ms: None,
+        }
+    }
+}
 
-    #[test]
-    fn test_deserialize_config_yaml() {
-        let yaml_data = r#"
-        services:
-          geocode_suggest: /geocode/suggest
-          geocode_forward: /geocode/forward
-          geocode_reverse: /geocode/reverse
-        backends:
-          - service: geocode_suggest
-            backend:
-              type: hetzner
-              labels:
-                - env: prod
-                  service: geocode
-              port: 8099
-          - service: geocode_forward
-            backend:
-              type: hetzner
-              labels:
-                - env: prod
-                  service: geocode
-              port: 8099
-          - service: geocode_reverse
-            backend:
-              type: hetzner
-              labels:
-                - env: prod
-                  service: geocode
-              port: 8099
-          - service: geocode_reverse
-            backend:
-              type: basic
-              ip: 10.120.32.12
-              port: 8099
-        "#;
+/// Debug headers (`X-LB-Service`, `X-LB-Upstream`, `X-LB-Limit-Source`) added to responses
+/// for requests carrying the debug token.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DebugHeadersConfig {
+    /// Add the headers.
+    pub enabled: bool,
+    /// Request header carrying the token.
+    pub header: String,
+    /// Token that must be sent in `header`; no request gets the headers when unset.
+    pub token: Option<String>,
+}
 
-        let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
-        assert!(config.validate().is_ok());
+impl Default for DebugHeadersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            header: "x-lb-debug".to_string(),
+            token: None,
+        }
+    }
+}
 
-        assert_eq!(config.services.len(), 3);
-        assert_eq!(config.backends.len(), 4);
+impl DebugHeadersConfig {
+    /// Whether a request with `value` in the token header gets debug headers.
+    pub fn allows(&self, value: Option<&str>) -> bool {
+        self.enabled
+            && self
+                .token
+                .as_deref()
+                .is_some_and(|token| !token.is_empty() && value == Some(token))
+    }
+}
 
-        // Check first backend
-        let b1 = &config.backends[0];
-        assert_eq!(b1.service, "geocode_suggest");
-        assert_eq!(
-            config.services.get(&b1.service).map(|s| s.as_str()),
-            Some("/geocode/suggest")
-        );
-        if let Backend::Hetzner { labels, port } = &b1.backend {
-            assert_eq!(*port, 8099);
-            assert_eq!(labels.len(), 1);
-            assert_eq!(labels[0].get("env").map(|s| s.as_str()), Some("prod"));
-            assert_eq!(
-                labels[0].get("service").map(|s| s.as_str()),
-                Some("geocode")
-            );
-        } else {
-            panic!("Expected Hetzner backend");
+/// How much of the LB's view of a request its responses reveal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticVerbosity {
+    /// No diagnostic headers.
+    #[default]
+    None,
+    /// `X-Cache` and `X-LB-Node`, which reveal nothing of the upstream topology.
+    Minimal,
+    /// `X-LB-Service` and `X-LB-Upstream` as well.
+    Full,
+}
+
+/// Diagnostic headers added to every response, unlike the token-gated debug headers.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiagnosticHeadersConfig {
+    pub verbosity: DiagnosticVerbosity,
+}
+
+/// Requests whose path matches no service go to `fallback_service` when set, and are
+/// answered with a 404 otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RouteMissConfig {
+    /// Service receiving unmatched requests.
+    pub fallback_service: Option<String>,
+    /// Body of the 404 response, replacing the default JSON error.
+    pub body: Option<String>,
+    /// Content type of `body`.
+    pub content_type: Option<String>,
+}
+
+/// Response to requests for services in maintenance.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// Seconds clients are told to wait before retrying.
+    pub retry_after_secs: u64,
+    /// Body of the 503 response, replacing the default JSON error.
+    pub body: Option<String>,
+    /// Content type of `body`.
+    pub content_type: Option<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            retry_after_secs: 300,
+            body: None,
+            content_type: None,
         }
+    }
+}
 
-        // Check last backend
-        let b4 = &config.backends[3];
-        assert_eq!(b4.service, "geocode_reverse");
-        assert_eq!(
-            config.services.get(&b4.service).map(|s| s.as_str()),
-            Some("/geocode/reverse")
-        );
-        if let Backend::Basic { ip, port } = &b4.backend {
-            assert_eq!(ip, "10.120.32.12");
-            assert_eq!(*port, 8099);
-        } else {
-            panic!("Expected Basic backend");
+/// Response a service answers with when its backends, fallback ones included, fail a
+/// request.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FallbackResponseConfig {
+    /// Status of the response.
+    pub status: u16,
+    /// Body of the response, replacing the default JSON error.
+    pub body: Option<String>,
+    /// Content type of `body`.
+    pub content_type: Option<String>,
+}
+
+impl Default for FallbackResponseConfig {
+    fn default() -> Self {
+        Self {
+            status: 503,
+            body: None,
+            content_type: None,
         }
     }
+}
 
-    #[test]
-    fn test_deserialize_config() {
-        let json_data = r#"
+/// Connection-level limits applied to the public listener.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ListenerConfig {
+    /// Address of the proxy listener, `0.0.0.0:8080` when unset. `[::]:8080` accepts IPv4
+    /// and IPv6 clients unless `ipv6_only` is set.
+    #[serde(default)]
+    pub listen: Option<String>,
+    /// Restrict an IPv6 listen address to IPv6 clients; the system default when unset.
+    #[serde(default)]
+    pub ipv6_only: Option<bool>,
+    /// Maximum number of concurrent downstream connections.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Maximum number of concurrent downstream connections from a single client IP.
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum total size of request headers in bytes (431 when exceeded).
+    #[serde(default)]
+    pub max_header_bytes: Option<usize>,
+    /// Maximum number of request headers (431 when exceeded).
+    #[serde(default)]
+    pub max_header_count: Option<usize>,
+    /// Maximum length of the request URI in bytes (414 when exceeded).
+    #[serde(default)]
+    pub max_uri_length: Option<usize>,
+    /// Seconds an idle keepalive connection waits for its next request; 0 disables
+    /// keepalive. Pingora keeps idle connections open indefinitely when unset.
+    #[serde(default)]
+    pub keepalive_timeout_secs: Option<u64>,
+    /// Close a downstream connection after this many requests.
+    #[serde(default)]
+    pub max_requests_per_connection: Option<u64>,
+    /// Seconds a read from or write to the client may stall within a request before the
+    /// connection is dropped.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    UndefinedService(String),
+    UnusedService(String),
+    InvalidLabelSelector(String, SelectorError),
+    UndefinedFallbackService(String),
+    /// Two services (named in order) route the same path prefix with the same predicates.
+    DuplicateServicePath(String, String, String),
+    /// The `match` predicates of a service are malformed.
+    InvalidRoutePredicates(String, String),
+    /// A service has both or neither of `path` and `path_regex`, an invalid `path_regex`,
+    /// a `rewrite` without one, or conflicting `strip_prefix` and `rewrite_prefix`.
+    InvalidServicePath(String, String),
+    /// A service has several backends in the same priority tier without explicit weights.
+    AmbiguousBackends(String, u32),
+    /// A static label of a service (service, key, value) is reserved or malformed.
+    InvalidServiceLabel(String, String, String),
+    InvalidTagging(TaggingError),
+    /// The OpenAPI spec of a service cannot be loaded.
+    InvalidOpenApiSpec(String, String),
+    InvalidLoadShedding(String, LoadSheddingError),
+    /// The `replay_protection` of a service has an invalid header or a zero TTL.
+    InvalidReplayProtection(String, String),
+    /// A `basic` backend of a service has an address that is neither an IP nor a host name.
+    InvalidBackendHost(String, String),
+    /// A `static` backend of a service lists no endpoints, or one that is not `ip:port`.
+    InvalidBackendEndpoint(String, String),
+    /// The egress proxy of a backend of a service is misconfigured.
+    InvalidEgressProxy(String, String),
+    /// The TLS settings of a backend of a service are invalid, or its CAs failed to load.
+    InvalidUpstreamTls(String, String),
+    /// A hook has no name or a name taken by another, or its module failed to load.
+    InvalidHook(String, String),
+    /// A timeout setting of a backend of a service is zero.
+    ZeroBackendTimeout(String, &'static str),
+    /// The `timeout_ms` of a service is zero.
+    ZeroServiceTimeout(String),
+    /// A service has an `xds` backend but no `xds.server` is configured.
+    MissingXdsServer(String),
+    /// A shadow backend of a service names a dedicated pool.
+    DedicatedShadowBackend(String),
+    /// The traffic groups of a service are misconfigured.
+    InvalidTrafficGroup(String, String),
+    /// A fallback backend of a service is also a shadow, in a traffic group or in a
+    /// dedicated pool.
+    InvalidFallbackBackend(String),
+    /// The `fallback_response` of a service has a status that is not an error.
+    InvalidFallbackResponse(String, u16),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UndefinedService(s) => {
+                write!(
+                    f,
+                    "Service '{}' referenced in backend but not defined in services",
+                    s
+                )
+            }
+            ConfigError::UnusedService(s) => {
+                write!(f, "Service '{}' defined but has no backend", s)
+            }
+            ConfigError::InvalidLabelSelector(s, e) => {
+                write!(f, "Invalid labels on a backend of service '{}': {}", s, e)
+            }
+            ConfigError::UndefinedFallbackService(s) => {
+                write!(f, "Fallback service '{}' is not defined in services", s)
+            }
+            ConfigError::DuplicateServicePath(path, a, b) => {
+                write!(f, "Services '{}' and '{}' both route path '{}'", a, b, path)
+            }
+            ConfigError::InvalidServicePath(s, e) => {
+                write!(f, "Invalid path of service '{}': {}", s, e)
+            }
+            ConfigError::InvalidRoutePredicates(s, e) => {
+                write!(f, "Invalid match of service '{}': {}", s, e)
+            }
+            ConfigError::AmbiguousBackends(s, priority) => {
+                write!(
+                    f,
+                    "Service '{}' has several backends with priority {}; set a weight on each",
+                    s, priority
+                )
+            }
+            ConfigError::InvalidServiceLabel(s, key, value) => {
+                write!(f, "Invalid label '{}={}' on service '{}'", key, value, s)
+            }
+            ConfigError::InvalidTagging(e) => write!(f, "Invalid tagging: {}", e),
+            ConfigError::InvalidOpenApiSpec(s, e) => {
+                write!(f, "Invalid OpenAPI spec of service '{}': {}", s, e)
+            }
+            ConfigError::InvalidLoadShedding(s, e) => {
+                write!(f, "Invalid load shedding of service '{}': {}", s, e)
+            }
+            ConfigError::InvalidReplayProtection(s, e) => {
+                write!(f, "Invalid replay protection of service '{}': {}", s, e)
+            }
+            ConfigError::InvalidBackendHost(s, host) => {
+                write!(
+                    f,
+                    "Invalid ip '{}' on a backend of service '{}'; expected an IP address or host name without a port",
+                    host, s
+                )
+            }
+            ConfigError::ZeroBackendTimeout(s, name) => {
+                write!(f, "{} of a backend of service '{}' must not be 0", name, s)
+            }
+            ConfigError::ZeroServiceTimeout(s) => {
+                write!(f, "timeout_ms of service '{}' must not be 0", s)
+            }
+            ConfigError::DedicatedShadowBackend(s) => {
+                write!(
+                    f,
+                    "A shadow backend of service '{}' cannot belong to a dedicated pool",
+                    s
+                )
+            }
+            ConfigError::InvalidTrafficGroup(s, e) => {
+                write!(f, "Invalid traffic groups for service '{}': {}", s, e)
+            }
+            ConfigError::InvalidFallbackBackend(s) => {
+                write!(
+                    f,
+                    "A fallback backend of service '{}' cannot be a shadow, in a traffic group \
+                     or in a dedicated pool",
+                    s
+                )
+            }
+            ConfigError::InvalidFallbackResponse(s, status) => {
+                write!(
+                    f,
+                    "fallback_response of service '{}' has status {}, not one from 400 to 599",
+                    s, status
+                )
+            }
+            ConfigError::MissingXdsServer(s) => {
+                write!(
+                    f,
+                    "Service '{}' has an xds backend but xds.server is not set",
+                    s
+                )
+            }
+            ConfigError::InvalidBackendEndpoint(s, endpoint) if endpoint.is_empty() => {
+                write!(f, "A static backend of service '{}' has no endpoints", s)
+            }
+            ConfigError::InvalidBackendEndpoint(s, endpoint) => {
+                write!(
+                    f,
+                    "Invalid endpoint '{}' on a backend of service '{}'; expected ip:port",
+                    endpoint, s
+                )
+            }
+            ConfigError::InvalidEgressProxy(s, e) => {
+                write!(
+                    f,
+                    "Invalid egress proxy on a backend of service '{}': {}",
+                    s, e
+                )
+            }
+            ConfigError::InvalidUpstreamTls(s, e) => {
+                write!(f, "Invalid TLS on a backend of service '{}': {}", s, e)
+            }
+            ConfigError::InvalidHook(name, e) => {
+                write!(f, "Invalid request hook '{}': {}", name, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// How requests to a service are authenticated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// Requests must carry a known API key and are rate limited per key.
+    #[default]
+    ApiKey,
+    /// Public route: no API key required, rate limited per client IP instead.
+    None,
+}
+
+const DEFAULT_IP_RPS_LIMIT: isize = 10;
+
+/// A routed service.
+///
+/// Written either as just the path prefix (`geocode: /geocode`) or as a map with `path` (or
+/// `path_regex` and optionally `rewrite`) and the optional `strip_prefix` or `rewrite_prefix`,
+/// `match`, `auth`, `ip_rps_limit`, `strategy`, `hash_header`, `failover_threshold`,
+/// `slow_start_secs`, `timeout_ms`, `retry_budget`, `labels`, `static_cache`, `openapi`, `load_shedding`,
+/// `replay_protection`, `reject_truncated`, `maintenance` and `fallback_response` settings.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "ServiceRepr")]
+pub struct ServiceConfig {
+    /// Path prefix routed to the service; empty for services routed by `path_regex`.
+    pub path: String,
+    /// Regex the whole path of the service's requests matches, instead of a prefix.
+    pub path_regex: Option<String>,
+    /// Upstream path of requests matching `path_regex`, with `$n` or `${name}` standing
+    /// for its captures.
+    pub rewrite: Option<String>,
+    /// Drop `path` from the upstream path of the service's requests.
+    pub strip_prefix: bool,
+    /// Replace `path` with this prefix in the upstream path of the service's requests.
+    pub rewrite_prefix: Option<String>,
+    /// Methods, headers and query parameters requests must have besides the path prefix.
+    pub predicates: RoutePredicates,
+    /// Authentication required for the service.
+    pub auth: AuthMode,
+    /// Requests per second allowed per client IP when `auth` is `none`.
+    pub ip_rps_limit: isize,
+    /// How requests are spread over the service's endpoints.
+    pub strategy: Strategy,
+    /// Header whose value `consistent_hash` hashes instead of the API key.
+    pub hash_header: Option<String>,
+    /// Share of a backend priority tier's weight that must be healthy for it to take all
+    /// traffic.
+    pub failover_threshold: f64,
+    /// Seconds over which endpoints that newly appear in the service's backends ramp up to
+    /// their full weight, for backends not setting their own; 0 gives them full weight.
+    pub slow_start_secs: u64,
+    /// Time a request to the service may take from its arrival, retries included; a 504 once
+    /// it is spent.
+    pub timeout_ms: Option<u64>,
+    /// Retries a request to the service may take in total, whatever its backends allow.
+    pub retry_budget: Option<u32>,
+    /// Static labels (team, tier, ...) added to the service's metrics, access log lines and
+    /// usage records.
+    pub labels: BTreeMap<String, String>,
+    /// Serve the service's responses from the LB's cache; for caller independent content
+    /// only.
+    pub static_cache: Option<StaticCacheConfig>,
+    /// OpenAPI spec file; requests for operations it does not document are rejected.
+    pub openapi: Option<String>,
+    /// Shed requests while the service's upstreams are saturated.
+    pub load_shedding: Option<LoadSheddingConfig>,
+    /// Reject deliveries whose signature or nonce header was seen before (webhook receivers).
+    pub replay_protection: Option<ReplayProtectionConfig>,
+    /// Keep upstream responses whose body disagrees with their `Content-Length` from
+    /// clients; see [`crate::integrity`].
+    pub reject_truncated: bool,
+    /// Answer the service's requests with a 503 instead of proxying them, as set by
+    /// [`Config::maintenance`].
+    pub maintenance: bool,
+    /// Response to requests its backends fail, instead of the upstream's error; see
+    /// [`crate::fallback`].
+    pub fallback_response: Option<FallbackResponseConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServiceRepr {
+    Path(String),
+    // Boxed, being many times the size of a path
+    Full(Box<FullServiceRepr>),
+}
+
+#[derive(Deserialize)]
+struct FullServiceRepr {
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    path_regex: Option<String>,
+    #[serde(default)]
+    rewrite: Option<String>,
+    #[serde(default)]
+    strip_prefix: bool,
+    #[serde(default)]
+    rewrite_prefix: Option<String>,
+    #[serde(default, rename = "match")]
+    predicates: RoutePredicates,
+    #[serde(default)]
+    auth: AuthMode,
+    #[serde(default)]
+    ip_rps_limit: Option<isize>,
+    #[serde(default)]
+    strategy: Strategy,
+    #[serde(default)]
+    hash_header: Option<String>,
+    #[serde(default)]
+    failover_threshold: Option<f64>,
+    #[serde(default)]
+    slow_start_secs: u64,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    retry_budget: Option<u32>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    static_cache: Option<StaticCacheConfig>,
+    #[serde(default)]
+    openapi: Option<String>,
+    #[serde(default)]
+    load_shedding: Option<LoadSheddingConfig>,
+    #[serde(default)]
+    replay_protection: Option<ReplayProtectionConfig>,
+    #[serde(default)]
+    reject_truncated: bool,
+    #[serde(default)]
+    maintenance: bool,
+    #[serde(default)]
+    fallback_response: Option<FallbackResponseConfig>,
+}
+
+impl From<ServiceRepr> for ServiceConfig {
+    fn from(repr: ServiceRepr) -> Self {
+        let full = match repr {
+            ServiceRepr::Path(path) => {
+                return Self {
+                    path,
+                    path_regex: None,
+                    rewrite: None,
+                    strip_prefix: false,
+                    rewrite_prefix: None,
+                    predicates: RoutePredicates::default(),
+                    auth: AuthMode::default(),
+                    ip_rps_limit: DEFAULT_IP_RPS_LIMIT,
+                    strategy: Strategy::default(),
+                    hash_header: None,
+                    failover_threshold: DEFAULT_FAILOVER_THRESHOLD,
+                    slow_start_secs: 0,
+                    timeout_ms: None,
+                    retry_budget: None,
+                    labels: BTreeMap::new(),
+                    static_cache: None,
+                    openapi: None,
+                    load_shedding: None,
+                    replay_protection: None,
+                    reject_truncated: false,
+                    maintenance: false,
+                    fallback_response: None,
+                };
+            }
+            ServiceRepr::Full(full) => *full,
+        };
+        Self {
+            path: full.path,
+            path_regex: full.path_regex,
+            rewrite: full.rewrite,
+            strip_prefix: full.strip_prefix,
+            rewrite_prefix: full.rewrite_prefix,
+            predicates: full.predicates,
+            auth: full.auth,
+            ip_rps_limit: full.ip_rps_limit.unwrap_or(DEFAULT_IP_RPS_LIMIT),
+            strategy: full.strategy,
+            hash_header: full.hash_header.map(|header| header.to_ascii_lowercase()),
+            failover_threshold: full
+                .failover_threshold
+                .unwrap_or(DEFAULT_FAILOVER_THRESHOLD),
+            slow_start_secs: full.slow_start_secs,
+            timeout_ms: full.timeout_ms,
+            retry_budget: full.retry_budget,
+            labels: full.labels,
+            static_cache: full.static_cache,
+            openapi: full.openapi,
+            load_shedding: full.load_shedding,
+            replay_protection: full.replay_protection,
+            reject_truncated: full.reject_truncated,
+            maintenance: full.maintenance,
+            fallback_response: full.fallback_response,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    pub services: HashMap<String, ServiceConfig>,
+    pub backends: Vec<BackendConfig>,
+    /// Discovery settings for `hetzner` backends.
+    #[serde(default)]
+    pub hetzner: HetznerConfig,
+    /// Resolution of `basic` backends given by host name.
+    #[serde(default)]
+    pub dns: DnsConfig,
+    /// Control plane of `xds` backends.
+    #[serde(default)]
+    pub xds: XdsConfig,
+    /// Zone of this node, whose endpoints are preferred.
+    #[serde(default)]
+    pub locality: LocalityConfig,
+    /// Ejection of endpoints that stop answering.
+    #[serde(default)]
+    pub passive_health: PassiveHealthConfig,
+    /// Handling of requests whose path matches no service.
+    #[serde(default)]
+    pub route_miss: RouteMissConfig,
+    /// Response to requests for services in maintenance.
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// Tags set on requests by rules, and the limits, services and log sampling they select.
+    #[serde(default)]
+    pub tagging: TaggingConfig,
+    /// Request hooks run from WebAssembly modules, in order.
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// Modules of `hooks`, loaded by [`Config::load_hooks`].
+    #[serde(skip)]
+    pub request_hooks: Vec<Arc<WasmHook>>,
+    /// Routes by path, compiled by [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub routes: RoutingTable,
+    /// Shared backends of each service, resolved by [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub pools: HashMap<String, Arc<ServicePool>>,
+    /// Backends of each service by dedicated pool, resolved by [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub dedicated_pools: HashMap<String, HashMap<String, Arc<ServicePool>>>,
+    /// Shadow backends of each service, resolved by [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub shadow_pools: HashMap<String, Arc<ServicePool>>,
+    /// Traffic groups of each service in name order, resolved by
+    /// [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub traffic_groups: HashMap<String, Vec<TrafficGroup>>,
+    /// Fallback backends of each service, resolved by [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub fallback_pools: HashMap<String, Arc<ServicePool>>,
+    /// OpenAPI specs of services, loaded by [`Config::load_openapi_specs`].
+    #[serde(skip)]
+    pub openapi: HashMap<String, Arc<OpenApiSpec>>,
+    /// Hash of the file the config was loaded from, telling nodes' configs apart.
+    #[serde(skip)]
+    pub version: String,
+}
+
+impl Config {
+    /// Read, parse and validate a backend config file.
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Config, String> {
+        Self::load_with_source(path).map(|(config, _)| config)
+    }
+
+    /// [`Config::load`], also returning the text of the file.
+    pub fn load_with_source(path: impl AsRef<std::path::Path>) -> Result<(Config, String), String> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read backend config: {e}"))?;
+        let config = Self::parse(&s)?;
+        Ok((config, s))
+    }
+
+    /// Parse and validate the text of a backend config.
+    pub fn parse(s: &str) -> Result<Config, String> {
+        let mut config: Config =
+            serde_yaml::from_str(s).map_err(|e| format!("failed to parse backend config: {e}"))?;
+        config
+            .validate()
+            .and_then(|()| config.load_openapi_specs())
+            .and_then(|()| config.load_upstream_tls())
+            .and_then(|()| config.load_hooks())
+            .map_err(|e| format!("invalid backend config: {e}"))?;
+        config.resolve_upstreams(None);
+        config.version = hex::encode(Sha256::digest(s.as_bytes()))[..12].to_string();
+        Ok(config)
+    }
+
+    /// Resolve every backend to its upstreams provider, group them into service pools and
+    /// compile the routing table over them. Providers of `previous` are kept for backends
+    /// whose definition did not change, so discovered endpoints survive a reload, and pools
+    /// continue their selection state.
+    pub fn resolve_upstreams(&mut self, previous: Option<&Config>) {
+        for backend in &mut self.backends {
+            let kept = previous
+                .filter(|previous| {
+                    previous.hetzner == self.hetzner
+                        && previous.dns == self.dns
+                        && previous.xds == self.xds
+                })
+                .and_then(|previous| {
+                    previous
+                        .backends
+                        .iter()
+                        .find(|b| b.service == backend.service && b.backend == backend.backend)
+                })
+                .and_then(|b| b.upstreams.clone());
+            backend.upstreams = Some(kept.unwrap_or_else(|| {
+                provider_for_backend(&backend.backend, &self.hetzner, &self.dns, &self.xds)
+            }));
+        }
+
+        let mut members: HashMap<PoolKey, Vec<PoolMember>> = HashMap::new();
+        for backend in &self.backends {
+            if let Some(upstreams) = &backend.upstreams {
+                let slow_start_secs = backend.slow_start_secs.unwrap_or_else(|| {
+                    self.services
+                        .get(&backend.service)
+                        .map_or(0, |s| s.slow_start_secs)
+                });
+                members
+                    .entry(backend.pool_key())
+                    .or_default()
+                    .push(PoolMember {
+                        upstreams: upstreams.clone(),
+                        weight: backend.weight(),
+                        slow_start: Duration::from_secs(slow_start_secs),
+                        priority: backend.priority,
+                        recycling: backend.recycling(),
+                        egress: backend
+                            .egress_proxy
+                            .clone()
+                            .map(|config| Arc::new(EgressProxy::new(config))),
+                        tls: backend.upstream_tls.clone(),
+                        retry: backend.retry(),
+                        timeouts: backend.timeouts(),
+                        zone: backend.zone.clone(),
+                    });
+            }
+        }
+        self.pools.clear();
+        self.dedicated_pools.clear();
+        self.shadow_pools.clear();
+        self.traffic_groups.clear();
+        self.fallback_pools.clear();
+        let local_zone = self.locality.preferred_zone();
+        for ((service, pool, shadow, group, fallback), members) in members {
+            let previous = previous.and_then(|p| match (pool, shadow, group, fallback) {
+                (_, true, _, _) => p.shadow_pools.get(service),
+                (_, _, _, true) => p.fallback_pools.get(service),
+                (_, _, Some(group), _) => p
+                    .traffic_groups
+                    .get(service)?
+                    .iter()
+                    .find(|g| &g.name == group)
+                    .map(|g| &g.pool),
+                (Some(pool), false, None, false) => p.dedicated_pools.get(service)?.get(pool),
+                (None, false, None, false) => p.pools.get(service),
+            });
+            let service_config = self.services.get(service);
+            let settings = PoolSettings {
+                strategy: service_config.map_or_else(Strategy::default, |s| s.strategy),
+                failover_threshold: service_config
+                    .map_or(DEFAULT_FAILOVER_THRESHOLD, |s| s.failover_threshold),
+                health: self.passive_health.clone(),
+                hash_header: service_config.and_then(|s| s.hash_header.clone()),
+                local_zone: local_zone.clone(),
+            };
+            let resolved = Arc::new(ServicePool::new(
+                members,
+                settings,
+                previous.map(|p| p.as_ref()),
+            ));
+            match (pool, shadow, group, fallback) {
+                // Validation keeps shadow and fallback backends and groups apart, and out of
+                // dedicated pools
+                (_, true, _, _) => {
+                    self.shadow_pools.insert(service.clone(), resolved);
+                }
+                (_, _, _, true) => {
+                    self.fallback_pools.insert(service.clone(), resolved);
+                }
+                (_, _, Some(group), _) => {
+                    let traffic_percent = self
+                        .backends
+                        .iter()
+                        .find(|b| &b.service == service && b.group.as_ref() == Some(group))
+                        .and_then(|b| b.traffic_percent)
+                        .unwrap_or_default();
+                    self.traffic_groups
+                        .entry(service.clone())
+                        .or_default()
+                        .push(TrafficGroup {
+                            name: group.clone(),
+                            traffic_percent,
+                            pool: resolved,
+                        });
+                }
+                (Some(pool), false, None, false) => {
+                    self.dedicated_pools
+                        .entry(service.clone())
+                        .or_default()
+                        .insert(pool.clone(), resolved);
+                }
+                (None, false, None, false) => {
+                    self.pools.insert(service.clone(), resolved);
+                }
+            }
+        }
+        for groups in self.traffic_groups.values_mut() {
+            groups.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        self.compile_routes();
+    }
+
+    /// Read the OpenAPI spec of every service that has one. Routes compiled afterwards
+    /// validate requests against them.
+    pub fn load_openapi_specs(&mut self) -> Result<(), ConfigError> {
+        self.openapi.clear();
+        for (name, service) in &self.services {
+            if let Some(path) = &service.openapi {
+                let spec = OpenApiSpec::load(path).map_err(|e| {
+                    ConfigError::InvalidOpenApiSpec(name.clone(), format!("{path}: {e}"))
+                })?;
+                self.openapi.insert(name.clone(), Arc::new(spec));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the CAs of backends reached over TLS.
+    pub fn load_upstream_tls(&mut self) -> Result<(), ConfigError> {
+        for backend in &mut self.backends {
+            backend.upstream_tls = match &backend.tls {
+                Some(tls) => Some(Arc::new(UpstreamTls::load(tls).map_err(|e| {
+                    ConfigError::InvalidUpstreamTls(backend.service.clone(), e)
+                })?)),
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Compile the modules of the request hooks.
+    pub fn load_hooks(&mut self) -> Result<(), ConfigError> {
+        let mut names = HashSet::new();
+        self.request_hooks = self
+            .hooks
+            .iter()
+            .map(|hook| {
+                if hook.name.is_empty() || !names.insert(hook.name.as_str()) {
+                    return Err(ConfigError::InvalidHook(
+                        hook.name.clone(),
+                        "hook names must be unique and not empty".to_string(),
+                    ));
+                }
+                WasmHook::load(hook)
+                    .map(Arc::new)
+                    .map_err(|e| ConfigError::InvalidHook(hook.name.clone(), e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Build the routing table from `services` and the current pools.
+    pub fn compile_routes(&mut self) {
+        self.routes = RoutingTable::new(self);
+    }
+
+    /// The service with the longest path prefix matching `path`.
+    pub fn service_for_path(&self, path: &str) -> Option<(&String, &ServiceConfig)> {
+        self.services
+            .get_key_value(&self.routes.resolve(path)?.service)
+    }
+
+    /// The route a request for `path` takes regardless of its method, headers and query:
+    /// the service without predicates matching the path, or the configured fallback
+    /// service.
+    pub fn route(&self, path: &str) -> Option<&Arc<Route>> {
+        self.routes.resolve(path).or(self.routes.fallback())
+    }
+
+    /// The route `req` takes: the service matching its path and predicates, or the
+    /// configured fallback service.
+    pub fn route_request(&self, req: &RequestHeader) -> Option<&Arc<Route>> {
+        self.routes.resolve_request(req).or(self.routes.fallback())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(fallback) = &self.route_miss.fallback_service
+            && !self.services.contains_key(fallback)
+        {
+            return Err(ConfigError::UndefinedFallbackService(fallback.clone()));
+        }
+        self.tagging
+            .validate(|service| self.services.contains_key(service))
+            .map_err(ConfigError::InvalidTagging)?;
+
+        let mut by_path: HashMap<(&str, &RoutePredicates), &String> = HashMap::new();
+        let mut names: Vec<&String> = self.services.keys().collect();
+        names.sort();
+        for name in names {
+            // `service` is the label every service already carries
+            if let Some((key, value)) = self.services[name]
+                .labels
+                .iter()
+                .find(|(k, v)| *k == "service" || !valid_label(k, v))
+            {
+                return Err(ConfigError::InvalidServiceLabel(
+                    name.clone(),
+                    key.clone(),
+                    value.clone(),
+                ));
+            }
+            if let Some(shedding) = &self.services[name].load_shedding {
+                shedding
+                    .validate()
+                    .map_err(|e| ConfigError::InvalidLoadShedding(name.clone(), e))?;
+            }
+            if let Some(replay) = &self.services[name].replay_protection {
+                replay
+                    .validate()
+                    .map_err(|e| ConfigError::InvalidReplayProtection(name.clone(), e))?;
+            }
+            let service = &self.services[name];
+            if service.timeout_ms == Some(0) {
+                return Err(ConfigError::ZeroServiceTimeout(name.clone()));
+            }
+            if let Some(response) = &service.fallback_response
+                && !(400..600).contains(&response.status)
+            {
+                return Err(ConfigError::InvalidFallbackResponse(
+                    name.clone(),
+                    response.status,
+                ));
+            }
+            service
+                .predicates
+                .validate()
+                .map_err(|e| ConfigError::InvalidRoutePredicates(name.clone(), e))?;
+            let invalid_path = |e: String| ConfigError::InvalidServicePath(name.clone(), e);
+            match &service.rewrite_prefix {
+                Some(_) if service.strip_prefix => {
+                    return Err(invalid_path(
+                        "set either strip_prefix or rewrite_prefix".to_string(),
+                    ));
+                }
+                Some(prefix) if !prefix.starts_with('/') => {
+                    return Err(invalid_path(
+                        "rewrite_prefix must start with '/'".to_string(),
+                    ));
+                }
+                _ => {}
+            }
+            if service.path_regex.is_some()
+                && (service.strip_prefix || service.rewrite_prefix.is_some())
+            {
+                return Err(invalid_path(
+                    "path_regex routes rewrite with rewrite, not the prefix options".to_string(),
+                ));
+            }
+            match (&service.path_regex, &service.rewrite) {
+                (Some(_), _) if !service.path.is_empty() => {
+                    return Err(invalid_path("set either path or path_regex".to_string()));
+                }
+                (Some(pattern), _) => {
+                    path_regex(pattern).map_err(|e| invalid_path(e.to_string()))?;
+                    continue;
+                }
+                (None, Some(_)) => {
+                    return Err(invalid_path("rewrite requires path_regex".to_string()));
+                }
+                (None, None) if service.path.is_empty() => {
+                    return Err(invalid_path("set path or path_regex".to_string()));
+                }
+                (None, None) => {}
+            }
+            if let Some(other) = by_path.insert((&service.path, &service.predicates), name) {
+                return Err(ConfigError::DuplicateServicePath(
+                    self.services[name].path.clone(),
+                    other.clone(),
+                    name.clone(),
+                ));
+            }
+        }
+
+        if let Some((service, host)) = self.backends.iter().find_map(|b| match &b.backend {
+            Backend::Basic { ip, .. } if !is_valid_host(ip) => Some((&b.service, ip)),
+            _ => None,
+        }) {
+            return Err(ConfigError::InvalidBackendHost(
+                service.clone(),
+                host.clone(),
+            ));
+        }
+
+        if let Some((service, endpoint)) = self.backends.iter().find_map(|b| match &b.backend {
+            Backend::Static { endpoints } if endpoints.is_empty() => Some((&b.service, "")),
+            Backend::Static { endpoints } => endpoints
+                .iter()
+                .find(|e| e.addr().parse::<SocketAddr>().is_err())
+                .map(|e| (&b.service, e.addr())),
+            _ => None,
+        }) {
+            return Err(ConfigError::InvalidBackendEndpoint(
+                service.clone(),
+                endpoint.to_string(),
+            ));
+        }
+
+        if self.xds.server.is_empty()
+            && let Some(backend) = self
+                .backends
+                .iter()
+                .find(|b| matches!(b.backend, Backend::Xds { .. }))
+        {
+            return Err(ConfigError::MissingXdsServer(backend.service.clone()));
+        }
+
+        for backend in &self.backends {
+            if let Some(Err(e)) = backend.egress_proxy.as_ref().map(|e| e.validate()) {
+                return Err(ConfigError::InvalidEgressProxy(backend.service.clone(), e));
+            }
+            if let Some(tls) = &backend.tls {
+                // Shadow copies are sent over plain HTTP
+                let valid = tls.validate().and_then(|()| match backend.shadow {
+                    true => Err("tls is not supported on shadow backends".to_string()),
+                    false => Ok(()),
+                });
+                if let Err(e) = valid {
+                    return Err(ConfigError::InvalidUpstreamTls(backend.service.clone(), e));
+                }
+            }
+            let timeouts = [
+                ("connect_timeout_ms", backend.connect_timeout_ms),
+                ("read_timeout_ms", backend.read_timeout_ms),
+                ("write_timeout_ms", backend.write_timeout_ms),
+            ];
+            if let Some((name, _)) = timeouts.iter().find(|(_, ms)| *ms == Some(0)) {
+                return Err(ConfigError::ZeroBackendTimeout(
+                    backend.service.clone(),
+                    name,
+                ));
+            }
+        }
+
+        // Several backends sharing a tier split its traffic by weight, which must be explicit
+        let mut tiers: HashMap<(PoolKey, u32), (usize, bool)> = HashMap::new();
+        for backend in &self.backends {
+            if backend.shadow && backend.pool.is_some() {
+                return Err(ConfigError::DedicatedShadowBackend(backend.service.clone()));
+            }
+            if backend.fallback
+                && (backend.shadow || backend.pool.is_some() || backend.group.is_some())
+            {
+                return Err(ConfigError::InvalidFallbackBackend(backend.service.clone()));
+            }
+            let invalid_group =
+                |e: &str| ConfigError::InvalidTrafficGroup(backend.service.clone(), e.to_string());
+            match (&backend.group, backend.traffic_percent) {
+                (None, None) => {}
+                (Some(_), Some(percent)) => {
+                    if !(percent > 0.0 && percent <= 100.0) {
+                        return Err(invalid_group("traffic_percent must be in (0, 100]"));
+                    }
+                    if backend.shadow || backend.pool.is_some() {
+                        return Err(invalid_group(
+                            "a group cannot be a shadow or in a dedicated pool",
+                        ));
+                    }
+                }
+                _ => return Err(invalid_group("set group and traffic_percent together")),
+            }
+            let (count, weighted) = tiers
+                .entry((backend.pool_key(), backend.priority))
+                .or_insert((0, true));
+            *count += 1;
+            *weighted &= backend.weight.is_some();
+            if *count > 1 && !*weighted {
+                return Err(ConfigError::AmbiguousBackends(
+                    backend.service.clone(),
+                    backend.priority,
+                ));
+            }
+        }
+
+        // Backends of a group share its percent, and groups share the service's keys
+        let mut groups: HashMap<(&String, &String), f64> = HashMap::new();
+        for backend in &self.backends {
+            if let (Some(group), Some(percent)) = (&backend.group, backend.traffic_percent)
+                && *groups.entry((&backend.service, group)).or_insert(percent) != percent
+            {
+                return Err(ConfigError::InvalidTrafficGroup(
+                    backend.service.clone(),
+                    format!("backends of group '{group}' set different traffic_percent"),
+                ));
+            }
+        }
+        let mut split: HashMap<&String, f64> = HashMap::new();
+        for ((service, _), percent) in &groups {
+            *split.entry(service).or_default() += percent;
+        }
+        if let Some((service, _)) = split.iter().find(|(_, total)| **total > 100.0) {
+            return Err(ConfigError::InvalidTrafficGroup(
+                (*service).clone(),
+                "traffic_percent of groups add up to more than 100".to_string(),
+            ));
+        }
+
+        let mut used_services: HashSet<&String> = HashSet::new();
+
+        for backend in &self.backends {
+            if !self.services.contains_key(&backend.service) {
+                return Err(ConfigError::UndefinedService(backend.service.clone()));
+            }
+            if let Backend::Hetzner { labels, .. } = &backend.backend {
+                LabelSelector::new(labels)
+                    .map_err(|e| ConfigError::InvalidLabelSelector(backend.service.clone(), e))?;
+            }
+            // A service needs a backend serving its requests besides any shadow or fallback
+            if !backend.shadow && !backend.fallback {
+                used_services.insert(&backend.service);
+            }
+        }
+
+        for service in self.services.keys() {
+            if !used_services.contains(service) {
+                return Err(ConfigError::UnusedService(service.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ConfigReloader {
+    pub path: String,
+    pub config: Arc<RwLock<Config>>,
+    pub alerts: Arc<AlertSink>,
+    /// Where applied versions are recorded, if anywhere.
+    pub history: Option<Arc<ConfigHistory>>,
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        loop {
+            // Check for shutdown signal
+            if *shutdown.borrow() {
+                return;
+            }
+            // Wait for 5 seconds or shutdown
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    return;
+                }
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {
+                    // Continue to reload
+                }
+            }
+
+            match Config::load_with_source(&self.path) {
+                Ok((mut new_config, source)) => {
+                    let mut w = self.config.write_or_recover();
+                    new_config.resolve_upstreams(Some(&w));
+                    if let Some(history) = &self.history
+                        && new_config.version != w.version
+                        && let Err(e) = history.record(&source, Some(&w), &new_config)
+                    {
+                        log::warn!("Failed to record backend config history: {e}");
+                    }
+                    *w = new_config;
+                    log::info!("Backend config reloaded successfully");
+                }
+                Err(e) => {
+                    self.alerts
+                        .critical("config", format!("Backend config reload failed: {e}"));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackendConfig {
+    pub service: String,
+    pub backend: Backend,
+    /// Relative weight of each endpoint of the backend among the backends of its service;
+    /// 1 when unset, 0 drains the backend.
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// Seconds over which endpoints that newly appear ramp up to their full weight; the
+    /// service's `slow_start_secs` when unset.
+    #[serde(default)]
+    pub slow_start_secs: Option<u64>,
+    /// Failover tier of the backend: 0 (the default) is primary, and each higher tier takes
+    /// traffic when the tiers before it have too few healthy endpoints.
+    #[serde(default)]
+    pub priority: u32,
+    /// Close an upstream keepalive connection after this many requests.
+    #[serde(default)]
+    pub max_requests_per_connection: Option<u64>,
+    /// Close an upstream keepalive connection once it is this many seconds old.
+    #[serde(default)]
+    pub max_connection_lifetime_secs: Option<u64>,
+    /// Dedicated pool the backend belongs to. It then only serves accounts routed to the
+    /// pool in the AccountRouting table, and those accounts only use the backends of their
+    /// pool for the service.
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Proxy the backend's endpoints are reached through, for upstreams only reachable
+    /// from the LB's network through one.
+    #[serde(default)]
+    pub egress_proxy: Option<EgressProxyConfig>,
+    /// Reach the backend's endpoints over TLS, optionally with pinned certificates; see
+    /// [`crate::tls`].
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+    /// Milliseconds to establish a connection to an endpoint.
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /// Milliseconds to wait for each read from an endpoint, such as the response header.
+    #[serde(default)]
+    pub read_timeout_ms: Option<u64>,
+    /// Milliseconds to wait for each write to an endpoint.
+    #[serde(default)]
+    pub write_timeout_ms: Option<u64>,
+    /// Times a failed request is sent again, each time to another endpoint of the service.
+    #[serde(default)]
+    pub retries: u32,
+    /// Failures that are retried: `connect_failure`, `502`, `503` and `504`; connection
+    /// failures only when unset.
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// Zone (availability zone, data center) the backend's endpoints run in, for those
+    /// whose provider does not report one.
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Send the backend a copy of each of the service's requests, whose response is
+    /// discarded, instead of serving them; see [`crate::shadow`].
+    #[serde(default)]
+    pub shadow: bool,
+    /// Serve the service's requests its other backends fail, or all of them while those
+    /// are ejected; see [`crate::fallback`].
+    #[serde(default)]
+    pub fallback: bool,
+    /// Traffic group of the backend, serving `traffic_percent` of the service's API keys
+    /// instead of its other backends; see [`crate::canary`].
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Share of the service's API keys the backend's group serves, in percent.
+    #[serde(default)]
+    pub traffic_percent: Option<f64>,
+    /// Endpoints of the backend, resolved by [`Config::resolve_upstreams`].
+    #[serde(skip)]
+    pub upstreams: Option<Arc<dyn UpstreamsProvider>>,
+    /// TLS settings of the backend, loaded by [`Config::load_upstream_tls`].
+    #[serde(skip)]
+    pub upstream_tls: Option<Arc<UpstreamTls>>,
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectFailure]
+}
+
+/// Pool a backend belongs to: its service, dedicated pool, whether it is a shadow, its
+/// traffic group and whether it is a fallback.
+type PoolKey<'a> = (
+    &'a String,
+    Option<&'a String>,
+    bool,
+    Option<&'a String>,
+    bool,
+);
+
+impl BackendConfig {
+    fn pool_key(&self) -> PoolKey<'_> {
+        (
+            &self.service,
+            self.pool.as_ref(),
+            self.shadow,
+            self.group.as_ref(),
+            self.fallback,
+        )
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+
+    pub fn timeouts(&self) -> UpstreamTimeouts {
+        UpstreamTimeouts {
+            connect: self.connect_timeout_ms.map(Duration::from_millis),
+            read: self.read_timeout_ms.map(Duration::from_millis),
+            write: self.write_timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    pub fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            on: self.retry_on.clone(),
+        }
+    }
+
+    pub fn recycling(&self) -> ConnectionRecycling {
+        ConnectionRecycling {
+            max_requests: self.max_requests_per_connection,
+            max_lifetime: self.max_connection_lifetime_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+    Hetzner {
+        labels: Vec<HashMap<String, String>>,
+        port: u16,
+    },
+    Basic {
+        /// IP address, or a host name resolved as configured under `dns`.
+        ip: String,
+        port: u16,
+    },
+    /// Fixed `ip:port` endpoints, taking the service's traffic in turn by weight.
+    Static { endpoints: Vec<StaticEndpoint> },
+    /// Endpoints of a cluster on the control plane configured under `xds`.
+    Xds { cluster: String },
+}
+
+/// An endpoint of a `static` backend: `ip:port`, or `{ addr: ip:port, weight: n }` to give
+/// it a weight relative to the other endpoints of the backend (1 by default).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum StaticEndpoint {
+    Addr(String),
+    Weighted { addr: String, weight: u32 },
+}
+
+impl StaticEndpoint {
+    pub fn addr(&self) -> &str {
+        match self {
+            StaticEndpoint::Addr(addr) | StaticEndpoint::Weighted { addr, .. } => addr,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            StaticEndpoint::Addr(_) => 1,
+            StaticEndpoint::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+impl From<&str> for StaticEndpoint {
+    fn from(addr: &str) -> Self {
+        StaticEndpoint::Addr(addr.to_string())
+    }
+}
+
+impl Backend {
+    /// Selector of a Hetzner backend; `None` for other backends or invalid labels, which
+    /// [`Config::validate`] rejects.
+    pub fn label_selector(&self) -> Option<LabelSelector> {
+        match self {
+            Backend::Hetzner { labels, .. } => LabelSelector::new(labels).ok(),
+            Backend::Basic { .. } | Backend::Static { .. } | Backend::Xds { .. } => None,
+        }
+    }
+
+    /// Name of the backend variant, as in the `type` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Backend::Hetzner { .. } => "hetzner",
+            Backend::Basic { .. } => "basic",
+            Backend::Static { .. } => "static",
+            Backend::Xds { .. } => "xds",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use serde_yaml;
+
+    #[test]
+    fn test_deserialize_config_yaml() {
+        let yaml_data = r#"
+        services:
+          geocode_suggest: /geocode/suggest
+          geocode_forward: /geocode/forward
+          geocode_reverse: /geocode/reverse
+        backends:
+          - service: geocode_suggest
+            backend:
+              type: hetzner
+              labels:
+                - env: prod
+                  service: geocode
+              port: 8099
+          - service: geocode_forward
+            backend:
+              type: hetzner
+              labels:
+                - env: prod
+                  service: geocode
+              port: 8099
+          - service: geocode_reverse
+            weight: 3
+            backend:
+              type: hetzner
+              labels:
+                - env: prod
+                  service: geocode
+              port: 8099
+          - service: geocode_reverse
+            weight: 1
+            backend:
+              type: basic
+              ip: 10.120.32.12
+              port: 8099
+        "#;
+
+        let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
+        assert!(config.validate().is_ok());
+
+        assert_eq!(config.services.len(), 3);
+        assert_eq!(config.backends.len(), 4);
+
+        // Check first backend
+        let b1 = &config.backends[0];
+        assert_eq!(b1.service, "geocode_suggest");
+        assert_eq!(
+            config.services.get(&b1.service).map(|s| s.path.as_str()),
+            Some("/geocode/suggest")
+        );
+        if let Backend::Hetzner { labels, port } = &b1.backend {
+            assert_eq!(*port, 8099);
+            assert_eq!(labels.len(), 1);
+            assert_eq!(labels[0].get("env").map(|s| s.as_str()), Some("prod"));
+            assert_eq!(
+                labels[0].get("service").map(|s| s.as_str()),
+                Some("geocode")
+            );
+        } else {
+            panic!("Expected Hetzner backend");
+        }
+
+        // Check last backend
+        let b4 = &config.backends[3];
+        assert_eq!(b4.service, "geocode_reverse");
+        assert_eq!(
+            config.services.get(&b4.service).map(|s| s.path.as_str()),
+            Some("/geocode/reverse")
+        );
+        if let Backend::Basic { ip, port } = &b4.backend {
+            assert_eq!(ip, "10.120.32.12");
+            assert_eq!(*port, 8099);
+        } else {
+            panic!("Expected Basic backend");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_config() {
+        let json_data = r#"
         {
             "services": {
                 "geocode_suggest": "/geocode/suggest",
@@ -251,6 +1977,7 @@ mod tests {
                 },
                 {
                     "service": "geocode_reverse",
+                    "weight": 3,
                     "backend": {
                         "type": "hetzner",
                         "labels": [
@@ -264,6 +1991,7 @@ mod tests {
                 },
                 {
                     "service": "geocode_reverse",
+                    "weight": 1,
                     "backend": {
                         "type": "basic",
                         "ip": "10.120.32.12",
@@ -272,84 +2000,937 @@ mod tests {
                 }
             ]
         }
-        "#;
+        "#;
+
+        let config: Config = serde_json::from_str(json_data).expect("Failed to deserialize config");
+        assert!(config.validate().is_ok());
+
+        assert_eq!(config.services.len(), 3);
+        assert_eq!(config.backends.len(), 4);
+
+        // Check first backend
+        let b1 = &config.backends[0];
+        assert_eq!(b1.service, "geocode_suggest");
+        assert_eq!(
+            config.services.get(&b1.service).map(|s| s.path.as_str()),
+            Some("/geocode/suggest")
+        );
+        if let Backend::Hetzner { labels, port } = &b1.backend {
+            assert_eq!(*port, 8099);
+            assert_eq!(labels.len(), 1);
+            assert_eq!(labels[0].get("env").map(|s| s.as_str()), Some("prod"));
+            assert_eq!(
+                labels[0].get("service").map(|s| s.as_str()),
+                Some("geocode")
+            );
+        } else {
+            panic!("Expected Hetzner backend");
+        }
+
+        // Check last backend
+        let b4 = &config.backends[3];
+        assert_eq!(b4.service, "geocode_reverse");
+        assert_eq!(
+            config.services.get(&b4.service).map(|s| s.path.as_str()),
+            Some("/geocode/reverse")
+        );
+        if let Backend::Basic { ip, port } = &b4.backend {
+            assert_eq!(ip, "10.120.32.12");
+            assert_eq!(*port, 8099);
+        } else {
+            panic!("Expected Basic backend");
+        }
+    }
+
+    #[test]
+    fn test_validate_undefined_service() {
+        let yaml_data = r#"
+        services:
+          geocode_suggest: /geocode/suggest
+        backends:
+          - service: unknown_service
+            backend:
+              type: basic
+              ip: 10.120.32.12
+              port: 8099
+        "#;
+        let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
+        match config.validate() {
+            Err(ConfigError::UndefinedService(s)) => assert_eq!(s, "unknown_service"),
+            _ => panic!("Expected UndefinedService error"),
+        }
+    }
+
+    #[test]
+    fn test_validate_unused_service() {
+        let yaml_data = r#"
+        services:
+          geocode_suggest: /geocode/suggest
+          unused_service: /unused
+        backends:
+          - service: geocode_suggest
+            backend:
+              type: basic
+              ip: 10.120.32.12
+              port: 8099
+        "#;
+        let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
+        match config.validate() {
+            Err(ConfigError::UnusedService(s)) => assert_eq!(s, "unused_service"),
+            _ => panic!("Expected UnusedService error"),
+        }
+    }
+
+    #[test]
+    fn test_validate_duplicate_service_path() {
+        let yaml_data = r#"
+        services:
+          geocode: /geocode
+          geocode_v2: /geocode
+        backends: []
+        "#;
+        let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
+        match config.validate() {
+            Err(ConfigError::DuplicateServicePath(path, a, b)) => {
+                assert_eq!(
+                    (path.as_str(), a.as_str(), b.as_str()),
+                    ("/geocode", "geocode", "geocode_v2")
+                )
+            }
+            other => panic!("Expected DuplicateServicePath error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_service_paths() {
+        let yaml_data = |service: &str| {
+            format!(
+                "services:\n  geocode: {service}\nbackends:\n- service: geocode\n  \
+                 backend: {{ type: basic, ip: 10.0.0.1, port: 8099 }}\n"
+            )
+        };
+        let config: Config =
+            serde_yaml::from_str(&yaml_data(r#"{ path_regex: "/v1/(.*)", rewrite: "/$1" }"#))
+                .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.services["geocode"].rewrite.as_deref(), Some("/$1"));
+
+        for (service, error) in [
+            (
+                r#"{ path: /v1, path_regex: "/v1/(.*)" }"#,
+                "set either path or path_regex",
+            ),
+            (
+                r#"{ path: /v1, rewrite: "/$1" }"#,
+                "rewrite requires path_regex",
+            ),
+            ("{ auth: none }", "set path or path_regex"),
+            (r#"{ path_regex: "/v1/(" }"#, "regex parse error"),
+            (
+                "{ path: /v1, strip_prefix: true, rewrite_prefix: /v2 }",
+                "set either strip_prefix or rewrite_prefix",
+            ),
+            (
+                "{ path: /v1, rewrite_prefix: v2 }",
+                "rewrite_prefix must start with '/'",
+            ),
+            (
+                r#"{ path_regex: "/v1/(.*)", strip_prefix: true }"#,
+                "not the prefix options",
+            ),
+        ] {
+            let config: Config = serde_yaml::from_str(&yaml_data(service)).unwrap();
+            match config.validate() {
+                Err(ConfigError::InvalidServicePath(s, e)) => {
+                    assert_eq!(s, "geocode");
+                    assert!(e.contains(error), "{e}");
+                }
+                other => panic!("Expected InvalidServicePath error, got {other:?}"),
+            }
+        }
+    }
 
-        let config: Config = serde_json::from_str(json_data).expect("Failed to deserialize config");
+    #[test]
+    fn test_validate_route_predicates() {
+        let yaml_data = |writer_match: &str| {
+            format!(
+                r#"
+        services:
+          writer: {{ path: /ingest, match: {writer_match} }}
+          reader: {{ path: /ingest, match: {{ methods: [GET] }} }}
+        backends:
+          - service: writer
+            backend: {{ type: basic, ip: 10.0.0.1, port: 8099 }}
+          - service: reader
+            backend: {{ type: basic, ip: 10.0.0.2, port: 8099 }}
+        "#
+            )
+        };
+        let config: Config = serde_yaml::from_str(&yaml_data("{ methods: [POST] }")).unwrap();
         assert!(config.validate().is_ok());
+        assert_eq!(config.services["writer"].predicates.methods, vec!["POST"]);
 
-        assert_eq!(config.services.len(), 3);
-        assert_eq!(config.backends.len(), 4);
+        let config: Config = serde_yaml::from_str(&yaml_data("{ methods: [GET] }")).unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DuplicateServicePath(..))
+        ));
+        let config: Config = serde_yaml::from_str(&yaml_data("{ methods: [\"PO ST\"] }")).unwrap();
+        match config.validate() {
+            Err(ConfigError::InvalidRoutePredicates(s, e)) => {
+                assert_eq!(
+                    (s.as_str(), e.as_str()),
+                    ("writer", "invalid method 'PO ST'")
+                )
+            }
+            other => panic!("Expected InvalidRoutePredicates error, got {other:?}"),
+        }
+    }
 
-        // Check first backend
-        let b1 = &config.backends[0];
-        assert_eq!(b1.service, "geocode_suggest");
+    #[test]
+    fn test_validate_service_labels() {
+        let yaml_data = |labels: &str| {
+            format!(
+                r#"
+        services:
+          geocode:
+            path: /geocode
+            labels: {labels}
+        backends:
+          - service: geocode
+            backend:
+              type: basic
+              ip: 10.0.0.1
+              port: 8099
+        "#
+            )
+        };
+        let config: Config = serde_yaml::from_str(&yaml_data("{team: geo, tier: gold}")).unwrap();
+        assert!(config.validate().is_ok());
+
+        for labels in ["{service: other}", "{team: 'geo,maps'}", "{'': geo}"] {
+            let config: Config = serde_yaml::from_str(&yaml_data(labels)).unwrap();
+            assert!(
+                matches!(config.validate(), Err(ConfigError::InvalidServiceLabel(..))),
+                "{labels} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_ambiguous_backends() {
+        let backends = |primary_weight: &str| {
+            format!(
+                r#"
+        services:
+          geocode: /geocode
+        backends:
+          - service: geocode
+            {primary_weight}
+            backend:
+              type: basic
+              ip: 10.0.0.1
+              port: 8099
+          - service: geocode
+            weight: 1
+            backend:
+              type: basic
+              ip: 10.0.0.2
+              port: 8099
+          - service: geocode
+            priority: 1
+            backend:
+              type: basic
+              ip: 10.0.0.3
+              port: 8099
+        "#
+            )
+        };
+
+        let config: Config = serde_yaml::from_str(&backends("")).unwrap();
+        match config.validate() {
+            Err(ConfigError::AmbiguousBackends(s, 0)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected AmbiguousBackends error, got {other:?}"),
+        }
+        // A lone backend in a failover tier needs no weight
+        let config: Config = serde_yaml::from_str(&backends("weight: 2")).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_backend_hosts_are_validated() {
+        let backend = |ip: &str| {
+            format!(
+                "services:\n  geocode: /geocode\nbackends:\n- service: geocode\n  backend:\n    \
+                 type: basic\n    ip: \"{ip}\"\n    port: 8099\n"
+            )
+        };
+        for ip in ["[::1]", "::1", "geocode.internal"] {
+            let config: Config = serde_yaml::from_str(&backend(ip)).unwrap();
+            assert!(config.validate().is_ok(), "{ip}");
+        }
+        let config: Config = serde_yaml::from_str(&backend("[::1]:8099")).unwrap();
+        match config.validate() {
+            Err(ConfigError::InvalidBackendHost(s, host)) => {
+                assert_eq!((s.as_str(), host.as_str()), ("geocode", "[::1]:8099"))
+            }
+            other => panic!("Expected InvalidBackendHost error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_backend_timeouts() {
+        let backend = |timeouts: &str| {
+            format!(
+                "services:\n  geocode: /geocode\nbackends:\n- service: geocode\n  {timeouts}\n  \
+                 backend:\n    type: basic\n    ip: 10.0.0.1\n    port: 8099\n"
+            )
+        };
+        let config: Config =
+            serde_yaml::from_str(&backend("connect_timeout_ms: 250\n  read_timeout_ms: 5000"))
+                .unwrap();
+        assert!(config.validate().is_ok());
         assert_eq!(
-            config.services.get(&b1.service).map(|s| s.as_str()),
-            Some("/geocode/suggest")
+            config.backends[0].timeouts(),
+            UpstreamTimeouts {
+                connect: Some(Duration::from_millis(250)),
+                read: Some(Duration::from_secs(5)),
+                write: None,
+            }
         );
-        if let Backend::Hetzner { labels, port } = &b1.backend {
-            assert_eq!(*port, 8099);
-            assert_eq!(labels.len(), 1);
-            assert_eq!(labels[0].get("env").map(|s| s.as_str()), Some("prod"));
-            assert_eq!(
-                labels[0].get("service").map(|s| s.as_str()),
-                Some("geocode")
-            );
-        } else {
-            panic!("Expected Hetzner backend");
+
+        let config: Config = serde_yaml::from_str(&backend("write_timeout_ms: 0")).unwrap();
+        match config.validate() {
+            Err(ConfigError::ZeroBackendTimeout(s, name)) => {
+                assert_eq!((s.as_str(), name), ("geocode", "write_timeout_ms"))
+            }
+            other => panic!("Expected ZeroBackendTimeout error, got {other:?}"),
         }
+    }
 
-        // Check last backend
-        let b4 = &config.backends[3];
-        assert_eq!(b4.service, "geocode_reverse");
+    #[test]
+    fn test_validate_service_timeout() {
+        let yaml_data = |timeout_ms: u64| {
+            format!(
+                "services:\n  geocode: {{ path: /geocode, timeout_ms: {timeout_ms}, retry_budget: 2 }}\n\
+                 backends:\n- service: geocode\n  backend: {{ type: basic, ip: 10.0.0.1, port: 8099 }}\n"
+            )
+        };
+        let config: Config = serde_yaml::from_str(&yaml_data(1500)).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.services["geocode"].timeout_ms, Some(1500));
+        assert_eq!(config.services["geocode"].retry_budget, Some(2));
+
+        let config: Config = serde_yaml::from_str(&yaml_data(0)).unwrap();
+        match config.validate() {
+            Err(ConfigError::ZeroServiceTimeout(s)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected ZeroServiceTimeout error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_shadow_backends_are_pooled_apart() {
+        let source = r#"
+        services:
+          geocode: /geocode
+        backends:
+          - service: geocode
+            backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+          - service: geocode
+            shadow: true
+            backend: { type: basic, ip: 10.0.0.9, port: 8080 }
+        "#;
+        let config = Config::parse(source).unwrap();
         assert_eq!(
-            config.services.get(&b4.service).map(|s| s.as_str()),
-            Some("/geocode/reverse")
+            config.pools["geocode"].select().unwrap().addr,
+            "10.0.0.1:8080"
         );
-        if let Backend::Basic { ip, port } = &b4.backend {
-            assert_eq!(ip, "10.120.32.12");
-            assert_eq!(*port, 8099);
-        } else {
-            panic!("Expected Basic backend");
+        assert_eq!(
+            config.shadow_pools["geocode"].select().unwrap().addr,
+            "10.0.0.9:8080"
+        );
+        let route = config.routes.service("geocode").unwrap();
+        assert!(route.shadow.is_some());
+        assert_eq!(route.backend, "basic");
+
+        // A shadow alone does not serve the service
+        let source = "services:\n  geocode: /geocode\nbackends:\n- service: geocode\n  \
+                      shadow: true\n  backend: { type: basic, ip: 10.0.0.9, port: 8080 }\n";
+        let config: Config = serde_yaml::from_str(source).unwrap();
+        match config.validate() {
+            Err(ConfigError::UnusedService(s)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected UnusedService error, got {other:?}"),
+        }
+        let config: Config = serde_yaml::from_str(&format!(
+            "{source}- service: geocode\n  backend: {{ type: basic, ip: 10.0.0.1, port: 8080 }}\n\
+             - service: geocode\n  shadow: true\n  pool: enterprise\n  \
+             backend: {{ type: basic, ip: 10.0.0.8, port: 8080 }}\n"
+        ))
+        .unwrap();
+        match config.validate() {
+            Err(ConfigError::DedicatedShadowBackend(s)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected DedicatedShadowBackend error, got {other:?}"),
         }
     }
 
     #[test]
-    fn test_validate_undefined_service() {
-        let yaml_data = r#"
+    fn test_service_slow_start_applies_to_its_backends() {
+        let source = r#"
         services:
-          geocode_suggest: /geocode/suggest
+          geocode:
+            path: /geocode
+            slow_start_secs: 60
         backends:
-          - service: unknown_service
+          - service: geocode
+            weight: 1
+            backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+          - service: geocode
+            weight: 1
+            slow_start_secs: 0
+            backend: { type: basic, ip: 10.0.0.2, port: 8080 }
+        "#;
+        let config = Config::parse(source).unwrap();
+        let mut slow_starts: Vec<u64> = config.pools["geocode"]
+            .members()
+            .iter()
+            .map(|m| m.slow_start.as_secs())
+            .collect();
+        slow_starts.sort_unstable();
+        assert_eq!(slow_starts, [0, 60]);
+    }
+
+    #[test]
+    fn test_upstream_tls_is_validated_and_loaded() {
+        let source = r#"
+        services:
+          billing: /billing
+        backends:
+          - service: billing
+            tls: { sni: billing.internal }
+            backend: { type: basic, ip: 10.0.0.1, port: 8443 }
+        "#;
+        let config = Config::parse(source).unwrap();
+        assert!(config.pools["billing"].tls_for("10.0.0.1:8443").is_some());
+
+        for (tls, expected) in [
+            ("{ sni: '' }", "tls.sni"),
+            (
+                "{ sni: billing.internal, cert_sha256: [abcd] }",
+                "tls.cert_sha256",
+            ),
+            (
+                "{ sni: billing.internal, ca_file: /nonexistent/ca.pem }",
+                "/nonexistent",
+            ),
+        ] {
+            let yaml = source.replace("{ sni: billing.internal }", tls);
+            match Config::parse(&yaml) {
+                Err(e) => assert!(e.contains(expected), "{e}"),
+                Ok(_) => panic!("Expected invalid TLS for {tls}"),
+            }
+        }
+
+        // Shadow copies are sent over plain HTTP
+        let yaml = format!(
+            "{source}
+            shadow: true
+"
+        );
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        match config.validate() {
+            Err(ConfigError::InvalidUpstreamTls(s, _)) => assert_eq!(s, "billing"),
+            other => panic!("Expected InvalidUpstreamTls error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_hooks_are_loaded_in_order() {
+        use crate::hooks::RequestHook;
+
+        let dir = tempfile::tempdir().unwrap();
+        let wasm = wat::parse_str(
+            r#"(module (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "on_request") (param i32 i32) (result i64) i64.const 0))"#,
+        )
+        .unwrap();
+        let path = dir.path().join("hook.wasm");
+        std::fs::write(&path, wasm).unwrap();
+        let path = path.display().to_string();
+        let source = format!(
+            r#"
+        services:
+          tiles: /tiles
+        backends:
+          - service: tiles
+            backend: {{ type: basic, ip: 10.0.0.1, port: 8080 }}
+        hooks:
+          - {{ name: first, wasm: {path} }}
+          - {{ name: second, wasm: {path}, fail_open: true }}
+        "#
+        );
+        let config = Config::parse(&source).unwrap();
+        let names: Vec<&str> = config
+            .request_hooks
+            .iter()
+            .map(|hook| hook.name())
+            .collect();
+        assert_eq!(names, ["first", "second"]);
+
+        for (yaml, expected) in [
+            (source.replace("name: second", "name: first"), "'first'"),
+            (
+                source.replace(
+                    &format!("{path}, fail_open"),
+                    "/nonexistent.wasm, fail_open",
+                ),
+                "/nonexistent.wasm",
+            ),
+        ] {
+            match Config::parse(&yaml) {
+                Err(e) => assert!(e.contains(expected), "{e}"),
+                Ok(_) => panic!("Expected invalid hooks for {yaml}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fallback_backends_are_pooled_apart() {
+        let source = r#"
+        services:
+          geocode:
+            path: /geocode
+            fallback_response:
+              body: degraded
+        backends:
+          - service: geocode
+            backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+          - service: geocode
+            fallback: true
+            backend: { type: basic, ip: 10.1.0.1, port: 8080 }
+        "#;
+        let config = Config::parse(source).unwrap();
+        assert_eq!(
+            config.pools["geocode"].select().unwrap().addr,
+            "10.0.0.1:8080"
+        );
+        let route = config.routes.service("geocode").unwrap();
+        assert_eq!(
+            route.fallback.as_ref().unwrap().select().unwrap().addr,
+            "10.1.0.1:8080"
+        );
+        let response = route.fallback_response.as_ref().unwrap();
+        assert_eq!(
+            (response.status, response.body.as_deref()),
+            (503, Some("degraded"))
+        );
+
+        // A fallback alone does not serve the service
+        let yaml = "services:\n  geocode: /geocode\nbackends:\n- service: geocode\n  \
+                    fallback: true\n  backend: { type: basic, ip: 10.1.0.1, port: 8080 }\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match config.validate() {
+            Err(ConfigError::UnusedService(s)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected UnusedService error, got {other:?}"),
+        }
+
+        let yaml = format!("{source}\n            shadow: true\n");
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        match config.validate() {
+            Err(ConfigError::InvalidFallbackBackend(s)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected InvalidFallbackBackend error, got {other:?}"),
+        }
+
+        let yaml = source.replace("body: degraded", "status: 200");
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        match config.validate() {
+            Err(ConfigError::InvalidFallbackResponse(s, status)) => {
+                assert_eq!((s.as_str(), status), ("geocode", 200));
+            }
+            other => panic!("Expected InvalidFallbackResponse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_traffic_groups_are_pooled_apart() {
+        let source = r#"
+        services:
+          geocode: /geocode
+        backends:
+          - service: geocode
+            backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+          - service: geocode
+            group: next
+            traffic_percent: 20
+            backend: { type: basic, ip: 10.0.0.8, port: 8080 }
+          - service: geocode
+            group: canary
+            traffic_percent: 5
+            backend: { type: basic, ip: 10.0.0.9, port: 8080 }
+        "#;
+        let config = Config::parse(source).unwrap();
+        assert_eq!(
+            config.pools["geocode"].select().unwrap().addr,
+            "10.0.0.1:8080"
+        );
+        let route = config.routes.service("geocode").unwrap();
+        let groups: Vec<_> = route
+            .groups
+            .iter()
+            .map(|g| (g.name.as_str(), g.traffic_percent))
+            .collect();
+        assert_eq!(groups, [("canary", 5.0), ("next", 20.0)]);
+        assert_eq!(route.groups[0].pool.select().unwrap().addr, "10.0.0.9:8080");
+
+        let invalid = |backends: &str| {
+            let config: Config = serde_yaml::from_str(&format!(
+                "services:\n  geocode: /geocode\nbackends:\n\
+                 - service: geocode\n  backend: {{ type: basic, ip: 10.0.0.1, port: 8080 }}\n\
+                 {backends}"
+            ))
+            .unwrap();
+            match config.validate() {
+                Err(ConfigError::InvalidTrafficGroup(s, e)) => {
+                    assert_eq!(s, "geocode");
+                    e
+                }
+                other => panic!("Expected InvalidTrafficGroup error, got {other:?}"),
+            }
+        };
+        let canary = |extra: &str| {
+            format!(
+                "- service: geocode\n  {extra}\n  weight: 1\n  \
+                 backend: {{ type: basic, ip: 10.0.0.9, port: 8080 }}\n"
+            )
+        };
+        assert_eq!(
+            invalid(&canary("group: canary")),
+            "set group and traffic_percent together"
+        );
+        assert_eq!(
+            invalid(&canary("group: canary\n  traffic_percent: 0")),
+            "traffic_percent must be in (0, 100]"
+        );
+        assert_eq!(
+            invalid(&canary(
+                "group: canary\n  traffic_percent: 5\n  pool: enterprise"
+            )),
+            "a group cannot be a shadow or in a dedicated pool"
+        );
+        assert_eq!(
+            invalid(&format!(
+                "{}{}",
+                canary("group: canary\n  traffic_percent: 5"),
+                canary("group: canary\n  traffic_percent: 10")
+            )),
+            "backends of group 'canary' set different traffic_percent"
+        );
+        assert_eq!(
+            invalid(&format!(
+                "{}{}",
+                canary("group: canary\n  traffic_percent: 60"),
+                canary("group: next\n  traffic_percent: 50")
+            )),
+            "traffic_percent of groups add up to more than 100"
+        );
+    }
+
+    #[test]
+    fn test_xds_backends_need_a_server() {
+        let source = "services:\n  geocode: /geocode\nbackends:\n- service: geocode\n  backend:\n    \
+                      type: xds\n    cluster: geocode-v2\n";
+        let config: Config = serde_yaml::from_str(source).unwrap();
+        assert_eq!(
+            config.backends[0].backend,
+            Backend::Xds {
+                cluster: "geocode-v2".to_string()
+            }
+        );
+        match config.validate() {
+            Err(ConfigError::MissingXdsServer(s)) => assert_eq!(s, "geocode"),
+            other => panic!("Expected MissingXdsServer error, got {other:?}"),
+        }
+
+        let config: Config = serde_yaml::from_str(&format!(
+            "xds:\n  server: http://127.0.0.1:18000\n  api_type: grpc\n{source}"
+        ))
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.xds.node_id, "lb");
+        assert_eq!(config.xds.api_type, crate::xds::XdsApiType::Grpc);
+        assert_eq!(config.backends[0].backend.kind(), "xds");
+    }
+
+    #[test]
+    fn test_static_backend_endpoints_are_validated() {
+        let backend = |endpoints: &str| {
+            format!(
+                "services:\n  geocode: /geocode\nbackends:\n- service: geocode\n  backend:\n    \
+                 type: static\n    endpoints: {endpoints}\n"
+            )
+        };
+        let config: Config = serde_yaml::from_str(&backend(
+            r#"["10.0.0.1:8099", { addr: "[::1]:8099", weight: 9 }]"#,
+        ))
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.backends[0].backend.kind(), "static");
+        let Backend::Static { endpoints } = &config.backends[0].backend else {
+            panic!("Expected a static backend");
+        };
+        assert_eq!(
+            endpoints
+                .iter()
+                .map(StaticEndpoint::weight)
+                .collect::<Vec<_>>(),
+            [1, 9]
+        );
+
+        for (endpoints, invalid) in [
+            (r#"["10.0.0.1:8099", "10.0.0.2"]"#, "10.0.0.2"),
+            (r#"["geocode.internal:8099"]"#, "geocode.internal:8099"),
+            (r#"[{ addr: "10.0.0.3", weight: 2 }]"#, "10.0.0.3"),
+            ("[]", ""),
+        ] {
+            let config: Config = serde_yaml::from_str(&backend(endpoints)).unwrap();
+            match config.validate() {
+                Err(ConfigError::InvalidBackendEndpoint(s, endpoint)) => {
+                    assert_eq!((s.as_str(), endpoint.as_str()), ("geocode", invalid))
+                }
+                other => panic!("Expected InvalidBackendEndpoint error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dedicated_pools() {
+        let yaml = r#"
+        services:
+          geocode: /geocode
+        backends:
+          - service: geocode
             backend:
               type: basic
-              ip: 10.120.32.12
+              ip: 10.0.0.1
+              port: 8099
+          - service: geocode
+            pool: acme
+            backend:
+              type: basic
+              ip: 10.1.0.1
               port: 8099
         "#;
-        let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
-        match config.validate() {
-            Err(ConfigError::UndefinedService(s)) => assert_eq!(s, "unknown_service"),
-            _ => panic!("Expected UndefinedService error"),
-        }
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        // Dedicated backends do not share a tier with the service's other backends
+        config.validate().unwrap();
+        config.resolve_upstreams(None);
+
+        let route = config.routes.resolve("/geocode").unwrap();
+        let shared = route.pool.as_ref().unwrap().select().unwrap();
+        assert_eq!(shared.addr, "10.0.0.1:8099");
+        let dedicated = route.dedicated["acme"].select().unwrap();
+        assert_eq!(dedicated.addr, "10.1.0.1:8099");
+        assert_eq!(route.dedicated.len(), 1);
     }
 
     #[test]
-    fn test_validate_unused_service() {
+    fn test_validate_label_selector() {
         let yaml_data = r#"
         services:
-          geocode_suggest: /geocode/suggest
-          unused_service: /unused
+          geocode: /geocode
         backends:
-          - service: geocode_suggest
+          - service: geocode
             backend:
-              type: basic
-              ip: 10.120.32.12
+              type: hetzner
+              labels:
+                - env: prod
+                - {}
               port: 8099
         "#;
         let config: Config = serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
         match config.validate() {
-            Err(ConfigError::UnusedService(s)) => assert_eq!(s, "unused_service"),
-            _ => panic!("Expected UnusedService error"),
+            Err(ConfigError::InvalidLabelSelector(s, SelectorError::EmptySet(1))) => {
+                assert_eq!(s, "geocode")
+            }
+            other => panic!("Expected InvalidLabelSelector error, got {other:?}"),
+        }
+        assert!(config.backends[0].backend.label_selector().is_none());
+    }
+
+    #[test]
+    fn test_route_miss_fallback_service() {
+        let yaml_data = r#"
+        services:
+          geocode: /geocode
+          catchall: /catchall
+        backends: []
+        route_miss:
+          fallback_service: catchall
+        "#;
+        let mut config: Config =
+            serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
+        config.compile_routes();
+
+        assert_eq!(config.route("/geocode/v1").unwrap().service, "geocode");
+        assert!(config.service_for_path("/other").is_none());
+        assert_eq!(config.route("/other").unwrap().service, "catchall");
+
+        config.route_miss.fallback_service = Some("missing".to_string());
+        match config.validate() {
+            Err(ConfigError::UndefinedFallbackService(s)) => assert_eq!(s, "missing"),
+            other => panic!("Expected UndefinedFallbackService error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_service_auth_modes() {
+        let yaml_data = r#"
+        services:
+          root: /
+          geocode: /geocode
+          status:
+            path: /status
+            auth: none
+          tiles:
+            path: /tiles
+            strategy: consistent_hash
+            hash_header: X-Tenant
+          webhooks:
+            path: /webhooks
+            auth: none
+            ip_rps_limit: 50
+            strategy: p2c_ewma
+            labels:
+              team: integrations
+        backends: []
+        "#;
+        let mut config: Config =
+            serde_yaml::from_str(yaml_data).expect("Failed to deserialize config");
+        config.compile_routes();
+
+        assert_eq!(config.services["geocode"].auth, AuthMode::ApiKey);
+        assert_eq!(config.services["status"].auth, AuthMode::None);
+        assert_eq!(config.services["status"].ip_rps_limit, DEFAULT_IP_RPS_LIMIT);
+        assert_eq!(config.services["webhooks"].ip_rps_limit, 50);
+        assert_eq!(config.services["webhooks"].strategy, Strategy::P2cEwma);
+        assert_eq!(config.services["status"].strategy, Strategy::RoundRobin);
+        assert_eq!(config.services["tiles"].strategy, Strategy::ConsistentHash);
+        assert_eq!(
+            config.services["tiles"].hash_header.as_deref(),
+            Some("x-tenant")
+        );
+        assert_eq!(config.services["webhooks"].labels["team"], "integrations");
+        assert!(config.services["status"].labels.is_empty());
+
+        let (name, service) = config.service_for_path("/status/health").unwrap();
+        assert_eq!(name, "status");
+        assert_eq!(service.auth, AuthMode::None);
+        assert_eq!(config.service_for_path("/other").unwrap().0, "root");
+    }
+
+    #[test]
+    fn test_debug_headers_require_token() {
+        let mut config = DebugHeadersConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(!config.allows(Some("")));
+
+        config.token = Some("support".to_string());
+        assert!(config.allows(Some("support")));
+        assert!(!config.allows(Some("other")));
+        assert!(!config.allows(None));
+
+        config.enabled = false;
+        assert!(!config.allows(Some("support")));
+    }
+
+    #[test]
+    fn test_internal_listener_takes_listener_limits() {
+        let server: ServerConfig = serde_yaml::from_str(
+            r#"
+            backend: backend.yml
+            accounts_db: accounts.db
+            listener:
+              max_header_bytes: 8192
+            internal:
+              listen: 10.0.0.5:8081
+              max_uri_length: 2048
+              keepalive_timeout_secs: 60
+            "#,
+        )
+        .unwrap();
+        let internal = server.internal.unwrap();
+        assert_eq!(internal.listen, "10.0.0.5:8081");
+        assert_eq!(internal.rps_limit, 1000);
+        assert_eq!(internal.limits.max_uri_length, Some(2048));
+        assert_eq!(internal.limits.keepalive_timeout_secs, Some(60));
+        // The public listener's limits are its own
+        assert_eq!(internal.limits.max_header_bytes, None);
+        assert_eq!(server.listener.max_uri_length, None);
+    }
+
+    #[test]
+    fn test_runtime_settings_override_pingora_conf() {
+        let server: ServerConfig = serde_yaml::from_str(
+            r#"
+            threads: 2
+            backend: backend.yml
+            accounts_db: accounts.db
+            runtime:
+              threads: 8
+              work_stealing: false
+              pid_file: /tmp/lb.pid
+            "#,
+        )
+        .unwrap();
+        server.runtime.validate().unwrap();
+
+        let mut conf = ServerConf::from_yaml("threads: 2\nupgrade_sock: /tmp/lb.sock").unwrap();
+        server.runtime.apply(&mut conf);
+        assert_eq!(conf.threads, 8);
+        assert!(!conf.work_stealing);
+        assert!(!conf.daemon);
+        assert_eq!(conf.pid_file, "/tmp/lb.pid");
+        assert_eq!(conf.upgrade_sock, "/tmp/lb.sock");
+
+        let invalid = [
+            RuntimeConfig {
+                threads: Some(0),
+                ..Default::default()
+            },
+            RuntimeConfig {
+                pid_file: Some("lb.pid".to_string()),
+                ..Default::default()
+            },
+            RuntimeConfig {
+                upgrade_sock: Some("/nonexistent/lb.sock".to_string()),
+                ..Default::default()
+            },
+        ];
+        for runtime in invalid {
+            assert!(runtime.validate().is_err(), "{runtime:?}");
         }
     }
+
+    #[test]
+    fn test_node_id_and_config_version() {
+        let mut server = ServerConfig {
+            node_id: Some("lb-1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(server.node_id(), "lb-1");
+        server.node_id = Some(String::new());
+        assert_eq!(server.node_id(), host_name());
+        assert!(!host_name().is_empty());
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("backend.yaml");
+        std::fs::write(&path, "services: {}\nbackends: []\n").unwrap();
+        let first = Config::load(&path).unwrap().version;
+        assert_eq!(first.len(), 12);
+        assert_eq!(Config::load(&path).unwrap().version, first);
+        std::fs::write(&path, "services: {}\nbackends: []\n# edited\n").unwrap();
+        assert_ne!(Config::load(&path).unwrap().version, first);
+    }
 }
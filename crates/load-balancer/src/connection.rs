@@ -0,0 +1,278 @@
+//! Downstream connection accounting for listener-level limits.
+//!
+//! [`LimitedApp`] wraps the proxy application of the public listener, built with the
+//! `http_proxy` constructor of the patched pingora-proxy (see `patches/README.md`), as
+//! published pingora only builds it inside a listening service. It registers each
+//! connection as it is accepted, before anything is read from it, and releases it when the
+//! connection closes however it ends. Connections over the caps are closed right away, so
+//! clients that never finish a request header still count. Requests are counted per
+//! connection to enforce `max_requests_per_connection`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+
+use crate::configuration::ListenerConfig;
+use crate::recorder::Recorder;
+use crate::sync::MutexExt;
+
+/// Reason a new connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionRejection {
+    /// The listener-wide connection cap was reached.
+    ListenerFull,
+    /// The per-client-IP connection cap was reached.
+    PerIpLimit,
+}
+
+impl ConnectionRejection {
+    /// Metric counter name for this rejection.
+    pub fn metric_name(&self) -> &'static str {
+        match self {
+            ConnectionRejection::ListenerFull => "connections_rejected_listener_full",
+            ConnectionRejection::PerIpLimit => "connections_rejected_per_ip",
+        }
+    }
+}
+
+impl fmt::Display for ConnectionRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionRejection::ListenerFull => write!(f, "listener connection limit reached"),
+            ConnectionRejection::PerIpLimit => write!(f, "per-IP connection limit reached"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionState {
+    /// Client socket -> its requests so far.
+    open: HashMap<SocketAddr, u64>,
+    /// Client IP -> number of open connections.
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionState {
+    fn remove(&mut self, peer: &SocketAddr) {
+        if self.open.remove(peer).is_some()
+            && let Some(count) = self.per_ip.get_mut(&peer.ip())
+        {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&peer.ip());
+            }
+        }
+    }
+}
+
+/// Tracks open downstream connections and enforces [`ListenerConfig`] caps.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_requests: Option<u64>,
+    state: Mutex<ConnectionState>,
+}
+
+impl ConnectionLimiter {
+    /// Create a limiter from listener settings.
+    pub fn new(config: &ListenerConfig) -> Self {
+        Self {
+            max_connections: config.max_connections,
+            max_per_ip: config.max_connections_per_ip,
+            max_requests: config.max_requests_per_connection,
+            state: Mutex::new(ConnectionState::default()),
+        }
+    }
+
+    /// Whether any limit is configured. When disabled no state is kept.
+    pub fn is_enabled(&self) -> bool {
+        self.max_connections.is_some() || self.max_per_ip.is_some() || self.max_requests.is_some()
+    }
+
+    /// Whether the connection must close after a request that was its `requests`th.
+    pub fn is_last_request(&self, requests: u64) -> bool {
+        self.max_requests.is_some_and(|max| requests >= max)
+    }
+
+    /// Register a connection accepted from `peer` if the caps allow it; it counts until
+    /// [`release`](Self::release)d.
+    pub fn open(&self, peer: SocketAddr) -> Result<(), ConnectionRejection> {
+        let mut state = self.state.lock_or_recover();
+        if let Some(max) = self.max_connections
+            && state.open.len() >= max
+        {
+            return Err(ConnectionRejection::ListenerFull);
+        }
+        let ip_count = state.per_ip.get(&peer.ip()).copied().unwrap_or(0);
+        if let Some(max) = self.max_per_ip
+            && ip_count >= max
+        {
+            return Err(ConnectionRejection::PerIpLimit);
+        }
+
+        state.open.insert(peer, 0);
+        *state.per_ip.entry(peer.ip()).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Count a request on the connection from `peer`. Returns the number of requests seen
+    /// on it, this one included.
+    pub fn on_request(&self, peer: SocketAddr) -> u64 {
+        let mut state = self.state.lock_or_recover();
+        match state.open.get_mut(&peer) {
+            Some(requests) => {
+                *requests += 1;
+                *requests
+            }
+            None => 1,
+        }
+    }
+
+    /// Release a closed connection.
+    pub fn release(&self, peer: SocketAddr) {
+        let mut state = self.state.lock_or_recover();
+        state.remove(&peer);
+    }
+
+    /// Number of connections currently counted against the limits.
+    pub fn open_connections(&self) -> usize {
+        self.state.lock_or_recover().open.len()
+    }
+}
+
+/// Connection released from its limiter when dropped, however the connection ends.
+struct OpenConnection {
+    limiter: Arc<ConnectionLimiter>,
+    peer: SocketAddr,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.limiter.release(self.peer);
+    }
+}
+
+/// Application serving connections through `inner` while a [`ConnectionLimiter`] admits
+/// them.
+pub struct LimitedApp<A> {
+    inner: Arc<A>,
+    limiter: Arc<ConnectionLimiter>,
+    recorder: Recorder,
+}
+
+impl<A> LimitedApp<A> {
+    pub fn new(inner: A, limiter: Arc<ConnectionLimiter>, recorder: Recorder) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            limiter,
+            recorder,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for LimitedApp<A> {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let peer = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+        let Some(peer) = peer else {
+            return self.inner.process_new(stream, shutdown).await;
+        };
+        if let Err(rejection) = self.limiter.open(peer) {
+            log::debug!("Closing connection from {peer}: {rejection}");
+            self.recorder.increment(rejection.metric_name());
+            return None;
+        }
+        let _open = OpenConnection {
+            limiter: self.limiter.clone(),
+            peer,
+        };
+        // Serve every request of the connection here, so it is released once it closes
+        let mut next = self.inner.process_new(stream, shutdown).await;
+        while let Some(stream) = next {
+            next = self.inner.process_new(stream, shutdown).await;
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.inner.cleanup().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max: Option<usize>, per_ip: Option<usize>) -> ConnectionLimiter {
+        ConnectionLimiter::new(&ListenerConfig {
+            max_connections: max,
+            max_connections_per_ip: per_ip,
+            ..Default::default()
+        })
+    }
+
+    fn peer(ip: &str, port: u16) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), port)
+    }
+
+    #[test]
+    fn per_ip_cap_rejects_extra_connections() {
+        let limiter = limiter(None, Some(2));
+        assert!(limiter.open(peer("10.0.0.1", 1000)).is_ok());
+        assert!(limiter.open(peer("10.0.0.1", 1001)).is_ok());
+        assert_eq!(
+            limiter.open(peer("10.0.0.1", 1002)),
+            Err(ConnectionRejection::PerIpLimit)
+        );
+        // Another client is unaffected
+        assert!(limiter.open(peer("10.0.0.2", 1000)).is_ok());
+    }
+
+    #[test]
+    fn listener_cap_and_release() {
+        let limiter = limiter(Some(1), None);
+        assert!(limiter.open(peer("10.0.0.1", 1000)).is_ok());
+        assert_eq!(
+            limiter.open(peer("10.0.0.2", 1000)),
+            Err(ConnectionRejection::ListenerFull)
+        );
+
+        limiter.release(peer("10.0.0.1", 1000));
+        assert_eq!(limiter.open_connections(), 0);
+        assert!(limiter.open(peer("10.0.0.2", 1000)).is_ok());
+    }
+
+    #[test]
+    fn requests_are_counted_per_connection() {
+        let limiter = ConnectionLimiter::new(&ListenerConfig {
+            max_requests_per_connection: Some(2),
+            ..Default::default()
+        });
+        assert!(limiter.is_enabled());
+        limiter.open(peer("10.0.0.1", 1000)).unwrap();
+        limiter.open(peer("10.0.0.1", 1001)).unwrap();
+        assert_eq!(limiter.on_request(peer("10.0.0.1", 1000)), 1);
+        assert_eq!(limiter.on_request(peer("10.0.0.1", 1001)), 1);
+        assert_eq!(limiter.on_request(peer("10.0.0.1", 1000)), 2);
+        assert!(!limiter.is_last_request(1));
+        assert!(limiter.is_last_request(2));
+
+        // A new connection reusing the port starts over
+        limiter.release(peer("10.0.0.1", 1000));
+        limiter.open(peer("10.0.0.1", 1000)).unwrap();
+        assert_eq!(limiter.on_request(peer("10.0.0.1", 1000)), 1);
+    }
+}
@@ -0,0 +1,483 @@
+//! Control-plane pushes.
+//!
+//! With `admin.control_plane: true` a control plane can push changes into the running LB
+//! through `POST /admin/control/push`, or over gRPC with `admin.control_plane_grpc` (see
+//! [`crate::grpc`]), instead of waiting for the next poll of the backend config file or the
+//! accounts DB:
+//!
+//! ```json
+//! { "version": 42, "kind": "backend_config", "source": "services: ..." }
+//! { "version": 43, "kind": "revoke_key", "fingerprint": "lb_1a2b3c4d" }
+//! { "version": 44, "kind": "plan", "plan": { "plan_id": 2, "name": "pro", ... } }
+//! { "version": 45, "kind": "account_plan", "account_id": 7, "plan_id": 2 }
+//! ```
+//!
+//! Every push carries a version, increasing with each change the control plane makes, and
+//! is answered with an [`Ack`] for it: `applied`, `duplicate` for a version applied
+//! already (pushes are safe to redeliver) or `rejected` with the error.
+//! `GET /admin/control` returns the last version applied, from which a control plane
+//! resumes after either side restarts.
+//!
+//! Pushes are a fast path, not a new source of truth. A backend config is written over the
+//! backend config file before it is applied, so reloads and restarts agree with it. Key and
+//! plan changes only take effect in memory: the accounts DB belongs to the control plane,
+//! which persists its own changes. Until it has, each load of the DB applies them again,
+//! so the periodic reloads do not roll them back (see [`Pushed`]); a restart starts from
+//! the DB. Revocations go to the account partition of the key's fingerprint, account
+//! moves to the partition with the account, and plans to the partitions with the plan, or
+//! the primary accounts DB for a new one.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{AccountStore, Plan, Pushed, PushedChange};
+use crate::clock::{Clock, SystemClock};
+use crate::reload::RuntimeReloader;
+use crate::sync::{MutexExt, RwLockExt};
+
+/// A change pushed by the control plane.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Push {
+    /// Replace the backend config with `source`.
+    BackendConfig { source: String },
+    /// Stop accepting the key with `fingerprint`.
+    RevokeKey { fingerprint: String },
+    /// Add a plan or change its limits.
+    Plan { plan: Plan },
+    /// Move an account to another plan.
+    AccountPlan { account_id: i64, plan_id: i64 },
+}
+
+/// A push with the control plane's version of it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PushRequest {
+    pub version: u64,
+    #[serde(flatten)]
+    pub push: Push,
+}
+
+/// What became of a push.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    Applied,
+    /// The version was applied before; nothing changed.
+    Duplicate,
+    Rejected,
+}
+
+/// Answer to a push.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ack {
+    pub version: u64,
+    pub status: AckStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Applies control-plane pushes to the running LB.
+pub struct ControlPlane {
+    store: Arc<RwLock<AccountStore>>,
+    /// Stores of the account partitions, by token prefix.
+    partitions: Vec<(String, Arc<RwLock<AccountStore>>)>,
+    reloader: Option<Arc<RuntimeReloader>>,
+    /// Last version applied; pushes are applied one at a time under it.
+    applied: Mutex<u64>,
+}
+
+impl ControlPlane {
+    /// Apply key and plan pushes to `store`.
+    pub fn new(store: Arc<RwLock<AccountStore>>) -> Self {
+        Self {
+            store,
+            partitions: Vec::new(),
+            reloader: None,
+            applied: Mutex::new(0),
+        }
+    }
+
+    /// Apply pushes for keys with `<prefix>_` fingerprints, and for the accounts and plans
+    /// of the partition, to `store`.
+    pub fn with_partition(
+        mut self,
+        prefix: impl Into<String>,
+        store: Arc<RwLock<AccountStore>>,
+    ) -> Self {
+        self.partitions.push((prefix.into(), store));
+        self
+    }
+
+    /// Apply backend config pushes through `reloader`.
+    pub fn with_reloader(mut self, reloader: Arc<RuntimeReloader>) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Last version applied; 0 before the first push.
+    pub fn version(&self) -> u64 {
+        *self.applied.lock_or_recover()
+    }
+
+    /// Apply `request` unless a version at least as new was applied already.
+    pub fn push(&self, request: PushRequest) -> Ack {
+        let mut applied = self.applied.lock_or_recover();
+        let version = request.version;
+        if version <= *applied {
+            return Ack {
+                version,
+                status: AckStatus::Duplicate,
+                error: None,
+            };
+        }
+        match self.apply(version, request.push) {
+            Ok(()) => {
+                *applied = version;
+                Ack {
+                    version,
+                    status: AckStatus::Applied,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                log::warn!("Rejected control plane push {version}: {e}");
+                Ack {
+                    version,
+                    status: AckStatus::Rejected,
+                    error: Some(e),
+                }
+            }
+        }
+    }
+
+    /// The primary store and the partitions' stores.
+    fn stores(&self) -> impl Iterator<Item = &Arc<RwLock<AccountStore>>> {
+        std::iter::once(&self.store).chain(self.partitions.iter().map(|(_, store)| store))
+    }
+
+    /// Store of the partition the key with `fingerprint` belongs to.
+    fn store_for_fingerprint(&self, fingerprint: &str) -> &Arc<RwLock<AccountStore>> {
+        self.partitions
+            .iter()
+            .find(|(prefix, _)| {
+                fingerprint
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with('_'))
+            })
+            .map_or(&self.store, |(_, store)| store)
+    }
+
+    fn apply(&self, version: u64, push: Push) -> Result<(), String> {
+        let pushed = |change| Pushed {
+            version,
+            pushed_at: SystemClock.unix_secs(),
+            change,
+        };
+        match push {
+            Push::BackendConfig { source } => {
+                let reloader = self
+                    .reloader
+                    .as_ref()
+                    .ok_or("backend config pushes are not enabled")?;
+                reloader.apply_config(&source).map(|_| ())
+            }
+            Push::RevokeKey { fingerprint } => {
+                let mut store = self.store_for_fingerprint(&fingerprint).write_or_recover();
+                let Some(api_key_id) = store.api_key_id_for_fingerprint(&fingerprint) else {
+                    return Err(format!("no active key {fingerprint}"));
+                };
+                store.apply_pushed(pushed(PushedChange::RevokeKey { api_key_id }))?;
+                log::info!("Key {fingerprint} revoked by the control plane");
+                Ok(())
+            }
+            Push::Plan { plan } => {
+                let with_plan: Vec<_> = self
+                    .stores()
+                    .filter(|store| store.read_or_recover().plan(plan.plan_id).is_some())
+                    .collect();
+                let stores = if with_plan.is_empty() {
+                    vec![&self.store]
+                } else {
+                    with_plan
+                };
+                for store in stores {
+                    store
+                        .write_or_recover()
+                        .apply_pushed(pushed(PushedChange::Plan(plan.clone())))?;
+                }
+                log::info!("Plan {} updated by the control plane", plan.plan_id);
+                Ok(())
+            }
+            Push::AccountPlan {
+                account_id,
+                plan_id,
+            } => {
+                let store = self
+                    .stores()
+                    .find(|store| store.read_or_recover().has_account(account_id))
+                    .ok_or_else(|| format!("unknown account {account_id}"))?;
+                store
+                    .write_or_recover()
+                    .apply_pushed(pushed(PushedChange::AccountPlan {
+                        account_id,
+                        plan_id,
+                    }))?;
+                log::info!("Account {account_id} moved to plan {plan_id} by the control plane");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::accounts::{Account, AccountLoader, ApiKey};
+
+    use super::*;
+
+    fn plan(plan_id: i64, rps_limit: i32) -> Plan {
+        Plan {
+            plan_id,
+            name: format!("plan-{plan_id}"),
+            monthly_quota: 1000,
+            rps_limit,
+            price_per_1k_req: 0.0,
+            burst_cap: 0,
+            accrual_rate: 0.0,
+            priority: 0,
+        }
+    }
+
+    fn store() -> AccountStore {
+        let mut store = AccountStore::new();
+        store.upsert_plan(plan(1, 5));
+        store.upsert_account(Account {
+            account_id: 7,
+            email: "ops@example.com".to_string(),
+            plan_id: 1,
+            billing_status: "active".to_string(),
+            residency: None,
+        });
+        store.upsert_api_key(api_key(Uuid::nil(), 7, "hash"));
+        store
+    }
+
+    fn api_key(api_key: Uuid, account_id: i64, hash: &str) -> ApiKey {
+        ApiKey {
+            api_key_id: 1,
+            api_key,
+            account_id,
+            api_key_hash: hash.to_string(),
+            version: 1,
+            is_active: true,
+            created_at: None,
+            last_used_at: None,
+            scopes: vec![],
+            plan_bound: false,
+        }
+    }
+
+    fn request(version: u64, push: Push) -> PushRequest {
+        PushRequest { version, push }
+    }
+
+    #[test]
+    fn test_pushes_apply_once_in_version_order() {
+        let store = Arc::new(RwLock::new(store()));
+        let control = ControlPlane::new(store.clone());
+        assert_eq!(control.version(), 0);
+
+        let ack = control.push(request(1, Push::Plan { plan: plan(2, 50) }));
+        assert_eq!(ack.status, AckStatus::Applied);
+        let moved = request(
+            2,
+            Push::AccountPlan {
+                account_id: 7,
+                plan_id: 2,
+            },
+        );
+        assert_eq!(control.push(moved.clone()).status, AckStatus::Applied);
+        assert_eq!(
+            store
+                .read()
+                .unwrap()
+                .get_plan_for_key("hash")
+                .unwrap()
+                .rps_limit,
+            50
+        );
+        // Redelivered
+        assert_eq!(control.push(moved).status, AckStatus::Duplicate);
+
+        let fingerprint = api_key::fingerprint(store.read().unwrap().token_prefix(), Uuid::nil());
+        let revoke = Push::RevokeKey { fingerprint };
+        assert_eq!(
+            control.push(request(3, revoke.clone())).status,
+            AckStatus::Applied
+        );
+        assert!(store.read().unwrap().get_plan_for_key("hash").is_none());
+        assert_eq!(control.version(), 3);
+
+        // Failures are acked with the error and leave the version where it was
+        let ack = control.push(request(4, revoke));
+        assert_eq!(ack.status, AckStatus::Rejected);
+        assert!(ack.error.unwrap().starts_with("no active key"));
+        let ack = control.push(request(
+            5,
+            Push::BackendConfig {
+                source: String::new(),
+            },
+        ));
+        assert_eq!(ack.status, AckStatus::Rejected);
+        assert_eq!(control.version(), 3);
+    }
+
+    #[test]
+    fn test_account_pushes_survive_loads_until_the_db_has_them() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let conn = crate::sqlite::open_wal(db.path()).unwrap();
+        conn.execute_batch(include_str!("../sql/accounts.sql"))
+            .unwrap();
+        conn.execute_batch(
+            "INSERT INTO Plans (plan_id, name, monthly_quota, rps_limit, price_per_1k_req)
+             VALUES (1, 'plan-1', 1000, 5, 0.0);
+             INSERT INTO Accounts (account_id, email, plan_id, billing_status)
+             VALUES (7, 'ops@example.com', 1, 'active');
+             INSERT INTO APIKeys (api_key_id, api_key, account_id, api_key_hash)
+             VALUES (1, '00000000-0000-0000-0000-000000000000', 7, 'hash');",
+        )
+        .unwrap();
+        let loader = AccountLoader::new(db.path());
+        let store = Arc::new(RwLock::new(loader.load_initial().unwrap()));
+        let control = ControlPlane::new(store.clone());
+
+        let pushes = [
+            Push::Plan { plan: plan(2, 50) },
+            Push::AccountPlan {
+                account_id: 7,
+                plan_id: 2,
+            },
+        ];
+        for (version, push) in (1..).zip(pushes) {
+            assert_eq!(
+                control.push(request(version, push)).status,
+                AckStatus::Applied
+            );
+        }
+        let rps_limit = || {
+            store
+                .read()
+                .unwrap()
+                .get_plan_for_key("hash")
+                .map(|plan| plan.rps_limit)
+        };
+        // Left to the control plane to write, and kept over a full load meanwhile
+        let plan_id: i64 = conn
+            .query_row("SELECT plan_id FROM Accounts", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(plan_id, 1);
+        loader.reload(&store).unwrap();
+        assert_eq!(rps_limit(), Some(50));
+
+        // Once the DB has a newer change of the rows, it wins
+        conn.execute_batch(
+            "INSERT INTO Plans (plan_id, name, monthly_quota, rps_limit, price_per_1k_req,
+                                updated_at)
+             VALUES (2, 'plan-2', 1000, 60, 0.0, datetime('now', '+1 minute'));
+             UPDATE Accounts SET plan_id = 2, updated_at = datetime('now', '+1 minute');",
+        )
+        .unwrap();
+        assert!(loader.refresh(&store).unwrap());
+        assert_eq!(rps_limit(), Some(60));
+        assert!(store.read().unwrap().pushed().is_empty());
+
+        let fingerprint = api_key::fingerprint(store.read().unwrap().token_prefix(), Uuid::nil());
+        let revoke = Push::RevokeKey { fingerprint };
+        assert_eq!(control.push(request(3, revoke)).status, AckStatus::Applied);
+        loader.reload(&store).unwrap();
+        assert_eq!(rps_limit(), None);
+        let active: bool = conn
+            .query_row("SELECT is_active FROM APIKeys", [], |row| row.get(0))
+            .unwrap();
+        assert!(active);
+
+        let unknown = Push::AccountPlan {
+            account_id: 8,
+            plan_id: 2,
+        };
+        assert_eq!(
+            control.push(request(4, unknown)).status,
+            AckStatus::Rejected
+        );
+    }
+
+    #[test]
+    fn test_pushes_go_to_the_partition_of_the_key_or_account() {
+        let primary = Arc::new(RwLock::new(store()));
+        let mut eu = AccountStore::new().with_token_prefix("eu");
+        eu.upsert_plan(plan(1, 5));
+        eu.upsert_account(Account {
+            account_id: 8,
+            email: "eu@example.com".to_string(),
+            plan_id: 1,
+            billing_status: "active".to_string(),
+            residency: None,
+        });
+        let key = Uuid::from_u128(2);
+        eu.upsert_api_key(api_key(key, 8, "eu-hash"));
+        let eu = Arc::new(RwLock::new(eu));
+        let control = ControlPlane::new(primary.clone()).with_partition("eu", eu.clone());
+
+        let revoke = Push::RevokeKey {
+            fingerprint: api_key::fingerprint("eu", key),
+        };
+        assert_eq!(control.push(request(1, revoke)).status, AckStatus::Applied);
+        assert!(eu.read().unwrap().get_plan_for_key("eu-hash").is_none());
+        assert!(primary.read().unwrap().get_plan_for_key("hash").is_some());
+
+        // The plan exists in both; the account only in the partition
+        assert_eq!(
+            control
+                .push(request(2, Push::Plan { plan: plan(1, 9) }))
+                .status,
+            AckStatus::Applied
+        );
+        assert_eq!(primary.read().unwrap().plan(1).unwrap().rps_limit, 9);
+        assert_eq!(eu.read().unwrap().plan(1).unwrap().rps_limit, 9);
+        let moved = Push::AccountPlan {
+            account_id: 8,
+            plan_id: 1,
+        };
+        assert_eq!(control.push(request(3, moved)).status, AckStatus::Applied);
+        assert_eq!(eu.read().unwrap().pushed().len(), 3);
+        assert_eq!(primary.read().unwrap().pushed().len(), 1);
+    }
+
+    #[test]
+    fn test_push_requests_are_parsed() {
+        let request: PushRequest = serde_json::from_str(
+            r#"{ "version": 9, "kind": "account_plan", "account_id": 7, "plan_id": 2 }"#,
+        )
+        .unwrap();
+        assert_eq!(request.version, 9);
+        assert!(matches!(
+            request.push,
+            Push::AccountPlan {
+                account_id: 7,
+                plan_id: 2
+            }
+        ));
+        let ack = Ack {
+            version: 9,
+            status: AckStatus::Duplicate,
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&ack).unwrap(),
+            serde_json::json!({ "version": 9, "status": "duplicate" })
+        );
+    }
+}
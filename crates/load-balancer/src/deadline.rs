@@ -0,0 +1,95 @@
+//! Request timeout budget propagation.
+//!
+//! Clients may send their remaining time budget in milliseconds (by default in the
+//! `X-Request-Timeout` header). The LB turns it into an absolute deadline when the request
+//! arrives, uses the remaining budget as the upstream timeout, and forwards the decremented
+//! budget so upstream services can give up early once the client has stopped waiting.
+
+use std::time::{Duration, Instant};
+
+use pingora::http::RequestHeader;
+
+use crate::configuration::DeadlineConfig;
+
+/// Parse a budget header value in milliseconds.
+pub fn parse_budget(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_millis)
+}
+
+/// Read the budget from a request and turn it into an absolute deadline.
+///
+/// The budget is capped by `max_ms` when configured. Returns `None` when the header is
+/// missing or malformed.
+pub fn deadline_for_request(
+    req: &RequestHeader,
+    config: &DeadlineConfig,
+    received_at: Instant,
+) -> Option<Instant> {
+    let value = req.headers.get(config.header.as_str())?.to_str().ok()?;
+    let mut budget = parse_budget(value)?;
+    if let Some(max_ms) = config.max_ms {
+        budget = budget.min(Duration::from_millis(max_ms));
+    }
+    Some(received_at + budget)
+}
+
+/// Budget left for the upstream once proxy overhead is accounted for.
+///
+/// Returns `None` when the deadline has already passed (or would pass before the
+/// upstream could do any work).
+pub fn remaining_budget(deadline: Instant, now: Instant, overhead: Duration) -> Option<Duration> {
+    let remaining = deadline
+        .checked_duration_since(now)?
+        .checked_sub(overhead)?;
+    if remaining.is_zero() {
+        None
+    } else {
+        Some(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_budget_accepts_milliseconds() {
+        assert_eq!(parse_budget("250"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_budget(" 10 "), Some(Duration::from_millis(10)));
+        assert_eq!(parse_budget("1.5s"), None);
+    }
+
+    #[test]
+    fn deadline_is_capped_by_max() {
+        let config = DeadlineConfig {
+            max_ms: Some(100),
+            ..Default::default()
+        };
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-request-timeout", "5000").unwrap();
+
+        let now = Instant::now();
+        let deadline = deadline_for_request(&req, &config, now).unwrap();
+        assert_eq!(deadline - now, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn remaining_budget_subtracts_overhead() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_millis(100);
+        let overhead = Duration::from_millis(5);
+
+        assert_eq!(
+            remaining_budget(deadline, now, overhead),
+            Some(Duration::from_millis(95))
+        );
+        assert_eq!(
+            remaining_budget(deadline, now + Duration::from_millis(96), overhead),
+            None
+        );
+        assert_eq!(
+            remaining_budget(deadline, now + Duration::from_millis(200), overhead),
+            None
+        );
+    }
+}
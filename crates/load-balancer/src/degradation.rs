@@ -0,0 +1,297 @@
+//! Degraded operation while dependencies fail.
+//!
+//! Every soft dependency of the proxy has an explicit behavior for the time it fails, set
+//! by the `degradation` section:
+//!
+//! ```yaml
+//! degradation:
+//!   accounts_db: serve_cached
+//!   usage_dir: buffer
+//!   usage_buffer_records: 100000
+//!   shared_limits: split
+//!   peer_timeout_secs: 10
+//! ```
+//!
+//! - `accounts_db`: while the accounts DB cannot be loaded the proxy serves from the
+//!   account data it holds (`serve_cached`); `unready` also fails `/readyz` until the DB
+//!   loads again, so the node is drained by whatever balances over it.
+//! - `usage_dir`: usage records that cannot be written are kept in memory and written by
+//!   the next flush (`buffer`), as long as no more than `usage_buffer_records` are held;
+//!   beyond that, and with `drop`, they are lost.
+//! - `shared_limits`: while gossip peers have not been heard from for `peer_timeout_secs`,
+//!   each node enforces limits on its own traffic (`local`), or on its share of the quota:
+//!   the quota divided by the number of silent peers plus one (`split`).
+//!
+//! Failures are alerted by the services running into them. The dependencies degraded at the
+//! moment, with the error that degraded them, are listed by `/readyz` and exported as the
+//! `lb_degraded` gauge.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metric::Metrics;
+use crate::sync::MutexExt;
+
+/// Gauge set to 1 for degraded dependencies and 0 for the others.
+pub const DEGRADED_GAUGE: &str = "degraded";
+
+/// A dependency the proxy keeps serving without.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Dependency {
+    AccountsDb,
+    UsageDir,
+    SharedLimits,
+}
+
+impl Dependency {
+    pub const ALL: [Dependency; 3] = [
+        Dependency::AccountsDb,
+        Dependency::UsageDir,
+        Dependency::SharedLimits,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dependency::AccountsDb => "accounts_db",
+            Dependency::UsageDir => "usage_dir",
+            Dependency::SharedLimits => "shared_limits",
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Behavior while the accounts DB cannot be loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountsDbPolicy {
+    /// Serve from the account data held in memory.
+    #[default]
+    ServeCached,
+    /// Serve from the account data held in memory, but fail `/readyz`.
+    Unready,
+}
+
+/// Behavior while usage files cannot be written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageDirPolicy {
+    /// Keep unwritten records in memory for the next flush.
+    #[default]
+    Buffer,
+    /// Drop unwritten records.
+    Drop,
+}
+
+/// Behavior while gossip peers are silent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedLimitsPolicy {
+    /// Enforce the full quota on this node's traffic.
+    #[default]
+    Local,
+    /// Enforce this node's share of the quota.
+    Split,
+}
+
+/// Behavior of the proxy while each dependency fails.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DegradationConfig {
+    pub accounts_db: AccountsDbPolicy,
+    pub usage_dir: UsageDirPolicy,
+    /// Most usage records held in memory before unwritten ones are dropped.
+    pub usage_buffer_records: usize,
+    pub shared_limits: SharedLimitsPolicy,
+    /// Seconds without a datagram after which a gossip peer counts as silent.
+    pub peer_timeout_secs: u64,
+}
+
+impl Default for DegradationConfig {
+    fn default() -> Self {
+        Self {
+            accounts_db: AccountsDbPolicy::default(),
+            usage_dir: UsageDirPolicy::default(),
+            usage_buffer_records: 100_000,
+            shared_limits: SharedLimitsPolicy::default(),
+            peer_timeout_secs: 10,
+        }
+    }
+}
+
+impl DegradationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        // Idle peers send a heartbeat every second
+        if self.peer_timeout_secs < 2 {
+            return Err("degradation.peer_timeout_secs must be at least 2".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Which dependencies are degraded, and the policies applying while they are.
+pub struct Degradation {
+    config: DegradationConfig,
+    /// Degraded dependencies and the error that degraded them.
+    degraded: Mutex<BTreeMap<Dependency, String>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for Degradation {
+    fn default() -> Self {
+        Self::new(DegradationConfig::default())
+    }
+}
+
+impl Degradation {
+    pub fn new(config: DegradationConfig) -> Self {
+        Self {
+            config,
+            degraded: Mutex::new(BTreeMap::new()),
+            metrics: None,
+        }
+    }
+
+    /// Export the state of every dependency to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        for dependency in Dependency::ALL {
+            metrics.set_gauge(DEGRADED_GAUGE, dependency.name(), 0);
+        }
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn config(&self) -> &DegradationConfig {
+        &self.config
+    }
+
+    /// Mark `dependency` as failing with `reason`.
+    pub fn enter(&self, dependency: Dependency, reason: impl Into<String>) {
+        let reason = reason.into();
+        let previous = self
+            .degraded
+            .lock_or_recover()
+            .insert(dependency, reason.clone());
+        if previous.is_none() {
+            log::warn!(
+                "Dependency {dependency} degraded, {}: {reason}",
+                self.action(dependency)
+            );
+            if let Some(metrics) = &self.metrics {
+                metrics.set_gauge(DEGRADED_GAUGE, dependency.name(), 1);
+            }
+        }
+    }
+
+    /// Mark `dependency` as working.
+    pub fn recover(&self, dependency: Dependency) {
+        if self
+            .degraded
+            .lock_or_recover()
+            .remove(&dependency)
+            .is_some()
+        {
+            log::info!("Dependency {dependency} recovered");
+            if let Some(metrics) = &self.metrics {
+                metrics.set_gauge(DEGRADED_GAUGE, dependency.name(), 0);
+            }
+        }
+    }
+
+    pub fn is_degraded(&self, dependency: Dependency) -> bool {
+        self.degraded.lock_or_recover().contains_key(&dependency)
+    }
+
+    /// Degraded dependencies by name, with the error that degraded them.
+    pub fn degraded(&self) -> BTreeMap<&'static str, String> {
+        self.degraded
+            .lock_or_recover()
+            .iter()
+            .map(|(dependency, reason)| (dependency.name(), reason.clone()))
+            .collect()
+    }
+
+    /// Whether the node must report not ready while degraded as it is.
+    pub fn blocks_readiness(&self) -> bool {
+        self.config.accounts_db == AccountsDbPolicy::Unready
+            && self.is_degraded(Dependency::AccountsDb)
+    }
+
+    /// What the proxy does while `dependency` fails, for the log.
+    fn action(&self, dependency: Dependency) -> &'static str {
+        match dependency {
+            Dependency::AccountsDb => match self.config.accounts_db {
+                AccountsDbPolicy::ServeCached => "serving cached account data",
+                AccountsDbPolicy::Unready => "serving cached account data, reporting not ready",
+            },
+            Dependency::UsageDir => match self.config.usage_dir {
+                UsageDirPolicy::Buffer => "buffering usage records",
+                UsageDirPolicy::Drop => "dropping usage records",
+            },
+            Dependency::SharedLimits => match self.config.shared_limits {
+                SharedLimitsPolicy::Local => "enforcing limits locally",
+                SharedLimitsPolicy::Split => "enforcing a share of limits locally",
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dependencies_enter_and_recover() {
+        let metrics = Arc::new(Metrics::new());
+        let degradation = Degradation::new(DegradationConfig {
+            accounts_db: AccountsDbPolicy::Unready,
+            ..Default::default()
+        })
+        .with_metrics(metrics.clone());
+        assert_eq!(metrics.gauge(DEGRADED_GAUGE, "usage_dir"), Some(0));
+
+        degradation.enter(Dependency::UsageDir, "disk full");
+        assert!(degradation.is_degraded(Dependency::UsageDir));
+        assert!(!degradation.blocks_readiness());
+        assert_eq!(metrics.gauge(DEGRADED_GAUGE, "usage_dir"), Some(1));
+
+        degradation.enter(Dependency::AccountsDb, "locked");
+        degradation.enter(Dependency::AccountsDb, "unable to open");
+        assert!(degradation.blocks_readiness());
+        assert_eq!(
+            degradation.degraded(),
+            BTreeMap::from([
+                ("accounts_db", "unable to open".to_string()),
+                ("usage_dir", "disk full".to_string()),
+            ])
+        );
+
+        degradation.recover(Dependency::AccountsDb);
+        degradation.recover(Dependency::UsageDir);
+        assert!(degradation.degraded().is_empty());
+        assert!(!degradation.blocks_readiness());
+        assert_eq!(metrics.gauge(DEGRADED_GAUGE, "accounts_db"), Some(0));
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: DegradationConfig =
+            serde_yaml::from_str("usage_dir: drop\nshared_limits: split").unwrap();
+        assert_eq!(config.usage_dir, UsageDirPolicy::Drop);
+        assert_eq!(config.shared_limits, SharedLimitsPolicy::Split);
+        assert_eq!(config.accounts_db, AccountsDbPolicy::ServeCached);
+        assert!(config.validate().is_ok());
+        let eager = DegradationConfig {
+            peer_timeout_secs: 1,
+            ..config
+        };
+        assert!(eager.validate().is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//! Egress proxies in front of upstreams.
+//!
+//! A backend with an `egress_proxy` section is reached through an HTTP `CONNECT` or SOCKS5
+//! proxy instead of directly, for upstreams only reachable from locked-down networks through
+//! such a proxy. The LB opens a TCP connection to the proxy, asks it for a tunnel to the
+//! endpoint's address, optionally with username and password, and then speaks plain HTTP
+//! through the tunnel as it would to the endpoint itself. Host names of `basic` backends are
+//! still resolved by the LB; the proxy is given their addresses.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use pingora::connectors::L4Connect;
+use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+use pingora::protocols::l4::stream::Stream;
+use pingora::{Error, ErrorType, OrErr};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest response head accepted from an HTTP proxy.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+/// How the LB asks the proxy for a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EgressProtocol {
+    /// HTTP `CONNECT`, with Basic `Proxy-Authorization` when credentials are set.
+    HttpConnect,
+    /// SOCKS5, with username/password authentication when credentials are set.
+    Socks5,
+}
+
+/// Proxy the endpoints of a backend are reached through.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EgressProxyConfig {
+    pub protocol: EgressProtocol,
+    /// `host:port` of the proxy.
+    pub address: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for EgressProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the password out of logged configs
+        f.debug_struct("EgressProxyConfig")
+            .field("protocol", &self.protocol)
+            .field("address", &self.address)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EgressProxyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let port = self
+            .address
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.is_empty())
+            .and_then(|(_, port)| port.parse::<u16>().ok());
+        if port.is_none_or(|port| port == 0) {
+            return Err(format!(
+                "egress_proxy.address '{}' must be host:port",
+                self.address
+            ));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err("egress_proxy.username and password must be set together".to_string());
+        }
+        if self.protocol == EgressProtocol::Socks5
+            && let (Some(username), Some(password)) = (&self.username, &self.password)
+            && [username, password]
+                .iter()
+                .any(|s| !(1..=255).contains(&s.len()))
+        {
+            return Err(
+                "egress_proxy.username and password must be 1 to 255 bytes for socks5".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Connector opening upstream connections through an egress proxy.
+pub struct EgressProxy {
+    config: EgressProxyConfig,
+}
+
+impl fmt::Debug for EgressProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+impl EgressProxy {
+    pub fn new(config: EgressProxyConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &EgressProxyConfig {
+        &self.config
+    }
+
+    fn credentials(&self) -> Option<(&str, &str)> {
+        Some((
+            self.config.username.as_deref()?,
+            self.config.password.as_deref()?,
+        ))
+    }
+
+    /// A connection to the proxy tunneled to `target`.
+    pub async fn tunnel(&self, target: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.config.address).await?;
+        stream.set_nodelay(true)?;
+        match self.config.protocol {
+            EgressProtocol::HttpConnect => {
+                http_connect(&mut stream, target, self.credentials()).await?
+            }
+            EgressProtocol::Socks5 => {
+                socks5_connect(&mut stream, target, self.credentials()).await?
+            }
+        }
+        Ok(stream)
+    }
+}
+
+#[async_trait]
+impl L4Connect for EgressProxy {
+    async fn connect(&self, addr: &PeerAddr) -> pingora::Result<Stream> {
+        let Some(target) = addr.as_inet() else {
+            return Error::e_explain(
+                ErrorType::ConnectProxyFailure,
+                "egress proxies only reach TCP upstreams",
+            );
+        };
+        self.tunnel(*target)
+            .await
+            .map(Stream::from)
+            .or_err_with(ErrorType::ConnectProxyFailure, || {
+                format!("egress proxy {} to {}", self.config.address, target)
+            })
+    }
+}
+
+/// Ask an HTTP proxy for a tunnel with `CONNECT`.
+async fn http_connect(
+    stream: &mut TcpStream,
+    target: SocketAddr,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = credentials {
+        let token = STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte so nothing past the response head is taken from the tunnel
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::other("CONNECT response head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "CONNECT refused: {}",
+            status_line.trim()
+        ))),
+    }
+}
+
+/// Ask a SOCKS5 proxy for a tunnel (RFC 1928), authenticating as in RFC 1929.
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    target: SocketAddr,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        return Err(io::Error::other(
+            "SOCKS5 proxy refused the authentication method",
+        ));
+    }
+    if let Some((username, password)) = credentials {
+        let mut auth = vec![0x01, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(io::Error::other("SOCKS5 proxy rejected the credentials"));
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(0x01);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(0x04);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 0x05 || head[1] != 0x00 {
+        return Err(io::Error::other(format!(
+            "SOCKS5 proxy refused the connection with reply {}",
+            head[1]
+        )));
+    }
+    // Skip the bound address and port
+    let bound = match head[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => usize::from(stream.read_u8().await?),
+        atyp => {
+            return Err(io::Error::other(format!(
+                "SOCKS5 proxy replied with address type {atyp}"
+            )));
+        }
+    };
+    let mut skipped = vec![0u8; bound + 2];
+    stream.read_exact(&mut skipped).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn proxy(protocol: EgressProtocol, address: SocketAddr, auth: bool) -> EgressProxy {
+        EgressProxy::new(EgressProxyConfig {
+            protocol,
+            address: address.to_string(),
+            username: auth.then(|| "lb".to_string()),
+            password: auth.then(|| "secret".to_string()),
+        })
+    }
+
+    /// Proxy accepting one connection: `handshake` answers the LB, then it sends "hello".
+    async fn spawn_proxy<F, Fut>(handshake: F) -> SocketAddr
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = Option<TcpStream>> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Some(mut stream) = handshake(stream).await {
+                stream.write_all(b"hello").await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn read_hello(mut stream: TcpStream) -> String {
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        String::from_utf8_lossy(&hello).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_tunnel() {
+        let addr = spawn_proxy(|mut stream| async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("CONNECT 10.0.0.1:8080 HTTP/1.1\r\n"));
+            // lb:secret
+            assert!(head.contains("Proxy-Authorization: Basic bGI6c2VjcmV0\r\n"));
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            Some(stream)
+        })
+        .await;
+        let stream = proxy(EgressProtocol::HttpConnect, addr, true)
+            .tunnel("10.0.0.1:8080".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(read_hello(stream).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_refused() {
+        let addr = spawn_proxy(|mut stream| async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            None
+        })
+        .await;
+        let err = proxy(EgressProtocol::HttpConnect, addr, false)
+            .tunnel("10.0.0.1:8080".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("407"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_socks5_tunnel() {
+        let addr = spawn_proxy(|mut stream| async move {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x02]);
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+            let mut auth = [0u8; 3 + 2 + 6];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x02lb\x06secret");
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+            let mut request = [0u8; 4 + 16 + 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x04]);
+            assert_eq!(request[19], 1);
+            assert_eq!(request[20..], 8080u16.to_be_bytes());
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            Some(stream)
+        })
+        .await;
+        let stream = proxy(EgressProtocol::Socks5, addr, true)
+            .tunnel("[::1]:8080".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(read_hello(stream).await, "hello");
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = |address: &str, username: Option<&str>| EgressProxyConfig {
+            protocol: EgressProtocol::Socks5,
+            address: address.to_string(),
+            username: username.map(str::to_string),
+            password: Some("secret".to_string()),
+        };
+        assert!(config("proxy.internal:1080", Some("lb")).validate().is_ok());
+        assert!(config("[fd00::1]:1080", Some("lb")).validate().is_ok());
+        assert!(config("proxy.internal", Some("lb")).validate().is_err());
+        assert!(config(":1080", Some("lb")).validate().is_err());
+        assert!(config("proxy.internal:1080", None).validate().is_err());
+        assert!(config("proxy.internal:1080", Some("")).validate().is_err());
+    }
+}
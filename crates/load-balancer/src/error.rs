@@ -0,0 +1,102 @@
+//! Error classes of the load balancer.
+//!
+//! Failures raised by the LB itself, at startup or while proxying, are an [`LbError`] of one
+//! class. Converted to a pingora error it keeps the class as the error's cause and maps to
+//! the same HTTP status wherever it is raised; failed requests are counted per class in
+//! [`ERRORS_COUNTER`], so operators can alert on, say, discovery errors separately from
+//! upstream timeouts.
+
+use std::fmt;
+
+use pingora::{Error, ErrorType};
+
+/// Counter of requests failed by an [`LbError`], labeled with its class.
+pub const ERRORS_COUNTER: &str = "lb_errors";
+
+#[derive(Debug)]
+pub enum LbError {
+    /// The server or backend config cannot be used.
+    Config(String),
+    /// No service or upstream could be found for the request.
+    Discovery(String),
+    /// The caller's credentials were rejected.
+    Auth(String),
+    /// The account store or a limiter cannot admit the request.
+    Limiter(String),
+    /// Usage cannot be recorded.
+    Usage(String),
+    /// No time is left to send the request upstream.
+    Upstream(String),
+    /// The process failed otherwise, e.g. a thread could not be started.
+    Internal(String),
+}
+
+impl LbError {
+    /// Class name, as logged and counted.
+    pub fn class(&self) -> &'static str {
+        match self {
+            LbError::Config(_) => "config",
+            LbError::Discovery(_) => "discovery",
+            LbError::Auth(_) => "auth",
+            LbError::Limiter(_) => "limiter",
+            LbError::Usage(_) => "usage",
+            LbError::Upstream(_) => "upstream",
+            LbError::Internal(_) => "internal",
+        }
+    }
+
+    /// Status answered to a request failing with this error.
+    pub fn status(&self) -> u16 {
+        match self {
+            LbError::Config(_) | LbError::Usage(_) | LbError::Internal(_) => 500,
+            LbError::Discovery(_) | LbError::Limiter(_) => 503,
+            LbError::Auth(_) => 401,
+            LbError::Upstream(_) => 504,
+        }
+    }
+
+    /// The LB error a pingora error was raised from.
+    pub fn of(e: &Error) -> Option<&LbError> {
+        e.root_cause().downcast_ref::<LbError>()
+    }
+}
+
+impl fmt::Display for LbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LbError::Config(msg)
+            | LbError::Discovery(msg)
+            | LbError::Auth(msg)
+            | LbError::Limiter(msg)
+            | LbError::Usage(msg)
+            | LbError::Upstream(msg)
+            | LbError::Internal(msg) => write!(f, "{} error: {msg}", self.class()),
+        }
+    }
+}
+
+impl std::error::Error for LbError {}
+
+impl From<LbError> for Box<Error> {
+    fn from(e: LbError) -> Self {
+        Error::because(ErrorType::HTTPStatus(e.status()), e.class(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_survives_conversion() {
+        let e: Box<Error> = LbError::Discovery("no upstream for geocode".to_string()).into();
+        assert_eq!(e.etype, ErrorType::HTTPStatus(503));
+        let lb = LbError::of(&e).unwrap();
+        assert_eq!(lb.class(), "discovery");
+        assert_eq!(lb.to_string(), "discovery error: no upstream for geocode");
+
+        let wrapped = Error::because(ErrorType::InternalError, "while proxying", e);
+        assert_eq!(LbError::of(&wrapped).unwrap().status(), 503);
+        assert!(LbError::of(&Error::new(ErrorType::ConnectTimedout)).is_none());
+    }
+}
@@ -0,0 +1,235 @@
+//! Export of hourly usage files as CSV or JSON lines.
+//!
+//! Merges the `usage-<YYYYMMDDHH>.db` files covering a time range into one stream of
+//! normalized records, oldest first, so analysts do not need to query each file. Files
+//! written before service attribution export an empty service and backend, and files
+//! written before service labels empty labels.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::sqlite;
+
+/// Output format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+/// One usage row, as exported.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportRecord {
+    /// Start of the minute, RFC 3339 in UTC.
+    pub minute: String,
+    pub account_id: i64,
+    pub api_key: String,
+    pub plan_id: i64,
+    pub service: String,
+    pub backend: String,
+    /// Static labels of the service as `key=value,...`.
+    pub labels: String,
+    pub total_requests: u64,
+    pub total_data_mb: f64,
+    pub aborted_requests: u64,
+}
+
+const CSV_HEADER: &str = "minute,account_id,api_key,plan_id,service,backend,total_requests,\
+                          total_data_mb,aborted_requests,labels\n";
+
+/// Parse a range bound: `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM[:SS]` (UTC) or RFC 3339.
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(ts.timestamp());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(ts) = chrono::NaiveDateTime::parse_from_str(s, format) {
+            return Ok(ts.and_utc().timestamp());
+        }
+    }
+    chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| {
+            date.and_hms_opt(0, 0, 0)
+                .unwrap_or_default()
+                .and_utc()
+                .timestamp()
+        })
+        .map_err(|_| format!("invalid time '{s}', expected YYYY-MM-DD or RFC 3339"))
+}
+
+/// Usage files in `usage_dir` whose hour overlaps `[from, to)`, oldest first.
+fn files_in_range(usage_dir: &Path, from: i64, to: i64) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<(i64, PathBuf)> = std::fs::read_dir(usage_dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name();
+            let stamp = name.to_str()?.strip_prefix("usage-")?.strip_suffix(".db")?;
+            let hour =
+                chrono::NaiveDateTime::parse_from_str(&format!("{stamp}0000"), "%Y%m%d%H%M%S")
+                    .ok()?
+                    .and_utc()
+                    .timestamp();
+            (hour + 3600 > from && hour < to).then(|| (hour, e.path()))
+        })
+        .collect();
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Records of one usage file within `[from, to)`.
+fn read_records(path: &Path, from: i64, to: i64) -> Result<Vec<ExportRecord>, rusqlite::Error> {
+    let conn = sqlite::open_read_only(path)?;
+    let has_service: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('Usage') WHERE name = 'service'",
+        [],
+        |row| row.get(0),
+    )?;
+    let has_labels: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('Usage') WHERE name = 'labels'",
+        [],
+        |row| row.get(0),
+    )?;
+    let route = match (has_service, has_labels) {
+        (true, true) => "service, backend, labels",
+        (true, false) => "service, backend, ''",
+        _ => "'', '', ''",
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT strftime('%Y-%m-%dT%H:%M:%SZ', date_time), account_id, api_key, plan_id, {route}, \
+         COALESCE(total_requests, 0), COALESCE(total_data_mb, 0.0), aborted_requests \
+         FROM Usage \
+         WHERE date_time >= datetime(?1, 'unixepoch') AND date_time < datetime(?2, 'unixepoch') \
+         ORDER BY date_time, account_id, api_key, 5, 6"
+    ))?;
+    stmt.query_map([from, to], |row| {
+        Ok(ExportRecord {
+            minute: row.get(0)?,
+            account_id: row.get(1)?,
+            api_key: row.get(2)?,
+            plan_id: row.get(3)?,
+            service: row.get(4)?,
+            backend: row.get(5)?,
+            labels: row.get(6)?,
+            total_requests: row.get::<_, i64>(7)?.max(0) as u64,
+            total_data_mb: row.get(8)?,
+            aborted_requests: row.get::<_, i64>(9)?.max(0) as u64,
+        })
+    })?
+    .collect()
+}
+
+/// Quote a CSV field if needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_record(
+    out: &mut impl Write,
+    record: &ExportRecord,
+    format: ExportFormat,
+) -> std::io::Result<()> {
+    match format {
+        ExportFormat::Csv => writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            record.minute,
+            record.account_id,
+            record.api_key,
+            record.plan_id,
+            csv_field(&record.service),
+            csv_field(&record.backend),
+            record.total_requests,
+            record.total_data_mb,
+            record.aborted_requests,
+            csv_field(&record.labels),
+        ),
+        ExportFormat::Jsonl => {
+            serde_json::to_writer(&mut *out, record)?;
+            out.write_all(b"\n")
+        }
+    }
+}
+
+/// Write every usage record in `[from, to)` to `out`, one file at a time. Returns the
+/// number of records written.
+pub fn export(
+    usage_dir: &Path,
+    from: i64,
+    to: i64,
+    format: ExportFormat,
+    out: &mut impl Write,
+) -> std::io::Result<usize> {
+    if format == ExportFormat::Csv {
+        out.write_all(CSV_HEADER.as_bytes())?;
+    }
+    let mut written = 0;
+    for path in files_in_range(usage_dir, from, to)? {
+        let records = read_records(&path, from, to).map_err(std::io::Error::other)?;
+        for record in &records {
+            write_record(out, record, format)?;
+        }
+        written += records.len();
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::{UsageRoute, UsageTracker, UsageWriter};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1970-01-02").unwrap(), 86_400);
+        assert_eq!(parse_time("1970-01-01T01:00").unwrap(), 3600);
+        assert_eq!(parse_time("1970-01-01T02:00:00+01:00").unwrap(), 3600);
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_export_merges_files_within_range() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let tracker = Arc::new(UsageTracker::new());
+        let writer = UsageWriter::new(tracker.clone(), dir.path());
+        let route = UsageRoute {
+            service: "geocode".to_string(),
+            backend: "basic".to_string(),
+            labels: "team=geo,tier=gold".to_string(),
+        };
+        tracker.record(1, Uuid::nil(), 2, &route, 1024 * 1024, 3000);
+        tracker.record(1, Uuid::nil(), 2, &route, 0, 3600);
+        tracker.record(1, Uuid::nil(), 2, &route, 0, 3660);
+        tracker.record_aborted(1, Uuid::nil(), 2, &route, 0, 7200);
+        writer.flush_all().unwrap();
+
+        let mut out = Vec::new();
+        let written = export(dir.path(), 3000, 3660, ExportFormat::Csv, &mut out).unwrap();
+        assert_eq!(written, 2);
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER.trim_end());
+        assert_eq!(
+            lines[1..],
+            [
+                "1970-01-01T00:50:00Z,1,00000000-0000-0000-0000-000000000000,2,geocode,basic,1,1,0,\"team=geo,tier=gold\"",
+                "1970-01-01T01:00:00Z,1,00000000-0000-0000-0000-000000000000,2,geocode,basic,1,0,0,\"team=geo,tier=gold\"",
+            ]
+        );
+
+        let mut out = Vec::new();
+        export(dir.path(), 7200, 10_800, ExportFormat::Jsonl, &mut out).unwrap();
+        let record: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(record["minute"], "1970-01-01T02:00:00Z");
+        assert_eq!(record["aborted_requests"], 1);
+        assert_eq!(record["labels"], "team=geo,tier=gold");
+    }
+}
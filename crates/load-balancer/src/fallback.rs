@@ -0,0 +1,78 @@
+//! Fallback backends and responses for requests a service's backends fail.
+//!
+//! A backend marked `fallback: true` takes none of its service's requests while the other
+//! backends serve them. It gets the requests they fail, with a 5xx response or no response
+//! once retries are spent. While every other endpoint is ejected by passive health checking,
+//! it gets all of them. A service's `fallback_response` answers the requests its fallback
+//! backends fail too. A service without fallback backends answers every failed request
+//! with it:
+//!
+//! ```yaml
+//! services:
+//!   geocode:
+//!     path: /geocode
+//!     fallback_response:
+//!       status: 503
+//!       body: '{"results": [], "degraded": true}'
+//!       content_type: application/json
+//! backends:
+//!   - service: geocode
+//!     backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+//!   - service: geocode
+//!     fallback: true
+//!     backend: { type: basic, ip: 10.1.0.1, port: 8080 }
+//! ```
+//!
+//! A request only falls back while nothing was sent downstream and its body is still
+//! buffered for a retry. Each request sent to fallback backends is counted in
+//! [`FALLBACK_REQUESTS_COUNTER`], and each answered with a fallback response in
+//! [`FALLBACK_RESPONSES_COUNTER`], by service.
+
+use crate::configuration::FallbackResponseConfig;
+
+/// Labeled counter of requests sent to their service's fallback backends, by service.
+pub const FALLBACK_REQUESTS_COUNTER: &str = "fallback_requests";
+/// Labeled counter of requests answered with their service's fallback response, by service.
+pub const FALLBACK_RESPONSES_COUNTER: &str = "fallback_responses";
+
+/// Body and content type of `response` for a request to `service`; a JSON error naming the
+/// service without a configured body.
+pub fn response_body(response: &FallbackResponseConfig, service: &str) -> (String, String) {
+    match &response.body {
+        Some(body) => (
+            body.clone(),
+            response
+                .content_type
+                .clone()
+                .unwrap_or_else(|| "text/plain".to_string()),
+        ),
+        None => (
+            serde_json::json!({ "error": "service unavailable", "service": service }).to_string(),
+            "application/json".to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_body() {
+        let (body, content_type) = response_body(&FallbackResponseConfig::default(), "geocode");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "error": "service unavailable", "service": "geocode" })
+        );
+        assert_eq!(content_type, "application/json");
+
+        let configured = FallbackResponseConfig {
+            body: Some("try again later".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            response_body(&configured, "geocode"),
+            ("try again later".to_string(), "text/plain".to_string())
+        );
+    }
+}
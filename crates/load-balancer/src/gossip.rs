@@ -0,0 +1,551 @@
+//! Rate limit counters shared between LB nodes.
+//!
+//! Without a shared store each node enforces a key's quota on its own share of the
+//! traffic, so N nodes let through up to N times the quota. With a `gossip` section every
+//! node sends the per-key counts of the current fixed window to the peers in its static
+//! peer list over UDP every `interval_ms`, and counts the latest counts received from its
+//! peers against the quota along with its own. Limits are roughly global: a peer's
+//! requests are seen up to one interval (plus network delay) late, and lost datagrams
+//! are made up for by the next change of the count, which is sent whole.
+//!
+//! Keys are sent as a hash, never in the clear. Datagrams from addresses outside the
+//! peer list, and from this node itself when it is listed, are ignored.
+//!
+//! Idle nodes send an empty datagram every second, so a peer that sends nothing for
+//! `degradation.peer_timeout_secs` is silent: shared limits are degraded until it is heard
+//! from again, and enforced as the `shared_limits` policy says (see [`crate::degradation`]).
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::net::UdpSocket;
+
+use crate::degradation::{Degradation, Dependency, SharedLimitsPolicy};
+use crate::sync::{MutexExt, RwLockExt};
+
+/// Counts sent per datagram, keeping datagrams below a typical MTU.
+const COUNTS_PER_DATAGRAM: usize = 16;
+/// Largest datagram accepted.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Sharing of rate limit counters with peer LB nodes.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// UDP address counters are sent from and received on.
+    pub listen: String,
+    /// `host:port` of every peer's `listen` address. This node may be listed too.
+    pub peers: Vec<String>,
+    /// How often changed counters are sent to the peers.
+    pub interval_ms: u64,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:7946".to_string(),
+            peers: Vec::new(),
+            interval_ms: 200,
+        }
+    }
+}
+
+impl GossipConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        self.listen
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("gossip.listen: {e}"))?;
+        if self.interval_ms == 0 {
+            return Err("gossip.interval_ms must be at least 1".to_string());
+        }
+        if let Some(peer) = self.peers.iter().find(|peer| !peer.contains(':')) {
+            return Err(format!("gossip.peers: {peer} has no port"));
+        }
+        Ok(())
+    }
+}
+
+/// Count of a key in one fixed window, as sent to peers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct Count {
+    /// Hash of the API key.
+    key: String,
+    window_secs: u64,
+    /// Index of the window since the epoch.
+    window: u64,
+    count: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Message {
+    /// Node id of the sender.
+    node: String,
+    counts: Vec<Count>,
+}
+
+/// Count of a key in a window of this node, and whether peers have seen it.
+#[derive(Debug, Clone, Copy)]
+struct LocalCount {
+    window: u64,
+    count: u64,
+    sent: bool,
+}
+
+/// `(window_secs, key hash)`.
+type CounterKey = (u64, String);
+/// Latest `(window, count)` of each key reported by a peer.
+type PeerCounts = HashMap<CounterKey, (u64, u64)>;
+
+/// Per-key counters of this node and the latest counts of its peers.
+pub struct Gossip {
+    config: GossipConfig,
+    node: String,
+    local: Mutex<HashMap<CounterKey, LocalCount>>,
+    /// Counts of each peer by address.
+    remote: Mutex<HashMap<SocketAddr, PeerCounts>>,
+    /// Peer addresses as last resolved.
+    peers: RwLock<HashSet<SocketAddr>>,
+    /// Unix time each peer was last heard from, or first seen.
+    heard: Mutex<HashMap<SocketAddr, u64>>,
+    /// Addresses in the peer list that turned out to be this node.
+    own: Mutex<HashSet<SocketAddr>>,
+    /// Unix time a datagram was last sent.
+    last_sent: AtomicU64,
+    /// Number of parts quotas are split into while peers are silent; 1 otherwise.
+    share: AtomicU64,
+    degradation: Option<Arc<Degradation>>,
+}
+
+impl Gossip {
+    /// Gossip for the node `node`, whose own datagrams are ignored.
+    pub fn new(config: GossipConfig, node: impl Into<String>) -> Self {
+        Self {
+            config,
+            node: node.into(),
+            local: Mutex::new(HashMap::new()),
+            remote: Mutex::new(HashMap::new()),
+            peers: RwLock::new(HashSet::new()),
+            heard: Mutex::new(HashMap::new()),
+            own: Mutex::new(HashSet::new()),
+            last_sent: AtomicU64::new(0),
+            share: AtomicU64::new(1),
+            degradation: None,
+        }
+    }
+
+    /// Mark shared limits as degraded in `degradation` while peers are silent, and apply
+    /// its policy.
+    pub fn with_degradation(mut self, degradation: Arc<Degradation>) -> Self {
+        self.degradation = Some(degradation);
+        self
+    }
+
+    /// Part of `quota` this node enforces on its own: all of it unless peers are silent
+    /// and quotas are split.
+    pub fn local_quota(&self, quota: isize) -> isize {
+        let share = self.share.load(Ordering::Relaxed) as isize;
+        if quota <= 0 || share <= 1 {
+            return quota;
+        }
+        (quota / share).max(1)
+    }
+
+    fn counter_key(api_key: &str, window_secs: u64) -> CounterKey {
+        let hash = hex::encode(&Sha256::digest(api_key.as_bytes())[..12]);
+        (window_secs.max(1), hash)
+    }
+
+    /// Count a request of `api_key` in its window of `window_secs` at `now` (Unix seconds)
+    /// and return the requests peers reported for the same window.
+    pub fn record(&self, api_key: &str, window_secs: u64, now: u64) -> u64 {
+        let key = Self::counter_key(api_key, window_secs);
+        let window = now / key.0;
+        {
+            let mut local = self.local.lock_or_recover();
+            let entry = local.entry(key.clone()).or_insert(LocalCount {
+                window,
+                count: 0,
+                sent: false,
+            });
+            if entry.window != window {
+                *entry = LocalCount {
+                    window,
+                    count: 0,
+                    sent: false,
+                };
+            }
+            entry.count += 1;
+            entry.sent = false;
+        }
+        self.peer_count(&key, window)
+    }
+
+    /// Requests of `api_key` peers reported for its window of `window_secs` at `now`.
+    pub fn remote_count(&self, api_key: &str, window_secs: u64, now: u64) -> u64 {
+        let key = Self::counter_key(api_key, window_secs);
+        let window = now / key.0;
+        self.peer_count(&key, window)
+    }
+
+    fn peer_count(&self, key: &CounterKey, window: u64) -> u64 {
+        self.remote
+            .lock_or_recover()
+            .values()
+            .filter_map(|counts| counts.get(key))
+            .filter(|(peer_window, _)| *peer_window == window)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Counts changed since the last call, dropping those of past windows.
+    fn take_changed(&self, now: u64) -> Vec<Count> {
+        let mut local = self.local.lock_or_recover();
+        local.retain(|(window_secs, _), count| count.window >= now / window_secs);
+        local
+            .iter_mut()
+            .filter(|(_, count)| !count.sent)
+            .map(|((window_secs, key), count)| {
+                count.sent = true;
+                Count {
+                    key: key.clone(),
+                    window_secs: *window_secs,
+                    window: count.window,
+                    count: count.count,
+                }
+            })
+            .collect()
+    }
+
+    /// Datagrams carrying `counts`.
+    fn encode(&self, counts: &[Count]) -> Vec<Vec<u8>> {
+        counts
+            .chunks(COUNTS_PER_DATAGRAM)
+            .filter_map(|chunk| {
+                serde_json::to_vec(&Message {
+                    node: self.node.clone(),
+                    counts: chunk.to_vec(),
+                })
+                .ok()
+            })
+            .collect()
+    }
+
+    /// A datagram without counts.
+    fn encode_heartbeat(&self) -> Vec<Vec<u8>> {
+        serde_json::to_vec(&Message {
+            node: self.node.clone(),
+            counts: Vec::new(),
+        })
+        .ok()
+        .into_iter()
+        .collect()
+    }
+
+    /// Apply a datagram received from `from`; false if it was ignored.
+    fn receive(&self, from: SocketAddr, datagram: &[u8], now: u64) -> bool {
+        if !self.peers.read_or_recover().contains(&from) {
+            return false;
+        }
+        let Ok(message) = serde_json::from_slice::<Message>(datagram) else {
+            log::debug!("Ignoring malformed gossip datagram from {from}");
+            return false;
+        };
+        if message.node == self.node {
+            self.own.lock_or_recover().insert(from);
+            return false;
+        }
+        self.heard.lock_or_recover().insert(from, now);
+        let mut remote = self.remote.lock_or_recover();
+        let counts = remote.entry(from).or_default();
+        counts.retain(|(window_secs, _), (window, _)| *window >= now / window_secs);
+        for count in message.counts {
+            let window_secs = count.window_secs.max(1);
+            if count.window < now / window_secs {
+                continue;
+            }
+            let entry = counts
+                .entry((window_secs, count.key))
+                .or_insert((count.window, 0));
+            // Datagrams may arrive out of order; counts only grow within a window
+            if count.window > entry.0 || (count.window == entry.0 && count.count > entry.1) {
+                *entry = (count.window, count.count);
+            }
+        }
+        true
+    }
+
+    /// Resolve the peer list; peers failing to resolve are kept from the last round.
+    async fn resolve_peers(&self) -> Vec<SocketAddr> {
+        let mut resolved = HashSet::new();
+        for peer in &self.config.peers {
+            match tokio::net::lookup_host(peer).await {
+                Ok(addrs) => resolved.extend(addrs),
+                Err(e) => log::warn!("Failed to resolve gossip peer {peer}: {e}"),
+            }
+        }
+        let mut peers = self.peers.write_or_recover();
+        if resolved.is_empty() && !self.config.peers.is_empty() {
+            return peers.iter().copied().collect();
+        }
+        *peers = resolved;
+        peers.iter().copied().collect()
+    }
+
+    /// Peers not heard from for `timeout` seconds at `now`. Peers are given `timeout`
+    /// from the first check that sees them.
+    fn silent_peers(&self, now: u64, timeout: u64) -> Vec<SocketAddr> {
+        let own = self.own.lock_or_recover();
+        let mut heard = self.heard.lock_or_recover();
+        let mut silent: Vec<SocketAddr> = self
+            .peers
+            .read_or_recover()
+            .iter()
+            .filter(|peer| !own.contains(peer))
+            .filter(|peer| now.saturating_sub(*heard.entry(**peer).or_insert(now)) >= timeout)
+            .copied()
+            .collect();
+        silent.sort();
+        silent
+    }
+
+    /// Degrade shared limits while peers are silent, or recover them.
+    fn check_peers(&self, now: u64) {
+        let Some(degradation) = &self.degradation else {
+            return;
+        };
+        let timeout = degradation.config().peer_timeout_secs;
+        let silent = self.silent_peers(now, timeout);
+        if silent.is_empty() {
+            self.share.store(1, Ordering::Relaxed);
+            degradation.recover(Dependency::SharedLimits);
+            return;
+        }
+        let share = match degradation.config().shared_limits {
+            SharedLimitsPolicy::Local => 1,
+            SharedLimitsPolicy::Split => silent.len() as u64 + 1,
+        };
+        self.share.store(share, Ordering::Relaxed);
+        let silent: Vec<String> = silent.iter().map(SocketAddr::to_string).collect();
+        degradation.enter(
+            Dependency::SharedLimits,
+            format!("no gossip for {timeout}s from {}", silent.join(", ")),
+        );
+    }
+
+    async fn send_changed(&self, socket: &UdpSocket) {
+        let peers = self.resolve_peers().await;
+        let now = unix_now();
+        let counts = self.take_changed(now);
+        let datagrams = if !counts.is_empty() {
+            self.encode(&counts)
+        } else if self.last_sent.load(Ordering::Relaxed) < now {
+            // A heartbeat, so peers can tell an idle node from a lost one
+            self.encode_heartbeat()
+        } else {
+            return;
+        };
+        self.last_sent.store(now, Ordering::Relaxed);
+        for datagram in datagrams {
+            for peer in &peers {
+                if let Err(e) = socket.send_to(&datagram, peer).await {
+                    log::debug!("Failed to send gossip to {peer}: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+#[async_trait]
+impl BackgroundService for Gossip {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        let socket = match UdpSocket::bind(&self.config.listen).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("Failed to bind gossip socket {}: {}", self.config.listen, e);
+                return;
+            }
+        };
+        log::info!(
+            "Gossiping rate limit counters on {} with {} peers",
+            self.config.listen,
+            self.config.peers.len()
+        );
+        self.resolve_peers().await;
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.interval_ms));
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {
+                    self.send_changed(&socket).await;
+                    self.check_peers(unix_now());
+                }
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => {
+                        self.receive(from, &buf[..len], unix_now());
+                    }
+                    Err(e) => log::debug!("Failed to receive gossip: {e}"),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn gossip(node: &str, peers: &[SocketAddr]) -> Gossip {
+        let gossip = Gossip::new(GossipConfig::default(), node);
+        *gossip.peers.write().unwrap() = peers.iter().copied().collect();
+        gossip
+    }
+
+    #[test]
+    fn test_peer_counts_are_added_per_window() {
+        let a = gossip("a", &[peer(1), peer(2)]);
+        let b = gossip("b", &[peer(1), peer(2)]);
+
+        for _ in 0..3 {
+            a.record("key", 60, 120);
+        }
+        a.record("other", 60, 120);
+        let counts = a.take_changed(120);
+        assert_eq!(counts.len(), 2);
+        assert!(a.take_changed(120).is_empty());
+        for datagram in a.encode(&counts) {
+            assert!(b.receive(peer(1), &datagram, 130));
+        }
+
+        assert_eq!(b.record("key", 60, 130), 3);
+        assert_eq!(b.remote_count("other", 60, 130), 1);
+        // Another window length is another counter
+        assert_eq!(b.remote_count("key", 1, 130), 0);
+        // The next window starts over
+        assert_eq!(b.remote_count("key", 60, 180), 0);
+
+        // Stale and reordered datagrams do not lower the count
+        a.record("key", 60, 121);
+        let newer = a.encode(&a.take_changed(121));
+        b.receive(peer(1), &newer[0], 131);
+        let stale: Vec<Count> = counts.iter().filter(|c| c.count == 3).cloned().collect();
+        b.receive(peer(1), &a.encode(&stale)[0], 131);
+        assert_eq!(b.remote_count("key", 60, 131), 4);
+    }
+
+    #[test]
+    fn test_unknown_senders_and_own_datagrams_are_ignored() {
+        let a = gossip("a", &[peer(1)]);
+        let b = gossip("b", &[peer(1)]);
+        a.record("key", 1, 10);
+        let datagram = a.encode(&a.take_changed(10)).remove(0);
+
+        assert!(!b.receive(peer(9), &datagram, 10));
+        assert!(!a.receive(peer(1), &datagram, 10));
+        assert!(!b.receive(peer(1), b"not json", 10));
+        assert_eq!(b.remote_count("key", 1, 10), 0);
+        // Keys travel as hashes only
+        assert!(!String::from_utf8_lossy(&datagram).contains("\"key\":\"key\""));
+    }
+
+    #[test]
+    fn test_silent_peers_degrade_shared_limits() {
+        use crate::degradation::DegradationConfig;
+
+        let degradation = Arc::new(Degradation::new(DegradationConfig {
+            shared_limits: SharedLimitsPolicy::Split,
+            ..Default::default()
+        }));
+        let a = gossip("a", &[peer(1), peer(2), peer(3)]).with_degradation(degradation.clone());
+        let b = gossip("b", &[peer(1)]);
+        let heartbeat = b.encode_heartbeat().remove(0);
+        // peer(3) is this node, which never counts as silent
+        a.receive(peer(3), &a.encode_heartbeat()[0], 100);
+
+        a.check_peers(100);
+        assert_eq!(a.local_quota(10), 10);
+        a.receive(peer(1), &heartbeat, 108);
+        a.check_peers(110);
+        assert!(degradation.is_degraded(Dependency::SharedLimits));
+        assert_eq!(
+            degradation.degraded()["shared_limits"],
+            "no gossip for 10s from 127.0.0.1:2"
+        );
+        assert_eq!(a.local_quota(10), 5);
+        assert_eq!(a.local_quota(1), 1);
+        assert_eq!(a.local_quota(0), 0);
+
+        a.receive(peer(2), &heartbeat, 111);
+        a.check_peers(112);
+        assert!(!degradation.is_degraded(Dependency::SharedLimits));
+        assert_eq!(a.local_quota(10), 10);
+    }
+
+    #[tokio::test]
+    async fn test_counters_reach_peers_over_udp() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (addr_a, addr_b) = (
+            socket_a.local_addr().unwrap(),
+            socket_b.local_addr().unwrap(),
+        );
+        let config = GossipConfig {
+            peers: vec![addr_a.to_string(), addr_b.to_string()],
+            ..Default::default()
+        };
+        let a = Gossip::new(config.clone(), "a");
+        let b = Gossip::new(config, "b");
+        b.resolve_peers().await;
+
+        let now = unix_now();
+        a.record("key", 3600, now);
+        a.record("key", 3600, now);
+        a.send_changed(&socket_a).await;
+
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let (len, from) = socket_b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, addr_a);
+        assert!(b.receive(from, &buf[..len], now));
+        assert_eq!(b.remote_count("key", 3600, now), 2);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(GossipConfig::default().validate().is_ok());
+        let invalid = [
+            GossipConfig {
+                listen: "nowhere".to_string(),
+                ..Default::default()
+            },
+            GossipConfig {
+                interval_ms: 0,
+                ..Default::default()
+            },
+            GossipConfig {
+                peers: vec!["lb-2".to_string()],
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
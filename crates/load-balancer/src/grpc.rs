@@ -0,0 +1,353 @@
+//! Control-plane pushes over gRPC.
+//!
+//! With `admin.control_plane_grpc` set, a control plane can push the changes of
+//! [`crate::control`] over gRPC instead of HTTP, with the service of `proto/control.proto`:
+//!
+//! ```yaml
+//! admin:
+//!   listen: 127.0.0.1:9090
+//!   token: change-me
+//!   control_plane_grpc: 127.0.0.1:9091
+//! ```
+//!
+//! `Push` applies a change and answers with its [`Ack`], and `Version` returns the last
+//! version applied. Pushes are the same as on `/admin/control/push`: versioned, safe to
+//! redeliver, and backend changes are written to the backend config file before they
+//! take effect. When the admin listener has a token, every call must carry it as
+//! `authorization: Bearer <token>` metadata.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use tonic::{Request, Response, Status};
+
+use crate::accounts;
+use crate::control::{self, ControlPlane, Push};
+use crate::logging::AUDIT_TARGET;
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/lb.control.v1.ControlPlane.rs"));
+}
+
+pub use generated::control_plane_client::ControlPlaneClient;
+pub use generated::control_plane_server::ControlPlaneServer;
+
+/// A change pushed by the control plane, with its version.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PushRequest {
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+    #[prost(oneof = "Change", tags = "2, 3, 4, 5")]
+    pub change: Option<Change>,
+}
+
+/// The change of a [`PushRequest`].
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Change {
+    /// Source of the backend config to replace the current one with.
+    #[prost(string, tag = "2")]
+    BackendConfig(String),
+    /// Fingerprint of the key to stop accepting.
+    #[prost(string, tag = "3")]
+    RevokeKey(String),
+    /// Plan to add or change.
+    #[prost(message, tag = "4")]
+    Plan(Plan),
+    /// Account to move to another plan.
+    #[prost(message, tag = "5")]
+    AccountPlan(AccountPlan),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Plan {
+    #[prost(int64, tag = "1")]
+    pub plan_id: i64,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(int32, tag = "3")]
+    pub monthly_quota: i32,
+    #[prost(int32, tag = "4")]
+    pub rps_limit: i32,
+    #[prost(double, tag = "5")]
+    pub price_per_1k_req: f64,
+    #[prost(int32, tag = "6")]
+    pub burst_cap: i32,
+    #[prost(double, tag = "7")]
+    pub accrual_rate: f64,
+    #[prost(int32, tag = "8")]
+    pub priority: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AccountPlan {
+    #[prost(int64, tag = "1")]
+    pub account_id: i64,
+    #[prost(int64, tag = "2")]
+    pub plan_id: i64,
+}
+
+/// What became of a push.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum AckStatus {
+    Unspecified = 0,
+    Applied = 1,
+    /// The version was applied before; nothing changed.
+    Duplicate = 2,
+    Rejected = 3,
+}
+
+/// Answer to a push.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ack {
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+    #[prost(enumeration = "AckStatus", tag = "2")]
+    pub status: i32,
+    /// Why the push was rejected.
+    #[prost(string, tag = "3")]
+    pub error: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VersionRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VersionReply {
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+}
+
+impl From<Plan> for accounts::Plan {
+    fn from(plan: Plan) -> Self {
+        Self {
+            plan_id: plan.plan_id,
+            name: plan.name,
+            monthly_quota: plan.monthly_quota,
+            rps_limit: plan.rps_limit,
+            price_per_1k_req: plan.price_per_1k_req,
+            burst_cap: plan.burst_cap,
+            accrual_rate: plan.accrual_rate,
+            priority: plan.priority,
+        }
+    }
+}
+
+impl From<Change> for Push {
+    fn from(change: Change) -> Self {
+        match change {
+            Change::BackendConfig(source) => Push::BackendConfig { source },
+            Change::RevokeKey(fingerprint) => Push::RevokeKey { fingerprint },
+            Change::Plan(plan) => Push::Plan { plan: plan.into() },
+            Change::AccountPlan(AccountPlan {
+                account_id,
+                plan_id,
+            }) => Push::AccountPlan {
+                account_id,
+                plan_id,
+            },
+        }
+    }
+}
+
+impl From<control::Ack> for Ack {
+    fn from(ack: control::Ack) -> Self {
+        let status = match ack.status {
+            control::AckStatus::Applied => AckStatus::Applied,
+            control::AckStatus::Duplicate => AckStatus::Duplicate,
+            control::AckStatus::Rejected => AckStatus::Rejected,
+        };
+        Self {
+            version: ack.version,
+            status: status as i32,
+            error: ack.error.unwrap_or_default(),
+        }
+    }
+}
+
+/// The gRPC service over a [`ControlPlane`].
+pub struct ControlPlaneService {
+    control: Arc<ControlPlane>,
+    token: Option<String>,
+}
+
+impl ControlPlaneService {
+    /// Apply pushes through `control`, to callers with `token` if any.
+    pub fn new(control: Arc<ControlPlane>, token: Option<String>) -> Self {
+        Self { control, token }
+    }
+
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token);
+        if authorized {
+            Ok(())
+        } else {
+            log::warn!(target: AUDIT_TARGET, "control plane gRPC call rejected: unauthenticated");
+            Err(Status::unauthenticated("invalid or missing token"))
+        }
+    }
+}
+
+#[async_trait]
+impl generated::control_plane_server::ControlPlane for ControlPlaneService {
+    async fn push(&self, request: Request<PushRequest>) -> Result<Response<Ack>, Status> {
+        self.authorize(&request)?;
+        let PushRequest { version, change } = request.into_inner();
+        let push: Push = change
+            .ok_or_else(|| Status::invalid_argument("push without a change"))?
+            .into();
+        let kind = serde_json::to_value(&push)
+            .ok()
+            .and_then(|push| push["kind"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        let control = self.control.clone();
+        let ack = tokio::task::spawn_blocking(move || {
+            control.push(control::PushRequest { version, push })
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        log::info!(target: AUDIT_TARGET, "control plane gRPC push {version} ({kind}): {:?}", ack.status);
+        Ok(Response::new(ack.into()))
+    }
+
+    async fn version(
+        &self,
+        request: Request<VersionRequest>,
+    ) -> Result<Response<VersionReply>, Status> {
+        self.authorize(&request)?;
+        Ok(Response::new(VersionReply {
+            version: self.control.version(),
+        }))
+    }
+}
+
+/// Serves the control-plane gRPC service until shutdown.
+pub struct GrpcListener {
+    listen: String,
+    control: Arc<ControlPlane>,
+    token: Option<String>,
+}
+
+impl GrpcListener {
+    /// Serve pushes to `control` on `listen`, to callers with `token` if any.
+    pub fn new(
+        listen: impl Into<String>,
+        control: Arc<ControlPlane>,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            listen: listen.into(),
+            control,
+            token,
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for GrpcListener {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let addr: SocketAddr = match self.listen.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error!("Invalid control plane gRPC address {}: {e}", self.listen);
+                return;
+            }
+        };
+        let service = ControlPlaneService::new(self.control.clone(), self.token.clone());
+        let result = tonic::transport::Server::builder()
+            .add_service(ControlPlaneServer::new(service))
+            .serve_with_shutdown(addr, async move {
+                let _ = shutdown.changed().await;
+            })
+            .await;
+        if let Err(e) = result {
+            log::error!("Control plane gRPC listener on {addr} failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use crate::accounts::AccountStore;
+
+    use super::*;
+    use generated::control_plane_server::ControlPlane as _;
+
+    fn service(token: Option<&str>) -> (Arc<RwLock<AccountStore>>, ControlPlaneService) {
+        let store = Arc::new(RwLock::new(AccountStore::new()));
+        let control = Arc::new(ControlPlane::new(store.clone()));
+        (
+            store,
+            ControlPlaneService::new(control, token.map(str::to_string)),
+        )
+    }
+
+    fn plan_push(version: u64) -> PushRequest {
+        PushRequest {
+            version,
+            change: Some(Change::Plan(Plan {
+                plan_id: 2,
+                name: "pro".to_string(),
+                rps_limit: 50,
+                ..Default::default()
+            })),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pushes_are_applied_and_acked() {
+        let (store, service) = service(None);
+        let ack = service.push(Request::new(plan_push(1))).await.unwrap();
+        assert_eq!(ack.get_ref().status(), AckStatus::Applied);
+        assert_eq!(store.read().unwrap().plan(2).unwrap().rps_limit, 50);
+        let ack = service.push(Request::new(plan_push(1))).await.unwrap();
+        assert_eq!(ack.get_ref().status(), AckStatus::Duplicate);
+
+        let revoke = PushRequest {
+            version: 2,
+            change: Some(Change::RevokeKey("lb_unknown".to_string())),
+        };
+        let ack = service
+            .push(Request::new(revoke))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(ack.status(), AckStatus::Rejected);
+        assert!(ack.error.starts_with("no active key"));
+
+        let version = service.version(Request::new(VersionRequest {})).await;
+        assert_eq!(version.unwrap().get_ref().version, 1);
+        let empty = PushRequest {
+            version: 3,
+            change: None,
+        };
+        let status = service.push(Request::new(empty)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_calls_need_the_admin_token() {
+        let (_, service) = service(Some("secret"));
+        let status = service.push(Request::new(plan_push(1))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let mut request = Request::new(plan_push(1));
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        assert!(service.push(request).await.is_ok());
+    }
+}
@@ -0,0 +1,319 @@
+//! Upstreams discovered from the Hetzner Cloud API.
+//!
+//! Backends of type `hetzner` take their upstreams from the running servers whose labels
+//! match the backend's `labels`, polled every `refresh_secs`:
+//!
+//! ```yaml
+//! hetzner:
+//!   token_env: HCLOUD_TOKEN
+//!   refresh_secs: 30
+//!   private_network: true
+//! backends:
+//!   - service: geocode
+//!     backend:
+//!       type: hetzner
+//!       labels: [{ service: geocode }]
+//!       port: 8099
+//! ```
+//!
+//! Each label set of the selector is queried on its own, following the pages of
+//! `GET /servers`. A server's endpoint is its first private network address, or its public
+//! IPv4 with `private_network: false` or no private network, zoned by its location.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::selector::LabelSelector;
+use crate::sync::RwLockExt;
+use crate::upstream::{Endpoint, UpstreamsProvider, http_client};
+
+/// Hetzner Cloud discovery settings, under `hetzner` in the backend config.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HetznerConfig {
+    /// Base URL of the Hetzner Cloud API.
+    pub api_url: String,
+    /// API token; read from the `token_env` variable when unset.
+    pub token: Option<String>,
+    /// Environment variable holding the API token.
+    pub token_env: String,
+    /// Seconds between discovery runs.
+    pub refresh_secs: u64,
+    /// Use the first private network address of a server, falling back to its public IPv4.
+    pub private_network: bool,
+}
+
+impl Default for HetznerConfig {
+    fn default() -> Self {
+        Self {
+            api_url: "https://api.hetzner.cloud/v1".to_string(),
+            token: None,
+            token_env: "HCLOUD_TOKEN".to_string(),
+            refresh_secs: 30,
+            private_network: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ServersPage {
+    servers: Vec<HetznerServer>,
+    #[serde(default)]
+    meta: Option<PageMeta>,
+}
+
+#[derive(Deserialize)]
+struct PageMeta {
+    pagination: Pagination,
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    next_page: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct HetznerServer {
+    status: String,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    public_net: PublicNet,
+    #[serde(default)]
+    private_net: Vec<PrivateNet>,
+    #[serde(default)]
+    datacenter: Option<Datacenter>,
+}
+
+#[derive(Deserialize)]
+struct Datacenter {
+    location: Location,
+}
+
+#[derive(Deserialize)]
+struct Location {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct PublicNet {
+    ipv4: Option<Ipv4>,
+}
+
+#[derive(Deserialize)]
+struct Ipv4 {
+    ip: String,
+}
+
+#[derive(Deserialize)]
+struct PrivateNet {
+    ip: String,
+}
+
+/// Running Hetzner Cloud servers matching a label selector.
+#[derive(Debug)]
+pub struct HetznerUpstreams {
+    selector: LabelSelector,
+    port: u16,
+    config: HetznerConfig,
+    endpoints: RwLock<Arc<Vec<Endpoint>>>,
+}
+
+impl HetznerUpstreams {
+    pub fn new(selector: LabelSelector, port: u16, config: HetznerConfig) -> Self {
+        Self {
+            selector,
+            port,
+            config,
+            endpoints: RwLock::new(Arc::new(Vec::new())),
+        }
+    }
+
+    /// Endpoints of the running servers in one page of `GET /servers` that match the
+    /// selector.
+    fn page_endpoints(&self, page: &ServersPage) -> Vec<Endpoint> {
+        page.servers
+            .iter()
+            .filter(|server| server.status == "running" && self.selector.matches(&server.labels))
+            .filter_map(|server| {
+                let private = self
+                    .config
+                    .private_network
+                    .then(|| server.private_net.first().map(|net| net.ip.as_str()))
+                    .flatten();
+                let public = server.public_net.ipv4.as_ref().map(|ip| ip.ip.as_str());
+                let endpoint = Endpoint::new(private.or(public)?, self.port);
+                // Servers are zoned by location (fsn1, nbg1, ...)
+                Some(match &server.datacenter {
+                    Some(datacenter) => endpoint.with_zone(&datacenter.location.name),
+                    None => endpoint,
+                })
+            })
+            .collect()
+    }
+
+    async fn discover(&self) -> Result<Vec<Endpoint>, String> {
+        let token = match &self.config.token {
+            Some(token) => token.clone(),
+            None => std::env::var(&self.config.token_env)
+                .map_err(|_| format!("{} is not set", self.config.token_env))?,
+        };
+        let mut endpoints = Vec::new();
+        // The API only ANDs labels, so each label set is queried separately
+        for query in self.selector.queries() {
+            let mut page = Some(1);
+            while let Some(number) = page {
+                let response: ServersPage = http_client()
+                    .get(format!("{}/servers", self.config.api_url))
+                    .bearer_auth(&token)
+                    .query(&[
+                        ("label_selector", query.as_str()),
+                        ("page", &number.to_string()),
+                        ("per_page", "50"),
+                    ])
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| format!("Hetzner API request failed: {e}"))?
+                    .json()
+                    .await
+                    .map_err(|e| format!("invalid Hetzner API response: {e}"))?;
+                endpoints.extend(self.page_endpoints(&response));
+                page = response.meta.and_then(|m| m.pagination.next_page);
+            }
+        }
+        endpoints.sort_by(|a, b| a.addr.cmp(&b.addr));
+        endpoints.dedup();
+        Ok(endpoints)
+    }
+}
+
+#[async_trait]
+impl UpstreamsProvider for HetznerUpstreams {
+    fn endpoints(&self) -> Arc<Vec<Endpoint>> {
+        self.endpoints.read_or_recover().clone()
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.config.refresh_secs.max(1)))
+    }
+
+    async fn refresh(&self) -> Result<(), String> {
+        let endpoints = self.discover().await?;
+        *self.endpoints.write_or_recover() = Arc::new(endpoints);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hetzner_page_filters_running_matching_servers() {
+        let selector = LabelSelector::new(&[HashMap::from([(
+            "service".to_string(),
+            "geocode".to_string(),
+        )])])
+        .unwrap();
+        let provider = HetznerUpstreams::new(selector, 8099, HetznerConfig::default());
+        let page: ServersPage = serde_json::from_value(serde_json::json!({
+            "servers": [
+                {
+                    "status": "running",
+                    "labels": {"service": "geocode"},
+                    "public_net": {"ipv4": {"ip": "1.2.3.4"}},
+                    "private_net": [{"ip": "10.0.0.2"}],
+                    "datacenter": {"name": "fsn1-dc14", "location": {"name": "fsn1"}}
+                },
+                {
+                    "status": "running",
+                    "labels": {"service": "geocode"},
+                    "public_net": {"ipv4": {"ip": "1.2.3.5"}}
+                },
+                {
+                    "status": "off",
+                    "labels": {"service": "geocode"},
+                    "public_net": {"ipv4": {"ip": "1.2.3.6"}}
+                },
+                {
+                    "status": "running",
+                    "labels": {"service": "search"},
+                    "public_net": {"ipv4": {"ip": "1.2.3.7"}}
+                }
+            ],
+            "meta": {"pagination": {"next_page": null}}
+        }))
+        .unwrap();
+        assert_eq!(
+            provider.page_endpoints(&page),
+            vec![
+                Endpoint::new("10.0.0.2", 8099).with_zone("fsn1"),
+                Endpoint::new("1.2.3.5", 8099)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hetzner_discovery_follows_pages() {
+        use axum::extract::Query;
+        use axum::http::HeaderMap;
+
+        let expected = "Bearer test-token";
+        let app = axum::Router::new().route(
+            "/servers",
+            axum::routing::get(
+                move |headers: HeaderMap, Query(query): Query<HashMap<String, String>>| {
+                    let authorized = headers["authorization"] == expected;
+                    async move {
+                        assert!(authorized);
+                        assert_eq!(query["label_selector"], "service=geocode");
+                        let (ip, next_page) = match query["page"].as_str() {
+                            "1" => ("10.0.0.2", serde_json::json!(2)),
+                            _ => ("10.0.0.1", serde_json::Value::Null),
+                        };
+                        axum::Json(serde_json::json!({
+                            "servers": [{
+                                "status": "running",
+                                "labels": {"service": "geocode"},
+                                "public_net": {"ipv4": null},
+                                "private_net": [{"ip": ip}]
+                            }],
+                            "meta": {"pagination": {"next_page": next_page}}
+                        }))
+                    }
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let selector = LabelSelector::new(&[HashMap::from([(
+            "service".to_string(),
+            "geocode".to_string(),
+        )])])
+        .unwrap();
+        let provider = HetznerUpstreams::new(
+            selector,
+            8099,
+            HetznerConfig {
+                api_url: format!("http://{addr}"),
+                token: Some("test-token".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(provider.endpoints().is_empty());
+        provider.refresh().await.unwrap();
+        assert_eq!(
+            *provider.endpoints(),
+            vec![
+                Endpoint::new("10.0.0.1", 8099),
+                Endpoint::new("10.0.0.2", 8099)
+            ]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! History of applied backend configs.
+//!
+//! With a `config_history` section every backend config the LB applies (at startup, by
+//! the periodic reload or a full reload) is kept in a local directory, so an operator can
+//! see what changed when and go back to a version that worked:
+//!
+//! ```yaml
+//! config_history:
+//!   dir: config-history
+//!   max_versions: 100
+//! ```
+//!
+//! Each applied version is stored as `<seq>-<version>.yaml`, the file as it was read, next
+//! to `<seq>-<version>.json` with its content hash, the time it was applied and what it
+//! changed against the version before. Applying the same content again records nothing;
+//! the oldest versions beyond `max_versions` are removed.
+//!
+//! `GET /admin/config/history` lists the versions and `POST
+//! /admin/config/history/<version>` re-applies one by writing it over the backend config
+//! file (see [`crate::reload::RuntimeReloader::apply_config`]).
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::configuration::Config;
+use crate::sync::MutexExt;
+
+/// Versions kept when `max_versions` is not set.
+pub const DEFAULT_MAX_VERSIONS: usize = 100;
+
+/// Where applied backend configs are kept, and how many.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConfigHistoryConfig {
+    pub dir: String,
+    #[serde(default = "default_max_versions")]
+    pub max_versions: usize,
+}
+
+fn default_max_versions() -> usize {
+    DEFAULT_MAX_VERSIONS
+}
+
+impl ConfigHistoryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.dir.is_empty() {
+            return Err("config_history.dir must not be empty".to_string());
+        }
+        if self.max_versions == 0 {
+            return Err("config_history.max_versions must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a config version changed against the version applied before it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConfigDiff {
+    pub services_added: Vec<String>,
+    pub services_removed: Vec<String>,
+    /// Services whose settings changed.
+    pub services_changed: Vec<String>,
+    /// Number of backends before and after.
+    pub backends: (usize, usize),
+}
+
+impl ConfigDiff {
+    /// Changes from `previous` (nothing, for the first version) to `config`.
+    pub fn between(previous: Option<&Config>, config: &Config) -> Self {
+        let names = |config: Option<&Config>| -> BTreeSet<String> {
+            config
+                .map(|config| config.services.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        let (before, after) = (names(previous), names(Some(config)));
+        Self {
+            services_added: after.difference(&before).cloned().collect(),
+            services_removed: before.difference(&after).cloned().collect(),
+            services_changed: before
+                .intersection(&after)
+                .filter(|name| {
+                    previous.and_then(|previous| previous.services.get(*name))
+                        != config.services.get(*name)
+                })
+                .cloned()
+                .collect(),
+            backends: (
+                previous.map_or(0, |previous| previous.backends.len()),
+                config.backends.len(),
+            ),
+        }
+    }
+}
+
+/// One applied config version, as listed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConfigVersion {
+    /// Position in the history, increasing with every applied version.
+    pub seq: u64,
+    /// Content hash of the config, as in [`Config::version`].
+    pub version: String,
+    /// RFC 3339 time the version was applied.
+    pub applied_at: String,
+    /// Version applied before it.
+    pub previous: Option<String>,
+    pub diff: ConfigDiff,
+}
+
+impl ConfigVersion {
+    fn stem(&self) -> String {
+        format!("{:06}-{}", self.seq, self.version)
+    }
+}
+
+/// Directory of applied config versions.
+#[derive(Debug)]
+pub struct ConfigHistory {
+    dir: PathBuf,
+    max_versions: usize,
+    clock: Arc<dyn Clock>,
+    /// Serializes writers, which number versions from the last one on disk.
+    write: Mutex<()>,
+}
+
+impl ConfigHistory {
+    /// A history in `dir` keeping the last `max_versions` versions.
+    pub fn new(dir: impl Into<PathBuf>, max_versions: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            max_versions,
+            clock: Arc::new(SystemClock),
+            write: Mutex::new(()),
+        }
+    }
+
+    /// Take the time versions are applied at from `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Record `config`, read from `source`, as applied over `previous`, or over the latest
+    /// recorded version when there is none (at startup). Returns the new entry, or `None`
+    /// when `config` is the latest version already.
+    pub fn record(
+        &self,
+        source: &str,
+        previous: Option<&Config>,
+        config: &Config,
+    ) -> std::io::Result<Option<ConfigVersion>> {
+        let _write = self.write.lock_or_recover();
+        let versions = self.list()?;
+        let latest = versions.first();
+        if latest.is_some_and(|latest| latest.version == config.version) {
+            return Ok(None);
+        }
+        let recorded;
+        let previous = match (previous, latest) {
+            (None, Some(latest)) => {
+                recorded = self.stored(latest);
+                recorded.as_ref()
+            }
+            (previous, _) => previous,
+        };
+        let entry = ConfigVersion {
+            seq: latest.map_or(1, |latest| latest.seq + 1),
+            version: config.version.clone(),
+            applied_at: chrono::DateTime::<chrono::Utc>::from(self.clock.now())
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            previous: previous
+                .map(|previous| previous.version.clone())
+                .filter(|version| !version.is_empty()),
+            diff: ConfigDiff::between(previous, config),
+        };
+        let stem = entry.stem();
+        std::fs::write(self.dir.join(format!("{stem}.yaml")), source)?;
+        std::fs::write(
+            self.dir.join(format!("{stem}.json")),
+            serde_json::to_vec_pretty(&entry)?,
+        )?;
+
+        for old in versions.iter().skip(self.max_versions.saturating_sub(1)) {
+            let stem = old.stem();
+            std::fs::remove_file(self.dir.join(format!("{stem}.json")))?;
+            // The metadata going first, a half-removed version is no longer listed
+            let _ = std::fs::remove_file(self.dir.join(format!("{stem}.yaml")));
+        }
+        Ok(Some(entry))
+    }
+
+    /// Recorded versions, newest first.
+    pub fn list(&self) -> std::io::Result<Vec<ConfigVersion>> {
+        let mut versions = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match serde_json::from_slice::<ConfigVersion>(&std::fs::read(&path)?) {
+                Ok(version) => versions.push(version),
+                Err(e) => log::warn!("Skipping config history entry {}: {e}", path.display()),
+            }
+        }
+        versions.sort_by_key(|v| std::cmp::Reverse(v.seq));
+        Ok(versions)
+    }
+
+    /// Config of a recorded version, parsed without validation; `None` if it no longer
+    /// parses.
+    fn stored(&self, entry: &ConfigVersion) -> Option<Config> {
+        let source = std::fs::read_to_string(self.dir.join(format!("{}.yaml", entry.stem())));
+        let mut config: Config = serde_yaml::from_str(&source.ok()?).ok()?;
+        config.version = entry.version.clone();
+        Some(config)
+    }
+
+    /// Text of the latest recorded config with content hash `version`.
+    pub fn source(&self, version: &str) -> std::io::Result<Option<String>> {
+        let Some(entry) = self.list()?.into_iter().find(|v| v.version == version) else {
+            return Ok(None);
+        };
+        std::fs::read_to_string(self.dir.join(format!("{}.yaml", entry.stem()))).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::TestClock;
+
+    use super::*;
+
+    const ONE: &str = r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend: { type: basic, ip: "127.0.0.1", port: 8000 }
+"#;
+
+    const TWO: &str = r#"
+services:
+  root: { path: /, strategy: p2c_ewma }
+  tiles: /tiles
+backends:
+  - service: root
+    backend: { type: basic, ip: "127.0.0.1", port: 8000 }
+  - service: tiles
+    backend: { type: basic, ip: "127.0.0.1", port: 8001 }
+"#;
+
+    #[test]
+    fn test_versions_are_recorded_once_with_their_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let clock = Arc::new(TestClock::at_unix(1_700_000_000));
+        let history = ConfigHistory::new(dir.path(), 10).with_clock(clock.clone());
+        let (one, two) = (Config::parse(ONE).unwrap(), Config::parse(TWO).unwrap());
+
+        let first = history.record(ONE, None, &one).unwrap().unwrap();
+        assert_eq!(first.seq, 1);
+        assert_eq!(first.diff.services_added, vec!["root"]);
+        assert_eq!(history.record(ONE, Some(&one), &one).unwrap(), None);
+
+        clock.advance(Duration::from_secs(60));
+        let second = history.record(TWO, Some(&one), &two).unwrap().unwrap();
+        assert_eq!(second.seq, 2);
+        assert_eq!(second.applied_at, "2023-11-14T22:14:20Z");
+        assert_eq!(second.previous.as_deref(), Some(one.version.as_str()));
+        assert_eq!(
+            second.diff,
+            ConfigDiff {
+                services_added: vec!["tiles".to_string()],
+                services_removed: vec![],
+                services_changed: vec!["root".to_string()],
+                backends: (1, 2),
+            }
+        );
+
+        // Going back is a new version
+        let third = history.record(ONE, Some(&two), &one).unwrap().unwrap();
+        assert_eq!(third.seq, 3);
+        assert_eq!(third.diff.services_removed, vec!["tiles"]);
+        assert_eq!(history.list().unwrap(), vec![third, second, first]);
+        assert_eq!(history.source(&two.version).unwrap().as_deref(), Some(TWO));
+        assert_eq!(history.source("000000000000").unwrap(), None);
+
+        // After a restart, the first version is compared with the last one recorded
+        let restarted = ConfigHistory::new(dir.path(), 10);
+        let fourth = restarted.record(TWO, None, &two).unwrap().unwrap();
+        assert_eq!(fourth.previous.as_deref(), Some(one.version.as_str()));
+        assert_eq!(fourth.diff.services_added, vec!["tiles"]);
+    }
+
+    #[test]
+    fn test_oldest_versions_are_pruned() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let history = ConfigHistory::new(dir.path(), 2);
+        let mut previous: Option<Config> = None;
+        for port in 8000..8004 {
+            let source = ONE.replace("8000", &port.to_string());
+            let config = Config::parse(&source).unwrap();
+            history.record(&source, previous.as_ref(), &config).unwrap();
+            previous = Some(config);
+        }
+
+        let seqs: Vec<u64> = history.list().unwrap().iter().map(|v| v.seq).collect();
+        assert_eq!(seqs, vec![4, 3]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: ConfigHistoryConfig = serde_yaml::from_str("dir: history").unwrap();
+        assert_eq!(config.max_versions, DEFAULT_MAX_VERSIONS);
+        assert!(config.validate().is_ok());
+        let none = ConfigHistoryConfig {
+            max_versions: 0,
+            ..config
+        };
+        assert!(none.validate().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Request hooks.
+//!
+//! An extension point for logic the proxy has no setting for. A [`RequestHook`] sees every
+//! request about to go upstream (`on_request`) and every upstream response before it is
+//! sent on (`on_response`), with the service, the routing tags and the key fingerprint of
+//! the request. It may change headers, or answer the request itself instead of the service.
+//!
+//! Hooks are installed by the embedder ([`Hooks`]) or loaded from the WebAssembly modules
+//! of the backend config ([`crate::wasm_hooks`]), and run in that order; the first one
+//! answering a request ends the chain. Both can be replaced while the proxy runs, the
+//! installed hooks with [`Hooks::replace`] and the modules by a reload of the config.
+
+use std::sync::{Arc, RwLock};
+
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::sync::RwLockExt;
+
+/// Labeled counter of requests answered by a hook, by hook name.
+pub const HOOK_RESPONSES_COUNTER: &str = "hook_responses";
+
+/// What a hook is told about the request it runs on.
+#[derive(Debug, Clone, Copy)]
+pub struct HookContext<'a> {
+    /// Service the request was routed to.
+    pub service: Option<&'a str>,
+    /// Tags set by the tagging rules, in rule order.
+    pub tags: &'a [String],
+    /// Fingerprint of the request's API key, when it carries one.
+    pub key_fingerprint: Option<&'a str>,
+}
+
+/// What happens to a request after a hook saw it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookAction {
+    /// Pass the request on to the next hook, and then upstream.
+    Continue,
+    /// Answer the request with `status` and a JSON `body` instead of the service.
+    Respond {
+        status: u16,
+        body: serde_json::Value,
+    },
+}
+
+/// Logic run on the requests and responses of the proxy.
+pub trait RequestHook: Send + Sync {
+    /// Name the hook is logged and counted by.
+    fn name(&self) -> &str;
+
+    /// Called with the request header before the request goes upstream.
+    fn on_request(&self, _ctx: &HookContext<'_>, _request: &mut RequestHeader) -> HookAction {
+        HookAction::Continue
+    }
+
+    /// Called with the upstream response header before it is sent to the client.
+    fn on_response(&self, _ctx: &HookContext<'_>, _response: &mut ResponseHeader) {}
+}
+
+/// The installed hooks, in the order they run.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: RwLock<Arc<Vec<Arc<dyn RequestHook>>>>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Arc<dyn RequestHook>>) -> Self {
+        Self {
+            hooks: RwLock::new(Arc::new(hooks)),
+        }
+    }
+
+    /// Install `hooks` in place of the current ones.
+    pub fn replace(&self, hooks: Vec<Arc<dyn RequestHook>>) {
+        let names: Vec<&str> = hooks.iter().map(|hook| hook.name()).collect();
+        log::info!("Installing request hooks: [{}]", names.join(", "));
+        *self.hooks.write_or_recover() = Arc::new(hooks);
+    }
+
+    /// Names of the installed hooks, in order.
+    pub fn names(&self) -> Vec<String> {
+        self.current()
+            .iter()
+            .map(|hook| hook.name().to_string())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current().is_empty()
+    }
+
+    /// The installed hooks, in order.
+    pub fn installed(&self) -> Vec<Arc<dyn RequestHook>> {
+        self.current().to_vec()
+    }
+
+    fn current(&self) -> Arc<Vec<Arc<dyn RequestHook>>> {
+        Arc::clone(&self.hooks.read_or_recover())
+    }
+
+    /// Run `on_request` of the installed hooks; see [`on_request`].
+    pub fn on_request(
+        &self,
+        ctx: &HookContext<'_>,
+        request: &mut RequestHeader,
+    ) -> Option<(String, u16, serde_json::Value)> {
+        on_request(&self.current(), ctx, request)
+    }
+
+    /// Run `on_response` of every installed hook.
+    pub fn on_response(&self, ctx: &HookContext<'_>, response: &mut ResponseHeader) {
+        on_response(&self.current(), ctx, response);
+    }
+}
+
+/// Run `on_request` of `hooks` until one answers the request; returns its name and answer.
+pub fn on_request(
+    hooks: &[Arc<dyn RequestHook>],
+    ctx: &HookContext<'_>,
+    request: &mut RequestHeader,
+) -> Option<(String, u16, serde_json::Value)> {
+    for hook in hooks {
+        if let HookAction::Respond { status, body } = hook.on_request(ctx, request) {
+            return Some((hook.name().to_string(), status, body));
+        }
+    }
+    None
+}
+
+/// Run `on_response` of every hook of `hooks`.
+pub fn on_response(
+    hooks: &[Arc<dyn RequestHook>],
+    ctx: &HookContext<'_>,
+    response: &mut ResponseHeader,
+) {
+    for hook in hooks {
+        hook.on_response(ctx, response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rejects requests without a tenant and tags the others' responses.
+    struct TenantGate;
+
+    impl RequestHook for TenantGate {
+        fn name(&self) -> &str {
+            "tenant-gate"
+        }
+
+        fn on_request(&self, ctx: &HookContext<'_>, request: &mut RequestHeader) -> HookAction {
+            if !ctx.tags.iter().any(|tag| tag == "tenant") {
+                return HookAction::Respond {
+                    status: 403,
+                    body: serde_json::json!({ "error": "tenant required" }),
+                };
+            }
+            request.insert_header("x-gate", "passed").unwrap();
+            HookAction::Continue
+        }
+
+        fn on_response(&self, ctx: &HookContext<'_>, response: &mut ResponseHeader) {
+            let service = ctx.service.unwrap_or("-").to_string();
+            response.insert_header("x-gate-service", service).unwrap();
+        }
+    }
+
+    /// Answers every request it sees.
+    struct Teapot;
+
+    impl RequestHook for Teapot {
+        fn name(&self) -> &str {
+            "teapot"
+        }
+
+        fn on_request(&self, _ctx: &HookContext<'_>, _request: &mut RequestHeader) -> HookAction {
+            HookAction::Respond {
+                status: 418,
+                body: serde_json::json!({}),
+            }
+        }
+    }
+
+    #[test]
+    fn test_hooks_run_in_order_until_one_answers() {
+        let hooks = Hooks::new(vec![Arc::new(TenantGate), Arc::new(Teapot)]);
+        let tags = vec!["tenant".to_string()];
+        let ctx = HookContext {
+            service: Some("tiles"),
+            tags: &tags,
+            key_fingerprint: None,
+        };
+        let mut request = RequestHeader::build("GET", b"/tiles", None).unwrap();
+        let (name, status, _) = hooks.on_request(&ctx, &mut request).unwrap();
+        assert_eq!((name.as_str(), status), ("teapot", 418));
+        assert_eq!(request.headers["x-gate"], "passed");
+
+        let untagged = HookContext { tags: &[], ..ctx };
+        let (name, status, body) = hooks.on_request(&untagged, &mut request).unwrap();
+        assert_eq!((name.as_str(), status), ("tenant-gate", 403));
+        assert_eq!(body["error"], "tenant required");
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        hooks.on_response(&ctx, &mut response);
+        assert_eq!(response.headers["x-gate-service"], "tiles");
+    }
+
+    #[test]
+    fn test_hooks_are_replaced_at_runtime() {
+        let hooks = Hooks::default();
+        assert!(hooks.is_empty());
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        let ctx = HookContext {
+            service: None,
+            tags: &[],
+            key_fingerprint: None,
+        };
+        assert_eq!(hooks.on_request(&ctx, &mut request), None);
+
+        hooks.replace(vec![Arc::new(Teapot)]);
+        assert_eq!(hooks.names(), vec!["teapot"]);
+        assert_eq!(hooks.on_request(&ctx, &mut request).unwrap().1, 418);
+    }
+}
@@ -0,0 +1,169 @@
+//! Checks of upstream response bodies against their `Content-Length`.
+//!
+//! An upstream that crashes or is cut off mid-response sends fewer body bytes than its
+//! `Content-Length` declares. Each such response, and each with an invalid
+//! `Content-Length`, is logged and counted in [`TRUNCATED_RESPONSES_COUNTER`] by service
+//! and upstream address. A service with
+//! `reject_truncated: true` also keeps them from its clients:
+//!
+//! ```yaml
+//! services:
+//!   reports:
+//!     path: /reports
+//!     reject_truncated: true
+//! ```
+//!
+//! A response whose `Content-Length` is invalid, or given twice with different values, is
+//! answered with a `502` instead. The header of any other response has gone downstream by
+//! the time its body runs short, so bodies of up to [`MAX_HELD_BODY_BYTES`] are held until
+//! they are complete: a truncated one is never sent, and the client's connection is closed.
+//! Larger bodies stream as they arrive.
+
+use bytes::{Bytes, BytesMut};
+use http::{Method, header};
+use pingora::http::ResponseHeader;
+
+/// Labeled counter of responses whose body disagrees with their `Content-Length`, or whose
+/// `Content-Length` is invalid, by service and upstream address.
+pub const TRUNCATED_RESPONSES_COUNTER: &str = "truncated_responses";
+
+/// Largest body held until complete for services rejecting truncated responses.
+pub const MAX_HELD_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Body length `response` to a `method` request declares; `None` when it has no body or
+/// does not declare one, and `Err` when its `Content-Length` is invalid or ambiguous.
+pub fn declared_length(method: &Method, response: &ResponseHeader) -> Result<Option<u64>, String> {
+    let status = response.status.as_u16();
+    if *method == Method::HEAD || status < 200 || status == 204 || status == 304 {
+        return Ok(None);
+    }
+    // Chunked framing overrides any Content-Length
+    if response.headers.contains_key(header::TRANSFER_ENCODING) {
+        return Ok(None);
+    }
+    let mut declared = None;
+    for value in response.headers.get_all(header::CONTENT_LENGTH) {
+        let length = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .ok_or_else(|| format!("invalid Content-Length {value:?}"))?;
+        if declared.is_some_and(|declared| declared != length) {
+            return Err("conflicting Content-Length values".to_string());
+        }
+        declared = Some(length);
+    }
+    Ok(declared)
+}
+
+/// Body bytes of an upstream response received against those it declared.
+#[derive(Debug)]
+pub struct LengthCheck {
+    pub declared: u64,
+    pub received: u64,
+    /// Body held until it is complete, when the response is held.
+    held: Option<BytesMut>,
+}
+
+impl LengthCheck {
+    /// Check a body of `declared` bytes, holding it if `hold` and it is small enough.
+    pub fn new(declared: u64, hold: bool) -> Self {
+        Self {
+            declared,
+            received: 0,
+            held: (hold && declared <= MAX_HELD_BODY_BYTES).then(BytesMut::new),
+        }
+    }
+
+    /// Count a chunk of the body. A held body is taken out of `body` until its last chunk,
+    /// which gives it back whole; a truncated one is dropped, and false returned.
+    pub fn on_chunk(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) -> bool {
+        if let Some(bytes) = body {
+            self.received += bytes.len() as u64;
+        }
+        let Some(held) = &mut self.held else {
+            return true;
+        };
+        if let Some(bytes) = body.take() {
+            held.extend_from_slice(&bytes);
+        }
+        if !end_of_stream {
+            return true;
+        }
+        let held = self.held.take().unwrap_or_default();
+        if !self.is_complete() {
+            return false;
+        }
+        *body = Some(held.freeze());
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.declared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, lengths: &[&str]) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).unwrap();
+        for length in lengths {
+            response
+                .append_header(header::CONTENT_LENGTH, *length)
+                .unwrap();
+        }
+        response
+    }
+
+    #[test]
+    fn test_declared_length() {
+        let get = Method::GET;
+        assert_eq!(declared_length(&get, &response(200, &["12"])), Ok(Some(12)));
+        assert_eq!(declared_length(&get, &response(200, &[])), Ok(None));
+        assert_eq!(
+            declared_length(&Method::HEAD, &response(200, &["12"])),
+            Ok(None)
+        );
+        assert_eq!(declared_length(&get, &response(304, &["12"])), Ok(None));
+        assert_eq!(
+            declared_length(&get, &response(200, &["12", "12"])),
+            Ok(Some(12))
+        );
+        assert!(declared_length(&get, &response(200, &["12", "13"])).is_err());
+        assert!(declared_length(&get, &response(200, &["-1"])).is_err());
+        let mut chunked = response(200, &["12"]);
+        chunked
+            .insert_header(header::TRANSFER_ENCODING, "chunked")
+            .unwrap();
+        assert_eq!(declared_length(&get, &chunked), Ok(None));
+    }
+
+    #[test]
+    fn test_held_bodies_are_released_only_when_complete() {
+        let mut check = LengthCheck::new(6, true);
+        let mut body = Some(Bytes::from_static(b"abc"));
+        assert!(check.on_chunk(&mut body, false));
+        assert_eq!(body, None);
+        let mut body = Some(Bytes::from_static(b"def"));
+        assert!(check.on_chunk(&mut body, true));
+        assert_eq!(body, Some(Bytes::from_static(b"abcdef")));
+
+        let mut check = LengthCheck::new(6, true);
+        let mut body = Some(Bytes::from_static(b"abc"));
+        assert!(!check.on_chunk(&mut body, true));
+        assert_eq!(body, None);
+        assert_eq!((check.received, check.declared), (3, 6));
+
+        // Unheld bodies pass as they arrive
+        let mut check = LengthCheck::new(6, false);
+        let mut body = Some(Bytes::from_static(b"abc"));
+        assert!(check.on_chunk(&mut body, false));
+        assert_eq!(body, Some(Bytes::from_static(b"abc")));
+        assert!(!check.is_complete());
+        let mut check = LengthCheck::new(MAX_HELD_BODY_BYTES + 1, true);
+        assert!(check.on_chunk(&mut body, false));
+        assert!(body.is_some());
+    }
+}
@@ -0,0 +1,190 @@
+//! Issuing and revoking API keys in an accounts DB.
+//!
+//! Keys are written the way the server reads them: a versioned token bound to the owning
+//! account, and optionally its current plan, stored as its UUID and hex hash. The ChangeLog triggers let running load
+//! balancers pick up the change on their next refresh.
+
+use std::fmt;
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::sqlite;
+
+#[derive(Debug)]
+pub enum KeyAdminError {
+    UnknownAccount(i64),
+    UnknownKey(Uuid),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for KeyAdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyAdminError::UnknownAccount(id) => write!(f, "Account {} does not exist", id),
+            KeyAdminError::UnknownKey(id) => write!(f, "API key {} does not exist", id),
+            KeyAdminError::Sqlite(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeyAdminError {}
+
+impl From<rusqlite::Error> for KeyAdminError {
+    fn from(e: rusqlite::Error) -> Self {
+        KeyAdminError::Sqlite(e)
+    }
+}
+
+/// A newly issued key. The token is only available here; the DB keeps its hash.
+#[derive(Debug, Clone)]
+pub struct IssuedKey {
+    pub token: String,
+    pub api_key: Uuid,
+    pub api_key_id: i64,
+    pub fingerprint: String,
+}
+
+/// Issue a key for `account_id` with the given scopes, using `token_prefix` for the token.
+/// A key bound to the account's plan stops verifying once the account changes plans.
+pub fn issue_key(
+    db_path: &Path,
+    token_prefix: &str,
+    account_id: i64,
+    scopes: &[String],
+    bind_plan: bool,
+) -> Result<IssuedKey, KeyAdminError> {
+    let conn = sqlite::open_wal(db_path)?;
+    let plan_id: i64 = conn
+        .query_row(
+            "SELECT plan_id FROM Accounts WHERE account_id = ?1",
+            [account_id],
+            |row| row.get(0),
+        )
+        .map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => KeyAdminError::UnknownAccount(account_id),
+            e => KeyAdminError::Sqlite(e),
+        })?;
+
+    let config = match bind_plan {
+        true => api_key::ApiKeyConfig::for_account_plan(token_prefix, account_id, plan_id),
+        false => api_key::ApiKeyConfig::for_account(token_prefix, account_id),
+    };
+    let (token, data) = api_key::generate_with_data(&config);
+    conn.execute(
+        "INSERT INTO APIKeys (api_key, account_id, api_key_hash, version, scopes, plan_bound) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            data.id.to_string(),
+            account_id,
+            hex::encode(data.secret_hash),
+            data.version,
+            scopes.join(","),
+            bind_plan,
+        ],
+    )?;
+    Ok(IssuedKey {
+        fingerprint: token.fingerprint(),
+        token: token.token,
+        api_key: data.id,
+        api_key_id: conn.last_insert_rowid(),
+    })
+}
+
+/// Deactivate the key with the given id; the id of the account that owned it.
+pub fn revoke_key(db_path: &Path, api_key: Uuid) -> Result<i64, KeyAdminError> {
+    let conn = sqlite::open_wal(db_path)?;
+    conn.query_row(
+        "UPDATE APIKeys SET is_active = 0, updated_at = CURRENT_TIMESTAMP WHERE api_key = ?1 \
+         RETURNING account_id",
+        [api_key.to_string()],
+        |row| row.get(0),
+    )
+    .map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => KeyAdminError::UnknownKey(api_key),
+        e => KeyAdminError::Sqlite(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{API_KEY_PREFIX, AccountLoader};
+
+    #[test]
+    fn test_issued_keys_resolve_until_revoked() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = dir.path().join("accounts.db");
+        let conn = sqlite::open_wal(&db).unwrap();
+        conn.execute_batch(include_str!("../sql/accounts.sql"))
+            .unwrap();
+        conn.execute_batch(
+            "INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req) \
+             VALUES ('Free', 10, 1, 0.0); \
+             INSERT INTO Accounts (email, plan_id, billing_status) \
+             VALUES ('ops@example.com', 1, 'active');",
+        )
+        .unwrap();
+        drop(conn);
+
+        assert!(matches!(
+            issue_key(&db, API_KEY_PREFIX, 2, &[], false),
+            Err(KeyAdminError::UnknownAccount(2))
+        ));
+
+        let issued = issue_key(&db, API_KEY_PREFIX, 1, &["read".to_string()], false).unwrap();
+        let store = AccountLoader::new(&db).load_initial().unwrap();
+        let meta = store
+            .key_metadata(&store.resolve_key(&issued.token))
+            .unwrap();
+        assert_eq!(meta.api_key, issued.api_key);
+        assert_eq!(meta.fingerprint, issued.fingerprint);
+        assert_eq!(meta.scopes, vec!["read".to_string()]);
+
+        assert_eq!(revoke_key(&db, issued.api_key).unwrap(), 1);
+        let store = AccountLoader::new(&db).load_initial().unwrap();
+        assert!(
+            store
+                .key_metadata(&store.resolve_key(&issued.token))
+                .is_none()
+        );
+        assert!(matches!(
+            revoke_key(&db, Uuid::nil()),
+            Err(KeyAdminError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_plan_bound_keys_stop_resolving_after_a_plan_change() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = dir.path().join("accounts.db");
+        let conn = sqlite::open_wal(&db).unwrap();
+        conn.execute_batch(include_str!("../sql/accounts.sql"))
+            .unwrap();
+        conn.execute_batch(
+            "INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req) \
+             VALUES ('Free', 10, 1, 0.0), ('Pro', 1000, 10, 1.0); \
+             INSERT INTO Accounts (email, plan_id, billing_status) \
+             VALUES ('ops@example.com', 1, 'active');",
+        )
+        .unwrap();
+
+        let bound = issue_key(&db, API_KEY_PREFIX, 1, &[], true).unwrap();
+        let unbound = issue_key(&db, API_KEY_PREFIX, 1, &[], false).unwrap();
+        let resolves = |token: &str| {
+            let store = AccountLoader::new(&db).load_initial().unwrap();
+            store.key_metadata(&store.resolve_key(token)).is_some()
+        };
+        assert!(resolves(&bound.token));
+        assert!(resolves(&unbound.token));
+
+        conn.execute("UPDATE Accounts SET plan_id = 2 WHERE account_id = 1", [])
+            .unwrap();
+        assert!(!resolves(&bound.token));
+        assert!(resolves(&unbound.token));
+
+        // Issued again on the new plan
+        let reissued = issue_key(&db, API_KEY_PREFIX, 1, &[], true).unwrap();
+        assert!(resolves(&reissued.token));
+    }
+}
@@ -1,26 +1,102 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, OnceLock, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::accounts::{AccountRatelimit, Ratelimit, hash_api_key};
-use crate::configuration::{Backend, Config};
+use crate::accounts::{
+    AccountRatelimit, AsyncRatelimit, Limit, LimitSource, SyncRatelimit, mask_email,
+};
+use crate::admission::{
+    Admission, OVERLOAD_QUEUED_COUNTER, OVERLOAD_REJECTED_COUNTER, Permit, Rejected,
+};
+use crate::burst::BurstCredits;
+use crate::canary::group_for_key;
+use crate::clock::{Clock, SystemClock};
+use crate::configuration::{
+    AuthMode, Config, DeadlineConfig, DebugHeadersConfig, DiagnosticHeadersConfig,
+    DiagnosticVerbosity, FallbackResponseConfig, ListenerConfig,
+};
+use crate::connection::ConnectionLimiter;
+use crate::deadline::{deadline_for_request, remaining_budget};
+use crate::error::{ERRORS_COUNTER, LbError};
+use crate::fallback::{FALLBACK_REQUESTS_COUNTER, FALLBACK_RESPONSES_COUNTER, response_body};
+use crate::gossip::Gossip;
+use crate::hooks::{HOOK_RESPONSES_COUNTER, HookContext, Hooks, RequestHook};
+use crate::integrity::{LengthCheck, TRUNCATED_RESPONSES_COUNTER, declared_length};
+use crate::limiter_state::LimiterState;
+use crate::logging::ACCESS_TARGET;
 use crate::metric::Metrics;
-use crate::usage::UsageTracker;
+use crate::openapi::{MAX_REJECTED_OPERATIONS, REJECTIONS_COUNTER, rejection_label};
+use crate::privacy::Privacy;
+use crate::recorder::{Recorder, UsageEvent};
+use crate::replay::{REPLAYED_REQUESTS_COUNTER, ReplayCache, replay_key};
+use crate::routing::Route;
+use crate::selection::hash_key;
+use crate::shadow::{MAX_BODY_BYTES, SHADOW_DROPPED_COUNTER, Shadow, ShadowRequest};
+use crate::shedding::SHED_REQUESTS_COUNTER;
+use crate::static_cache::{StaticCache, cache_key, is_storable};
+use crate::sync::{MutexExt, RwLockExt};
+use crate::tenant::Tenants;
+use crate::tls::{TLS_MISMATCH_COUNTER, UpstreamTls};
+use crate::trace::{Trace, TraceCapture, TraceEvent};
+use crate::upstream::{
+    ConnectionRecycler, ConnectionRecycling, Endpoint, RetryOn, RetryPolicy, ServicePool,
+};
+use crate::usage::{UsageRoute, UsageTracker};
 use async_trait::async_trait;
-use pingora::http::ResponseHeader;
+use pingora::ErrorSource;
+use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::prelude::*;
+use pingora::proxy::FailToProxy;
 use pingora_limits::rate::Rate;
 use uuid::Uuid;
 
 pub const API_KEY_HEADER: &str = "x-api-key";
 pub const MISSING_API_KEY: &str = "<missing>";
+/// Metrics key for requests to services with `auth: none`.
+pub const ANONYMOUS_KEY: &str = "<anonymous>";
+/// Metrics key for requests on the internal listener.
+pub const INTERNAL_KEY: &str = "<internal>";
+/// Pre-flight quota endpoint answered by the LB itself.
+pub const RATELIMIT_PATH: &str = "/v1/ratelimit";
+/// Self-service key metadata endpoint answered by the LB itself.
+pub const ME_PATH: &str = "/v1/me";
+/// Debug header naming the service a request was routed to.
+pub const SERVICE_HEADER: &str = "X-LB-Service";
+/// Debug header naming the upstream address a request was sent to.
+pub const UPSTREAM_HEADER: &str = "X-LB-Upstream";
+/// Debug header naming where the applied rate limit came from.
+pub const LIMIT_SOURCE_HEADER: &str = "X-LB-Limit-Source";
+/// Diagnostic header telling whether the response came from the static cache.
+pub const CACHE_HEADER: &str = "X-Cache";
+/// Diagnostic header naming the LB node that answered.
+pub const NODE_HEADER: &str = "X-LB-Node";
+/// Labeled counter of routed requests, by service and its static labels.
+pub const ROUTED_REQUESTS_COUNTER: &str = "requests";
+/// Labeled counter of requests matching no service, by first path segment.
+pub const ROUTE_MISSES_COUNTER: &str = "route_misses";
+/// Labeled counter of requests answered for services in maintenance, by service.
+pub const MAINTENANCE_RESPONSES_COUNTER: &str = "maintenance_responses";
+/// Labeled counter of responses served from the static cache, by service.
+pub const STATIC_CACHE_HITS_COUNTER: &str = "static_cache_hits";
+/// Labeled counter of requests sent upstream again after a failure, by service.
+pub const UPSTREAM_RETRIES_COUNTER: &str = "upstream_retries";
+/// Labeled counter of requests failed by a timeout, by service and upstream address.
+pub const UPSTREAM_TIMEOUTS_COUNTER: &str = "upstream_timeouts";
+/// Histogram of request body sizes, by service.
+pub const REQUEST_SIZE_HISTOGRAM: &str = "request_body_bytes";
+/// Histogram of response body sizes, by service.
+pub const RESPONSE_SIZE_HISTOGRAM: &str = "response_body_bytes";
+/// Distinct path prefixes tracked in the route miss counter; later ones count as `other`.
+const MAX_ROUTE_MISS_PREFIXES: usize = 100;
+/// Longest path prefix kept as a route miss label.
+const MAX_ROUTE_MISS_PREFIX_LEN: usize = 64;
 
 // Registry of Rate estimators keyed by window seconds.
 static RATE_LIMITERS: OnceLock<Mutex<HashMap<u64, Arc<Rate>>>> = OnceLock::new();
 
-fn rate_for_window(window_secs: u64) -> Arc<Rate> {
+pub(crate) fn rate_for_window(window_secs: u64) -> Arc<Rate> {
     let store = RATE_LIMITERS.get_or_init(|| Mutex::new(HashMap::new()));
-    let mut guard = store.lock().expect("rate limiter store poisoned");
+    let mut guard = store.lock_or_recover();
     Arc::clone(
         guard
             .entry(window_secs)
@@ -31,8 +107,40 @@ fn rate_for_window(window_secs: u64) -> Arc<Rate> {
 pub struct Lb {
     config: Arc<RwLock<Config>>,
     limiter: Arc<AccountRatelimit>,
-    metrics: Arc<Metrics>,
+    /// Source of key limits; `limiter` unless replaced.
+    limits: Arc<dyn AsyncRatelimit>,
+    /// Where metrics and usage are recorded; read back through `usage_tracker`.
+    recorder: Recorder,
     usage_tracker: Option<Arc<UsageTracker>>,
+    listener: ListenerConfig,
+    connections: Arc<ConnectionLimiter>,
+    deadline: DeadlineConfig,
+    burst: Arc<BurstCredits>,
+    /// Counters saved across restarts, when configured.
+    limiter_state: Option<Arc<LimiterState>>,
+    /// Per-IP limit when serving the internal listener, which skips API key auth.
+    internal_rps_limit: Option<isize>,
+    /// Time source for burst windows, usage timestamps and monthly quotas.
+    clock: Arc<dyn Clock>,
+    debug_headers: DebugHeadersConfig,
+    diagnostic_headers: DiagnosticVerbosity,
+    /// Reported in access logs and [`NODE_HEADER`].
+    node_id: Option<String>,
+    /// Requests of peer nodes counted against rate limits.
+    gossip: Option<Arc<Gossip>>,
+    /// De-identification of client IPs and request targets, in privacy mode.
+    privacy: Option<Arc<Privacy>>,
+    upstream_connections: ConnectionRecycler,
+    tenants: Tenants,
+    static_cache: StaticCache,
+    /// Delivery keys of services with replay protection.
+    replays: ReplayCache,
+    /// Copies of requests to shadow backends.
+    shadow: Shadow,
+    traces: Option<Arc<TraceCapture>>,
+    /// Plan-priority admission beyond a concurrency limit, when overload mode is configured.
+    admission: Option<Admission>,
+    hooks: Option<Arc<Hooks>>,
 }
 
 impl Lb {
@@ -44,22 +152,754 @@ impl Lb {
     ) -> Self {
         Self {
             config,
+            limits: Arc::new(SyncRatelimit(limiter.clone())),
             limiter,
-            metrics,
+            recorder: Recorder::direct(metrics, usage_tracker.clone()),
             usage_tracker,
+            listener: ListenerConfig::default(),
+            connections: Arc::new(ConnectionLimiter::new(&ListenerConfig::default())),
+            deadline: DeadlineConfig::default(),
+            burst: Arc::new(BurstCredits::new()),
+            limiter_state: None,
+            internal_rps_limit: None,
+            clock: Arc::new(SystemClock),
+            debug_headers: DebugHeadersConfig::default(),
+            diagnostic_headers: DiagnosticVerbosity::None,
+            node_id: None,
+            gossip: None,
+            privacy: None,
+            upstream_connections: ConnectionRecycler::new(),
+            tenants: Tenants::default(),
+            static_cache: StaticCache::new(),
+            replays: ReplayCache::new(),
+            shadow: Shadow::new(),
+            traces: None,
+            admission: None,
+            hooks: None,
+        }
+    }
+
+    /// Record metrics and usage through `recorder` (such as a shared pipeline) instead of
+    /// applying them on the request path.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Use `clock` for the current time instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Serve the internal listener: no API keys, every caller limited to `rps_limit` per IP.
+    pub fn with_internal_limit(mut self, rps_limit: isize) -> Self {
+        self.internal_rps_limit = Some(rps_limit);
+        self
+    }
+
+    /// Enforce the connection and request header limits from the listener settings.
+    pub fn with_listener_config(mut self, listener: ListenerConfig) -> Self {
+        self.connections = Arc::new(ConnectionLimiter::new(&listener));
+        self.listener = listener;
+        self
+    }
+
+    /// Limiter of the connections to the listener, which the listener's
+    /// [`LimitedApp`](crate::connection::LimitedApp) admits them through.
+    pub fn connections(&self) -> Arc<ConnectionLimiter> {
+        self.connections.clone()
+    }
+
+    /// Configure timeout budget propagation.
+    pub fn with_deadline_config(mut self, deadline: DeadlineConfig) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Add routing and limit debug headers to responses for requests carrying the debug token.
+    pub fn with_debug_headers(mut self, debug_headers: DebugHeadersConfig) -> Self {
+        self.debug_headers = debug_headers;
+        self
+    }
+
+    /// Add cache and topology headers to every response, as verbose as configured.
+    pub fn with_diagnostic_headers(mut self, diagnostic_headers: &DiagnosticHeadersConfig) -> Self {
+        self.diagnostic_headers = diagnostic_headers.verbosity;
+        self
+    }
+
+    /// Name this node in access logs and `X-LB-Node`.
+    pub fn with_node_id(mut self, node_id: impl Into<String>) -> Self {
+        self.node_id = Some(node_id.into());
+        self
+    }
+
+    /// Count the requests peers report through `gossip` against rate limits.
+    pub fn with_gossip(mut self, gossip: Arc<Gossip>) -> Self {
+        self.gossip = Some(gossip);
+        self
+    }
+
+    /// De-identify client IPs and request targets in access logs, traces and usage.
+    pub fn with_privacy(mut self, privacy: Arc<Privacy>) -> Self {
+        self.privacy = Some(privacy);
+        self
+    }
+
+    /// Apply per-tenant service restrictions and default limits to API keys.
+    pub fn with_tenants(mut self, tenants: Tenants) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Capture traces of the requests of keys armed in `traces`.
+    pub fn with_trace_capture(mut self, traces: Arc<TraceCapture>) -> Self {
+        self.traces = Some(traces);
+        self
+    }
+
+    /// Queue requests beyond the admission limit by plan priority.
+    pub fn with_admission(mut self, admission: Admission) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    /// Take the limits of API keys from `limits`, such as a quota service, instead of the
+    /// accounts DB.
+    pub fn with_limits(mut self, limits: Arc<dyn AsyncRatelimit>) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Run the requests going upstream and their responses through `hooks`.
+    pub fn with_hooks(mut self, hooks: Arc<Hooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Count API key requests in `state` too, and keep burst credits there, so both are
+    /// saved on exit and restored at the next start.
+    pub fn with_limiter_state(mut self, state: Arc<LimiterState>) -> Self {
+        self.burst = state.burst();
+        self.limiter_state = Some(state);
+        self
+    }
+
+    /// The hooks installed by the embedder, followed by the modules of the current config.
+    fn request_hooks(&self) -> Vec<Arc<dyn RequestHook>> {
+        let mut hooks = self
+            .hooks
+            .as_ref()
+            .map_or_else(Vec::new, |hooks| hooks.installed());
+        hooks.extend(
+            self.config
+                .read_or_recover()
+                .request_hooks
+                .iter()
+                .map(|hook| Arc::clone(hook) as Arc<dyn RequestHook>),
+        );
+        hooks
+    }
+
+    /// Diagnostic headers for the response, and the debug headers if the request asked for
+    /// them.
+    fn debug_headers(&self, ctx: &RequestCtx) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if self.diagnostic_headers >= DiagnosticVerbosity::Minimal {
+            let cache = if ctx.cache_hit { "HIT" } else { "MISS" };
+            headers.push((CACHE_HEADER, cache.to_string()));
+            if let Some(node_id) = &self.node_id {
+                headers.push((NODE_HEADER, node_id.clone()));
+            }
+        }
+        if ctx.debug || self.diagnostic_headers == DiagnosticVerbosity::Full {
+            headers.push((
+                SERVICE_HEADER,
+                ctx.route
+                    .as_ref()
+                    .map_or_else(|| "-".to_string(), |route| route.service.clone()),
+            ));
+            headers.push((
+                UPSTREAM_HEADER,
+                ctx.upstream.clone().unwrap_or_else(|| "-".to_string()),
+            ));
+        }
+        if ctx.debug {
+            headers.push((
+                LIMIT_SOURCE_HEADER,
+                ctx.limit_source
+                    .map_or("-", |source| source.as_str())
+                    .to_string(),
+            ));
+        }
+        headers
+    }
+
+    /// Service and backend variant for usage records. Requests rejected after routing, such
+    /// as by the rate limiter, are attributed to the service they would have gone to.
+    fn usage_route(&self, ctx: &RequestCtx) -> UsageRoute {
+        ctx.route
+            .as_ref()
+            .map_or_else(UsageRoute::default, |route| UsageRoute {
+                service: route.service.clone(),
+                backend: route.backend.to_string(),
+                labels: route.labels.clone(),
+            })
+    }
+
+    /// Response for the endpoints the LB answers itself, or `None` to proxy the request.
+    async fn self_service_response(
+        &self,
+        path: &str,
+        api_key: &str,
+    ) -> Option<(u16, serde_json::Value)> {
+        match path {
+            RATELIMIT_PATH => Some((200, self.ratelimit_status(api_key).await)),
+            ME_PATH => Some(match self.limiter.key_metadata(api_key) {
+                Some(mut meta) => {
+                    // Activity not yet flushed is more recent than what the accounts DB holds
+                    if let Some(activity) = self
+                        .usage_tracker
+                        .as_ref()
+                        .and_then(|t| t.last_used(meta.api_key))
+                        && let Some(ts) = chrono::DateTime::from_timestamp(activity.last_used_ts, 0)
+                    {
+                        meta.last_used_at = Some(ts.format("%Y-%m-%d %H:%M:%S").to_string());
+                    }
+                    (
+                        200,
+                        serde_json::json!({
+                            "key_id": meta.api_key,
+                            "fingerprint": meta.fingerprint,
+                            "email": mask_email(&meta.email),
+                            "plan": meta.plan_name,
+                            "created_at": meta.created_at,
+                            "last_used_at": meta.last_used_at,
+                            "scopes": meta.scopes,
+                        }),
+                    )
+                }
+                None => (401, serde_json::json!({ "error": "unknown API key" })),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Limit of an API key, with its tenant's default for keys without a plan.
+    async fn limit_for_key(&self, api_key: &str) -> Limit {
+        let limit = self.limits.limit_for_key(api_key).await;
+        match self.tenants.for_key(api_key) {
+            Some(tenant) => tenant.apply_default(limit),
+            None => limit,
+        }
+    }
+
+    /// Current limit, remaining quota, plan and monthly usage for an API key.
+    async fn ratelimit_status(&self, api_key: &str) -> serde_json::Value {
+        let now = self.clock.unix_secs() as u64;
+        let limit = self.limit_for_key(api_key).await;
+        let window_secs = limit.per_seconds.max(1);
+        let used = if limit.burst.is_some() {
+            self.burst.used(api_key, &limit, now)
+        } else {
+            let peers = self.gossip.as_ref().map_or(0, |gossip| {
+                gossip.remote_count(api_key, window_secs, now) as isize
+            });
+            rate_for_window(window_secs).rate_with(&api_key, |c| c.curr_samples) + peers
+        };
+
+        let plan = self.limiter.plan_for_key(api_key);
+        let api_key_id = self
+            .limiter
+            .key_context(api_key)
+            .map(|(_, api_key_id, _)| api_key_id);
+        let monthly = match (&plan, &self.usage_tracker, api_key_id) {
+            (Some(plan), Some(tracker), Some(api_key_id)) => {
+                let used = tracker.monthly_requests(api_key_id, now as i64);
+                serde_json::json!({
+                    "quota": plan.monthly_quota,
+                    "used": used,
+                    "remaining": (plan.monthly_quota as i64 - used as i64).max(0),
+                })
+            }
+            (Some(plan), _, _) => serde_json::json!({ "quota": plan.monthly_quota }),
+            (None, _, _) => serde_json::Value::Null,
+        };
+
+        let mut body = serde_json::json!({
+            "plan": plan.as_ref().map(|p| p.name.clone()),
+            "limit": {
+                "quota": limit.quota,
+                "window_secs": window_secs,
+                "remaining": (limit.quota - used).max(0),
+            },
+            "monthly": monthly,
+        });
+        if limit.burst.is_some() {
+            body["burst_credits"] = self.burst.credits(api_key).floor().into();
+        }
+        body
+    }
+
+    /// Answer a request no service matches with the configured 404 body.
+    async fn respond_route_miss(&self, session: &mut Session) -> Result<()> {
+        let route_miss = self.config.read_or_recover().route_miss.clone();
+        let Some(body) = route_miss.body else {
+            let body = serde_json::json!({ "error": "no service for path" });
+            return respond_json(session, 404, &body).await;
+        };
+        let mut header = ResponseHeader::build(404, None)?;
+        header.insert_header(
+            "Content-Type",
+            route_miss.content_type.as_deref().unwrap_or("text/plain"),
+        )?;
+        header.insert_header("Content-Length", body.len().to_string())?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(bytes::Bytes::from(body)), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Answer a request for a service in maintenance with a 503.
+    async fn respond_maintenance(&self, session: &mut Session, service: &str) -> Result<()> {
+        let maintenance = self.config.read_or_recover().maintenance.clone();
+        let (body, content_type) = match maintenance.body {
+            Some(body) => (
+                body,
+                maintenance.content_type.as_deref().unwrap_or("text/plain"),
+            ),
+            None => (
+                serde_json::json!({ "error": "service under maintenance", "service": service })
+                    .to_string(),
+                "application/json",
+            ),
+        };
+        let mut header = ResponseHeader::build(503, None)?;
+        header.insert_header("Retry-After", maintenance.retry_after_secs.to_string())?;
+        header.insert_header("Content-Type", content_type)?;
+        header.insert_header("Content-Length", body.len().to_string())?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(bytes::Bytes::from(body)), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Answer the request from the static cache of its service; whether it was.
+    async fn serve_cached(&self, session: &mut Session, ctx: &mut RequestCtx) -> Result<bool> {
+        let Some(route) = ctx
+            .route
+            .clone()
+            .filter(|route| route.static_cache.is_some())
+        else {
+            return Ok(false);
+        };
+        let Some(key) = cache_key(&route.service, session.req_header()) else {
+            return Ok(false);
+        };
+        let Some(cached) = self.static_cache.get(&key, Instant::now()) else {
+            ctx.trace("static_cache", || "miss".to_string());
+            ctx.cache_key = Some(key);
+            return Ok(false);
+        };
+        ctx.trace("static_cache", || format!("hit etag={}", cached.etag));
+        ctx.cache_hit = true;
+
+        self.recorder
+            .increment_labeled(STATIC_CACHE_HITS_COUNTER, &route.service);
+        let (mut header, body) = if cached.not_modified(session.req_header()) {
+            let mut header = ResponseHeader::build(304, None)?;
+            header.insert_header(http::header::ETAG, cached.etag.as_str())?;
+            (header, None)
+        } else {
+            (cached.header.clone(), Some(cached.body.clone()))
+        };
+        if let Some(fingerprint) = ctx.key_fingerprint.as_ref() {
+            self.recorder.record(fingerprint, header.status.as_u16());
+        }
+        for (name, value) in self.debug_headers(ctx) {
+            header.insert_header(name, value)?;
+        }
+        ctx.response_bytes = body.as_ref().map_or(0, |body| body.len() as u64);
+        session
+            .write_response_header(Box::new(header), body.is_none())
+            .await?;
+        if body.is_some() {
+            session.write_response_body(body, true).await?;
+        }
+        Ok(true)
+    }
+
+    /// Service and `Retry-After` seconds of a request shed because its service's upstreams
+    /// are saturated; requests of priority plans are shed last.
+    fn shed(&self, ctx: &RequestCtx) -> Option<(String, u64)> {
+        let route = ctx.route.as_ref()?;
+        let shedding = route.load_shedding.as_ref()?;
+        let saturation = shedding.saturation(&route.pool.as_ref()?.load(Instant::now()));
+        // No plan is shed below this, so the plan is only looked up under load
+        if saturation < shedding.shed_others_at {
+            return None;
+        }
+        let plan = ctx
+            .api_key
+            .as_deref()
+            .and_then(|api_key| self.limiter.plan_for_key(api_key));
+        (saturation >= shedding.threshold(plan.as_ref().map(|plan| plan.name.as_str())))
+            .then(|| (route.service.clone(), shedding.retry_after_secs))
+    }
+
+    /// Whether the request goes upstream again after `failure`, by the retry policy of the
+    /// backend it was sent to; counts the retry if so.
+    fn retry(&self, ctx: &mut RequestCtx, failure: RetryOn) -> bool {
+        if !ctx.upstream_retry.allows(failure, ctx.retries) {
+            return false;
+        }
+        // The service's budget caps the retries of all of its backends
+        if let Some(budget) = ctx.route.as_ref().and_then(|route| route.retry_budget)
+            && ctx.retries >= budget
+        {
+            ctx.trace("retry", || format!("budget of {budget} spent"));
+            return false;
+        }
+        ctx.retries += 1;
+        let (attempt, upstream) = (ctx.retries, ctx.upstream.clone().unwrap_or_default());
+        ctx.trace("retry", || {
+            format!("attempt={attempt} after={failure:?} upstream={upstream}")
+        });
+        if let Some(route) = &ctx.route {
+            self.recorder
+                .increment_labeled(UPSTREAM_RETRIES_COUNTER, &route.service);
+        }
+        true
+    }
+
+    /// Whether the request goes to its service's fallback backends after the others failed
+    /// it; counts it if so.
+    fn fall_back(&self, ctx: &mut RequestCtx) -> bool {
+        let Some(route) = &ctx.route else {
+            return false;
+        };
+        if ctx.on_fallback || route.fallback.is_none() {
+            return false;
+        }
+        self.recorder
+            .increment_labeled(FALLBACK_REQUESTS_COUNTER, &route.service);
+        ctx.on_fallback = true;
+        // The other address family of a failed endpoint is not tried instead
+        ctx.upstream_fallback = None;
+        let retries = ctx.retries;
+        ctx.trace("fallback", || format!("after {retries} retries"));
+        true
+    }
+
+    /// Count and log a connection to the upstream failed because its certificate did not
+    /// chain to the trusted CAs or matched no pin.
+    fn tls_mismatch(&self, ctx: &RequestCtx) {
+        let service = ctx
+            .route
+            .as_ref()
+            .map_or("", |route| route.service.as_str());
+        log::error!(
+            "TLS certificate of upstream {} of service {} is not trusted or pinned",
+            ctx.upstream.as_deref().unwrap_or_default(),
+            service
+        );
+        self.recorder
+            .increment_labeled(TLS_MISMATCH_COUNTER, service);
+    }
+
+    /// Admit a request to go upstream, waiting behind requests of higher plans while every
+    /// slot is taken. Returns the plan priority of a rejected request.
+    async fn admit(&self, ctx: &mut RequestCtx) -> std::result::Result<(), i32> {
+        let Some(admission) = &self.admission else {
+            return Ok(());
+        };
+        // The plan is only looked up once requests queue
+        if let Some(permit) = admission.try_admit() {
+            ctx.admission = Some(permit);
+            return Ok(());
+        }
+        let priority = ctx
+            .api_key
+            .as_deref()
+            .and_then(|api_key| self.limiter.plan_for_key(api_key))
+            .map_or(0, |plan| plan.priority);
+        self.recorder
+            .increment_labeled(OVERLOAD_QUEUED_COUNTER, &priority.to_string());
+        match admission.admit(priority).await {
+            Ok(permit) => {
+                ctx.trace("admitted", || format!("priority={priority}"));
+                ctx.admission = Some(permit);
+                Ok(())
+            }
+            Err(rejected) => {
+                ctx.trace("overload_rejected", || {
+                    let reason = match rejected {
+                        Rejected::QueueFull => "queue full",
+                        Rejected::TimedOut => "queue timeout",
+                    };
+                    format!("priority={priority} {reason}")
+                });
+                Err(priority)
+            }
+        }
+    }
+
+    /// Remaining upstream budget for a request, until the earlier of the client's deadline
+    /// and its service's timeout, or a 504 once it is exhausted.
+    fn upstream_budget(&self, ctx: &RequestCtx) -> Result<Option<Duration>> {
+        let Some(deadline) = ctx.deadline.into_iter().chain(ctx.service_deadline()).min() else {
+            return Ok(None);
+        };
+        let overhead = Duration::from_millis(self.deadline.overhead_ms);
+        match remaining_budget(deadline, Instant::now(), overhead) {
+            Some(budget) => Ok(Some(budget)),
+            None => Err(LbError::Upstream("request timeout budget exhausted".to_string()).into()),
         }
     }
 }
 
+/// Check a request header against the listener's size limits.
+///
+/// Returns the status to reject with (414 or 431) and the metric counter to bump.
+fn check_header_limits(
+    req: &RequestHeader,
+    listener: &ListenerConfig,
+) -> Option<(u16, &'static str)> {
+    if let Some(max) = listener.max_uri_length {
+        let uri_len = req.uri.path_and_query().map_or(0, |pq| pq.as_str().len());
+        if uri_len > max {
+            return Some((414, "requests_rejected_uri_too_long"));
+        }
+    }
+    if let Some(max) = listener.max_header_count
+        && req.headers.len() > max
+    {
+        return Some((431, "requests_rejected_header_count"));
+    }
+    if let Some(max) = listener.max_header_bytes {
+        // name + ": " + value + CRLF, as sent on the wire
+        let header_bytes: usize = req
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        if header_bytes > max {
+            return Some((431, "requests_rejected_header_bytes"));
+        }
+    }
+    None
+}
+
 /// Context for each request, tracking API key and usage information.
 #[derive(Default)]
 pub struct RequestCtx {
     /// The API key from the request header.
     pub api_key: Option<String>,
+    /// Fingerprint of the API key; the only form of the key logged or used in metrics.
+    pub key_fingerprint: Option<String>,
     /// Usage context: (account_id, api_key_id, plan_id) if resolved.
     pub usage_ctx: Option<(i64, Uuid, i64)>,
-    /// Accumulated response body size in bytes.
+    /// Accumulated request body size in bytes.
+    pub request_bytes: u64,
+    /// Response body bytes sent downstream so far, chunk by chunk for streamed responses.
     pub response_bytes: u64,
+    /// Absolute deadline derived from the client's timeout budget header.
+    pub deadline: Option<Instant>,
+    /// Route the request took, set once it is matched.
+    pub route: Option<Arc<Route>>,
+    /// When the request header was received, for access log timing.
+    pub received_at: Option<Instant>,
+    /// Whether the request carried the debug token.
+    pub debug: bool,
+    /// Upstream address the request was sent to.
+    pub upstream: Option<String>,
+    /// Pool the upstream was picked from and when, until its outcome is reported.
+    pub upstream_pick: Option<(Arc<ServicePool>, Instant)>,
+    /// Endpoint of the other address family to retry on if connecting to `upstream` fails.
+    pub upstream_fallback: Option<Endpoint>,
+    /// Whether the upstream is an address family fallback, which gets no fallback itself.
+    pub fell_back: bool,
+    /// Connection recycling limits of the upstream's backend.
+    pub upstream_recycling: ConnectionRecycling,
+    /// Retry policy of the upstream's backend.
+    pub upstream_retry: RetryPolicy,
+    /// TLS settings of the upstream's backend, if it is reached over TLS.
+    pub upstream_tls: Option<Arc<UpstreamTls>>,
+    /// Endpoints the request was sent to so far, which retries avoid.
+    pub tried: Vec<String>,
+    /// Retries taken by the request's retry policy.
+    pub retries: u32,
+    /// Whether the request goes to its service's fallback backends.
+    pub on_fallback: bool,
+    /// Whether the upstream connection is closed after this request.
+    pub close_upstream: bool,
+    /// Where the rate limit applied to the request came from.
+    pub limit_source: Option<LimitSource>,
+    /// Tags set by the tagging rules, in rule order.
+    pub tags: Vec<String>,
+    /// Tag whose rate limit applies to the request on top of its key's, and that limit.
+    pub tag_rps_limit: Option<(String, isize)>,
+    /// Share of access log lines kept for the request; all when unset.
+    pub log_sample: Option<f64>,
+    /// Static cache key of a request to a cached service, set when it goes upstream.
+    pub cache_key: Option<String>,
+    /// Whether the response is served from the static cache.
+    pub cache_hit: bool,
+    /// Upstream response being collected for the static cache.
+    pub cache_fill: Option<(ResponseHeader, bytes::BytesMut)>,
+    /// Replay cache key claimed by the request, released unless it succeeds.
+    pub replay_key: Option<String>,
+    /// Body length the upstream response declared, checked as its chunks arrive.
+    pub length_check: Option<LengthCheck>,
+    /// Request body collected so far for the service's shadow backends.
+    pub shadow_body: Option<bytes::BytesMut>,
+    /// Whether the request body is too large to copy to shadow backends.
+    pub shadow_oversized: bool,
+    /// Decisions taken so far, when the key's requests are traced.
+    pub trace: Option<Vec<TraceEvent>>,
+    /// Admission slot held until the request completes.
+    pub admission: Option<Permit>,
+}
+
+impl RequestCtx {
+    /// When the timeout of the request's service runs out.
+    fn service_deadline(&self) -> Option<Instant> {
+        Some(self.received_at? + self.route.as_ref()?.timeout?)
+    }
+
+    /// Record a decision if the request is traced.
+    fn trace(&mut self, stage: &'static str, detail: impl FnOnce() -> String) {
+        if let Some(events) = &mut self.trace {
+            events.push(TraceEvent {
+                stage,
+                at_us: self
+                    .received_at
+                    .map_or(0, |t| t.elapsed().as_micros() as u64),
+                detail: detail(),
+            });
+        }
+    }
+}
+
+/// Whether a proxy error means the client went away before the response completed.
+///
+/// Pingora drops the upstream connection (instead of returning it to the pool) when the
+/// downstream fails, so the upstream request is cancelled as soon as this happens.
+fn is_client_abort(e: &Error) -> bool {
+    e.esource == ErrorSource::Downstream
+        && matches!(
+            e.etype,
+            ErrorType::ConnectionClosed
+                | ErrorType::ReadError
+                | ErrorType::WriteError
+                | ErrorType::ReadTimedout
+                | ErrorType::WriteTimedout
+        )
+}
+
+/// Whether a proxy error is a timeout connecting to or reading from the upstream, or the
+/// request's time running out before it could be sent.
+fn is_timeout(e: &Error) -> bool {
+    matches!(LbError::of(e), Some(LbError::Upstream(_)))
+        || (e.esource != ErrorSource::Downstream
+            && matches!(
+                e.etype,
+                ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout
+            ))
+}
+
+/// Path and query of a request for `uri` as sent to the upstreams of `route`, if the route
+/// rewrites its path; the query is kept.
+fn rewritten_uri(route: &Route, uri: &http::Uri) -> Option<String> {
+    let rewritten = route.rewrite_path(uri.path())?;
+    Some(match uri.query() {
+        Some(query) if rewritten.contains('?') => format!("{rewritten}&{query}"),
+        Some(query) => format!("{rewritten}?{query}"),
+        None => rewritten,
+    })
+}
+
+/// Route miss label for `path`: its first segment, cut to a bounded length.
+fn route_miss_prefix(path: &str) -> &str {
+    let end = path[1.min(path.len())..]
+        .find('/')
+        .map_or(path.len(), |i| i + 1);
+    let mut end = end.min(MAX_ROUTE_MISS_PREFIX_LEN);
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    &path[..end]
+}
+
+/// Write the fallback response of `service` for a request its backends failed.
+async fn respond_fallback(
+    session: &mut Session,
+    service: &str,
+    response: &FallbackResponseConfig,
+) -> Result<()> {
+    let (body, content_type) = response_body(response, service);
+    let mut header = ResponseHeader::build(response.status, None)?;
+    header.insert_header("Content-Type", content_type)?;
+    header.insert_header("Content-Length", body.len().to_string())?;
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session
+        .write_response_body(Some(bytes::Bytes::from(body)), true)
+        .await?;
+    Ok(())
+}
+
+/// Write a complete JSON response generated by the LB itself.
+async fn respond_json(session: &mut Session, status: u16, body: &serde_json::Value) -> Result<()> {
+    let body = bytes::Bytes::from(body.to_string());
+    let mut header = ResponseHeader::build(status, None)?;
+    header.insert_header("Content-Type", "application/json")?;
+    header.insert_header("Content-Length", body.len().to_string())?;
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session.write_response_body(Some(body), true).await?;
+    Ok(())
+}
+
+/// Write a 429 response for a client over its limit.
+async fn reject_rate_limited(
+    session: &mut Session,
+    quota: isize,
+    window_secs: u64,
+    debug_headers: Vec<(&'static str, String)>,
+) -> Result<()> {
+    let mut header = ResponseHeader::build(429, None)?;
+    header.insert_header("Retry-After", window_secs.to_string())?;
+    header.insert_header("X-RateLimit-Limit", quota.to_string())?;
+    header.insert_header("X-RateLimit-Remaining", "0")?;
+    for (name, value) in debug_headers {
+        header.insert_header(name, value)?;
+    }
+    session.set_keepalive(None);
+    session.write_response_header(Box::new(header), true).await
+}
+
+/// Write a 503 response for a request shed or refused admission under overload.
+async fn reject_overloaded(
+    session: &mut Session,
+    retry_after_secs: u64,
+    debug_headers: Vec<(&'static str, String)>,
+) -> Result<()> {
+    let mut header = ResponseHeader::build(503, None)?;
+    header.insert_header("Retry-After", retry_after_secs.to_string())?;
+    for (name, value) in debug_headers {
+        header.insert_header(name, value)?;
+    }
+    session.write_response_header(Box::new(header), true).await
 }
 
 #[async_trait]
@@ -70,10 +910,149 @@ impl ProxyHttp for Lb {
         RequestCtx::default()
     }
 
+    async fn early_request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.received_at = Some(Instant::now());
+        if self.deadline.enabled {
+            ctx.deadline =
+                deadline_for_request(session.req_header(), &self.deadline, Instant::now());
+        }
+
+        // Pingora keeps connections the client asked to keep alive open indefinitely
+        if let Some(timeout) = self.listener.keepalive_timeout_secs
+            && session.get_keepalive().is_some()
+        {
+            session.set_keepalive((timeout > 0).then_some(timeout));
+        }
+        if let Some(idle) = self.listener.idle_timeout_secs {
+            let idle = Duration::from_secs(idle);
+            session.set_read_timeout(Some(idle));
+            session.set_write_timeout(Some(idle));
+        }
+
+        if !self.connections.is_enabled() {
+            return Ok(());
+        }
+        let Some(peer) = session.client_addr().and_then(|a| a.as_inet()).copied() else {
+            return Ok(());
+        };
+
+        // Connections were admitted when accepted
+        let requests = self.connections.on_request(peer);
+        if self.connections.is_last_request(requests) {
+            session.set_keepalive(None);
+        }
+        Ok(())
+    }
+
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
     where
         Self::CTX: Send + Sync,
     {
+        if let Some((status, counter)) = check_header_limits(session.req_header(), &self.listener) {
+            self.recorder.increment(counter);
+            let header = ResponseHeader::build(status, None)?;
+            session.set_keepalive(None);
+            session
+                .write_response_header(Box::new(header), true)
+                .await?;
+            return Ok(true);
+        }
+
+        ctx.debug = self.debug_headers.allows(
+            session
+                .req_header()
+                .headers
+                .get(self.debug_headers.header.as_str())
+                .and_then(|v| v.to_str().ok()),
+        );
+
+        // Routed before auth so unknown paths are answered without touching key state
+        let path = session.req_header().uri.path();
+        let route = {
+            let config = self.config.read_or_recover();
+            let tagging = &config.tagging;
+            ctx.tags = tagging.tags(session.req_header());
+            ctx.tag_rps_limit = tagging
+                .rps_limit(&ctx.tags)
+                .map(|(tag, limit)| (tag.to_string(), limit));
+            ctx.log_sample = tagging.log_sample(&ctx.tags);
+            tagging
+                .service(&ctx.tags)
+                .and_then(|service| config.routes.service(service))
+                .or_else(|| config.route_request(session.req_header()))
+                .cloned()
+        };
+        let public_limit = match route {
+            // Answered before auth, so clients learn of it whatever their key
+            Some(route) if route.maintenance && !matches!(path, RATELIMIT_PATH | ME_PATH) => {
+                self.recorder
+                    .increment_labeled(MAINTENANCE_RESPONSES_COUNTER, &route.service);
+                ctx.trace("maintenance", || format!("service={}", route.service));
+                self.respond_maintenance(session, &route.service).await?;
+                ctx.route = Some(route);
+                return Ok(true);
+            }
+            // Undocumented operations never reach the service
+            Some(route)
+                if !matches!(path, RATELIMIT_PATH | ME_PATH)
+                    && let Some(Err(violation)) = route
+                        .openapi
+                        .as_ref()
+                        .map(|spec| spec.check(session.req_header())) =>
+            {
+                self.recorder.increment_labeled_bounded(
+                    REJECTIONS_COUNTER,
+                    &rejection_label(&route.service, session.req_header()),
+                    MAX_REJECTED_OPERATIONS,
+                );
+                ctx.route = Some(route);
+                let body = serde_json::json!({ "error": violation.to_string() });
+                respond_json(session, violation.status(), &body).await?;
+                return Ok(true);
+            }
+            Some(route) => {
+                let public_limit = (route.auth == AuthMode::None).then_some(route.ip_rps_limit);
+                ctx.route = Some(route);
+                public_limit
+            }
+            // The LB's own endpoints need no service
+            None if matches!(path, RATELIMIT_PATH | ME_PATH) => None,
+            None => {
+                self.recorder.increment_labeled_bounded(
+                    ROUTE_MISSES_COUNTER,
+                    route_miss_prefix(path),
+                    MAX_ROUTE_MISS_PREFIXES,
+                );
+                self.respond_route_miss(session).await?;
+                return Ok(true);
+            }
+        };
+
+        // Internal callers and public routes skip API key auth and are limited per client IP
+        let ip_limit = match self.internal_rps_limit {
+            Some(rps_limit) => Some((INTERNAL_KEY, rps_limit)),
+            None => public_limit.map(|rps_limit| (ANONYMOUS_KEY, rps_limit)),
+        };
+        if let Some((metrics_key, rps_limit)) = ip_limit {
+            ctx.key_fingerprint = Some(metrics_key.to_string());
+            ctx.limit_source = Some(LimitSource::Ip);
+            let client_ip = session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+            let rate = rate_for_window(1);
+            if rate.observe(&format!("{metrics_key}{client_ip}"), 1) > rps_limit {
+                self.recorder.record(metrics_key, 429);
+                let debug = self.debug_headers(ctx);
+                reject_rate_limited(session, rps_limit, 1, debug).await?;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
         let api_key = match session
             .req_header()
             .headers
@@ -82,7 +1061,7 @@ impl ProxyHttp for Lb {
         {
             Some(k) => k.to_owned(),
             None => {
-                self.metrics.record(MISSING_API_KEY, 401);
+                self.recorder.record(MISSING_API_KEY, 401);
                 let mut header = ResponseHeader::build(401, None)?;
                 header.insert_header("WWW-Authenticate", "API key missing")?;
                 session.set_keepalive(None);
@@ -94,34 +1073,198 @@ impl ProxyHttp for Lb {
         };
 
         ctx.api_key = Some(api_key.clone());
+        // Keys provisioned since the last account refresh are looked up in the DB (if enabled)
+        self.limiter.read_through(&api_key);
+        let fingerprint = self.limiter.fingerprint(&api_key);
+        ctx.key_fingerprint = Some(fingerprint.clone());
+        if self
+            .traces
+            .as_ref()
+            .is_some_and(|traces| traces.claim(&fingerprint))
+        {
+            ctx.trace = Some(Vec::new());
+            let detail = format!(
+                "service={} tags={}",
+                ctx.route.as_ref().map_or("-", |r| r.service.as_str()),
+                ctx.tags.join(",")
+            );
+            ctx.trace("route", || detail);
+        }
+
+        // Answered before rate limiting and usage tracking so these calls cost nothing
+        if session.req_header().method == "GET"
+            && let Some((status, body)) = self
+                .self_service_response(session.req_header().uri.path(), &api_key)
+                .await
+        {
+            ctx.trace("self_service", || format!("status={status}"));
+            respond_json(session, status, &body).await?;
+            return Ok(true);
+        }
+
+        if let (Some(tenant), Some(route)) = (self.tenants.for_key(&api_key), &ctx.route)
+            && !tenant.allows(&route.service)
+        {
+            self.recorder.record(&fingerprint, 403);
+            ctx.trace("tenant", || format!("denied tenant={}", tenant.name));
+            let body = serde_json::json!({ "error": "service not available for this API key" });
+            respond_json(session, 403, &body).await?;
+            return Ok(true);
+        }
 
         // Resolve usage context for tracking
         if self.usage_tracker.is_some() {
-            let api_key_hash = hash_api_key(&api_key);
-            ctx.usage_ctx = self.limiter.get_key_context(&api_key_hash);
+            ctx.usage_ctx = self.limiter.key_context(&api_key);
         }
 
-        let limit = self.limiter.limit_for_key(&api_key);
+        let limit = self.limit_for_key(&api_key).await;
         let window_secs = limit.per_seconds.max(1);
-        let rate = rate_for_window(window_secs);
-        let seen = rate.observe(&api_key, 1);
-
-        if seen > limit.quota {
-            self.metrics.record(&api_key, 429);
-            let mut header = ResponseHeader::build(429, None)?;
-            header.insert_header("Retry-After", window_secs.to_string())?;
-            header.insert_header("X-RateLimit-Limit", limit.quota.to_string())?;
-            header.insert_header("X-RateLimit-Remaining", "0")?;
-            session.set_keepalive(None);
-            session
-                .write_response_header(Box::new(header), true)
-                .await?;
+        ctx.limit_source = Some(limit.source);
+        let allowed = if limit.burst.is_some() {
+            // Plans with burst credits are tracked per fixed window by the credit store
+            let now = self.clock.unix_secs() as u64;
+            if let Some(state) = &self.limiter_state {
+                state.restore_burst(&api_key);
+            }
+            self.burst.allow(&api_key, &limit, now)
+        } else {
+            // Requests counted before a restart join the first request after it
+            let restored = self.limiter_state.as_ref().map_or(0, |state| {
+                state.record(&api_key, window_secs, self.clock.unix_secs() as u64)
+            });
+            let rate = rate_for_window(window_secs);
+            let local = rate.observe(&api_key, 1 + restored);
+            // Requests peers let through in the same window count as well
+            let peers = self.gossip.as_ref().map_or(0, |gossip| {
+                let now = self.clock.unix_secs() as u64;
+                gossip.record(&api_key, window_secs, now) as isize
+            });
+            let quota = self
+                .gossip
+                .as_ref()
+                .map_or(limit.quota, |gossip| gossip.local_quota(limit.quota));
+            local + peers <= quota
+        };
+
+        ctx.trace("rate_limit", || {
+            format!(
+                "{} source={} quota={} window={}s",
+                if allowed { "allowed" } else { "rejected" },
+                limit.source.as_str(),
+                limit.quota,
+                window_secs
+            )
+        });
+        if !allowed {
+            self.recorder.record(&fingerprint, 429);
+            let debug = self.debug_headers(ctx);
+            reject_rate_limited(session, limit.quota, window_secs, debug).await?;
+            return Ok(true);
+        }
+
+        // Tagged requests are also counted per key and tag against the tag's own limit
+        if let Some((tag, rps_limit)) = &ctx.tag_rps_limit
+            && rate_for_window(1).observe(&format!("{api_key}:{tag}"), 1) > *rps_limit
+        {
+            let (tag, rps_limit) = (tag.clone(), *rps_limit);
+            ctx.limit_source = Some(LimitSource::Tag);
+            ctx.trace("rate_limit", || {
+                format!("rejected tag={tag} quota={rps_limit}")
+            });
+            self.recorder.record(&fingerprint, 429);
+            let debug = self.debug_headers(ctx);
+            reject_rate_limited(session, rps_limit, 1, debug).await?;
             return Ok(true);
         }
 
         Ok(false)
     }
 
+    async fn proxy_upstream_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if self.serve_cached(session, ctx).await? {
+            return Ok(false);
+        }
+
+        // Shed before connecting upstream, once the cache had its chance
+        if let Some((service, retry_after)) = self.shed(ctx) {
+            ctx.trace("shed", || format!("retry_after={retry_after}s"));
+            self.recorder
+                .increment_labeled(SHED_REQUESTS_COUNTER, &service);
+            if let Some(fingerprint) = ctx.key_fingerprint.as_ref() {
+                self.recorder.record(fingerprint, 503);
+            }
+            let debug = self.debug_headers(ctx);
+            reject_overloaded(session, retry_after, debug).await?;
+            return Ok(false);
+        }
+
+        if let Err(priority) = self.admit(ctx).await {
+            self.recorder
+                .increment_labeled(OVERLOAD_REJECTED_COUNTER, &priority.to_string());
+            if let Some(fingerprint) = ctx.key_fingerprint.as_ref() {
+                self.recorder.record(fingerprint, 503);
+            }
+            let retry_after = self
+                .admission
+                .as_ref()
+                .map_or(1, |admission| admission.config().retry_after_secs);
+            let debug = self.debug_headers(ctx);
+            reject_overloaded(session, retry_after, debug).await?;
+            return Ok(false);
+        }
+
+        let hooks = self.request_hooks();
+        if !hooks.is_empty() {
+            let hook_ctx = HookContext {
+                service: ctx.route.as_ref().map(|route| route.service.as_str()),
+                tags: &ctx.tags,
+                key_fingerprint: ctx.key_fingerprint.as_deref(),
+            };
+            if let Some((hook, status, body)) =
+                crate::hooks::on_request(&hooks, &hook_ctx, session.req_header_mut())
+            {
+                ctx.trace("hook", || format!("{hook} responded status={status}"));
+                self.recorder
+                    .increment_labeled(HOOK_RESPONSES_COUNTER, &hook);
+                if let Some(fingerprint) = ctx.key_fingerprint.as_ref() {
+                    self.recorder.record(fingerprint, status);
+                }
+                respond_json(session, status, &body).await?;
+                return Ok(false);
+            }
+        }
+
+        // Claimed last, so only deliveries sent upstream are remembered
+        if let Some(route) = ctx.route.clone()
+            && let Some(replay) = &route.replay_protection
+            && let Some(key) = replay_key(&route.service, replay, session.req_header())
+        {
+            if !self
+                .replays
+                .claim(key.clone(), replay.ttl(), Instant::now())
+            {
+                ctx.trace("replay", || "rejected".to_string());
+                self.recorder
+                    .increment_labeled(REPLAYED_REQUESTS_COUNTER, &route.service);
+                if let Some(fingerprint) = ctx.key_fingerprint.as_ref() {
+                    self.recorder.record(fingerprint, 409);
+                }
+                let body = serde_json::json!({ "error": "request already received" });
+                respond_json(session, 409, &body).await?;
+                return Ok(false);
+            }
+            ctx.replay_key = Some(key);
+        }
+        Ok(true)
+    }
+
     async fn response_filter(
         &self,
         _session: &mut Session,
@@ -131,97 +1274,692 @@ impl ProxyHttp for Lb {
     where
         Self::CTX: Send + Sync,
     {
-        if let Some(api_key) = ctx.api_key.as_ref() {
-            self.metrics
-                .record(api_key, upstream_response.status.as_u16());
+        // Stored as the upstream sent it, without the debug headers of this request
+        if ctx.cache_key.is_some() && is_storable(upstream_response) {
+            ctx.cache_fill = Some((upstream_response.clone(), bytes::BytesMut::new()));
+        }
+        if let Some(fingerprint) = ctx.key_fingerprint.as_ref() {
+            self.recorder
+                .record(fingerprint, upstream_response.status.as_u16());
+        }
+        let hooks = self.request_hooks();
+        if !hooks.is_empty() {
+            let hook_ctx = HookContext {
+                service: ctx.route.as_ref().map(|route| route.service.as_str()),
+                tags: &ctx.tags,
+                key_fingerprint: ctx.key_fingerprint.as_deref(),
+            };
+            crate::hooks::on_response(&hooks, &hook_ctx, upstream_response);
+        }
+        for (name, value) in self.debug_headers(ctx) {
+            upstream_response.insert_header(name, value)?;
         }
         Ok(())
     }
 
-    fn upstream_response_body_filter(
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        ctx.trace("upstream_response", || {
+            format!("status={}", upstream_response.status.as_u16())
+        });
+        // Latency up to the response header feeds latency-aware selection, and server
+        // errors passive health checking
+        let status = upstream_response.status.as_u16();
+        if let (Some((pool, sent_at)), Some(addr)) = (ctx.upstream_pick.take(), &ctx.upstream) {
+            pool.report_status(addr, status, sent_at.elapsed(), Instant::now());
+        }
+        // Nothing was sent downstream yet, so the request can go to another endpoint
+        if let Some(failure) = RetryOn::for_status(status)
+            && !session.as_ref().retry_buffer_truncated()
+            && self.retry(ctx, failure)
+        {
+            let mut e = Error::explain(HTTPStatus(status), "retrying upstream response");
+            e.set_retry(true);
+            return Err(e);
+        }
+        if status >= 500 && !session.as_ref().retry_buffer_truncated() {
+            if self.fall_back(ctx) {
+                let mut e = Error::explain(HTTPStatus(status), "falling back from upstream");
+                e.set_retry(true);
+                return Err(e);
+            }
+            // Answered with the fallback response by fail_to_proxy
+            if ctx
+                .route
+                .as_ref()
+                .is_some_and(|route| route.fallback_response.is_some())
+            {
+                return Error::e_explain(HTTPStatus(status), "upstream failed the request");
+            }
+        }
+        ctx.length_check = None;
+        let reject = ctx
+            .route
+            .as_ref()
+            .is_some_and(|route| route.reject_truncated);
+        match declared_length(&session.req_header().method, upstream_response) {
+            Ok(declared) => {
+                ctx.length_check = declared.map(|declared| LengthCheck::new(declared, reject));
+            }
+            Err(e) => {
+                let service = ctx
+                    .route
+                    .as_ref()
+                    .map_or("-", |route| route.service.as_str());
+                let upstream = ctx.upstream.as_deref().unwrap_or("-");
+                log::warn!("Upstream {upstream} of service {service} sent an {e}");
+                self.recorder.increment_labeled(
+                    TRUNCATED_RESPONSES_COUNTER,
+                    &format!("service={service},upstream={upstream}"),
+                );
+                if reject {
+                    return Error::e_explain(HTTPStatus(502), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
         &self,
         _session: &mut Session,
         body: &mut Option<bytes::Bytes>,
         _end_of_stream: bool,
         ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(bytes) = body {
+            ctx.request_bytes += bytes.len() as u64;
+            if ctx
+                .route
+                .as_ref()
+                .is_some_and(|route| route.shadow.is_some())
+                && !ctx.shadow_oversized
+            {
+                let collected = ctx.shadow_body.get_or_insert_with(bytes::BytesMut::new);
+                if collected.len() + bytes.len() > MAX_BODY_BYTES {
+                    ctx.shadow_body = None;
+                    ctx.shadow_oversized = true;
+                } else {
+                    collected.extend_from_slice(bytes);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
-        // Accumulate response body size
+        if let Some(config) = ctx.route.as_ref().and_then(|r| r.static_cache.as_ref()) {
+            if let (Some((_, collected)), Some(bytes)) = (&mut ctx.cache_fill, &*body) {
+                collected.extend_from_slice(bytes);
+            }
+            if ctx
+                .cache_fill
+                .as_ref()
+                .is_some_and(|(_, collected)| collected.len() > config.max_body_bytes)
+            {
+                ctx.cache_fill = None;
+            }
+            if end_of_stream
+                && let (Some((header, collected)), Some(key)) =
+                    (ctx.cache_fill.take(), ctx.cache_key.clone())
+            {
+                self.static_cache.insert(
+                    key,
+                    header,
+                    collected.freeze(),
+                    Duration::from_secs(config.ttl_secs),
+                    Instant::now(),
+                );
+            }
+        }
+        // After the cache took its copy, since held chunks are taken out of the body
+        if let Some(check) = &mut ctx.length_check
+            && !check.on_chunk(body, end_of_stream)
+        {
+            return Error::e_explain(HTTPStatus(502), "truncated upstream response");
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<bytes::Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // Counted as chunks go downstream, so a response the client drops mid-stream
+        // counts what it was sent; logging records the total
         if let Some(bytes) = body {
             ctx.response_bytes += bytes.len() as u64;
         }
-        Ok(())
+        Ok(None)
     }
 
-    async fn logging(&self, _session: &mut Session, _e: Option<&Error>, ctx: &mut Self::CTX)
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX)
     where
         Self::CTX: Send + Sync,
     {
+        let aborted = e.is_some_and(is_client_abort);
+
+        // Failed deliveries may be retried by their sender
+        if let Some(key) = ctx.replay_key.take()
+            && (e.is_some()
+                || !session
+                    .response_written()
+                    .is_some_and(|r| r.status.is_success()))
+        {
+            self.replays.release(&key);
+        }
+        if let Some(error) = e.and_then(LbError::of) {
+            self.recorder
+                .increment_labeled(ERRORS_COUNTER, error.class());
+        }
+
+        // The upstream never answered
+        if let (Some((pool, sent_at)), Some(addr)) = (ctx.upstream_pick.take(), &ctx.upstream) {
+            let latency = aborted.then(|| sent_at.elapsed());
+            pool.report(addr, latency, Instant::now());
+        }
+
+        // Keys are identified by their fingerprint only; raw keys never reach the logs
+        let req = session.req_header();
+
+        // Copied once the client is answered, so shadow backends never delay it
+        if let Some(route) = &ctx.route
+            && let Some(pool) = &route.shadow
+            && ctx.upstream.is_some()
+        {
+            if ctx.shadow_oversized {
+                self.recorder
+                    .increment_labeled(SHADOW_DROPPED_COUNTER, &route.service);
+            } else {
+                let mut headers = req.headers.clone();
+                headers.remove(self.debug_headers.header.as_str());
+                let request = ShadowRequest {
+                    service: route.service.clone(),
+                    method: req.method.clone(),
+                    path: rewritten_uri(route, &req.uri).unwrap_or_else(|| {
+                        req.uri
+                            .path_and_query()
+                            .map_or_else(|| "/".to_string(), |pq| pq.to_string())
+                    }),
+                    headers,
+                    body: ctx
+                        .shadow_body
+                        .take()
+                        .map(|body| body.freeze())
+                        .unwrap_or_default(),
+                };
+                self.shadow.mirror(pool.clone(), request, &self.recorder);
+            }
+        }
+        let client_ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        let target = match &self.privacy {
+            Some(privacy) => privacy.request_target(
+                ctx.route.as_ref().map(|route| route.service.as_str()),
+                &req.uri,
+            ),
+            None => req.uri.path().to_string(),
+        };
+        let sampled = ctx
+            .log_sample
+            .is_none_or(|rate| rand::random::<f64>() < rate);
+        if sampled {
+            log::info!(
+                target: ACCESS_TARGET,
+                "{} {} {} {} {}B {}ms key={} service={} labels={} node={}{}",
+                match (&self.privacy, client_ip) {
+                    (Some(privacy), Some(ip)) => privacy.client_ip(ip),
+                    _ => session
+                        .client_addr()
+                        .map_or_else(|| "-".to_string(), |a| a.to_string()),
+                },
+                req.method,
+                target,
+                session
+                    .response_written()
+                    .map_or(0, |r| r.status.as_u16()),
+                ctx.response_bytes,
+                ctx.received_at
+                    .map_or(0, |t| t.elapsed().as_millis()),
+                ctx.key_fingerprint.as_deref().unwrap_or("-"),
+                ctx.route.as_ref().map_or("-", |route| route.service.as_str()),
+                ctx.route
+                    .as_ref()
+                    .filter(|route| !route.labels.is_empty())
+                    .map_or("-", |route| route.labels.as_str()),
+                self.node_id.as_deref().unwrap_or("-"),
+                if aborted { " aborted" } else { "" },
+            );
+        }
+        if let Some(fingerprint) = ctx.key_fingerprint.as_deref() {
+            self.recorder.record_bytes(fingerprint, ctx.response_bytes);
+        }
+        if let Some(e) = e {
+            ctx.trace("error", || e.to_string());
+        }
+        if let (Some(traces), Some(fingerprint), Some(events)) =
+            (&self.traces, &ctx.key_fingerprint, ctx.trace.take())
+        {
+            traces.record(
+                fingerprint,
+                Trace {
+                    finished_at: self.clock.unix_secs(),
+                    method: req.method.to_string(),
+                    path: target,
+                    status: session.response_written().map_or(0, |r| r.status.as_u16()),
+                    duration_us: ctx
+                        .received_at
+                        .map_or(0, |t| t.elapsed().as_micros() as u64),
+                    events,
+                },
+            );
+        }
+        if let Some(route) = &ctx.route {
+            self.recorder
+                .increment_labeled(ROUTED_REQUESTS_COUNTER, &route.metric_label);
+            self.recorder
+                .observe(REQUEST_SIZE_HISTOGRAM, &route.service, ctx.request_bytes);
+            self.recorder
+                .observe(RESPONSE_SIZE_HISTOGRAM, &route.service, ctx.response_bytes);
+            if aborted {
+                self.recorder
+                    .increment_labeled("requests_aborted", &route.service);
+            }
+            // A client going away stops the body as much as the upstream does
+            if let Some(check) = &ctx.length_check
+                && !check.is_complete()
+                && !aborted
+            {
+                let upstream = ctx.upstream.as_deref().unwrap_or("-");
+                log::warn!(
+                    "Upstream {upstream} of service {} sent {} of {} body bytes",
+                    route.service,
+                    check.received,
+                    check.declared
+                );
+                self.recorder.increment_labeled(
+                    TRUNCATED_RESPONSES_COUNTER,
+                    &format!("service={},upstream={upstream}", route.service),
+                );
+            }
+            if e.is_some_and(is_timeout) {
+                let upstream = ctx.upstream.as_deref().unwrap_or("-");
+                self.recorder.increment_labeled(
+                    UPSTREAM_TIMEOUTS_COUNTER,
+                    &format!("service={},upstream={upstream}", route.service),
+                );
+            }
+        }
+
         // Record usage at the end of the request
-        if let (Some(tracker), Some((account_id, api_key_id, plan_id))) =
-            (&self.usage_tracker, &ctx.usage_ctx)
+        if let Some((account_id, api_key_id, plan_id)) = ctx.usage_ctx {
+            self.recorder.usage(UsageEvent {
+                account_id,
+                api_key: api_key_id,
+                plan_id,
+                route: self.usage_route(ctx),
+                response_bytes: ctx.response_bytes,
+                timestamp_secs: self.clock.unix_secs(),
+                aborted,
+                client_ip: client_ip.map(|ip| match &self.privacy {
+                    Some(privacy) => privacy.client_ip(ip),
+                    None => ip.to_string(),
+                }),
+            });
+        }
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] sock: std::os::windows::io::RawSocket,
+        digest: Option<&pingora::protocols::Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(tls) = &ctx.upstream_tls
+            && !tls.accepts(digest)
         {
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64;
-            tracker.record(*account_id, *api_key_id, *plan_id, ctx.response_bytes, now);
+            self.tls_mismatch(ctx);
+            return Err(Error::explain(
+                ErrorType::InvalidCert,
+                "upstream certificate matches no pinned cert_sha256",
+            )
+            .into_up());
         }
+        #[cfg(unix)]
+        let id = fd as u64;
+        #[cfg(windows)]
+        let id = sock;
+        ctx.close_upstream = self.upstream_connections.on_request(
+            id,
+            reused,
+            ctx.upstream_recycling,
+            Instant::now(),
+        );
+        Ok(())
     }
 
-    async fn upstream_peer(
+    async fn upstream_request_filter(
         &self,
-        session: &mut Session,
-        _ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>> {
-        let path = session.req_header().uri.path();
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // The debug token is for the LB only
+        upstream_request.remove_header(self.debug_headers.header.as_str());
 
-        let config = self.config.read().unwrap();
+        // Public paths are translated to the service's own
+        if let Some(uri) = ctx
+            .route
+            .as_ref()
+            .and_then(|route| rewritten_uri(route, &upstream_request.uri))
+        {
+            let uri = http::Uri::try_from(uri).map_err(|e| {
+                Error::because(ErrorType::InternalError, "invalid rewritten path", e)
+            })?;
+            upstream_request.set_uri(uri);
+        }
 
-        // Strategy: Match path to service, then service to backend.
-        // Assuming path matches the service path prefix or exact match?
-        // configuration.rs: `services: HashMap<String, String>` (Name -> Path)
-        // User didn't specify matching strategy, but usually it's prefix or exact.
-        // Let's assume the value in services map is the prefix.
+        // Keeps the upstream connection out of the pool after this request
+        if ctx.close_upstream {
+            upstream_request.insert_header(http::header::CONNECTION, "close")?;
+        }
 
-        let mut selected_service = None;
-        for (service_name, service_path) in &config.services {
-            if path.starts_with(service_path) {
-                // simple longest match or just first match?
-                // For now, let's take the first one, or maybe longest match would be better.
-                // Let's stick to simple logic: match is valid.
-                selected_service = Some(service_name.clone());
-                break;
+        // Forward what is left of the client's budget
+        if let Some(budget) = self.upstream_budget(ctx)?
+            && ctx.deadline.is_some()
+        {
+            upstream_request
+                .insert_header(self.deadline.header.clone(), budget.as_millis().to_string())?;
+        }
+        Ok(())
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        // A request its service's timeout cut short is told so
+        let overhead = Duration::from_millis(self.deadline.overhead_ms);
+        let timed_out = is_timeout(e)
+            && ctx.service_deadline().is_some_and(|deadline| {
+                remaining_budget(deadline, Instant::now(), overhead).is_none()
+            });
+        if timed_out
+            && session.response_written().is_none()
+            && let Some(route) = ctx.route.clone()
+        {
+            ctx.trace("timeout", || "service timeout spent".to_string());
+            let body = serde_json::json!({
+                "error": "service timeout exceeded",
+                "service": route.service,
+                "timeout_ms": route.timeout.map_or(0, |timeout| timeout.as_millis() as u64),
+                "attempts": ctx.retries + 1,
+            });
+            // Tells the client the connection is not reused, as it may hold request body
+            session.set_keepalive(None);
+            if let Err(e) = respond_json(session, 504, &body).await {
+                log::error!("Failed to send timeout response to downstream: {e}");
             }
+            return FailToProxy {
+                error_code: 504,
+                can_reuse_downstream: false,
+            };
         }
 
-        let service_name = selected_service.ok_or_else(|| {
-            Error::explain(ErrorType::HTTPStatus(404), "Service not found for path")
-        })?;
+        // As pingora answers by default
+        let code = match e.etype {
+            ErrorType::HTTPStatus(code) => code,
+            _ => match e.esource {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype {
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        // Requests that went upstream, unless the failure is the client's, may get their
+        // service's fallback response instead
+        if code >= 500
+            && ctx.upstream.is_some()
+            && session.response_written().is_none()
+            && let Some(route) = ctx.route.clone()
+            && let Some(response) = &route.fallback_response
+        {
+            self.recorder
+                .increment_labeled(FALLBACK_RESPONSES_COUNTER, &route.service);
+            ctx.trace("fallback", || {
+                format!("response status={}", response.status)
+            });
+            session.set_keepalive(None);
+            if let Err(e) = respond_fallback(session, &route.service, response).await {
+                log::error!("Failed to send fallback response to downstream: {e}");
+            }
+            return FailToProxy {
+                error_code: response.status,
+                can_reuse_downstream: false,
+            };
+        }
+        if code > 0
+            && let Err(e) = session.respond_error(code).await
+        {
+            log::error!("Failed to send error response to downstream: {e}");
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
 
-        // Find backend for this service
-        // config.backends is Vec<BackendConfig>.
-        let backend_config = config
-            .backends
-            .iter()
-            .find(|b| b.service == service_name)
-            .ok_or_else(|| {
-                Error::explain(ErrorType::HTTPStatus(503), "No backend found for service")
-            })?;
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        if e.etype == ErrorType::InvalidCert && ctx.upstream_tls.is_some() {
+            self.tls_mismatch(ctx);
+        }
+        // upstream_peer is asked again and connects to the other address family, or to
+        // another endpoint, and then to the fallback backends
+        if ctx.upstream_fallback.is_some()
+            || self.retry(ctx, RetryOn::ConnectFailure)
+            || self.fall_back(ctx)
+        {
+            e.set_retry(true);
+        }
+        e
+    }
 
-        match &backend_config.backend {
-            Backend::Basic { ip, port } => {
-                let addr = format!("{}:{}", ip, port);
-                Ok(Box::new(HttpPeer::new(
-                    addr,
-                    false, // plain HTTP to the upstream
-                    String::new(),
-                )))
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        let replayable = !session.as_ref().retry_buffer_truncated();
+        // Stale keepalive connections are retried as pingora does by default
+        e.retry.decide_reuse(client_reused && replayable);
+        // Failures that would be answered with 502 while nothing was sent downstream
+        if !e.retry()
+            && replayable
+            && session.as_ref().response_written().is_none()
+            && (self.retry(ctx, RetryOn::BadGateway) || self.fall_back(ctx))
+        {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        // Routed in request_filter, which answers requests no service matches
+        let route = ctx
+            .route
+            .clone()
+            .ok_or_else(|| LbError::Discovery("no service for path".to_string()))?;
+        // Accounts with dedicated backends for the service only use those
+        let dedicated = if route.dedicated.is_empty() {
+            None
+        } else {
+            ctx.api_key
+                .as_deref()
+                .and_then(|api_key| self.limiter.pool_for_key(api_key))
+                .and_then(|name| route.dedicated.get(&name).map(|pool| (name, pool)))
+        };
+        // Other keys may fall in a traffic group, such as a canary
+        let group = match dedicated {
+            Some(_) => None,
+            None => ctx
+                .key_fingerprint
+                .as_deref()
+                .and_then(|fingerprint| group_for_key(&route.groups, fingerprint)),
+        };
+        let group_name = group.map(|group| group.name.clone());
+        let (pool, dedicated) = match (dedicated, group) {
+            (Some((name, pool)), _) => (pool.clone(), Some(name)),
+            (None, Some(group)) => (group.pool.clone(), None),
+            (None, None) => (
+                route.pool.clone().ok_or_else(|| {
+                    LbError::Discovery(format!("no backend for service {}", route.service))
+                })?,
+                None,
+            ),
+        };
+        // The fallback backends serve requests the others failed, and all of them while
+        // every other endpoint is ejected
+        let pool = match &route.fallback {
+            Some(fallback) if ctx.on_fallback => fallback.clone(),
+            Some(fallback) if pool.is_down(Instant::now()) => {
+                self.fall_back(ctx);
+                fallback.clone()
+            }
+            _ => pool,
+        };
+
+        let budget = self.upstream_budget(ctx)?;
+
+        // A retry after a failed connection picks again
+        if let (Some((previous, _)), Some(addr)) = (ctx.upstream_pick.take(), &ctx.upstream) {
+            previous.report(addr, None, Instant::now());
+        }
+        let (endpoint, fallback) = match ctx.upstream_fallback.take() {
+            Some(endpoint) => {
+                pool.pick(&endpoint);
+                ctx.fell_back = true;
+                ctx.trace("happy_eyeballs", || format!("fallback={}", endpoint.addr));
+                (endpoint, None)
             }
-            Backend::Hetzner { .. } => Err(Error::explain(
-                ErrorType::HTTPStatus(501),
-                "Hetzner backend not implemented yet",
-            )),
+            None => {
+                let key = hash_key(
+                    pool.settings(),
+                    session.req_header(),
+                    ctx.api_key.as_deref(),
+                );
+                let selected = if ctx.tried.is_empty() {
+                    pool.select_for(key)
+                } else {
+                    pool.select_retry(key, &ctx.tried)
+                };
+                let endpoint = selected.ok_or_else(|| {
+                    LbError::Discovery(format!(
+                        "no upstream available for service {}",
+                        route.service
+                    ))
+                })?;
+                let fallback = (!ctx.fell_back)
+                    .then(|| pool.fallback_for(&endpoint.addr))
+                    .flatten();
+                (endpoint, fallback)
+            }
+        };
+        if let Some(name) = dedicated {
+            ctx.trace("dedicated_pool", || format!("pool={name}"));
         }
+        if let Some(name) = group_name {
+            ctx.trace("traffic_group", || format!("group={name}"));
+        }
+        ctx.trace("upstream", || {
+            format!("addr={} budget={budget:?}", endpoint.addr)
+        });
+        ctx.upstream = Some(endpoint.addr.clone());
+        ctx.upstream_pick = Some((pool.clone(), Instant::now()));
+        ctx.upstream_recycling = pool.recycling_for(&endpoint.addr);
+        ctx.upstream_retry = pool.retry_for(&endpoint.addr);
+        ctx.tried.push(endpoint.addr.clone());
+        ctx.upstream_tls = pool.tls_for(&endpoint.addr);
+        let egress = pool.egress_for(&endpoint.addr);
+        let timeouts = pool.timeouts_for(&endpoint.addr);
+        let mut peer = match &ctx.upstream_tls {
+            Some(tls) => tls.peer(endpoint.addr),
+            None => HttpPeer::new(
+                endpoint.addr,
+                false, // plain HTTP to the upstream
+                String::new(),
+            ),
+        };
+        // The backend's timeouts, cut short by what is left of the client's budget
+        let within_budget = |timeout: Option<Duration>| match (timeout, budget) {
+            (Some(timeout), Some(budget)) => Some(timeout.min(budget)),
+            (timeout, budget) => timeout.or(budget),
+        };
+        peer.options.connection_timeout = timeouts.connect;
+        peer.options.total_connection_timeout = budget;
+        peer.options.read_timeout = within_budget(timeouts.read);
+        peer.options.write_timeout = within_budget(timeouts.write);
+        // A host with both address families gets a short connect attempt on the first one
+        if let Some((fallback, delay)) = fallback {
+            peer.options.connection_timeout =
+                Some(timeouts.connect.map_or(delay, |connect| connect.min(delay)));
+            ctx.upstream_fallback = Some(fallback);
+        }
+        if let Some(egress) = egress {
+            peer.options.custom_l4 = Some(egress);
+        }
+        Ok(Box::new(peer))
     }
 }
 
@@ -238,4 +1976,75 @@ mod tests {
         assert!(Arc::ptr_eq(&r1, &r2));
         assert!(!Arc::ptr_eq(&r1, &r3));
     }
+
+    #[test]
+    fn route_miss_prefix_is_first_segment() {
+        assert_eq!(route_miss_prefix("/wp-admin/login.php"), "/wp-admin");
+        assert_eq!(route_miss_prefix("/favicon.ico"), "/favicon.ico");
+        assert_eq!(route_miss_prefix("/"), "/");
+        let long = format!("/{}", "é".repeat(100));
+        let prefix = route_miss_prefix(&long);
+        assert!(prefix.len() <= MAX_ROUTE_MISS_PREFIX_LEN);
+        assert!(long.starts_with(prefix));
+    }
+
+    #[test]
+    fn client_abort_requires_downstream_source() {
+        let closed = Error::new(ErrorType::ConnectionClosed).into_down();
+        assert!(is_client_abort(&closed));
+
+        let upstream_closed = Error::new(ErrorType::ConnectionClosed).into_up();
+        assert!(!is_client_abort(&upstream_closed));
+
+        let bad_request = Error::new(ErrorType::InvalidHTTPHeader).into_down();
+        assert!(!is_client_abort(&bad_request));
+    }
+
+    #[test]
+    fn timeouts_are_upstream_or_budget_failures() {
+        assert!(is_timeout(&Error::new(ErrorType::ReadTimedout).into_up()));
+        assert!(is_timeout(&Error::new(ErrorType::ConnectTimedout)));
+        let exhausted: Box<Error> = LbError::Upstream("budget exhausted".to_string()).into();
+        assert!(is_timeout(&exhausted));
+
+        assert!(!is_timeout(
+            &Error::new(ErrorType::ReadTimedout).into_down()
+        ));
+        assert!(!is_timeout(&Error::new(ErrorType::ConnectRefused)));
+    }
+
+    #[test]
+    fn header_limits_reject_long_uri_and_large_headers() {
+        let listener = ListenerConfig {
+            max_header_bytes: Some(64),
+            max_header_count: Some(2),
+            max_uri_length: Some(16),
+            ..Default::default()
+        };
+
+        let ok = RequestHeader::build("GET", b"/short", None).unwrap();
+        assert_eq!(check_header_limits(&ok, &listener), None);
+
+        let long_uri = RequestHeader::build("GET", b"/a/very/long/path?q=1", None).unwrap();
+        assert_eq!(
+            check_header_limits(&long_uri, &listener).map(|(s, _)| s),
+            Some(414)
+        );
+
+        let mut many = RequestHeader::build("GET", b"/", None).unwrap();
+        many.insert_header("a", "1").unwrap();
+        many.insert_header("b", "2").unwrap();
+        many.insert_header("c", "3").unwrap();
+        assert_eq!(
+            check_header_limits(&many, &listener),
+            Some((431, "requests_rejected_header_count"))
+        );
+
+        let mut big = RequestHeader::build("GET", b"/", None).unwrap();
+        big.insert_header("x-big", "v".repeat(100)).unwrap();
+        assert_eq!(
+            check_header_limits(&big, &listener),
+            Some((431, "requests_rejected_header_bytes"))
+        );
+    }
 }
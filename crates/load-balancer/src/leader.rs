@@ -0,0 +1,315 @@
+//! Leader election for jobs that must run on one node only.
+//!
+//! With a `leader` section every node competes for a lease row in a SQLite database all
+//! nodes share. The holder renews the lease every third of `ttl_secs`; when it stops (it
+//! crashed, or lost the shared DB) another node takes the lease over once it has expired.
+//! Singleton background jobs, such as ChangeLog compaction, only do their work while
+//! [`LeaderElection::is_leader`] holds.
+//!
+//! Nodes are told apart by their node id, which must therefore be unique.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::compact_changelog;
+use crate::clock::{Clock, SystemClock};
+use crate::sqlite;
+use crate::sync::MutexExt;
+
+/// Lease held by the node running the singleton background jobs.
+pub const JOBS_LEASE: &str = "background-jobs";
+
+/// Election of the node running singleton background jobs.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LeaderConfig {
+    /// SQLite database holding the lease, on storage every node can write.
+    pub db: String,
+    /// Lease lifetime; a leader that stops renewing is replaced after this long.
+    pub ttl_secs: u64,
+    /// ChangeLog entries of the accounts DB older than this are deleted by the leader;
+    /// kept forever when unset.
+    pub changelog_retention_secs: Option<u64>,
+}
+
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        Self {
+            db: "leases.db".to_string(),
+            ttl_secs: 15,
+            changelog_retention_secs: None,
+        }
+    }
+}
+
+impl LeaderConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ttl_secs < 3 {
+            return Err("leader.ttl_secs must be at least 3".to_string());
+        }
+        if self.changelog_retention_secs == Some(0) {
+            return Err("leader.changelog_retention_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Lease-based election among the nodes sharing a lease DB.
+pub struct LeaderElection {
+    db_path: PathBuf,
+    node: String,
+    ttl: Duration,
+    leader: AtomicBool,
+    conn: Mutex<Option<Connection>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl LeaderElection {
+    pub fn new(db_path: impl Into<PathBuf>, node: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            db_path: db_path.into(),
+            node: node.into(),
+            ttl,
+            leader: AtomicBool::new(false),
+            conn: Mutex::new(None),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use `clock` for lease expiry instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether this node held the lease at the last renewal.
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Run `f` on the lease DB connection, opening it and creating the table if needed.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, rusqlite::Error>,
+    ) -> Result<T, rusqlite::Error> {
+        let mut guard = self.conn.lock_or_recover();
+        let conn = match guard.take() {
+            Some(conn) => conn,
+            None => {
+                // The default rollback journal, unlike WAL, works on network file systems
+                let conn = Connection::open(&self.db_path)?;
+                conn.busy_timeout(sqlite::BUSY_TIMEOUT)?;
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS Leases (
+                        name TEXT PRIMARY KEY,
+                        holder TEXT NOT NULL,
+                        expires_at INTEGER NOT NULL
+                    )",
+                )?;
+                conn
+            }
+        };
+        let result = f(&conn);
+        if result.is_ok() {
+            *guard = Some(conn);
+        }
+        result
+    }
+
+    /// Take or renew the lease; whether this node holds it now.
+    pub fn renew(&self) -> Result<bool, rusqlite::Error> {
+        let now = self.clock.unix_secs();
+        let expires_at = now + self.ttl.as_secs() as i64;
+        let result = self.with_connection(|conn| {
+            conn.execute(
+                "INSERT INTO Leases (name, holder, expires_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(name) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+                 WHERE Leases.holder = excluded.holder OR Leases.expires_at <= ?4",
+                rusqlite::params![JOBS_LEASE, self.node, expires_at, now],
+            )
+            .map(|changed| changed > 0)
+        });
+        // A leader that cannot reach the lease DB steps down rather than risk two leaders
+        let leader = *result.as_ref().unwrap_or(&false);
+        let was_leader = self.leader.swap(leader, Ordering::Relaxed);
+        if leader && !was_leader {
+            log::info!("Node {} is now the leader for background jobs", self.node);
+        } else if !leader && was_leader {
+            log::warn!(
+                "Node {} is no longer the leader for background jobs",
+                self.node
+            );
+        }
+        result
+    }
+
+    /// Give the lease up, so another node takes over without waiting for it to expire.
+    pub fn release(&self) -> Result<(), rusqlite::Error> {
+        if !self.leader.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.with_connection(|conn| {
+            conn.execute(
+                "DELETE FROM Leases WHERE name = ?1 AND holder = ?2",
+                rusqlite::params![JOBS_LEASE, self.node],
+            )
+            .map(|_| ())
+        })
+    }
+}
+
+#[async_trait]
+impl BackgroundService for LeaderElection {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        let interval = (self.ttl / 3).max(Duration::from_secs(1));
+        loop {
+            if let Err(e) = self.renew() {
+                log::error!("Failed to renew leader lease in {:?}: {}", self.db_path, e);
+            }
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+        if let Err(e) = self.release() {
+            log::error!("Failed to release leader lease: {}", e);
+        }
+    }
+}
+
+/// Deletes old ChangeLog entries from the accounts DB while this node is the leader.
+pub struct ChangeLogCompactor {
+    accounts_db: PathBuf,
+    retention: Duration,
+    leader: Arc<LeaderElection>,
+}
+
+impl ChangeLogCompactor {
+    pub fn new(
+        accounts_db: impl AsRef<Path>,
+        retention: Duration,
+        leader: Arc<LeaderElection>,
+    ) -> Self {
+        Self {
+            accounts_db: accounts_db.as_ref().to_path_buf(),
+            retention,
+            leader,
+        }
+    }
+
+    /// Compact if this node is the leader; the number of entries deleted.
+    pub fn run_once(&self) -> Result<usize, rusqlite::Error> {
+        if !self.leader.is_leader() {
+            return Ok(0);
+        }
+        let before = self.leader.clock.unix_secs() - self.retention.as_secs() as i64;
+        compact_changelog(&self.accounts_db, before)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ChangeLogCompactor {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        let interval = self
+            .retention
+            .clamp(Duration::from_secs(60), Duration::from_secs(3600));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(interval) => {}
+            }
+            match self.run_once() {
+                Ok(0) => {}
+                Ok(deleted) => log::info!("Compacted {deleted} ChangeLog entries"),
+                Err(e) => log::error!("Failed to compact ChangeLog: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    fn election(path: &Path, node: &str, clock: &Arc<TestClock>) -> LeaderElection {
+        LeaderElection::new(path, node, Duration::from_secs(15)).with_clock(clock.clone())
+    }
+
+    #[test]
+    fn test_one_leader_until_the_lease_expires() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("leases.db");
+        let clock = Arc::new(TestClock::at_unix(1_000));
+        let a = election(&path, "lb-a", &clock);
+        let b = election(&path, "lb-b", &clock);
+
+        assert!(a.renew().unwrap());
+        assert!(!b.renew().unwrap());
+        clock.advance(Duration::from_secs(10));
+        assert!(a.renew().unwrap());
+        assert!(!b.renew().unwrap());
+
+        // The leader stops renewing
+        clock.advance(Duration::from_secs(15));
+        assert!(b.renew().unwrap());
+        assert!(b.is_leader());
+        assert!(!a.renew().unwrap());
+        assert!(!a.is_leader());
+
+        // A released lease is free at once
+        b.release().unwrap();
+        assert!(!b.is_leader());
+        assert!(a.renew().unwrap());
+    }
+
+    #[test]
+    fn test_changelog_is_compacted_by_the_leader_only() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let accounts_db = dir.path().join("accounts.db");
+        let conn = Connection::open(&accounts_db).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE ChangeLog (
+                change_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                table_name TEXT NOT NULL,
+                record_id INTEGER NOT NULL,
+                operation TEXT NOT NULL,
+                occurred_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO ChangeLog (table_name, record_id, operation, occurred_at) VALUES
+                ('Plans', 1, 'INSERT', datetime(100, 'unixepoch')),
+                ('Plans', 1, 'UPDATE', datetime(5000, 'unixepoch'));",
+        )
+        .unwrap();
+
+        let clock = Arc::new(TestClock::at_unix(5_100));
+        let leader = Arc::new(election(&dir.path().join("leases.db"), "lb-a", &clock));
+        let compactor =
+            ChangeLogCompactor::new(&accounts_db, Duration::from_secs(1000), leader.clone());
+        assert_eq!(compactor.run_once().unwrap(), 0);
+
+        leader.renew().unwrap();
+        assert_eq!(compactor.run_once().unwrap(), 1);
+        let left: i64 = conn
+            .query_row("SELECT COUNT(*) FROM ChangeLog", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 1);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(LeaderConfig::default().validate().is_ok());
+        let short = LeaderConfig {
+            ttl_secs: 1,
+            ..Default::default()
+        };
+        assert!(short.validate().is_err());
+    }
+}
@@ -1,6 +1,60 @@
 pub mod accounts;
+pub mod admin;
+pub mod admission;
+pub mod alert;
+pub mod anomaly;
+pub mod audit;
+pub mod billing;
+pub mod burst;
+pub mod canary;
+pub mod clock;
 pub mod configuration;
+pub mod connection;
+pub mod control;
+pub mod deadline;
+pub mod degradation;
+pub mod egress;
+pub mod error;
+pub mod export;
+pub mod fallback;
+pub mod gossip;
+pub mod grpc;
+pub mod hetzner;
+pub mod history;
+pub mod hooks;
+pub mod integrity;
+pub mod keys;
 pub mod lb;
+pub mod leader;
+pub mod limiter_state;
+pub mod logging;
 pub mod metric;
+pub mod openapi;
+pub mod privacy;
+pub mod readiness;
+pub mod recorder;
+pub mod reload;
+pub mod replay;
+pub mod residency;
+pub mod retention;
+pub mod routing;
+pub mod selection;
+pub mod selector;
 pub mod server;
+pub mod shadow;
+pub mod shedding;
+pub mod shutdown;
+pub mod sqlite;
+pub mod static_cache;
+pub mod sync;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tagging;
+pub mod tenant;
+pub mod tls;
+pub mod top;
+pub mod trace;
+pub mod upstream;
 pub mod usage;
+pub mod wasm_hooks;
+pub mod xds;
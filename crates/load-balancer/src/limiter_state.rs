@@ -0,0 +1,323 @@
+//! Rate limit state kept across restarts.
+//!
+//! Rate counters and burst credits live in memory, so a restarted LB would hand every key
+//! a full quota in the middle of its window. With `limiter_state` set to a file, the LB
+//! writes the requests each key made in its current window, and its burst credits, to the
+//! file when it shuts down and reads them back at startup:
+//!
+//! ```yaml
+//! limiter_state: /var/lib/lb/limiter-state.json
+//! ```
+//!
+//! Counts are coarse: they are kept per window of the key's limit aligned to the Unix
+//! epoch, and a count restored into the window it was saved in is added to the key's
+//! counter by its first request after the restart. Counts of windows that ended in the
+//! meantime are dropped. As the in-memory counters start their windows at startup, a
+//! restored key may be held to the saved count for up to one window longer than without
+//! the restart, never shorter. Burst credits are restored as they were.
+//!
+//! Keys are saved by their hash, not in clear.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::hash_api_key;
+use crate::burst::{BurstBucket, BurstCredits};
+use crate::sync::MutexExt;
+
+/// Keys counted before windows that ended are pruned.
+const PRUNE_AT: usize = 1024;
+
+/// Requests of a key in one window.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct SavedCount {
+    key_hash: String,
+    window_secs: u64,
+    /// Index of the window (`unix secs / window_secs`).
+    window: u64,
+    count: isize,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct SavedState {
+    saved_at: u64,
+    counts: Vec<SavedCount>,
+    burst: HashMap<String, BurstBucket>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// Window and requests by key and window length.
+    by_key: HashMap<(String, u64), (u64, isize)>,
+    prune_at: usize,
+}
+
+#[derive(Debug, Default)]
+struct Restored {
+    /// Window and requests by key hash and window length.
+    counts: HashMap<(String, u64), (u64, isize)>,
+    /// Unix time the last window of `counts` ends.
+    counts_until: u64,
+    burst: HashMap<String, BurstBucket>,
+}
+
+/// Per-key limiter state saved on exit and restored at startup.
+#[derive(Debug)]
+pub struct LimiterState {
+    path: PathBuf,
+    counts: Mutex<Counts>,
+    /// Saved state not yet claimed by a request of its key.
+    restored: Mutex<Restored>,
+    /// Whether `restored` may hold anything, sparing requests the key hash otherwise.
+    pending: AtomicBool,
+    burst: Arc<BurstCredits>,
+}
+
+impl LimiterState {
+    /// State saved to `path`, restoring what it holds for the windows around `now`. A
+    /// missing or unreadable file starts empty.
+    pub fn load(path: impl Into<PathBuf>, now: u64) -> Self {
+        let path = path.into();
+        let saved = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<SavedState>(&data).unwrap_or_else(|e| {
+                log::warn!("Ignoring limiter state {}: {e}", path.display());
+                SavedState::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SavedState::default(),
+            Err(e) => {
+                log::warn!("Failed to read limiter state {}: {e}", path.display());
+                SavedState::default()
+            }
+        };
+        let counts: HashMap<(String, u64), (u64, isize)> = saved
+            .counts
+            .into_iter()
+            .filter(|c| c.window == now / c.window_secs.max(1))
+            .map(|c| ((c.key_hash, c.window_secs), (c.window, c.count)))
+            .collect();
+        if !counts.is_empty() || !saved.burst.is_empty() {
+            log::info!(
+                "Restored limiter state of {} counters and {} burst balances from {}",
+                counts.len(),
+                saved.burst.len(),
+                path.display()
+            );
+        }
+        let counts_until = counts
+            .iter()
+            .map(|((_, secs), (window, _))| (window + 1) * (*secs).max(1))
+            .max()
+            .unwrap_or(0);
+        let pending = !counts.is_empty() || !saved.burst.is_empty();
+        Self {
+            path,
+            counts: Mutex::new(Counts {
+                by_key: HashMap::new(),
+                prune_at: PRUNE_AT,
+            }),
+            restored: Mutex::new(Restored {
+                counts,
+                counts_until,
+                burst: saved.burst,
+            }),
+            pending: AtomicBool::new(pending),
+            burst: Arc::new(BurstCredits::new()),
+        }
+    }
+
+    /// Burst credits saved and restored with the counters.
+    pub fn burst(&self) -> Arc<BurstCredits> {
+        self.burst.clone()
+    }
+
+    /// Count a request of `api_key` against its window of `window_secs` containing `now`.
+    /// Returns the requests restored for the key in that window, which the caller adds to
+    /// its counter; they are returned once.
+    pub fn record(&self, api_key: &str, window_secs: u64, now: u64) -> isize {
+        let window_secs = window_secs.max(1);
+        let window = now / window_secs;
+        let mut carried = 0;
+        if self.pending.load(Ordering::Relaxed) {
+            let mut restored = self.restored.lock_or_recover();
+            let key = (hash_api_key(api_key), window_secs);
+            if let Some((saved_window, count)) = restored.counts.remove(&key)
+                && saved_window == window
+            {
+                carried = count;
+            }
+            // Counts of windows that ended can no longer be claimed
+            if now >= restored.counts_until {
+                restored.counts.clear();
+            }
+            if restored.counts.is_empty() && restored.burst.is_empty() {
+                self.pending.store(false, Ordering::Relaxed);
+            }
+        }
+
+        let mut counts = self.counts.lock_or_recover();
+        if counts.by_key.len() >= counts.prune_at {
+            counts
+                .by_key
+                .retain(|(_, secs), (counted, _)| *counted == now / secs);
+            counts.prune_at = (counts.by_key.len() * 2).max(PRUNE_AT);
+        }
+        let entry = counts
+            .by_key
+            .entry((api_key.to_string(), window_secs))
+            .or_insert((window, 0));
+        if entry.0 != window {
+            *entry = (window, 0);
+        }
+        entry.1 += 1 + carried;
+        carried
+    }
+
+    /// Restore the saved burst credits of `api_key` into [`LimiterState::burst`], on the
+    /// key's first request after the restart.
+    pub fn restore_burst(&self, api_key: &str) {
+        if !self.pending.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut restored = self.restored.lock_or_recover();
+        if let Some(bucket) = restored.burst.remove(&hash_api_key(api_key)) {
+            self.burst.restore(api_key, bucket);
+        }
+        if restored.counts.is_empty() && restored.burst.is_empty() {
+            self.pending.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Write the counters of the window containing `now` and the burst credits to the
+    /// state file, including what was restored and not claimed since.
+    pub fn save(&self, now: u64) -> io::Result<()> {
+        let current = |window_secs: u64, window: u64| window == now / window_secs.max(1);
+        let restored = self.restored.lock_or_recover();
+        let mut state = SavedState {
+            saved_at: now,
+            counts: restored
+                .counts
+                .iter()
+                .filter(|((_, secs), (window, _))| current(*secs, *window))
+                .map(|((key_hash, secs), (window, count))| SavedCount {
+                    key_hash: key_hash.clone(),
+                    window_secs: *secs,
+                    window: *window,
+                    count: *count,
+                })
+                .collect(),
+            burst: restored.burst.clone(),
+        };
+        drop(restored);
+        state.counts.extend(
+            self.counts
+                .lock_or_recover()
+                .by_key
+                .iter()
+                .filter(|((_, secs), (window, _))| current(*secs, *window))
+                .map(|((key, secs), (window, count))| SavedCount {
+                    key_hash: hash_api_key(key),
+                    window_secs: *secs,
+                    window: *window,
+                    count: *count,
+                }),
+        );
+        state.burst.extend(
+            self.burst
+                .export()
+                .into_iter()
+                .map(|(key, bucket)| (hash_api_key(&key), bucket)),
+        );
+
+        write_atomically(&self.path, &serde_json::to_vec(&state)?)?;
+        log::info!(
+            "Saved limiter state of {} counters and {} burst balances to {}",
+            state.counts.len(),
+            state.burst.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Write `data` next to `path` and rename it into place, so a crash never leaves a
+/// truncated file behind.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounts::{BurstPolicy, Limit, LimitSource};
+
+    use super::*;
+
+    #[test]
+    fn test_counts_survive_a_restart_within_their_window() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("limiter-state.json");
+        let state = LimiterState::load(&path, 600);
+        for _ in 0..7 {
+            assert_eq!(state.record("key", 60, 610), 0);
+        }
+        state.record("other", 60, 610);
+        state.record("old", 60, 540);
+        state.save(615).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("\"key\""));
+        assert!(!saved.contains(&hash_api_key("old")));
+
+        // The first request after the restart carries the saved count, once
+        let restarted = LimiterState::load(&path, 620);
+        assert_eq!(restarted.record("key", 60, 620), 7);
+        assert_eq!(restarted.record("key", 60, 621), 0);
+        // Other window lengths are counted apart
+        assert_eq!(restarted.record("other", 1, 621), 0);
+        // Unclaimed counts are saved again
+        restarted.save(625).unwrap();
+        let again = LimiterState::load(&path, 630);
+        assert_eq!(again.record("key", 60, 630), 9);
+        assert_eq!(again.record("other", 60, 630), 1);
+
+        // Windows that ended meanwhile are dropped
+        let later = LimiterState::load(&path, 660);
+        assert_eq!(later.record("key", 60, 660), 0);
+    }
+
+    #[test]
+    fn test_burst_credits_are_restored() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("limiter-state.json");
+        let limit = Limit {
+            quota: 2,
+            per_seconds: 1,
+            burst: Some(BurstPolicy {
+                cap: 10,
+                accrual_rate: 1.0,
+            }),
+            source: LimitSource::Plan,
+        };
+        let state = LimiterState::load(&path, 0);
+        state.burst().allow("key", &limit, 0);
+        state.burst().allow("key", &limit, 4);
+        assert_eq!(state.burst().credits("key"), 7.0);
+        state.save(4).unwrap();
+
+        let restarted = LimiterState::load(&path, 5);
+        assert_eq!(restarted.burst().credits("key"), 0.0);
+        restarted.restore_burst("key");
+        assert_eq!(restarted.burst().credits("key"), 7.0);
+
+        // A missing file starts empty
+        let empty = LimiterState::load(dir.path().join("missing.json"), 5);
+        assert!(!empty.pending.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,297 @@
+//! Logging subsystem.
+//!
+//! Built on `tracing` with the `log` facade bridged in, so existing `log::` call sites (and
+//! pingora's) keep working. Records are routed by target into three streams:
+//!
+//! - `access`: one line per proxied request,
+//! - `audit`: administrative actions (reloads, level changes),
+//! - everything else goes to the application log.
+//!
+//! Without a log directory all streams go to stderr. With one, each stream is written to its
+//! own file (`app.log`, `access.log`, `audit.log`) and rotated by size and/or time. The
+//! application log level can be changed at runtime through [`LogHandle`].
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing_subscriber::filter::{EnvFilter, filter_fn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry, reload};
+
+use crate::configuration::{LoggingConfig, Rotation};
+use crate::sync::MutexExt;
+
+/// Target for per-request access log lines.
+pub const ACCESS_TARGET: &str = "access";
+/// Target for administrative audit log lines.
+pub const AUDIT_TARGET: &str = "audit";
+
+#[derive(Debug)]
+struct FileState {
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+/// Append-only log file rotated by size and/or time.
+///
+/// Rotated files are renamed `<name>.1`, `<name>.2`, ... with `.1` the most recent.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    max_bytes: Option<u64>,
+    max_files: usize,
+    state: Mutex<FileState>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+impl RotatingFile {
+    /// Open (or create) `path` for appending.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        rotation: Rotation,
+        max_bytes: Option<u64>,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let (file, size) = open_append(&path)?;
+        Ok(Self {
+            state: Mutex::new(FileState {
+                file,
+                size,
+                period: rotation.period(now_secs()),
+            }),
+            path,
+            rotation,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    /// Reopen the file at its path, e.g. after an external tool moved it away.
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut state = self.state.lock_or_recover();
+        let (file, size) = open_append(&self.path)?;
+        state.file = file;
+        state.size = size;
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], now: u64) -> io::Result<usize> {
+        let mut state = self.state.lock_or_recover();
+        let period = self.rotation.period(now);
+        let too_big = self
+            .max_bytes
+            .is_some_and(|max| state.size > 0 && state.size + buf.len() as u64 > max);
+        if period != state.period || too_big {
+            self.rotate(&mut state)?;
+            state.period = period;
+        }
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self, state: &mut FileState) -> io::Result<()> {
+        state.file.flush()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path).ok();
+        } else {
+            std::fs::remove_file(self.rotated_path(self.max_files)).ok();
+            for n in (1..self.max_files).rev() {
+                std::fs::rename(self.rotated_path(n), self.rotated_path(n + 1)).ok();
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        let (file, size) = open_append(&self.path)?;
+        state.file = file;
+        state.size = size;
+        Ok(())
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, now_secs())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock_or_recover().file.flush()
+    }
+}
+
+/// Runtime control over the installed logging subsystem.
+#[derive(Clone)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    files: Vec<Arc<RotatingFile>>,
+}
+
+impl LogHandle {
+    /// Replace the application log filter.
+    pub fn set_level(&self, filter: &str) -> Result<(), String> {
+        let new_filter = EnvFilter::try_new(filter).map_err(|e| e.to_string())?;
+        self.filter.reload(new_filter).map_err(|e| e.to_string())?;
+        tracing::info!(target: AUDIT_TARGET, "log level changed to {filter}");
+        Ok(())
+    }
+
+    /// Reopen every log file (for logrotate).
+    pub fn reopen(&self) -> io::Result<()> {
+        for file in &self.files {
+            file.reopen()?;
+        }
+        Ok(())
+    }
+}
+
+fn file_writer(config: &LoggingConfig, dir: &Path, name: &str) -> io::Result<Arc<RotatingFile>> {
+    RotatingFile::open(
+        dir.join(name),
+        config.rotation,
+        config.max_size_mb.map(|mb| mb * 1024 * 1024),
+        config.max_files,
+    )
+    .map(Arc::new)
+}
+
+/// Install the global logger. Call once, before any log output.
+pub fn init(config: &LoggingConfig, base_path: &Path) -> io::Result<LogHandle> {
+    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| config.level.clone());
+    let app_filter = EnvFilter::try_new(&level).unwrap_or_else(|e| {
+        eprintln!("invalid log level {level:?} ({e}), using info");
+        EnvFilter::new("info")
+    });
+    let (app_filter, filter) = reload::Layer::new(app_filter);
+
+    let mut files = Vec::new();
+    let mut writer = |name: &str| -> io::Result<BoxMakeWriter> {
+        match &config.dir {
+            Some(dir) => {
+                let file = file_writer(config, &base_path.join(dir), name)?;
+                files.push(file.clone());
+                Ok(BoxMakeWriter::new(file))
+            }
+            None => Ok(BoxMakeWriter::new(io::stderr)),
+        }
+    };
+    let to_file = config.dir.is_some();
+
+    let layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer("app.log")?)
+            .with_ansi(!to_file)
+            .with_filter(app_filter)
+            .with_filter(filter_fn(|meta| {
+                meta.target() != ACCESS_TARGET && meta.target() != AUDIT_TARGET
+            }))
+            .boxed(),
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer("access.log")?)
+            .with_ansi(!to_file)
+            .with_filter(filter_fn(|meta| meta.target() == ACCESS_TARGET))
+            .boxed(),
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer("audit.log")?)
+            .with_ansi(!to_file)
+            .with_filter(filter_fn(|meta| meta.target() == AUDIT_TARGET))
+            .boxed(),
+    ];
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .map_err(io::Error::other)?;
+
+    Ok(LogHandle { filter, files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_line(file: &RotatingFile, line: &str, now: u64) {
+        file.write_at(line.as_bytes(), now).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_max_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("app.log");
+        let file = RotatingFile::open(&path, Rotation::Never, Some(10), 2).unwrap();
+
+        for line in ["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+            write_line(&file, line, 0);
+        }
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "dddddddd\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("app.log.1")).unwrap(),
+            "cccccccc\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("app.log.2")).unwrap(),
+            "bbbbbbbb\n"
+        );
+        assert!(!dir.path().join("app.log.3").exists());
+    }
+
+    #[test]
+    fn rotates_when_period_changes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("access.log");
+        let file = RotatingFile::open(&path, Rotation::Hourly, None, 3).unwrap();
+        let hour = now_secs() - now_secs() % 3600;
+
+        write_line(&file, "first\n", hour);
+        write_line(&file, "same hour\n", hour + 10);
+        write_line(&file, "next hour\n", hour + 3600);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "next hour\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("access.log.1")).unwrap(),
+            "first\nsame hour\n"
+        );
+    }
+
+    #[test]
+    fn reopen_follows_moved_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("audit.log");
+        let file = RotatingFile::open(&path, Rotation::Never, None, 1).unwrap();
+
+        write_line(&file, "before\n", 0);
+        std::fs::rename(&path, dir.path().join("audit.log.moved")).unwrap();
+        file.reopen().unwrap();
+        write_line(&file, "after\n", 0);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after\n");
+    }
+}
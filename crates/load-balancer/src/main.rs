@@ -1,49 +1,566 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use load_balancer::configuration::ServerConfig;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use load_balancer::accounts::{self, API_KEY_PREFIX, AccountLoader};
+use load_balancer::audit::{KeyAction, KeyAudit, KeyAuditEvent};
+use load_balancer::billing::{self, Month};
+use load_balancer::configuration::{Config, ServerConfig};
+use load_balancer::export::{self, ExportFormat};
+use load_balancer::keys;
+use load_balancer::logging;
 use load_balancer::metric::Metrics;
 use load_balancer::server::Server;
 use pingora::server::configuration::Opt;
+use uuid::Uuid;
 
-fn main() {
-    // Enable basic logging; set RUST_LOG=info for visibility.
-    env_logger::init();
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+/// API load balancer with per-account rate limiting and usage tracking.
+///
+/// Without a subcommand the server runs, as with `serve`.
+#[derive(Parser)]
+#[command(name = "lb", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the load balancer.
+    Serve(ServeArgs),
+    /// Validate the server and backend configs and load the accounts DBs, then exit.
+    CheckConfig(ConfArg),
+    /// Issue and revoke API keys.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Read the hourly usage files.
+    #[command(subcommand)]
+    Usage(UsageCommand),
+    /// Inspect accounts as the server loads them.
+    #[command(subcommand)]
+    Accounts(AccountsCommand),
+    /// Offline billing jobs over the usage files.
+    #[command(subcommand)]
+    Billing(BillingCommand),
+}
+
+/// Pingora's server options.
+#[derive(Args)]
+struct ServeArgs {
+    /// Take over the listeners of a running server.
+    #[arg(short, long)]
+    upgrade: bool,
+    /// Run in the background.
+    #[arg(short, long)]
+    daemon: bool,
+    /// Test the configuration and exit.
+    #[arg(short, long)]
+    test: bool,
+    /// Path to the configuration file.
+    #[arg(short, long)]
+    conf: Option<String>,
+    /// Accepted and ignored, for `cargo test` style invocations.
+    #[arg(long, hide = true)]
+    nocapture: bool,
+}
+
+impl ServeArgs {
+    fn into_opt(self) -> Opt {
+        Opt {
+            upgrade: self.upgrade,
+            daemon: self.daemon,
+            nocapture: self.nocapture,
+            test: self.test,
+            conf: self.conf,
+        }
+    }
+}
+
+#[derive(Args)]
+struct ConfArg {
+    /// Server config.
+    #[arg(short, long, default_value = "conf.yaml")]
+    conf: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Issue a key for an account and print its token, which is not stored.
+    Generate {
+        /// Account owning the key.
+        #[arg(long)]
+        account: i64,
+        /// Scopes granted to the key.
+        #[arg(long, value_delimiter = ',')]
+        scopes: Vec<String>,
+        /// Bind the key to the account's current plan, so that it must be issued again
+        /// after an upgrade or downgrade.
+        #[arg(long)]
+        bind_plan: bool,
+        /// Name or token prefix of the account partition; the main accounts DB when unset.
+        #[arg(long)]
+        partition: Option<String>,
+        /// Who issues the key, for the audit event; `$USER` when unset.
+        #[arg(long)]
+        actor: Option<String>,
+        #[command(flatten)]
+        conf: ConfArg,
+    },
+    /// Deactivate a key.
+    Revoke {
+        /// Key id (UUID).
+        key_id: Uuid,
+        /// Name or token prefix of the account partition; the main accounts DB when unset.
+        #[arg(long)]
+        partition: Option<String>,
+        /// Who revokes the key, for the audit event; `$USER` when unset.
+        #[arg(long)]
+        actor: Option<String>,
+        #[command(flatten)]
+        conf: ConfArg,
+    },
+}
+
+#[derive(Subcommand)]
+enum UsageCommand {
+    /// Merge the usage files covering a time range into one CSV or JSON lines stream.
+    Export {
+        /// Start of the range (inclusive): YYYY-MM-DD, YYYY-MM-DDTHH:MM (UTC) or RFC 3339.
+        #[arg(long, value_parser = export::parse_time)]
+        from: i64,
+        /// End of the range (exclusive), in the same formats.
+        #[arg(long, value_parser = export::parse_time)]
+        to: i64,
+        #[arg(long, value_enum, default_value_t = UsageFormat::Csv)]
+        format: UsageFormat,
+        /// Output file; records go to stdout when unset.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        conf: ConfArg,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UsageFormat {
+    Csv,
+    Jsonl,
+}
+
+#[derive(Subcommand)]
+enum AccountsCommand {
+    /// Print an account with its plan and active keys.
+    Show {
+        account_id: i64,
+        /// Name or token prefix of the account partition; the main accounts DB when unset.
+        #[arg(long)]
+        partition: Option<String>,
+        #[command(flatten)]
+        conf: ConfArg,
+    },
+    /// Add the columns an accounts DB created with an older schema lacks. The server only
+    /// reads the accounts DB, so run this before it loads one.
+    Migrate {
+        /// Name or token prefix of the account partition; the main accounts DB when unset.
+        #[arg(long)]
+        partition: Option<String>,
+        #[command(flatten)]
+        conf: ConfArg,
+    },
+}
+
+#[derive(Subcommand)]
+enum BillingCommand {
+    /// Compute invoice line items for a month.
+    Compute {
+        /// Month to bill, as YYYY-MM.
+        #[arg(long)]
+        month: String,
+        /// Output file; defaults to billing-YYYYMM.db (or .csv) in the usage directory.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = BillingFormat::Db)]
+        format: BillingFormat,
+        #[command(flatten)]
+        conf: ConfArg,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BillingFormat {
+    Db,
+    Csv,
+}
+
+/// Paths in a server config are relative to the directory of the config file.
+struct LoadedConf {
+    server: ServerConfig,
+    base: PathBuf,
+}
 
-    // Read command line arguments
-    let opt = Opt::parse_args();
+impl LoadedConf {
+    fn read(conf: &ConfArg) -> Result<Self, String> {
+        Ok(Self {
+            server: ServerConfig::load(&conf.conf)?,
+            base: conf.conf.parent().unwrap_or(Path::new(".")).to_path_buf(),
+        })
+    }
 
-    // Create new Server wrapper
-    let mut server = Server::new(Some(opt)).expect("Failed to create server");
+    fn resolve(&self, path: &str) -> PathBuf {
+        if Path::new(path).is_absolute() {
+            PathBuf::from(path)
+        } else {
+            self.base.join(path)
+        }
+    }
 
-    // We need to read the configuration file (passing the path if provided in Opt, but Opt might not expose the path directly in a way we can re-read easily if we want "our" fields)
-    // Pingora's Server::new loads the config into server.configuration.
-    // However, Pingora's ServerConf is unrelated to our ServerConfig struct.
-    // We assumed we have a single file with both.
-    // If we use Server::new(Some(opt)), Pingora reads the config file specified in -c/--conf.
-    // We need to read that SAME file to get our `backend` field.
+    fn usage_dir(&self) -> Result<PathBuf, String> {
+        self.server
+            .usage_dir
+            .as_deref()
+            .map(|dir| self.resolve(dir))
+            .ok_or_else(|| "usage_dir is not configured".to_string())
+    }
 
-    // Hack: Get the config path from args again or assume it was passed.
-    // Opt struct has `conf: Option<String>`.
-    let conf_path = Opt::parse_args()
-        .conf
-        .unwrap_or_else(|| "conf.yaml".to_string());
+    /// Accounts DB and token prefix of the main store, or of the partition (or tenant with
+    /// its own accounts DB) with the given name or token prefix.
+    fn accounts_db(&self, partition: Option<&str>) -> Result<(PathBuf, String), String> {
+        match partition {
+            None => Ok((
+                self.resolve(&self.server.accounts_db),
+                API_KEY_PREFIX.to_string(),
+            )),
+            Some(wanted) => self
+                .server
+                .partitions()
+                .into_iter()
+                .find(|p| p.name == wanted || p.token_prefix == wanted)
+                .map(|p| (self.resolve(&p.accounts_db), p.token_prefix.clone()))
+                .ok_or_else(|| format!("no account partition named {wanted}")),
+        }
+    }
+}
+
+fn serve(args: ServeArgs) {
+    let opt = args.into_opt();
+    let conf_path = opt.conf.clone().unwrap_or_else(|| "conf.yaml".to_string());
 
-    // Parse our part of the config
-    let conf_str = std::fs::read_to_string(&conf_path).expect("Failed to read config file");
-    let server_conf: ServerConfig =
-        serde_yaml::from_str(&conf_str).expect("Failed to parse server config");
+    let server_conf = ServerConfig::load(&conf_path).expect("Failed to load server config");
+
+    // Pingora reads its own settings from the same file, overridden by the runtime section
+    let mut server = Server::new(Some(opt), &server_conf.runtime).expect("Failed to create server");
 
     let conf_path_buf = std::path::Path::new(&conf_path);
     let config_base_path = conf_path_buf.parent().unwrap_or(std::path::Path::new("."));
 
+    // Logging is configured from the same file; RUST_LOG overrides the configured level.
+    let log_handle = logging::init(&server_conf.logging, config_base_path)
+        .expect("Failed to initialize logging");
+    server.set_log_handle(log_handle);
+
+    let listen = server_conf
+        .listener
+        .listen
+        .clone()
+        .unwrap_or_else(|| "0.0.0.0:8080".to_string());
     server
         .bootstrap(
             server_conf,
             config_base_path,
-            "0.0.0.0:8080",
+            &listen,
             Arc::new(Metrics::default()),
         )
         .expect("Failed to bootstrap server");
 
     server.run_forever();
 }
+
+fn check_config(conf: ConfArg) -> CliResult {
+    let loaded = LoadedConf::read(&conf)?;
+    loaded.server.runtime.validate()?;
+    if let Some(overload) = &loaded.server.overload {
+        overload.validate()?;
+    }
+    if let Some(gossip) = &loaded.server.gossip {
+        gossip.validate()?;
+    }
+    if let Some(leader) = &loaded.server.leader {
+        leader.validate()?;
+    }
+    if let Some(privacy) = &loaded.server.privacy {
+        privacy.validate()?;
+    }
+    if let Some(retention) = &loaded.server.usage_retention {
+        retention.validate()?;
+    }
+    if let Some(residency) = &loaded.server.usage_residency {
+        residency.validate()?;
+    }
+    if let Some(audit) = &loaded.server.key_audit {
+        audit.validate()?;
+    }
+    if let Some(history) = &loaded.server.config_history {
+        history.validate()?;
+    }
+    loaded.server.degradation.validate()?;
+    let config = Config::load(loaded.resolve(&loaded.server.backend))?;
+    println!(
+        "backend config: {} services, {} backends",
+        config.services.len(),
+        config.backends.len()
+    );
+
+    let mut stores = vec![("accounts".to_string(), loaded.accounts_db(None)?)];
+    for partition in &loaded.server.partitions() {
+        stores.push((
+            partition.name.clone(),
+            loaded.accounts_db(Some(&partition.name))?,
+        ));
+    }
+    for (name, (db, prefix)) in stores {
+        match AccountLoader::new(&db)
+            .with_token_prefix(prefix)
+            .load_initial()
+        {
+            Ok(store) => {
+                let (plans, accounts, keys) = store.counts();
+                println!("{name}: {plans} plans, {accounts} accounts, {keys} active keys");
+            }
+            // The server starts in degraded mode instead of failing
+            Err(e) if name == "accounts" && loaded.server.accounts_fallback.is_some() => {
+                println!(
+                    "{name}: cannot load {} ({e}), would start degraded",
+                    db.display()
+                );
+            }
+            Err(e) => return Err(format!("{name}: cannot load {}: {e}", db.display()).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Publish a key audit event to the sink of the config, if it has one.
+fn publish_key_audit(
+    loaded: &LoadedConf,
+    action: KeyAction,
+    actor: Option<String>,
+    account_id: i64,
+    key_id: Uuid,
+    fingerprint: String,
+) -> CliResult {
+    let Some(config) = &loaded.server.key_audit else {
+        return Ok(());
+    };
+    let actor = actor
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    let event = KeyAuditEvent::now(action, actor, account_id, key_id, fingerprint);
+    let audit = KeyAudit::new(config, &loaded.base);
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(audit.publish(&event))
+        .map_err(|e| format!("the key audit event was not published: {e}"))?;
+    Ok(())
+}
+
+fn run_keys(command: KeysCommand) -> CliResult {
+    match command {
+        KeysCommand::Generate {
+            account,
+            scopes,
+            bind_plan,
+            partition,
+            actor,
+            conf,
+        } => {
+            let loaded = LoadedConf::read(&conf)?;
+            if let Some(audit) = &loaded.server.key_audit {
+                audit.validate()?;
+            }
+            let (db, prefix) = loaded.accounts_db(partition.as_deref())?;
+            let issued = keys::issue_key(&db, &prefix, account, &scopes, bind_plan)?;
+            println!(
+                "{}",
+                serde_json::json!({
+                    "token": issued.token,
+                    "key_id": issued.api_key,
+                    "api_key_id": issued.api_key_id,
+                    "fingerprint": issued.fingerprint,
+                })
+            );
+            // The token is printed first: the key exists even if the event is lost
+            publish_key_audit(
+                &loaded,
+                KeyAction::Created,
+                actor,
+                account,
+                issued.api_key,
+                issued.fingerprint,
+            )?;
+        }
+        KeysCommand::Revoke {
+            key_id,
+            partition,
+            actor,
+            conf,
+        } => {
+            let loaded = LoadedConf::read(&conf)?;
+            if let Some(audit) = &loaded.server.key_audit {
+                audit.validate()?;
+            }
+            let (db, prefix) = loaded.accounts_db(partition.as_deref())?;
+            let account = keys::revoke_key(&db, key_id)?;
+            eprintln!("Revoked {key_id}");
+            publish_key_audit(
+                &loaded,
+                KeyAction::Revoked,
+                actor,
+                account,
+                key_id,
+                api_key::fingerprint(&prefix, key_id),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn run_usage(command: UsageCommand) -> CliResult {
+    let UsageCommand::Export {
+        from,
+        to,
+        format,
+        output,
+        conf,
+    } = command;
+
+    let usage_dir = LoadedConf::read(&conf)?.usage_dir()?;
+    let format = match format {
+        UsageFormat::Csv => ExportFormat::Csv,
+        UsageFormat::Jsonl => ExportFormat::Jsonl,
+    };
+    let written = match output {
+        Some(path) => {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            export::export(&usage_dir, from, to, format, &mut out)?
+        }
+        None => export::export(&usage_dir, from, to, format, &mut std::io::stdout().lock())?,
+    };
+    eprintln!("Exported {written} usage records");
+    Ok(())
+}
+
+fn run_accounts(command: AccountsCommand) -> CliResult {
+    match command {
+        AccountsCommand::Show {
+            account_id,
+            partition,
+            conf,
+        } => show_account(account_id, partition, conf),
+        AccountsCommand::Migrate { partition, conf } => {
+            let (db, _) = LoadedConf::read(&conf)?.accounts_db(partition.as_deref())?;
+            let applied = accounts::migrate_schema(&db)?;
+            eprintln!("Applied {applied} migrations to {}", db.display());
+            Ok(())
+        }
+    }
+}
+
+fn show_account(account_id: i64, partition: Option<String>, conf: ConfArg) -> CliResult {
+    let (db, prefix) = LoadedConf::read(&conf)?.accounts_db(partition.as_deref())?;
+    let store = AccountLoader::new(&db)
+        .with_token_prefix(prefix)
+        .load_initial()?;
+    let account = store
+        .account_summary(account_id)
+        .ok_or_else(|| format!("account {account_id} not found"))?;
+    let keys: Vec<_> = account
+        .keys
+        .iter()
+        .map(|key| {
+            serde_json::json!({
+                "key_id": key.api_key,
+                "fingerprint": key.fingerprint,
+                "created_at": key.created_at,
+                "last_used_at": key.last_used_at,
+                "scopes": key.scopes,
+            })
+        })
+        .collect();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&serde_json::json!({
+            "account_id": account.account_id,
+            "email": account.email,
+            "plan": account.plan,
+            "keys": keys,
+        }))?
+    );
+    Ok(())
+}
+
+fn run_billing(command: BillingCommand) -> CliResult {
+    let BillingCommand::Compute {
+        month,
+        output,
+        format,
+        conf,
+    } = command;
+
+    let month = Month::parse(&month)?;
+    let loaded = LoadedConf::read(&conf)?;
+    let usage_dir = loaded.usage_dir()?;
+    let (accounts_db, _) = loaded.accounts_db(None)?;
+
+    let run = billing::compute(&usage_dir, &accounts_db, month)?;
+    for file in &run.unclosed_files {
+        eprintln!("warning: {file} has no manifest; its hour may be incomplete");
+    }
+    let output = match format {
+        BillingFormat::Db => {
+            let path = output.unwrap_or_else(|| usage_dir.join(month.billing_filename()));
+            billing::write_db(&path, &run.line_items)?;
+            path
+        }
+        BillingFormat::Csv => {
+            let path = output
+                .unwrap_or_else(|| usage_dir.join(month.billing_filename().replace(".db", ".csv")));
+            billing::write_csv(&path, &run.line_items)?;
+            path
+        }
+    };
+    println!(
+        "Wrote {} line items from {} usage files to {}",
+        run.line_items.len(),
+        run.files,
+        output.display()
+    );
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        None => {
+            serve(cli.serve);
+            return;
+        }
+        Some(Command::Serve(args)) => {
+            serve(args);
+            return;
+        }
+        Some(Command::CheckConfig(conf)) => check_config(conf),
+        Some(Command::Keys(command)) => run_keys(command),
+        Some(Command::Usage(command)) => run_usage(command),
+        Some(Command::Accounts(command)) => run_accounts(command),
+        Some(Command::Billing(command)) => run_billing(command),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
@@ -1,10 +1,75 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-/// In-memory per-minute status counts keyed by API key.
-#[derive(Default)]
+use crate::clock::{Clock, SystemClock};
+use crate::sync::MutexExt;
+
+/// Status code counts keyed by minute bucket.
+pub type MinuteCounts = HashMap<u64, HashMap<u16, u64>>;
+
+/// Label value collecting the overflow of bounded labeled counters.
+pub const OTHER_LABEL: &str = "other";
+
+/// Upper bounds, in bytes, of the buckets of size histograms; larger sizes fall in `+Inf`.
+pub const SIZE_BUCKETS: [u64; 9] = [
+    256,
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+/// Distribution of observed sizes over [`SIZE_BUCKETS`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Observations per bucket, the last one past the largest bound.
+    pub buckets: [u64; SIZE_BUCKETS.len() + 1],
+    pub sum: u64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: u64) {
+        let bucket = SIZE_BUCKETS.partition_point(|bound| *bound < value);
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// In-memory per-minute status counts keyed by API key fingerprint.
 pub struct Metrics {
-    counts: std::sync::Mutex<HashMap<String, HashMap<u64, HashMap<u16, u64>>>>,
+    clock: Arc<dyn Clock>,
+    counts: std::sync::Mutex<HashMap<String, MinuteCounts>>,
+    /// Named event counters (rejections, limit hits, ...).
+    counters: std::sync::Mutex<HashMap<String, u64>>,
+    /// Named event counters broken down by a label such as the service name.
+    labeled: std::sync::Mutex<HashMap<String, HashMap<String, u64>>>,
+    /// Response bytes per key per minute bucket.
+    bytes: std::sync::Mutex<HashMap<String, HashMap<u64, u64>>>,
+    /// Named size histograms broken down by service.
+    histograms: std::sync::Mutex<HashMap<String, HashMap<String, Histogram>>>,
+    /// Named gauges broken down by a label.
+    gauges: std::sync::Mutex<HashMap<String, HashMap<String, i64>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            counts: Default::default(),
+            counters: Default::default(),
+            labeled: Default::default(),
+            bytes: Default::default(),
+            histograms: Default::default(),
+            gauges: Default::default(),
+        }
+    }
 }
 
 impl Metrics {
@@ -12,30 +77,266 @@ impl Metrics {
         Self::default()
     }
 
-    /// Record a status code occurrence using the current wall-clock time.
+    /// Bucket records by `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Current time of the metrics clock.
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Record a status code occurrence at the current time.
     pub fn record(&self, api_key: &str, status: u16) {
-        self.record_at(api_key, status, SystemTime::now());
+        self.record_at(api_key, status, self.clock.now());
     }
 
     /// Record a status code occurrence at a provided time (useful for tests).
     pub fn record_at(&self, api_key: &str, status: u16, at: SystemTime) {
         let minute = Self::minute_bucket(at);
-        let mut guard = self.counts.lock().expect("metrics store poisoned");
+        let mut guard = self.counts.lock_or_recover();
         let per_key = guard.entry(api_key.to_string()).or_default();
         let per_minute = per_key.entry(minute).or_default();
         *per_minute.entry(status).or_insert(0) += 1;
     }
 
+    /// Add response bytes for a key at the current time.
+    pub fn record_bytes(&self, api_key: &str, bytes: u64) {
+        self.record_bytes_at(api_key, bytes, self.clock.now());
+    }
+
+    /// Add response bytes for a key at a provided time.
+    pub fn record_bytes_at(&self, api_key: &str, bytes: u64, at: SystemTime) {
+        let minute = Self::minute_bucket(at);
+        let mut guard = self.bytes.lock_or_recover();
+        *guard
+            .entry(api_key.to_string())
+            .or_default()
+            .entry(minute)
+            .or_insert(0) += bytes;
+    }
+
+    /// Response bytes per minute bucket for a key. Empty when the key is unknown.
+    pub fn bytes_snapshot(&self, api_key: &str) -> HashMap<u64, u64> {
+        self.bytes
+            .lock_or_recover()
+            .get(api_key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Minute bucket of the current time.
+    pub fn current_minute(&self) -> u64 {
+        Self::minute_bucket(self.clock.now())
+    }
+
     /// Snapshot counts for a given API key. Returns an empty map when the key is unknown.
-    pub fn snapshot(&self, api_key: &str) -> HashMap<u64, HashMap<u16, u64>> {
+    pub fn snapshot(&self, api_key: &str) -> MinuteCounts {
         self.counts
-            .lock()
-            .expect("metrics store poisoned")
+            .lock_or_recover()
             .get(api_key)
             .cloned()
             .unwrap_or_default()
     }
 
+    /// API keys that have recorded at least one status code.
+    pub fn keys(&self) -> Vec<String> {
+        self.counts.lock_or_recover().keys().cloned().collect()
+    }
+
+    /// Increment a named event counter by one.
+    pub fn increment(&self, counter: &str) {
+        let mut guard = self.counters.lock_or_recover();
+        *guard.entry(counter.to_string()).or_insert(0) += 1;
+    }
+
+    /// Add `n` to a named event counter.
+    pub fn add(&self, counter: &str, n: u64) {
+        let mut guard = self.counters.lock_or_recover();
+        *guard.entry(counter.to_string()).or_insert(0) += n;
+    }
+
+    /// Current value of a named event counter. Unknown counters read as zero.
+    pub fn counter(&self, counter: &str) -> u64 {
+        self.counters
+            .lock_or_recover()
+            .get(counter)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Increment a named counter for a single label value (e.g. a service name).
+    pub fn increment_labeled(&self, counter: &str, label: &str) {
+        let mut guard = self.labeled.lock_or_recover();
+        let per_label = guard.entry(counter.to_string()).or_default();
+        *per_label.entry(label.to_string()).or_insert(0) += 1;
+    }
+
+    /// Like [`Metrics::increment_labeled`], but once the counter has `max_labels` label
+    /// values new ones are counted under `other`, for labels taken from client input.
+    pub fn increment_labeled_bounded(&self, counter: &str, label: &str, max_labels: usize) {
+        let mut guard = self.labeled.lock_or_recover();
+        let per_label = guard.entry(counter.to_string()).or_default();
+        let label = if per_label.contains_key(label) || per_label.len() < max_labels {
+            label
+        } else {
+            OTHER_LABEL
+        };
+        *per_label.entry(label.to_string()).or_insert(0) += 1;
+    }
+
+    /// Snapshot a labeled counter. Returns an empty map when the counter is unknown.
+    pub fn labeled_counter(&self, counter: &str) -> HashMap<String, u64> {
+        self.labeled
+            .lock_or_recover()
+            .get(counter)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Set a named gauge for a single label value.
+    pub fn set_gauge(&self, gauge: &str, label: &str, value: i64) {
+        let mut guard = self.gauges.lock_or_recover();
+        guard
+            .entry(gauge.to_string())
+            .or_default()
+            .insert(label.to_string(), value);
+    }
+
+    /// Current value of a gauge for a label; `None` when never set.
+    pub fn gauge(&self, gauge: &str, label: &str) -> Option<i64> {
+        self.gauges
+            .lock_or_recover()
+            .get(gauge)
+            .and_then(|labels| labels.get(label))
+            .copied()
+    }
+
+    /// Add a size to a named histogram of a service.
+    pub fn observe(&self, histogram: &str, service: &str, value: u64) {
+        let mut guard = self.histograms.lock_or_recover();
+        guard
+            .entry(histogram.to_string())
+            .or_default()
+            .entry(service.to_string())
+            .or_default()
+            .observe(value);
+    }
+
+    /// Snapshot a histogram by service. Returns an empty map when the histogram is unknown.
+    pub fn histogram(&self, histogram: &str) -> HashMap<String, Histogram> {
+        self.histograms
+            .lock_or_recover()
+            .get(histogram)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Counters, labeled counters, gauges and histograms in the Prometheus text format, named
+    /// `lb_<name>` and sorted by name and label. Every series is labeled with `node` when
+    /// given, so the exports of several LB nodes can be told apart.
+    pub fn render_prometheus(&self, node: Option<&str>) -> String {
+        let node = node.map(|node| format!("node=\"{}\"", escape_label(node)));
+        let node = node.as_deref();
+        let mut out = String::new();
+
+        let mut counters: Vec<(String, u64)> = self
+            .counters
+            .lock_or_recover()
+            .iter()
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        counters.sort();
+        for (name, value) in counters {
+            let name = prometheus_name(&name);
+            let labels = label_set(node, &[]);
+            out.push_str(&format!(
+                "# TYPE {name}_total counter\n{name}_total{labels} {value}\n"
+            ));
+        }
+
+        let mut labeled: Vec<(String, Vec<(String, u64)>)> = self
+            .labeled
+            .lock_or_recover()
+            .iter()
+            .map(|(name, labels)| {
+                let mut labels: Vec<(String, u64)> =
+                    labels.iter().map(|(l, v)| (l.clone(), *v)).collect();
+                labels.sort();
+                (name.clone(), labels)
+            })
+            .collect();
+        labeled.sort();
+        for (name, labels) in labeled {
+            let name = prometheus_name(&name);
+            out.push_str(&format!("# TYPE {name}_total counter\n"));
+            for (label, value) in labels {
+                let labels = label_set(node, &[format!("label=\"{}\"", escape_label(&label))]);
+                out.push_str(&format!("{name}_total{labels} {value}\n"));
+            }
+        }
+
+        let mut gauges: Vec<(String, Vec<(String, i64)>)> = self
+            .gauges
+            .lock_or_recover()
+            .iter()
+            .map(|(name, labels)| {
+                let mut labels: Vec<(String, i64)> =
+                    labels.iter().map(|(l, v)| (l.clone(), *v)).collect();
+                labels.sort();
+                (name.clone(), labels)
+            })
+            .collect();
+        gauges.sort();
+        for (name, labels) in gauges {
+            let name = prometheus_name(&name);
+            out.push_str(&format!("# TYPE {name} gauge\n"));
+            for (label, value) in labels {
+                let labels = label_set(node, &[format!("label=\"{}\"", escape_label(&label))]);
+                out.push_str(&format!("{name}{labels} {value}\n"));
+            }
+        }
+
+        let mut histograms: Vec<(String, Vec<(String, Histogram)>)> = self
+            .histograms
+            .lock_or_recover()
+            .iter()
+            .map(|(name, services)| {
+                let mut services: Vec<(String, Histogram)> = services
+                    .iter()
+                    .map(|(s, h)| (s.clone(), h.clone()))
+                    .collect();
+                services.sort_by(|a, b| a.0.cmp(&b.0));
+                (name.clone(), services)
+            })
+            .collect();
+        histograms.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, services) in histograms {
+            let name = prometheus_name(&name);
+            out.push_str(&format!("# TYPE {name} histogram\n"));
+            for (service, histogram) in services {
+                let service = format!("service=\"{}\"", escape_label(&service));
+                let mut cumulative = 0;
+                for (i, count) in histogram.buckets.iter().enumerate() {
+                    cumulative += count;
+                    let le = SIZE_BUCKETS
+                        .get(i)
+                        .map_or_else(|| "+Inf".to_string(), u64::to_string);
+                    let labels = label_set(node, &[service.clone(), format!("le=\"{le}\"")]);
+                    out.push_str(&format!("{name}_bucket{labels} {cumulative}\n"));
+                }
+                let labels = label_set(node, &[service]);
+                out.push_str(&format!(
+                    "{name}_sum{labels} {}\n{name}_count{labels} {}\n",
+                    histogram.sum, histogram.count
+                ));
+            }
+        }
+        out
+    }
+
     fn minute_bucket(at: SystemTime) -> u64 {
         at.duration_since(SystemTime::UNIX_EPOCH)
             .unwrap_or(Duration::ZERO)
@@ -44,6 +345,38 @@ impl Metrics {
     }
 }
 
+/// Metric name for `name`, prefixed with `lb_` and limited to `[a-zA-Z0-9_]`.
+fn prometheus_name(name: &str) -> String {
+    let name: String = name
+        .strip_prefix("lb_")
+        .unwrap_or(name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("lb_{name}")
+}
+
+/// `{node,labels...}`, or nothing without any label.
+fn label_set(node: Option<&str>, labels: &[String]) -> String {
+    let labels: Vec<&str> = node
+        .into_iter()
+        .chain(labels.iter().map(String::as_str))
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,6 +408,102 @@ mod tests {
         assert_eq!(second_min.get(&200), Some(&1));
     }
 
+    #[test]
+    fn record_buckets_by_injected_clock() {
+        let clock = Arc::new(crate::clock::TestClock::at_unix(119));
+        let metrics = Metrics::new().with_clock(clock.clone());
+
+        metrics.record("k", 200);
+        clock.advance(Duration::from_secs(1));
+        metrics.record("k", 200);
+
+        let snap = metrics.snapshot("k");
+        assert_eq!(snap.get(&1).and_then(|m| m.get(&200)), Some(&1));
+        assert_eq!(snap.get(&2).and_then(|m| m.get(&200)), Some(&1));
+    }
+
+    #[test]
+    fn named_counters_increment_independently() {
+        let metrics = Metrics::new();
+        metrics.increment("a");
+        metrics.increment("a");
+        metrics.increment("b");
+
+        assert_eq!(metrics.counter("a"), 2);
+        assert_eq!(metrics.counter("b"), 1);
+        assert_eq!(metrics.counter("missing"), 0);
+    }
+
+    #[test]
+    fn labeled_counters_group_by_label() {
+        let metrics = Metrics::new();
+        metrics.increment_labeled("aborted", "geocode");
+        metrics.increment_labeled("aborted", "geocode");
+        metrics.increment_labeled("aborted", "search");
+
+        let snap = metrics.labeled_counter("aborted");
+        assert_eq!(snap.get("geocode"), Some(&2));
+        assert_eq!(snap.get("search"), Some(&1));
+        assert!(metrics.labeled_counter("missing").is_empty());
+    }
+
+    #[test]
+    fn bounded_labels_overflow_into_other() {
+        let metrics = Metrics::new();
+        for label in ["/a", "/b", "/a", "/c", "/d"] {
+            metrics.increment_labeled_bounded("misses", label, 2);
+        }
+
+        let snap = metrics.labeled_counter("misses");
+        assert_eq!(snap.get("/a"), Some(&2));
+        assert_eq!(snap.get("/b"), Some(&1));
+        assert_eq!(snap.get(OTHER_LABEL), Some(&2));
+        assert!(!snap.contains_key("/c"));
+    }
+
+    #[test]
+    fn size_histograms_bucket_by_upper_bound() {
+        let metrics = Metrics::new();
+        for size in [0, 256, 257, 100 << 20] {
+            metrics.observe("response_body_bytes", "geocode", size);
+        }
+
+        let geocode = &metrics.histogram("response_body_bytes")["geocode"];
+        assert_eq!(geocode.count, 4);
+        assert_eq!(geocode.sum, 513 + (100 << 20));
+        assert_eq!(geocode.buckets[0], 2);
+        assert_eq!(geocode.buckets[1], 1);
+        assert_eq!(geocode.buckets[SIZE_BUCKETS.len()], 1);
+        assert!(metrics.histogram("missing").is_empty());
+    }
+
+    #[test]
+    fn prometheus_export() {
+        let metrics = Metrics::new();
+        metrics.increment("limited");
+        metrics.increment_labeled("lb_errors", "say \"hi\"");
+        metrics.observe("request_body_bytes", "geocode", 300);
+        metrics.set_gauge("degraded", "usage_dir", 1);
+
+        let text = metrics.render_prometheus(None);
+        assert!(text.contains("# TYPE lb_degraded gauge\nlb_degraded{label=\"usage_dir\"} 1\n"));
+        assert!(text.contains("# TYPE lb_limited_total counter\nlb_limited_total 1\n"));
+        assert!(text.contains("lb_errors_total{label=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains("# TYPE lb_request_body_bytes histogram\n"));
+        assert!(text.contains("lb_request_body_bytes_bucket{service=\"geocode\",le=\"256\"} 0\n"));
+        assert!(text.contains("lb_request_body_bytes_bucket{service=\"geocode\",le=\"1024\"} 1\n"));
+        assert!(text.contains("lb_request_body_bytes_bucket{service=\"geocode\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("lb_request_body_bytes_sum{service=\"geocode\"} 300\n"));
+        assert!(text.contains("lb_request_body_bytes_count{service=\"geocode\"} 1\n"));
+
+        let text = metrics.render_prometheus(Some("lb-1"));
+        assert!(text.contains("lb_limited_total{node=\"lb-1\"} 1\n"));
+        assert!(text.contains("lb_errors_total{node=\"lb-1\",label=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains(
+            "lb_request_body_bytes_bucket{node=\"lb-1\",service=\"geocode\",le=\"+Inf\"} 1\n"
+        ));
+    }
+
     #[test]
     fn snapshot_unknown_key_is_empty() {
         let metrics = Metrics::new();
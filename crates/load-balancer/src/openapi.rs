@@ -0,0 +1,404 @@
+//! Request validation against a service's OpenAPI spec.
+//!
+//! A service with an `openapi` spec file only gets requests for documented operations:
+//!
+//! - a path matching no path of the spec is answered with 404,
+//! - a method the matching path does not document, or a request missing a required query
+//!   parameter or header, with 400.
+//!
+//! Spec paths are matched against the full request path, so they include the service's
+//! prefix. A templated segment (`{id}`, `{name}.json`) matches any value; when several spec
+//! paths match, the one with the most literal segments wins. Parameters may be defined on
+//! the path or the operation and may be `$ref`s into `#/components/parameters`.
+//!
+//! Only the structure of the spec is used; schemas and request bodies are not checked.
+//!
+//! Rejected operations are counted per service, method and path in
+//! [`REJECTIONS_COUNTER`], and reported by the admin API so gaps in a spec show up as
+//! operations clients keep calling.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use pingora::http::RequestHeader;
+use serde::Serialize;
+use serde_yaml::Value;
+
+use crate::metric::OTHER_LABEL;
+
+/// Labeled counter of requests rejected by a spec, by [`rejection_label`].
+pub const REJECTIONS_COUNTER: &str = "openapi_rejections";
+/// Distinct operations tracked in the rejections counter; later ones count as `other`.
+pub const MAX_REJECTED_OPERATIONS: usize = 500;
+/// Longest request path kept in a rejection label.
+const MAX_REJECTED_PATH_LEN: usize = 128;
+
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// Literal text around a template variable, as in `{name}.json`.
+    Template {
+        prefix: String,
+        suffix: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Location {
+    Query,
+    Header,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RequiredParam {
+    name: String,
+    location: Location,
+}
+
+#[derive(Debug)]
+struct PathItem {
+    segments: Vec<Segment>,
+    /// Required parameters by upper case method.
+    operations: HashMap<String, Vec<RequiredParam>>,
+}
+
+/// Why a request does not match the spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    UnknownPath,
+    UnknownMethod,
+    MissingParameter(String),
+}
+
+impl Violation {
+    /// Status the request is rejected with.
+    pub fn status(&self) -> u16 {
+        match self {
+            Violation::UnknownPath => 404,
+            Violation::UnknownMethod | Violation::MissingParameter(_) => 400,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::UnknownPath => write!(f, "path not documented"),
+            Violation::UnknownMethod => write!(f, "method not documented for path"),
+            Violation::MissingParameter(name) => {
+                write!(f, "missing required parameter '{}'", name)
+            }
+        }
+    }
+}
+
+/// The operations of an OpenAPI spec.
+#[derive(Debug)]
+pub struct OpenApiSpec {
+    paths: Vec<PathItem>,
+}
+
+fn parse_segment(segment: &str) -> Segment {
+    match (segment.find('{'), segment.rfind('}')) {
+        (Some(open), Some(close)) if open < close => Segment::Template {
+            prefix: segment[..open].to_string(),
+            suffix: segment[close + 1..].to_string(),
+        },
+        _ => Segment::Literal(segment.to_string()),
+    }
+}
+
+impl OpenApiSpec {
+    /// Read a JSON or YAML spec.
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("failed to read: {e}"))?;
+        Self::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let doc: Value = serde_yaml::from_str(s).map_err(|e| format!("failed to parse: {e}"))?;
+        let paths = doc
+            .get("paths")
+            .and_then(Value::as_mapping)
+            .ok_or("spec has no paths")?;
+
+        // Local references only, which is how specs share parameters
+        let resolve = |param: &'_ Value| -> Result<Value, String> {
+            match param.get("$ref").and_then(Value::as_str) {
+                Some(reference) => reference
+                    .strip_prefix("#/components/parameters/")
+                    .and_then(|name| doc.get("components")?.get("parameters")?.get(name))
+                    .cloned()
+                    .ok_or(format!("unresolved parameter reference '{reference}'")),
+                None => Ok(param.clone()),
+            }
+        };
+        let required = |params: Option<&Value>| -> Result<Vec<RequiredParam>, String> {
+            let mut required = Vec::new();
+            for param in params.and_then(Value::as_sequence).into_iter().flatten() {
+                let param = resolve(param)?;
+                if param.get("required").and_then(Value::as_bool) != Some(true) {
+                    continue;
+                }
+                let location = match param.get("in").and_then(Value::as_str) {
+                    Some("query") => Location::Query,
+                    Some("header") => Location::Header,
+                    _ => continue,
+                };
+                let name = param
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .ok_or("parameter without a name")?;
+                required.push(RequiredParam {
+                    name: match location {
+                        Location::Header => name.to_ascii_lowercase(),
+                        Location::Query => name.to_string(),
+                    },
+                    location,
+                });
+            }
+            Ok(required)
+        };
+
+        let mut items = Vec::new();
+        for (path, item) in paths {
+            let path = path.as_str().ok_or("path is not a string")?;
+            if !path.starts_with('/') {
+                return Err(format!("path '{path}' does not start with '/'"));
+            }
+            let shared = required(item.get("parameters"))?;
+            let mut operations = HashMap::new();
+            for method in METHODS {
+                if let Some(operation) = item.get(method) {
+                    let mut params = shared.clone();
+                    params.extend(required(operation.get("parameters"))?);
+                    operations.insert(method.to_ascii_uppercase(), params);
+                }
+            }
+            items.push(PathItem {
+                segments: path[1..].split('/').map(parse_segment).collect(),
+                operations,
+            });
+        }
+        Ok(Self { paths: items })
+    }
+
+    fn path_item(&self, path: &str) -> Option<&PathItem> {
+        let segments: Vec<&str> = path.get(1..)?.split('/').collect();
+        self.paths
+            .iter()
+            .filter(|item| {
+                item.segments.len() == segments.len()
+                    && item
+                        .segments
+                        .iter()
+                        .zip(&segments)
+                        .all(|(s, value)| match s {
+                            Segment::Literal(literal) => literal == value,
+                            Segment::Template { prefix, suffix } => {
+                                value.len() > prefix.len() + suffix.len()
+                                    && value.starts_with(prefix.as_str())
+                                    && value.ends_with(suffix.as_str())
+                            }
+                        })
+            })
+            .max_by_key(|item| {
+                item.segments
+                    .iter()
+                    .filter(|s| matches!(s, Segment::Literal(_)))
+                    .count()
+            })
+    }
+
+    /// Check a request against the documented operations.
+    pub fn check(&self, req: &RequestHeader) -> Result<(), Violation> {
+        let item = self
+            .path_item(req.uri.path())
+            .ok_or(Violation::UnknownPath)?;
+        let params = item
+            .operations
+            .get(req.method.as_str())
+            .ok_or(Violation::UnknownMethod)?;
+        let query = req.uri.query().unwrap_or("");
+        for param in params {
+            let present = match param.location {
+                Location::Header => req.headers.contains_key(param.name.as_str()),
+                Location::Query => query
+                    .split('&')
+                    .any(|pair| pair.split_once('=').map_or(pair, |(k, _)| k) == param.name),
+            };
+            if !present {
+                return Err(Violation::MissingParameter(param.name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Label of a rejected request: service, method and path.
+pub fn rejection_label(service: &str, req: &RequestHeader) -> String {
+    let path = req.uri.path();
+    let mut end = path.len().min(MAX_REJECTED_PATH_LEN);
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{service} {} {}", req.method, &path[..end])
+}
+
+/// An operation rejected by a service's spec.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RejectedOperation {
+    pub service: String,
+    pub method: String,
+    pub path: String,
+    pub count: u64,
+}
+
+/// Rejected operations from a snapshot of [`REJECTIONS_COUNTER`], most rejected first,
+/// and the count of those past the tracked limit.
+pub fn rejection_report(counts: HashMap<String, u64>) -> (Vec<RejectedOperation>, u64) {
+    let mut other = 0;
+    let mut operations = Vec::new();
+    for (label, count) in counts {
+        let mut parts = label.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(service), Some(method), Some(path)) if label != OTHER_LABEL => {
+                operations.push(RejectedOperation {
+                    service: service.to_string(),
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    count,
+                })
+            }
+            _ => other += count,
+        }
+    }
+    operations.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| (&a.service, &a.path, &a.method).cmp(&(&b.service, &b.path, &b.method)))
+    });
+    (operations, other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r##"
+openapi: 3.0.3
+paths:
+  /geocode/search:
+    parameters:
+      - $ref: '#/components/parameters/Query'
+    get:
+      parameters:
+        - { name: X-Client, in: header, required: true }
+        - { name: limit, in: query }
+  /geocode/places/{id}:
+    get: {}
+    delete: {}
+  /geocode/places/{id}.json:
+    get: {}
+  /geocode/places/nearby:
+    post: {}
+components:
+  parameters:
+    Query: { name: q, in: query, required: true }
+"##;
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_documented_operations_pass() {
+        let spec = OpenApiSpec::parse(SPEC).unwrap();
+        let client = [("x-client", "web")];
+        assert_eq!(
+            spec.check(&request("GET", "/geocode/search?q=berlin", &client)),
+            Ok(())
+        );
+        assert_eq!(
+            spec.check(&request("DELETE", "/geocode/places/42", &[])),
+            Ok(())
+        );
+        assert_eq!(
+            spec.check(&request("GET", "/geocode/places/42.json", &[])),
+            Ok(())
+        );
+        // The literal path wins over the template
+        assert_eq!(
+            spec.check(&request("POST", "/geocode/places/nearby", &[])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_undocumented_requests_are_rejected() {
+        let spec = OpenApiSpec::parse(SPEC).unwrap();
+        assert_eq!(
+            spec.check(&request("GET", "/geocode/reverse", &[])),
+            Err(Violation::UnknownPath)
+        );
+        assert_eq!(
+            spec.check(&request("GET", "/geocode/places/42/photos", &[])),
+            Err(Violation::UnknownPath)
+        );
+        assert_eq!(
+            spec.check(&request("PUT", "/geocode/places/42", &[])),
+            Err(Violation::UnknownMethod)
+        );
+        assert_eq!(
+            spec.check(&request(
+                "GET",
+                "/geocode/search?limit=1",
+                &[("x-client", "web")]
+            )),
+            Err(Violation::MissingParameter("q".to_string()))
+        );
+        let missing_header = spec
+            .check(&request("GET", "/geocode/search?q=a", &[]))
+            .unwrap_err();
+        assert_eq!(missing_header.status(), 400);
+        assert_eq!(Violation::UnknownPath.status(), 404);
+    }
+
+    #[test]
+    fn test_rejection_report() {
+        let label = rejection_label("geocode", &request("PUT", "/geocode/places/42", &[]));
+        assert_eq!(label, "geocode PUT /geocode/places/42");
+
+        let counts = HashMap::from([
+            (label, 2),
+            ("geocode GET /geocode/reverse".to_string(), 5),
+            (OTHER_LABEL.to_string(), 3),
+        ]);
+        let (operations, other) = rejection_report(counts);
+        assert_eq!(other, 3);
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations[0].path, "/geocode/reverse");
+        assert_eq!(operations[0].count, 5);
+        assert_eq!(operations[1].method, "PUT");
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        assert!(OpenApiSpec::parse("openapi: 3.0.3").is_err());
+        assert!(OpenApiSpec::parse("paths: {search: {get: {}}}").is_err());
+        assert!(
+            OpenApiSpec::parse(
+                "paths: {/a: {get: {parameters: [{$ref: '#/components/parameters/X'}]}}}"
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Request de-identification for logs and usage (privacy mode).
+//!
+//! With a `privacy` section, client IPs are truncated to their network or replaced by a
+//! keyed hash before they reach the access log or the KeyActivity rows of the usage DBs,
+//! and request targets are redacted before they are logged or traced:
+//!
+//! ```yaml
+//! privacy:
+//!   client_ip: hash
+//!   hash_key: 0b9f5c2e...
+//!   pii_params: [email, phone]
+//!   services:
+//!     accounts: { pii_params: [name], path_segments: [2] }
+//! ```
+//!
+//! Truncation keeps the /24 of an IPv4 and the /48 of an IPv6 address; a hash is stable
+//! for the same key, so requests of one client still correlate without revealing it.
+//! Logged targets keep their query string unless it carries a parameter marked as PII,
+//! globally or for the service, in which case the query string is stripped whole; the
+//! listed 1-based path segments of a service are replaced by `redacted`. Outside privacy
+//! mode the logs carry the path only.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use http::Uri;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Replacement of redacted path segments.
+pub const REDACTED: &str = "redacted";
+
+/// How client IPs are recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpMode {
+    /// As they are.
+    Keep,
+    /// Without their host part: the /24 of IPv4 and the /48 of IPv6 addresses.
+    #[default]
+    Truncate,
+    /// As a hash keyed with `hash_key`.
+    Hash,
+}
+
+/// Redaction of the requests of one service.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RedactionRules {
+    /// Query parameters carrying personal data, besides the global ones.
+    pub pii_params: Vec<String>,
+    /// 1-based positions of path segments carrying personal data.
+    pub path_segments: Vec<usize>,
+}
+
+/// De-identification of what logs and usage records keep of a request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub client_ip: ClientIpMode,
+    /// Secret mixed into client IP hashes, so they cannot be reversed by hashing every
+    /// address.
+    pub hash_key: Option<String>,
+    /// Query parameters carrying personal data in every service.
+    pub pii_params: Vec<String>,
+    /// Redaction rules per service name.
+    pub services: HashMap<String, RedactionRules>,
+}
+
+impl PrivacyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.client_ip == ClientIpMode::Hash
+            && self.hash_key.as_deref().is_none_or(str::is_empty)
+        {
+            return Err("privacy.hash_key must be set to hash client IPs".to_string());
+        }
+        if let Some(service) = self
+            .services
+            .iter()
+            .find(|(_, rules)| rules.path_segments.contains(&0))
+            .map(|(service, _)| service)
+        {
+            return Err(format!(
+                "privacy.services.{service}.path_segments are 1-based"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Applies a [`PrivacyConfig`] to client IPs and request targets.
+#[derive(Debug, Clone)]
+pub struct Privacy {
+    config: PrivacyConfig,
+}
+
+impl Privacy {
+    pub fn new(config: PrivacyConfig) -> Self {
+        Self { config }
+    }
+
+    /// `ip` as it may be recorded.
+    pub fn client_ip(&self, ip: IpAddr) -> String {
+        match self.config.client_ip {
+            ClientIpMode::Keep => ip.to_string(),
+            ClientIpMode::Truncate => match ip {
+                IpAddr::V4(v4) => Ipv4Addr::from(v4.to_bits() & !0xff).to_string(),
+                IpAddr::V6(v6) => Ipv6Addr::from(v6.to_bits() & !((1u128 << 80) - 1)).to_string(),
+            },
+            ClientIpMode::Hash => {
+                let mut hasher = Sha256::new();
+                hasher.update(self.config.hash_key.as_deref().unwrap_or_default());
+                hasher.update(ip.to_string());
+                hex::encode(&hasher.finalize()[..8])
+            }
+        }
+    }
+
+    /// Path and query of `uri` as they may be logged for a request to `service`.
+    pub fn request_target(&self, service: Option<&str>, uri: &Uri) -> String {
+        let rules = service.and_then(|service| self.config.services.get(service));
+        let path = match rules.filter(|rules| !rules.path_segments.is_empty()) {
+            Some(rules) => uri
+                .path()
+                .split('/')
+                .enumerate()
+                .map(|(i, segment)| {
+                    // The path starts with '/', so segment i is the i-th after it
+                    if rules.path_segments.contains(&i) && !segment.is_empty() {
+                        REDACTED
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/"),
+            None => uri.path().to_string(),
+        };
+        let Some(query) = uri.query().filter(|query| !query.is_empty()) else {
+            return path;
+        };
+        let is_pii = |name: &str| {
+            self.config.pii_params.iter().any(|p| p == name)
+                || rules.is_some_and(|rules| rules.pii_params.iter().any(|p| p == name))
+        };
+        let has_pii = query
+            .split('&')
+            .any(|pair| is_pii(pair.split_once('=').map_or(pair, |(name, _)| name)));
+        if has_pii {
+            path
+        } else {
+            format!("{path}?{query}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privacy(client_ip: ClientIpMode) -> Privacy {
+        Privacy::new(PrivacyConfig {
+            client_ip,
+            hash_key: Some("secret".to_string()),
+            pii_params: vec!["email".to_string()],
+            services: HashMap::from([(
+                "accounts".to_string(),
+                RedactionRules {
+                    pii_params: vec!["name".to_string()],
+                    path_segments: vec![2],
+                },
+            )]),
+        })
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let v4: IpAddr = "203.0.113.77".parse().unwrap();
+        let v6: IpAddr = "2001:db8:aa:bb::1".parse().unwrap();
+        let truncate = privacy(ClientIpMode::Truncate);
+        assert_eq!(truncate.client_ip(v4), "203.0.113.0");
+        assert_eq!(truncate.client_ip(v6), "2001:db8:aa::");
+        assert_eq!(privacy(ClientIpMode::Keep).client_ip(v4), "203.0.113.77");
+
+        let hash = privacy(ClientIpMode::Hash);
+        assert_eq!(hash.client_ip(v4).len(), 16);
+        assert_eq!(hash.client_ip(v4), hash.client_ip(v4));
+        assert_ne!(hash.client_ip(v4), hash.client_ip(v6));
+    }
+
+    #[test]
+    fn test_request_target() {
+        let privacy = privacy(ClientIpMode::Truncate);
+        let target = |service, uri: &str| privacy.request_target(service, &uri.parse().unwrap());
+        assert_eq!(
+            target(Some("geocode"), "/geocode?q=berlin"),
+            "/geocode?q=berlin"
+        );
+        assert_eq!(
+            target(Some("geocode"), "/geocode?q=berlin&email=a@b.c"),
+            "/geocode"
+        );
+        assert_eq!(
+            target(Some("accounts"), "/accounts/alice/orders?name=alice"),
+            "/accounts/redacted/orders"
+        );
+        assert_eq!(
+            target(Some("geocode"), "/geocode?name=x"),
+            "/geocode?name=x"
+        );
+        assert_eq!(target(None, "/accounts/alice"), "/accounts/alice");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(PrivacyConfig::default().validate().is_ok());
+        let unkeyed = PrivacyConfig {
+            client_ip: ClientIpMode::Hash,
+            ..Default::default()
+        };
+        assert!(unkeyed.validate().is_err());
+        let zero = PrivacyConfig {
+            services: HashMap::from([(
+                "accounts".to_string(),
+                RedactionRules {
+                    path_segments: vec![0],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        assert!(zero.validate().is_err());
+    }
+}
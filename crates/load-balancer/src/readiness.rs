@@ -0,0 +1,92 @@
+//! Startup phases and readiness.
+//!
+//! The proxy must not report ready before it can apply real limits: until the backend config
+//! and the account store have loaded, every key would get the restrictive default. Each phase
+//! is marked complete once it succeeds and `/readyz` on the admin listener passes only when
+//! all of them are.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A startup step that must complete before the proxy is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The backend config was read and validated.
+    BackendConfig,
+    /// Account data was loaded from the accounts DB or a snapshot.
+    Accounts,
+}
+
+impl Phase {
+    const ALL: [Phase; 2] = [Phase::BackendConfig, Phase::Accounts];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::BackendConfig => "backend_config",
+            Phase::Accounts => "accounts",
+        }
+    }
+}
+
+/// Completion state of the startup phases.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    backend_config: AtomicBool,
+    accounts: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn flag(&self, phase: Phase) -> &AtomicBool {
+        match phase {
+            Phase::BackendConfig => &self.backend_config,
+            Phase::Accounts => &self.accounts,
+        }
+    }
+
+    /// Mark `phase` as complete.
+    pub fn complete(&self, phase: Phase) {
+        if !self.flag(phase).swap(true, Ordering::Release) {
+            log::info!("Startup phase {} complete", phase.name());
+        }
+    }
+
+    /// Whether `phase` has completed.
+    pub fn is_complete(&self, phase: Phase) -> bool {
+        self.flag(phase).load(Ordering::Acquire)
+    }
+
+    /// Whether every phase has completed.
+    pub fn is_ready(&self) -> bool {
+        self.pending().is_empty()
+    }
+
+    /// Names of the phases still pending.
+    pub fn pending(&self) -> Vec<&'static str> {
+        Phase::ALL
+            .iter()
+            .filter(|phase| !self.is_complete(**phase))
+            .map(Phase::name)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_once_all_phases_complete() {
+        let readiness = Readiness::new();
+        assert_eq!(readiness.pending(), vec!["backend_config", "accounts"]);
+
+        readiness.complete(Phase::BackendConfig);
+        assert!(!readiness.is_ready());
+        assert_eq!(readiness.pending(), vec!["accounts"]);
+
+        readiness.complete(Phase::Accounts);
+        assert!(readiness.is_ready());
+    }
+}
@@ -0,0 +1,371 @@
+//! Metrics and usage recording off the request path.
+//!
+//! The proxy hands every status count, counter increment and usage record to a
+//! [`Recorder`]. A pipelined recorder only queues them on a bounded channel; an aggregator
+//! thread applies them to [`Metrics`] and the [`UsageTracker`] in batches. When the queue is
+//! full the record is dropped and counted in [`RECORDS_DROPPED_COUNTER`] instead of waiting,
+//! so contention on the stores or a stalled flush never holds up a request.
+//!
+//! A direct recorder applies records as they come, for tools and tests that need the
+//! stores up to date after each request.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::metric::Metrics;
+use crate::sync::{self, POISONED_LOCKS_COUNTER};
+use crate::usage::{UsageRoute, UsageTracker};
+
+/// Counter of records dropped because the queue was full.
+pub const RECORDS_DROPPED_COUNTER: &str = "records_dropped";
+/// Records applied per wakeup of the aggregator at most.
+const BATCH_SIZE: usize = 1024;
+
+/// Settings of the recording pipeline.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Records queued for the aggregator at most; 0 records on the request path instead.
+    pub queue_capacity: usize,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 65_536,
+        }
+    }
+}
+
+/// Usage of a finished request.
+#[derive(Debug, Clone)]
+pub struct UsageEvent {
+    pub account_id: i64,
+    pub api_key: Uuid,
+    pub plan_id: i64,
+    pub route: UsageRoute,
+    pub response_bytes: u64,
+    pub timestamp_secs: i64,
+    pub aborted: bool,
+    /// Client IP as it may be recorded, de-identified in privacy mode.
+    pub client_ip: Option<String>,
+}
+
+#[derive(Debug)]
+enum Record {
+    Status {
+        key: String,
+        status: u16,
+        at: SystemTime,
+    },
+    Bytes {
+        key: String,
+        bytes: u64,
+        at: SystemTime,
+    },
+    Counter(String),
+    Labeled {
+        counter: String,
+        label: String,
+        max_labels: Option<usize>,
+    },
+    Observation {
+        histogram: String,
+        service: String,
+        value: u64,
+    },
+    Usage(UsageEvent),
+    /// Acknowledged once every record queued before it is applied.
+    Flush(SyncSender<()>),
+}
+
+struct Stores {
+    metrics: Arc<Metrics>,
+    usage: Option<Arc<UsageTracker>>,
+}
+
+impl Stores {
+    fn apply(&self, record: Record) {
+        match record {
+            Record::Status { key, status, at } => self.metrics.record_at(&key, status, at),
+            Record::Bytes { key, bytes, at } => self.metrics.record_bytes_at(&key, bytes, at),
+            Record::Counter(counter) => self.metrics.increment(&counter),
+            Record::Labeled {
+                counter,
+                label,
+                max_labels: Some(max_labels),
+            } => self
+                .metrics
+                .increment_labeled_bounded(&counter, &label, max_labels),
+            Record::Labeled { counter, label, .. } => {
+                self.metrics.increment_labeled(&counter, &label)
+            }
+            Record::Observation {
+                histogram,
+                service,
+                value,
+            } => self.metrics.observe(&histogram, &service, value),
+            Record::Usage(event) => {
+                if let Some(tracker) = &self.usage {
+                    let record = if event.aborted {
+                        UsageTracker::record_aborted
+                    } else {
+                        UsageTracker::record
+                    };
+                    record(
+                        tracker,
+                        event.account_id,
+                        event.api_key,
+                        event.plan_id,
+                        &event.route,
+                        event.response_bytes,
+                        event.timestamp_secs,
+                    );
+                    tracker.touch_key(
+                        event.account_id,
+                        event.api_key,
+                        event.client_ip,
+                        event.timestamp_secs,
+                    );
+                }
+            }
+            Record::Flush(ack) => {
+                let _ = ack.send(());
+            }
+        }
+    }
+
+    /// Add the records dropped and the locks recovered since the last call to the metrics.
+    fn add_failures(&self, dropped: &AtomicU64) {
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            self.metrics.add(RECORDS_DROPPED_COUNTER, dropped);
+        }
+        let recovered = sync::take_recovered();
+        if recovered > 0 {
+            self.metrics.add(POISONED_LOCKS_COUNTER, recovered);
+        }
+    }
+}
+
+/// Handle the proxy records through; cheap to clone.
+#[derive(Clone)]
+pub struct Recorder {
+    stores: Arc<Stores>,
+    queue: Option<SyncSender<Record>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    /// Apply records on the calling thread.
+    pub fn direct(metrics: Arc<Metrics>, usage: Option<Arc<UsageTracker>>) -> Self {
+        Self {
+            stores: Arc::new(Stores { metrics, usage }),
+            queue: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Queue records for an aggregator thread, or apply them directly when `config` has no
+    /// queue capacity.
+    pub fn spawn(
+        config: &RecordingConfig,
+        metrics: Arc<Metrics>,
+        usage: Option<Arc<UsageTracker>>,
+    ) -> std::io::Result<Self> {
+        let mut recorder = Self::direct(metrics, usage);
+        if config.queue_capacity == 0 {
+            return Ok(recorder);
+        }
+        let (tx, rx) = mpsc::sync_channel(config.queue_capacity);
+        let stores = recorder.stores.clone();
+        let dropped = recorder.dropped.clone();
+        std::thread::Builder::new()
+            .name("metrics aggregator".to_string())
+            .spawn(move || aggregate(&stores, &dropped, rx))?;
+        recorder.queue = Some(tx);
+        Ok(recorder)
+    }
+
+    fn send(&self, record: Record) {
+        let Some(queue) = &self.queue else {
+            self.stores.apply(record);
+            self.stores.add_failures(&self.dropped);
+            return;
+        };
+        match queue.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // The aggregator is gone, so nothing is recorded anymore
+            Err(TrySendError::Disconnected(record)) => self.stores.apply(record),
+        }
+    }
+
+    /// See [`Metrics::record`].
+    pub fn record(&self, key: &str, status: u16) {
+        self.send(Record::Status {
+            key: key.to_string(),
+            status,
+            at: self.stores.metrics.now(),
+        });
+    }
+
+    /// See [`Metrics::record_bytes`].
+    pub fn record_bytes(&self, key: &str, bytes: u64) {
+        self.send(Record::Bytes {
+            key: key.to_string(),
+            bytes,
+            at: self.stores.metrics.now(),
+        });
+    }
+
+    /// See [`Metrics::increment`].
+    pub fn increment(&self, counter: &str) {
+        self.send(Record::Counter(counter.to_string()));
+    }
+
+    /// See [`Metrics::increment_labeled`].
+    pub fn increment_labeled(&self, counter: &str, label: &str) {
+        self.send(Record::Labeled {
+            counter: counter.to_string(),
+            label: label.to_string(),
+            max_labels: None,
+        });
+    }
+
+    /// See [`Metrics::increment_labeled_bounded`].
+    pub fn increment_labeled_bounded(&self, counter: &str, label: &str, max_labels: usize) {
+        self.send(Record::Labeled {
+            counter: counter.to_string(),
+            label: label.to_string(),
+            max_labels: Some(max_labels),
+        });
+    }
+
+    /// See [`Metrics::observe`].
+    pub fn observe(&self, histogram: &str, service: &str, value: u64) {
+        self.send(Record::Observation {
+            histogram: histogram.to_string(),
+            service: service.to_string(),
+            value,
+        });
+    }
+
+    /// Record the usage of a finished request and the key's last use.
+    pub fn usage(&self, event: UsageEvent) {
+        if self.stores.usage.is_some() {
+            self.send(Record::Usage(event));
+        }
+    }
+
+    /// Wait until every record queued so far is applied.
+    pub fn flush(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let (ack, done) = mpsc::sync_channel(1);
+        if queue.send(Record::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+/// Apply queued records until every sender is gone.
+fn aggregate(stores: &Stores, dropped: &AtomicU64, rx: Receiver<Record>) {
+    while let Ok(first) = rx.recv() {
+        stores.apply(first);
+        for record in rx.try_iter().take(BATCH_SIZE - 1) {
+            stores.apply(record);
+        }
+        stores.add_failures(dropped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipelined_records_are_applied_by_flush() {
+        let metrics = Arc::new(Metrics::new());
+        let usage = Arc::new(UsageTracker::new());
+        let recorder = Recorder::spawn(
+            &RecordingConfig::default(),
+            metrics.clone(),
+            Some(usage.clone()),
+        )
+        .unwrap();
+
+        recorder.record("fp", 200);
+        recorder.record_bytes("fp", 10);
+        recorder.increment("rejected");
+        recorder.increment_labeled_bounded("misses", "/a", 1);
+        recorder.increment_labeled_bounded("misses", "/b", 1);
+        let api_key = Uuid::now_v7();
+        recorder.usage(UsageEvent {
+            account_id: 1,
+            api_key,
+            plan_id: 1,
+            route: UsageRoute::default(),
+            response_bytes: 10,
+            timestamp_secs: 120,
+            aborted: false,
+            client_ip: None,
+        });
+        recorder.flush();
+
+        assert_eq!(metrics.snapshot("fp").values().next().unwrap()[&200], 1);
+        assert_eq!(metrics.bytes_snapshot("fp").values().sum::<u64>(), 10);
+        assert_eq!(metrics.counter("rejected"), 1);
+        assert_eq!(metrics.labeled_counter("misses").len(), 2);
+        assert_eq!(usage.pending_requests(api_key, 0), 1);
+        assert_eq!(usage.last_used(api_key).unwrap().last_used_ts, 120);
+    }
+
+    #[test]
+    fn test_full_queue_drops_and_counts() {
+        let metrics = Arc::new(Metrics::new());
+        // A queue without an aggregator fills up at once
+        let (tx, rx) = mpsc::sync_channel(1);
+        let recorder = Recorder {
+            queue: Some(tx),
+            ..Recorder::direct(metrics.clone(), None)
+        };
+        recorder.increment("a");
+        recorder.increment("b");
+        recorder.increment("c");
+        assert_eq!(recorder.dropped.load(Ordering::Relaxed), 2);
+
+        drop(recorder);
+        aggregate(
+            &Stores {
+                metrics: metrics.clone(),
+                usage: None,
+            },
+            &AtomicU64::new(2),
+            rx,
+        );
+        assert_eq!(metrics.counter("a"), 1);
+        assert_eq!(metrics.counter("b"), 0);
+        assert_eq!(metrics.counter(RECORDS_DROPPED_COUNTER), 2);
+    }
+
+    #[test]
+    fn test_direct_recorder_applies_at_once() {
+        let metrics = Arc::new(Metrics::new());
+        let recorder = Recorder::direct(metrics.clone(), None);
+        recorder.increment_labeled("requests", "service=geocode");
+        recorder.flush();
+        assert_eq!(
+            metrics.labeled_counter("requests").get("service=geocode"),
+            Some(&1)
+        );
+    }
+}
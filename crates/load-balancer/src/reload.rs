@@ -0,0 +1,301 @@
+//! Full reload of runtime state on demand.
+//!
+//! On SIGHUP (or `POST /admin/reload` on the admin listener) the LB re-reads the backend
+//! config, performs a full (not delta) reload of the accounts DB, flushes pending usage, and
+//! reopens log files so logrotate can move them. A one-line summary of what changed is logged.
+//! Applied backend configs are recorded in the config history when one is kept (see
+//! [`crate::history`]).
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+
+use crate::accounts::{AccountLoader, AccountStore};
+use crate::configuration::Config;
+use crate::history::ConfigHistory;
+use crate::sync::RwLockExt;
+use crate::usage::UsageWriter;
+
+/// Hook that reopens log files after rotation.
+pub type LogReopener = Arc<dyn Fn() -> std::io::Result<()> + Send + Sync>;
+
+/// `(services, backends)` in the backend config.
+pub type ConfigCounts = (usize, usize);
+/// `(plans, accounts, keys)` in the account store.
+pub type AccountCounts = (usize, usize, usize);
+/// Counts before and after a reload step, or the error that stopped it.
+pub type StepResult<T> = Result<(T, T), String>;
+
+/// Outcome of each reload step.
+#[derive(Debug, Default)]
+pub struct ReloadSummary {
+    pub config: Option<StepResult<ConfigCounts>>,
+    pub accounts: Option<StepResult<AccountCounts>>,
+    /// Number of usage records flushed, or the error.
+    pub usage: Option<Result<usize, String>>,
+    /// Whether log files were reopened, or the error.
+    pub logs: Option<Result<(), String>>,
+}
+
+impl ReloadSummary {
+    /// Whether every attempted step succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.config.as_ref().is_none_or(|r| r.is_ok())
+            && self.accounts.as_ref().is_none_or(|r| r.is_ok())
+            && self.usage.as_ref().is_none_or(|r| r.is_ok())
+            && self.logs.as_ref().is_none_or(|r| r.is_ok())
+    }
+}
+
+impl fmt::Display for ReloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.config {
+            Some(Ok(((s0, b0), (s1, b1)))) => {
+                write!(f, "config: services {s0} -> {s1}, backends {b0} -> {b1}")?
+            }
+            Some(Err(e)) => write!(f, "config: FAILED ({e})")?,
+            None => write!(f, "config: skipped")?,
+        }
+        match &self.accounts {
+            Some(Ok(((p0, a0, k0), (p1, a1, k1)))) => write!(
+                f,
+                "; accounts: plans {p0} -> {p1}, accounts {a0} -> {a1}, keys {k0} -> {k1}"
+            )?,
+            Some(Err(e)) => write!(f, "; accounts: FAILED ({e})")?,
+            None => write!(f, "; accounts: skipped")?,
+        }
+        match &self.usage {
+            Some(Ok(n)) => write!(f, "; usage: flushed {n} records")?,
+            Some(Err(e)) => write!(f, "; usage: FAILED ({e})")?,
+            None => write!(f, "; usage: disabled")?,
+        }
+        match &self.logs {
+            Some(Ok(())) => write!(f, "; logs: reopened"),
+            Some(Err(e)) => write!(f, "; logs: FAILED ({e})"),
+            None => write!(f, "; logs: nothing to reopen"),
+        }
+    }
+}
+
+/// Reloads every piece of runtime state the LB holds.
+pub struct RuntimeReloader {
+    backend_path: PathBuf,
+    config: Arc<RwLock<Config>>,
+    accounts_db: PathBuf,
+    store: Arc<RwLock<AccountStore>>,
+    usage: Option<Arc<UsageWriter>>,
+    log_reopen: Option<LogReopener>,
+    history: Option<Arc<ConfigHistory>>,
+}
+
+impl RuntimeReloader {
+    pub fn new(
+        backend_path: impl Into<PathBuf>,
+        config: Arc<RwLock<Config>>,
+        accounts_db: impl Into<PathBuf>,
+        store: Arc<RwLock<AccountStore>>,
+    ) -> Self {
+        Self {
+            backend_path: backend_path.into(),
+            config,
+            accounts_db: accounts_db.into(),
+            store,
+            usage: None,
+            log_reopen: None,
+            history: None,
+        }
+    }
+
+    /// Flush usage through `writer` on reload.
+    pub fn with_usage_writer(mut self, writer: Arc<UsageWriter>) -> Self {
+        self.usage = Some(writer);
+        self
+    }
+
+    /// Reopen log files through `reopen` on reload.
+    pub fn with_log_reopen(mut self, reopen: LogReopener) -> Self {
+        self.log_reopen = Some(reopen);
+        self
+    }
+
+    /// Record applied backend configs in `history`.
+    pub fn with_history(mut self, history: Arc<ConfigHistory>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Run every reload step. A failing step does not stop the others.
+    pub fn reload_all(&self) -> ReloadSummary {
+        let summary = ReloadSummary {
+            config: Some(self.reload_config()),
+            accounts: Some(self.reload_accounts()),
+            usage: self
+                .usage
+                .as_ref()
+                .map(|writer| writer.flush_all().map_err(|e| e.to_string())),
+            logs: self
+                .log_reopen
+                .as_ref()
+                .map(|reopen| reopen().map_err(|e| e.to_string())),
+        };
+
+        if summary.is_ok() {
+            log::info!("Reload complete: {summary}");
+        } else {
+            log::error!("Reload finished with errors: {summary}");
+        }
+        summary
+    }
+
+    /// Make `source` the backend config: it is validated, written over the backend config
+    /// file, so later reloads keep it, and reloaded.
+    pub fn apply_config(&self, source: &str) -> StepResult<ConfigCounts> {
+        Config::parse(source)?;
+        let tmp = self.backend_path.with_extension("tmp");
+        std::fs::write(&tmp, source)
+            .and_then(|()| std::fs::rename(&tmp, &self.backend_path))
+            .map_err(|e| format!("failed to write backend config: {e}"))?;
+        let result = self.reload_config();
+        match &result {
+            Ok(_) => log::info!("Backend config replaced and reloaded"),
+            Err(e) => log::error!("Backend config replaced but not reloaded: {e}"),
+        }
+        result
+    }
+
+    fn reload_config(&self) -> StepResult<ConfigCounts> {
+        let (mut new_config, source) = Config::load_with_source(&self.backend_path)?;
+        let mut config = self.config.write_or_recover();
+        new_config.resolve_upstreams(Some(&config));
+        if let Some(history) = &self.history
+            && let Err(e) = history.record(&source, Some(&config), &new_config)
+        {
+            log::warn!("Failed to record backend config history: {e}");
+        }
+        let before = (config.services.len(), config.backends.len());
+        let after = (new_config.services.len(), new_config.backends.len());
+        *config = new_config;
+        Ok((before, after))
+    }
+
+    fn reload_accounts(&self) -> StepResult<AccountCounts> {
+        let new_store = AccountLoader::new(&self.accounts_db)
+            .load_initial()
+            .map_err(|e| e.to_string())?;
+        let mut store = self.store.write_or_recover();
+        let before = store.counts();
+        let after = new_store.counts();
+        *store = new_store;
+        Ok((before, after))
+    }
+}
+
+/// Background service running a full reload on every SIGHUP.
+pub struct ReloadService {
+    reloader: Arc<RuntimeReloader>,
+}
+
+impl ReloadService {
+    pub fn new(reloader: Arc<RuntimeReloader>) -> Self {
+        Self { reloader }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ReloadService {
+    #[cfg(unix)]
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = hangup.recv() => {
+                    log::info!("SIGHUP received, reloading runtime state");
+                    self.reloader.reload_all();
+                }
+            }
+        }
+    }
+
+    // Without signals, reloads are triggered through the admin listener only.
+    #[cfg(not(unix))]
+    async fn start(&self, _shutdown: tokio::sync::watch::Receiver<bool>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const BACKEND: &str = r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "127.0.0.1"
+      port: 8000
+"#;
+
+    #[test]
+    fn reload_all_swaps_config_and_accounts() {
+        let mut backend = tempfile::NamedTempFile::new().unwrap();
+        backend.write_all(BACKEND.as_bytes()).unwrap();
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let conn = crate::sqlite::open_wal(db.path()).unwrap();
+        conn.execute_batch(include_str!("../sql/accounts.sql"))
+            .unwrap();
+
+        let config = Arc::new(RwLock::new(Config::load(backend.path()).unwrap()));
+        let store = Arc::new(RwLock::new(AccountStore::new()));
+        let reloader =
+            RuntimeReloader::new(backend.path(), config.clone(), db.path(), store.clone());
+
+        conn.execute(
+            "INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req) VALUES ('Free', 10, 1, 0.0)",
+            [],
+        )
+        .unwrap();
+        let summary = reloader.reload_all();
+
+        assert!(summary.is_ok());
+        assert_eq!(store.read().unwrap().counts(), (1, 0, 0));
+        assert_eq!(
+            summary.to_string(),
+            "config: services 1 -> 1, backends 1 -> 1; accounts: plans 0 -> 1, accounts 0 -> 0, \
+             keys 0 -> 0; usage: disabled; logs: nothing to reopen"
+        );
+    }
+
+    #[test]
+    fn failed_step_is_reported_without_stopping_others() {
+        let config = Arc::new(RwLock::new(Config::default()));
+        let store = Arc::new(RwLock::new(AccountStore::new()));
+        let reopened = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = reopened.clone();
+        let reloader =
+            RuntimeReloader::new("/nonexistent/backend.yml", config, "/nonexistent.db", store)
+                .with_log_reopen(Arc::new(move || {
+                    flag.store(true, std::sync::atomic::Ordering::SeqCst);
+                    Ok(())
+                }));
+
+        let summary = reloader.reload_all();
+        assert!(!summary.is_ok());
+        assert!(matches!(summary.config, Some(Err(_))));
+        assert!(matches!(summary.accounts, Some(Err(_))));
+        assert!(reopened.load(std::sync::atomic::Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,167 @@
+//! Replay protection for webhook receivers.
+//!
+//! A service with a `replay_protection` section identifies each delivery by the value of
+//! its `header`, such as a signature or nonce the sender puts on every request:
+//!
+//! ```yaml
+//! services:
+//!   payments-webhook:
+//!     path: /hooks/payments
+//!     auth: none
+//!     replay_protection:
+//!       header: webhook-id
+//!       ttl_secs: 300
+//! ```
+//!
+//! A request carrying a value the service accepted within `ttl_secs` is answered with a
+//! `409 Conflict` without reaching the upstream, as is one arriving while the first is in
+//! flight. Deliveries that do not get a `2xx` release their value, so the sender's retry
+//! goes through. Requests without the header are not checked; the upstream verifying
+//! signatures rejects them. Values are kept hashed, in the memory of each node.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use pingora::http::RequestHeader;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::sync::MutexExt;
+
+/// Counter of requests rejected as replays, labeled with the service.
+pub const REPLAYED_REQUESTS_COUNTER: &str = "replayed_requests";
+
+/// Values kept before expired ones are pruned.
+const PRUNE_AT: usize = 4096;
+
+/// Replay protection of a service.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ReplayProtectionConfig {
+    /// Header whose value identifies a delivery.
+    pub header: String,
+    /// How long an accepted value is remembered.
+    pub ttl_secs: u64,
+}
+
+impl Default for ReplayProtectionConfig {
+    fn default() -> Self {
+        Self {
+            header: "webhook-id".to_string(),
+            ttl_secs: 300,
+        }
+    }
+}
+
+impl ReplayProtectionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        http::HeaderName::from_bytes(self.header.as_bytes())
+            .map_err(|_| format!("invalid header '{}'", self.header))?;
+        if self.ttl_secs == 0 {
+            return Err("ttl_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+/// Key of a request to `service` in the replay cache; `None` without the header.
+pub fn replay_key(
+    service: &str,
+    config: &ReplayProtectionConfig,
+    req: &RequestHeader,
+) -> Option<String> {
+    let value = req.headers.get(config.header.as_str())?;
+    Some(format!(
+        "{service} {}",
+        hex::encode(Sha256::digest(value.as_bytes()))
+    ))
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    expires_at: HashMap<String, Instant>,
+    prune_at: usize,
+}
+
+/// Delivery keys accepted across services, until they expire.
+#[derive(Debug, Default)]
+pub struct ReplayCache {
+    seen: Mutex<Seen>,
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `key` for `ttl`; false when it is claimed already, the request being a replay.
+    pub fn claim(&self, key: String, ttl: Duration, now: Instant) -> bool {
+        let mut seen = self.seen.lock_or_recover();
+        if seen.expires_at.len() >= seen.prune_at.max(PRUNE_AT) {
+            seen.expires_at.retain(|_, expires_at| *expires_at > now);
+            seen.prune_at = seen.expires_at.len() * 2;
+        }
+        match seen.expires_at.get(&key) {
+            Some(expires_at) if *expires_at > now => false,
+            _ => {
+                seen.expires_at.insert(key, now + ttl);
+                true
+            }
+        }
+    }
+
+    /// Forget a claimed key, so the request may be sent again.
+    pub fn release(&self, key: &str) {
+        self.seen.lock_or_recover().expires_at.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliveries_are_accepted_once_within_their_ttl() {
+        let config = ReplayProtectionConfig::default();
+        let mut req = RequestHeader::build("POST", b"/hooks", None).unwrap();
+        assert_eq!(replay_key("hooks", &config, &req), None);
+        req.insert_header("webhook-id", "msg_1").unwrap();
+        let key = replay_key("hooks", &config, &req).unwrap();
+        assert!(!key.contains("msg_1"));
+        assert_ne!(replay_key("other", &config, &req), Some(key.clone()));
+
+        let cache = ReplayCache::new();
+        let now = Instant::now();
+        assert!(cache.claim(key.clone(), config.ttl(), now));
+        assert!(!cache.claim(key.clone(), config.ttl(), now + Duration::from_secs(299)));
+        // Expired keys are accepted again
+        assert!(cache.claim(key.clone(), config.ttl(), now + Duration::from_secs(300)));
+
+        // A released key, of a failed delivery, may be retried at once
+        cache.release(&key);
+        assert!(cache.claim(key, config.ttl(), now + Duration::from_secs(301)));
+    }
+
+    #[test]
+    fn test_validate_replay_protection() {
+        assert_eq!(ReplayProtectionConfig::default().validate(), Ok(()));
+        let config = ReplayProtectionConfig {
+            header: "bad header".to_string(),
+            ttl_secs: 60,
+        };
+        assert_eq!(
+            config.validate(),
+            Err("invalid header 'bad header'".to_string())
+        );
+        let config = ReplayProtectionConfig {
+            ttl_secs: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
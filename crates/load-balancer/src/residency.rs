@@ -0,0 +1,119 @@
+//! Data residency of usage records.
+//!
+//! Accounts with a `residency` region in the accounts DB have their usage rows and key
+//! activity written to the usage directory of that region instead of `usage_dir`, so
+//! billing data of an account is only ever stored where it was committed to:
+//!
+//! ```yaml
+//! usage_dir: usage
+//! usage_residency:
+//!   dirs:
+//!     eu: /mnt/eu/usage
+//!     us: /mnt/us/usage
+//! ```
+//!
+//! Every region directory holds hourly files and manifests of its own, closed, purged and
+//! rolled up like those of `usage_dir`. Accounts without a region, or with a region that
+//! has no directory, stay in `usage_dir`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::AccountStore;
+use crate::sync::RwLockExt;
+
+/// Usage directories per residency region.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UsageResidencyConfig {
+    /// Region name, as in the `residency` column of Accounts -> usage directory.
+    pub dirs: BTreeMap<String, String>,
+}
+
+impl UsageResidencyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(region) = self
+            .dirs
+            .iter()
+            .find(|(region, dir)| region.is_empty() || dir.is_empty())
+            .map(|(region, _)| region)
+        {
+            return Err(format!(
+                "usage_residency.dirs.{region} needs a region name and a directory"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Picks the usage directory of each account from its residency region.
+#[derive(Debug)]
+pub struct UsageResidency {
+    dirs: BTreeMap<String, PathBuf>,
+    store: Arc<RwLock<AccountStore>>,
+}
+
+impl UsageResidency {
+    /// Route accounts of `store` to the directories of their regions in `dirs`.
+    pub fn new(dirs: BTreeMap<String, PathBuf>, store: Arc<RwLock<AccountStore>>) -> Self {
+        Self { dirs, store }
+    }
+
+    /// Directory of the region of `account_id`; `None` for the default usage directory.
+    pub fn dir_for(&self, account_id: i64) -> Option<&Path> {
+        let store = self.store.read_or_recover();
+        let region = store.residency(account_id)?;
+        let dir = self.dirs.get(region);
+        if dir.is_none() {
+            log::debug!("No usage directory for region {region} of account {account_id}");
+        }
+        dir.map(PathBuf::as_path)
+    }
+
+    /// Directories of every region.
+    pub fn dirs(&self) -> impl Iterator<Item = &Path> {
+        self.dirs.values().map(PathBuf::as_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Account;
+
+    #[test]
+    fn test_accounts_are_routed_by_region() {
+        let mut store = AccountStore::new();
+        for (account_id, residency) in [(1, None), (2, Some("eu")), (3, Some("apac"))] {
+            store.upsert_account(Account {
+                account_id,
+                email: format!("{account_id}@example.com"),
+                plan_id: 1,
+                billing_status: "active".to_string(),
+                residency: residency.map(str::to_string),
+            });
+        }
+        let residency = UsageResidency::new(
+            BTreeMap::from([("eu".to_string(), PathBuf::from("/usage/eu"))]),
+            Arc::new(RwLock::new(store)),
+        );
+
+        assert_eq!(residency.dir_for(1), None);
+        assert_eq!(residency.dir_for(2), Some(Path::new("/usage/eu")));
+        // Regions without a directory stay in the default one
+        assert_eq!(residency.dir_for(3), None);
+        assert_eq!(residency.dir_for(4), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(UsageResidencyConfig::default().validate().is_ok());
+        let empty = UsageResidencyConfig {
+            dirs: BTreeMap::from([("eu".to_string(), String::new())]),
+        };
+        assert!(empty.validate().is_err());
+    }
+}
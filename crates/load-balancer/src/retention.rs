@@ -0,0 +1,403 @@
+//! Retention and deletion of usage data.
+//!
+//! With a `usage_retention` section a background purger deletes hourly usage files (with
+//! their manifests) `hourly_days` after their hour, and the monthly billing rollups
+//! written to the usage directory (`billing-YYYYMM.db` or `.csv`) `rollup_days` after
+//! their month. Billing reads the hourly files of the month it bills, so `hourly_days`
+//! must cover the time until the month is billed.
+//!
+//! [`delete_account`] removes every usage row, key activity row and billing line item of
+//! one account, to honor deletion requests; the manifests of closed hours it changes are
+//! rewritten so billing still verifies them.
+//!
+//! With `shred`, purged files are overwritten with zeros before they are unlinked, and
+//! deleted rows are zeroed by SQLite's `secure_delete`. This only reaches storage that
+//! overwrites in place; copy-on-write file systems and SSD wear levelling may keep old
+//! blocks.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::sqlite;
+use crate::usage::{close_hour, hour_of_stamp};
+
+/// Suffixes of the files making up an hourly usage file.
+const HOURLY_SUFFIXES: [&str; 4] = [".db", ".db-wal", ".db-shm", ".manifest.json"];
+/// Suffixes of the files making up a billing rollup.
+const ROLLUP_SUFFIXES: [&str; 4] = [".db", ".db-wal", ".db-shm", ".csv"];
+
+/// How long usage data is kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UsageRetentionConfig {
+    /// Days hourly usage files are kept after their hour; forever when unset.
+    pub hourly_days: Option<u64>,
+    /// Days billing rollups are kept after their month; forever when unset.
+    pub rollup_days: Option<u64>,
+    /// Overwrite purged files and deleted rows with zeros.
+    pub shred: bool,
+}
+
+impl UsageRetentionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hourly_days == Some(0) {
+            return Err("usage_retention.hourly_days must be at least 1".to_string());
+        }
+        if self.rollup_days == Some(0) {
+            return Err("usage_retention.rollup_days must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Usage data of an account removed by [`delete_account`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AccountDeletion {
+    pub usage_rows: u64,
+    pub activity_rows: u64,
+    pub line_items: u64,
+    /// Files that held data of the account.
+    pub files: Vec<String>,
+}
+
+impl AccountDeletion {
+    /// Add what was deleted from another usage directory.
+    pub fn merge(&mut self, other: AccountDeletion) {
+        self.usage_rows += other.usage_rows;
+        self.activity_rows += other.activity_rows;
+        self.line_items += other.line_items;
+        self.files.extend(other.files);
+    }
+}
+
+/// End of the hour or month a usage or billing file covers, for the files that make up
+/// hourly usage files and billing rollups.
+fn covered_until(name: &str) -> Option<(i64, bool)> {
+    if let Some(rest) = name.strip_prefix("usage-") {
+        let (stamp, suffix) = rest.split_at_checked(10)?;
+        HOURLY_SUFFIXES.contains(&suffix).then_some(())?;
+        return Some((hour_of_stamp(stamp)? + 3600, true));
+    }
+    let (stamp, suffix) = name.strip_prefix("billing-")?.split_at_checked(6)?;
+    ROLLUP_SUFFIXES.contains(&suffix).then_some(())?;
+    let month = chrono::NaiveDate::parse_from_str(&format!("{stamp}01"), "%Y%m%d").ok()?;
+    let next = month.checked_add_months(chrono::Months::new(1))?;
+    Some((next.and_hms_opt(0, 0, 0)?.and_utc().timestamp(), false))
+}
+
+/// Delete `path`, first overwriting it with zeros when `shred` is set.
+fn remove_file(path: &Path, shred: bool) -> std::io::Result<()> {
+    if shred {
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        let mut left = file.metadata()?.len();
+        let zeros = [0u8; 64 * 1024];
+        while left > 0 {
+            let n = left.min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[..n])?;
+            left -= n as u64;
+        }
+        file.sync_all()?;
+    }
+    std::fs::remove_file(path)
+}
+
+/// Files of `dir` by name, sorted.
+fn file_names(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// Rows of `table` in `conn` deleted for `account_id`; 0 when the table does not exist.
+fn delete_rows(
+    conn: &rusqlite::Connection,
+    table: &str,
+    account_id: i64,
+) -> Result<u64, rusqlite::Error> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    let deleted = conn.execute(
+        &format!("DELETE FROM {table} WHERE account_id = ?1"),
+        [account_id],
+    )?;
+    Ok(deleted as u64)
+}
+
+/// Delete every usage row, key activity row and billing line item of `account_id` in
+/// `usage_dir`.
+pub fn delete_account(
+    usage_dir: &Path,
+    account_id: i64,
+    shred: bool,
+) -> std::io::Result<AccountDeletion> {
+    let mut deletion = AccountDeletion::default();
+    for name in file_names(usage_dir)? {
+        let path = usage_dir.join(&name);
+        if let Some(stamp) = name
+            .strip_prefix("usage-")
+            .and_then(|rest| rest.strip_suffix(".db"))
+        {
+            let Some(hour) = hour_of_stamp(stamp) else {
+                continue;
+            };
+            let conn = sqlite::open_existing(&path).map_err(std::io::Error::other)?;
+            conn.pragma_update(None, "secure_delete", shred)
+                .map_err(std::io::Error::other)?;
+            let usage = delete_rows(&conn, "Usage", account_id).map_err(std::io::Error::other)?;
+            let activity =
+                delete_rows(&conn, "KeyActivity", account_id).map_err(std::io::Error::other)?;
+            drop(conn);
+            if usage + activity == 0 {
+                continue;
+            }
+            deletion.usage_rows += usage;
+            deletion.activity_rows += activity;
+            // Closed hours get a manifest matching their new content
+            if usage_dir
+                .join(format!("usage-{stamp}.manifest.json"))
+                .exists()
+            {
+                close_hour(usage_dir, hour)?;
+            }
+            deletion.files.push(name);
+        } else if name.starts_with("billing-") && name.ends_with(".db") {
+            let conn = sqlite::open_existing(&path).map_err(std::io::Error::other)?;
+            conn.pragma_update(None, "secure_delete", shred)
+                .map_err(std::io::Error::other)?;
+            let items =
+                delete_rows(&conn, "LineItems", account_id).map_err(std::io::Error::other)?;
+            if items > 0 {
+                deletion.line_items += items;
+                deletion.files.push(name);
+            }
+        } else if name.starts_with("billing-") && name.ends_with(".csv") {
+            let content = std::fs::read_to_string(&path)?;
+            let account = account_id.to_string();
+            let (mut kept, mut removed) = (String::new(), 0);
+            for (i, line) in content.lines().enumerate() {
+                // The first column of rows after the header is the account id
+                if i > 0 && line.split(',').next() == Some(account.as_str()) {
+                    removed += 1;
+                } else {
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+            }
+            if removed == 0 {
+                continue;
+            }
+            if shred {
+                remove_file(&path, true)?;
+            }
+            std::fs::write(&path, kept)?;
+            deletion.line_items += removed;
+            deletion.files.push(name);
+        }
+    }
+    Ok(deletion)
+}
+
+/// Background service deleting usage files past their retention.
+pub struct UsagePurger {
+    usage_dir: PathBuf,
+    config: UsageRetentionConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl UsagePurger {
+    pub fn new(usage_dir: impl Into<PathBuf>, config: UsageRetentionConfig) -> Self {
+        Self {
+            usage_dir: usage_dir.into(),
+            config,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use `clock` for file ages instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Delete the files past their retention; the names of the files deleted.
+    pub fn run_once(&self) -> std::io::Result<Vec<String>> {
+        let now = self.clock.unix_secs();
+        let cutoff = |days: Option<u64>| days.map(|days| now - days as i64 * 86_400);
+        let (hourly, rollup) = (
+            cutoff(self.config.hourly_days),
+            cutoff(self.config.rollup_days),
+        );
+        let mut purged = Vec::new();
+        for name in file_names(&self.usage_dir)? {
+            let Some((until, is_hourly)) = covered_until(&name) else {
+                continue;
+            };
+            let cutoff = if is_hourly { hourly } else { rollup };
+            if cutoff.is_some_and(|cutoff| until <= cutoff) {
+                remove_file(&self.usage_dir.join(&name), self.config.shred)?;
+                purged.push(name);
+            }
+        }
+        Ok(purged)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UsagePurger {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        loop {
+            match self.run_once() {
+                Ok(purged) if purged.is_empty() => {}
+                Ok(purged) => log::info!("Purged {} expired usage files", purged.len()),
+                Err(e) => log::error!("Failed to purge usage files: {}", e),
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(Duration::from_secs(3600)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+    use crate::usage::{UsageRoute, UsageTracker, UsageWriter, closed_files};
+    use sha2::Digest;
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    fn record(tracker: &UsageTracker, account_id: i64, ts: i64) {
+        let route = UsageRoute {
+            service: "geocode".to_string(),
+            backend: "basic".to_string(),
+            labels: String::new(),
+        };
+        tracker.record(
+            account_id,
+            Uuid::from_u128(account_id as u128),
+            1,
+            &route,
+            10,
+            ts,
+        );
+        tracker.touch_key(account_id, Uuid::from_u128(account_id as u128), None, ts);
+    }
+
+    #[test]
+    fn test_expired_files_are_purged() {
+        let dir = TempDir::new().unwrap();
+        for name in [
+            "usage-2024060100.db",
+            "usage-2024060100.manifest.json",
+            "usage-2024061023.db",
+            "billing-202404.db",
+            "billing-202405.csv",
+            "accounts.db",
+        ] {
+            std::fs::write(dir.path().join(name), b"data").unwrap();
+        }
+        // 2024-06-11 00:00 UTC
+        let clock = Arc::new(TestClock::at_unix(1_718_064_000));
+        let purger = UsagePurger::new(
+            dir.path(),
+            UsageRetentionConfig {
+                hourly_days: Some(1),
+                rollup_days: Some(31),
+                shred: true,
+            },
+        )
+        .with_clock(clock);
+        assert_eq!(
+            purger.run_once().unwrap(),
+            [
+                "billing-202404.db",
+                "usage-2024060100.db",
+                "usage-2024060100.manifest.json"
+            ]
+        );
+        assert_eq!(
+            file_names(dir.path()).unwrap(),
+            ["accounts.db", "billing-202405.csv", "usage-2024061023.db"]
+        );
+    }
+
+    #[test]
+    fn test_account_usage_is_deleted_and_manifests_rewritten() {
+        let dir = TempDir::new().unwrap();
+        let tracker = Arc::new(UsageTracker::new());
+        let writer = UsageWriter::new(tracker.clone(), dir.path());
+        record(&tracker, 1, 3700);
+        record(&tracker, 2, 3700);
+        record(&tracker, 1, 7300);
+        writer.flush_all().unwrap();
+        close_hour(dir.path(), 3600).unwrap();
+        std::fs::write(
+            dir.path().join("billing-197001.csv"),
+            "account_id,plan_id\n1,1\n2,1\n",
+        )
+        .unwrap();
+        // Not yet written to disk
+        record(&tracker, 1, 7400);
+        tracker.forget_account(1);
+        assert_eq!(tracker.drain_all().len(), 0);
+
+        let deletion = delete_account(dir.path(), 1, true).unwrap();
+        assert_eq!(
+            deletion,
+            AccountDeletion {
+                usage_rows: 2,
+                activity_rows: 1,
+                line_items: 1,
+                files: vec![
+                    "billing-197001.csv".to_string(),
+                    "usage-1970010101.db".to_string(),
+                    "usage-1970010102.db".to_string(),
+                ],
+            }
+        );
+        let manifest = &closed_files(dir.path()).unwrap()[0];
+        assert_eq!((manifest.usage_rows, manifest.activity_rows), (1, 1));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("billing-197001.csv")).unwrap(),
+            "account_id,plan_id\n2,1\n"
+        );
+        // Files without rows of the account are left as they are
+        assert_eq!(
+            delete_account(dir.path(), 1, false).unwrap(),
+            AccountDeletion::default()
+        );
+        let bytes = std::fs::read(dir.path().join("usage-1970010101.db")).unwrap();
+        assert_eq!(
+            manifest.sha256.as_deref(),
+            Some(hex::encode(sha2::Sha256::digest(&bytes)).as_str())
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(UsageRetentionConfig::default().validate().is_ok());
+        let zero = UsageRetentionConfig {
+            hourly_days: Some(0),
+            ..Default::default()
+        };
+        assert!(zero.validate().is_err());
+    }
+}
@@ -0,0 +1,584 @@
+//! Path routing compiled from a backend config.
+//!
+//! A request goes to the service whose path prefix is the longest prefix of the request
+//! path. Prefixes are plain string prefixes, so `/geocode` also matches `/geocoder`.
+//!
+//! Services may share a prefix when they set different [`RoutePredicates`] under `match`,
+//! to route by method, header or query parameter as well:
+//!
+//! ```yaml
+//! services:
+//!   ingest-writer: { path: /ingest, match: { methods: [POST, PUT] } }
+//!   ingest-reader: /ingest
+//! ```
+//!
+//! Of the services of the longest matching prefix, those with predicates are tried first,
+//! by name, and the one without predicates takes the remaining requests. When none of them
+//! matches, shorter prefixes are tried. [`Config::validate`] rejects two services with the
+//! same prefix and predicates; the table still keeps the service with the lowest name for
+//! such ties so lookups never depend on map iteration order.
+//!
+//! A service may give a `path_regex` instead of a prefix, matched against the whole path,
+//! and a `rewrite` template for the path sent upstream, with `$1` or `${name}` standing for
+//! the groups the regex captured (the query string is kept):
+//!
+//! ```yaml
+//! services:
+//!   geocode-v1: { path_regex: "/v1/geocode/(.*)", rewrite: "/$1" }
+//! ```
+//!
+//! Regex services are tried before prefixes, by name, and the first matching one wins.
+//!
+//! A prefix service may instead drop its prefix from the upstream path with
+//! `strip_prefix: true`, or replace it with `rewrite_prefix`:
+//!
+//! ```yaml
+//! services:
+//!   geocode: { path: /geocode, strip_prefix: true }        # /geocode/forward -> /forward
+//!   search: { path: /search, rewrite_prefix: /internal }   # /search/q -> /internal/q
+//! ```
+//!
+//! The table is built once per (re)load into a byte trie, so a lookup costs one step per
+//! byte of the request path whatever the number of routes. Each [`Route`] carries what the
+//! proxy needs per request, including the [`ServicePool`] that decides which endpoint
+//! serves it from the weights and priorities of the service's backends.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use pingora::http::RequestHeader;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::canary::TrafficGroup;
+use crate::configuration::{AuthMode, Config, FallbackResponseConfig};
+use crate::openapi::OpenApiSpec;
+use crate::replay::ReplayProtectionConfig;
+use crate::shedding::LoadSheddingConfig;
+use crate::static_cache::StaticCacheConfig;
+use crate::upstream::ServicePool;
+
+/// Conditions besides the path prefix a request must meet to go to a service. Empty ones
+/// match every request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutePredicates {
+    /// HTTP methods the request may have.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Headers the request must carry, with these values.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Query parameters the request must carry, with these values.
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+}
+
+impl RoutePredicates {
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty() && self.headers.is_empty() && self.query.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(method) = self
+            .methods
+            .iter()
+            .find(|m| http::Method::from_bytes(m.as_bytes()).is_err())
+        {
+            return Err(format!("invalid method '{method}'"));
+        }
+        if let Some(name) = self
+            .headers
+            .keys()
+            .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            return Err(format!("invalid header name '{name}'"));
+        }
+        Ok(())
+    }
+
+    /// Whether `req` meets every condition.
+    pub fn matches(&self, req: &RequestHeader) -> bool {
+        let method = req.method.as_str();
+        (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            && self.headers.iter().all(|(name, value)| {
+                req.headers
+                    .get_all(name.as_str())
+                    .iter()
+                    .any(|v| v.as_bytes() == value.as_bytes())
+            })
+            && self.query.iter().all(|(name, value)| {
+                req.uri.query().unwrap_or_default().split('&').any(|pair| {
+                    let (key, actual) = pair.split_once('=').unwrap_or((pair, ""));
+                    key == name && actual == value
+                })
+            })
+    }
+}
+
+/// A routed service, resolved against the backends of the config.
+#[derive(Debug)]
+pub struct Route {
+    pub service: String,
+    /// Path prefix of the route; empty for regex routes.
+    pub path: String,
+    /// Conditions on the request besides the path prefix.
+    pub predicates: RoutePredicates,
+    /// Regex matching the whole path, routing instead of a prefix.
+    pub path_regex: Option<Regex>,
+    /// Template of the upstream path, expanded with the captures of `path_regex`.
+    pub rewrite: Option<String>,
+    /// What `path` is replaced with in the upstream path, empty to strip it.
+    pub prefix_rewrite: Option<String>,
+    pub auth: AuthMode,
+    /// Requests per second allowed per client IP when `auth` is `none`.
+    pub ip_rps_limit: isize,
+    /// Time a request may take from its arrival, retries included.
+    pub timeout: Option<Duration>,
+    /// Retries a request may take in total.
+    pub retry_budget: Option<u32>,
+    /// Kind of the service's first backend, recorded with its usage.
+    pub backend: &'static str,
+    /// Static labels of the service as `key=value,...` in key order; empty without labels.
+    pub labels: String,
+    /// Label of the route in metrics: `service=<name>` followed by the static labels.
+    pub metric_label: String,
+    /// Caching of the service's responses, if enabled.
+    pub static_cache: Option<StaticCacheConfig>,
+    /// Operations requests to the service must match, if the service has a spec.
+    pub openapi: Option<Arc<OpenApiSpec>>,
+    /// Shedding of the service's requests, if enabled.
+    pub load_shedding: Option<LoadSheddingConfig>,
+    /// Rejection of replayed deliveries, if enabled.
+    pub replay_protection: Option<ReplayProtectionConfig>,
+    /// Whether responses disagreeing with their `Content-Length` are kept from clients.
+    pub reject_truncated: bool,
+    /// Whether the service's requests are answered with a 503 instead of proxied.
+    pub maintenance: bool,
+    /// Response to requests the service's backends fail, if set.
+    pub fallback_response: Option<FallbackResponseConfig>,
+    /// Endpoints of the service; `None` until [`Config::resolve_upstreams`] has run.
+    pub pool: Option<Arc<ServicePool>>,
+    /// Endpoints of the service dedicated to accounts, by pool name.
+    pub dedicated: HashMap<String, Arc<ServicePool>>,
+    /// Endpoints receiving a copy of the service's requests.
+    pub shadow: Option<Arc<ServicePool>>,
+    /// Endpoints serving a share of the service's keys, by group in name order.
+    pub groups: Vec<TrafficGroup>,
+    /// Endpoints serving the requests the others fail.
+    pub fallback: Option<Arc<ServicePool>>,
+}
+
+impl Route {
+    /// The upstream path of a request for `path`, if the route rewrites it.
+    pub fn rewrite_path(&self, path: &str) -> Option<String> {
+        if let Some(prefix) = &self.prefix_rewrite {
+            let rest = path.strip_prefix(self.path.as_str())?;
+            let rewritten = format!("{prefix}{rest}");
+            return Some(match rewritten.starts_with('/') {
+                true => rewritten,
+                false => format!("/{rewritten}"),
+            });
+        }
+        let (regex, rewrite) = (self.path_regex.as_ref()?, self.rewrite.as_ref()?);
+        let captures = regex.captures(path)?;
+        let mut rewritten = String::new();
+        captures.expand(rewrite, &mut rewritten);
+        Some(rewritten)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        self.path_regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(path))
+    }
+}
+
+/// Compile `pattern` to match whole paths.
+pub fn path_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{pattern})$"))
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Sorted by byte.
+    children: Vec<(u8, usize)>,
+    /// Routes of the prefix, those with predicates first.
+    routes: Vec<usize>,
+}
+
+/// Service path prefixes in a byte trie.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    nodes: Vec<Node>,
+    routes: Vec<Arc<Route>>,
+    /// Routes by path regex, in name order.
+    regex_routes: Vec<usize>,
+    by_service: HashMap<String, usize>,
+    fallback: Option<Arc<Route>>,
+}
+
+impl RoutingTable {
+    pub fn new(config: &Config) -> Self {
+        let mut names: Vec<&String> = config.services.keys().collect();
+        names.sort();
+
+        let mut table = Self {
+            nodes: vec![Node::default()],
+            ..Default::default()
+        };
+        for name in names {
+            let service = &config.services[name];
+            let labels = service
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            let route = Arc::new(Route {
+                service: name.clone(),
+                path: service.path.clone(),
+                predicates: service.predicates.clone(),
+                // Invalid regexes are rejected by Config::validate
+                path_regex: service
+                    .path_regex
+                    .as_deref()
+                    .and_then(|pattern| path_regex(pattern).ok()),
+                rewrite: service.rewrite.clone(),
+                prefix_rewrite: match service.strip_prefix {
+                    true => Some(String::new()),
+                    false => service.rewrite_prefix.clone(),
+                },
+                auth: service.auth,
+                ip_rps_limit: service.ip_rps_limit,
+                timeout: service.timeout_ms.map(Duration::from_millis),
+                retry_budget: service.retry_budget,
+                static_cache: service.static_cache.clone(),
+                load_shedding: service.load_shedding.clone(),
+                replay_protection: service.replay_protection.clone(),
+                reject_truncated: service.reject_truncated,
+                maintenance: service.maintenance,
+                fallback_response: service.fallback_response.clone(),
+                openapi: config.openapi.get(name).cloned(),
+                backend: config
+                    .backends
+                    .iter()
+                    .find(|b| &b.service == name && !b.shadow && !b.fallback)
+                    .map_or("", |b| b.backend.kind()),
+                pool: config.pools.get(name).cloned(),
+                dedicated: config
+                    .dedicated_pools
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default(),
+                shadow: config.shadow_pools.get(name).cloned(),
+                groups: config.traffic_groups.get(name).cloned().unwrap_or_default(),
+                fallback: config.fallback_pools.get(name).cloned(),
+                metric_label: std::iter::once(format!("service={name}"))
+                    .chain((!labels.is_empty()).then(|| labels.clone()))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                labels,
+            });
+            if config.route_miss.fallback_service.as_ref() == Some(name) {
+                table.fallback = Some(route.clone());
+            }
+            if route.path_regex.is_some() {
+                table.regex_routes.push(table.routes.len());
+                table.by_service.insert(name.clone(), table.routes.len());
+                table.routes.push(route);
+            } else if service.path_regex.is_none() {
+                table.insert(&service.path, route);
+            }
+        }
+        table
+    }
+
+    fn insert(&mut self, prefix: &str, route: Arc<Route>) {
+        let mut node = 0;
+        for &byte in prefix.as_bytes() {
+            node = match self.nodes[node]
+                .children
+                .binary_search_by_key(&byte, |&(b, _)| b)
+            {
+                Ok(i) => self.nodes[node].children[i].1,
+                Err(i) => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[node].children.insert(i, (byte, child));
+                    child
+                }
+            };
+        }
+        // Names are inserted in order, so the first service keeps a duplicated prefix
+        let routes = &self.nodes[node].routes;
+        if routes
+            .iter()
+            .any(|&i| self.routes[i].predicates == route.predicates)
+        {
+            return;
+        }
+        let position = match route.predicates.is_empty() {
+            true => routes.len(),
+            false => routes
+                .iter()
+                .position(|&i| self.routes[i].predicates.is_empty())
+                .unwrap_or(routes.len()),
+        };
+        self.nodes[node].routes.insert(position, self.routes.len());
+        self.by_service
+            .insert(route.service.clone(), self.routes.len());
+        self.routes.push(route);
+    }
+
+    /// The route with the longest prefix of `path` among the routes `accept` takes.
+    fn resolve_with(&self, path: &str, accept: impl Fn(&Route) -> bool) -> Option<&Arc<Route>> {
+        if let Some(route) = self
+            .regex_routes
+            .iter()
+            .map(|&i| &self.routes[i])
+            .find(|route| route.matches_path(path) && accept(route))
+        {
+            return Some(route);
+        }
+        let first = |node: &Node| {
+            node.routes
+                .iter()
+                .map(|&i| &self.routes[i])
+                .find(|route| accept(route))
+        };
+        let mut node = self.nodes.first()?;
+        let mut best = first(node);
+        for &byte in path.as_bytes() {
+            match node.children.binary_search_by_key(&byte, |&(b, _)| b) {
+                Ok(i) => node = &self.nodes[node.children[i].1],
+                Err(_) => break,
+            }
+            best = first(node).or(best);
+        }
+        best
+    }
+
+    /// The route without predicates with the longest prefix of `path`.
+    pub fn resolve(&self, path: &str) -> Option<&Arc<Route>> {
+        self.resolve_with(path, |route| route.predicates.is_empty())
+    }
+
+    /// The route with the longest prefix of the path of `req` whose predicates it meets.
+    pub fn resolve_request(&self, req: &RequestHeader) -> Option<&Arc<Route>> {
+        self.resolve_with(req.uri.path(), |route| route.predicates.matches(req))
+    }
+
+    /// The route of a service by name.
+    pub fn service(&self, name: &str) -> Option<&Arc<Route>> {
+        self.by_service.get(name).map(|&i| &self.routes[i])
+    }
+
+    /// The route of the configured fallback service.
+    pub fn fallback(&self) -> Option<&Arc<Route>> {
+        self.fallback.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(routes: &[(&str, &str)]) -> RoutingTable {
+        let yaml: String = routes
+            .iter()
+            .map(|(name, path)| format!("  {name}: {path}\n"))
+            .collect();
+        let config: Config =
+            serde_yaml::from_str(&format!("services:\n{yaml}backends: []\n")).unwrap();
+        RoutingTable::new(&config)
+    }
+
+    fn resolve<'a>(table: &'a RoutingTable, path: &str) -> Option<&'a str> {
+        table.resolve(path).map(|route| route.service.as_str())
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let table = table(&[
+            ("root", "/"),
+            ("geocode", "/geocode"),
+            ("reverse", "/geocode/reverse"),
+        ]);
+        assert_eq!(table.len(), 3);
+        assert_eq!(resolve(&table, "/geocode/reverse?lat=1"), Some("reverse"));
+        assert_eq!(resolve(&table, "/geocode/rev"), Some("geocode"));
+        assert_eq!(resolve(&table, "/geocoder"), Some("geocode"));
+        assert_eq!(resolve(&table, "/other"), Some("root"));
+        assert_eq!(resolve(&table, ""), None);
+        assert!(RoutingTable::default().resolve("/").is_none());
+    }
+
+    #[test]
+    fn test_equal_prefixes_resolve_by_name() {
+        for _ in 0..10 {
+            let table = table(&[("b", "/api"), ("a", "/api"), ("c", "/")]);
+            assert_eq!(resolve(&table, "/api/v1"), Some("a"));
+            assert_eq!(table.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_predicates_split_a_prefix() {
+        let yaml = r#"
+        services:
+          writer: { path: /ingest, match: { methods: [POST, put] } }
+          tenant: { path: /ingest, match: { headers: { x-tenant: acme }, query: { v: "2" } } }
+          reader: /ingest
+          root: { path: /, match: { methods: [GET] } }
+        backends: []
+        "#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let table = RoutingTable::new(&config);
+        let request = |method: &str, uri: &str, tenant: Option<&str>| {
+            let mut req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+            if let Some(tenant) = tenant {
+                req.insert_header("x-tenant", tenant).unwrap();
+            }
+            req
+        };
+        let resolve = |req: &RequestHeader| {
+            table
+                .resolve_request(req)
+                .map(|route| route.service.as_str())
+        };
+
+        assert_eq!(table.len(), 4);
+        assert_eq!(resolve(&request("POST", "/ingest", None)), Some("writer"));
+        assert_eq!(resolve(&request("PUT", "/ingest/a", None)), Some("writer"));
+        assert_eq!(resolve(&request("GET", "/ingest", None)), Some("reader"));
+        let tenant = request("GET", "/ingest?x=1&v=2", Some("acme"));
+        assert_eq!(resolve(&tenant), Some("tenant"));
+        let other = request("GET", "/ingest?v=2", Some("globex"));
+        assert_eq!(resolve(&other), Some("reader"));
+        // Shorter prefixes are tried when no route of the longest one matches
+        assert_eq!(resolve(&request("GET", "/tiles", None)), Some("root"));
+        assert_eq!(resolve(&request("POST", "/tiles", None)), None);
+        // Path lookups only see routes without predicates
+        assert_eq!(table.resolve("/ingest").unwrap().service, "reader");
+        assert!(table.resolve("/tiles").is_none());
+    }
+
+    #[test]
+    fn test_regex_routes_rewrite_paths() {
+        let yaml = r#"
+        services:
+          legacy: { path_regex: "/v1/(?P<rest>.*)", rewrite: "/api/${rest}" }
+          items: { path_regex: "/items/([0-9]+)/(\\w+)", rewrite: "/$2?id=$1" }
+          plain: { path_regex: "/health" }
+          api: /api
+        backends: []
+        "#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let table = RoutingTable::new(&config);
+        assert_eq!(table.len(), 4);
+
+        let route = table.resolve("/v1/geocode/reverse").unwrap();
+        assert_eq!(route.service, "legacy");
+        assert_eq!(
+            route.rewrite_path("/v1/geocode/reverse").as_deref(),
+            Some("/api/geocode/reverse")
+        );
+        let route = table.resolve("/items/42/photos").unwrap();
+        assert_eq!(
+            route.rewrite_path("/items/42/photos").as_deref(),
+            Some("/photos?id=42")
+        );
+        // Regexes match whole paths
+        assert_eq!(resolve(&table, "/items/42"), None);
+        assert_eq!(resolve(&table, "/x/v1/a"), None);
+        assert_eq!(resolve(&table, "/api/v1"), Some("api"));
+        let health = table.resolve("/health").unwrap();
+        assert_eq!(health.rewrite_path("/health"), None);
+        assert_eq!(table.service("legacy").unwrap().service, "legacy");
+    }
+
+    #[test]
+    fn test_prefix_routes_strip_or_replace_their_prefix() {
+        let yaml = r#"
+        services:
+          geocode: { path: /geocode, strip_prefix: true }
+          search: { path: /search/, rewrite_prefix: /internal/ }
+          plain: /plain
+        backends: []
+        "#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let table = RoutingTable::new(&config);
+
+        let geocode = table.service("geocode").unwrap();
+        assert_eq!(
+            geocode.rewrite_path("/geocode/forward").as_deref(),
+            Some("/forward")
+        );
+        assert_eq!(geocode.rewrite_path("/geocode").as_deref(), Some("/"));
+        // Prefixes match within a segment too
+        assert_eq!(geocode.rewrite_path("/geocoder").as_deref(), Some("/r"));
+        // The fallback route does not rewrite paths of other prefixes
+        assert_eq!(geocode.rewrite_path("/other"), None);
+
+        let search = table.service("search").unwrap();
+        assert_eq!(
+            search.rewrite_path("/search/q").as_deref(),
+            Some("/internal/q")
+        );
+        assert_eq!(
+            table.service("plain").unwrap().rewrite_path("/plain/a"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_routes_carry_backend_and_pool() {
+        let yaml = r#"
+        services:
+          geocode:
+            path: /geocode
+            labels: {tier: gold, team: geo}
+          catchall: /
+        backends:
+          - service: geocode
+            backend:
+              type: basic
+              ip: 127.0.0.1
+              port: 8099
+          - service: catchall
+            backend:
+              type: basic
+              ip: 127.0.0.1
+              port: 8098
+        route_miss:
+          fallback_service: catchall
+        "#;
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        config.resolve_upstreams(None);
+
+        let route = config.routes.resolve("/geocode/v1").unwrap();
+        assert_eq!(route.backend, "basic");
+        assert_eq!(route.labels, "team=geo,tier=gold");
+        assert_eq!(route.metric_label, "service=geocode,team=geo,tier=gold");
+        assert_eq!(
+            route.pool.as_ref().unwrap().select().unwrap().addr,
+            "127.0.0.1:8099"
+        );
+        assert_eq!(config.routes.fallback().unwrap().service, "catchall");
+        assert_eq!(
+            config.routes.service("geocode").unwrap().labels,
+            route.labels
+        );
+        assert!(config.routes.service("missing").is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! Consistent-hash selection of upstream endpoints.
+//!
+//! Services with `strategy: consistent_hash` send every request with the same key to the
+//! same endpoint, so upstream caches hold each tenant's data once. The key is the API key
+//! of the request, or the value of the service's `hash_header`:
+//!
+//! ```yaml
+//! services:
+//!   tiles: { path: /tiles, strategy: consistent_hash, hash_header: x-tenant }
+//! ```
+//!
+//! Endpoints sit on a [`HashRing`] at [`POINTS_PER_WEIGHT`] points per unit of weight, and a
+//! key goes to the endpoint of the first point at or after its hash. When an endpoint leaves
+//! the ring (removed, ejected or failed over) only the keys it held move. Requests without
+//! a key are spread by smooth weighted round robin.
+
+use pingora::http::RequestHeader;
+
+use crate::upstream::{PoolSettings, Strategy};
+
+/// Ring points per unit of endpoint weight.
+pub const POINTS_PER_WEIGHT: f64 = 100.0;
+
+/// Endpoints placed on a hash ring by weight.
+#[derive(Debug, Default)]
+pub struct HashRing {
+    /// Address and number of points of each endpoint, as the ring was built.
+    nodes: Vec<(String, usize)>,
+    /// (hash, index into `nodes`), sorted by hash.
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// A ring over endpoints with the given weights; those without weight are left out.
+    pub fn new(weights: &[(String, f64)]) -> Self {
+        let nodes = Self::nodes(weights);
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (addr, count))| {
+                (0..*count).map(move |point| (hash(format!("{addr}#{point}").as_bytes()), i))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { nodes, points }
+    }
+
+    fn nodes(weights: &[(String, f64)]) -> Vec<(String, usize)> {
+        weights
+            .iter()
+            .filter(|(_, weight)| *weight > 0.0)
+            .map(|(addr, weight)| {
+                let count = (weight * POINTS_PER_WEIGHT).round().max(1.0) as usize;
+                (addr.clone(), count)
+            })
+            .collect()
+    }
+
+    /// Rebuild the ring if `weights` place endpoints differently than it was built for.
+    pub fn update(&mut self, weights: &[(String, f64)]) {
+        if self.nodes != Self::nodes(weights) {
+            *self = Self::new(weights);
+        }
+    }
+
+    /// Endpoint holding `key`; `None` for an empty ring.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let hash = hash(key.as_bytes());
+        let index = self.points.partition_point(|(point, _)| *point < hash);
+        let (_, node) = self.points.get(index).or_else(|| self.points.first())?;
+        Some(&self.nodes[*node].0)
+    }
+}
+
+/// Stable 64-bit hash: FNV-1a, finished with the SplitMix64 mixer so that similar inputs
+/// (`addr#1`, `addr#2`) land far apart on the ring.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        h ^= u64::from(*byte);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Key a request to a pool with `settings` is hashed by: the value of its `hash_header`,
+/// or its API key. `None` for pools of other strategies and requests without the key.
+pub fn hash_key<'a>(
+    settings: &PoolSettings,
+    req: &'a RequestHeader,
+    api_key: Option<&'a str>,
+) -> Option<&'a str> {
+    if settings.strategy != Strategy::ConsistentHash {
+        return None;
+    }
+    match &settings.hash_header {
+        Some(header) => req
+            .headers
+            .get(header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty()),
+        None => api_key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn weights(endpoints: &[(&str, f64)]) -> Vec<(String, f64)> {
+        endpoints
+            .iter()
+            .map(|(addr, weight)| (addr.to_string(), *weight))
+            .collect()
+    }
+
+    fn owners(ring: &HashRing) -> HashMap<String, String> {
+        (0..1000)
+            .map(|i| {
+                let key = format!("tenant-{i}");
+                let owner = ring.get(&key).unwrap().to_string();
+                (key, owner)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keys_stick_and_move_only_from_a_leaving_endpoint() {
+        let three = HashRing::new(&weights(&[("a", 1.0), ("b", 1.0), ("c", 1.0)]));
+        let before = owners(&three);
+        assert_eq!(owners(&three), before);
+        let held = |owner: &str| before.values().filter(|o| *o == owner).count();
+        for owner in ["a", "b", "c"] {
+            assert!(
+                (250..420).contains(&held(owner)),
+                "{owner}: {}",
+                held(owner)
+            );
+        }
+
+        let two = HashRing::new(&weights(&[("a", 1.0), ("b", 1.0)]));
+        for (key, owner) in owners(&two) {
+            if before[&key] != "c" {
+                assert_eq!(owner, before[&key]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_weights_set_the_share_of_keys() {
+        let ring = HashRing::new(&weights(&[("old", 9.0), ("new", 1.0), ("off", 0.0)]));
+        let owners = owners(&ring);
+        let new = owners.values().filter(|o| *o == "new").count();
+        assert!((50..160).contains(&new), "{new}");
+        assert!(!owners.values().any(|o| o == "off"));
+        assert_eq!(HashRing::default().get("tenant"), None);
+    }
+
+    #[test]
+    fn test_update_keeps_ring_for_same_placement() {
+        let mut ring = HashRing::new(&weights(&[("a", 1.0)]));
+        ring.update(&weights(&[("a", 1.0001)]));
+        assert_eq!(ring.points.len(), 100);
+        ring.update(&weights(&[("a", 1.0), ("b", 0.5)]));
+        assert_eq!(ring.points.len(), 150);
+    }
+
+    #[test]
+    fn test_hash_key() {
+        let mut req = RequestHeader::build("GET", b"/tiles", None).unwrap();
+        req.insert_header("x-tenant", "acme").unwrap();
+        let mut settings = PoolSettings {
+            strategy: Strategy::ConsistentHash,
+            ..Default::default()
+        };
+        assert_eq!(hash_key(&settings, &req, Some("key")), Some("key"));
+        settings.hash_header = Some("x-tenant".to_string());
+        assert_eq!(hash_key(&settings, &req, Some("key")), Some("acme"));
+        settings.strategy = Strategy::RoundRobin;
+        assert_eq!(hash_key(&settings, &req, Some("key")), None);
+    }
+}
@@ -0,0 +1,160 @@
+//! Label selectors for discovered backends.
+//!
+//! A selector is a list of label sets. A target matches when any set matches (OR), and a
+//! set matches when the target carries every label in it with the same value (AND):
+//!
+//! ```yaml
+//! labels:
+//!   - env: prod
+//!     service: geocode
+//!   - env: canary
+//! ```
+//!
+//! selects targets labelled `env=prod` and `service=geocode`, plus every `env=canary`
+//! target. Providers whose API only supports AND selectors query once per set
+//! ([`LabelSelector::queries`]) and merge the results.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectorError {
+    /// The selector has no label sets, so it would never match.
+    Empty,
+    /// The label set at this index is empty, so it would match every target.
+    EmptySet(usize),
+    /// A label key or value cannot be used in a selector query.
+    InvalidLabel { key: String, value: String },
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectorError::Empty => write!(f, "Label selector has no label sets"),
+            SelectorError::EmptySet(i) => write!(f, "Label set {} is empty", i),
+            SelectorError::InvalidLabel { key, value } => {
+                write!(f, "Invalid label '{}={}' in selector", key, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+/// OR of AND-ed label sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelSelector {
+    // Sorted so queries and comparisons do not depend on map order
+    sets: Vec<BTreeMap<String, String>>,
+}
+
+/// Keys must be non-empty; neither keys nor values may contain the selector syntax
+/// characters or whitespace.
+pub(crate) fn valid_label(key: &str, value: &str) -> bool {
+    let valid =
+        |s: &str| !s.contains([',', '=', '!', '(', ')']) && !s.contains(char::is_whitespace);
+    !key.is_empty() && valid(key) && valid(value)
+}
+
+impl LabelSelector {
+    /// Build a selector from the `labels` field of a backend, rejecting selectors that would
+    /// match nothing or everything.
+    pub fn new(sets: &[HashMap<String, String>]) -> Result<Self, SelectorError> {
+        if sets.is_empty() {
+            return Err(SelectorError::Empty);
+        }
+        let mut sorted = Vec::with_capacity(sets.len());
+        for (i, set) in sets.iter().enumerate() {
+            if set.is_empty() {
+                return Err(SelectorError::EmptySet(i));
+            }
+            if let Some((key, value)) = set.iter().find(|(k, v)| !valid_label(k, v)) {
+                return Err(SelectorError::InvalidLabel {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+            sorted.push(set.iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+        }
+        Ok(Self { sets: sorted })
+    }
+
+    /// Whether a target with these labels is selected.
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.sets
+            .iter()
+            .any(|set| set.iter().all(|(k, v)| labels.get(k) == Some(v)))
+    }
+
+    /// One `key=value,key=value` query per label set, for APIs without OR.
+    pub fn queries(&self) -> Vec<String> {
+        self.sets
+            .iter()
+            .map(|set| {
+                set.iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sets_are_or_of_and() {
+        let selector = LabelSelector::new(&[
+            labels(&[("env", "prod"), ("service", "geocode")]),
+            labels(&[("env", "canary")]),
+        ])
+        .unwrap();
+
+        assert!(selector.matches(&labels(&[
+            ("env", "prod"),
+            ("service", "geocode"),
+            ("zone", "fsn1")
+        ])));
+        // Partial match of the first set
+        assert!(!selector.matches(&labels(&[("env", "prod")])));
+        assert!(!selector.matches(&labels(&[("env", "prod"), ("service", "search")])));
+        assert!(selector.matches(&labels(&[("env", "canary"), ("service", "search")])));
+        assert!(!selector.matches(&labels(&[])));
+
+        assert_eq!(
+            selector.queries(),
+            vec![
+                "env=prod,service=geocode".to_string(),
+                "env=canary".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_degenerate_selectors() {
+        assert_eq!(LabelSelector::new(&[]), Err(SelectorError::Empty));
+        assert_eq!(
+            LabelSelector::new(&[labels(&[("env", "prod")]), labels(&[])]),
+            Err(SelectorError::EmptySet(1))
+        );
+        assert!(matches!(
+            LabelSelector::new(&[labels(&[("env", "prod,staging")])]),
+            Err(SelectorError::InvalidLabel { .. })
+        ));
+        assert!(matches!(
+            LabelSelector::new(&[labels(&[("", "prod")])]),
+            Err(SelectorError::InvalidLabel { .. })
+        ));
+        // Empty values are allowed
+        assert!(LabelSelector::new(&[labels(&[("env", "")])]).is_ok());
+    }
+}
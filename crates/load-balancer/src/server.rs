@@ -1,25 +1,120 @@
+use std::collections::{BTreeMap, HashSet};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use pingora::listeners::TcpSocketOptions;
 use pingora::prelude::*;
+use pingora::proxy::{http_proxy, http_proxy_service_with_name};
+#[cfg(windows)]
 use pingora::server::RunArgs;
 use pingora::server::Server as PingoraServer;
-use pingora::server::configuration::Opt;
+use pingora::server::configuration::{Opt, ServerConf};
 use pingora::services::background::GenBackgroundService;
+use pingora::services::listening::Service as ListeningService;
 
-use crate::accounts::AccountRatelimit;
-use crate::configuration::{Config, ConfigReloader, ServerConfig};
+use crate::accounts::{
+    API_KEY_PREFIX, AccountRatelimit, AsyncRatelimit, FallbackLimits, ReadThrough, load_allowlist,
+};
+use crate::admin::AdminApp;
+use crate::admission::Admission;
+use crate::alert::AlertSink;
+use crate::anomaly::AnomalyDetector;
+use crate::clock::{Clock, SystemClock};
+use crate::configuration::{Config, ConfigReloader, RuntimeConfig, ServerConfig};
+use crate::connection::LimitedApp;
+use crate::control::ControlPlane;
+use crate::degradation::{Degradation, Dependency};
+use crate::error::LbError;
+use crate::gossip::Gossip;
+use crate::grpc::GrpcListener;
+use crate::history::ConfigHistory;
+use crate::hooks::Hooks;
 use crate::lb::Lb;
+use crate::leader::{ChangeLogCompactor, LeaderElection};
+use crate::limiter_state::LimiterState;
+use crate::logging::LogHandle;
 use crate::metric::Metrics;
+use crate::privacy::Privacy;
+use crate::readiness::{Phase, Readiness};
+use crate::recorder::Recorder;
+use crate::reload::{ReloadService, RuntimeReloader};
+use crate::residency::UsageResidency;
+use crate::retention::UsagePurger;
+use crate::shutdown::Shutdown;
+#[cfg(unix)]
+use crate::systemd::{Notifier, Watchdog};
+use crate::tenant::Tenants;
+use crate::top::TopConsumersLogger;
+use crate::trace::TraceCapture;
+use crate::upstream::UpstreamRefresher;
 use crate::usage::{UsageTracker, UsageWriter};
 
 pub struct Server {
     server: PingoraServer,
+    log_handle: Option<LogHandle>,
+    readiness: Arc<Readiness>,
+    /// Drained on exit, after the proxy has stopped recording.
+    recorder: Option<Recorder>,
+    /// Flushed on exit again for usage recorded after its own shutdown flush.
+    usage_writer: Option<Arc<UsageWriter>>,
+    /// Saved on exit, after the proxy has stopped counting.
+    limiter_state: Option<Arc<LimiterState>>,
+    hooks: Arc<Hooks>,
+    /// Source of API key limits replacing the accounts DB, if set.
+    limits: Option<Arc<dyn AsyncRatelimit>>,
 }
 
 impl Server {
-    pub fn new(opt: Option<Opt>) -> Result<Self> {
-        let server = PingoraServer::new(opt)?;
-        Ok(Server { server })
+    /// Create the server with pingora's settings from the config file in `opt` (or its
+    /// defaults), overridden by the `runtime` section of our config.
+    pub fn new(opt: Option<Opt>, runtime: &RuntimeConfig) -> Result<Self> {
+        runtime.validate().map_err(LbError::Config)?;
+        let mut conf = match opt.as_ref().and_then(|opt| opt.conf.as_deref()) {
+            Some(path) => ServerConf::load_from_yaml(path)?,
+            None => ServerConf::new()
+                .ok_or_else(|| LbError::Config("invalid pingora conf".to_string()))?,
+        };
+        runtime.apply(&mut conf);
+        // Pingora has no shutdown signals on Windows and shuts down after the grace period
+        // right away; the process waits for Ctrl+C instead, see `Server::run`
+        #[cfg(windows)]
+        {
+            conf.grace_period_seconds = Some(u64::MAX);
+        }
+        // The file is read already; the flags still apply (`--daemon`, `--upgrade`, `--test`)
+        let opt = opt.map(|opt| Opt { conf: None, ..opt });
+        let server = PingoraServer::new_with_opt_and_conf(opt, conf);
+        Ok(Server {
+            server,
+            log_handle: None,
+            readiness: Arc::new(Readiness::new()),
+            recorder: None,
+            usage_writer: None,
+            limiter_state: None,
+            hooks: Arc::new(Hooks::default()),
+            limits: None,
+        })
+    }
+
+    /// Startup phase completion, as reported by `/readyz`.
+    pub fn readiness(&self) -> Arc<Readiness> {
+        self.readiness.clone()
+    }
+
+    /// Hooks run by the proxy listeners; install or replace them at any time.
+    pub fn hooks(&self) -> Arc<Hooks> {
+        self.hooks.clone()
+    }
+
+    /// Take the limits of API keys from `limits`, such as a quota service, instead of the
+    /// accounts DB; set before [`Server::bootstrap`].
+    pub fn set_limits(&mut self, limits: Arc<dyn AsyncRatelimit>) {
+        self.limits = Some(limits);
+    }
+
+    /// Expose runtime control of the installed logger (level changes, reopen on reload).
+    pub fn set_log_handle(&mut self, handle: LogHandle) {
+        self.log_handle = Some(handle);
     }
 
     pub fn bootstrap(
@@ -30,6 +125,40 @@ impl Server {
         metrics: Arc<Metrics>,
     ) -> Result<()> {
         self.server.bootstrap();
+        if let Some(overload) = &server_conf.overload {
+            overload.validate().map_err(LbError::Config)?;
+        }
+        if let Some(gossip) = &server_conf.gossip {
+            gossip.validate().map_err(LbError::Config)?;
+        }
+        if let Some(leader) = &server_conf.leader {
+            leader.validate().map_err(LbError::Config)?;
+        }
+        if let Some(privacy) = &server_conf.privacy {
+            privacy.validate().map_err(LbError::Config)?;
+        }
+        if let Some(retention) = &server_conf.usage_retention {
+            retention.validate().map_err(LbError::Config)?;
+        }
+        if let Some(residency) = &server_conf.usage_residency {
+            residency.validate().map_err(LbError::Config)?;
+        }
+        if let Some(history) = &server_conf.config_history {
+            history.validate().map_err(LbError::Config)?;
+        }
+        server_conf
+            .degradation
+            .validate()
+            .map_err(LbError::Config)?;
+        let degradation = Arc::new(
+            Degradation::new(server_conf.degradation.clone()).with_metrics(metrics.clone()),
+        );
+        let privacy = server_conf
+            .privacy
+            .clone()
+            .map(|p| Arc::new(Privacy::new(p)));
+        let node_id = server_conf.node_id();
+        log::info!("Starting LB node {node_id}");
 
         let backend_config_path = if std::path::Path::new(&server_conf.backend).is_absolute() {
             std::path::PathBuf::from(&server_conf.backend)
@@ -38,109 +167,591 @@ impl Server {
         };
 
         // Initial load of backend config
-        let config_str = std::fs::read_to_string(&backend_config_path).map_err(|e| {
-            Error::explain(
-                ErrorType::InternalError,
-                format!("failed to read backend config: {e}"),
-            )
-        })?;
-        let config: Config = serde_yaml::from_str(&config_str).map_err(|e| {
-            Error::explain(
-                ErrorType::InternalError,
-                format!("failed to parse backend config: {e}"),
-            )
-        })?;
-        config.validate().map_err(|e| {
-            Error::explain(
-                ErrorType::InternalError,
-                format!("invalid backend config: {e}"),
-            )
-        })?;
+        let (config, source) =
+            Config::load_with_source(&backend_config_path).map_err(LbError::Config)?;
+        self.readiness.complete(Phase::BackendConfig);
+
+        let config_history = match &server_conf.config_history {
+            Some(history_conf) => {
+                let dir = if std::path::Path::new(&history_conf.dir).is_absolute() {
+                    std::path::PathBuf::from(&history_conf.dir)
+                } else {
+                    config_base_path.join(&history_conf.dir)
+                };
+                std::fs::create_dir_all(&dir).map_err(|e| {
+                    LbError::Config(format!("failed to create config history directory: {e}"))
+                })?;
+                let history = ConfigHistory::new(dir, history_conf.max_versions);
+                if let Err(e) = history.record(&source, None, &config) {
+                    log::warn!("Failed to record backend config history: {e}");
+                }
+                Some(Arc::new(history))
+            }
+            None => None,
+        };
 
         let config_arc = Arc::new(RwLock::new(config));
+        let alerts = Arc::new(AlertSink::new(&server_conf.alerts));
 
         // Background service for reloading config
         let reloader = ConfigReloader {
             path: backend_config_path.to_string_lossy().into_owned(),
             config: config_arc.clone(),
+            alerts: alerts.clone(),
+            history: config_history.clone(),
         };
         let background =
             GenBackgroundService::new("config reloader".to_string(), Arc::new(reloader));
         self.server.add_service(background);
 
-        // Setup rate limiter from accounts DB (required)
+        // Discovery for dynamic backends, also picking up backends added by reloads
+        self.server.add_service(GenBackgroundService::new(
+            "upstream refresher".to_string(),
+            Arc::new(UpstreamRefresher::new(config_arc.clone(), alerts.clone())),
+        ));
+
+        // Setup rate limiter from accounts DB, its snapshot, or static fallback limits
         let accounts_db_path = if std::path::Path::new(&server_conf.accounts_db).is_absolute() {
             std::path::PathBuf::from(&server_conf.accounts_db)
         } else {
             config_base_path.join(&server_conf.accounts_db)
         };
 
-        let (account_limiter, account_service) = AccountRatelimit::from_db(&accounts_db_path)
-            .map_err(|e| {
-                Error::explain(
-                    ErrorType::InternalError,
-                    format!("failed to load accounts DB: {e}"),
+        let accounts = match &server_conf.accounts_snapshot {
+            Some(snapshot) => {
+                let snapshot_path = if std::path::Path::new(snapshot).is_absolute() {
+                    std::path::PathBuf::from(snapshot)
+                } else {
+                    config_base_path.join(snapshot)
+                };
+                AccountRatelimit::from_db_with_snapshot(&accounts_db_path, &snapshot_path)
+            }
+            None => AccountRatelimit::from_db(&accounts_db_path),
+        };
+        // A degraded start completes the accounts phase only once the DB loads
+        let mut accounts_loaded = true;
+        let accounts = match (accounts, &server_conf.accounts_fallback) {
+            (Err(e), Some(_)) => {
+                alerts.critical(
+                    "accounts",
+                    format!("Failed to load accounts DB ({e}), starting in degraded mode"),
+                );
+                degradation.enter(Dependency::AccountsDb, e.to_string());
+                accounts_loaded = false;
+                Ok(AccountRatelimit::degraded(&accounts_db_path))
+            }
+            (accounts, _) => accounts,
+        };
+        let (mut account_limiter, account_service) =
+            accounts.map_err(|e| LbError::Limiter(format!("failed to load accounts DB: {e}")))?;
+        let read_through = &server_conf.accounts_read_through;
+        if read_through.enabled {
+            account_limiter = account_limiter.with_read_through(
+                ReadThrough::new(
+                    &accounts_db_path,
+                    Duration::from_secs(read_through.negative_ttl_secs),
+                    read_through.max_negative_entries,
                 )
-            })?;
+                .with_max_lookups_per_sec(read_through.max_lookups_per_sec),
+            );
+        }
+        if let Some(fallback) = &server_conf.accounts_fallback {
+            let allowlist = fallback.allowlist.as_ref().map(|path| {
+                let path = if std::path::Path::new(path).is_absolute() {
+                    std::path::PathBuf::from(path)
+                } else {
+                    config_base_path.join(path)
+                };
+                // An unreadable allowlist admits no key rather than every key
+                load_allowlist(&path).unwrap_or_else(|e| {
+                    alerts.critical(
+                        "accounts",
+                        format!("Failed to read fallback allowlist {path:?}: {e}"),
+                    );
+                    HashSet::new()
+                })
+            });
+            account_limiter = account_limiter.with_fallback(FallbackLimits {
+                rps_limit: fallback.rps_limit,
+                allowlist,
+            });
+        }
 
         log::info!(
             "Using account-based rate limiting from {:?}",
             accounts_db_path
         );
+
+        let mut tenant_prefixes = HashSet::new();
+        for tenant in &server_conf.tenants {
+            if !tenant_prefixes.insert(&tenant.token_prefix) {
+                return Err(LbError::Config(format!(
+                    "tenant {}: token prefix {:?} is already in use",
+                    tenant.name, tenant.token_prefix
+                ))
+                .into());
+            }
+        }
+
+        let mut prefixes = HashSet::from([API_KEY_PREFIX.to_string()]);
+        for partition in &server_conf.partitions() {
+            if !prefixes.insert(partition.token_prefix.clone()) {
+                return Err(LbError::Config(format!(
+                    "account partition {}: token prefix {:?} is already in use",
+                    partition.name, partition.token_prefix
+                ))
+                .into());
+            }
+            let db_path = if std::path::Path::new(&partition.accounts_db).is_absolute() {
+                std::path::PathBuf::from(&partition.accounts_db)
+            } else {
+                config_base_path.join(&partition.accounts_db)
+            };
+            let (mut limiter, service) =
+                AccountRatelimit::partition(&db_path, &partition.token_prefix).map_err(|e| {
+                    LbError::Limiter(format!(
+                        "failed to load accounts DB for partition {}: {e}",
+                        partition.name
+                    ))
+                })?;
+            if read_through.enabled {
+                limiter = limiter.with_read_through(
+                    ReadThrough::new(
+                        &db_path,
+                        Duration::from_secs(read_through.negative_ttl_secs),
+                        read_through.max_negative_entries,
+                    )
+                    .with_max_lookups_per_sec(read_through.max_lookups_per_sec),
+                );
+            }
+            self.server.add_service(GenBackgroundService::new(
+                format!("account data reloader ({})", partition.name),
+                Arc::new(service.with_alerts(alerts.clone())),
+            ));
+            account_limiter =
+                account_limiter.with_partition(partition.token_prefix.clone(), limiter);
+            log::info!(
+                "Account partition {} serving {}_ tokens from {:?}",
+                partition.name,
+                partition.token_prefix,
+                db_path
+            );
+        }
         let account_bg = GenBackgroundService::new(
             "account data reloader".to_string(),
-            Arc::new(account_service),
+            Arc::new(
+                account_service
+                    .with_alerts(alerts.clone())
+                    .with_readiness(self.readiness.clone())
+                    .with_degradation(degradation.clone()),
+            ),
         );
         self.server.add_service(account_bg);
+        let account_limiter = Arc::new(account_limiter);
+        if accounts_loaded {
+            self.readiness.complete(Phase::Accounts);
+        }
+
+        if server_conf.anomaly.enabled {
+            let detector = AnomalyDetector::new(
+                server_conf.anomaly.clone(),
+                metrics.clone(),
+                account_limiter.clone(),
+            )
+            .with_alerts(alerts.clone());
+            self.server.add_service(GenBackgroundService::new(
+                "anomaly detector".to_string(),
+                Arc::new(detector),
+            ));
+        }
+
+        if server_conf.top_consumers.enabled {
+            self.server.add_service(GenBackgroundService::new(
+                "top consumers logger".to_string(),
+                Arc::new(TopConsumersLogger::new(
+                    server_conf.top_consumers.clone(),
+                    metrics.clone(),
+                    account_limiter.clone(),
+                )),
+            ));
+        }
 
         // Setup usage tracking if configured
+        let mut usage_writer = None;
+        let mut usage_path = None;
         let usage_tracker = if let Some(usage_dir) = &server_conf.usage_dir {
-            let usage_path = if std::path::Path::new(usage_dir).is_absolute() {
+            let path = if std::path::Path::new(usage_dir).is_absolute() {
                 std::path::PathBuf::from(usage_dir)
             } else {
                 config_base_path.join(usage_dir)
             };
 
             // Create directory if it doesn't exist
-            std::fs::create_dir_all(&usage_path).map_err(|e| {
-                Error::explain(
-                    ErrorType::InternalError,
-                    format!("failed to create usage directory: {e}"),
-                )
-            })?;
+            std::fs::create_dir_all(&path)
+                .map_err(|e| LbError::Usage(format!("failed to create usage directory: {e}")))?;
 
-            let tracker = Arc::new(UsageTracker::new());
-            let writer = UsageWriter::new(tracker.clone(), &usage_path);
-            let usage_bg = GenBackgroundService::new("usage writer".to_string(), Arc::new(writer));
+            let mut tracker = UsageTracker::new().with_node(node_id.clone());
+            if let Some(residency) = &server_conf.usage_residency {
+                let mut dirs = BTreeMap::new();
+                for (region, dir) in &residency.dirs {
+                    let dir = if std::path::Path::new(dir).is_absolute() {
+                        std::path::PathBuf::from(dir)
+                    } else {
+                        config_base_path.join(dir)
+                    };
+                    std::fs::create_dir_all(&dir).map_err(|e| {
+                        LbError::Usage(format!("failed to create usage directory of {region}: {e}"))
+                    })?;
+                    log::info!("Usage of accounts resident in {region} goes to {:?}", dir);
+                    dirs.insert(region.clone(), dir);
+                }
+                tracker = tracker
+                    .with_residency(Arc::new(UsageResidency::new(dirs, account_limiter.store())));
+            }
+            let tracker = Arc::new(tracker);
+            let writer = Arc::new(
+                UsageWriter::new(tracker.clone(), &path)
+                    .with_alerts(alerts.clone())
+                    .with_degradation(degradation.clone()),
+            );
+            let usage_bg = GenBackgroundService::new("usage writer".to_string(), writer.clone());
             self.server.add_service(usage_bg);
+            usage_writer = Some(writer);
+            if let Some(retention) = &server_conf.usage_retention {
+                for dir in std::iter::once(path.clone()).chain(tracker.residency_dirs()) {
+                    self.server.add_service(GenBackgroundService::new(
+                        "usage purger".to_string(),
+                        Arc::new(UsagePurger::new(&dir, retention.clone())),
+                    ));
+                }
+            }
 
-            log::info!("Usage tracking enabled, writing to {:?}", usage_path);
+            log::info!("Usage tracking enabled, writing to {:?}", path);
+            usage_path = Some(path);
             Some(tracker)
         } else {
             None
         };
 
-        let mut lb_service = http_proxy_service(
-            &self.server.configuration,
-            Lb::new(
-                config_arc,
-                Arc::new(account_limiter),
-                metrics,
-                usage_tracker,
-            ),
+        // Singleton background jobs run on the elected leader only
+        if let Some(leader_conf) = &server_conf.leader {
+            let lease_db = if std::path::Path::new(&leader_conf.db).is_absolute() {
+                std::path::PathBuf::from(&leader_conf.db)
+            } else {
+                config_base_path.join(&leader_conf.db)
+            };
+            let leader = Arc::new(LeaderElection::new(
+                lease_db,
+                node_id.clone(),
+                Duration::from_secs(leader_conf.ttl_secs),
+            ));
+            self.server.add_service(GenBackgroundService::new(
+                "leader election".to_string(),
+                leader.clone(),
+            ));
+            if let Some(retention) = leader_conf.changelog_retention_secs {
+                self.server.add_service(GenBackgroundService::new(
+                    "changelog compactor".to_string(),
+                    Arc::new(ChangeLogCompactor::new(
+                        &accounts_db_path,
+                        Duration::from_secs(retention),
+                        leader,
+                    )),
+                ));
+            }
+        }
+
+        // Full reload on SIGHUP or through the admin listener
+        let mut reloader = RuntimeReloader::new(
+            &backend_config_path,
+            config_arc.clone(),
+            &accounts_db_path,
+            account_limiter.store(),
         );
+        if let Some(writer) = usage_writer {
+            self.usage_writer = Some(writer.clone());
+            reloader = reloader.with_usage_writer(writer);
+        }
+        if let Some(history) = &config_history {
+            reloader = reloader.with_history(history.clone());
+        }
+        if let Some(handle) = self.log_handle.clone() {
+            reloader = reloader.with_log_reopen(Arc::new(move || handle.reopen()));
+        }
+        let reloader = Arc::new(reloader);
+        self.server.add_service(GenBackgroundService::new(
+            "reload on SIGHUP".to_string(),
+            Arc::new(ReloadService::new(reloader.clone())),
+        ));
+
+        let recorder = Recorder::spawn(
+            &server_conf.recording,
+            metrics.clone(),
+            usage_tracker.clone(),
+        )
+        .map_err(|e| LbError::Internal(format!("failed to start metrics aggregator: {e}")))?;
+        self.recorder = Some(recorder.clone());
+
+        // Armed through the admin listener, captured by the public listener
+        let traces = Arc::new(TraceCapture::new());
+
+        if let Some(admin_conf) = &server_conf.admin {
+            let mut app = AdminApp::new(admin_conf)
+                .with_node_id(node_id.clone())
+                .with_config(config_arc.clone())
+                .with_reloader(reloader.clone())
+                .with_readiness(self.readiness.clone())
+                .with_degradation(degradation.clone());
+            if let Some(handle) = self.log_handle.clone() {
+                app = app.with_log_handle(handle);
+            }
+            if let Some(history) = &config_history {
+                app = app.with_config_history(history.clone());
+            }
+            if admin_conf.control_plane || admin_conf.control_plane_grpc.is_some() {
+                let control = account_limiter.partition_stores().into_iter().fold(
+                    ControlPlane::new(account_limiter.store()).with_reloader(reloader),
+                    |control, (prefix, store)| control.with_partition(prefix, store),
+                );
+                let control = Arc::new(control);
+                if admin_conf.control_plane {
+                    app = app.with_control_plane(control.clone());
+                }
+                if let Some(listen) = &admin_conf.control_plane_grpc {
+                    self.server.add_service(GenBackgroundService::new(
+                        "control plane gRPC".to_string(),
+                        Arc::new(GrpcListener::new(
+                            listen.clone(),
+                            control,
+                            admin_conf.token.clone(),
+                        )),
+                    ));
+                    log::info!("Control plane gRPC listener on {listen}");
+                }
+            }
+            if let Some(path) = usage_path {
+                app = app.with_usage_dir(path);
+            }
+            if let Some(tracker) = &usage_tracker {
+                let shred = server_conf
+                    .usage_retention
+                    .as_ref()
+                    .is_some_and(|retention| retention.shred);
+                app = app.with_usage_deletion(tracker.clone(), shred);
+            }
+            app = app
+                .with_top_consumers(metrics.clone(), account_limiter.clone())
+                .with_openapi_rejections(metrics.clone())
+                .with_metrics(metrics.clone())
+                .with_trace_capture(traces.clone());
+            let mut admin = ListeningService::new("admin".to_string(), app);
+            admin.add_tcp(&admin_conf.listen);
+            self.server.add_service(admin);
+            log::info!("Admin listener on {}", admin_conf.listen);
+        }
+
+        if let Some(internal) = &server_conf.internal {
+            let mut internal_lb = Lb::new(
+                config_arc.clone(),
+                account_limiter.clone(),
+                metrics.clone(),
+                None,
+            )
+            .with_listener_config(internal.limits.clone())
+            .with_deadline_config(server_conf.deadline.clone())
+            .with_debug_headers(server_conf.debug_headers.clone())
+            .with_diagnostic_headers(&server_conf.diagnostic_headers)
+            .with_node_id(node_id.clone())
+            .with_internal_limit(internal.rps_limit)
+            .with_hooks(self.hooks.clone())
+            .with_recorder(recorder.clone());
+            if let Some(privacy) = &privacy {
+                internal_lb = internal_lb.with_privacy(privacy.clone());
+            }
+            self.add_proxy_listener(
+                "Internal Proxy HTTP",
+                internal_lb,
+                &internal.listen,
+                internal.limits.ipv6_only,
+                recorder.clone(),
+            );
+            log::info!("Internal listener on {}", internal.listen);
+        }
+
+        #[cfg(unix)]
+        if let Some(notifier) = Notifier::from_env() {
+            if let Some(watchdog) =
+                Watchdog::from_env(notifier.clone(), config_arc.clone(), self.readiness.clone())
+            {
+                self.server.add_service(GenBackgroundService::new(
+                    "systemd watchdog".to_string(),
+                    Arc::new(watchdog),
+                ));
+            }
+            let phases = self.server.watch_execution_phase();
+            std::thread::Builder::new()
+                .name("systemd notifier".to_string())
+                .spawn(move || notifier.follow(phases))
+                .map_err(|e| LbError::Internal(format!("failed to start systemd notifier: {e}")))?;
+        }
+
+        // Rate limit counters shared with peer nodes
+        let gossip = server_conf.gossip.as_ref().map(|gossip| {
+            let gossip = Arc::new(
+                Gossip::new(gossip.clone(), node_id.clone()).with_degradation(degradation.clone()),
+            );
+            self.server.add_service(GenBackgroundService::new(
+                "gossip".to_string(),
+                gossip.clone(),
+            ));
+            gossip
+        });
+
+        let mut lb = Lb::new(config_arc, account_limiter, metrics, usage_tracker)
+            .with_listener_config(server_conf.listener.clone())
+            .with_deadline_config(server_conf.deadline.clone())
+            .with_debug_headers(server_conf.debug_headers.clone())
+            .with_diagnostic_headers(&server_conf.diagnostic_headers)
+            .with_node_id(node_id)
+            .with_tenants(Tenants::new(&server_conf.tenants))
+            .with_trace_capture(traces)
+            .with_hooks(self.hooks.clone())
+            .with_recorder(recorder.clone());
+        if let Some(limits) = &self.limits {
+            lb = lb.with_limits(limits.clone());
+        }
+        if let Some(overload) = &server_conf.overload {
+            lb = lb.with_admission(Admission::new(overload.clone()));
+        }
+        if let Some(gossip) = gossip {
+            lb = lb.with_gossip(gossip);
+        }
+        if let Some(privacy) = privacy {
+            lb = lb.with_privacy(privacy);
+        }
+        if let Some(path) = &server_conf.limiter_state {
+            let path = if std::path::Path::new(path).is_absolute() {
+                std::path::PathBuf::from(path)
+            } else {
+                config_base_path.join(path)
+            };
+            let state = Arc::new(LimiterState::load(path, SystemClock.unix_secs() as u64));
+            self.limiter_state = Some(state.clone());
+            lb = lb.with_limiter_state(state);
+        }
 
-        lb_service.add_tcp(listen_addr);
-        self.server.add_service(lb_service);
+        // The proxy listener is added last, so it only accepts traffic once the startup
+        // phases above have run
+        self.add_proxy_listener(
+            "Pingora HTTP Proxy Service",
+            lb,
+            listen_addr,
+            server_conf.listener.ipv6_only,
+            recorder,
+        );
 
         Ok(())
     }
 
-    pub fn run_forever(self) {
-        self.server.run_forever();
+    /// Serve `lb` on `addr`, admitting connections through its limiter when its listener
+    /// settings limit them.
+    fn add_proxy_listener(
+        &mut self,
+        name: &str,
+        lb: Lb,
+        addr: &str,
+        ipv6_only: Option<bool>,
+        recorder: Recorder,
+    ) {
+        let connections = lb.connections();
+        let mut socket = TcpSocketOptions::default();
+        socket.ipv6_only = ipv6_only;
+        if connections.is_enabled() {
+            // Connections are admitted as they are accepted, and released once closed
+            let app = http_proxy(&self.server.configuration, lb);
+            let mut service = ListeningService::new(
+                name.to_string(),
+                LimitedApp::new(app, connections, recorder),
+            );
+            service.add_tcp_with_settings(addr, socket);
+            self.server.add_service(service);
+        } else {
+            let mut service = http_proxy_service_with_name(&self.server.configuration, lb, name);
+            service.add_tcp_with_settings(addr, socket);
+            self.server.add_service(service);
+        }
+    }
+
+    pub fn run_forever(self) -> ! {
+        self.run(Shutdown::signals());
+        std::process::exit(0)
+    }
+
+    /// Serve until `shutdown`, then flush what the proxy recorded.
+    #[cfg(unix)]
+    pub fn run(self, shutdown: Shutdown) {
+        let Server {
+            server,
+            recorder,
+            usage_writer,
+            limiter_state,
+            ..
+        } = self;
+        server.run(shutdown.into_run_args());
+        Self::flush(
+            recorder.as_ref(),
+            usage_writer.as_deref(),
+            limiter_state.as_deref(),
+        );
+    }
+
+    /// Serve until `shutdown`, then flush what the proxy recorded.
+    ///
+    /// Pingora starts the services and then only waits out its grace period on Windows,
+    /// which [`Server::new`] makes unbounded; the services stop when the process exits.
+    #[cfg(windows)]
+    pub fn run(self, shutdown: Shutdown) {
+        let Server {
+            server,
+            recorder,
+            usage_writer,
+            limiter_state,
+            ..
+        } = self;
+        if let Err(e) = std::thread::Builder::new()
+            .name("pingora".to_string())
+            .spawn(move || server.run(RunArgs::default()))
+        {
+            log::error!("Failed to start the server: {e}");
+            return;
+        }
+        shutdown.wait();
+        Self::flush(
+            recorder.as_ref(),
+            usage_writer.as_deref(),
+            limiter_state.as_deref(),
+        );
     }
 
-    pub fn run(self, args: RunArgs) {
-        self.server.run(args);
+    fn flush(
+        recorder: Option<&Recorder>,
+        usage_writer: Option<&UsageWriter>,
+        limiter_state: Option<&LimiterState>,
+    ) {
+        // Requests finishing during shutdown may have queued records after the usage
+        // writer's final flush
+        if let Some(recorder) = recorder {
+            recorder.flush();
+        }
+        if let Some(writer) = usage_writer
+            && let Err(e) = writer.flush_all()
+        {
+            log::error!("Failed to flush usage data on exit: {e}");
+        }
+        if let Some(state) = limiter_state
+            && let Err(e) = state.save(SystemClock.unix_secs() as u64)
+        {
+            log::error!("Failed to save limiter state on exit: {e}");
+        }
     }
 }
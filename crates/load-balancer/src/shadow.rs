@@ -0,0 +1,123 @@
+//! Traffic mirroring to shadow backends.
+//!
+//! A backend marked `shadow: true` does not serve its service's requests. It gets a copy of
+//! each of them once the client has been answered, and its responses are discarded, so a
+//! new upstream version can be soaked with production traffic without clients noticing:
+//!
+//! ```yaml
+//! backends:
+//!   - service: geocode
+//!     backend: { type: basic, ip: 10.0.0.1, port: 8080 }
+//!   - service: geocode
+//!     shadow: true
+//!     backend: { type: basic, ip: 10.0.0.9, port: 8080 }
+//! ```
+//!
+//! Shadow endpoints are picked and health checked like the service's own, and get the path
+//! its upstreams get, with an [`SHADOW_HEADER`] header. Only requests that went upstream
+//! are copied. Copies are best effort: a request whose body exceeds [`MAX_BODY_BYTES`], or
+//! that arrives while [`MAX_IN_FLIGHT`] copies are pending, is not copied.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use bytes::Bytes;
+use http::{HeaderMap, Method, header};
+
+use crate::recorder::Recorder;
+use crate::upstream::{ServicePool, http_client};
+
+/// Header marking the copies sent to shadow backends.
+pub const SHADOW_HEADER: &str = "X-LB-Shadow";
+/// Labeled counter of copies sent to shadow backends, by service.
+pub const SHADOW_REQUESTS_COUNTER: &str = "shadow_requests";
+/// Labeled counter of requests not copied to their service's shadow backends, by service.
+pub const SHADOW_DROPPED_COUNTER: &str = "shadow_dropped";
+/// Labeled counter of copies that got no response, by service.
+pub const SHADOW_ERRORS_COUNTER: &str = "shadow_errors";
+
+/// Largest request body copied.
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Copies pending at once across services.
+pub const MAX_IN_FLIGHT: usize = 256;
+
+/// A request to copy to a shadow backend.
+#[derive(Debug)]
+pub struct ShadowRequest {
+    pub service: String,
+    pub method: Method,
+    /// Path and query sent upstream.
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Sender of copies to shadow backends.
+#[derive(Debug, Default)]
+pub struct Shadow {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Shadow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `request` to an endpoint of `pool` in the background.
+    pub fn mirror(&self, pool: Arc<ServicePool>, mut request: ShadowRequest, recorder: &Recorder) {
+        let service = request.service.clone();
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            recorder.increment_labeled(SHADOW_DROPPED_COUNTER, &service);
+            return;
+        }
+        let Some(endpoint) = pool.select() else {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            recorder.increment_labeled(SHADOW_DROPPED_COUNTER, &service);
+            return;
+        };
+
+        // Framing and connection headers are the client's to the LB, not the copy's
+        for name in [
+            header::HOST,
+            header::CONNECTION,
+            header::CONTENT_LENGTH,
+            header::TRANSFER_ENCODING,
+            header::TE,
+            header::UPGRADE,
+        ] {
+            request.headers.remove(name);
+        }
+        request
+            .headers
+            .insert(SHADOW_HEADER, http::HeaderValue::from_static("true"));
+        let url = format!("http://{}{}", endpoint.addr, request.path);
+        let in_flight = self.in_flight.clone();
+        let recorder = recorder.clone();
+        recorder.increment_labeled(SHADOW_REQUESTS_COUNTER, &service);
+        tokio::spawn(async move {
+            let sent_at = Instant::now();
+            let result = http_client()
+                .request(request.method, &url)
+                .headers(request.headers)
+                .body(request.body)
+                .send()
+                .await;
+            match result {
+                Ok(resp) => {
+                    let status = resp.status().as_u16();
+                    // Read to the end so the connection can be reused
+                    let _ = resp.bytes().await;
+                    pool.report_status(&endpoint.addr, status, sent_at.elapsed(), Instant::now());
+                }
+                Err(e) => {
+                    log::debug!("Shadow copy to {url} failed: {e}");
+                    pool.report(&endpoint.addr, None, Instant::now());
+                    recorder.increment_labeled(SHADOW_ERRORS_COUNTER, &service);
+                }
+            }
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
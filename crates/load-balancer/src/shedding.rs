@@ -0,0 +1,145 @@
+//! Load shedding in front of saturated upstreams.
+//!
+//! A service with a `load_shedding` section is saturated when its requests in flight reach
+//! `max_in_flight` or the latency EWMA of its endpoints reaches `max_latency_ms`. Its
+//! saturation is the larger of the two ratios; from `shed_others_at` on, requests of plans
+//! other than the `priority_plans` get a `503` with `Retry-After` before any upstream
+//! connection is made, and from 1 on, every request does. The upstreams keep serving the
+//! requests they can answer instead of all of them slowing down.
+
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::upstream::PoolLoad;
+
+/// Counter of shed requests, labeled with the service.
+pub const SHED_REQUESTS_COUNTER: &str = "shed_requests";
+
+/// Shedding of a service's requests.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LoadSheddingConfig {
+    /// Requests in flight to the service's endpoints at which it is saturated.
+    pub max_in_flight: Option<u32>,
+    /// Latency EWMA of the service's endpoints, in milliseconds, at which it is saturated.
+    pub max_latency_ms: Option<u64>,
+    /// Plans whose requests are only shed once the service is saturated.
+    pub priority_plans: Vec<String>,
+    /// Saturation, between 0 and 1, from which the requests of other plans are shed.
+    pub shed_others_at: f64,
+    /// Seconds clients are asked to wait before retrying.
+    pub retry_after_secs: u64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: None,
+            max_latency_ms: None,
+            priority_plans: Vec::new(),
+            shed_others_at: 0.8,
+            retry_after_secs: 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoadSheddingError {
+    /// Neither `max_in_flight` nor `max_latency_ms` is set.
+    NoThreshold,
+    InvalidShedOthersAt(f64),
+}
+
+impl fmt::Display for LoadSheddingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadSheddingError::NoThreshold => {
+                write!(f, "set max_in_flight or max_latency_ms")
+            }
+            LoadSheddingError::InvalidShedOthersAt(at) => {
+                write!(f, "shed_others_at must be within (0, 1], not {at}")
+            }
+        }
+    }
+}
+
+impl LoadSheddingConfig {
+    pub fn validate(&self) -> Result<(), LoadSheddingError> {
+        if self.max_in_flight.is_none() && self.max_latency_ms.is_none() {
+            return Err(LoadSheddingError::NoThreshold);
+        }
+        if !(self.shed_others_at > 0.0 && self.shed_others_at <= 1.0) {
+            return Err(LoadSheddingError::InvalidShedOthersAt(self.shed_others_at));
+        }
+        Ok(())
+    }
+
+    /// How close the service is to saturation; 1 and above is saturated.
+    pub fn saturation(&self, load: &PoolLoad) -> f64 {
+        let in_flight = self
+            .max_in_flight
+            .map_or(0.0, |max| load.in_flight as f64 / max.max(1) as f64);
+        let latency = match (self.max_latency_ms, load.latency) {
+            (Some(max), Some(latency)) => latency.as_secs_f64() * 1000.0 / max.max(1) as f64,
+            _ => 0.0,
+        };
+        in_flight.max(latency)
+    }
+
+    /// Saturation from which requests of `plan` are shed.
+    pub fn threshold(&self, plan: Option<&str>) -> f64 {
+        if plan.is_some_and(|plan| self.priority_plans.iter().any(|p| p == plan)) {
+            1.0
+        } else {
+            self.shed_others_at
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lower_plans_are_shed_first() {
+        let config = LoadSheddingConfig {
+            max_in_flight: Some(100),
+            max_latency_ms: Some(500),
+            priority_plans: vec!["enterprise".to_string()],
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        let load = |in_flight, latency_ms: Option<u64>| PoolLoad {
+            in_flight,
+            latency: latency_ms.map(Duration::from_millis),
+        };
+        assert_eq!(config.saturation(&load(50, None)), 0.5);
+        assert_eq!(config.saturation(&load(50, Some(450))), 0.9);
+        assert_eq!(config.saturation(&load(120, Some(100))), 1.2);
+
+        let saturation = config.saturation(&load(90, None));
+        assert!(saturation >= config.threshold(Some("free")));
+        assert!(saturation >= config.threshold(None));
+        assert!(saturation < config.threshold(Some("enterprise")));
+    }
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            LoadSheddingConfig::default().validate(),
+            Err(LoadSheddingError::NoThreshold)
+        );
+        let config = LoadSheddingConfig {
+            max_in_flight: Some(10),
+            shed_others_at: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(LoadSheddingError::InvalidShedOthersAt(0.0))
+        );
+    }
+}
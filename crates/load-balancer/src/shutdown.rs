@@ -0,0 +1,105 @@
+//! What stops the server.
+//!
+//! On unix pingora watches the signals itself: SIGTERM shuts down gracefully, SIGINT at
+//! once and SIGQUIT hands the listeners over to an upgraded process. Windows has no such
+//! signals and pingora does not wait for a shutdown there, so the server waits for Ctrl+C
+//! or Ctrl+Break instead. Tests and embedders stop the server through a channel on both.
+
+#[cfg(unix)]
+use async_trait::async_trait;
+#[cfg(unix)]
+use pingora::server::{RunArgs, ShutdownSignal, ShutdownSignalWatch};
+use tokio::sync::oneshot;
+
+/// How the server learns it must stop.
+pub struct Shutdown {
+    /// Fired to stop the server; the platform's signals when unset.
+    trigger: Option<oneshot::Receiver<()>>,
+}
+
+impl Shutdown {
+    /// Stop on the platform's termination signals.
+    pub fn signals() -> Self {
+        Self { trigger: None }
+    }
+
+    /// Stop once `trigger` fires or its sender is dropped, without grace period.
+    pub fn on(trigger: oneshot::Receiver<()>) -> Self {
+        Self {
+            trigger: Some(trigger),
+        }
+    }
+
+    /// Stop when the returned sender fires or is dropped.
+    pub fn channel() -> (oneshot::Sender<()>, Self) {
+        let (tx, rx) = oneshot::channel();
+        (tx, Self::on(rx))
+    }
+
+    /// Arguments for pingora's main loop, which waits for the shutdown.
+    #[cfg(unix)]
+    pub(crate) fn into_run_args(self) -> RunArgs {
+        match self.trigger {
+            None => RunArgs::default(),
+            Some(trigger) => RunArgs {
+                shutdown_signal: Box::new(ChannelWatch(tokio::sync::Mutex::new(Some(trigger)))),
+            },
+        }
+    }
+
+    /// Block until the shutdown is requested.
+    #[cfg(windows)]
+    pub(crate) fn wait(self) {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                log::error!("Failed to start the shutdown watch: {e}");
+                return;
+            }
+        };
+        runtime.block_on(async move {
+            match self.trigger {
+                Some(trigger) => {
+                    let _ = trigger.await;
+                }
+                None => windows_signals().await,
+            }
+        });
+    }
+}
+
+/// Wait for Ctrl+C or Ctrl+Break.
+#[cfg(windows)]
+async fn windows_signals() {
+    use tokio::signal::windows::{ctrl_break, ctrl_c};
+
+    let (mut interrupt, mut brk) = match (ctrl_c(), ctrl_break()) {
+        (Ok(interrupt), Ok(brk)) => (interrupt, brk),
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("Failed to install console control handlers: {e}");
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = interrupt.recv() => log::info!("Ctrl+C received, shutting down"),
+        _ = brk.recv() => log::info!("Ctrl+Break received, shutting down"),
+    }
+}
+
+/// Pingora shutdown watch on a channel.
+#[cfg(unix)]
+struct ChannelWatch(tokio::sync::Mutex<Option<oneshot::Receiver<()>>>);
+
+#[cfg(unix)]
+#[async_trait]
+impl ShutdownSignalWatch for ChannelWatch {
+    async fn recv(&self) -> ShutdownSignal {
+        if let Some(trigger) = self.0.lock().await.take() {
+            let _ = trigger.await;
+        }
+        ShutdownSignal::FastShutdown
+    }
+}
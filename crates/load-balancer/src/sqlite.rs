@@ -0,0 +1,56 @@
+//! SQLite connection settings shared by the usage and accounts databases.
+//!
+//! Hourly usage files are read by external jobs while the writer flushes into them, so they
+//! are written in WAL mode: readers see the last committed state instead of failing with
+//! `database is locked`. Every connection also waits up to [`BUSY_TIMEOUT`] for a lock
+//! instead of failing immediately.
+
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{Connection, OpenFlags};
+
+/// How long a connection waits for a lock held by another connection.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open (or create) a database for writing, switching it to WAL mode.
+pub fn open_wal(path: impl AsRef<Path>) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // The journal mode is persistent; this is a no-op for files already in WAL mode
+    conn.query_row("PRAGMA journal_mode=WAL", [], |_| Ok(()))?;
+    Ok(conn)
+}
+
+/// Open an existing database for writing, keeping its journal mode, so a closed usage
+/// file only changes where rows do.
+pub fn open_existing(path: impl AsRef<Path>) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Open an existing database read-only.
+pub fn open_read_only(path: impl AsRef<Path>) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_wal_enables_wal_mode() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("test.db");
+        open_wal(&path).unwrap();
+
+        let conn = open_read_only(&path).unwrap();
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+}
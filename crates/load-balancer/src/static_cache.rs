@@ -0,0 +1,250 @@
+//! Edge cache for static assets of a service, such as `/docs` or `/openapi.json`.
+//!
+//! Services with a `static_cache` section have their successful `GET` responses kept in
+//! memory for `ttl_secs`, keyed by service, path and query only. The responses must not
+//! depend on the caller: the API key is not part of the key, and responses that set cookies
+//! or are marked `private`, `no-store` or `no-cache` are never stored.
+//!
+//! Every stored response carries an `ETag` (the upstream's, or a digest of the body), and a
+//! request whose `If-None-Match` lists it gets a `304 Not Modified` without a body.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::sync::MutexExt;
+
+/// Responses kept at once across all services.
+const MAX_ENTRIES: usize = 1024;
+
+/// Caching of a service's responses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct StaticCacheConfig {
+    /// How long a response is served from the cache.
+    pub ttl_secs: u64,
+    /// Larger responses are not cached.
+    pub max_body_bytes: usize,
+}
+
+impl Default for StaticCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 86_400,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// A stored response.
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+    pub etag: String,
+    expires_at: Instant,
+}
+
+impl CachedResponse {
+    /// Whether the request's `If-None-Match` lists this response's ETag.
+    pub fn not_modified(&self, req: &RequestHeader) -> bool {
+        req.headers
+            .get_all(http::header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == self.etag)
+    }
+}
+
+/// Key of a request in the cache, or `None` when it may not be served from it.
+pub fn cache_key(service: &str, req: &RequestHeader) -> Option<String> {
+    (req.method == http::Method::GET).then(|| {
+        format!(
+            "{service} {}",
+            req.uri.path_and_query().map_or("/", |pq| pq.as_str())
+        )
+    })
+}
+
+/// Whether an upstream response may be stored and served to other callers.
+pub fn is_storable(resp: &ResponseHeader) -> bool {
+    let no_store = resp
+        .headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| {
+            matches!(
+                directive.trim().to_ascii_lowercase().as_str(),
+                "no-store" | "private" | "no-cache"
+            )
+        });
+    resp.status == http::StatusCode::OK
+        && !no_store
+        && !resp.headers.contains_key(http::header::SET_COOKIE)
+}
+
+/// Responses stored per cache key.
+#[derive(Debug, Default)]
+pub struct StaticCache {
+    entries: Mutex<HashMap<String, Arc<CachedResponse>>>,
+}
+
+impl StaticCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The fresh response stored for `key`.
+    pub fn get(&self, key: &str, now: Instant) -> Option<Arc<CachedResponse>> {
+        let mut entries = self.entries.lock_or_recover();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > now => Some(entry.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store an upstream response for `ttl`. It is dropped when the cache is full of fresh
+    /// entries.
+    pub fn insert(
+        &self,
+        key: String,
+        mut header: ResponseHeader,
+        body: Bytes,
+        ttl: Duration,
+        now: Instant,
+    ) {
+        let etag = match header
+            .headers
+            .get(http::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(etag) => etag.trim_start_matches("W/").to_string(),
+            None => format!("\"{}\"", &hex::encode(Sha256::digest(&body))[..32]),
+        };
+        // The body is sent whole, with its length, whatever the upstream framing was
+        header.remove_header(&http::header::TRANSFER_ENCODING);
+        header.remove_header(&http::header::CONNECTION);
+        let _ = header.insert_header(http::header::CONTENT_LENGTH, body.len().to_string());
+        let _ = header.insert_header(http::header::ETAG, etag.as_str());
+
+        let mut entries = self.entries.lock_or_recover();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert(
+            key,
+            Arc::new(CachedResponse {
+                header,
+                body,
+                etag,
+                expires_at: now + ttl,
+            }),
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock_or_recover().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, if_none_match: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        if let Some(etag) = if_none_match {
+            req.insert_header("If-None-Match", etag).unwrap();
+        }
+        req
+    }
+
+    fn response(headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.insert_header(name.to_string(), *value).unwrap();
+        }
+        resp
+    }
+
+    #[test]
+    fn test_entries_expire_and_answer_conditional_requests() {
+        let cache = StaticCache::new();
+        let now = Instant::now();
+        let key = cache_key("docs", &request("/docs?v=2", None)).unwrap();
+        assert_eq!(key, "docs /docs?v=2");
+
+        cache.insert(
+            key.clone(),
+            response(&[("Transfer-Encoding", "chunked")]),
+            Bytes::from_static(b"<html>"),
+            Duration::from_secs(60),
+            now,
+        );
+        let entry = cache.get(&key, now).unwrap();
+        assert_eq!(entry.body, Bytes::from_static(b"<html>"));
+        assert_eq!(entry.header.headers["content-length"], "6");
+        assert!(entry.header.headers.get("transfer-encoding").is_none());
+        assert!(entry.not_modified(&request("/docs?v=2", Some(&entry.etag))));
+        assert!(entry.not_modified(&request(
+            "/docs?v=2",
+            Some(&format!("\"x\", W/{}", entry.etag))
+        )));
+        assert!(!entry.not_modified(&request("/docs?v=2", Some("\"x\""))));
+        assert!(!entry.not_modified(&request("/docs?v=2", None)));
+
+        assert!(cache.get(&key, now + Duration::from_secs(61)).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_upstream_etag_is_kept() {
+        let cache = StaticCache::new();
+        let now = Instant::now();
+        cache.insert(
+            "docs /".to_string(),
+            response(&[("ETag", "W/\"v1\"")]),
+            Bytes::new(),
+            Duration::from_secs(60),
+            now,
+        );
+        assert_eq!(cache.get("docs /", now).unwrap().etag, "\"v1\"");
+    }
+
+    #[test]
+    fn test_only_shared_successful_gets_are_cached() {
+        let post = RequestHeader::build("POST", b"/docs", None).unwrap();
+        assert!(cache_key("docs", &post).is_none());
+
+        assert!(is_storable(&response(&[(
+            "Cache-Control",
+            "public, max-age=60"
+        )])));
+        assert!(!is_storable(&response(&[(
+            "Cache-Control",
+            "max-age=60, private"
+        )])));
+        assert!(!is_storable(&response(&[("Set-Cookie", "session=1")])));
+        assert!(!is_storable(&ResponseHeader::build(404, None).unwrap()));
+    }
+}
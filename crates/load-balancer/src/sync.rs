@@ -0,0 +1,93 @@
+//! Lock access that survives poisoning.
+//!
+//! A thread panicking while it holds a `Mutex` or `RwLock` poisons it, and unwrapping the
+//! guard then panics every later request touching the same store. The stores guarded here
+//! (metrics, limiters, account data, ...) stay usable after such a panic: at worst the
+//! update in progress is lost. The lock is recovered instead, its poison cleared, and the
+//! recovery logged and counted in [`POISONED_LOCKS_COUNTER`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Counter of poisoned locks recovered.
+pub const POISONED_LOCKS_COUNTER: &str = "poisoned_locks";
+
+/// Recoveries not yet added to the metrics.
+static RECOVERED: AtomicU64 = AtomicU64::new(0);
+
+fn recovered<T, G>(poisoned: PoisonError<G>) -> G {
+    log::error!(
+        "Recovered a {} lock poisoned by a panicking thread",
+        std::any::type_name::<T>()
+    );
+    RECOVERED.fetch_add(1, Ordering::Relaxed);
+    poisoned.into_inner()
+}
+
+/// Recoveries since the last call, to be added to [`POISONED_LOCKS_COUNTER`].
+pub fn take_recovered() -> u64 {
+    RECOVERED.swap(0, Ordering::Relaxed)
+}
+
+pub trait MutexExt<T> {
+    /// Lock, recovering the mutex when poisoned.
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            recovered::<T, _>(poisoned)
+        })
+    }
+}
+
+pub trait RwLockExt<T> {
+    /// Lock for reading, recovering the lock when poisoned.
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    /// Lock for writing, recovering the lock when poisoned.
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            recovered::<T, _>(poisoned)
+        })
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|poisoned| {
+            self.clear_poison();
+            recovered::<T, _>(poisoned)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_poisoned_locks_are_recovered() {
+        let mutex = Arc::new(Mutex::new(1));
+        let rwlock = Arc::new(RwLock::new(1));
+        let (m, r) = (mutex.clone(), rwlock.clone());
+        let _ = std::thread::spawn(move || {
+            let _m = m.lock().unwrap();
+            let _r = r.write().unwrap();
+            panic!("poisoning");
+        })
+        .join();
+        assert!(mutex.is_poisoned() && rwlock.is_poisoned());
+
+        *mutex.lock_or_recover() += 1;
+        *rwlock.write_or_recover() += 1;
+        assert_eq!(*rwlock.read_or_recover(), 2);
+        assert_eq!(*mutex.lock().unwrap(), 2);
+        assert!(!mutex.is_poisoned() && !rwlock.is_poisoned());
+    }
+}
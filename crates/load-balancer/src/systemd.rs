@@ -0,0 +1,220 @@
+//! systemd service notifications.
+//!
+//! When started by systemd with `Type=notify`, `NOTIFY_SOCKET` names the socket the service
+//! reports its state to. The proxy sends `READY=1` once pingora runs its services and
+//! `STOPPING=1` when it starts draining. With `WatchdogSec=` set, systemd also passes
+//! `WATCHDOG_USEC` and restarts the service unless `WATCHDOG=1` arrives within that time;
+//! [`Watchdog`] sends it at half the interval for as long as the proxy is healthy, so a
+//! wedged proxy is restarted. Nothing is sent when not running under systemd.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::server::ExecutionPhase;
+use pingora::services::background::BackgroundService;
+use tokio::sync::broadcast;
+
+use crate::configuration::Config;
+use crate::readiness::{Phase, Readiness};
+use crate::sync::RwLockExt;
+
+/// Sends state changes to the service manager.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    socket: PathBuf,
+}
+
+impl Notifier {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    /// The notifier for `NOTIFY_SOCKET`, when set.
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os("NOTIFY_SOCKET")
+            .filter(|socket| !socket.is_empty())
+            .map(Self::new)
+    }
+
+    /// Send a newline-separated list of `KEY=VALUE` assignments.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        // Sockets in the abstract namespace are named with a leading `@`
+        #[cfg(target_os = "linux")]
+        if let Some(name) = self.socket.to_str().and_then(|s| s.strip_prefix('@')) {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        socket.send_to(state.as_bytes(), &self.socket)?;
+        Ok(())
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.notify(state) {
+            log::warn!("Failed to notify systemd of {state}: {e}");
+        }
+    }
+
+    /// Report state changes of the pingora server until it terminates.
+    pub fn follow(&self, mut phases: broadcast::Receiver<ExecutionPhase>) {
+        let mut stopping = false;
+        loop {
+            match phases.blocking_recv() {
+                Ok(ExecutionPhase::Running) => {
+                    self.send("READY=1");
+                    log::info!("Notified systemd of readiness");
+                }
+                Ok(
+                    ExecutionPhase::GracefulTerminate
+                    | ExecutionPhase::GracefulUpgradeTransferringFds
+                    | ExecutionPhase::ShutdownStarted,
+                ) if !stopping => {
+                    stopping = true;
+                    self.send("STOPPING=1");
+                }
+                Ok(ExecutionPhase::Terminated) | Err(broadcast::error::RecvError::Closed) => {
+                    return;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            }
+        }
+    }
+}
+
+/// Interval between watchdog keepalives: half the timeout systemd set for this process.
+pub fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    // The watchdog is meant for another process when its PID is given
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Background service sending watchdog keepalives while the proxy is healthy.
+pub struct Watchdog {
+    notifier: Notifier,
+    interval: Duration,
+    config: Arc<RwLock<Config>>,
+    readiness: Arc<Readiness>,
+}
+
+impl Watchdog {
+    /// The watchdog requested by systemd through `WATCHDOG_USEC`, when any.
+    pub fn from_env(
+        notifier: Notifier,
+        config: Arc<RwLock<Config>>,
+        readiness: Arc<Readiness>,
+    ) -> Option<Self> {
+        let usec = std::env::var("WATCHDOG_USEC").ok();
+        let pid = std::env::var("WATCHDOG_PID").ok();
+        let interval = watchdog_interval(usec.as_deref(), pid.as_deref())?;
+        Some(Self {
+            notifier,
+            interval,
+            config,
+            readiness,
+        })
+    }
+
+    /// Whether the proxy can serve requests: its config is loaded and not stuck behind a
+    /// writer. A hung lock hangs this check as well, and the keepalives stop.
+    fn healthy(&self) -> bool {
+        let _config = self.config.read_or_recover();
+        self.readiness.is_complete(Phase::BackendConfig)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Watchdog {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        log::info!("systemd watchdog keepalive every {:?}", self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+
+            if self.healthy() {
+                self.notifier.send("WATCHDOG=1");
+            } else {
+                log::warn!("Proxy unhealthy, withholding the systemd watchdog keepalive");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notifications_reach_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::new(&path);
+        notifier.notify("READY=1").unwrap();
+        notifier.notify("WATCHDOG=1").unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_server_phases_are_reported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        let (tx, rx) = broadcast::channel(8);
+        for phase in [
+            ExecutionPhase::Bootstrap,
+            ExecutionPhase::Running,
+            ExecutionPhase::GracefulTerminate,
+            ExecutionPhase::ShutdownStarted,
+            ExecutionPhase::Terminated,
+        ] {
+            tx.send(phase).unwrap();
+        }
+        Notifier::new(&path).follow(rx);
+
+        listener.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 64];
+        let mut sent = Vec::new();
+        while let Ok(n) = listener.recv(&mut buf) {
+            sent.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+        }
+        assert_eq!(sent, vec!["READY=1", "STOPPING=1"]);
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval(Some("10000000"), None),
+            Some(Duration::from_secs(5))
+        );
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("2000000"), Some(&pid)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(watchdog_interval(Some("2000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
@@ -0,0 +1,324 @@
+//! Request tags set by rules over the request header, path and query.
+//!
+//! Each rule tags the requests its condition matches. A condition is one or more tests
+//! joined by `&&`:
+//!
+//! ```yaml
+//! tagging:
+//!   rules:
+//!     - when: 'header.user-agent *= kube-probe'
+//!       tag: probe
+//!     - when: 'path ^= /geocode/batch && query.async == "true"'
+//!       tag: batch
+//!     - when: header.x-canary
+//!       tag: canary
+//!   rps_limits: { batch: 2 }
+//!   services: { canary: geocode_canary }
+//!   log_sample: { probe: 0.01 }
+//! ```
+//!
+//! Tests read `path`, `header.<name>` or `query.<name>` and compare with `==` (equals),
+//! `^=` (starts with) or `*=` (contains); a header or query parameter without an operator
+//! only has to be present. Query values are compared undecoded. Values may be quoted.
+//!
+//! Tags then select a per-key rate limit of their own, the service (and so the backends)
+//! the request goes to, and the share of access log lines kept.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use pingora::http::RequestHeader;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Path,
+    Header(String),
+    Query(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Test {
+    Present,
+    Equals(String),
+    StartsWith(String),
+    Contains(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    operand: Operand,
+    test: Test,
+}
+
+/// A parsed `when` condition.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expr {
+    conditions: Vec<Condition>,
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+        .unwrap_or(value)
+}
+
+fn parse_condition(s: &str) -> Result<Condition, String> {
+    let (left, test) = match ["==", "^=", "*="]
+        .iter()
+        .filter_map(|op| s.find(op).map(|i| (i, *op)))
+        .min()
+    {
+        Some((i, op)) => {
+            let value = unquote(s[i + op.len()..].trim()).to_string();
+            let test = match op {
+                "==" => Test::Equals(value),
+                "^=" => Test::StartsWith(value),
+                _ => Test::Contains(value),
+            };
+            (s[..i].trim(), test)
+        }
+        None => (s, Test::Present),
+    };
+
+    let operand = if left == "path" {
+        Operand::Path
+    } else if let Some(name) = left.strip_prefix("header.") {
+        Operand::Header(name.to_ascii_lowercase())
+    } else if let Some(name) = left.strip_prefix("query.") {
+        Operand::Query(name.to_string())
+    } else {
+        return Err(format!(
+            "unknown operand '{left}', expected path, header.<name> or query.<name>"
+        ));
+    };
+    match &operand {
+        Operand::Header(name) | Operand::Query(name) if name.is_empty() => {
+            Err(format!("missing name in '{left}'"))
+        }
+        Operand::Path if test == Test::Present => Err("path needs an operator".to_string()),
+        _ => Ok(Condition { operand, test }),
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        let conditions = s
+            .split("&&")
+            .map(|c| parse_condition(c.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid condition '{s}': {e}"))?;
+        Ok(Self { conditions })
+    }
+}
+
+impl Expr {
+    /// Whether the request satisfies every test.
+    pub fn matches(&self, req: &RequestHeader) -> bool {
+        self.conditions.iter().all(|condition| {
+            let value = match &condition.operand {
+                Operand::Path => Some(req.uri.path()),
+                Operand::Header(name) => {
+                    req.headers.get(name.as_str()).and_then(|v| v.to_str().ok())
+                }
+                Operand::Query(name) => req.uri.query().and_then(|query| {
+                    query.split('&').find_map(|pair| {
+                        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        (key == name).then_some(value)
+                    })
+                }),
+            };
+            value.is_some_and(|value| match &condition.test {
+                Test::Present => true,
+                Test::Equals(expected) => value == expected,
+                Test::StartsWith(prefix) => value.starts_with(prefix.as_str()),
+                Test::Contains(needle) => value.contains(needle.as_str()),
+            })
+        })
+    }
+}
+
+/// Tags the requests `when` matches with `tag`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TagRule {
+    pub when: Expr,
+    pub tag: String,
+}
+
+#[derive(Debug)]
+pub enum TaggingError {
+    /// A tag is mapped to a service that is not defined.
+    UndefinedService { tag: String, service: String },
+    /// A log sample rate is outside 0..=1.
+    InvalidLogSample(String),
+}
+
+impl fmt::Display for TaggingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaggingError::UndefinedService { tag, service } => {
+                write!(f, "Tag '{}' routes to undefined service '{}'", tag, service)
+            }
+            TaggingError::InvalidLogSample(tag) => {
+                write!(f, "Log sample rate of tag '{}' must be within 0 and 1", tag)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TaggingError {}
+
+/// Tagging rules and what the tags select.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TaggingConfig {
+    /// Evaluated in order; a request gets the tag of every rule it matches.
+    pub rules: Vec<TagRule>,
+    /// Requests per second per API key for requests with the tag, counted apart from the
+    /// key's other requests.
+    pub rps_limits: HashMap<String, isize>,
+    /// Service requests with the tag are routed to, whatever their path.
+    pub services: HashMap<String, String>,
+    /// Share of access log lines kept for requests with the tag.
+    pub log_sample: HashMap<String, f64>,
+}
+
+impl TaggingConfig {
+    /// Check the settings against the defined services.
+    pub fn validate(&self, has_service: impl Fn(&str) -> bool) -> Result<(), TaggingError> {
+        if let Some((tag, service)) = self
+            .services
+            .iter()
+            .find(|(_, service)| !has_service(service))
+        {
+            return Err(TaggingError::UndefinedService {
+                tag: tag.clone(),
+                service: service.clone(),
+            });
+        }
+        if let Some((tag, _)) = self
+            .log_sample
+            .iter()
+            .find(|(_, rate)| !(0.0..=1.0).contains(*rate))
+        {
+            return Err(TaggingError::InvalidLogSample(tag.clone()));
+        }
+        Ok(())
+    }
+
+    /// Tags of a request, in rule order and without repeats.
+    pub fn tags(&self, req: &RequestHeader) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for rule in &self.rules {
+            if !tags.contains(&rule.tag) && rule.when.matches(req) {
+                tags.push(rule.tag.clone());
+            }
+        }
+        tags
+    }
+
+    /// The first tag with a rate limit, and that limit.
+    pub fn rps_limit<'a>(&self, tags: &'a [String]) -> Option<(&'a str, isize)> {
+        tags.iter()
+            .find_map(|tag| Some((tag.as_str(), *self.rps_limits.get(tag)?)))
+    }
+
+    /// Service selected by the first tag mapped to one.
+    pub fn service(&self, tags: &[String]) -> Option<&String> {
+        tags.iter().find_map(|tag| self.services.get(tag))
+    }
+
+    /// Lowest log sample rate of the tags; `None` when every line is kept.
+    pub fn log_sample(&self, tags: &[String]) -> Option<f64> {
+        tags.iter()
+            .filter_map(|tag| self.log_sample.get(tag).copied())
+            .reduce(f64::min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn expr(s: &str) -> Expr {
+        Expr::try_from(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_conditions() {
+        let req = request(
+            "/geocode/batch?async=true&q",
+            &[("User-Agent", "kube-probe/1.29")],
+        );
+        assert!(expr("path ^= /geocode").matches(&req));
+        assert!(expr("path == /geocode/batch").matches(&req));
+        assert!(!expr("path == /geocode").matches(&req));
+        assert!(expr("header.user-agent *= kube-probe").matches(&req));
+        assert!(expr("header.User-Agent").matches(&req));
+        assert!(!expr("header.x-canary").matches(&req));
+        assert!(expr(r#"query.async == "true" && query.q"#).matches(&req));
+        assert!(!expr("query.async == true && path ^= /search").matches(&req));
+        assert!(expr("query.q == ''").matches(&req));
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        for s in ["", "path", "method == GET", "header. == x", "path == /a &&"] {
+            assert!(Expr::try_from(s.to_string()).is_err(), "{s:?} should fail");
+        }
+    }
+
+    #[test]
+    fn test_tags_select_limits_services_and_sampling() {
+        let config: TaggingConfig = serde_yaml::from_str(
+            r#"
+            rules:
+              - when: header.x-canary
+                tag: canary
+              - when: path ^= /geocode/batch
+                tag: batch
+              - when: header.user-agent *= probe
+                tag: probe
+              - when: path ^= /geocode
+                tag: batch
+            rps_limits: { batch: 2 }
+            services: { canary: geocode_canary }
+            log_sample: { probe: 0.01, canary: 0.5 }
+            "#,
+        )
+        .unwrap();
+
+        let tags = config.tags(&request("/geocode/batch", &[("x-canary", "1")]));
+        assert_eq!(tags, vec!["canary".to_string(), "batch".to_string()]);
+        assert_eq!(config.rps_limit(&tags), Some(("batch", 2)));
+        assert_eq!(config.service(&tags).unwrap(), "geocode_canary");
+        assert_eq!(config.log_sample(&tags), Some(0.5));
+
+        let tags = config.tags(&request("/health", &[("user-agent", "probe")]));
+        assert_eq!(config.rps_limit(&tags), None);
+        assert_eq!(config.service(&tags), None);
+        assert_eq!(config.log_sample(&tags), Some(0.01));
+
+        assert!(config.validate(|s| s == "geocode_canary").is_ok());
+        assert!(matches!(
+            config.validate(|s| s == "geocode"),
+            Err(TaggingError::UndefinedService { .. })
+        ));
+        assert!(
+            serde_yaml::from_str::<TaggingConfig>("rules: [{when: 'body == x', tag: t}]").is_err()
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Tenants: API key populations told apart by the prefix of their tokens.
+//!
+//! The prefix is read off the raw key before any account lookup, so a tenant's defaults
+//! apply even to keys its accounts DB does not know. A tenant restricts which services its
+//! keys may call and may replace the default limit of keys without a plan.
+
+use std::collections::HashSet;
+
+use crate::accounts::{Limit, LimitSource};
+use crate::configuration::TenantConfig;
+
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub name: String,
+    pub token_prefix: String,
+    /// Requests per second for keys without a plan.
+    pub default_rps_limit: Option<isize>,
+    /// Services the tenant's keys may call; every service when empty.
+    allowed_services: HashSet<String>,
+}
+
+impl Tenant {
+    /// Whether the tenant's keys may call `service`.
+    pub fn allows(&self, service: &str) -> bool {
+        self.allowed_services.is_empty() || self.allowed_services.contains(service)
+    }
+
+    /// `limit` with the tenant's default applied to keys that only have the global default.
+    pub fn apply_default(&self, mut limit: Limit) -> Limit {
+        if limit.source == LimitSource::Default
+            && let Some(rps_limit) = self.default_rps_limit
+        {
+            limit.quota = rps_limit;
+            limit.source = LimitSource::Tenant;
+        }
+        limit
+    }
+}
+
+/// Configured tenants, looked up by token prefix.
+#[derive(Debug, Clone, Default)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+impl Tenants {
+    pub fn new(configs: &[TenantConfig]) -> Self {
+        Self {
+            tenants: configs
+                .iter()
+                .map(|config| Tenant {
+                    name: config.name.clone(),
+                    token_prefix: config.token_prefix.clone(),
+                    default_rps_limit: config.default_rps_limit,
+                    allowed_services: config.allowed_services.iter().cloned().collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Tenant of a raw API key: the one whose prefix the token starts with, followed by `_`.
+    pub fn for_key(&self, api_key: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| {
+            api_key
+                .strip_prefix(tenant.token_prefix.as_str())
+                .is_some_and(|rest| rest.starts_with('_'))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenants() -> Tenants {
+        let configs: Vec<TenantConfig> = serde_yaml::from_str(
+            r#"
+            - name: partner
+              token_prefix: partner
+              default_rps_limit: 50
+              allowed_services: [geocode]
+            - name: internal
+              token_prefix: int
+            "#,
+        )
+        .unwrap();
+        Tenants::new(&configs)
+    }
+
+    #[test]
+    fn test_tenant_is_selected_by_token_prefix() {
+        let tenants = tenants();
+        assert_eq!(tenants.for_key("partner_v1_abc").unwrap().name, "partner");
+        assert_eq!(tenants.for_key("int_v1_abc").unwrap().name, "internal");
+        assert!(tenants.for_key("partnerx_v1_abc").is_none());
+        assert!(tenants.for_key("lb_v1_abc").is_none());
+        assert!(tenants.for_key("legacy-key").is_none());
+    }
+
+    #[test]
+    fn test_tenant_services_and_default_limit() {
+        let tenants = tenants();
+        let partner = tenants.for_key("partner_x").unwrap();
+        assert!(partner.allows("geocode"));
+        assert!(!partner.allows("search"));
+        assert!(tenants.for_key("int_x").unwrap().allows("search"));
+
+        let limit = |source| Limit {
+            quota: 5,
+            per_seconds: 1,
+            burst: None,
+            source,
+        };
+        let applied = partner.apply_default(limit(LimitSource::Default));
+        assert_eq!((applied.quota, applied.source), (50, LimitSource::Tenant));
+        assert_eq!(partner.apply_default(limit(LimitSource::Plan)).quota, 5);
+        let internal = tenants.for_key("int_x").unwrap();
+        assert_eq!(internal.apply_default(limit(LimitSource::Default)).quota, 5);
+    }
+}
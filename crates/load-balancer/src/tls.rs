@@ -0,0 +1,156 @@
+//! TLS to upstreams, with certificate pinning.
+//!
+//! A backend with a `tls` section is reached over TLS instead of plain HTTP. Its endpoints
+//! must present a certificate for `sni` that chains to a CA of `ca_file`, or to a system
+//! root without one. For sensitive internal upstreams, `cert_sha256` pins the certificates
+//! accepted by the hex SHA-256 of their DER; list the current and the next certificate to
+//! rotate them without downtime:
+//!
+//! ```yaml
+//! backends:
+//!   - service: billing
+//!     tls:
+//!       sni: billing.internal
+//!       ca_file: /etc/lb/internal-ca.pem
+//!       cert_sha256:
+//!         - 3b5e0f6a1c5a2ed0b1a2bd1e6a2e9d0c6f4e1f1c9a4b5d3e2f1a0b9c8d7e6f5a
+//!     backend: { type: basic, ip: 10.120.32.40, port: 8443 }
+//! ```
+//!
+//! Pins are of the whole leaf certificate rather than its public key, as pingora only
+//! exposes the digest of the presented certificate. A connection whose certificate does
+//! not chain to the trusted CAs or matches no pin fails, and is counted in
+//! [`TLS_MISMATCH_COUNTER`] by service.
+
+use std::sync::Arc;
+
+use pingora::prelude::HttpPeer;
+use pingora::protocols::Digest;
+use pingora::protocols::tls::CaType;
+use pingora::tls::x509::X509;
+use serde::{Deserialize, Serialize};
+
+/// Labeled counter of upstream connections failed because the presented certificate did
+/// not chain to the trusted CAs or matched no pin, by service.
+pub const TLS_MISMATCH_COUNTER: &str = "upstream_tls_mismatch";
+
+/// TLS settings of a backend.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UpstreamTlsConfig {
+    /// Server name sent to the endpoints, which their certificates must be valid for.
+    pub sni: String,
+    /// PEM file of the CAs trusted for the endpoints' certificates, instead of the system
+    /// roots.
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// Hex SHA-256 of the DER of each accepted certificate; any trusted one when empty.
+    #[serde(default)]
+    pub cert_sha256: Vec<String>,
+}
+
+impl UpstreamTlsConfig {
+    /// Check the settings without reading `ca_file`.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sni.is_empty() {
+            return Err("tls.sni must not be empty".to_string());
+        }
+        if let Some(pin) = self
+            .cert_sha256
+            .iter()
+            .find(|pin| pin.len() != 64 || !pin.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(format!("tls.cert_sha256 '{pin}' is not a hex SHA-256"));
+        }
+        Ok(())
+    }
+}
+
+/// TLS settings of a backend, with its CAs loaded.
+#[derive(Debug)]
+pub struct UpstreamTls {
+    sni: String,
+    ca: Option<Arc<CaType>>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl UpstreamTls {
+    /// Load the CAs of validated settings.
+    pub fn load(config: &UpstreamTlsConfig) -> Result<Self, String> {
+        let ca = match &config.ca_file {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+                let certs = X509::stack_from_pem(&pem).map_err(|e| format!("{path}: {e}"))?;
+                if certs.is_empty() {
+                    return Err(format!("{path}: no certificates"));
+                }
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+        let pins = config
+            .cert_sha256
+            .iter()
+            .map(|pin| hex::decode(pin).map_err(|e| format!("cert_sha256 '{pin}': {e}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            sni: config.sni.clone(),
+            ca,
+            pins,
+        })
+    }
+
+    /// A peer connecting to `addr` over TLS with these settings.
+    pub fn peer(&self, addr: String) -> HttpPeer {
+        let mut peer = HttpPeer::new(addr, true, self.sni.clone());
+        peer.options.ca = self.ca.clone();
+        peer
+    }
+
+    /// Whether the certificate of a connection with `digest` matches a pin; any does
+    /// without pins.
+    pub fn accepts(&self, digest: Option<&Digest>) -> bool {
+        self.pins.is_empty()
+            || digest
+                .and_then(|digest| digest.ssl_digest.as_ref())
+                .is_some_and(|ssl| self.pins.contains(&ssl.cert_digest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pins: &[&str]) -> UpstreamTlsConfig {
+        UpstreamTlsConfig {
+            sni: "billing.internal".to_string(),
+            ca_file: None,
+            cert_sha256: pins.iter().map(|pin| pin.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config(&[&"ab".repeat(32)]).validate().is_ok());
+        assert!(config(&["abcd"]).validate().is_err());
+        assert!(config(&[&"zz".repeat(32)]).validate().is_err());
+        let without_sni = UpstreamTlsConfig {
+            sni: String::new(),
+            ..config(&[])
+        };
+        assert!(without_sni.validate().is_err());
+    }
+
+    #[test]
+    fn test_pins_are_matched_against_the_certificate_digest() {
+        let pin = "ab".repeat(32);
+        let tls = UpstreamTls::load(&config(&[&pin])).unwrap();
+        assert!(!tls.accepts(None));
+        assert!(UpstreamTls::load(&config(&[])).unwrap().accepts(None));
+
+        let missing = UpstreamTlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".to_string()),
+            ..config(&[])
+        };
+        assert!(UpstreamTls::load(&missing).is_err());
+    }
+}
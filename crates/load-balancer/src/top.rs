@@ -0,0 +1,326 @@
+//! Top consumers by requests, rate-limited requests and response bytes.
+//!
+//! Computed from the per-minute counts in [`Metrics`], so it covers every listener and needs
+//! no usage directory. Keys are reported by fingerprint; accounts are found through the
+//! limiter. Served by the admin listener and, when enabled, logged periodically on the `top`
+//! target.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+use serde::Serialize;
+
+use crate::accounts::{AccountRatelimit, mask_email};
+use crate::configuration::TopConsumersConfig;
+use crate::metric::Metrics;
+
+/// Windows covered by the periodic log summary, in minutes.
+pub const LOG_WINDOWS: [u64; 2] = [5, 60];
+
+/// What consumers are ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ranking {
+    Requests,
+    RateLimited,
+    Bytes,
+}
+
+impl FromStr for Ranking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "requests" => Ok(Ranking::Requests),
+            "rate_limited" | "429" => Ok(Ranking::RateLimited),
+            "bytes" => Ok(Ranking::Bytes),
+            other => Err(format!("unknown ranking {other:?}")),
+        }
+    }
+}
+
+/// Traffic totals over a window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub bytes: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.requests += other.requests;
+        self.rate_limited += other.rate_limited;
+        self.bytes += other.bytes;
+    }
+
+    fn value(&self, by: Ranking) -> u64 {
+        match by {
+            Ranking::Requests => self.requests,
+            Ranking::RateLimited => self.rate_limited,
+            Ranking::Bytes => self.bytes,
+        }
+    }
+}
+
+/// A key's traffic over the window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyUsage {
+    /// Key fingerprint, or a placeholder such as `<missing>` for unauthenticated traffic.
+    pub key_id: String,
+    pub account_id: Option<i64>,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// An account's traffic over the window, summed over its keys.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountUsage {
+    /// Token prefix of the partition holding the account.
+    pub partition: String,
+    pub account_id: i64,
+    /// Masked account email.
+    pub email: String,
+    pub keys: usize,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// The top keys and accounts over a window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopConsumers {
+    pub window_minutes: u64,
+    pub by: Ranking,
+    pub keys: Vec<KeyUsage>,
+    pub accounts: Vec<AccountUsage>,
+}
+
+/// Sort by `by` (descending, ties by name) and keep the first `n`.
+fn top_n<T>(mut items: Vec<T>, n: usize, value: impl Fn(&T) -> (u64, String)) -> Vec<T> {
+    items.sort_by(|a, b| {
+        let (va, na) = value(a);
+        let (vb, nb) = value(b);
+        vb.cmp(&va).then(na.cmp(&nb))
+    });
+    items.truncate(n);
+    items
+}
+
+/// The `n` heaviest keys and accounts by `by` over the last `window_minutes` minutes,
+/// including the current one.
+pub fn top_consumers(
+    metrics: &Metrics,
+    limiter: &AccountRatelimit,
+    window_minutes: u64,
+    by: Ranking,
+    n: usize,
+) -> TopConsumers {
+    let current = metrics.current_minute();
+    let first = current.saturating_sub(window_minutes.saturating_sub(1));
+    let in_window = |minute: &u64| (first..=current).contains(minute);
+
+    let mut keys = Vec::new();
+    let mut accounts: HashMap<(String, i64), AccountUsage> = HashMap::new();
+    for key_id in metrics.keys() {
+        let mut totals = Totals::default();
+        for (_, statuses) in metrics
+            .snapshot(&key_id)
+            .iter()
+            .filter(|(m, _)| in_window(m))
+        {
+            for (status, count) in statuses {
+                totals.requests += count;
+                if *status == 429 {
+                    totals.rate_limited += count;
+                }
+            }
+        }
+        totals.bytes = metrics
+            .bytes_snapshot(&key_id)
+            .iter()
+            .filter(|(m, _)| in_window(m))
+            .map(|(_, bytes)| bytes)
+            .sum();
+        if totals == Totals::default() {
+            continue;
+        }
+
+        let meta = limiter.metadata_for_fingerprint(&key_id);
+        if let Some(meta) = &meta {
+            let partition = key_id
+                .rsplit_once('_')
+                .map_or_else(String::new, |(prefix, _)| prefix.to_string());
+            let account = accounts
+                .entry((partition.clone(), meta.account_id))
+                .or_insert_with(|| AccountUsage {
+                    partition,
+                    account_id: meta.account_id,
+                    email: mask_email(&meta.email),
+                    keys: 0,
+                    totals: Totals::default(),
+                });
+            account.keys += 1;
+            account.totals.add(totals);
+        }
+        keys.push(KeyUsage {
+            key_id,
+            account_id: meta.map(|m| m.account_id),
+            totals,
+        });
+    }
+
+    TopConsumers {
+        window_minutes,
+        by,
+        keys: top_n(keys, n, |k| (k.totals.value(by), k.key_id.clone())),
+        accounts: top_n(accounts.into_values().collect(), n, |a| {
+            (
+                a.totals.value(by),
+                format!("{}{}", a.partition, a.account_id),
+            )
+        }),
+    }
+}
+
+/// Background service logging the top consumers on the `top` target.
+pub struct TopConsumersLogger {
+    config: TopConsumersConfig,
+    metrics: Arc<Metrics>,
+    limiter: Arc<AccountRatelimit>,
+}
+
+impl TopConsumersLogger {
+    pub fn new(
+        config: TopConsumersConfig,
+        metrics: Arc<Metrics>,
+        limiter: Arc<AccountRatelimit>,
+    ) -> Self {
+        Self {
+            config,
+            metrics,
+            limiter,
+        }
+    }
+
+    /// One summary per window and ranking.
+    pub fn summaries(&self) -> Vec<TopConsumers> {
+        LOG_WINDOWS
+            .iter()
+            .flat_map(|window| {
+                [Ranking::Requests, Ranking::RateLimited, Ranking::Bytes].map(|by| {
+                    top_consumers(&self.metrics, &self.limiter, *window, by, self.config.n)
+                })
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TopConsumersLogger {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        let interval = Duration::from_secs(self.config.interval_secs.max(1));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(interval) => {}
+            }
+
+            for summary in self.summaries() {
+                if summary.keys.is_empty() {
+                    continue;
+                }
+                if let Ok(json) = serde_json::to_string(&summary) {
+                    log::info!(target: "top", "{json}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{Account, AccountStore, ApiKey, Plan};
+    use crate::clock::TestClock;
+    use std::sync::RwLock;
+    use std::time::SystemTime;
+
+    #[test]
+    fn ranks_keys_and_accounts_within_window() {
+        let clock = Arc::new(TestClock::at_unix(10 * 60));
+        let metrics = Metrics::new().with_clock(clock.clone());
+        let at = |minute: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(minute * 60);
+
+        let mut store = AccountStore::new();
+        store.upsert_plan(Plan {
+            plan_id: 1,
+            name: "Free".to_string(),
+            monthly_quota: 1000,
+            rps_limit: 5,
+            price_per_1k_req: 0.0,
+            burst_cap: 0,
+            accrual_rate: 0.0,
+            priority: 0,
+        });
+        store.upsert_account(Account {
+            account_id: 7,
+            email: "heavy@example.com".to_string(),
+            plan_id: 1,
+            billing_status: "active".to_string(),
+            residency: None,
+        });
+        let mut fingerprints = Vec::new();
+        for (i, hash) in ["h1", "h2"].iter().enumerate() {
+            let api_key = uuid::Uuid::from_u128((i as u128 + 1) << 120);
+            store.upsert_api_key(ApiKey {
+                api_key_id: i as i64 + 1,
+                api_key,
+                account_id: 7,
+                api_key_hash: hash.to_string(),
+                is_active: true,
+                created_at: None,
+                last_used_at: None,
+                scopes: Vec::new(),
+                version: 0,
+                plan_bound: false,
+            });
+            fingerprints.push(api_key::fingerprint(store.token_prefix(), api_key));
+        }
+        let limiter = AccountRatelimit::new(Arc::new(RwLock::new(store)));
+
+        for _ in 0..3 {
+            metrics.record_at(&fingerprints[0], 200, at(10));
+        }
+        metrics.record_at(&fingerprints[1], 429, at(9));
+        metrics.record_bytes_at(&fingerprints[1], 5000, at(9));
+        metrics.record_at("<missing>", 401, at(8));
+        metrics.record_at("<missing>", 401, at(8));
+        // Outside a 5 minute window
+        for _ in 0..10 {
+            metrics.record_at("old", 200, at(2));
+        }
+
+        let top = top_consumers(&metrics, &limiter, 5, Ranking::Requests, 2);
+        let keys: Vec<(&str, u64)> = top
+            .keys
+            .iter()
+            .map(|k| (k.key_id.as_str(), k.totals.requests))
+            .collect();
+        assert_eq!(keys, vec![(fingerprints[0].as_str(), 3), ("<missing>", 2)]);
+        assert_eq!(top.accounts.len(), 1);
+        assert_eq!(top.accounts[0].account_id, 7);
+        assert_eq!(top.accounts[0].keys, 2);
+        assert_eq!(top.accounts[0].totals.requests, 4);
+
+        let by_bytes = top_consumers(&metrics, &limiter, 5, Ranking::Bytes, 1);
+        assert_eq!(by_bytes.keys[0].key_id, fingerprints[1]);
+        assert_eq!(by_bytes.keys[0].totals.rate_limited, 1);
+
+        let hour = top_consumers(&metrics, &limiter, 60, Ranking::Requests, 1);
+        assert_eq!(hour.keys[0].key_id, "old");
+    }
+}
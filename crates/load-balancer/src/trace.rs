@@ -0,0 +1,171 @@
+//! Per-key request traces for support.
+//!
+//! An operator arms tracing for a key fingerprint through the admin API; the next `n`
+//! requests of that key record each decision the LB takes (routing, rate limiting, upstream
+//! choice, response) with the time since the request was received. The traces are kept in
+//! memory, the last [`MAX_TRACES_PER_KEY`] per key, until read back through the admin API,
+//! so a single customer can be debugged without verbose logging for everyone.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::sync::MutexExt;
+
+/// Traces kept per key, and the most requests that can be armed at once.
+pub const MAX_TRACES_PER_KEY: usize = 100;
+/// Keys that can have traces at once.
+const MAX_TRACED_KEYS: usize = 32;
+
+/// A decision taken while handling a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceEvent {
+    pub stage: &'static str,
+    /// Microseconds since the request header was received.
+    pub at_us: u64,
+    pub detail: String,
+}
+
+/// A finished request of a traced key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Trace {
+    /// Unix timestamp the request finished at.
+    pub finished_at: i64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_us: u64,
+    pub events: Vec<TraceEvent>,
+}
+
+#[derive(Debug, Default)]
+struct KeyTraces {
+    /// Requests still to be traced.
+    remaining: usize,
+    traces: VecDeque<Trace>,
+}
+
+/// Armed keys and their captured traces.
+#[derive(Debug, Default)]
+pub struct TraceCapture {
+    keys: Mutex<HashMap<String, KeyTraces>>,
+}
+
+impl TraceCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trace the next `n` requests of the key, replacing any count still armed. Returns
+    /// the count armed, or `None` when too many keys are traced already.
+    pub fn arm(&self, fingerprint: &str, n: usize) -> Option<usize> {
+        let mut keys = self.keys.lock_or_recover();
+        if !keys.contains_key(fingerprint) && keys.len() >= MAX_TRACED_KEYS {
+            // Make room from keys with nothing armed or captured
+            keys.retain(|_, key| key.remaining > 0 || !key.traces.is_empty());
+            if keys.len() >= MAX_TRACED_KEYS {
+                return None;
+            }
+        }
+        let n = n.min(MAX_TRACES_PER_KEY);
+        keys.entry(fingerprint.to_string()).or_default().remaining = n;
+        Some(n)
+    }
+
+    /// Whether this request of the key is traced; counts it against the armed requests.
+    pub fn claim(&self, fingerprint: &str) -> bool {
+        let mut keys = self.keys.lock_or_recover();
+        match keys.get_mut(fingerprint) {
+            Some(key) if key.remaining > 0 => {
+                key.remaining -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keep a finished trace of the key, dropping its oldest when full.
+    pub fn record(&self, fingerprint: &str, trace: Trace) {
+        let mut keys = self.keys.lock_or_recover();
+        if let Some(key) = keys.get_mut(fingerprint) {
+            if key.traces.len() >= MAX_TRACES_PER_KEY {
+                key.traces.pop_front();
+            }
+            key.traces.push_back(trace);
+        }
+    }
+
+    /// Requests still to be traced and the traces captured for the key, oldest first.
+    pub fn traces(&self, fingerprint: &str) -> (usize, Vec<Trace>) {
+        let keys = self.keys.lock_or_recover();
+        keys.get(fingerprint).map_or((0, Vec::new()), |key| {
+            (key.remaining, key.traces.iter().cloned().collect())
+        })
+    }
+
+    /// Stop tracing the key and drop its traces.
+    pub fn clear(&self, fingerprint: &str) -> bool {
+        self.keys.lock_or_recover().remove(fingerprint).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(status: u16) -> Trace {
+        Trace {
+            finished_at: 0,
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            status,
+            duration_us: 0,
+            events: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_only_armed_requests_are_traced() {
+        let capture = TraceCapture::new();
+        assert!(!capture.claim("fp"));
+
+        assert_eq!(capture.arm("fp", 2), Some(2));
+        assert!(capture.claim("fp"));
+        capture.record("fp", trace(200));
+        assert!(capture.claim("fp"));
+        capture.record("fp", trace(429));
+        assert!(!capture.claim("fp"));
+        assert!(!capture.claim("other"));
+
+        let (remaining, traces) = capture.traces("fp");
+        assert_eq!(remaining, 0);
+        assert_eq!(
+            traces.iter().map(|t| t.status).collect::<Vec<_>>(),
+            vec![200, 429]
+        );
+
+        assert!(capture.clear("fp"));
+        assert!(capture.traces("fp").1.is_empty());
+    }
+
+    #[test]
+    fn test_buffers_are_bounded() {
+        let capture = TraceCapture::new();
+        assert_eq!(capture.arm("fp", 1000), Some(MAX_TRACES_PER_KEY));
+        for status in 0..MAX_TRACES_PER_KEY as u16 + 5 {
+            capture.record("fp", trace(status));
+        }
+        let (_, traces) = capture.traces("fp");
+        assert_eq!(traces.len(), MAX_TRACES_PER_KEY);
+        assert_eq!(traces[0].status, 5);
+
+        for i in 1..MAX_TRACED_KEYS {
+            capture.arm(&format!("key{i}"), 1);
+        }
+        assert_eq!(capture.arm("one-too-many", 1), None);
+        // Keys with nothing pending or captured make room
+        capture.arm("key1", 0);
+        assert_eq!(capture.arm("one-too-many", 1), Some(1));
+    }
+}
@@ -0,0 +1,2039 @@
+//! Upstream endpoints of backends.
+//!
+//! Each backend in the backend config resolves to an [`UpstreamsProvider`] when the config
+//! is loaded: `basic` backends to a fixed [`StaticUpstreams`], or to a [`DnsUpstreams`]
+//! re-resolving their host name when it is not an IP address, and `hetzner` backends to a
+//! [`HetznerUpstreams`] discovering servers by label. The backends of a service form a
+//! [`ServicePool`] that `upstream_peer` picks from by weight, in round robin, by latency or
+//! by a hash of the request ([`Strategy`]); [`UpstreamRefresher`] keeps the dynamic
+//! providers current and alerts on endpoints ejected by passive health checking and pools
+//! going down.
+//!
+//! Providers survive config reloads while their backend definition is unchanged, so a
+//! reload does not drop the endpoints discovered so far.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use pingora::services::background::BackgroundService;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::alert::AlertSink;
+use crate::configuration::{Backend, Config};
+use crate::egress::EgressProxy;
+use crate::hetzner::{HetznerConfig, HetznerUpstreams};
+use crate::selection::HashRing;
+use crate::selector::LabelSelector;
+use crate::sync::{MutexExt, RwLockExt};
+use crate::tls::UpstreamTls;
+use crate::xds::{XdsConfig, XdsUpstreams};
+
+/// An upstream address requests can be sent to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// `ip:port`, or `[ip]:port` for IPv6.
+    pub addr: String,
+    /// Weight relative to the other endpoints of its backend.
+    pub weight: u32,
+    /// Zone the endpoint runs in, when its provider knows it; the zone of its backend
+    /// otherwise.
+    pub zone: Option<String>,
+}
+
+impl Endpoint {
+    pub fn new(ip: &str, port: u16) -> Self {
+        let addr = match ip_literal(ip) {
+            Some(ip) => SocketAddr::new(ip, port).to_string(),
+            None => format!("{ip}:{port}"),
+        };
+        Self {
+            addr,
+            weight: 1,
+            zone: None,
+        }
+    }
+
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn with_zone(mut self, zone: impl Into<String>) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Whether the endpoint is an IPv6 address.
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.starts_with('[')
+    }
+}
+
+/// The IP address `host` spells, with or without the brackets of IPv6 literals.
+pub fn ip_literal(host: &str) -> Option<IpAddr> {
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(v6) => v6.parse::<std::net::Ipv6Addr>().ok().map(IpAddr::V6),
+        None => host.parse().ok(),
+    }
+}
+
+/// Whether `host` is an IP literal or a syntactically valid host name.
+pub fn is_valid_host(host: &str) -> bool {
+    ip_literal(host).is_some()
+        || (host.len() <= 253
+            && host.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            }))
+}
+
+/// Source of the endpoints of a backend.
+#[async_trait]
+pub trait UpstreamsProvider: fmt::Debug + Send + Sync {
+    /// The endpoints known right now.
+    fn endpoints(&self) -> Arc<Vec<Endpoint>>;
+
+    /// How often [`refresh`](Self::refresh) should run; `None` for providers that never
+    /// change.
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Update the endpoint set. On failure the previous set is kept.
+    async fn refresh(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Ask for a refresh ahead of the interval, as one of the endpoints failed.
+    fn request_refresh(&self) {}
+
+    /// Whether a refresh was asked for since the last one.
+    fn refresh_requested(&self) -> bool {
+        false
+    }
+
+    /// Endpoint of the other address family of the host behind `addr`, to fall back to when
+    /// connecting to `addr` takes longer than the returned delay.
+    fn fallback(&self, _addr: &str) -> Option<(Endpoint, Duration)> {
+        None
+    }
+
+    /// Time to establish a connection to the endpoints, for backends that do not set their
+    /// own.
+    fn connect_timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A fixed set of endpoints.
+#[derive(Debug)]
+pub struct StaticUpstreams {
+    endpoints: Arc<Vec<Endpoint>>,
+}
+
+impl StaticUpstreams {
+    pub fn new(endpoints: Vec<Endpoint>) -> Self {
+        Self {
+            endpoints: Arc::new(endpoints),
+        }
+    }
+}
+
+#[async_trait]
+impl UpstreamsProvider for StaticUpstreams {
+    fn endpoints(&self) -> Arc<Vec<Endpoint>> {
+        self.endpoints.clone()
+    }
+}
+
+/// Host name resolution of `basic` backends, under `dns` in the backend config.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// How long resolved addresses are used before the host name is resolved again.
+    pub ttl_secs: u64,
+    /// For hosts with IPv6 and IPv4 addresses, how long a connection to one family may take
+    /// before the other is tried instead; 0 disables the fallback.
+    pub happy_eyeballs_delay_ms: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 60,
+            happy_eyeballs_delay_ms: 250,
+        }
+    }
+}
+
+/// The addresses of a host name, resolved when the config is loaded and again every
+/// `ttl_secs` or when one of them fails. A failed resolution keeps the last addresses.
+///
+/// Addresses alternate between IPv6 and IPv4 (RFC 8305), and a connection to one family
+/// that takes longer than `happy_eyeballs_delay_ms` falls back to the other.
+#[derive(Debug)]
+pub struct DnsUpstreams {
+    host: String,
+    port: u16,
+    ttl: Duration,
+    happy_eyeballs_delay: Duration,
+    endpoints: RwLock<Arc<Vec<Endpoint>>>,
+    /// An endpoint failed since the last resolution.
+    stale: AtomicBool,
+}
+
+impl DnsUpstreams {
+    /// Resolve `host` right away, so the backend has endpoints before the first refresh.
+    pub fn new(host: &str, port: u16, config: &DnsConfig) -> Self {
+        let upstreams = Self {
+            host: host.to_string(),
+            port,
+            ttl: Duration::from_secs(config.ttl_secs.max(1)),
+            happy_eyeballs_delay: Duration::from_millis(config.happy_eyeballs_delay_ms),
+            endpoints: RwLock::new(Arc::new(Vec::new())),
+            stale: AtomicBool::new(false),
+        };
+        match (host, port).to_socket_addrs() {
+            Ok(addrs) => upstreams.set(addrs.collect()),
+            Err(e) => log::warn!("Failed to resolve upstream host {host}: {e}"),
+        }
+        upstreams
+    }
+
+    fn set(&self, mut addrs: Vec<SocketAddr>) {
+        addrs.sort();
+        addrs.dedup();
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(SocketAddr::is_ipv6);
+        let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+        let mut endpoints = Vec::new();
+        loop {
+            let next: Vec<SocketAddr> = v6.next().into_iter().chain(v4.next()).collect();
+            if next.is_empty() {
+                break;
+            }
+            endpoints.extend(next.into_iter().map(|addr| Endpoint {
+                addr: addr.to_string(),
+                weight: 1,
+                zone: None,
+            }));
+        }
+        *self.endpoints.write_or_recover() = Arc::new(endpoints);
+    }
+}
+
+#[async_trait]
+impl UpstreamsProvider for DnsUpstreams {
+    fn endpoints(&self) -> Arc<Vec<Endpoint>> {
+        self.endpoints.read_or_recover().clone()
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.ttl)
+    }
+
+    async fn refresh(&self) -> Result<(), String> {
+        self.stale.store(false, Ordering::Relaxed);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| format!("failed to resolve {}: {e}", self.host))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("{} resolved to no addresses", self.host));
+        }
+        self.set(addrs);
+        Ok(())
+    }
+
+    fn request_refresh(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    fn refresh_requested(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+
+    fn fallback(&self, addr: &str) -> Option<(Endpoint, Duration)> {
+        if self.happy_eyeballs_delay.is_zero() {
+            return None;
+        }
+        let endpoints = self.endpoints();
+        let ipv6 = endpoints.iter().find(|e| e.addr == addr)?.is_ipv6();
+        let other = endpoints.iter().find(|e| e.is_ipv6() != ipv6)?;
+        Some((other.clone(), self.happy_eyeballs_delay))
+    }
+}
+
+// One client for every provider, as providers are recreated on each config reload
+pub(crate) fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// Provider for a backend definition.
+pub fn provider_for_backend(
+    backend: &Backend,
+    hetzner: &HetznerConfig,
+    dns: &DnsConfig,
+    xds: &XdsConfig,
+) -> Arc<dyn UpstreamsProvider> {
+    match backend {
+        Backend::Basic { ip, port } if ip_literal(ip).is_some() => {
+            Arc::new(StaticUpstreams::new(vec![Endpoint::new(ip, *port)]))
+        }
+        Backend::Basic { ip: host, port } => Arc::new(DnsUpstreams::new(host, *port, dns)),
+        // Invalid endpoints are rejected by Config::validate before resolution
+        Backend::Static { endpoints } => Arc::new(StaticUpstreams::new(
+            endpoints
+                .iter()
+                .filter_map(|e| Some((e.addr().parse::<SocketAddr>().ok()?, e.weight())))
+                .map(|(addr, weight)| {
+                    Endpoint::new(&addr.ip().to_string(), addr.port()).with_weight(weight)
+                })
+                .collect(),
+        )),
+        // Invalid selectors are rejected by Config::validate before resolution
+        Backend::Hetzner { labels, port } => match LabelSelector::new(labels) {
+            Ok(selector) => Arc::new(HetznerUpstreams::new(selector, *port, hetzner.clone())),
+            Err(_) => Arc::new(StaticUpstreams::new(Vec::new())),
+        },
+        Backend::Xds { cluster } => Arc::new(XdsUpstreams::new(cluster, xds.clone())),
+    }
+}
+
+/// Fraction of full weight an endpoint starts slow-start with.
+pub const SLOW_START_INITIAL: f64 = 0.1;
+
+/// Weight factor of an endpoint first seen `age` ago: ramps linearly from
+/// [`SLOW_START_INITIAL`] to 1 over `slow_start`.
+fn slow_start_factor(age: Duration, slow_start: Duration) -> f64 {
+    if slow_start.is_zero() || age >= slow_start {
+        return 1.0;
+    }
+    let ramp = age.as_secs_f64() / slow_start.as_secs_f64();
+    SLOW_START_INITIAL + (1.0 - SLOW_START_INITIAL) * ramp
+}
+
+/// A backend of a service with its share of the traffic.
+#[derive(Debug, Clone)]
+pub struct PoolMember {
+    pub upstreams: Arc<dyn UpstreamsProvider>,
+    /// Relative weight of each endpoint of the backend; 0 drains it.
+    pub weight: u32,
+    /// Ramp-up period of endpoints that newly appear.
+    pub slow_start: Duration,
+    /// Failover tier: 0 is primary, higher tiers take traffic when lower ones are unhealthy.
+    pub priority: u32,
+    pub recycling: ConnectionRecycling,
+    /// Proxy the endpoints are reached through; directly when unset.
+    pub egress: Option<Arc<EgressProxy>>,
+    /// TLS the endpoints are reached over; plain HTTP when unset.
+    pub tls: Option<Arc<UpstreamTls>>,
+    pub retry: RetryPolicy,
+    pub timeouts: UpstreamTimeouts,
+    /// Zone of the backend's endpoints that do not report their own.
+    pub zone: Option<String>,
+}
+
+/// How a service pool picks among its endpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Smooth weighted round robin.
+    #[default]
+    RoundRobin,
+    /// Power of two choices: of two endpoints drawn by weight, the one with the lower
+    /// latency EWMA scaled by its requests in flight.
+    P2cEwma,
+    /// The endpoint holding the request's key on a hash ring (see [`crate::selection`]).
+    ConsistentHash,
+}
+
+/// Time constant of the latency EWMA: samples older than this weigh about a third.
+pub const EWMA_DECAY: Duration = Duration::from_secs(10);
+/// Latency sample recorded for a request that got no upstream response.
+pub const FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// Response timing of an endpoint.
+#[derive(Debug, Clone, Copy, Default)]
+struct LatencyStat {
+    /// EWMA of response latency in seconds; `None` until the first response.
+    ewma: Option<f64>,
+    updated: Option<Instant>,
+    pending: u32,
+}
+
+impl LatencyStat {
+    fn observe(&mut self, sample: Duration, now: Instant) {
+        let sample = sample.as_secs_f64();
+        self.ewma = Some(match (self.ewma, self.updated) {
+            (Some(ewma), Some(updated)) => {
+                let age = now.saturating_duration_since(updated).as_secs_f64();
+                let w = (-age / EWMA_DECAY.as_secs_f64()).exp();
+                ewma * w + sample * (1.0 - w)
+            }
+            _ => sample,
+        });
+        self.updated = Some(now);
+    }
+
+    /// The EWMA decayed by the time since its last sample, so an endpoint that stopped
+    /// getting requests is not judged by its last slow responses forever.
+    fn current(&self, now: Instant) -> Option<f64> {
+        let age = self.updated.map_or(0.0, |updated| {
+            now.saturating_duration_since(updated).as_secs_f64()
+        });
+        Some(self.ewma? * (-age / EWMA_DECAY.as_secs_f64()).exp())
+    }
+
+    /// Lower is better. Unmeasured endpoints score by requests in flight alone.
+    fn score(&self, weight: f64) -> (f64, u32) {
+        let load = self.ewma.unwrap_or(0.0) * (self.pending + 1) as f64;
+        (load / weight.max(f64::MIN_POSITIVE), self.pending)
+    }
+}
+
+/// Requests in flight and response latency of a service pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolLoad {
+    pub in_flight: u32,
+    /// Mean latency EWMA of the measured endpoints; `None` before any response.
+    pub latency: Option<Duration>,
+}
+
+/// Passive health checking, under `passive_health` in the backend config: endpoints that
+/// fail several requests in a row are ejected for a while, and ramp back up when they
+/// return.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PassiveHealthConfig {
+    /// Consecutive failed requests that eject an endpoint; 0 disables ejection.
+    pub consecutive_failures: u32,
+    /// Seconds an ejected endpoint receives no traffic.
+    pub ejection_secs: u64,
+    /// Whether 5xx responses count as failures, besides requests without a response.
+    pub server_errors: bool,
+    /// Seconds over which an endpoint back from ejection ramps up to its full weight, or
+    /// over its backend's slow start when that is longer.
+    pub readmit_secs: u64,
+}
+
+impl Default for PassiveHealthConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            ejection_secs: 30,
+            server_errors: true,
+            readmit_secs: 30,
+        }
+    }
+}
+
+/// Default share of a tier's weight that must be healthy for it to take all traffic.
+pub const DEFAULT_FAILOVER_THRESHOLD: f64 = 0.5;
+
+/// Selection settings of a service pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolSettings {
+    pub strategy: Strategy,
+    /// Share of a priority tier's weight that must be healthy for the tier to take all
+    /// traffic; below it the next tier takes traffic too.
+    pub failover_threshold: f64,
+    pub health: PassiveHealthConfig,
+    /// Header hashed by [`Strategy::ConsistentHash`] instead of the API key.
+    pub hash_header: Option<String>,
+    /// Zone of this node; endpoints in it are preferred over those of other zones.
+    pub local_zone: Option<String>,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::default(),
+            failover_threshold: DEFAULT_FAILOVER_THRESHOLD,
+            health: PassiveHealthConfig::default(),
+            hash_header: None,
+            local_zone: None,
+        }
+    }
+}
+
+/// Zone awareness, under `locality` in the backend config.
+///
+/// Endpoints are zoned by the `zone` of their backend, or by their provider where it knows
+/// (the location of Hetzner servers, the locality of xDS endpoints). With the zone of this
+/// node known, from `zone` or the `LB_ZONE` environment variable, and `prefer_local_zone`
+/// set, each priority tier is split in two: the endpoints in this node's zone, and the
+/// others, which only take traffic while the local ones are not healthy enough (by the
+/// service's `failover_threshold`). Without a zone, selection ignores zones.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LocalityConfig {
+    pub zone: Option<String>,
+    pub prefer_local_zone: bool,
+}
+
+impl Default for LocalityConfig {
+    fn default() -> Self {
+        Self {
+            zone: None,
+            prefer_local_zone: true,
+        }
+    }
+}
+
+impl LocalityConfig {
+    /// Zone whose endpoints are preferred: the configured one, or `LB_ZONE`.
+    pub fn preferred_zone(&self) -> Option<String> {
+        if !self.prefer_local_zone {
+            return None;
+        }
+        self.zone
+            .clone()
+            .or_else(|| std::env::var("LB_ZONE").ok())
+            .map(|zone| zone.trim().to_string())
+            .filter(|zone| !zone.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Health {
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    /// When each endpoint first appeared, or last returned from ejection.
+    first_seen: HashMap<String, Instant>,
+    /// Smooth weighted round robin counters.
+    current: HashMap<String, f64>,
+    latency: HashMap<String, LatencyStat>,
+    health: HashMap<String, Health>,
+    /// Endpoints ramping up after an ejection.
+    readmitted: HashSet<String>,
+    /// Endpoints ejected since the last [`ServicePool::health_events`].
+    ejections: Vec<String>,
+    /// Whether every endpoint was ejected at the last [`ServicePool::health_events`].
+    down: bool,
+    /// Ring of the healthy endpoints, for consistent hashing.
+    ring: HashRing,
+}
+
+/// An endpoint eligible for selection.
+struct Candidate {
+    addr: String,
+    weight: f64,
+    priority: u32,
+    /// Outside the preferred zone.
+    remote: bool,
+    healthy: bool,
+}
+
+impl Candidate {
+    /// Failover tier: by priority, and within one the preferred zone first.
+    fn tier(&self) -> (u32, bool) {
+        (self.priority, self.remote)
+    }
+}
+
+/// Endpoints of the lowest tiers that together are healthy enough: each tier whose healthy
+/// share of weight is below `threshold` spills traffic to the next one.
+fn failover(candidates: &[Candidate], threshold: f64) -> Vec<(String, f64)> {
+    let mut tiers: Vec<(u32, bool)> = candidates.iter().map(Candidate::tier).collect();
+    tiers.sort_unstable();
+    tiers.dedup();
+
+    let mut chosen = Vec::new();
+    for tier in tiers {
+        let in_tier = || candidates.iter().filter(move |c| c.tier() == tier);
+        let total: f64 = in_tier().map(|c| c.weight).sum();
+        let healthy: f64 = in_tier().filter(|c| c.healthy).map(|c| c.weight).sum();
+        chosen.extend(
+            in_tier()
+                .filter(|c| c.healthy)
+                .map(|c| (c.addr.clone(), c.weight)),
+        );
+        if total > 0.0 && healthy / total >= threshold {
+            break;
+        }
+    }
+    chosen
+}
+
+/// The endpoints of every backend of a service, selected by weight according to the
+/// service's [`Strategy`].
+///
+/// Endpoints appearing after the pool was created (through discovery or a reload) start at
+/// a fraction of their weight and reach it at the end of their backend's slow-start period,
+/// as do endpoints returning from ejection. Backends with a higher `priority` only take
+/// traffic while the tiers before them are not healthy enough, and so do endpoints outside
+/// the [`local zone`](PoolSettings::local_zone) within a priority. Every selection must be
+/// followed by a [`report`](Self::report) of its outcome.
+#[derive(Debug)]
+pub struct ServicePool {
+    members: Vec<PoolMember>,
+    settings: PoolSettings,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl ServicePool {
+    /// A pool over `members`, continuing the selection state of `previous` so endpoints
+    /// that were already serving are not ramped again after a reload.
+    pub fn new(
+        members: Vec<PoolMember>,
+        settings: PoolSettings,
+        previous: Option<&ServicePool>,
+    ) -> Self {
+        Self {
+            members,
+            settings,
+            state: previous.map_or_else(Default::default, |p| p.state.clone()),
+        }
+    }
+
+    pub fn members(&self) -> &[PoolMember] {
+        &self.members
+    }
+
+    pub fn settings(&self) -> &PoolSettings {
+        &self.settings
+    }
+
+    /// Next endpoint, or `None` when no backend has an endpoint with a non-zero weight.
+    pub fn select(&self) -> Option<Endpoint> {
+        self.select_for(None)
+    }
+
+    /// Next endpoint for a request with the hash key `key` (see
+    /// [`selection::hash_key`](crate::selection::hash_key)).
+    pub fn select_for(&self, key: Option<&str>) -> Option<Endpoint> {
+        self.select_with(Instant::now(), key, &mut rand::thread_rng())
+    }
+
+    /// Next endpoint for a retry of a request already sent to the endpoints in `tried`;
+    /// one of those only when no other endpoint can take it.
+    pub fn select_retry(&self, key: Option<&str>, tried: &[String]) -> Option<Endpoint> {
+        self.select_avoiding(Instant::now(), key, tried, &mut rand::thread_rng())
+    }
+
+    pub fn select_with(
+        &self,
+        now: Instant,
+        key: Option<&str>,
+        rng: &mut impl Rng,
+    ) -> Option<Endpoint> {
+        self.select_avoiding(now, key, &[], rng)
+    }
+
+    fn select_avoiding(
+        &self,
+        now: Instant,
+        key: Option<&str>,
+        avoid: &[String],
+        rng: &mut impl Rng,
+    ) -> Option<Endpoint> {
+        let mut guard = self.state.lock_or_recover();
+        let state = &mut *guard;
+
+        // Ejections that ran out; the endpoint ramps up again
+        let returned: Vec<String> = state
+            .health
+            .iter()
+            .filter(|(_, h)| h.ejected_until.is_some_and(|until| until <= now))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in returned {
+            state.health.remove(&addr);
+            state.first_seen.insert(addr.clone(), now);
+            state.readmitted.insert(addr);
+        }
+        let readmit = Duration::from_secs(self.settings.health.readmit_secs);
+
+        // Effective weight per address, summed when backends share an endpoint
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for member in self.members.iter().filter(|m| m.weight > 0) {
+            for endpoint in member.upstreams.endpoints().iter().filter(|e| e.weight > 0) {
+                let first_seen = *state.first_seen.entry(endpoint.addr.clone()).or_insert(now);
+                let age = now.saturating_duration_since(first_seen);
+                let ramp = if state.readmitted.contains(&endpoint.addr) {
+                    member.slow_start.max(readmit)
+                } else {
+                    member.slow_start
+                };
+                let weight =
+                    member.weight as f64 * endpoint.weight as f64 * slow_start_factor(age, ramp);
+                let zone = endpoint.zone.as_ref().or(member.zone.as_ref());
+                let remote = self
+                    .settings
+                    .local_zone
+                    .as_ref()
+                    .is_some_and(|local| zone != Some(local));
+                match candidates.iter_mut().find(|c| c.addr == endpoint.addr) {
+                    Some(c) => {
+                        c.weight += weight;
+                        c.priority = c.priority.min(member.priority);
+                        c.remote &= remote;
+                    }
+                    None => candidates.push(Candidate {
+                        addr: endpoint.addr.clone(),
+                        weight,
+                        priority: member.priority,
+                        remote,
+                        healthy: state
+                            .health
+                            .get(&endpoint.addr)
+                            .is_none_or(|h| h.ejected_until.is_none()),
+                    }),
+                }
+            }
+        }
+        // Endpoints that left start over when they return
+        let listed = |addr: &String| candidates.iter().any(|c| c.addr == *addr);
+        state.first_seen.retain(|addr, _| listed(addr));
+        state.current.retain(|addr, _| listed(addr));
+        state.health.retain(|addr, _| listed(addr));
+        let first_seen = &state.first_seen;
+        state.readmitted.retain(|addr| {
+            first_seen
+                .get(addr)
+                .is_some_and(|since| now.saturating_duration_since(*since) < readmit)
+        });
+        state
+            .latency
+            .retain(|addr, stat| listed(addr) || stat.pending > 0);
+
+        let mut weights = failover(&candidates, self.settings.failover_threshold);
+        if weights.is_empty() {
+            // With every endpoint ejected, trying one beats failing the request
+            weights = candidates
+                .iter()
+                .map(|c| (c.addr.clone(), c.weight))
+                .collect();
+        }
+        if weights.iter().any(|(addr, _)| !avoid.contains(addr)) {
+            weights.retain(|(addr, _)| !avoid.contains(addr));
+        }
+        let addr = match (self.settings.strategy, key) {
+            // Retries leave the ring, whose endpoint for the key failed
+            (Strategy::ConsistentHash, Some(key)) if avoid.is_empty() => {
+                state.ring.update(&weights);
+                state.ring.get(key)?.to_string()
+            }
+            (Strategy::RoundRobin | Strategy::ConsistentHash, _) => {
+                smooth_round_robin(&mut state.current, &weights)?
+            }
+            (Strategy::P2cEwma, _) => power_of_two(&state.latency, &weights, rng)?,
+        };
+        state.latency.entry(addr.clone()).or_default().pending += 1;
+        Some(Endpoint {
+            addr,
+            weight: 1,
+            zone: None,
+        })
+    }
+
+    /// Record the outcome of a request sent to `addr`: its response latency, or `None` when
+    /// it got no response.
+    pub fn report(&self, addr: &str, latency: Option<Duration>, now: Instant) {
+        self.report_outcome(addr, latency, latency.is_none(), now);
+    }
+
+    /// Record a response from `addr` with `status`, after `latency`; 5xx responses count
+    /// as failures when passive health checking says so.
+    pub fn report_status(&self, addr: &str, status: u16, latency: Duration, now: Instant) {
+        let failed = self.settings.health.server_errors && (500..600).contains(&status);
+        self.report_outcome(addr, Some(latency), failed, now);
+    }
+
+    fn report_outcome(&self, addr: &str, latency: Option<Duration>, failed: bool, now: Instant) {
+        if latency.is_none() {
+            // The address may be out of date
+            self.members
+                .iter()
+                .filter(|m| m.upstreams.endpoints().iter().any(|e| e.addr == addr))
+                .for_each(|m| m.upstreams.request_refresh());
+        }
+        let mut state = self.state.lock_or_recover();
+        if let Some(stat) = state.latency.get_mut(addr) {
+            stat.pending = stat.pending.saturating_sub(1);
+            stat.observe(latency.unwrap_or(FAILURE_PENALTY), now);
+        }
+
+        let health = &self.settings.health;
+        if !failed {
+            if let Some(h) = state.health.get_mut(addr)
+                && h.ejected_until.is_none()
+            {
+                h.failures = 0;
+            }
+            return;
+        }
+        let h = state.health.entry(addr.to_string()).or_default();
+        h.failures += 1;
+        if health.consecutive_failures > 0
+            && h.failures >= health.consecutive_failures
+            && h.ejected_until.is_none()
+        {
+            h.ejected_until = Some(now + Duration::from_secs(health.ejection_secs));
+            log::warn!(
+                "Ejecting upstream {} after {} consecutive failures",
+                addr,
+                h.failures
+            );
+            state.ejections.push(addr.to_string());
+        }
+    }
+
+    /// Endpoint to fall back to when connecting to `addr` takes longer than the returned
+    /// delay, from the backend serving `addr`.
+    pub fn fallback_for(&self, addr: &str) -> Option<(Endpoint, Duration)> {
+        self.members
+            .iter()
+            .find_map(|member| member.upstreams.fallback(addr))
+    }
+
+    /// Send the next request to `endpoint` without selecting it; its outcome must be
+    /// reported like that of a selection.
+    pub fn pick(&self, endpoint: &Endpoint) {
+        let mut state = self.state.lock_or_recover();
+        state
+            .latency
+            .entry(endpoint.addr.clone())
+            .or_default()
+            .pending += 1;
+    }
+
+    /// Current load of the pool's endpoints.
+    pub fn load(&self, now: Instant) -> PoolLoad {
+        let state = self.state.lock_or_recover();
+        let in_flight = state.latency.values().map(|stat| stat.pending).sum();
+        let measured: Vec<f64> = state
+            .latency
+            .values()
+            .filter_map(|stat| stat.current(now))
+            .collect();
+        PoolLoad {
+            in_flight,
+            latency: (!measured.is_empty()).then(|| {
+                Duration::from_secs_f64(measured.iter().sum::<f64>() / measured.len() as f64)
+            }),
+        }
+    }
+
+    /// Connection recycling of the backend serving `addr`; the strictest one when several
+    /// backends share the endpoint.
+    pub fn recycling_for(&self, addr: &str) -> ConnectionRecycling {
+        self.members
+            .iter()
+            .filter(|m| m.upstreams.endpoints().iter().any(|e| e.addr == addr))
+            .fold(ConnectionRecycling::default(), |acc, m| {
+                acc.strictest(m.recycling)
+            })
+    }
+
+    /// Retry policy of the first backend serving `addr`.
+    pub fn retry_for(&self, addr: &str) -> RetryPolicy {
+        self.members
+            .iter()
+            .find(|m| m.upstreams.endpoints().iter().any(|e| e.addr == addr))
+            .map(|m| m.retry.clone())
+            .unwrap_or_default()
+    }
+
+    /// Timeouts of the first backend serving `addr`.
+    pub fn timeouts_for(&self, addr: &str) -> UpstreamTimeouts {
+        self.members
+            .iter()
+            .find(|m| m.upstreams.endpoints().iter().any(|e| e.addr == addr))
+            .map(|m| UpstreamTimeouts {
+                connect: m.timeouts.connect.or_else(|| m.upstreams.connect_timeout()),
+                ..m.timeouts
+            })
+            .unwrap_or_default()
+    }
+
+    /// Egress proxy of the first backend serving `addr`, if it is reached through one.
+    pub fn egress_for(&self, addr: &str) -> Option<Arc<EgressProxy>> {
+        self.members
+            .iter()
+            .find(|m| m.upstreams.endpoints().iter().any(|e| e.addr == addr))
+            .and_then(|m| m.egress.clone())
+    }
+
+    /// TLS settings of the first backend serving `addr`, if it is reached over TLS.
+    pub fn tls_for(&self, addr: &str) -> Option<Arc<UpstreamTls>> {
+        self.members
+            .iter()
+            .find(|m| m.upstreams.endpoints().iter().any(|e| e.addr == addr))
+            .and_then(|m| m.tls.clone())
+    }
+
+    /// Whether none of the pool's endpoints can take requests, all of them being ejected
+    /// by passive health checking at `now`, or the pool having none.
+    pub fn is_down(&self, now: Instant) -> bool {
+        let state = self.state.lock_or_recover();
+        !self.members.iter().filter(|m| m.weight > 0).any(|member| {
+            member
+                .upstreams
+                .endpoints()
+                .iter()
+                .filter(|e| e.weight > 0)
+                .any(|e| {
+                    state
+                        .health
+                        .get(&e.addr)
+                        .and_then(|h| h.ejected_until)
+                        .is_none_or(|until| until <= now)
+                })
+        })
+    }
+
+    /// Changes in the pool's health since the last call: endpoints ejected meanwhile, and
+    /// whether the pool went down or recovered at `now`.
+    ///
+    /// Only a pool whose endpoints are all ejected is down here; one without endpoints is
+    /// still waiting for discovery.
+    pub fn health_events(&self, now: Instant) -> Vec<PoolHealthEvent> {
+        let has_endpoints = self
+            .members
+            .iter()
+            .any(|m| m.weight > 0 && m.upstreams.endpoints().iter().any(|e| e.weight > 0));
+        let down = has_endpoints && self.is_down(now);
+        let mut state = self.state.lock_or_recover();
+        let mut events: Vec<PoolHealthEvent> = state
+            .ejections
+            .drain(..)
+            .map(PoolHealthEvent::Ejected)
+            .collect();
+        if down != state.down {
+            state.down = down;
+            events.push(if down {
+                PoolHealthEvent::Down
+            } else {
+                PoolHealthEvent::Recovered
+            });
+        }
+        events
+    }
+
+    /// Addresses currently ejected by passive health checking.
+    pub fn ejected(&self) -> Vec<String> {
+        let state = self.state.lock_or_recover();
+        let mut ejected: Vec<String> = state
+            .health
+            .iter()
+            .filter(|(_, h)| h.ejected_until.is_some())
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        ejected.sort();
+        ejected
+    }
+}
+
+/// A change in the health of a [`ServicePool`], for alerting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolHealthEvent {
+    /// The endpoint with this address was ejected by passive health checking.
+    Ejected(String),
+    /// Every endpoint of the pool was ejected.
+    Down,
+    /// An endpoint of the pool takes requests again after it was down.
+    Recovered,
+}
+
+fn smooth_round_robin(
+    current: &mut HashMap<String, f64>,
+    weights: &[(String, f64)],
+) -> Option<String> {
+    let total: f64 = weights.iter().map(|(_, w)| w).sum();
+    let mut best: Option<(&str, f64)> = None;
+    for (addr, weight) in weights {
+        let counter = current.entry(addr.clone()).or_insert(0.0);
+        *counter += weight;
+        if best.is_none_or(|(_, b)| *counter > b) {
+            best = Some((addr, *counter));
+        }
+    }
+    let (addr, _) = best?;
+    if let Some(counter) = current.get_mut(addr) {
+        *counter -= total;
+    }
+    Some(addr.to_string())
+}
+
+/// Index drawn with probability proportional to weight, skipping `exclude`.
+fn draw(weights: &[(String, f64)], exclude: Option<usize>, rng: &mut impl Rng) -> Option<usize> {
+    let total: f64 = weights
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Some(*i) != exclude)
+        .map(|(_, (_, w))| w)
+        .sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut target = rng.r#gen::<f64>() * total;
+    let mut last = None;
+    for (i, (_, weight)) in weights.iter().enumerate() {
+        if Some(i) == exclude || *weight <= 0.0 {
+            continue;
+        }
+        if target < *weight {
+            return Some(i);
+        }
+        target -= weight;
+        last = Some(i);
+    }
+    last
+}
+
+fn power_of_two(
+    latency: &HashMap<String, LatencyStat>,
+    weights: &[(String, f64)],
+    rng: &mut impl Rng,
+) -> Option<String> {
+    let first = draw(weights, None, rng)?;
+    let Some(second) = draw(weights, Some(first), rng) else {
+        return Some(weights[first].0.clone());
+    };
+    let score = |i: usize| {
+        let (addr, weight) = &weights[i];
+        latency
+            .get(addr)
+            .copied()
+            .unwrap_or_default()
+            .score(*weight)
+    };
+    let pick = if score(second) < score(first) {
+        second
+    } else {
+        first
+    };
+    Some(weights[pick].0.clone())
+}
+
+/// Limits after which a keepalive connection to an upstream is closed instead of reused,
+/// so connections to upstreams behind their own L4 balancer get spread again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionRecycling {
+    pub max_requests: Option<u64>,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl ConnectionRecycling {
+    fn strictest(self, other: Self) -> Self {
+        let min = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Self {
+            max_requests: min(self.max_requests, other.max_requests),
+            max_lifetime: min(
+                self.max_lifetime.map(|d| d.as_millis() as u64),
+                other.max_lifetime.map(|d| d.as_millis() as u64),
+            )
+            .map(Duration::from_millis),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_requests.is_some() || self.max_lifetime.is_some()
+    }
+}
+
+/// Timeouts of requests to a backend's endpoints; pingora's defaults where unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpstreamTimeouts {
+    /// Time to establish a connection.
+    pub connect: Option<Duration>,
+    /// Time to wait for each read from the upstream.
+    pub read: Option<Duration>,
+    /// Time to wait for each write to the upstream.
+    pub write: Option<Duration>,
+}
+
+/// Failure of an upstream request that a retry policy can retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RetryOnValue")]
+pub enum RetryOn {
+    /// No connection to the endpoint could be made.
+    ConnectFailure,
+    /// The endpoint answered 502, or the connection failed before it answered.
+    BadGateway,
+    /// The endpoint answered 503.
+    ServiceUnavailable,
+    /// The endpoint answered 504.
+    GatewayTimeout,
+}
+
+/// `retry_on` entries as written: `connect_failure` or a status code.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RetryOnValue {
+    Status(u16),
+    Name(String),
+}
+
+impl TryFrom<RetryOnValue> for RetryOn {
+    type Error = String;
+
+    fn try_from(value: RetryOnValue) -> Result<Self, Self::Error> {
+        match value {
+            RetryOnValue::Name(name) if name == "connect_failure" => Ok(RetryOn::ConnectFailure),
+            RetryOnValue::Name(name) => match name.parse::<u16>() {
+                Ok(status) => Self::try_from(RetryOnValue::Status(status)),
+                Err(_) => Err(format!("unknown retry_on condition {name}")),
+            },
+            RetryOnValue::Status(502) => Ok(RetryOn::BadGateway),
+            RetryOnValue::Status(503) => Ok(RetryOn::ServiceUnavailable),
+            RetryOnValue::Status(504) => Ok(RetryOn::GatewayTimeout),
+            RetryOnValue::Status(status) => Err(format!(
+                "retry_on status {status} is not one of 502, 503 and 504"
+            )),
+        }
+    }
+}
+
+impl RetryOn {
+    /// Condition matching an upstream response with `status`.
+    pub fn for_status(status: u16) -> Option<Self> {
+        match status {
+            502 => Some(RetryOn::BadGateway),
+            503 => Some(RetryOn::ServiceUnavailable),
+            504 => Some(RetryOn::GatewayTimeout),
+            _ => None,
+        }
+    }
+}
+
+/// How often, and on which failures, a request to a backend is sent again to another
+/// endpoint of its service.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one; no retries when 0.
+    pub retries: u32,
+    pub on: Vec<RetryOn>,
+}
+
+impl RetryPolicy {
+    /// Whether a request that failed with `failure` after `attempts` retries goes again.
+    pub fn allows(&self, failure: RetryOn, attempts: u32) -> bool {
+        attempts < self.retries && self.on.contains(&failure)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ConnectionUse {
+    opened: Instant,
+    requests: u64,
+}
+
+/// Requests and age of the open upstream connections, by socket.
+#[derive(Debug, Default)]
+pub struct ConnectionRecycler {
+    connections: Mutex<HashMap<u64, ConnectionUse>>,
+}
+
+impl ConnectionRecycler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a request on connection `id`, which is new unless `reused`. Returns whether
+    /// the connection must be closed after this request.
+    pub fn on_request(
+        &self,
+        id: u64,
+        reused: bool,
+        limits: ConnectionRecycling,
+        now: Instant,
+    ) -> bool {
+        let mut connections = self.connections.lock_or_recover();
+        if !limits.is_enabled() {
+            connections.remove(&id);
+            return false;
+        }
+        // Socket ids are reused once closed; a new connection starts over
+        let fresh = ConnectionUse {
+            opened: now,
+            requests: 0,
+        };
+        let used = connections.entry(id).or_insert(fresh);
+        if !reused {
+            *used = fresh;
+        }
+        used.requests += 1;
+
+        let retire = limits.max_requests.is_some_and(|max| used.requests >= max)
+            || limits
+                .max_lifetime
+                .is_some_and(|max| now.saturating_duration_since(used.opened) >= max);
+        if retire {
+            connections.remove(&id);
+        }
+        retire
+    }
+}
+
+/// Background service refreshing the dynamic providers of the current backend config and
+/// alerting on the health of its pools.
+pub struct UpstreamRefresher {
+    config: Arc<RwLock<Config>>,
+    alerts: Arc<AlertSink>,
+    /// Last refresh of each provider, by provider address.
+    last_refresh: Mutex<HashMap<usize, Instant>>,
+}
+
+impl UpstreamRefresher {
+    pub fn new(config: Arc<RwLock<Config>>, alerts: Arc<AlertSink>) -> Self {
+        Self {
+            config,
+            alerts,
+            last_refresh: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refresh every dynamic provider whose interval has elapsed or that asked for a
+    /// refresh. New providers, such as those of a reloaded config, are refreshed right away.
+    pub async fn refresh_due(&self, now: Instant) {
+        let due: Vec<(String, Arc<dyn UpstreamsProvider>)> = {
+            let config = self.config.read_or_recover();
+            let last_refresh = self.last_refresh.lock_or_recover();
+            config
+                .backends
+                .iter()
+                .filter_map(|b| {
+                    let provider = b.upstreams.clone()?;
+                    let interval = provider.refresh_interval()?;
+                    let key = Arc::as_ptr(&provider) as *const () as usize;
+                    let due = provider.refresh_requested()
+                        || last_refresh
+                            .get(&key)
+                            .is_none_or(|last| now.duration_since(*last) >= interval);
+                    due.then(|| (b.service.clone(), provider))
+                })
+                .collect()
+        };
+
+        for (service, provider) in due {
+            let key = Arc::as_ptr(&provider) as *const () as usize;
+            self.last_refresh.lock_or_recover().insert(key, now);
+            if let Err(e) = provider.refresh().await {
+                self.alerts.warning(
+                    "upstreams",
+                    format!("Refreshing upstreams of service {service} failed: {e}"),
+                );
+            }
+        }
+
+        // Forget providers dropped by a reload
+        let live: Vec<usize> = {
+            let config = self.config.read_or_recover();
+            config
+                .backends
+                .iter()
+                .filter_map(|b| b.upstreams.as_ref())
+                .map(|u| Arc::as_ptr(u) as *const () as usize)
+                .collect()
+        };
+        self.last_refresh
+            .lock_or_recover()
+            .retain(|key, _| live.contains(key));
+
+        self.alert_pool_health(now);
+    }
+
+    /// Alert on the [`PoolHealthEvent`]s of every pool of the current config.
+    pub fn alert_pool_health(&self, now: Instant) {
+        let pools: Vec<(String, Arc<ServicePool>)> = {
+            let config = self.config.read_or_recover();
+            let named = |pools: &HashMap<String, Arc<ServicePool>>, kind: &str| {
+                pools
+                    .iter()
+                    .map(|(service, pool)| (format!("{service}{kind}"), pool.clone()))
+                    .collect::<Vec<_>>()
+            };
+            let mut pools = named(&config.pools, "");
+            pools.extend(named(&config.shadow_pools, " (shadow)"));
+            pools.extend(named(&config.fallback_pools, " (fallback)"));
+            for (service, dedicated) in &config.dedicated_pools {
+                pools.extend(
+                    dedicated
+                        .iter()
+                        .map(|(name, pool)| (format!("{service} (pool {name})"), pool.clone())),
+                );
+            }
+            for (service, groups) in &config.traffic_groups {
+                pools.extend(
+                    groups
+                        .iter()
+                        .map(|g| (format!("{service} (group {})", g.name), g.pool.clone())),
+                );
+            }
+            pools
+        };
+
+        for (service, pool) in pools {
+            for event in pool.health_events(now) {
+                match event {
+                    PoolHealthEvent::Ejected(addr) => self.alerts.warning(
+                        "upstreams",
+                        format!("Upstream {addr} of service {service} ejected after consecutive failures"),
+                    ),
+                    PoolHealthEvent::Down => self.alerts.critical(
+                        "upstreams",
+                        format!("Every upstream of service {service} is ejected"),
+                    ),
+                    PoolHealthEvent::Recovered => self.alerts.warning(
+                        "upstreams",
+                        format!("Upstreams of service {service} take requests again"),
+                    ),
+                };
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UpstreamRefresher {
+    async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+        loop {
+            self.refresh_due(Instant::now()).await;
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::StaticEndpoint;
+    use rand::SeedableRng;
+
+    fn member(addrs: &[&str], weight: u32, slow_start_secs: u64) -> PoolMember {
+        PoolMember {
+            upstreams: Arc::new(StaticUpstreams::new(
+                addrs.iter().map(|ip| Endpoint::new(ip, 80)).collect(),
+            )),
+            weight,
+            slow_start: Duration::from_secs(slow_start_secs),
+            priority: 0,
+            recycling: ConnectionRecycling::default(),
+            egress: None,
+            tls: None,
+            retry: RetryPolicy::default(),
+            timeouts: UpstreamTimeouts::default(),
+            zone: None,
+        }
+    }
+
+    fn settings(strategy: Strategy) -> PoolSettings {
+        PoolSettings {
+            strategy,
+            ..Default::default()
+        }
+    }
+
+    fn pick(pool: &ServicePool, now: Instant) -> Option<Endpoint> {
+        let endpoint = pool.select_with(now, None, &mut rand::thread_rng())?;
+        pool.report(&endpoint.addr, Some(Duration::from_millis(10)), now);
+        Some(endpoint)
+    }
+
+    fn picks(pool: &ServicePool, now: Instant, n: usize) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..n {
+            *counts.entry(pick(pool, now).unwrap().addr).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_weighted_selection_across_backends() {
+        let now = Instant::now();
+        let pool = ServicePool::new(
+            vec![
+                member(&["10.0.0.1", "10.0.0.2"], 1, 0),
+                member(&["10.0.0.3"], 2, 0),
+                member(&["10.0.0.4"], 0, 0),
+            ],
+            settings(Strategy::RoundRobin),
+            None,
+        );
+        let sequence: Vec<String> = (0..4).map(|_| pick(&pool, now).unwrap().addr).collect();
+        // Smooth: the heavier endpoint is interleaved rather than picked twice in a row
+        assert_eq!(
+            sequence,
+            ["10.0.0.3:80", "10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]
+        );
+        let counts = picks(&pool, now, 400);
+        assert_eq!(counts["10.0.0.1:80"], 100);
+        assert_eq!(counts["10.0.0.3:80"], 200);
+        assert!(!counts.contains_key("10.0.0.4:80"));
+
+        let empty = ServicePool::new(
+            vec![member(&[], 1, 0)],
+            settings(Strategy::RoundRobin),
+            None,
+        );
+        assert_eq!(pick(&empty, now), None);
+    }
+
+    #[test]
+    fn test_consistent_hash_sticks_keys_to_endpoints() {
+        let now = Instant::now();
+        let pool = ServicePool::new(
+            vec![member(&["10.0.0.1", "10.0.0.2", "10.0.0.3"], 1, 0)],
+            settings(Strategy::ConsistentHash),
+            None,
+        );
+        let select = |key: &str| pool.select_with(now, Some(key), &mut rand::thread_rng());
+        let owners: Vec<String> = (0..30)
+            .map(|i| select(&format!("tenant-{i}")).unwrap().addr)
+            .collect();
+        for (i, owner) in owners.iter().enumerate() {
+            assert_eq!(&select(&format!("tenant-{i}")).unwrap().addr, owner);
+        }
+        assert!(owners.iter().any(|o| o != &owners[0]));
+
+        // Requests without a key take turns
+        let turns = picks(&pool, now, 3);
+        assert_eq!(turns.len(), 3);
+    }
+
+    #[test]
+    fn test_slow_start_ramps_new_endpoints() {
+        assert_eq!(
+            slow_start_factor(Duration::ZERO, Duration::from_secs(60)),
+            SLOW_START_INITIAL
+        );
+        assert_eq!(
+            slow_start_factor(Duration::from_secs(60), Duration::from_secs(60)),
+            1.0
+        );
+
+        let start = Instant::now();
+        let warm = ServicePool::new(
+            vec![member(&["10.0.0.1"], 1, 100)],
+            settings(Strategy::RoundRobin),
+            None,
+        );
+        pick(&warm, start);
+        // A reload adds an endpoint; the pool keeps the first one warm
+        let pool = ServicePool::new(
+            vec![member(&["10.0.0.1", "10.0.0.2"], 1, 100)],
+            settings(Strategy::RoundRobin),
+            Some(&warm),
+        );
+        let later = start + Duration::from_secs(100);
+        pick(&pool, later);
+
+        let counts = picks(&pool, later, 110);
+        assert_eq!(counts["10.0.0.2:80"], 10);
+        let counts = picks(&pool, later + Duration::from_secs(100), 100);
+        assert_eq!(counts["10.0.0.2:80"], 50);
+    }
+
+    #[test]
+    fn test_p2c_ewma_prefers_fast_and_idle_endpoints() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let now = Instant::now();
+        let pool = ServicePool::new(
+            vec![member(&["10.0.0.1", "10.0.0.2"], 1, 0)],
+            settings(Strategy::P2cEwma),
+            None,
+        );
+        // Unmeasured endpoints are spread by requests in flight
+        let a = pool.select_with(now, None, &mut rng).unwrap();
+        let b = pool.select_with(now, None, &mut rng).unwrap();
+        assert_ne!(a, b);
+        pool.report("10.0.0.1:80", Some(Duration::from_millis(200)), now);
+        pool.report("10.0.0.2:80", Some(Duration::from_millis(20)), now);
+
+        for _ in 0..20 {
+            let endpoint = pool.select_with(now, None, &mut rng).unwrap();
+            assert_eq!(endpoint.addr, "10.0.0.2:80");
+            pool.report(&endpoint.addr, Some(Duration::from_millis(20)), now);
+        }
+
+        // Enough requests in flight outweigh the latency difference
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..15 {
+            *counts
+                .entry(pool.select_with(now, None, &mut rng).unwrap().addr)
+                .or_default() += 1;
+        }
+        assert!(counts["10.0.0.1:80"] >= 1);
+        assert!(counts["10.0.0.2:80"] >= 9);
+
+        // A failure counts as a slow response
+        let mut stat = LatencyStat::default();
+        stat.observe(Duration::from_millis(10), now);
+        stat.observe(FAILURE_PENALTY, now + EWMA_DECAY);
+        let ewma = stat.ewma.unwrap();
+        assert!(ewma > 0.6 && ewma < 0.7, "{ewma}");
+    }
+
+    #[test]
+    fn test_failover_spills_to_next_tier_and_recovers() {
+        let now = Instant::now();
+        let mut secondary = member(&["10.1.0.1"], 1, 10);
+        secondary.priority = 1;
+        let pool = ServicePool::new(
+            vec![
+                member(&["10.0.0.1", "10.0.0.2", "10.0.0.3"], 1, 10),
+                secondary,
+            ],
+            PoolSettings {
+                failover_threshold: 0.6,
+                health: PassiveHealthConfig {
+                    consecutive_failures: 2,
+                    ejection_secs: 30,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        assert!(!picks(&pool, now, 30).contains_key("10.1.0.1:80"));
+
+        // One primary ejected: 2/3 healthy is above the threshold
+        pool.report("10.0.0.1:80", None, now);
+        pool.report("10.0.0.1:80", None, now);
+        assert_eq!(pool.ejected(), ["10.0.0.1:80"]);
+        let counts = picks(&pool, now, 30);
+        assert!(!counts.contains_key("10.0.0.1:80"));
+        assert!(!counts.contains_key("10.1.0.1:80"));
+
+        // A second one: the secondary takes traffic alongside the healthy primary
+        pool.report("10.0.0.2:80", Some(Duration::from_millis(5)), now);
+        pool.report("10.0.0.2:80", None, now);
+        pool.report("10.0.0.2:80", None, now);
+        let counts = picks(&pool, now, 30);
+        assert_eq!(counts["10.0.0.3:80"], 15);
+        assert_eq!(counts["10.1.0.1:80"], 15);
+
+        // Ejections end and the primaries ramp back up from slow-start
+        let later = now + Duration::from_secs(30);
+        let counts = picks(&pool, later, 120);
+        assert!(pool.ejected().is_empty());
+        assert!(!counts.contains_key("10.1.0.1:80"));
+        assert_eq!(counts["10.0.0.1:80"], 10);
+        assert_eq!(counts["10.0.0.3:80"], 100);
+
+        // With everything ejected, requests still go somewhere
+        for addr in ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.1.0.1:80"] {
+            pool.report(addr, None, later);
+            pool.report(addr, None, later);
+        }
+        assert!(pick(&pool, later).is_some());
+    }
+
+    #[test]
+    fn test_local_zone_is_preferred_with_cross_zone_failover() {
+        let now = Instant::now();
+        let zoned = |addrs: &[&str], zone: &str| PoolMember {
+            zone: Some(zone.to_string()),
+            ..member(addrs, 1, 0)
+        };
+        let locality = LocalityConfig {
+            zone: Some("fsn1".to_string()),
+            ..Default::default()
+        };
+        let pool = ServicePool::new(
+            vec![
+                zoned(&["10.0.0.1", "10.0.0.2"], "fsn1"),
+                zoned(&["10.1.0.1", "10.1.0.2"], "nbg1"),
+            ],
+            PoolSettings {
+                local_zone: locality.preferred_zone(),
+                health: PassiveHealthConfig {
+                    consecutive_failures: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        let counts = picks(&pool, now, 20);
+        assert_eq!(counts["10.0.0.1:80"], 10);
+        assert_eq!(counts["10.0.0.2:80"], 10);
+
+        // Half the local zone down is still enough
+        pool.report("10.0.0.1:80", None, now);
+        assert_eq!(picks(&pool, now, 10)["10.0.0.2:80"], 10);
+        // With all of it down, the other zone takes over
+        pool.report("10.0.0.2:80", None, now);
+        let counts = picks(&pool, now, 20);
+        assert_eq!(counts["10.1.0.1:80"], 10);
+        assert_eq!(counts["10.1.0.2:80"], 10);
+
+        // Without a preference, zones are ignored
+        let indifferent = LocalityConfig {
+            prefer_local_zone: false,
+            ..locality
+        };
+        assert_eq!(indifferent.preferred_zone(), None);
+        let pool = ServicePool::new(
+            vec![zoned(&["10.0.0.1"], "fsn1"), zoned(&["10.1.0.1"], "nbg1")],
+            settings(Strategy::RoundRobin),
+            None,
+        );
+        assert_eq!(picks(&pool, now, 10).len(), 2);
+    }
+
+    #[test]
+    fn test_server_errors_eject_and_endpoints_are_readmitted_gradually() {
+        let now = Instant::now();
+        let pool = ServicePool::new(
+            vec![member(&["10.0.0.1", "10.0.0.2"], 1, 0)],
+            PoolSettings {
+                health: PassiveHealthConfig {
+                    consecutive_failures: 3,
+                    ejection_secs: 10,
+                    readmit_secs: 20,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        let ok = Duration::from_millis(5);
+
+        // Any other response in between, 4xx included, starts the count over
+        for status in [500, 503, 200, 502, 404, 500, 502] {
+            pool.report_status("10.0.0.1:80", status, ok, now);
+        }
+        assert!(pool.ejected().is_empty());
+        pool.report_status("10.0.0.1:80", 504, ok, now);
+        assert_eq!(pool.ejected(), ["10.0.0.1:80"]);
+        assert!(!picks(&pool, now, 10).contains_key("10.0.0.1:80"));
+
+        // Back from ejection at a tenth of its weight, full again after readmit_secs
+        let back = now + Duration::from_secs(10);
+        assert_eq!(picks(&pool, back, 110)["10.0.0.1:80"], 10);
+        let ramped = back + Duration::from_secs(20);
+        assert_eq!(picks(&pool, ramped, 100)["10.0.0.1:80"], 50);
+
+        let tolerant = ServicePool::new(
+            vec![member(&["10.0.0.1"], 1, 0)],
+            PoolSettings {
+                health: PassiveHealthConfig {
+                    consecutive_failures: 1,
+                    server_errors: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        tolerant.report_status("10.0.0.1:80", 500, ok, now);
+        assert!(tolerant.ejected().is_empty());
+
+        // A pool is down while every endpoint is ejected
+        assert!(!pool.is_down(now));
+        let single = ServicePool::new(
+            vec![member(&["10.0.0.1"], 1, 0)],
+            PoolSettings {
+                health: PassiveHealthConfig {
+                    consecutive_failures: 1,
+                    ejection_secs: 10,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        assert!(!single.is_down(now));
+        single.report_status("10.0.0.1:80", 500, ok, now);
+        assert!(single.is_down(now));
+        assert!(!single.is_down(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_health_events_report_ejections_and_down_transitions() {
+        let now = Instant::now();
+        let ok = Duration::from_millis(10);
+        let pool = ServicePool::new(
+            vec![member(&["10.0.0.1", "10.0.0.2"], 1, 0)],
+            PoolSettings {
+                health: PassiveHealthConfig {
+                    consecutive_failures: 1,
+                    ejection_secs: 10,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        );
+        assert!(pool.health_events(now).is_empty());
+
+        pool.report_status("10.0.0.1:80", 500, ok, now);
+        assert_eq!(
+            pool.health_events(now),
+            [PoolHealthEvent::Ejected("10.0.0.1:80".to_string())]
+        );
+        pool.report_status("10.0.0.2:80", 500, ok, now);
+        assert_eq!(
+            pool.health_events(now),
+            [
+                PoolHealthEvent::Ejected("10.0.0.2:80".to_string()),
+                PoolHealthEvent::Down
+            ]
+        );
+        assert!(pool.health_events(now).is_empty());
+        assert_eq!(
+            pool.health_events(now + Duration::from_secs(10)),
+            [PoolHealthEvent::Recovered]
+        );
+
+        // A pool without endpoints yet is not down
+        let empty = ServicePool::new(Vec::new(), PoolSettings::default(), None);
+        assert!(empty.health_events(now).is_empty());
+    }
+
+    #[test]
+    fn test_refresher_alerts_on_pool_health() {
+        let config = Config::parse(
+            r#"
+services:
+  geocode: /geocode
+passive_health:
+  consecutive_failures: 1
+backends:
+  - service: geocode
+    backend: { type: basic, ip: 10.0.0.1, port: 80 }
+"#,
+        )
+        .unwrap();
+        let pool = config.pools["geocode"].clone();
+        let alerts = Arc::new(AlertSink::default());
+        let refresher = UpstreamRefresher::new(Arc::new(RwLock::new(config)), alerts.clone());
+
+        let now = Instant::now();
+        pool.report("10.0.0.1:80", None, now);
+        refresher.alert_pool_health(now);
+
+        // Both were sent, so the same alerts are suppressed now
+        assert!(!alerts.warning(
+            "upstreams",
+            "Upstream 10.0.0.1:80 of service geocode ejected after consecutive failures"
+        ));
+        assert!(!alerts.critical("upstreams", "Every upstream of service geocode is ejected"));
+    }
+
+    #[test]
+    fn test_connections_recycled_after_requests_or_lifetime() {
+        let now = Instant::now();
+        let recycler = ConnectionRecycler::new();
+        let by_requests = ConnectionRecycling {
+            max_requests: Some(3),
+            max_lifetime: None,
+        };
+        let closes: Vec<bool> = [false, true, true, false, true, true]
+            .iter()
+            .map(|reused| recycler.on_request(7, *reused, by_requests, now))
+            .collect();
+        assert_eq!(closes, [false, false, true, false, false, true]);
+        // A new connection on a reused socket id starts over
+        assert!(!recycler.on_request(7, true, by_requests, now));
+        assert!(!recycler.on_request(7, false, by_requests, now));
+
+        let by_age = ConnectionRecycling {
+            max_requests: None,
+            max_lifetime: Some(Duration::from_secs(60)),
+        };
+        assert!(!recycler.on_request(8, false, by_age, now));
+        assert!(!recycler.on_request(8, true, by_age, now + Duration::from_secs(59)));
+        assert!(recycler.on_request(8, true, by_age, now + Duration::from_secs(60)));
+
+        assert!(!recycler.on_request(9, true, ConnectionRecycling::default(), now));
+
+        let mut strict = member(&["10.0.0.1"], 1, 0);
+        strict.recycling = by_requests;
+        let mut old = member(&["10.0.0.1", "10.0.0.2"], 1, 0);
+        old.recycling = by_age;
+        let pool = ServicePool::new(vec![strict, old], PoolSettings::default(), None);
+        assert_eq!(
+            pool.recycling_for("10.0.0.1:80"),
+            ConnectionRecycling {
+                max_requests: Some(3),
+                max_lifetime: Some(Duration::from_secs(60)),
+            }
+        );
+        assert_eq!(pool.recycling_for("10.0.0.2:80"), by_age);
+    }
+
+    #[test]
+    fn test_reload_keeps_unchanged_providers() {
+        let yaml = |port: u16| {
+            format!(
+                "services:\n  geocode: /geocode\n  search: /search\nbackends:\n\
+                 - service: geocode\n  backend:\n    type: hetzner\n    labels:\n\
+                 \x20     - service: geocode\n    port: 8099\n\
+                 - service: search\n  backend:\n    type: basic\n    ip: 10.0.0.1\n    port: {port}\n"
+            )
+        };
+        let mut old: Config = serde_yaml::from_str(&yaml(80)).unwrap();
+        old.resolve_upstreams(None);
+        let mut new: Config = serde_yaml::from_str(&yaml(81)).unwrap();
+        new.resolve_upstreams(Some(&old));
+
+        let provider = |config: &Config, i: usize| config.backends[i].upstreams.clone().unwrap();
+        assert!(Arc::ptr_eq(&provider(&old, 0), &provider(&new, 0)));
+        assert!(!Arc::ptr_eq(&provider(&old, 1), &provider(&new, 1)));
+        assert_eq!(
+            new.pools["search"].select(),
+            Some(Endpoint::new("10.0.0.1", 81))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_names_are_resolved_and_refreshed_on_failure() {
+        let backend = Backend::Basic {
+            ip: "localhost".to_string(),
+            port: 8099,
+        };
+        let provider = provider_for_backend(
+            &backend,
+            &HetznerConfig::default(),
+            &DnsConfig::default(),
+            &XdsConfig::default(),
+        );
+        assert_eq!(provider.refresh_interval(), Some(Duration::from_secs(60)));
+        let endpoints = provider.endpoints();
+        assert!(!endpoints.is_empty());
+        assert!(
+            endpoints
+                .iter()
+                .all(|e| e.addr == "127.0.0.1:8099" || e.addr == "[::1]:8099"),
+            "{endpoints:?}"
+        );
+
+        let mut member = member(&[], 1, 0);
+        member.upstreams = provider.clone();
+        let pool = ServicePool::new(vec![member], settings(Strategy::RoundRobin), None);
+        let endpoint = pool.select().unwrap();
+        pool.report(
+            &endpoint.addr,
+            Some(Duration::from_millis(5)),
+            Instant::now(),
+        );
+        assert!(!provider.refresh_requested());
+        let endpoint = pool.select().unwrap();
+        pool.report(&endpoint.addr, None, Instant::now());
+        assert!(provider.refresh_requested());
+
+        provider.refresh().await.unwrap();
+        assert!(!provider.refresh_requested());
+        assert_eq!(provider.endpoints(), endpoints);
+
+        // IP addresses are never resolved
+        let backend = Backend::Basic {
+            ip: "10.0.0.1".to_string(),
+            port: 80,
+        };
+        let provider = provider_for_backend(
+            &backend,
+            &HetznerConfig::default(),
+            &DnsConfig::default(),
+            &XdsConfig::default(),
+        );
+        assert_eq!(provider.refresh_interval(), None);
+    }
+
+    #[test]
+    fn test_static_backend_endpoints_take_turns() {
+        let backend = Backend::Static {
+            endpoints: vec!["10.0.0.1:8080".into(), "10.0.0.2:8080".into()],
+        };
+        let provider = provider_for_backend(
+            &backend,
+            &HetznerConfig::default(),
+            &DnsConfig::default(),
+            &XdsConfig::default(),
+        );
+        assert_eq!(provider.refresh_interval(), None);
+
+        let mut member = member(&[], 1, 0);
+        member.upstreams = provider;
+        let pool = ServicePool::new(vec![member], settings(Strategy::RoundRobin), None);
+        let picks: Vec<String> = (0..4).map(|_| pool.select().unwrap().addr).collect();
+        assert_eq!(
+            picks,
+            [
+                "10.0.0.1:8080",
+                "10.0.0.2:8080",
+                "10.0.0.1:8080",
+                "10.0.0.2:8080"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_static_endpoint_weights_split_traffic() {
+        let backend = Backend::Static {
+            endpoints: vec![
+                StaticEndpoint::Weighted {
+                    addr: "10.0.0.1:8080".to_string(),
+                    weight: 9,
+                },
+                "10.0.0.2:8080".into(),
+                StaticEndpoint::Weighted {
+                    addr: "10.0.0.3:8080".to_string(),
+                    weight: 0,
+                },
+            ],
+        };
+        let mut member = member(&[], 1, 0);
+        member.upstreams = provider_for_backend(
+            &backend,
+            &HetznerConfig::default(),
+            &DnsConfig::default(),
+            &XdsConfig::default(),
+        );
+        let pool = ServicePool::new(vec![member], settings(Strategy::RoundRobin), None);
+
+        let now = Instant::now();
+        let sequence: Vec<String> = (0..10).map(|_| pick(&pool, now).unwrap().addr).collect();
+        // The light endpoint gets its turn mid-cycle rather than after nine in a row
+        assert_eq!(
+            sequence.iter().position(|addr| addr == "10.0.0.2:8080"),
+            Some(5)
+        );
+        let counts = picks(&pool, now, 1000);
+        assert_eq!(counts["10.0.0.1:8080"], 900);
+        assert_eq!(counts["10.0.0.2:8080"], 100);
+        assert!(!counts.contains_key("10.0.0.3:8080"));
+    }
+
+    #[test]
+    fn test_ipv6_endpoints_and_hosts() {
+        assert_eq!(Endpoint::new("::1", 8099).addr, "[::1]:8099");
+        assert_eq!(Endpoint::new("[::1]", 8099).addr, "[::1]:8099");
+        assert_eq!(Endpoint::new("10.0.0.1", 80).addr, "10.0.0.1:80");
+        assert!(Endpoint::new("::1", 80).is_ipv6());
+
+        for host in ["::1", "[2001:db8::1]", "10.0.0.1", "api.internal", "geo-1"] {
+            assert!(is_valid_host(host), "{host}");
+        }
+        for host in [
+            "",
+            "10.0.0.1:80",
+            "[::1]:8099",
+            "[10.0.0.1]",
+            "bad host",
+            "-a.b",
+        ] {
+            assert!(!is_valid_host(host), "{host}");
+        }
+    }
+
+    #[test]
+    fn test_dual_stack_hosts_alternate_families_and_fall_back() {
+        let upstreams = DnsUpstreams::new("unresolved.invalid", 80, &DnsConfig::default());
+        upstreams.set(vec![
+            "10.0.0.2:80".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+        ]);
+        let addrs: Vec<String> = upstreams
+            .endpoints()
+            .iter()
+            .map(|e| e.addr.clone())
+            .collect();
+        assert_eq!(addrs, ["[2001:db8::1]:80", "10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(
+            upstreams.fallback("[2001:db8::1]:80"),
+            Some((Endpoint::new("10.0.0.1", 80), Duration::from_millis(250)))
+        );
+        assert_eq!(
+            upstreams.fallback("10.0.0.2:80").map(|(e, _)| e),
+            Some(Endpoint::new("2001:db8::1", 80))
+        );
+
+        let mut member = member(&[], 1, 0);
+        member.upstreams = Arc::new(upstreams);
+        let pool = ServicePool::new(vec![member], settings(Strategy::RoundRobin), None);
+        assert!(pool.fallback_for("10.0.0.1:80").is_some());
+        assert!(pool.fallback_for("10.0.0.9:80").is_none());
+
+        // Single-family hosts and a zero delay have no fallback
+        let upstreams = DnsUpstreams::new(
+            "unresolved.invalid",
+            80,
+            &DnsConfig {
+                happy_eyeballs_delay_ms: 0,
+                ..Default::default()
+            },
+        );
+        upstreams.set(vec![
+            "10.0.0.1:80".parse().unwrap(),
+            "[::1]:80".parse().unwrap(),
+        ]);
+        assert_eq!(upstreams.fallback("10.0.0.1:80"), None);
+        let upstreams = DnsUpstreams::new("unresolved.invalid", 80, &DnsConfig::default());
+        upstreams.set(vec!["10.0.0.1:80".parse().unwrap()]);
+        assert_eq!(upstreams.fallback("10.0.0.1:80"), None);
+    }
+
+    #[test]
+    fn test_retries_go_to_endpoints_not_tried() {
+        let pool = ServicePool::new(
+            vec![member(&["10.0.0.1", "10.0.0.2", "10.0.0.3"], 1, 0)],
+            settings(Strategy::ConsistentHash),
+            None,
+        );
+        let first = pool.select_for(Some("tenant")).unwrap().addr;
+        let mut tried = vec![first];
+        for _ in 0..2 {
+            let next = pool.select_retry(Some("tenant"), &tried).unwrap().addr;
+            assert!(!tried.contains(&next));
+            tried.push(next);
+        }
+        // With every endpoint tried, one of them goes again
+        assert!(pool.select_retry(Some("tenant"), &tried).is_some());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let on: Vec<RetryOn> = serde_yaml::from_str("[connect_failure, 502, '504']").unwrap();
+        assert_eq!(
+            on,
+            vec![
+                RetryOn::ConnectFailure,
+                RetryOn::BadGateway,
+                RetryOn::GatewayTimeout
+            ]
+        );
+        assert!(serde_yaml::from_str::<Vec<RetryOn>>("[500]").is_err());
+        assert!(serde_yaml::from_str::<Vec<RetryOn>>("[reset]").is_err());
+
+        let policy = RetryPolicy { retries: 1, on };
+        assert!(policy.allows(RetryOn::BadGateway, 0));
+        assert!(!policy.allows(RetryOn::BadGateway, 1));
+        assert!(!policy.allows(RetryOn::ServiceUnavailable, 0));
+        assert_eq!(RetryOn::for_status(503), Some(RetryOn::ServiceUnavailable));
+        assert_eq!(RetryOn::for_status(500), None);
+    }
+}
@@ -1,38 +1,83 @@
 //! API usage tracking with minute-level granularity and hourly SQLite dumps.
 //!
 //! This module captures per-request metrics (request count, response data size) grouped by
-//! (account_id, api_key, plan_id, minute). Every hour, the data is flushed to a timestamped
+//! (account_id, api_key, plan_id, service, backend, minute). Every hour, the data is flushed to a timestamped
 //! SQLite database file (`usage-<YYYYMMDDHH>.db`).
+//!
+//! The tracker also remembers when each key was last used and from which client IP. These are
+//! written to a `KeyActivity` table in the hourly files so the control plane can find stale keys.
+//!
+//! Once an hour is over its file is closed: switched out of WAL mode so it is self-contained,
+//! then described by a `usage-<YYYYMMDDHH>.manifest.json` sidecar with row counts, totals and
+//! a SHA-256 checksum. Billing uses the manifests to detect truncated or missing hours.
+//!
+//! With [`UsageResidency`], the rows and key activity of accounts with a residency region go
+//! to the directory of that region instead, which gets hourly files of its own.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::iter;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use async_trait::async_trait;
 use pingora::services::background::BackgroundService;
-use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use crate::alert::AlertSink;
+use crate::clock::{Clock, SystemClock};
+use crate::degradation::{Degradation, Dependency, UsageDirPolicy};
+use crate::residency::UsageResidency;
+use crate::sqlite;
+use crate::sync::{MutexExt, RwLockExt};
+
 // ============================================================================
 // Data Structures
 // ============================================================================
 
-/// Composite key for usage aggregation: (account_id, api_key, plan_id, minute_timestamp).
+/// Composite key for usage aggregation: (account_id, api_key, plan_id, route, minute_timestamp).
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct UsageKey {
     pub account_id: i64,
     pub api_key: Uuid,
     pub plan_id: i64,
+    pub route: UsageRoute,
     /// Unix timestamp truncated to the start of the minute.
     pub minute_ts: i64,
 }
 
+/// Where a request was routed, so revenue can be attributed per service.
+///
+/// Both are empty for requests that never matched a service.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct UsageRoute {
+    /// Name of the matched service, as in the `services` config.
+    pub service: String,
+    /// Backend variant serving it, e.g. `basic`.
+    pub backend: String,
+    /// Static labels of the service as `key=value,...`.
+    pub labels: String,
+}
+
 /// Mutable counters for a single usage key.
 #[derive(Debug, Clone, Default)]
 pub struct UsageRecord {
     pub total_requests: u64,
     pub total_data_bytes: u64,
+    /// Requests where the client disconnected before the response completed.
+    pub aborted_requests: u64,
+}
+
+/// Most recent use of an API key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyActivity {
+    pub account_id: i64,
+    /// Unix timestamp of the last request.
+    pub last_used_ts: i64,
+    /// Client IP of the last request, when known; de-identified in privacy mode.
+    pub client_ip: Option<String>,
 }
 
 // ============================================================================
@@ -46,6 +91,23 @@ pub struct UsageTracker {
     data: RwLock<HashMap<UsageKey, UsageRecord>>,
     /// Output directory for shutdown flush (optional).
     output_dir: RwLock<Option<PathBuf>>,
+    /// Per-key request totals written to disk, by month start: the last two months.
+    flushed_months: Mutex<BTreeMap<i64, HashMap<Uuid, MonthTotal>>>,
+    /// Last use of each key, and whether it changed since the last flush.
+    activity: RwLock<HashMap<Uuid, (KeyActivity, bool)>>,
+    /// Time source for hour rollover in the writer.
+    clock: Arc<dyn Clock>,
+    /// Id of the LB node recorded with every usage row.
+    node: String,
+    /// Directories of accounts with a residency region, instead of the output directory.
+    residency: Option<Arc<UsageResidency>>,
+}
+
+/// Requests of a key written to the hourly files of a month.
+#[derive(Debug, Clone, Copy, Default)]
+struct MonthTotal {
+    account_id: i64,
+    requests: u64,
 }
 
 impl Default for UsageTracker {
@@ -53,6 +115,11 @@ impl Default for UsageTracker {
         Self {
             data: RwLock::new(HashMap::new()),
             output_dir: RwLock::new(None),
+            flushed_months: Mutex::new(BTreeMap::new()),
+            activity: RwLock::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            node: String::new(),
+            residency: None,
         }
     }
 }
@@ -63,15 +130,72 @@ impl UsageTracker {
         Self::default()
     }
 
+    /// Use `clock` for the current time instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Record usage as served by the LB node `node`.
+    pub fn with_node(mut self, node: impl Into<String>) -> Self {
+        self.node = node.into();
+        self
+    }
+
+    /// Write the usage of accounts with a residency region to the directory of the region.
+    pub fn with_residency(mut self, residency: Arc<UsageResidency>) -> Self {
+        self.residency = Some(residency);
+        self
+    }
+
+    /// Directories of the residency regions, written besides the output directory.
+    pub fn residency_dirs(&self) -> Vec<PathBuf> {
+        self.residency
+            .iter()
+            .flat_map(|residency| residency.dirs().map(Path::to_path_buf))
+            .collect()
+    }
+
+    /// Split `items` by the directory the usage of their account goes to, `output_dir`
+    /// unless the account has a residency region.
+    fn by_dir<T>(
+        &self,
+        output_dir: &Path,
+        items: impl IntoIterator<Item = T>,
+        account_id: impl Fn(&T) -> i64,
+    ) -> BTreeMap<PathBuf, Vec<T>> {
+        let mut by_dir: BTreeMap<PathBuf, Vec<T>> = BTreeMap::new();
+        for item in items {
+            let dir = self
+                .residency
+                .as_ref()
+                .and_then(|residency| residency.dir_for(account_id(&item)))
+                .unwrap_or(output_dir);
+            by_dir.entry(dir.to_path_buf()).or_default().push(item);
+        }
+        by_dir
+    }
+
+    /// Id of the LB node usage is recorded for; empty when unnamed.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Current Unix timestamp according to the tracker's clock.
+    pub fn now_secs(&self) -> i64 {
+        self.clock.unix_secs()
+    }
+
     /// Set the output directory for shutdown flush.
     pub fn set_output_dir(&self, path: impl AsRef<Path>) {
-        let mut dir = self.output_dir.write().unwrap();
+        let mut dir = self.output_dir.write_or_recover();
         *dir = Some(path.as_ref().to_path_buf());
     }
 
     /// Record a single request's usage.
     ///
     /// - `account_id`, `api_key`, `plan_id`: identifiers from AccountStore
+    /// - `route`: service and backend the request was routed to
     /// - `response_bytes`: size of the response body in bytes
     /// - `timestamp_secs`: Unix timestamp of the request (seconds since epoch)
     pub fn record(
@@ -79,23 +203,55 @@ impl UsageTracker {
         account_id: i64,
         api_key: Uuid,
         plan_id: i64,
+        route: &UsageRoute,
+        response_bytes: u64,
+        timestamp_secs: i64,
+    ) {
+        let key = Self::key(account_id, api_key, plan_id, route, timestamp_secs);
+        self.record_inner(key, response_bytes, false);
+    }
+
+    /// Record a request the client abandoned before the response completed.
+    ///
+    /// Counts towards `total_requests` (the upstream did see it) and `aborted_requests`.
+    pub fn record_aborted(
+        &self,
+        account_id: i64,
+        api_key: Uuid,
+        plan_id: i64,
+        route: &UsageRoute,
         response_bytes: u64,
         timestamp_secs: i64,
     ) {
-        // Truncate to minute boundary
-        let minute_ts = timestamp_secs - (timestamp_secs % 60);
+        let key = Self::key(account_id, api_key, plan_id, route, timestamp_secs);
+        self.record_inner(key, response_bytes, true);
+    }
 
-        let key = UsageKey {
+    fn key(
+        account_id: i64,
+        api_key: Uuid,
+        plan_id: i64,
+        route: &UsageRoute,
+        timestamp_secs: i64,
+    ) -> UsageKey {
+        UsageKey {
             account_id,
             api_key,
             plan_id,
-            minute_ts,
-        };
+            route: route.clone(),
+            // Truncate to minute boundary
+            minute_ts: timestamp_secs - (timestamp_secs % 60),
+        }
+    }
 
-        let mut data = self.data.write().unwrap();
+    fn record_inner(&self, key: UsageKey, response_bytes: u64, aborted: bool) {
+        let mut data = self.data.write_or_recover();
         let record = data.entry(key).or_default();
         record.total_requests += 1;
         record.total_data_bytes += response_bytes;
+        if aborted {
+            record.aborted_requests += 1;
+        }
     }
 
     /// Extract all records for a given hour and remove them from the tracker.
@@ -104,7 +260,7 @@ impl UsageTracker {
     pub fn drain_hour(&self, hour_ts: i64) -> Vec<(UsageKey, UsageRecord)> {
         let hour_end = hour_ts + 3600;
 
-        let mut data = self.data.write().unwrap();
+        let mut data = self.data.write_or_recover();
         let mut drained = Vec::new();
 
         data.retain(|key, record| {
@@ -119,37 +275,212 @@ impl UsageTracker {
         drained
     }
 
+    /// Put back drained records that could not be written, adding them to those recorded
+    /// since.
+    pub fn restore(&self, records: Vec<(UsageKey, UsageRecord)>) {
+        let mut data = self.data.write_or_recover();
+        for (key, restored) in records {
+            let record = data.entry(key).or_default();
+            record.total_requests += restored.total_requests;
+            record.total_data_bytes += restored.total_data_bytes;
+            record.aborted_requests += restored.aborted_requests;
+        }
+    }
+
+    /// Number of records held in memory.
+    pub fn held_records(&self) -> usize {
+        self.data.read_or_recover().len()
+    }
+
+    /// Hours (Unix timestamp at hour start) with records in memory, oldest first.
+    pub fn hours(&self) -> Vec<i64> {
+        let data = self.data.read_or_recover();
+        let mut hours: Vec<i64> = data
+            .keys()
+            .map(|key| key.minute_ts - key.minute_ts.rem_euclid(3600))
+            .collect();
+        hours.sort_unstable();
+        hours.dedup();
+        hours
+    }
+
+    /// Requests recorded in memory (not yet flushed) for a key since `since_ts`.
+    pub fn pending_requests(&self, api_key: Uuid, since_ts: i64) -> u64 {
+        let data = self.data.read_or_recover();
+        data.iter()
+            .filter(|(key, _)| key.api_key == api_key && key.minute_ts >= since_ts)
+            .map(|(_, record)| record.total_requests)
+            .sum()
+    }
+
+    /// Add records written to disk to the monthly totals.
+    fn add_flushed(&self, records: &[(UsageKey, UsageRecord)]) {
+        let mut months = self.flushed_months.lock_or_recover();
+        for (key, record) in records {
+            let total = months
+                .entry(month_start_ts(key.minute_ts))
+                .or_default()
+                .entry(key.api_key)
+                .or_default();
+            total.account_id = key.account_id;
+            total.requests += record.total_requests;
+        }
+        // Late records of the previous month still count towards it
+        while months.len() > 2 {
+            months.pop_first();
+        }
+    }
+
+    /// Rebuild the monthly totals from the hourly files of the month containing `now_ts`,
+    /// in the output directory and those of the residency regions.
+    ///
+    /// Run once at startup; afterwards the totals grow with each flush, so quota checks
+    /// never read the files.
+    pub fn load_monthly_totals(&self, now_ts: i64) {
+        let Some(dir) = self.output_dir.read_or_recover().clone() else {
+            return;
+        };
+        let month_start = month_start_ts(now_ts);
+        let mut totals: HashMap<Uuid, MonthTotal> = HashMap::new();
+        for dir in iter::once(dir).chain(self.residency_dirs()) {
+            match month_totals_on_disk(&dir, month_start) {
+                Ok(on_disk) => {
+                    for (api_key, on_disk) in on_disk {
+                        let total = totals.entry(api_key).or_default();
+                        total.account_id = on_disk.account_id;
+                        total.requests += on_disk.requests;
+                    }
+                }
+                Err(e) => log::warn!("Failed to read monthly usage from {:?}: {}", dir, e),
+            }
+        }
+        let mut months = self.flushed_months.lock_or_recover();
+        months.clear();
+        months.insert(month_start, totals);
+    }
+
+    /// Total requests for a key in the calendar month (UTC) containing `now_ts`.
+    ///
+    /// Adds the records still held in memory to the running total of those written to the
+    /// output directory and those of the residency regions.
+    pub fn monthly_requests(&self, api_key: Uuid, now_ts: i64) -> u64 {
+        let month_start = month_start_ts(now_ts);
+        let on_disk = self
+            .flushed_months
+            .lock_or_recover()
+            .get(&month_start)
+            .and_then(|totals| totals.get(&api_key))
+            .map_or(0, |total| total.requests);
+        on_disk + self.pending_requests(api_key, month_start)
+    }
+
+    /// Note a request from `api_key`, keeping the most recent time and client IP.
+    pub fn touch_key(
+        &self,
+        account_id: i64,
+        api_key: Uuid,
+        client_ip: Option<String>,
+        timestamp_secs: i64,
+    ) {
+        let mut activity = self.activity.write_or_recover();
+        let entry = activity.entry(api_key).or_insert_with(|| {
+            (
+                KeyActivity {
+                    account_id,
+                    last_used_ts: timestamp_secs,
+                    client_ip: client_ip.clone(),
+                },
+                true,
+            )
+        });
+        if timestamp_secs >= entry.0.last_used_ts {
+            entry.0 = KeyActivity {
+                account_id,
+                last_used_ts: timestamp_secs,
+                client_ip,
+            };
+            entry.1 = true;
+        }
+    }
+
+    /// Last recorded use of a key since the process started.
+    pub fn last_used(&self, api_key: Uuid) -> Option<KeyActivity> {
+        let activity = self.activity.read_or_recover();
+        activity.get(&api_key).map(|(a, _)| a.clone())
+    }
+
+    /// Take the key activity that changed since the last call.
+    pub fn take_dirty_activity(&self) -> Vec<(Uuid, KeyActivity)> {
+        let mut activity = self.activity.write_or_recover();
+        activity
+            .iter_mut()
+            .filter(|(_, (_, dirty))| *dirty)
+            .map(|(key, (a, dirty))| {
+                *dirty = false;
+                (*key, a.clone())
+            })
+            .collect()
+    }
+
+    /// Mark the activity of `keys` as changed again, after writing it failed.
+    pub fn restore_activity(&self, keys: impl IntoIterator<Item = Uuid>) {
+        let mut activity = self.activity.write_or_recover();
+        for key in keys {
+            if let Some((_, dirty)) = activity.get_mut(&key) {
+                *dirty = true;
+            }
+        }
+    }
+
+    /// Drop the records and key activity of `account_id` not yet written to disk.
+    pub fn forget_account(&self, account_id: i64) {
+        self.data
+            .write_or_recover()
+            .retain(|key, _| key.account_id != account_id);
+        self.activity
+            .write_or_recover()
+            .retain(|_, (activity, _)| activity.account_id != account_id);
+        // Its rows on disk are deleted along with it
+        for totals in self.flushed_months.lock_or_recover().values_mut() {
+            totals.retain(|_, total| total.account_id != account_id);
+        }
+    }
+
     /// Drain all records regardless of hour. Used for shutdown flush.
     pub fn drain_all(&self) -> Vec<(UsageKey, UsageRecord)> {
-        let mut data = self.data.write().unwrap();
+        let mut data = self.data.write_or_recover();
         data.drain().collect()
     }
 
     /// Flush all remaining data to disk. Called on drop.
     fn flush_to_disk(&self) {
         let output_dir = {
-            let dir = self.output_dir.read().unwrap();
+            let dir = self.output_dir.read_or_recover();
             dir.clone()
         };
 
         if let Some(output_dir) = output_dir {
-            let all_records = self.drain_all();
-            if all_records.is_empty() {
-                return;
-            }
-
             // Group by hour
             let mut by_hour: HashMap<i64, Vec<(UsageKey, UsageRecord)>> = HashMap::new();
-            for (key, record) in all_records {
+            for (key, record) in self.drain_all() {
                 let hour_ts = key.minute_ts - (key.minute_ts % 3600);
                 by_hour.entry(hour_ts).or_default().push((key, record));
             }
 
             for (hour_ts, records) in by_hour {
-                if let Err(e) = write_records_to_db(&output_dir, hour_ts, &records) {
-                    log::error!("Failed to flush usage data on drop: {}", e);
-                } else {
-                    log::info!("Flushed {} usage records on drop", records.len());
+                for (dir, records) in self.by_dir(&output_dir, records, |(key, _)| key.account_id) {
+                    if let Err(e) = write_records_to_db(&dir, &self.node, hour_ts, &records) {
+                        log::error!("Failed to flush usage data on drop: {}", e);
+                    } else {
+                        log::info!("Flushed {} usage records on drop", records.len());
+                    }
+                }
+            }
+
+            let activity = self.take_dirty_activity();
+            for (dir, activity) in self.by_dir(&output_dir, activity, |(_, a)| a.account_id) {
+                if let Err(e) = write_activity_to_db(&dir, &activity) {
+                    log::error!("Failed to flush key activity on drop: {}", e);
                 }
             }
         }
@@ -162,50 +493,193 @@ impl Drop for UsageTracker {
     }
 }
 
-/// Write records to the SQLite database for a given hour.
-fn write_records_to_db(
-    output_dir: &Path,
-    hour_ts: i64,
-    records: &[(UsageKey, UsageRecord)],
-) -> Result<(), rusqlite::Error> {
-    use std::time::{Duration, UNIX_EPOCH};
+/// Unix timestamp of the start of the UTC calendar month containing `ts`.
+fn month_start_ts(ts: i64) -> i64 {
+    use chrono::{Datelike, TimeZone};
+
+    let dt = chrono::Utc
+        .timestamp_opt(ts, 0)
+        .single()
+        .unwrap_or_default();
+    chrono::Utc
+        .with_ymd_and_hms(dt.year(), dt.month(), 1, 0, 0, 0)
+        .single()
+        .map_or(0, |m| m.timestamp())
+}
 
-    let datetime = UNIX_EPOCH + Duration::from_secs(hour_ts as u64);
-    let datetime: chrono::DateTime<chrono::Utc> = datetime.into();
-    let filename = format!("usage-{}.db", datetime.format("%Y%m%d%H"));
-    let db_path = output_dir.join(&filename);
+/// Requests per key across the hourly files of the month starting at `month_start`.
+fn month_totals_on_disk(
+    output_dir: &Path,
+    month_start: i64,
+) -> Result<HashMap<Uuid, MonthTotal>, rusqlite::Error> {
+    // usage-YYYYMMDDHH.db -> every file of the month shares the usage-YYYYMM prefix
+    let month_prefix = &UsageWriter::db_filename(month_start)[..12];
+    let entries = match std::fs::read_dir(output_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(HashMap::new()),
+    };
+
+    let mut totals: HashMap<Uuid, MonthTotal> = HashMap::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if !(name.starts_with(month_prefix) && name.ends_with(".db")) {
+            continue;
+        }
+        let conn = sqlite::open_read_only(entry.path())?;
+        let mut stmt = conn.prepare(
+            "SELECT api_key, account_id, SUM(total_requests) FROM Usage GROUP BY api_key, account_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (api_key, account_id, requests) = row?;
+            let Ok(api_key) = Uuid::parse_str(&api_key) else {
+                continue;
+            };
+            let total = totals.entry(api_key).or_default();
+            total.account_id = account_id;
+            total.requests += requests.max(0) as u64;
+        }
+    }
+    Ok(totals)
+}
 
-    // Create directory if it doesn't exist
-    if let Some(parent) = db_path.parent() {
-        std::fs::create_dir_all(parent).ok();
+/// Open the usage file for `hour_ts` for writing.
+///
+/// Writing to a closed hour (late records after the clock stepped backwards) reopens it: its
+/// manifest is removed so the hour is closed again with the new rows.
+fn open_hour(output_dir: &Path, hour_ts: i64) -> Result<rusqlite::Connection, rusqlite::Error> {
+    let manifest = output_dir.join(manifest_filename(hour_ts));
+    if manifest.exists() {
+        log::warn!(
+            "Reopening closed usage file {} for late records",
+            UsageWriter::db_filename(hour_ts)
+        );
+        std::fs::remove_file(&manifest).ok();
     }
+    sqlite::open_wal(output_dir.join(UsageWriter::db_filename(hour_ts)))
+}
 
-    let conn = Connection::open(&db_path)?;
+/// Create the `Usage` table, migrating a table written before abort counts, service
+/// attribution or service labels.
+///
+/// Rows of the old layouts are kept with an empty service, backend and labels.
+fn create_usage_table(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    let (columns, has_service): (i64, bool) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(name = 'service'), 0) > 0 FROM pragma_table_info('Usage')",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let migrate = columns > 0 && !has_service;
+    if migrate {
+        conn.execute_batch("ALTER TABLE Usage RENAME TO UsageWithoutService")?;
+    }
 
-    // Create table if it doesn't exist
     conn.execute_batch(
         r#"
         CREATE TABLE IF NOT EXISTS Usage (
             account_id INTEGER NOT NULL,
             api_key CHAR(36) NOT NULL,
             plan_id INTEGER NOT NULL,
+            service TEXT NOT NULL DEFAULT '',
+            backend TEXT NOT NULL DEFAULT '',
+            labels TEXT NOT NULL DEFAULT '',
+            node TEXT NOT NULL DEFAULT '',
             date_time DATETIME NOT NULL,
             total_requests INTEGER,
             total_data_mb REAL,
-            PRIMARY KEY (account_id, api_key, plan_id, date_time)
+            aborted_requests INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (account_id, api_key, plan_id, service, backend, date_time)
         );
         "#,
     )?;
 
+    if migrate {
+        // Older files lack some of the counters; those keep their default of 0
+        let mut stmt = conn.prepare(
+            "SELECT name FROM pragma_table_info('UsageWithoutService') WHERE name IN \
+             ('account_id', 'api_key', 'plan_id', 'date_time', 'total_requests', \
+              'total_data_mb', 'aborted_requests')",
+        )?;
+        let columns = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .join(", ");
+        conn.execute_batch(&format!(
+            r#"
+            INSERT INTO Usage ({columns}) SELECT {columns} FROM UsageWithoutService;
+            DROP TABLE UsageWithoutService;
+            "#
+        ))?;
+    }
+
+    let has_labels: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('Usage') WHERE name = 'labels'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_labels {
+        conn.execute_batch("ALTER TABLE Usage ADD COLUMN labels TEXT NOT NULL DEFAULT ''")?;
+    }
+    let has_node: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('Usage') WHERE name = 'node'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_node {
+        conn.execute_batch("ALTER TABLE Usage ADD COLUMN node TEXT NOT NULL DEFAULT ''")?;
+    }
+    let has_aborted_requests: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('Usage') WHERE name = 'aborted_requests'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_aborted_requests {
+        conn.execute_batch(
+            "ALTER TABLE Usage ADD COLUMN aborted_requests INTEGER NOT NULL DEFAULT 0",
+        )?;
+    }
+    Ok(())
+}
+
+/// Write records of the LB node `node` to the SQLite database for a given hour.
+fn write_records_to_db(
+    output_dir: &Path,
+    node: &str,
+    hour_ts: i64,
+    records: &[(UsageKey, UsageRecord)],
+) -> Result<(), rusqlite::Error> {
+    let filename = UsageWriter::db_filename(hour_ts);
+    let db_path = output_dir.join(&filename);
+
+    // Create directory if it doesn't exist
+    if let Some(parent) = db_path.parent() {
+        std::fs::create_dir_all(parent).ok();
+    }
+
+    let mut conn = open_hour(output_dir, hour_ts)?;
+    // One transaction per flush, so readers never see a partially written hour
+    let tx = conn.transaction()?;
+    create_usage_table(&tx)?;
+
     // Insert or update records
-    let mut stmt = conn.prepare(
+    let mut stmt = tx.prepare(
         r#"
-        INSERT INTO Usage (account_id, api_key, plan_id, date_time, total_requests, total_data_mb)
-        VALUES (?1, ?2, ?3, datetime(?4, 'unixepoch'), ?5, ?6)
-        ON CONFLICT(account_id, api_key, plan_id, date_time)
+        INSERT INTO Usage (account_id, api_key, plan_id, service, backend, labels, node, date_time, total_requests, total_data_mb, aborted_requests)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime(?8, 'unixepoch'), ?9, ?10, ?11)
+        ON CONFLICT(account_id, api_key, plan_id, service, backend, date_time)
         DO UPDATE SET
+            labels = excluded.labels,
+            node = excluded.node,
             total_requests = total_requests + excluded.total_requests,
-            total_data_mb = total_data_mb + excluded.total_data_mb
+            total_data_mb = total_data_mb + excluded.total_data_mb,
+            aborted_requests = aborted_requests + excluded.aborted_requests
         "#,
     )?;
 
@@ -215,11 +689,18 @@ fn write_records_to_db(
             key.account_id,
             key.api_key.to_string(),
             key.plan_id,
+            key.route.service,
+            key.route.backend,
+            key.route.labels,
+            node,
             key.minute_ts,
             record.total_requests as i64,
             data_mb,
+            record.aborted_requests as i64,
         ])?;
     }
+    drop(stmt);
+    tx.commit()?;
 
     log::info!(
         "Flushed {} usage records to {}",
@@ -230,6 +711,288 @@ fn write_records_to_db(
     Ok(())
 }
 
+/// Write key activity into the hourly file matching each key's last use.
+///
+/// Rows are upserted per key and only ever move forward in time.
+fn write_activity_to_db(
+    output_dir: &Path,
+    entries: &[(Uuid, KeyActivity)],
+) -> Result<(), rusqlite::Error> {
+    let mut by_hour: HashMap<i64, Vec<&(Uuid, KeyActivity)>> = HashMap::new();
+    for entry in entries {
+        let hour_ts = entry.1.last_used_ts - (entry.1.last_used_ts % 3600);
+        by_hour.entry(hour_ts).or_default().push(entry);
+    }
+
+    for (hour_ts, entries) in by_hour {
+        std::fs::create_dir_all(output_dir).ok();
+        let mut conn = open_hour(output_dir, hour_ts)?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS KeyActivity (
+                api_key CHAR(36) PRIMARY KEY,
+                account_id INTEGER NOT NULL,
+                last_used_at DATETIME NOT NULL,
+                client_ip TEXT
+            );
+            "#,
+        )?;
+        let mut stmt = tx.prepare(
+            r#"
+            INSERT INTO KeyActivity (api_key, account_id, last_used_at, client_ip)
+            VALUES (?1, ?2, datetime(?3, 'unixepoch'), ?4)
+            ON CONFLICT(api_key) DO UPDATE SET
+                account_id = excluded.account_id,
+                last_used_at = excluded.last_used_at,
+                client_ip = excluded.client_ip
+            WHERE excluded.last_used_at >= last_used_at
+            "#,
+        )?;
+        for (api_key, activity) in entries {
+            stmt.execute(rusqlite::params![
+                api_key.to_string(),
+                activity.account_id,
+                activity.last_used_ts,
+                activity.client_ip.clone(),
+            ])?;
+        }
+        drop(stmt);
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Manifests
+// ============================================================================
+
+/// Sidecar describing a closed hourly usage file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageManifest {
+    /// Usage file name, e.g. `usage-2024010112.db`.
+    pub file: String,
+    /// Covered interval, `[hour_start, hour_end)` in UTC.
+    pub hour_start: String,
+    pub hour_end: String,
+    /// Rows in the `Usage` table.
+    pub usage_rows: u64,
+    pub total_requests: u64,
+    pub total_data_mb: f64,
+    pub aborted_requests: u64,
+    /// Totals per service, keyed by service name (empty for unrouted requests).
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceTotals>,
+    /// Rows in the `KeyActivity` table.
+    pub activity_rows: u64,
+    /// Size of the file in bytes; 0 when the hour had no traffic and no file was written.
+    pub size_bytes: u64,
+    /// Hex SHA-256 of the file, or `None` when no file was written.
+    pub sha256: Option<String>,
+}
+
+/// Usage totals of one service within an hour.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceTotals {
+    pub total_requests: u64,
+    pub total_data_mb: f64,
+    pub aborted_requests: u64,
+}
+
+fn manifest_filename(hour_ts: i64) -> String {
+    UsageWriter::db_filename(hour_ts).replace(".db", ".manifest.json")
+}
+
+fn utc_timestamp(ts: i64) -> String {
+    chrono::DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string()
+}
+
+/// Count rows in `table`, treating a missing table as empty.
+fn count_rows(conn: &rusqlite::Connection, table: &str) -> Result<u64, rusqlite::Error> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|n| n.max(0) as u64)
+}
+
+/// Whether `table` has `column`.
+fn has_column(
+    conn: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )
+}
+
+/// Usage totals per service in an hourly file. Files written before services or aborted
+/// requests were recorded count under `''` and with no aborted requests.
+fn service_totals(
+    conn: &rusqlite::Connection,
+) -> Result<BTreeMap<String, ServiceTotals>, rusqlite::Error> {
+    let service = if has_column(conn, "Usage", "service")? {
+        "service"
+    } else {
+        "''"
+    };
+    let aborted = if has_column(conn, "Usage", "aborted_requests")? {
+        "aborted_requests"
+    } else {
+        "0"
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {service}, SUM(total_requests), SUM(total_data_mb), SUM({aborted}) \
+         FROM Usage GROUP BY 1"
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            ServiceTotals {
+                total_requests: row.get::<_, i64>(1)?.max(0) as u64,
+                total_data_mb: row.get(2)?,
+                aborted_requests: row.get::<_, i64>(3)?.max(0) as u64,
+            },
+        ))
+    })?;
+    rows.collect()
+}
+
+/// Close the usage file for `hour_ts` and write its manifest.
+///
+/// The file is switched to rollback journal mode first, which checkpoints the WAL into it, so
+/// the checksum covers every committed row. This fails while a reader has the file open; the
+/// caller retries on its next pass.
+pub fn close_hour(output_dir: &Path, hour_ts: i64) -> Result<UsageManifest, std::io::Error> {
+    let file = UsageWriter::db_filename(hour_ts);
+    let db_path = output_dir.join(&file);
+    let mut manifest = UsageManifest {
+        file,
+        hour_start: utc_timestamp(hour_ts),
+        hour_end: utc_timestamp(hour_ts + 3600),
+        usage_rows: 0,
+        total_requests: 0,
+        total_data_mb: 0.0,
+        aborted_requests: 0,
+        services: BTreeMap::new(),
+        activity_rows: 0,
+        size_bytes: 0,
+        sha256: None,
+    };
+
+    if db_path.exists() {
+        let conn = sqlite::open_wal(&db_path).map_err(std::io::Error::other)?;
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode=DELETE", [], |row| row.get(0))
+            .map_err(std::io::Error::other)?;
+        if !mode.eq_ignore_ascii_case("delete") {
+            return Err(std::io::Error::other(format!(
+                "{} is still in use (journal mode {mode})",
+                db_path.display()
+            )));
+        }
+        manifest.usage_rows = count_rows(&conn, "Usage").map_err(std::io::Error::other)?;
+        manifest.activity_rows = count_rows(&conn, "KeyActivity").map_err(std::io::Error::other)?;
+        if manifest.usage_rows > 0 {
+            let aborted =
+                if has_column(&conn, "Usage", "aborted_requests").map_err(std::io::Error::other)? {
+                    "aborted_requests"
+                } else {
+                    "0"
+                };
+            (
+                manifest.total_requests,
+                manifest.total_data_mb,
+                manifest.aborted_requests,
+            ) = conn
+                .query_row(
+                    &format!(
+                        "SELECT COALESCE(SUM(total_requests), 0), \
+                         COALESCE(SUM(total_data_mb), 0.0), COALESCE(SUM({aborted}), 0) FROM Usage"
+                    ),
+                    [],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?.max(0) as u64,
+                            row.get(1)?,
+                            row.get::<_, i64>(2)?.max(0) as u64,
+                        ))
+                    },
+                )
+                .map_err(std::io::Error::other)?;
+            manifest.services = service_totals(&conn).map_err(std::io::Error::other)?;
+        }
+        drop(conn);
+
+        let bytes = std::fs::read(&db_path)?;
+        manifest.size_bytes = bytes.len() as u64;
+        manifest.sha256 = Some(hex::encode(Sha256::digest(&bytes)));
+    }
+
+    let path = output_dir.join(manifest_filename(hour_ts));
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&manifest)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(manifest)
+}
+
+/// Hour timestamp of a `YYYYMMDDHH` file name stamp.
+pub(crate) fn hour_of_stamp(stamp: &str) -> Option<i64> {
+    let hour =
+        chrono::NaiveDateTime::parse_from_str(&format!("{stamp}0000"), "%Y%m%d%H%M%S").ok()?;
+    Some(hour.and_utc().timestamp())
+}
+
+/// Hour timestamps of usage files before `current_hour` that have no manifest yet.
+fn unclosed_hours(output_dir: &Path, current_hour: i64) -> Vec<i64> {
+    let Ok(entries) = std::fs::read_dir(output_dir) else {
+        return Vec::new();
+    };
+    let mut hours: Vec<i64> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name();
+            let stamp = name.to_str()?.strip_prefix("usage-")?.strip_suffix(".db")?;
+            hour_of_stamp(stamp)
+        })
+        .filter(|hour| *hour < current_hour && !output_dir.join(manifest_filename(*hour)).exists())
+        .collect();
+    hours.sort_unstable();
+    hours
+}
+
+/// Manifests of every closed usage file in `output_dir`, oldest first.
+pub fn closed_files(output_dir: &Path) -> Result<Vec<UsageManifest>, std::io::Error> {
+    let mut manifests = Vec::new();
+    for entry in std::fs::read_dir(output_dir)? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.ends_with(".manifest.json"))
+        {
+            continue;
+        }
+        let manifest: UsageManifest = serde_json::from_slice(&std::fs::read(entry.path())?)?;
+        manifests.push(manifest);
+    }
+    manifests.sort_by(|a, b| a.hour_start.cmp(&b.hour_start));
+    Ok(manifests)
+}
+
 // ============================================================================
 // Usage Writer
 // ============================================================================
@@ -238,8 +1001,11 @@ fn write_records_to_db(
 pub struct UsageWriter {
     tracker: Arc<UsageTracker>,
     output_dir: PathBuf,
-    /// Tracks the last hour we flushed (Unix timestamp at hour start).
-    last_flushed_hour: RwLock<Option<i64>>,
+    /// Hour of the previous rollover check (Unix timestamp at hour start).
+    last_seen_hour: RwLock<Option<i64>>,
+    alerts: Arc<AlertSink>,
+    /// Whether records that fail to be written are kept for the next flush.
+    degradation: Arc<Degradation>,
 }
 
 impl UsageWriter {
@@ -247,20 +1013,34 @@ impl UsageWriter {
     pub fn new(tracker: Arc<UsageTracker>, output_dir: impl AsRef<Path>) -> Self {
         // Set the output dir on the tracker for Drop-based flush
         tracker.set_output_dir(output_dir.as_ref());
+        let now = tracker.now_secs();
+        tracker.load_monthly_totals(now);
 
         Self {
             tracker,
             output_dir: output_dir.as_ref().to_path_buf(),
-            last_flushed_hour: RwLock::new(None),
+            last_seen_hour: RwLock::new(Some(now - now % 3600)),
+            alerts: Arc::new(AlertSink::default()),
+            degradation: Arc::new(Degradation::default()),
         }
     }
 
+    /// Report flush failures to `alerts`.
+    pub fn with_alerts(mut self, alerts: Arc<AlertSink>) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    /// Track the usage directory in `degradation`, and handle records that fail to be
+    /// written by its policy.
+    pub fn with_degradation(mut self, degradation: Arc<Degradation>) -> Self {
+        self.degradation = degradation;
+        self
+    }
+
     /// Get the current hour timestamp (Unix timestamp at hour start).
-    fn current_hour_ts() -> i64 {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    fn current_hour_ts(&self) -> i64 {
+        let now = self.tracker.now_secs();
         now - (now % 3600)
     }
 
@@ -273,8 +1053,10 @@ impl UsageWriter {
         format!("usage-{}.db", datetime.format("%Y%m%d%H"))
     }
 
-    /// Flush records for a specific hour to a SQLite file.
+    /// Flush records for a specific hour to a SQLite file, along with changed key activity.
     pub fn flush_hour(&self, hour_ts: i64) -> Result<usize, rusqlite::Error> {
+        self.flush_activity()?;
+
         let records = self.tracker.drain_hour(hour_ts);
         if records.is_empty() {
             return Ok(0);
@@ -286,8 +1068,11 @@ impl UsageWriter {
 
     /// Flush all remaining records (for shutdown). Groups by hour and writes each.
     pub fn flush_all(&self) -> Result<usize, rusqlite::Error> {
+        self.flush_activity()?;
+
         let all_records = self.tracker.drain_all();
         if all_records.is_empty() {
+            self.degradation.recover(Dependency::UsageDir);
             return Ok(0);
         }
 
@@ -303,90 +1088,171 @@ impl UsageWriter {
             self.write_records_to_db(hour_ts, &records)?;
             total += records.len();
         }
-
+        self.degradation.recover(Dependency::UsageDir);
         Ok(total)
     }
 
-    /// Write records to the SQLite database for a given hour.
-    fn write_records_to_db(
-        &self,
-        hour_ts: i64,
-        records: &[(UsageKey, UsageRecord)],
-    ) -> Result<(), rusqlite::Error> {
-        let filename = Self::db_filename(hour_ts);
-        let db_path = self.output_dir.join(&filename);
-
-        // Create directory if it doesn't exist
-        if let Some(parent) = db_path.parent() {
-            std::fs::create_dir_all(parent).ok();
+    /// Write key activity that changed since the last flush.
+    fn flush_activity(&self) -> Result<(), rusqlite::Error> {
+        let activity = self.tracker.take_dirty_activity();
+        if activity.is_empty() {
+            return Ok(());
         }
+        for (dir, activity) in self
+            .tracker
+            .by_dir(&self.output_dir, activity, |(_, a)| a.account_id)
+        {
+            if let Err(e) = write_activity_to_db(&dir, &activity) {
+                self.degradation.enter(Dependency::UsageDir, e.to_string());
+                if self.degradation.config().usage_dir == UsageDirPolicy::Buffer {
+                    self.tracker
+                        .restore_activity(activity.iter().map(|(key, _)| *key));
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
 
-        let conn = Connection::open(&db_path)?;
-
-        // Create table if it doesn't exist
-        conn.execute_batch(
-            r#"
-            CREATE TABLE IF NOT EXISTS Usage (
-                account_id INTEGER NOT NULL,
-                api_key CHAR(36) NOT NULL,
-                plan_id INTEGER NOT NULL,
-                date_time DATETIME NOT NULL,
-                total_requests INTEGER,
-                total_data_mb REAL,
-                PRIMARY KEY (account_id, api_key, plan_id, date_time)
+    /// Keep `records` that could not be written for the next flush, as far as the
+    /// degradation policy allows.
+    fn keep_unwritten(&self, records: Vec<(UsageKey, UsageRecord)>, error: &rusqlite::Error) {
+        self.degradation
+            .enter(Dependency::UsageDir, error.to_string());
+        let config = self.degradation.config();
+        if config.usage_dir == UsageDirPolicy::Buffer
+            && self.tracker.held_records() + records.len() <= config.usage_buffer_records
+        {
+            self.tracker.restore(records);
+        } else {
+            self.alerts.critical(
+                "usage",
+                format!(
+                    "Dropped {} usage records that could not be written",
+                    records.len()
+                ),
             );
-            "#,
-        )?;
+        }
+    }
 
-        // Insert or update records
-        let mut stmt = conn.prepare(
-            r#"
-            INSERT INTO Usage (account_id, api_key, plan_id, date_time, total_requests, total_data_mb)
-            VALUES (?1, ?2, ?3, datetime(?4, 'unixepoch'), ?5, ?6)
-            ON CONFLICT(account_id, api_key, plan_id, date_time)
-            DO UPDATE SET
-                total_requests = total_requests + excluded.total_requests,
-                total_data_mb = total_data_mb + excluded.total_data_mb
-            "#,
-        )?;
+    /// The output directory followed by those of the residency regions.
+    fn dirs(&self) -> Vec<PathBuf> {
+        iter::once(self.output_dir.clone())
+            .chain(self.tracker.residency_dirs())
+            .collect()
+    }
 
-        for (key, record) in records {
-            let data_mb = record.total_data_bytes as f64 / (1024.0 * 1024.0);
-            stmt.execute(rusqlite::params![
-                key.account_id,
-                key.api_key.to_string(),
-                key.plan_id,
-                key.minute_ts,
-                record.total_requests as i64,
-                data_mb,
-            ])?;
+    /// Flush every hour held in memory other than the current one, then close finished hours.
+    ///
+    /// Works from the hour buckets present in the tracker rather than from the previous
+    /// clock reading, so a clock stepped backwards or forwards neither strands records in
+    /// memory nor flushes an hour twice. Records landing in an hour that was already closed
+    /// reopen it, and it is closed again with an updated manifest.
+    pub fn check_hour_rollover(&self) {
+        let current_hour = self.current_hour_ts();
+        let mut flushed = false;
+        let mut failed = false;
+        for hour_ts in self.tracker.hours() {
+            if hour_ts == current_hour {
+                continue;
+            }
+            match self.flush_hour(hour_ts) {
+                Ok(_) => flushed = true,
+                Err(e) => {
+                    failed = true;
+                    self.alerts.critical(
+                        "usage",
+                        format!("Failed to flush usage data for hour {hour_ts}: {e}"),
+                    );
+                }
+            }
+        }
+        if !flushed && let Err(e) = self.flush_activity() {
+            failed = true;
+            self.alerts
+                .critical("usage", format!("Failed to flush key activity: {e}"));
+        }
+        if !failed {
+            self.degradation.recover(Dependency::UsageDir);
         }
 
-        log::info!(
-            "Flushed {} usage records to {}",
-            records.len(),
-            db_path.display()
-        );
+        let last_seen = self.last_seen_hour.write_or_recover().replace(current_hour);
+        if let Some(last) = last_seen
+            && last < current_hour
+        {
+            for dir in self.dirs() {
+                if dir.join(Self::db_filename(last)).exists()
+                    || dir.join(manifest_filename(last)).exists()
+                {
+                    continue;
+                }
+                // Record hours without traffic too, so gaps mean missing data
+                if let Err(e) = close_hour(&dir, last) {
+                    self.alerts.warning(
+                        "usage",
+                        format!("Failed to write usage manifest for hour {last}: {e}"),
+                    );
+                }
+            }
+        }
+        self.close_finished_hours(current_hour);
+    }
 
-        Ok(())
+    /// Close every finished hour that has a usage file but no manifest yet.
+    ///
+    /// Covers hours written before a restart and closes that failed on an earlier pass.
+    pub fn close_finished_hours(&self, current_hour: i64) {
+        for dir in self.dirs() {
+            for hour_ts in unclosed_hours(&dir, current_hour) {
+                if let Err(e) = close_hour(&dir, hour_ts) {
+                    self.alerts.warning(
+                        "usage",
+                        format!("Failed to close usage file for hour {hour_ts}: {e}"),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Write records to the SQLite database for a given hour.
+    fn write_records_to_db(
+        &self,
+        hour_ts: i64,
+        records: &[(UsageKey, UsageRecord)],
+    ) -> Result<(), rusqlite::Error> {
+        let by_dir = self
+            .tracker
+            .by_dir(&self.output_dir, records, |(key, _)| key.account_id);
+        let mut result = Ok(());
+        for (dir, records) in by_dir {
+            let records: Vec<_> = records.into_iter().cloned().collect();
+            // Other directories are still written when one fails
+            match write_records_to_db(&dir, self.tracker.node(), hour_ts, &records) {
+                Ok(()) => self.tracker.add_flushed(&records),
+                Err(e) => {
+                    self.keep_unwritten(records, &e);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        result
     }
 }
 
 #[async_trait]
 impl BackgroundService for UsageWriter {
     async fn start(&self, mut shutdown: tokio::sync::watch::Receiver<bool>) {
-        // Initialize last flushed hour
-        {
-            let mut last = self.last_flushed_hour.write().unwrap();
-            *last = Some(Self::current_hour_ts());
-        }
+        self.close_finished_hours(self.current_hour_ts());
 
         loop {
             // Check for shutdown
             if *shutdown.borrow() {
                 // Flush all remaining data on shutdown
                 if let Err(e) = self.flush_all() {
-                    log::error!("Failed to flush usage data on shutdown: {}", e);
+                    self.alerts.critical(
+                        "usage",
+                        format!("Failed to flush usage data on shutdown: {e}"),
+                    );
                 } else {
                     log::info!("Flushed remaining usage data on shutdown");
                 }
@@ -398,32 +1264,15 @@ impl BackgroundService for UsageWriter {
                 _ = shutdown.changed() => {
                     // Shutdown requested - flush all data
                     if let Err(e) = self.flush_all() {
-                        log::error!("Failed to flush usage data on shutdown: {}", e);
+                        self.alerts
+                        .critical("usage", format!("Failed to flush usage data on shutdown: {e}"));
                     } else {
                         log::info!("Flushed remaining usage data on shutdown");
                     }
                     return;
                 }
                 _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                    // Check if we crossed an hour boundary
-                    let current_hour = Self::current_hour_ts();
-                    let last_hour = {
-                        let last = self.last_flushed_hour.read().unwrap();
-                        *last
-                    };
-
-                    if let Some(last) = last_hour {
-                        if current_hour > last {
-                            // New hour - flush the previous hour
-                            if let Err(e) = self.flush_hour(last) {
-                                log::error!("Failed to flush usage data for hour {}: {}", last, e);
-                            }
-
-                            // Update last flushed hour
-                            let mut last_guard = self.last_flushed_hour.write().unwrap();
-                            *last_guard = Some(current_hour);
-                        }
-                    }
+                    self.check_hour_rollover();
                 }
             }
         }
@@ -437,6 +1286,9 @@ impl BackgroundService for UsageWriter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::TestClock;
+    use rusqlite::Connection;
+    use std::time::SystemTime;
     use tempfile::TempDir;
 
     const TEST_UUID: &str = "00000000-0000-0000-0000-000000000010";
@@ -445,14 +1297,22 @@ mod tests {
         Uuid::parse_str(TEST_UUID).unwrap()
     }
 
+    fn route() -> UsageRoute {
+        UsageRoute {
+            service: "geocode".to_string(),
+            backend: "basic".to_string(),
+            labels: "team=geo".to_string(),
+        }
+    }
+
     #[test]
     fn test_usage_tracker_record_increments_counts() {
         let tracker = UsageTracker::new();
 
         // Record 3 requests
-        tracker.record(1, test_uuid(), 100, 1024, 1000);
-        tracker.record(1, test_uuid(), 100, 2048, 1001);
-        tracker.record(1, test_uuid(), 100, 512, 1002);
+        tracker.record(1, test_uuid(), 100, &route(), 1024, 1000);
+        tracker.record(1, test_uuid(), 100, &route(), 2048, 1001);
+        tracker.record(1, test_uuid(), 100, &route(), 512, 1002);
 
         let records = tracker.drain_all();
         assert_eq!(records.len(), 1);
@@ -471,10 +1331,10 @@ mod tests {
         let tracker = UsageTracker::new();
 
         // Record requests in different minutes
-        tracker.record(1, test_uuid(), 100, 100, 60); // minute 60
-        tracker.record(1, test_uuid(), 100, 100, 119); // minute 60
-        tracker.record(1, test_uuid(), 100, 100, 120); // minute 120
-        tracker.record(1, test_uuid(), 100, 100, 180); // minute 180
+        tracker.record(1, test_uuid(), 100, &route(), 100, 60); // minute 60
+        tracker.record(1, test_uuid(), 100, &route(), 100, 119); // minute 60
+        tracker.record(1, test_uuid(), 100, &route(), 100, 120); // minute 120
+        tracker.record(1, test_uuid(), 100, &route(), 100, 180); // minute 180
 
         let records = tracker.drain_all();
         assert_eq!(records.len(), 3);
@@ -495,13 +1355,13 @@ mod tests {
         let tracker = UsageTracker::new();
 
         // Hour 0: timestamps 0-3599
-        tracker.record(1, test_uuid(), 100, 100, 0);
-        tracker.record(1, test_uuid(), 100, 100, 1800);
-        tracker.record(1, test_uuid(), 100, 100, 3599);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 0);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 1800);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 3599);
 
         // Hour 1: timestamps 3600-7199
-        tracker.record(1, test_uuid(), 100, 100, 3600);
-        tracker.record(1, test_uuid(), 100, 100, 7199);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 3600);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 7199);
 
         // Drain hour 0
         let hour0_records = tracker.drain_hour(0);
@@ -523,7 +1383,7 @@ mod tests {
         let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
 
         // Record some data
-        tracker.record(1, test_uuid(), 100, 1024 * 1024, 3600); // 1 MB at hour 1
+        tracker.record(1, test_uuid(), 100, &route(), 1024 * 1024, 3600); // 1 MB at hour 1
 
         // Flush hour 1
         let count = writer.flush_hour(3600).unwrap();
@@ -550,6 +1410,551 @@ mod tests {
         assert!((row.get::<_, f64>(4).unwrap() - 1.0).abs() < 0.001); // ~1 MB
     }
 
+    #[test]
+    fn test_usage_is_attributed_per_service() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        let routing = UsageRoute {
+            service: "routing".to_string(),
+            backend: "basic".to_string(),
+            ..Default::default()
+        };
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3600);
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3610);
+        tracker.record(1, test_uuid(), 100, &routing, 10, 3620);
+        assert_eq!(writer.flush_hour(3600).unwrap(), 2);
+
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        let rows: Vec<(String, String, i64)> = conn
+            .prepare("SELECT service, backend, total_requests FROM Usage ORDER BY service")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("geocode".to_string(), "basic".to_string(), 2),
+                ("routing".to_string(), "basic".to_string(), 1),
+            ]
+        );
+        drop(conn);
+
+        let manifest = close_hour(temp_dir.path(), 3600).unwrap();
+        assert_eq!(manifest.services["geocode"].total_requests, 2);
+        assert_eq!(manifest.services["routing"].total_requests, 1);
+    }
+
+    #[test]
+    fn test_usage_table_without_service_is_migrated() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        // A file written with the first schema, for the hour in progress at upgrade
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        conn.execute_batch(include_str!("../test_data/usage-baseline.sql"))
+            .unwrap();
+        conn.execute(
+            "INSERT INTO Usage VALUES (1, ?1, 100, datetime(3600, 'unixepoch'), 4, 0.0)",
+            [TEST_UUID],
+        )
+        .unwrap();
+        drop(conn);
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3600);
+        writer.flush_hour(3600).unwrap();
+
+        let manifest = close_hour(temp_dir.path(), 3600).unwrap();
+        assert_eq!(manifest.total_requests, 5);
+        assert_eq!(manifest.aborted_requests, 0);
+        assert_eq!(manifest.services[""].total_requests, 4);
+        assert_eq!(manifest.services["geocode"].total_requests, 1);
+    }
+
+    #[test]
+    fn test_usage_table_without_labels_gains_column() {
+        let tracker = Arc::new(UsageTracker::new().with_node("lb-1"));
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        // A file written before service labels, for the hour in progress at upgrade
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE Usage (
+                account_id INTEGER NOT NULL,
+                api_key CHAR(36) NOT NULL,
+                plan_id INTEGER NOT NULL,
+                service TEXT NOT NULL DEFAULT '',
+                backend TEXT NOT NULL DEFAULT '',
+                date_time DATETIME NOT NULL,
+                total_requests INTEGER,
+                total_data_mb REAL,
+                aborted_requests INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (account_id, api_key, plan_id, service, backend, date_time)
+            );
+            INSERT INTO Usage VALUES
+                (1, '{TEST_UUID}', 100, 'geocode', 'basic', datetime(3600, 'unixepoch'), 4, 0.0, 0);
+            "#
+        ))
+        .unwrap();
+        drop(conn);
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3600);
+        writer.flush_hour(3600).unwrap();
+
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        let (labels, node, total): (String, String, i64) = conn
+            .query_row(
+                "SELECT labels, node, total_requests FROM Usage",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(labels, "team=geo");
+        assert_eq!(node, "lb-1");
+        assert_eq!(total, 5);
+    }
+
+    #[test]
+    fn test_flush_does_not_block_on_open_reader() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3600);
+        writer.flush_hour(3600).unwrap();
+
+        // A reader in the middle of a read transaction, like the ETL
+        let reader = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        reader.execute_batch("BEGIN").unwrap();
+        let count = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT SUM(total_requests) FROM Usage", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count(&reader), 1);
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3660);
+        writer.flush_hour(3600).unwrap();
+
+        // The reader keeps its snapshot until its transaction ends
+        assert_eq!(count(&reader), 1);
+        reader.execute_batch("COMMIT").unwrap();
+        assert_eq!(count(&reader), 2);
+    }
+
+    #[test]
+    fn test_close_hour_writes_manifest_with_checksum() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 1024 * 1024, 3600);
+        tracker.record_aborted(1, test_uuid(), 100, &route(), 0, 3660);
+        tracker.touch_key(1, test_uuid(), None, 3660);
+        writer.flush_hour(3600).unwrap();
+
+        // Only finished hours without a manifest are closed
+        assert_eq!(unclosed_hours(temp_dir.path(), 7200), vec![3600]);
+        assert!(unclosed_hours(temp_dir.path(), 3600).is_empty());
+        writer.close_finished_hours(7200);
+        assert!(unclosed_hours(temp_dir.path(), 7200).is_empty());
+
+        let manifests = closed_files(temp_dir.path()).unwrap();
+        assert_eq!(manifests.len(), 1);
+        let manifest = &manifests[0];
+        assert_eq!(manifest.file, "usage-1970010101.db");
+        assert_eq!(manifest.hour_start, "1970-01-01T01:00:00Z");
+        assert_eq!(manifest.hour_end, "1970-01-01T02:00:00Z");
+        assert_eq!(manifest.usage_rows, 2);
+        assert_eq!(manifest.total_requests, 2);
+        assert_eq!(manifest.aborted_requests, 1);
+        assert!((manifest.total_data_mb - 1.0).abs() < 0.001);
+        assert_eq!(manifest.activity_rows, 1);
+
+        // The checksum covers the complete, self-contained file
+        let bytes = std::fs::read(temp_dir.path().join(&manifest.file)).unwrap();
+        assert_eq!(manifest.size_bytes, bytes.len() as u64);
+        assert_eq!(
+            manifest.sha256.as_deref(),
+            Some(hex::encode(Sha256::digest(&bytes)).as_str())
+        );
+        assert!(!temp_dir.path().join("usage-1970010101.db-wal").exists());
+    }
+
+    #[test]
+    fn test_hour_rollover_follows_clock() {
+        let clock = Arc::new(TestClock::at_unix(3599));
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock.clone()));
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+        writer.check_hour_rollover();
+        assert!(!temp_dir.path().join("usage-1970010100.db").exists());
+
+        // One second later the hour is over: flushed and closed
+        clock.advance(Duration::from_secs(1));
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+        writer.check_hour_rollover();
+        let manifests = closed_files(temp_dir.path()).unwrap();
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0].file, "usage-1970010100.db");
+        assert_eq!(manifests[0].total_requests, 1);
+
+        // The new hour's request stays in memory until that hour is over
+        assert_eq!(tracker.pending_requests(test_uuid(), 3600), 1);
+        assert!(!temp_dir.path().join("usage-1970010101.db").exists());
+    }
+
+    #[test]
+    fn test_clock_stepping_backwards_neither_strands_nor_double_counts() {
+        let clock = Arc::new(TestClock::at_unix(2 * 3600 + 30));
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock.clone()));
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+
+        // NTP steps the clock back an hour: the newer bucket is flushed but not closed,
+        // since its hour has not (again) ended
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(3600 + 30));
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+        writer.check_hour_rollover();
+        assert_eq!(tracker.hours(), vec![3600]);
+        assert!(temp_dir.path().join("usage-1970010102.db").exists());
+        assert!(closed_files(temp_dir.path()).unwrap().is_empty());
+
+        // Time catches up: each hour is flushed once and closed
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(3 * 3600));
+        writer.check_hour_rollover();
+        assert!(tracker.hours().is_empty());
+        let totals: Vec<(String, u64)> = closed_files(temp_dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.file, m.total_requests))
+            .collect();
+        assert_eq!(
+            totals,
+            vec![
+                ("usage-1970010101.db".to_string(), 1),
+                ("usage-1970010102.db".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_late_records_reopen_closed_hour() {
+        let clock = Arc::new(TestClock::at_unix(3600 + 30));
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock.clone()));
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+        clock.advance(Duration::from_secs(3600));
+        writer.check_hour_rollover();
+        assert_eq!(closed_files(temp_dir.path()).unwrap()[0].total_requests, 1);
+
+        // A record for the closed hour arrives after the clock stepped back
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(3600 + 90));
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+        clock.set(SystemTime::UNIX_EPOCH + Duration::from_secs(2 * 3600 + 120));
+        writer.check_hour_rollover();
+
+        let manifests = closed_files(temp_dir.path()).unwrap();
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0].total_requests, 2);
+    }
+
+    #[test]
+    fn test_clock_jumping_forward_flushes_every_past_hour() {
+        let clock = Arc::new(TestClock::at_unix(30));
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock.clone()));
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+        clock.advance(Duration::from_secs(3600));
+        tracker.record(1, test_uuid(), 100, &route(), 10, tracker.now_secs());
+
+        // Several hours pass between checks
+        clock.advance(Duration::from_secs(5 * 3600));
+        writer.check_hour_rollover();
+        assert!(tracker.hours().is_empty());
+        assert_eq!(closed_files(temp_dir.path()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_monthly_requests_reset_at_month_boundary() {
+        // 2024-01-31T23:59:30Z
+        let clock = Arc::new(TestClock::at_unix(1_706_745_570));
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock.clone()));
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        let january = tracker.now_secs();
+        tracker.record(1, test_uuid(), 100, &route(), 10, january);
+        assert_eq!(tracker.monthly_requests(test_uuid(), january), 1);
+
+        clock.advance(Duration::from_secs(60));
+        writer.check_hour_rollover();
+        assert!(temp_dir.path().join("usage-2024013123.db").exists());
+
+        let february = tracker.now_secs();
+        assert_eq!(tracker.monthly_requests(test_uuid(), february), 0);
+        tracker.record(1, test_uuid(), 100, &route(), 10, february);
+        assert_eq!(tracker.monthly_requests(test_uuid(), february), 1);
+        // January's request was written to January's file
+        assert_eq!(
+            month_totals_on_disk(temp_dir.path(), month_start_ts(january)).unwrap()[&test_uuid()]
+                .requests,
+            1
+        );
+    }
+
+    #[test]
+    fn test_close_hour_of_a_file_with_the_first_schema() {
+        let temp_dir = TempDir::new().unwrap();
+        // A finished hour written before the upgrade, never opened for writing since
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        conn.execute_batch(include_str!("../test_data/usage-baseline.sql"))
+            .unwrap();
+        conn.execute(
+            "INSERT INTO Usage VALUES (1, ?1, 100, datetime(3600, 'unixepoch'), 4, 1.5)",
+            [TEST_UUID],
+        )
+        .unwrap();
+        drop(conn);
+
+        assert_eq!(unclosed_hours(temp_dir.path(), 7200), vec![3600]);
+        let manifest = close_hour(temp_dir.path(), 3600).unwrap();
+        assert_eq!(manifest.usage_rows, 1);
+        assert_eq!(manifest.total_requests, 4);
+        assert_eq!(manifest.aborted_requests, 0);
+        assert_eq!(manifest.services[""].total_requests, 4);
+        assert!((manifest.services[""].total_data_mb - 1.5).abs() < 0.001);
+        assert!(unclosed_hours(temp_dir.path(), 7200).is_empty());
+    }
+
+    #[test]
+    fn test_close_hour_without_traffic() {
+        let temp_dir = TempDir::new().unwrap();
+        let manifest = close_hour(temp_dir.path(), 7200).unwrap();
+        assert_eq!(manifest.usage_rows, 0);
+        assert_eq!(manifest.size_bytes, 0);
+        assert_eq!(manifest.sha256, None);
+        assert!(
+            temp_dir
+                .path()
+                .join("usage-1970010102.manifest.json")
+                .exists()
+        );
+    }
+
+    #[test]
+    fn test_aborted_requests_are_counted_and_persisted() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3600);
+        tracker.record_aborted(1, test_uuid(), 100, &route(), 5, 3601);
+
+        writer.flush_hour(3600).unwrap();
+
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        let (total, aborted): (i64, i64) = conn
+            .query_row(
+                "SELECT total_requests, aborted_requests FROM Usage",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(aborted, 1);
+    }
+
+    #[test]
+    fn test_usage_table_without_aborted_requests_gains_column() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        // A file written before abort counts, for the hour in progress at upgrade
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        conn.execute_batch(&format!(
+            r#"
+            CREATE TABLE Usage (
+                account_id INTEGER NOT NULL,
+                api_key CHAR(36) NOT NULL,
+                plan_id INTEGER NOT NULL,
+                service TEXT NOT NULL DEFAULT '',
+                backend TEXT NOT NULL DEFAULT '',
+                labels TEXT NOT NULL DEFAULT '',
+                node TEXT NOT NULL DEFAULT '',
+                date_time DATETIME NOT NULL,
+                total_requests INTEGER NOT NULL DEFAULT 0,
+                total_data_mb REAL NOT NULL DEFAULT 0,
+                PRIMARY KEY (account_id, api_key, plan_id, service, backend, date_time)
+            );
+            INSERT INTO Usage VALUES
+                (1, '{TEST_UUID}', 100, 'geocode', 'basic', 'team=geo', '', datetime(3600, 'unixepoch'), 4, 0.0);
+            "#
+        ))
+        .unwrap();
+        drop(conn);
+
+        tracker.record_aborted(1, test_uuid(), 100, &route(), 5, 3601);
+        writer.flush_hour(3600).unwrap();
+
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        let (total, aborted): (i64, i64) = conn
+            .query_row(
+                "SELECT total_requests, aborted_requests FROM Usage",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(aborted, 1);
+    }
+
+    #[test]
+    fn test_monthly_requests_combines_disk_and_memory() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        // 2024-06-01T00:00:00Z and an hour later; 2024-05-31T23:00:00Z is the previous month
+        let june = 1_717_200_000;
+        tracker.record(1, test_uuid(), 100, &route(), 0, june - 3600);
+        tracker.record(1, test_uuid(), 100, &route(), 0, june);
+        tracker.record(1, test_uuid(), 100, &route(), 0, june + 10);
+        writer.flush_all().unwrap();
+
+        tracker.record(1, test_uuid(), 100, &route(), 0, june + 3600);
+        assert_eq!(tracker.monthly_requests(test_uuid(), june + 3700), 3);
+
+        // A flush moves records to disk without changing the total
+        writer.flush_hour(june + 3600).unwrap();
+        assert_eq!(tracker.monthly_requests(test_uuid(), june + 3700), 3);
+    }
+
+    #[test]
+    fn test_monthly_totals_are_rebuilt_at_startup() {
+        // 2024-06-01T01:00:00Z, with usage of May and June on disk
+        let june: i64 = 1_717_200_000;
+        let clock = Arc::new(TestClock::at_unix(june as u64 + 3600));
+        let temp_dir = TempDir::new().unwrap();
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock.clone()));
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+        tracker.record(1, test_uuid(), 100, &route(), 0, june - 3600);
+        tracker.record(1, test_uuid(), 100, &route(), 0, june);
+        tracker.record(1, test_uuid(), 100, &route(), 0, june + 10);
+        writer.flush_all().unwrap();
+        drop(writer);
+        drop(tracker);
+
+        let tracker = Arc::new(UsageTracker::new().with_clock(clock));
+        let _writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+        assert_eq!(tracker.monthly_requests(test_uuid(), june + 3700), 2);
+
+        // Quota checks use the totals, not the files
+        std::fs::remove_file(temp_dir.path().join("usage-2024060100.db")).unwrap();
+        assert_eq!(tracker.monthly_requests(test_uuid(), june + 3700), 2);
+
+        // Erasing the account drops its total
+        tracker.forget_account(1);
+        assert_eq!(tracker.monthly_requests(test_uuid(), june + 3700), 0);
+    }
+
+    #[test]
+    fn test_usage_of_resident_accounts_goes_to_their_region() {
+        use crate::accounts::{Account, AccountStore};
+        use crate::residency::UsageResidency;
+
+        let temp_dir = TempDir::new().unwrap();
+        let eu_dir = temp_dir.path().join("eu");
+        let mut store = AccountStore::new();
+        store.upsert_account(Account {
+            account_id: 2,
+            email: "eu@example.com".to_string(),
+            plan_id: 100,
+            billing_status: "active".to_string(),
+            residency: Some("eu".to_string()),
+        });
+        let residency = UsageResidency::new(
+            BTreeMap::from([("eu".to_string(), eu_dir.clone())]),
+            Arc::new(RwLock::new(store)),
+        );
+        let tracker = Arc::new(UsageTracker::new().with_residency(Arc::new(residency)));
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+        let eu_key = Uuid::from_u128(2);
+
+        tracker.record(1, test_uuid(), 100, &route(), 0, 3700);
+        tracker.record(2, eu_key, 100, &route(), 0, 3700);
+        tracker.record(2, eu_key, 100, &route(), 0, 3790);
+        tracker.touch_key(2, eu_key, None, 3790);
+        writer.flush_all().unwrap();
+
+        let count = |dir: &Path, table: &str| -> i64 {
+            let conn = Connection::open(dir.join("usage-1970010101.db")).unwrap();
+            conn.query_row(
+                &format!("SELECT COUNT(*) FROM {table} WHERE account_id = 2"),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count(temp_dir.path(), "Usage"), 0);
+        assert_eq!(count(&eu_dir, "Usage"), 2);
+        assert_eq!(count(&eu_dir, "KeyActivity"), 1);
+        assert_eq!(tracker.monthly_requests(eu_key, 3800), 2);
+        assert_eq!(tracker.monthly_requests(test_uuid(), 3800), 1);
+
+        // Both directories get their hour closed
+        writer.close_finished_hours(7200);
+        assert!(
+            temp_dir
+                .path()
+                .join("usage-1970010101.manifest.json")
+                .exists()
+        );
+        assert!(eu_dir.join("usage-1970010101.manifest.json").exists());
+    }
+
+    #[test]
+    fn test_key_activity_is_flushed_to_hourly_file() {
+        let tracker = Arc::new(UsageTracker::new());
+        let temp_dir = TempDir::new().unwrap();
+        let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
+
+        tracker.touch_key(1, test_uuid(), Some("10.0.0.7".to_string()), 3700);
+        // Out-of-order updates do not move last use backwards
+        tracker.touch_key(1, test_uuid(), None, 3650);
+        assert_eq!(tracker.last_used(test_uuid()).unwrap().last_used_ts, 3700);
+
+        writer.flush_all().unwrap();
+        assert!(tracker.take_dirty_activity().is_empty());
+
+        let conn = Connection::open(temp_dir.path().join("usage-1970010101.db")).unwrap();
+        let (last_used, client_ip): (String, String) = conn
+            .query_row(
+                "SELECT last_used_at, client_ip FROM KeyActivity WHERE api_key = ?1",
+                [TEST_UUID],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(last_used, "1970-01-01 01:01:40");
+        assert_eq!(client_ip, "10.0.0.7");
+    }
+
     #[test]
     fn test_flush_all_groups_by_hour() {
         let tracker = Arc::new(UsageTracker::new());
@@ -557,8 +1962,8 @@ mod tests {
         let writer = UsageWriter::new(tracker.clone(), temp_dir.path());
 
         // Records in hour 0 and hour 1
-        tracker.record(1, test_uuid(), 100, 100, 0);
-        tracker.record(1, test_uuid(), 100, 100, 3600);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 0);
+        tracker.record(1, test_uuid(), 100, &route(), 100, 3600);
 
         let count = writer.flush_all().unwrap();
         assert_eq!(count, 2);
@@ -567,4 +1972,50 @@ mod tests {
         assert!(temp_dir.path().join("usage-1970010100.db").exists());
         assert!(temp_dir.path().join("usage-1970010101.db").exists());
     }
+
+    #[test]
+    fn test_unwritten_usage_is_buffered_until_the_dir_works() {
+        use crate::degradation::{Degradation, DegradationConfig};
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("usage");
+        std::fs::write(&dir, "not a directory").unwrap();
+        let tracker = Arc::new(UsageTracker::new());
+        let degradation = Arc::new(Degradation::default());
+        let writer = UsageWriter::new(tracker.clone(), &dir).with_degradation(degradation.clone());
+
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3700);
+        assert!(writer.flush_hour(3600).is_err());
+        assert!(degradation.is_degraded(Dependency::UsageDir));
+        assert_eq!(tracker.held_records(), 1);
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3700);
+        tracker.touch_key(1, test_uuid(), None, 3700);
+        assert!(writer.flush_all().is_err());
+
+        std::fs::remove_file(&dir).unwrap();
+        assert_eq!(writer.flush_all().unwrap(), 1);
+        assert!(!degradation.is_degraded(Dependency::UsageDir));
+        let conn = Connection::open(dir.join("usage-1970010101.db")).unwrap();
+        let (requests, activity): (i64, i64) = conn
+            .query_row(
+                "SELECT SUM(total_requests), (SELECT COUNT(*) FROM KeyActivity) FROM Usage",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((requests, activity), (2, 1));
+
+        // Dropped instead under the drop policy
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::write(&dir, "not a directory").unwrap();
+        let writer = UsageWriter::new(tracker.clone(), &dir).with_degradation(Arc::new(
+            Degradation::new(DegradationConfig {
+                usage_dir: UsageDirPolicy::Drop,
+                ..Default::default()
+            }),
+        ));
+        tracker.record(1, test_uuid(), 100, &route(), 10, 3700);
+        assert!(writer.flush_hour(3600).is_err());
+        assert_eq!(tracker.held_records(), 0);
+    }
 }
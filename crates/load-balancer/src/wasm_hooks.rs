@@ -0,0 +1,427 @@
+//! Request hooks loaded from WebAssembly modules.
+//!
+//! Each entry of `hooks` in the backend config names a compiled module run as a
+//! [`RequestHook`] after the hooks installed by the embedder. The modules are compiled when
+//! the config is loaded, and swapped with it on every reload, so a changed module is picked
+//! up without a restart:
+//!
+//! ```yaml
+//! hooks:
+//!   - name: tenant-gate
+//!     wasm: /etc/lb/hooks/tenant_gate.wasm
+//!     fuel: 1000000
+//!     fail_open: false
+//! ```
+//!
+//! A module imports nothing and exports its `memory`, an `alloc(len: i32) -> i32` returning
+//! where the proxy may write `len` bytes, and `on_request(ptr: i32, len: i32) -> i64`,
+//! `on_response(ptr: i32, len: i32) -> i64` or both. They are called with the JSON of the
+//! request or response:
+//!
+//! ```json
+//! {"service": "tiles", "tags": ["tenant"], "key_fingerprint": "9f2c...",
+//!  "method": "GET", "path": "/tiles/1", "headers": [["x-tenant", "acme"]]}
+//! ```
+//!
+//! A response has its `status` instead of the `method` and `path`. They return where their
+//! answer is as `ptr << 32 | len`, or 0 to change nothing. The answer is the JSON of a
+//! [`HookOutput`]: headers to set and to remove, and for a request, a `respond` with the
+//! `status` and JSON `body` answering it instead of the service.
+//!
+//! Every call runs in a fresh instance, so modules keep no state between requests, limited
+//! to `fuel` units of execution and [`MAX_MEMORY_BYTES`] of memory. A request whose hook
+//! fails, by trapping, running out of fuel or answering something else than a
+//! [`HookOutput`], is answered with a 503 unless the hook is `fail_open`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde::{Deserialize, Serialize};
+use wasmi::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::hooks::{HookAction, HookContext, RequestHook};
+
+/// Fuel of a call to a hook without `fuel`.
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+/// Largest memory an instance of a hook may grow.
+pub const MAX_MEMORY_BYTES: usize = 16 << 20;
+
+/// A hook of the backend config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HookConfig {
+    /// Name the hook is logged and counted by.
+    pub name: String,
+    /// Path of the compiled module.
+    pub wasm: String,
+    /// Units of execution of each call, [`DEFAULT_FUEL`] when unset.
+    #[serde(default)]
+    pub fuel: Option<u64>,
+    /// Pass requests on when the hook fails, instead of answering them with a 503.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+/// What a hook answers about a request or response.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HookOutput {
+    /// Headers to set, replacing any of the same name.
+    pub set_headers: BTreeMap<String, String>,
+    /// Headers to remove.
+    pub remove_headers: Vec<String>,
+    /// Answer to the request instead of the service; ignored for responses.
+    pub respond: Option<HookResponse>,
+}
+
+/// A request's answer from a hook.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct HookResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub body: serde_json::Value,
+}
+
+/// A compiled hook module.
+pub struct WasmHook {
+    name: String,
+    fuel: u64,
+    fail_open: bool,
+    engine: Engine,
+    module: Module,
+    on_request: bool,
+    on_response: bool,
+}
+
+impl fmt::Debug for WasmHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmHook")
+            .field("name", &self.name)
+            .field("fuel", &self.fuel)
+            .field("fail_open", &self.fail_open)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WasmHook {
+    /// Read and compile the module of a hook.
+    pub fn load(config: &HookConfig) -> Result<Self, String> {
+        let wasm = std::fs::read(&config.wasm).map_err(|e| format!("{}: {e}", config.wasm))?;
+        Self::new(config, &wasm).map_err(|e| format!("{}: {e}", config.wasm))
+    }
+
+    /// Compile `wasm` as the module of a hook, checking its imports and exports.
+    pub fn new(config: &HookConfig, wasm: &[u8]) -> Result<Self, String> {
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, wasm).map_err(|e| e.to_string())?;
+        if let Some(import) = module.imports().next() {
+            return Err(format!(
+                "imports {}.{}, but hooks may import nothing",
+                import.module(),
+                import.name()
+            ));
+        }
+        let exports: Vec<&str> = module.exports().map(|export| export.name()).collect();
+        for required in ["memory", "alloc"] {
+            if !exports.contains(&required) {
+                return Err(format!("does not export {required}"));
+            }
+        }
+        let on_request = exports.contains(&"on_request");
+        let on_response = exports.contains(&"on_response");
+        if !on_request && !on_response {
+            return Err("exports neither on_request nor on_response".to_string());
+        }
+        Ok(Self {
+            name: config.name.clone(),
+            fuel: config.fuel.unwrap_or(DEFAULT_FUEL),
+            fail_open: config.fail_open,
+            engine,
+            module,
+            on_request,
+            on_response,
+        })
+    }
+
+    /// Call `export` of a fresh instance with `input`; returns the bytes it answered, if
+    /// any.
+    pub fn call(&self, export: &str, input: &serde_json::Value) -> Result<Vec<u8>, String> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or("memory is not a memory")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|e| format!("alloc: {e}"))?;
+        let func = instance
+            .get_typed_func::<(i32, i32), i64>(&store, export)
+            .map_err(|e| format!("{export}: {e}"))?;
+
+        let input = serde_json::to_vec(input).map_err(|e| e.to_string())?;
+        let len = i32::try_from(input.len()).map_err(|_| "input too large")?;
+        let ptr = alloc
+            .call(&mut store, len)
+            .map_err(|e| format!("alloc: {e}"))?;
+        memory
+            .write(&mut store, ptr as u32 as usize, &input)
+            .map_err(|e| format!("alloc: {e}"))?;
+        let packed = func
+            .call(&mut store, (ptr, len))
+            .map_err(|e| format!("{export}: {e}"))? as u64;
+
+        let start = (packed >> 32) as usize;
+        let end = start + (packed & 0xffff_ffff) as usize;
+        memory
+            .data(&store)
+            .get(start..end)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| format!("{export}: answer out of bounds of memory"))
+    }
+
+    /// Call `export` with `input` and parse its answer.
+    fn output(&self, export: &str, input: &serde_json::Value) -> Result<HookOutput, String> {
+        let answer = self.call(export, input)?;
+        if answer.is_empty() {
+            return Ok(HookOutput::default());
+        }
+        let output: HookOutput =
+            serde_json::from_slice(&answer).map_err(|e| format!("{export}: {e}"))?;
+        if let Some(respond) = &output.respond
+            && !(200..=599).contains(&respond.status)
+        {
+            return Err(format!("{export}: status {} out of range", respond.status));
+        }
+        Ok(output)
+    }
+}
+
+/// JSON of a header map, as name and value pairs.
+fn headers_json(headers: &http::HeaderMap) -> serde_json::Value {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            serde_json::json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())])
+        })
+        .collect()
+}
+
+impl RequestHook for WasmHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_request(&self, ctx: &HookContext<'_>, request: &mut RequestHeader) -> HookAction {
+        if !self.on_request {
+            return HookAction::Continue;
+        }
+        let input = serde_json::json!({
+            "service": ctx.service,
+            "tags": ctx.tags,
+            "key_fingerprint": ctx.key_fingerprint,
+            "method": request.method.as_str(),
+            "path": request.uri.path(),
+            "headers": headers_json(&request.headers),
+        });
+        let applied = self.output("on_request", &input).and_then(|output| {
+            for name in output.remove_headers {
+                request.remove_header(&name);
+            }
+            for (name, value) in output.set_headers {
+                request
+                    .insert_header(name, value)
+                    .map_err(|e| format!("on_request: {e}"))?;
+            }
+            Ok(output.respond)
+        });
+        match applied {
+            Ok(None) => HookAction::Continue,
+            Ok(Some(respond)) => HookAction::Respond {
+                status: respond.status,
+                body: respond.body,
+            },
+            Err(e) if self.fail_open => {
+                log::warn!(
+                    "Request hook {} failed, passing the request on: {e}",
+                    self.name
+                );
+                HookAction::Continue
+            }
+            Err(e) => {
+                log::warn!("Request hook {} failed: {e}", self.name);
+                HookAction::Respond {
+                    status: 503,
+                    body: serde_json::json!({ "error": "request hook failed" }),
+                }
+            }
+        }
+    }
+
+    fn on_response(&self, ctx: &HookContext<'_>, response: &mut ResponseHeader) {
+        if !self.on_response {
+            return;
+        }
+        let input = serde_json::json!({
+            "service": ctx.service,
+            "tags": ctx.tags,
+            "key_fingerprint": ctx.key_fingerprint,
+            "status": response.status.as_u16(),
+            "headers": headers_json(&response.headers),
+        });
+        let applied = self.output("on_response", &input).and_then(|output| {
+            for name in output.remove_headers {
+                response.remove_header(&name);
+            }
+            for (name, value) in output.set_headers {
+                response
+                    .insert_header(name, value)
+                    .map_err(|e| format!("on_response: {e}"))?;
+            }
+            Ok(())
+        });
+        if let Err(e) = applied {
+            log::warn!("Request hook {} failed: {e}", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(fail_open: bool) -> HookConfig {
+        HookConfig {
+            name: "test-hook".to_string(),
+            wasm: "test.wasm".to_string(),
+            fuel: Some(100_000),
+            fail_open,
+        }
+    }
+
+    /// A module whose `on_request` answers `answer` and whose `on_response` echoes its
+    /// input; inputs are written from offset 1024.
+    fn module(answer: &str) -> Vec<u8> {
+        let escaped: String = answer.bytes().map(|b| format!("\\{b:02x}")).collect();
+        wat::parse_str(format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{escaped}")
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_request") (param i32 i32) (result i64)
+                    i64.const {len})
+                (func (export "on_response") (param $ptr i32) (param $len i32) (result i64)
+                    (i64.or
+                        (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                        (i64.extend_i32_u (local.get $len)))))"#,
+            len = answer.len()
+        ))
+        .unwrap()
+    }
+
+    fn ctx(tags: &[String]) -> HookContext<'_> {
+        HookContext {
+            service: Some("tiles"),
+            tags,
+            key_fingerprint: Some("9f2c"),
+        }
+    }
+
+    #[test]
+    fn test_modules_are_checked_when_compiled() {
+        assert!(WasmHook::new(&config(false), &module("")).is_ok());
+        let without_alloc = wat::parse_str(
+            r#"(module (memory (export "memory") 1)
+                (func (export "on_request") (param i32 i32) (result i64) i64.const 0))"#,
+        )
+        .unwrap();
+        let err = WasmHook::new(&config(false), &without_alloc).unwrap_err();
+        assert!(err.contains("alloc"), "{err}");
+        let importing = wat::parse_str(
+            r#"(module (import "env" "log" (func)) (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "on_request") (param i32 i32) (result i64) i64.const 0))"#,
+        )
+        .unwrap();
+        let err = WasmHook::new(&config(false), &importing).unwrap_err();
+        assert!(err.contains("env.log"), "{err}");
+        assert!(WasmHook::new(&config(false), b"not wasm").is_err());
+    }
+
+    #[test]
+    fn test_requests_and_responses_are_passed_as_json() {
+        let hook = WasmHook::new(&config(false), &module("")).unwrap();
+        let input = serde_json::json!({ "status": 200, "headers": [["x-a", "b"]] });
+        let echoed = hook.call("on_response", &input).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&echoed).unwrap(),
+            input
+        );
+    }
+
+    #[test]
+    fn test_answers_change_headers_or_respond() {
+        let tags = vec!["tenant".to_string()];
+        let mut request = RequestHeader::build("GET", b"/tiles/1", None).unwrap();
+        request.insert_header("x-internal", "1").unwrap();
+        let headers =
+            module(r#"{"set_headers":{"x-hook":"seen"},"remove_headers":["x-internal"]}"#);
+        let hook = WasmHook::new(&config(false), &headers).unwrap();
+        assert_eq!(
+            hook.on_request(&ctx(&tags), &mut request),
+            HookAction::Continue
+        );
+        assert_eq!(request.headers["x-hook"], "seen");
+        assert!(!request.headers.contains_key("x-internal"));
+
+        let respond = module(r#"{"respond":{"status":403,"body":{"error":"no tenant"}}}"#);
+        let hook = WasmHook::new(&config(false), &respond).unwrap();
+        assert_eq!(
+            hook.on_request(&ctx(&tags), &mut request),
+            HookAction::Respond {
+                status: 403,
+                body: serde_json::json!({ "error": "no tenant" })
+            }
+        );
+    }
+
+    #[test]
+    fn test_failed_hooks_answer_unless_fail_open() {
+        let looping = wat::parse_str(
+            r#"(module (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "on_request") (param i32 i32) (result i64)
+                    (loop $forever (br $forever))
+                    i64.const 0))"#,
+        )
+        .unwrap();
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        let hook = WasmHook::new(&config(false), &looping).unwrap();
+        let err = hook.call("on_request", &serde_json::json!({})).unwrap_err();
+        assert!(err.contains("fuel"), "{err}");
+        assert!(matches!(
+            hook.on_request(&ctx(&[]), &mut request),
+            HookAction::Respond { status: 503, .. }
+        ));
+        let hook = WasmHook::new(&config(true), &looping).unwrap();
+        assert_eq!(
+            hook.on_request(&ctx(&[]), &mut request),
+            HookAction::Continue
+        );
+
+        let invalid = WasmHook::new(&config(false), &module(r#"{"respond":{"status":42}}"#));
+        assert!(matches!(
+            invalid.unwrap().on_request(&ctx(&[]), &mut request),
+            HookAction::Respond { status: 503, .. }
+        ));
+    }
+}
@@ -0,0 +1,1228 @@
+//! Clusters and endpoints from an xDS control plane.
+//!
+//! Backends of type `xds` take their upstreams from the cluster of that name on an Envoy
+//! control plane, for teams that already describe their upstreams there:
+//!
+//! ```yaml
+//! xds:
+//!   server: http://xds-control-plane:18000
+//!   api_type: grpc
+//!   node_id: lb
+//!   refresh_secs: 15
+//! backends:
+//!   - service: geocode
+//!     backend: { type: xds, cluster: geocode-v2 }
+//! ```
+//!
+//! With `api_type: rest`, the default, the LB polls the control plane's REST-JSON cluster
+//! (CDS, `POST /v3/discovery:clusters`) and endpoint (EDS, `POST /v3/discovery:endpoints`)
+//! discovery services, sending the versions it has so unchanged resources are answered
+//! with 304. With `api_type: grpc`, it subscribes to both on the aggregated discovery
+//! service (ADS) instead, ACKing the updates it applies and NACKing those it cannot map,
+//! and opens the stream again after `refresh_secs` when it breaks.
+//!
+//! The `Cluster` maps onto the backend as follows: an `EDS` cluster takes its endpoints from
+//! the `ClusterLoadAssignment` named by its `eds_cluster_config.service_name`, or by the
+//! cluster name without one, and a `STATIC` cluster from its inline `load_assignment`.
+//! Other cluster types are rejected. The cluster's `connect_timeout` applies to its
+//! endpoints unless the backend sets `connect_timeout_ms`, and its `lb_policy` is ignored
+//! in favour of the service's `strategy`. Of a `ClusterLoadAssignment`, only the
+//! localities of the lowest priority are used, endpoints reported `UNHEALTHY`, `DRAINING`
+//! or `TIMEOUT` are left out, and `load_balancing_weight` becomes the endpoint weight, and
+//! the zone of the locality the endpoint's zone (see [`crate::upstream::LocalityConfig`]).
+//! Endpoints given by host name instead of IP address are skipped.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::sync::{MutexExt, RwLockExt};
+use crate::upstream::{Endpoint, UpstreamsProvider, http_client, ip_literal};
+
+/// Type URL of the cluster discovery resources.
+pub const CDS_TYPE_URL: &str = "type.googleapis.com/envoy.config.cluster.v3.Cluster";
+/// Path of the REST-JSON cluster discovery service.
+pub const CDS_PATH: &str = "/v3/discovery:clusters";
+/// Type URL of the endpoint discovery resources.
+pub const EDS_TYPE_URL: &str = "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment";
+/// Path of the REST-JSON endpoint discovery service.
+pub const EDS_PATH: &str = "/v3/discovery:endpoints";
+
+mod generated {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/envoy.service.discovery.v3.AggregatedDiscoveryService.rs"
+    ));
+}
+
+pub use generated::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
+pub use generated::aggregated_discovery_service_server::{
+    AggregatedDiscoveryService, AggregatedDiscoveryServiceServer,
+};
+
+/// How the LB talks to the control plane.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum XdsApiType {
+    /// Polling of the REST-JSON discovery services.
+    #[default]
+    Rest,
+    /// A stream of the gRPC aggregated discovery service.
+    Grpc,
+}
+
+/// Control plane settings, under `xds` in the backend config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct XdsConfig {
+    /// Base URL of the control plane's API; required by `xds` backends.
+    pub server: String,
+    /// API the control plane is reached with.
+    pub api_type: XdsApiType,
+    /// Node id the LB identifies as.
+    pub node_id: String,
+    /// Node cluster the LB identifies as.
+    pub node_cluster: String,
+    /// Seconds between polls, or before a broken stream is opened again.
+    pub refresh_secs: u64,
+}
+
+impl Default for XdsConfig {
+    fn default() -> Self {
+        Self {
+            server: String::new(),
+            api_type: XdsApiType::default(),
+            node_id: "lb".to_string(),
+            node_cluster: "lb".to_string(),
+            refresh_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscoveryResponse<T> {
+    #[serde(default)]
+    version_info: String,
+    #[serde(default = "Vec::new")]
+    resources: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct Cluster {
+    name: String,
+    /// Discovery type; proto3 JSON leaves out the default, `STATIC`.
+    #[serde(rename = "type", default = "static_type")]
+    discovery_type: String,
+    #[serde(default)]
+    eds_cluster_config: Option<EdsClusterConfig>,
+    #[serde(default, deserialize_with = "proto_duration")]
+    connect_timeout: Option<Duration>,
+    #[serde(default)]
+    load_assignment: Option<ClusterLoadAssignment>,
+}
+
+fn static_type() -> String {
+    "STATIC".to_string()
+}
+
+/// A `google.protobuf.Duration` in its JSON form, such as `"0.25s"`.
+fn proto_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    value
+        .strip_suffix('s')
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration '{value}'")))
+}
+
+#[derive(Deserialize)]
+struct EdsClusterConfig {
+    #[serde(default)]
+    service_name: String,
+}
+
+#[derive(Deserialize, Default)]
+struct ClusterLoadAssignment {
+    #[serde(default)]
+    cluster_name: String,
+    #[serde(default)]
+    endpoints: Vec<LocalityLbEndpoints>,
+}
+
+#[derive(Deserialize)]
+struct LocalityLbEndpoints {
+    #[serde(default)]
+    lb_endpoints: Vec<LbEndpoint>,
+    #[serde(default)]
+    priority: u32,
+    #[serde(default)]
+    locality: Option<Locality>,
+}
+
+#[derive(Deserialize)]
+struct Locality {
+    #[serde(default)]
+    zone: String,
+}
+
+#[derive(Deserialize)]
+struct LbEndpoint {
+    endpoint: Option<EndpointAddress>,
+    #[serde(default)]
+    health_status: Option<String>,
+    #[serde(default)]
+    load_balancing_weight: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct EndpointAddress {
+    address: Address,
+}
+
+#[derive(Deserialize)]
+struct Address {
+    socket_address: Option<SocketAddress>,
+}
+
+#[derive(Deserialize)]
+struct SocketAddress {
+    address: String,
+    port_value: u16,
+}
+
+/// Endpoints of a `ClusterLoadAssignment`.
+fn assignment_endpoints(assignment: &ClusterLoadAssignment) -> Vec<Endpoint> {
+    let Some(priority) = assignment.endpoints.iter().map(|l| l.priority).min() else {
+        return Vec::new();
+    };
+    let mut endpoints: Vec<Endpoint> = assignment
+        .endpoints
+        .iter()
+        .filter(|locality| locality.priority == priority)
+        .flat_map(|locality| {
+            let zone = locality.locality.as_ref().map(|l| l.zone.as_str());
+            let zone = zone.filter(|zone| !zone.is_empty());
+            locality.lb_endpoints.iter().map(move |lb| (lb, zone))
+        })
+        .filter(|(lb, _)| {
+            !matches!(
+                lb.health_status.as_deref(),
+                Some("UNHEALTHY" | "DRAINING" | "TIMEOUT")
+            )
+        })
+        .filter_map(|(lb, zone)| {
+            let socket = lb.endpoint.as_ref()?.address.socket_address.as_ref()?;
+            ip_literal(&socket.address)?;
+            let endpoint = Endpoint::new(&socket.address, socket.port_value)
+                .with_weight(lb.load_balancing_weight.unwrap_or(1));
+            Some(match zone {
+                Some(zone) => endpoint.with_zone(zone),
+                None => endpoint,
+            })
+        })
+        .collect();
+    endpoints.sort_by(|a, b| a.addr.cmp(&b.addr));
+    endpoints.dedup_by(|a, b| a.addr == b.addr);
+    endpoints
+}
+
+/// Where the endpoints of a cluster come from.
+#[derive(Debug, Clone, PartialEq)]
+enum EndpointSource {
+    /// The `ClusterLoadAssignment` of this name, from EDS.
+    Eds(String),
+    /// The cluster's own load assignment.
+    Static(Vec<Endpoint>),
+}
+
+/// A cluster mapped onto the backend model.
+#[derive(Debug, Clone, PartialEq)]
+struct MappedCluster {
+    endpoints: EndpointSource,
+    connect_timeout: Option<Duration>,
+}
+
+/// Map a cluster from CDS onto the backend model.
+fn map_cluster(cluster: &Cluster) -> Result<MappedCluster, String> {
+    let endpoints = match cluster.discovery_type.as_str() {
+        "EDS" => EndpointSource::Eds(
+            cluster
+                .eds_cluster_config
+                .as_ref()
+                .map(|eds| eds.service_name.clone())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| cluster.name.clone()),
+        ),
+        "STATIC" => EndpointSource::Static(
+            cluster
+                .load_assignment
+                .as_ref()
+                .map(assignment_endpoints)
+                .unwrap_or_default(),
+        ),
+        other => {
+            return Err(format!(
+                "cluster {} has type {other}; only EDS and STATIC clusters are supported",
+                cluster.name
+            ));
+        }
+    };
+    Ok(MappedCluster {
+        endpoints,
+        connect_timeout: cluster.connect_timeout,
+    })
+}
+
+/// What the LB knows of its cluster, with the resource versions it came with.
+#[derive(Debug, Default)]
+struct XdsState {
+    cluster: Option<MappedCluster>,
+    cluster_version: String,
+    endpoints: Arc<Vec<Endpoint>>,
+    endpoints_version: String,
+}
+
+impl XdsState {
+    /// Name of the `ClusterLoadAssignment` to take the endpoints from, if EDS is used.
+    fn eds_name(&self) -> Option<&str> {
+        match &self.cluster.as_ref()?.endpoints {
+            EndpointSource::Eds(name) => Some(name),
+            EndpointSource::Static(_) => None,
+        }
+    }
+
+    fn apply_cluster(&mut self, cluster: MappedCluster, version: String) {
+        match &cluster.endpoints {
+            EndpointSource::Static(endpoints) => {
+                self.endpoints = Arc::new(endpoints.clone());
+                self.endpoints_version.clear();
+            }
+            // The endpoints of the previous assignment serve until the new one arrives
+            EndpointSource::Eds(name) if self.eds_name() != Some(name) => {
+                self.endpoints_version.clear();
+            }
+            EndpointSource::Eds(_) => {}
+        }
+        self.cluster = Some(cluster);
+        self.cluster_version = version;
+    }
+
+    /// Apply the assignments of an EDS response; whether one was for the cluster.
+    fn apply_assignments(
+        &mut self,
+        assignments: &[ClusterLoadAssignment],
+        version: String,
+    ) -> bool {
+        let Some(name) = self.eds_name() else {
+            return false;
+        };
+        let Some(assignment) = assignments.iter().find(|a| a.cluster_name == name) else {
+            return false;
+        };
+        self.endpoints = Arc::new(assignment_endpoints(assignment));
+        self.endpoints_version = version;
+        true
+    }
+}
+
+/// The cluster of an xDS control plane, with its endpoints.
+#[derive(Debug)]
+pub struct XdsUpstreams {
+    cluster: String,
+    config: XdsConfig,
+    state: Arc<RwLock<XdsState>>,
+    /// ADS stream of the cluster, when the control plane is reached over gRPC.
+    stream: Mutex<Option<JoinHandle<()>>>,
+    /// Why the ADS stream broke or an update was NACKed, since the last refresh.
+    stream_error: Arc<Mutex<Option<String>>>,
+}
+
+impl XdsUpstreams {
+    pub fn new(cluster: &str, config: XdsConfig) -> Self {
+        Self {
+            cluster: cluster.to_string(),
+            config,
+            state: Arc::default(),
+            stream: Mutex::new(None),
+            stream_error: Arc::default(),
+        }
+    }
+
+    /// Fetch the `name` resources of `type_url` from `path`; `None` when they did not change
+    /// since `version`.
+    async fn fetch<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        type_url: &str,
+        name: &str,
+        version: &str,
+    ) -> Result<Option<DiscoveryResponse<T>>, String> {
+        let request = serde_json::json!({
+            "version_info": version,
+            "node": { "id": self.config.node_id, "cluster": self.config.node_cluster },
+            "resource_names": [name],
+            "type_url": type_url,
+        });
+        let response = http_client()
+            .post(format!(
+                "{}{path}",
+                self.config.server.trim_end_matches('/')
+            ))
+            .json(&request)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("xDS request for {name} failed: {e}"))?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        response
+            .json()
+            .await
+            .map(Some)
+            .map_err(|e| format!("invalid xDS response for {name}: {e}"))
+    }
+
+    /// Poll the REST-JSON discovery services for the cluster and its endpoints.
+    async fn poll(&self) -> Result<(), String> {
+        let version = self.state.read_or_recover().cluster_version.clone();
+        let clusters = self
+            .fetch::<Cluster>(CDS_PATH, CDS_TYPE_URL, &self.cluster, &version)
+            .await?;
+        if let Some(response) = clusters {
+            let cluster = response
+                .resources
+                .iter()
+                .find(|c| c.name == self.cluster)
+                .ok_or_else(|| format!("xDS server has no cluster {}", self.cluster))?;
+            let cluster = map_cluster(cluster)?;
+            self.state
+                .write_or_recover()
+                .apply_cluster(cluster, response.version_info);
+        }
+
+        let (name, version) = {
+            let state = self.state.read_or_recover();
+            match state.eds_name() {
+                Some(name) => (name.to_string(), state.endpoints_version.clone()),
+                None => return Ok(()),
+            }
+        };
+        let assignments = self
+            .fetch::<ClusterLoadAssignment>(EDS_PATH, EDS_TYPE_URL, &name, &version)
+            .await?;
+        if let Some(response) = assignments
+            && !self
+                .state
+                .write_or_recover()
+                .apply_assignments(&response.resources, response.version_info)
+        {
+            return Err(format!("xDS server has no endpoints for cluster {name}"));
+        }
+        Ok(())
+    }
+
+    /// Open the ADS stream unless it is running; the error it reported since the last call.
+    fn ensure_stream(&self) -> Result<(), String> {
+        let error = self.stream_error.lock_or_recover().take();
+        let mut stream = self.stream.lock_or_recover();
+        if stream.as_ref().is_none_or(|task| task.is_finished()) {
+            let cluster = self.cluster.clone();
+            let config = self.config.clone();
+            let state = self.state.clone();
+            let stream_error = self.stream_error.clone();
+            *stream = Some(tokio::spawn(async move {
+                let error = match subscribe(&cluster, &config, &state, &stream_error).await {
+                    Ok(()) => format!("xDS stream for cluster {cluster} was closed"),
+                    Err(e) => e,
+                };
+                *stream_error.lock_or_recover() = Some(error);
+            }));
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for XdsUpstreams {
+    fn drop(&mut self) {
+        if let Some(task) = self.stream.lock_or_recover().take() {
+            task.abort();
+        }
+    }
+}
+
+/// A request on the ADS stream for `names` of `type_url`, ACKing `version` and `nonce`, or
+/// NACKing them with `error`.
+fn discovery_request(
+    config: &XdsConfig,
+    type_url: &str,
+    names: &[&str],
+    (version, nonce): (&str, &str),
+    error: Option<String>,
+) -> proto::DiscoveryRequest {
+    proto::DiscoveryRequest {
+        version_info: version.to_string(),
+        node: Some(proto::Node {
+            id: config.node_id.clone(),
+            cluster: config.node_cluster.clone(),
+        }),
+        resource_names: names.iter().map(|name| name.to_string()).collect(),
+        type_url: type_url.to_string(),
+        response_nonce: nonce.to_string(),
+        error_detail: error.map(|message| proto::Status {
+            // INVALID_ARGUMENT
+            code: 3,
+            message,
+        }),
+    }
+}
+
+/// Subscribe to `cluster` and its endpoints on the ADS stream of the control plane, and
+/// apply their updates to `state` until the stream breaks. Updates that cannot be mapped
+/// are NACKed and reported in `stream_error`.
+async fn subscribe(
+    cluster: &str,
+    config: &XdsConfig,
+    state: &RwLock<XdsState>,
+    stream_error: &Mutex<Option<String>>,
+) -> Result<(), String> {
+    let failed =
+        |e: &dyn std::fmt::Display| format!("xDS stream for cluster {cluster} failed: {e}");
+    let mut client = AggregatedDiscoveryServiceClient::connect(config.server.clone())
+        .await
+        .map_err(|e| failed(&e))?;
+    let (requests, outgoing) = mpsc::unbounded_channel();
+    let send = |request| {
+        // The stream is gone when the receiver is; its status is reported below
+        let _ = requests.send(request);
+    };
+    send(discovery_request(
+        config,
+        CDS_TYPE_URL,
+        &[cluster],
+        ("", ""),
+        None,
+    ));
+    let mut responses = client
+        .stream_aggregated_resources(UnboundedReceiverStream::new(outgoing))
+        .await
+        .map_err(|e| failed(&e))?
+        .into_inner();
+
+    // Version and nonce of the last EDS response, for the next EDS request
+    let mut eds: (String, String) = Default::default();
+    while let Some(response) = responses.message().await.map_err(|e| failed(&e))? {
+        let nonce = response.nonce.as_str();
+        match response.type_url.as_str() {
+            CDS_TYPE_URL => {
+                let mapped = decode::<proto::Cluster>(&response.resources, CDS_TYPE_URL).and_then(
+                    |clusters| {
+                        let cluster = clusters
+                            .into_iter()
+                            .map(Cluster::from)
+                            .find(|c| c.name == cluster)
+                            .ok_or_else(|| format!("xDS server has no cluster {cluster}"))?;
+                        map_cluster(&cluster)
+                    },
+                );
+                let mut state = state.write_or_recover();
+                match mapped {
+                    Ok(mapped) => {
+                        let previous = state.eds_name().map(str::to_string);
+                        state.apply_cluster(mapped, response.version_info.clone());
+                        let ack = (response.version_info.as_str(), nonce);
+                        send(discovery_request(
+                            config,
+                            CDS_TYPE_URL,
+                            &[cluster],
+                            ack,
+                            None,
+                        ));
+                        if let Some(name) = state.eds_name()
+                            && previous.as_deref() != Some(name)
+                        {
+                            let last = (eds.0.as_str(), eds.1.as_str());
+                            send(discovery_request(config, EDS_TYPE_URL, &[name], last, None));
+                        }
+                    }
+                    Err(e) => {
+                        let nack = (state.cluster_version.as_str(), nonce);
+                        let request = discovery_request(
+                            config,
+                            CDS_TYPE_URL,
+                            &[cluster],
+                            nack,
+                            Some(e.clone()),
+                        );
+                        send(request);
+                        *stream_error.lock_or_recover() = Some(e);
+                    }
+                }
+            }
+            EDS_TYPE_URL => {
+                let assignments =
+                    decode::<proto::ClusterLoadAssignment>(&response.resources, EDS_TYPE_URL).map(
+                        |assignments| {
+                            assignments
+                                .into_iter()
+                                .map(ClusterLoadAssignment::from)
+                                .collect::<Vec<_>>()
+                        },
+                    );
+                let mut state = state.write_or_recover();
+                let Some(name) = state.eds_name().map(str::to_string) else {
+                    continue;
+                };
+                match assignments {
+                    Ok(assignments) => {
+                        // Assignments of other names are of a subscription already replaced
+                        state.apply_assignments(&assignments, response.version_info.clone());
+                        eds = (response.version_info.clone(), nonce.to_string());
+                        let ack = (eds.0.as_str(), nonce);
+                        send(discovery_request(config, EDS_TYPE_URL, &[&name], ack, None));
+                    }
+                    Err(e) => {
+                        eds.1 = nonce.to_string();
+                        let nack = (eds.0.as_str(), nonce);
+                        let request = discovery_request(
+                            config,
+                            EDS_TYPE_URL,
+                            &[&name],
+                            nack,
+                            Some(e.clone()),
+                        );
+                        send(request);
+                        *stream_error.lock_or_recover() = Some(e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Decode the `resources` of an ADS response, which must all be of `type_url`.
+fn decode<T: prost::Message + Default>(
+    resources: &[proto::Any],
+    type_url: &str,
+) -> Result<Vec<T>, String> {
+    resources
+        .iter()
+        .map(|resource| {
+            if resource.type_url != type_url {
+                return Err(format!("unexpected xDS resource {}", resource.type_url));
+            }
+            T::decode(resource.value.as_slice())
+                .map_err(|e| format!("invalid xDS resource {type_url}: {e}"))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl UpstreamsProvider for XdsUpstreams {
+    fn endpoints(&self) -> Arc<Vec<Endpoint>> {
+        self.state.read_or_recover().endpoints.clone()
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.config.refresh_secs.max(1)))
+    }
+
+    async fn refresh(&self) -> Result<(), String> {
+        match self.config.api_type {
+            XdsApiType::Rest => self.poll().await,
+            XdsApiType::Grpc => self.ensure_stream(),
+        }
+    }
+
+    fn connect_timeout(&self) -> Option<Duration> {
+        self.state
+            .read_or_recover()
+            .cluster
+            .as_ref()?
+            .connect_timeout
+    }
+}
+
+/// The subset of the Envoy v3 API the LB uses, as protobuf messages.
+pub mod proto {
+    use std::time::Duration;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DiscoveryRequest {
+        #[prost(string, tag = "1")]
+        pub version_info: String,
+        #[prost(message, optional, tag = "2")]
+        pub node: Option<Node>,
+        #[prost(string, repeated, tag = "3")]
+        pub resource_names: Vec<String>,
+        #[prost(string, tag = "4")]
+        pub type_url: String,
+        #[prost(string, tag = "5")]
+        pub response_nonce: String,
+        /// Why the response of `response_nonce` was NACKed.
+        #[prost(message, optional, tag = "6")]
+        pub error_detail: Option<Status>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DiscoveryResponse {
+        #[prost(string, tag = "1")]
+        pub version_info: String,
+        #[prost(message, repeated, tag = "2")]
+        pub resources: Vec<Any>,
+        #[prost(string, tag = "4")]
+        pub type_url: String,
+        #[prost(string, tag = "5")]
+        pub nonce: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Node {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub cluster: String,
+    }
+
+    /// `google.rpc.Status`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: String,
+    }
+
+    /// `google.protobuf.Any`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Any {
+        #[prost(string, tag = "1")]
+        pub type_url: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub value: Vec<u8>,
+    }
+
+    /// `google.protobuf.Duration`.
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct ProtoDuration {
+        #[prost(int64, tag = "1")]
+        pub seconds: i64,
+        #[prost(int32, tag = "2")]
+        pub nanos: i32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum DiscoveryType {
+        Static = 0,
+        StrictDns = 1,
+        LogicalDns = 2,
+        Eds = 3,
+        OriginalDst = 4,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Cluster {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(enumeration = "DiscoveryType", tag = "2")]
+        pub r#type: i32,
+        #[prost(message, optional, tag = "3")]
+        pub eds_cluster_config: Option<EdsClusterConfig>,
+        #[prost(message, optional, tag = "4")]
+        pub connect_timeout: Option<ProtoDuration>,
+        #[prost(message, optional, tag = "33")]
+        pub load_assignment: Option<ClusterLoadAssignment>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EdsClusterConfig {
+        #[prost(string, tag = "2")]
+        pub service_name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClusterLoadAssignment {
+        #[prost(string, tag = "1")]
+        pub cluster_name: String,
+        #[prost(message, repeated, tag = "2")]
+        pub endpoints: Vec<LocalityLbEndpoints>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocalityLbEndpoints {
+        #[prost(message, optional, tag = "1")]
+        pub locality: Option<Locality>,
+        #[prost(message, repeated, tag = "2")]
+        pub lb_endpoints: Vec<LbEndpoint>,
+        #[prost(uint32, tag = "5")]
+        pub priority: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Locality {
+        #[prost(string, tag = "1")]
+        pub region: String,
+        #[prost(string, tag = "2")]
+        pub zone: String,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum HealthStatus {
+        Unknown = 0,
+        Healthy = 1,
+        Unhealthy = 2,
+        Draining = 3,
+        Timeout = 4,
+        Degraded = 5,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LbEndpoint {
+        #[prost(message, optional, tag = "1")]
+        pub endpoint: Option<Endpoint>,
+        #[prost(enumeration = "HealthStatus", tag = "2")]
+        pub health_status: i32,
+        /// `google.protobuf.UInt32Value`.
+        #[prost(message, optional, tag = "4")]
+        pub load_balancing_weight: Option<UInt32Value>,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct UInt32Value {
+        #[prost(uint32, tag = "1")]
+        pub value: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Endpoint {
+        #[prost(message, optional, tag = "1")]
+        pub address: Option<Address>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Address {
+        #[prost(message, optional, tag = "1")]
+        pub socket_address: Option<SocketAddress>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SocketAddress {
+        #[prost(string, tag = "2")]
+        pub address: String,
+        #[prost(uint32, tag = "3")]
+        pub port_value: u32,
+    }
+
+    impl From<Cluster> for super::Cluster {
+        fn from(cluster: Cluster) -> Self {
+            let discovery_type = match cluster.r#type() {
+                DiscoveryType::Static => "STATIC",
+                DiscoveryType::StrictDns => "STRICT_DNS",
+                DiscoveryType::LogicalDns => "LOGICAL_DNS",
+                DiscoveryType::Eds => "EDS",
+                DiscoveryType::OriginalDst => "ORIGINAL_DST",
+            };
+            Self {
+                name: cluster.name,
+                discovery_type: discovery_type.to_string(),
+                eds_cluster_config: cluster
+                    .eds_cluster_config
+                    .map(|eds| super::EdsClusterConfig {
+                        service_name: eds.service_name,
+                    }),
+                connect_timeout: cluster.connect_timeout.and_then(|timeout| {
+                    let nanos = u32::try_from(timeout.nanos).ok()?;
+                    Some(Duration::new(u64::try_from(timeout.seconds).ok()?, nanos))
+                }),
+                load_assignment: cluster.load_assignment.map(Into::into),
+            }
+        }
+    }
+
+    impl From<ClusterLoadAssignment> for super::ClusterLoadAssignment {
+        fn from(assignment: ClusterLoadAssignment) -> Self {
+            Self {
+                cluster_name: assignment.cluster_name,
+                endpoints: assignment
+                    .endpoints
+                    .into_iter()
+                    .map(|locality| super::LocalityLbEndpoints {
+                        lb_endpoints: locality.lb_endpoints.into_iter().map(Into::into).collect(),
+                        priority: locality.priority,
+                        locality: locality.locality.map(|l| super::Locality { zone: l.zone }),
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    impl From<LbEndpoint> for super::LbEndpoint {
+        fn from(lb: LbEndpoint) -> Self {
+            let health_status = match lb.health_status() {
+                HealthStatus::Unknown => None,
+                HealthStatus::Healthy => Some("HEALTHY"),
+                HealthStatus::Unhealthy => Some("UNHEALTHY"),
+                HealthStatus::Draining => Some("DRAINING"),
+                HealthStatus::Timeout => Some("TIMEOUT"),
+                HealthStatus::Degraded => Some("DEGRADED"),
+            };
+            let socket_address = lb
+                .endpoint
+                .and_then(|endpoint| endpoint.address?.socket_address)
+                .and_then(|socket| {
+                    Some(super::SocketAddress {
+                        address: socket.address,
+                        port_value: u16::try_from(socket.port_value).ok()?,
+                    })
+                });
+            Self {
+                endpoint: Some(super::EndpointAddress {
+                    address: super::Address { socket_address },
+                }),
+                health_status: health_status.map(str::to_string),
+                load_balancing_weight: lb.load_balancing_weight.map(|w| w.value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use tonic::{Request, Response, Status, Streaming};
+
+    use super::*;
+
+    const ASSIGNMENT: &str = r#"{
+        "version_info": "7",
+        "resources": [{
+            "@type": "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment",
+            "cluster_name": "geocode",
+            "endpoints": [
+                { "locality": { "zone": "eu-central-1a" }, "lb_endpoints": [
+                    { "endpoint": { "address": { "socket_address":
+                        { "address": "10.0.0.1", "port_value": 8080 } } },
+                      "health_status": "HEALTHY", "load_balancing_weight": 3 },
+                    { "endpoint": { "address": { "socket_address":
+                        { "address": "10.0.0.2", "port_value": 8080 } } },
+                      "health_status": "DRAINING" },
+                    { "endpoint": { "address": { "socket_address":
+                        { "address": "geocode.internal", "port_value": 8080 } } } }
+                ] },
+                { "lb_endpoints": [
+                    { "endpoint": { "address": { "socket_address":
+                        { "address": "2001:db8::1", "port_value": 8080 } } } }
+                ] },
+                { "priority": 1, "lb_endpoints": [
+                    { "endpoint": { "address": { "socket_address":
+                        { "address": "10.1.0.1", "port_value": 8080 } } } }
+                ] }
+            ]
+        }]
+    }"#;
+
+    const CLUSTERS: &str = r#"{
+        "version_info": "1",
+        "resources": [
+            { "@type": "type.googleapis.com/envoy.config.cluster.v3.Cluster",
+              "name": "geocode", "type": "EDS", "connect_timeout": "0.25s",
+              "eds_cluster_config": { "eds_config": { "ads": {} } } },
+            { "name": "tiles", "type": "EDS",
+              "eds_cluster_config": { "service_name": "tiles-v2" } },
+            { "name": "search", "lb_policy": "LEAST_REQUEST",
+              "load_assignment": { "cluster_name": "search", "endpoints": [
+                  { "lb_endpoints": [
+                      { "endpoint": { "address": { "socket_address":
+                          { "address": "10.2.0.1", "port_value": 9200 } } } }
+                  ] }
+              ] } },
+            { "name": "legacy", "type": "STRICT_DNS" }
+        ]
+    }"#;
+
+    #[test]
+    fn test_assignment_maps_to_endpoints() {
+        let response: DiscoveryResponse<ClusterLoadAssignment> =
+            serde_json::from_str(ASSIGNMENT).unwrap();
+        assert_eq!(
+            assignment_endpoints(&response.resources[0]),
+            vec![
+                Endpoint::new("10.0.0.1", 8080)
+                    .with_weight(3)
+                    .with_zone("eu-central-1a"),
+                Endpoint::new("2001:db8::1", 8080),
+            ]
+        );
+        assert!(assignment_endpoints(&ClusterLoadAssignment::default()).is_empty());
+    }
+
+    #[test]
+    fn test_clusters_map_onto_the_backend() {
+        let response: DiscoveryResponse<Cluster> = serde_json::from_str(CLUSTERS).unwrap();
+        let mapped: Vec<_> = response.resources.iter().map(map_cluster).collect();
+        assert_eq!(
+            mapped[0],
+            Ok(MappedCluster {
+                endpoints: EndpointSource::Eds("geocode".to_string()),
+                connect_timeout: Some(Duration::from_millis(250)),
+            })
+        );
+        assert_eq!(
+            mapped[1].as_ref().unwrap().endpoints,
+            EndpointSource::Eds("tiles-v2".to_string())
+        );
+        assert_eq!(
+            mapped[2],
+            Ok(MappedCluster {
+                endpoints: EndpointSource::Static(vec![Endpoint::new("10.2.0.1", 9200)]),
+                connect_timeout: None,
+            })
+        );
+        assert!(mapped[3].as_ref().unwrap_err().contains("STRICT_DNS"));
+
+        let invalid = r#"{ "name": "geocode", "connect_timeout": "soon" }"#;
+        assert!(serde_json::from_str::<Cluster>(invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clusters_and_endpoints_are_polled_with_their_versions() {
+        let versions: Arc<Mutex<Vec<String>>> = Arc::default();
+        let handler = |type_url: &'static str, current: &'static str, body: &'static str| {
+            let versions = versions.clone();
+            post(move |body_in: String| async move {
+                let request: serde_json::Value = serde_json::from_str(&body_in).unwrap();
+                assert_eq!(request["resource_names"][0], "geocode");
+                assert_eq!(request["type_url"], type_url);
+                let version = request["version_info"].as_str().unwrap().to_string();
+                versions.lock().unwrap().push(version.clone());
+                if version == current {
+                    (StatusCode::NOT_MODIFIED, String::new())
+                } else {
+                    (StatusCode::OK, body.to_string())
+                }
+            })
+        };
+        let app = axum::Router::new()
+            .route(CDS_PATH, handler(CDS_TYPE_URL, "1", CLUSTERS))
+            .route(EDS_PATH, handler(EDS_TYPE_URL, "7", ASSIGNMENT));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = XdsConfig {
+            server: format!("http://{addr}/"),
+            ..Default::default()
+        };
+        let upstreams = XdsUpstreams::new("geocode", config.clone());
+        upstreams.refresh().await.unwrap();
+        assert_eq!(upstreams.endpoints().len(), 2);
+        assert_eq!(
+            upstreams.connect_timeout(),
+            Some(Duration::from_millis(250))
+        );
+        // Unchanged: the endpoints are kept
+        upstreams.refresh().await.unwrap();
+        assert_eq!(upstreams.endpoints().len(), 2);
+        assert_eq!(*versions.lock().unwrap(), vec!["", "", "1", "7"]);
+
+        let missing = XdsUpstreams::new("maps", config);
+        assert!(missing.refresh().await.is_err());
+        assert!(missing.endpoints().is_empty());
+    }
+
+    fn any(type_url: &str, message: &impl prost::Message) -> proto::Any {
+        proto::Any {
+            type_url: type_url.to_string(),
+            value: message.encode_to_vec(),
+        }
+    }
+
+    fn cluster_response(
+        version: &str,
+        discovery_type: proto::DiscoveryType,
+    ) -> proto::DiscoveryResponse {
+        let cluster = proto::Cluster {
+            name: "geocode".to_string(),
+            r#type: discovery_type as i32,
+            eds_cluster_config: Some(proto::EdsClusterConfig {
+                service_name: "geocode-v2".to_string(),
+            }),
+            connect_timeout: Some(proto::ProtoDuration {
+                seconds: 1,
+                nanos: 500_000_000,
+            }),
+            load_assignment: None,
+        };
+        proto::DiscoveryResponse {
+            version_info: version.to_string(),
+            resources: vec![any(CDS_TYPE_URL, &cluster)],
+            type_url: CDS_TYPE_URL.to_string(),
+            nonce: format!("c{version}"),
+        }
+    }
+
+    fn endpoints_response(version: &str, ips: &[&str]) -> proto::DiscoveryResponse {
+        let lb_endpoints = ips
+            .iter()
+            .map(|ip| proto::LbEndpoint {
+                endpoint: Some(proto::Endpoint {
+                    address: Some(proto::Address {
+                        socket_address: Some(proto::SocketAddress {
+                            address: ip.to_string(),
+                            port_value: 8080,
+                        }),
+                    }),
+                }),
+                health_status: proto::HealthStatus::Healthy as i32,
+                load_balancing_weight: Some(proto::UInt32Value { value: 2 }),
+            })
+            .collect();
+        let assignment = proto::ClusterLoadAssignment {
+            cluster_name: "geocode-v2".to_string(),
+            endpoints: vec![proto::LocalityLbEndpoints {
+                locality: Some(proto::Locality {
+                    region: "eu-central-1".to_string(),
+                    zone: "eu-central-1a".to_string(),
+                }),
+                lb_endpoints,
+                priority: 0,
+            }],
+        };
+        proto::DiscoveryResponse {
+            version_info: version.to_string(),
+            resources: vec![any(EDS_TYPE_URL, &assignment)],
+            type_url: EDS_TYPE_URL.to_string(),
+            nonce: format!("e{version}"),
+        }
+    }
+
+    /// An ADS server that sends the cluster, its endpoints, an update of the endpoints and
+    /// then a cluster the LB cannot map, each after the request before was ACKed.
+    #[derive(Default)]
+    struct ScriptedAds {
+        requests: Arc<Mutex<Vec<proto::DiscoveryRequest>>>,
+    }
+
+    #[async_trait]
+    impl AggregatedDiscoveryService for ScriptedAds {
+        type StreamAggregatedResourcesStream =
+            UnboundedReceiverStream<Result<proto::DiscoveryResponse, Status>>;
+
+        async fn stream_aggregated_resources(
+            &self,
+            request: Request<Streaming<proto::DiscoveryRequest>>,
+        ) -> Result<Response<Self::StreamAggregatedResourcesStream>, Status> {
+            let mut incoming = request.into_inner();
+            let requests = self.requests.clone();
+            let (responses, outgoing) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Ok(Some(request)) = incoming.message().await {
+                    let response = match request.response_nonce.as_str() {
+                        "" if request.type_url == CDS_TYPE_URL => {
+                            Some(cluster_response("1", proto::DiscoveryType::Eds))
+                        }
+                        "" => Some(endpoints_response("7", &["10.0.0.1", "10.0.0.2"])),
+                        "e7" => Some(endpoints_response("8", &["10.0.0.3"])),
+                        "e8" => Some(cluster_response("2", proto::DiscoveryType::StrictDns)),
+                        _ => None,
+                    };
+                    requests.lock().unwrap().push(request);
+                    if let Some(response) = response {
+                        let _ = responses.send(Ok(response));
+                    }
+                }
+            });
+            Ok(Response::new(UnboundedReceiverStream::new(outgoing)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clusters_and_endpoints_are_streamed_over_ads() {
+        let ads = ScriptedAds::default();
+        let requests = ads.requests.clone();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(AggregatedDiscoveryServiceServer::new(ads))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        let upstreams = XdsUpstreams::new(
+            "geocode",
+            XdsConfig {
+                server: format!("http://{addr}"),
+                api_type: XdsApiType::Grpc,
+                ..Default::default()
+            },
+        );
+        upstreams.refresh().await.unwrap();
+        let mut error = None;
+        for _ in 0..100 {
+            if upstreams.endpoints().first().map(|e| e.addr.as_str()) == Some("10.0.0.3:8080") {
+                error = upstreams.refresh().await.err();
+                if error.is_some() {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(error.unwrap().contains("STRICT_DNS"));
+        // The NACKed cluster leaves the LB with the last one it applied
+        assert_eq!(
+            *upstreams.endpoints(),
+            vec![
+                Endpoint::new("10.0.0.3", 8080)
+                    .with_weight(2)
+                    .with_zone("eu-central-1a")
+            ]
+        );
+        assert_eq!(
+            upstreams.connect_timeout(),
+            Some(Duration::from_millis(1500))
+        );
+
+        let requests = requests.lock().unwrap();
+        let summary: Vec<_> = requests
+            .iter()
+            .map(|r| {
+                (
+                    r.type_url.as_str(),
+                    r.resource_names.join(","),
+                    r.version_info.as_str(),
+                    r.response_nonce.as_str(),
+                    r.error_detail.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (CDS_TYPE_URL, "geocode".to_string(), "", "", false),
+                (CDS_TYPE_URL, "geocode".to_string(), "1", "c1", false),
+                (EDS_TYPE_URL, "geocode-v2".to_string(), "", "", false),
+                (EDS_TYPE_URL, "geocode-v2".to_string(), "7", "e7", false),
+                (EDS_TYPE_URL, "geocode-v2".to_string(), "8", "e8", false),
+                (CDS_TYPE_URL, "geocode".to_string(), "1", "c2", true),
+            ]
+        );
+        assert_eq!(requests[0].node.as_ref().unwrap().id, "lb");
+    }
+}
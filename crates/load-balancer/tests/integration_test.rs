@@ -1,20 +1,25 @@
-#![cfg(unix)]
-
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-use async_trait::async_trait;
 use axum::{Router, extract::Query, http::StatusCode, routing::get};
-use load_balancer::accounts::hash_api_key;
-use load_balancer::lb::API_KEY_HEADER;
+use load_balancer::accounts::{API_KEY_PREFIX, AsyncRatelimit, Limit, LimitSource, hash_api_key};
+use load_balancer::fallback::{FALLBACK_REQUESTS_COUNTER, FALLBACK_RESPONSES_COUNTER};
+use load_balancer::hooks::{HOOK_RESPONSES_COUNTER, HookAction, HookContext, RequestHook};
+use load_balancer::integrity::TRUNCATED_RESPONSES_COUNTER;
+use load_balancer::lb::{
+    ANONYMOUS_KEY, API_KEY_HEADER, CACHE_HEADER, INTERNAL_KEY, LIMIT_SOURCE_HEADER,
+    MAINTENANCE_RESPONSES_COUNTER, MISSING_API_KEY, NODE_HEADER, RESPONSE_SIZE_HISTOGRAM,
+    ROUTE_MISSES_COUNTER, ROUTED_REQUESTS_COUNTER, SERVICE_HEADER, STATIC_CACHE_HITS_COUNTER,
+    UPSTREAM_HEADER, UPSTREAM_RETRIES_COUNTER, UPSTREAM_TIMEOUTS_COUNTER,
+};
 use load_balancer::metric::Metrics;
-use pingora::server::{RunArgs, ShutdownSignal, ShutdownSignalWatch};
+use load_balancer::openapi::REJECTIONS_COUNTER;
 use reqwest::Client;
 use serde::Deserialize;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, oneshot};
+use tokio::sync::oneshot;
 use tokio::time::sleep;
 
 #[derive(Deserialize)]
@@ -35,10 +40,18 @@ async fn upstream_handler(Query(params): Query<UpstreamParams>) -> (StatusCode,
 }
 
 async fn spawn_upstream_server() -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
-    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    spawn_upstream_server_on("127.0.0.1:0").await
+}
+
+async fn spawn_upstream_server_on(
+    bind: &str,
+) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+    let listener = TcpListener::bind(bind).await.unwrap();
     let addr = listener.local_addr().unwrap();
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    let app = Router::new().route("/", get(upstream_handler));
+    let app = Router::new()
+        .route("/", get(upstream_handler))
+        .route("/status", get(upstream_handler));
     let server = axum::serve(listener, app).with_graceful_shutdown(async {
         let _ = shutdown_rx.await;
     });
@@ -56,99 +69,29 @@ fn reserve_port() -> u16 {
         .port()
 }
 
-struct ChannelShutdown {
-    rx: Mutex<Option<oneshot::Receiver<()>>>,
-}
-
-#[async_trait]
-impl ShutdownSignalWatch for ChannelShutdown {
-    async fn recv(&self) -> ShutdownSignal {
-        if let Some(rx) = self.rx.lock().await.take() {
-            let _ = rx.await;
-        }
-        ShutdownSignal::FastShutdown
-    }
-}
-
-use load_balancer::configuration::ServerConfig;
+use load_balancer::configuration::{
+    AdminConfig, DebugHeadersConfig, DiagnosticHeadersConfig, DiagnosticVerbosity,
+    InternalListenerConfig, ListenerConfig, RuntimeConfig, ServerConfig, TenantConfig,
+};
 use load_balancer::server::Server;
+use load_balancer::shadow::{SHADOW_HEADER, SHADOW_REQUESTS_COUNTER};
+use load_balancer::shedding::SHED_REQUESTS_COUNTER;
+use load_balancer::shutdown::Shutdown;
+use load_balancer::sqlite;
 use rusqlite::Connection;
 
 /// Create a test accounts database with a plan that allows 5 requests per second.
 fn create_test_accounts_db(api_key: &str) -> tempfile::NamedTempFile {
     let file = tempfile::NamedTempFile::new().unwrap();
-    let conn = Connection::open(file.path()).unwrap();
+    let conn = sqlite::open_wal(file.path()).unwrap();
 
     let api_key_hash = hash_api_key(api_key);
 
+    conn.execute_batch(include_str!("../sql/accounts.sql"))
+        .unwrap();
     conn.execute_batch(&format!(
         r#"
-        CREATE TABLE Plans (
-            plan_id INTEGER PRIMARY KEY AUTOINCREMENT,
-            name TEXT NOT NULL,
-            monthly_quota INTEGER NOT NULL,
-            rps_limit INTEGER NOT NULL,
-            price_per_1k_req REAL NOT NULL,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        );
-        CREATE TABLE Accounts (
-            account_id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT UNIQUE NOT NULL,
-            plan_id INTEGER NOT NULL,
-            billing_status TEXT NOT NULL,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (plan_id) REFERENCES Plans(plan_id)
-        );
-        CREATE TABLE APIKeys (
-            api_key_id INTEGER PRIMARY KEY AUTOINCREMENT,
-            api_key CHAR(36) UNIQUE NOT NULL,
-            account_id INTEGER NOT NULL,
-            api_key_hash TEXT UNIQUE NOT NULL,
-            is_active BOOLEAN NOT NULL DEFAULT 1,
-            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            FOREIGN KEY (account_id) REFERENCES Accounts(account_id)
-        );
-        CREATE TABLE ChangeLog (
-            change_id INTEGER PRIMARY KEY AUTOINCREMENT,
-            table_name TEXT NOT NULL,
-            record_id INTEGER NOT NULL,
-            operation TEXT NOT NULL,
-            occurred_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        );
-
-        -- Plans triggers
-        CREATE TRIGGER trg_plans_insert AFTER INSERT ON Plans BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Plans', NEW.plan_id, 'INSERT');
-        END;
-        CREATE TRIGGER trg_plans_update AFTER UPDATE ON Plans BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Plans', NEW.plan_id, 'UPDATE');
-        END;
-        CREATE TRIGGER trg_plans_delete AFTER DELETE ON Plans BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Plans', OLD.plan_id, 'DELETE');
-        END;
-
-        -- Accounts triggers
-        CREATE TRIGGER trg_accounts_insert AFTER INSERT ON Accounts BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Accounts', NEW.account_id, 'INSERT');
-        END;
-        CREATE TRIGGER trg_accounts_update AFTER UPDATE ON Accounts BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Accounts', NEW.account_id, 'UPDATE');
-        END;
-        CREATE TRIGGER trg_accounts_delete AFTER DELETE ON Accounts BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('Accounts', OLD.account_id, 'DELETE');
-        END;
-
-        -- APIKeys triggers
-        CREATE TRIGGER trg_apikeys_insert AFTER INSERT ON APIKeys BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', NEW.api_key_id, 'INSERT');
-        END;
-        CREATE TRIGGER trg_apikeys_update AFTER UPDATE ON APIKeys BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', NEW.api_key_id, 'UPDATE');
-        END;
-        CREATE TRIGGER trg_apikeys_delete AFTER DELETE ON APIKeys BEGIN
-            INSERT INTO ChangeLog (table_name, record_id, operation) VALUES ('APIKeys', OLD.api_key_id, 'DELETE');
-        END;
-
+        BEGIN;
         INSERT INTO Plans (name, monthly_quota, rps_limit, price_per_1k_req)
         VALUES ('Test', 1000, 5, 0.0);
 
@@ -157,6 +100,7 @@ fn create_test_accounts_db(api_key: &str) -> tempfile::NamedTempFile {
 
         INSERT INTO APIKeys (api_key, account_id, api_key_hash, is_active)
         VALUES ('00000000-0000-0000-0000-000000000001', 1, '{}', 1);
+        COMMIT;
         "#,
         api_key_hash
     ))
@@ -175,12 +119,13 @@ fn spawn_load_balancer(
     let handle = thread::spawn(move || {
         let listen_addr = format!("127.0.0.1:{listen_port}");
 
-        let mut server = Server::new(None).expect("create server");
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
 
         let server_conf = ServerConfig {
             backend: config_path.clone(),
             accounts_db: accounts_db_path,
             usage_dir: None,
+            ..Default::default()
         };
 
         server
@@ -192,13 +137,7 @@ fn spawn_load_balancer(
             )
             .expect("bootstrap server");
 
-        let run_args = RunArgs {
-            shutdown_signal: Box::new(ChannelShutdown {
-                rx: Mutex::new(Some(shutdown_rx)),
-            }),
-        };
-
-        server.run(run_args);
+        server.run(Shutdown::on(shutdown_rx));
     });
 
     (shutdown_tx, handle)
@@ -215,6 +154,24 @@ async fn wait_for_port(port: u16) {
     panic!("port {addr} did not open in time");
 }
 
+/// Status counts of `key` once at least `total` requests are recorded; records reach the
+/// metrics through the aggregator, shortly after the responses.
+async fn recorded_status_counts(
+    metrics: &Metrics,
+    key: &str,
+    total: u64,
+) -> std::collections::HashMap<u16, u64> {
+    let mut counts = flatten_status_counts(metrics.snapshot(key));
+    for _ in 0..20 {
+        if counts.values().sum::<u64>() >= total {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+        counts = flatten_status_counts(metrics.snapshot(key));
+    }
+    counts
+}
+
 fn flatten_status_counts(
     snapshot: std::collections::HashMap<u64, std::collections::HashMap<u16, u64>>,
 ) -> std::collections::HashMap<u16, u64> {
@@ -273,31 +230,51 @@ backends:
     let client = Client::new();
     let url = format!("http://127.0.0.1:{lb_port}/?status=200&latency_ms=5");
 
-    for _ in 0..5 {
-        let resp = client
-            .get(&url)
-            .header(API_KEY_HEADER, api_key)
-            .send()
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), StatusCode::OK);
+    // Sent at once so the burst cannot straddle a rate window reset
+    let mut requests = tokio::task::JoinSet::new();
+    for _ in 0..6 {
+        let request = client.get(&url).header(API_KEY_HEADER, api_key).send();
+        requests.spawn(async move { request.await.unwrap().status() });
     }
+    let mut statuses = requests.join_all().await;
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        [
+            [StatusCode::OK; 5].as_slice(),
+            &[StatusCode::TOO_MANY_REQUESTS]
+        ]
+        .concat()
+    );
 
-    let limited = client
-        .get(&url)
-        .header(API_KEY_HEADER, api_key)
-        .send()
-        .await
-        .unwrap();
-    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
-
-    let counts = flatten_status_counts(metrics.snapshot(api_key));
+    // Metrics are keyed by the key's fingerprint, never the raw key
+    assert!(metrics.snapshot(api_key).is_empty());
+    let fingerprint = api_key::fingerprint(
+        API_KEY_PREFIX,
+        "00000000-0000-0000-0000-000000000001".parse().unwrap(),
+    );
+    let counts = recorded_status_counts(&metrics, &fingerprint, 6).await;
     assert_eq!(counts.get(&StatusCode::OK.as_u16()), Some(&5));
     assert_eq!(
         counts.get(&StatusCode::TOO_MANY_REQUESTS.as_u16()),
         Some(&1)
     );
 
+    // Every routed request adds its response size, the rate limited one included; sizes
+    // are recorded once the request is logged, which may follow its response
+    let mut sizes = 0;
+    for _ in 0..20 {
+        sizes = metrics
+            .histogram(RESPONSE_SIZE_HISTOGRAM)
+            .get("root")
+            .map_or(0, |sizes| sizes.count);
+        if sizes >= 6 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(sizes, 6);
+
     let _ = lb_shutdown.send(());
     let _ = lb_handle.join();
 
@@ -318,12 +295,13 @@ fn spawn_load_balancer_with_usage(
     let handle = thread::spawn(move || {
         let listen_addr = format!("127.0.0.1:{listen_port}");
 
-        let mut server = Server::new(None).expect("create server");
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
 
         let server_conf = ServerConfig {
             backend: config_path.clone(),
             accounts_db: accounts_db_path,
             usage_dir: Some(usage_dir),
+            ..Default::default()
         };
 
         server
@@ -335,13 +313,7 @@ fn spawn_load_balancer_with_usage(
             )
             .expect("bootstrap server");
 
-        let run_args = RunArgs {
-            shutdown_signal: Box::new(ChannelShutdown {
-                rx: Mutex::new(Some(shutdown_rx)),
-            }),
-        };
-
-        server.run(run_args);
+        server.run(Shutdown::on(shutdown_rx));
     });
 
     (shutdown_tx, handle)
@@ -453,6 +425,2685 @@ backends:
         total_requests
     );
 
+    // Usage is attributed to the matched service and its backend variant
+    let (service, backend): (String, String) = conn
+        .query_row("SELECT DISTINCT service, backend FROM Usage", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .unwrap();
+    assert_eq!((service.as_str(), backend.as_str()), ("root", "basic"));
+
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_disconnect_is_recorded_as_aborted() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "abort-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    // The client gives up long before the upstream answers
+    let client = Client::builder()
+        .timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let url = format!("http://127.0.0.1:{lb_port}/?status=200&latency_ms=1000");
+    let result = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await;
+    assert!(result.is_err());
+
+    let mut aborted = 0;
+    for _ in 0..20 {
+        aborted = metrics
+            .labeled_counter("requests_aborted")
+            .get("root")
+            .copied()
+            .unwrap_or(0);
+        if aborted > 0 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(aborted, 1);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
     let _ = up_shutdown.send(());
     up_handle.await.unwrap();
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_abort_drops_upstream_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Upstream that never answers and reports when its connection is closed
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let up_addr = listener.local_addr().unwrap();
+    let (closed_tx, closed_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+        let _ = closed_tx.send(());
+    });
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "abort-upstream-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+    let request =
+        format!("GET / HTTP/1.1\r\nHost: localhost\r\n{API_KEY_HEADER}: {api_key}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    drop(stream);
+
+    // The upstream request is cancelled instead of waiting for the upstream's answer
+    tokio::time::timeout(Duration::from_secs(5), closed_rx)
+        .await
+        .expect("upstream connection kept open after the client aborted")
+        .unwrap();
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    assert_eq!(metrics.labeled_counter("requests_aborted")["root"], 1);
+}
+
+/// Upstream streaming every response as `chunks` chunked chunks of 1000 bytes, `gap` apart.
+async fn spawn_streaming_upstream(chunks: usize, gap: Duration) -> SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(n) if n > 0 => head.extend_from_slice(&buf[..n]),
+                        _ => return,
+                    }
+                }
+                let header = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+                if stream.write_all(header.as_bytes()).await.is_err() {
+                    return;
+                }
+                for _ in 0..chunks {
+                    let chunk = format!("3e8\r\n{}\r\n", "x".repeat(1000));
+                    if stream.write_all(chunk.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    sleep(gap).await;
+                }
+                let _ = stream.write_all(b"0\r\n\r\n").await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streamed_responses_are_counted_as_they_pass() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let up_addr = spawn_streaming_upstream(10, Duration::from_millis(50)).await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "streaming-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  stream: /
+backends:
+  - service: stream
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let body = Client::new()
+        .get(format!("http://127.0.0.1:{lb_port}/"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(body.len(), 10_000);
+
+    // The client hangs up after the first bytes of the body
+    let mut stream = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+    let request =
+        format!("GET / HTTP/1.1\r\nHost: localhost\r\n{API_KEY_HEADER}: {api_key}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !received.windows(4).any(|w| w == b"xxxx") {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0);
+        received.extend_from_slice(&buf[..n]);
+    }
+    drop(stream);
+
+    let mut aborted = 0;
+    for _ in 0..40 {
+        aborted = metrics
+            .labeled_counter("requests_aborted")
+            .get("stream")
+            .copied()
+            .unwrap_or(0);
+        if aborted > 0 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(aborted, 1);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    // The whole first body and part of the second
+    let sizes = &metrics.histogram(RESPONSE_SIZE_HISTOGRAM)["stream"];
+    assert_eq!(sizes.count, 2);
+    assert!(
+        sizes.sum > 10_000 && sizes.sum < 20_000,
+        "counted {} bytes",
+        sizes.sum
+    );
+}
+
+/// Raw upstream declaring a 100 byte body and closing after 10, or declaring an invalid
+/// length for paths ending in `/invalid`.
+async fn spawn_truncating_upstream() -> SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(n) if n > 0 => head.extend_from_slice(&buf[..n]),
+                        _ => return,
+                    }
+                }
+                let request_line = String::from_utf8_lossy(&head);
+                let response = if request_line.contains("/invalid ") {
+                    "HTTP/1.1 200 OK\r\nContent-Length: ten\r\n\r\n0123456789"
+                } else {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n0123456789"
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn truncated_responses_are_counted_and_optionally_withheld() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let up_addr = spawn_truncating_upstream().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "truncation-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  loose: /loose
+  strict:
+    path: /strict
+    reject_truncated: true
+backends:
+  - service: loose
+    backend: {{ type: basic, ip: "{ip}", port: {port} }}
+  - service: strict
+    backend: {{ type: basic, ip: "{ip}", port: {port} }}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    // Everything the LB sends for `path` until it closes the connection
+    let raw_get = |path: &'static str| async move {
+        let mut stream = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\n{API_KEY_HEADER}: {api_key}\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut received = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .unwrap();
+        String::from_utf8_lossy(&received).into_owned()
+    };
+
+    // Passed on as it arrives by default
+    let loose = raw_get("/loose/report").await;
+    assert!(loose.starts_with("HTTP/1.1 200"), "{loose}");
+    assert!(loose.ends_with("0123456789"), "{loose}");
+
+    // Held back, the client seeing none of the partial body
+    let strict = raw_get("/strict/report").await;
+    assert!(strict.starts_with("HTTP/1.1 200"), "{strict}");
+    assert!(!strict.contains("0123456789"), "{strict}");
+
+    // A response with an invalid length is answered with a 502
+    let invalid = raw_get("/strict/invalid").await;
+    assert!(invalid.starts_with("HTTP/1.1 502"), "{invalid}");
+
+    let label = |service: &str| format!("service={service},upstream={up_addr}");
+    let mut counted = (0, 0);
+    for _ in 0..40 {
+        let truncated = metrics.labeled_counter(TRUNCATED_RESPONSES_COUNTER);
+        counted = (
+            truncated.get(&label("loose")).copied().unwrap_or(0),
+            truncated.get(&label("strict")).copied().unwrap_or(0),
+        );
+        if counted == (1, 2) {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(counted, (1, 2));
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shadow_backends_get_a_copy_of_each_request() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+    // Records the path, shadow header and body of every request it gets
+    let seen: Arc<std::sync::Mutex<Vec<(String, String, String)>>> = Arc::default();
+    let shadow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let shadow_addr = shadow.local_addr().unwrap();
+    let recorded = seen.clone();
+    let app = Router::new().fallback(
+        move |uri: axum::http::Uri, headers: axum::http::HeaderMap, body: String| {
+            let recorded = recorded.clone();
+            async move {
+                let marker = headers
+                    .get(SHADOW_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((uri.to_string(), marker, body));
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        },
+    );
+    let shadow_handle = tokio::spawn(async move {
+        let _ = axum::serve(shadow, app).await;
+    });
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "shadow-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+  - service: root
+    shadow: true
+    backend:
+      type: static
+      endpoints: ["{shadow_addr}"]
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    // Clients only ever see the primary backend's responses
+    let client = Client::new();
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/?status=200"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await.unwrap(), "status 200");
+    let resp = client
+        .post(format!("http://127.0.0.1:{lb_port}/?status=201"))
+        .header(API_KEY_HEADER, api_key)
+        .body("payload")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    for _ in 0..40 {
+        if seen.lock().unwrap().len() >= 2 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    let mut copies = seen.lock().unwrap().clone();
+    copies.sort();
+    assert_eq!(
+        copies,
+        [
+            (
+                "/?status=200".to_string(),
+                "true".to_string(),
+                String::new()
+            ),
+            (
+                "/?status=201".to_string(),
+                "true".to_string(),
+                "payload".to_string()
+            ),
+        ]
+    );
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let sent = metrics.labeled_counter(SHADOW_REQUESTS_COUNTER);
+    assert_eq!(sent.get("root"), Some(&2));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+    shadow_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn self_service_endpoints_are_answered_by_load_balancer() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "preflight-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+    let usage_dir = tempfile::TempDir::new().unwrap();
+    let usage_dir_path = usage_dir.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) = spawn_load_balancer_with_usage(
+        lb_port,
+        config_path,
+        accounts_db_path,
+        usage_dir_path,
+        metrics.clone(),
+    );
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/?status=200");
+    for _ in 0..2 {
+        let resp = client
+            .get(&url)
+            .header(API_KEY_HEADER, api_key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let status_url = format!("http://127.0.0.1:{lb_port}/v1/ratelimit");
+    let mut body = serde_json::Value::Null;
+    for _ in 0..3 {
+        let resp = client
+            .get(&status_url)
+            .header(API_KEY_HEADER, api_key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        body = resp.json().await.unwrap();
+    }
+
+    assert_eq!(body["plan"], "Test");
+    assert_eq!(body["limit"]["quota"], 5);
+    // The window may roll over between the proxied requests and the checks
+    let remaining = body["limit"]["remaining"].as_i64().unwrap();
+    assert!((3..=5).contains(&remaining), "remaining = {remaining}");
+    assert_eq!(body["monthly"]["quota"], 1000);
+    assert_eq!(body["monthly"]["used"], 2);
+    assert_eq!(body["monthly"]["remaining"], 998);
+
+    let me = client
+        .get(format!("http://127.0.0.1:{lb_port}/v1/me"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(me.status(), StatusCode::OK);
+    let me: serde_json::Value = me.json().await.unwrap();
+    assert_eq!(me["email"], "t***@example.com");
+    assert_eq!(me["plan"], "Test");
+    assert_eq!(me["key_id"], "00000000-0000-0000-0000-000000000001");
+    assert_eq!(
+        me["fingerprint"],
+        api_key::fingerprint(
+            API_KEY_PREFIX,
+            "00000000-0000-0000-0000-000000000001".parse().unwrap()
+        )
+    );
+    assert!(me["last_used_at"].is_string());
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn public_service_is_rate_limited_per_ip_without_api_key() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let accounts_db = create_test_accounts_db("public-test-key");
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+  status:
+    path: /status
+    auth: none
+    ip_rps_limit: 2
+    labels:
+      team: platform
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+
+    // Other routes still require a key
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/?status=200"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    for _ in 0..2 {
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let limited = client.get(&url).send().await.unwrap();
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let counts = recorded_status_counts(&metrics, ANONYMOUS_KEY, 3).await;
+    assert_eq!(counts.get(&StatusCode::OK.as_u16()), Some(&2));
+    assert_eq!(
+        counts.get(&StatusCode::TOO_MANY_REQUESTS.as_u16()),
+        Some(&1)
+    );
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+
+    // Routed requests are counted with the service's static labels, rejected ones included.
+    // They are counted once logged, which can trail the response.
+    let mut routed = metrics.labeled_counter(ROUTED_REQUESTS_COUNTER);
+    for _ in 0..20 {
+        if routed.values().sum::<u64>() >= 4 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+        routed = metrics.labeled_counter(ROUTED_REQUESTS_COUNTER);
+    }
+    assert_eq!(routed.get("service=status,team=platform"), Some(&3));
+    assert_eq!(routed.get("service=root"), Some(&1));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+fn spawn_load_balancer_with_conf(
+    listen_port: u16,
+    server_conf: ServerConfig,
+    metrics: Arc<Metrics>,
+) -> (oneshot::Sender<()>, thread::JoinHandle<()>) {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let handle = thread::spawn(move || {
+        let listen_addr = format!("127.0.0.1:{listen_port}");
+
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
+        server
+            .bootstrap(
+                server_conf,
+                std::path::Path::new("."),
+                &listen_addr,
+                metrics,
+            )
+            .expect("bootstrap server");
+
+        server.run(Shutdown::on(shutdown_rx));
+    });
+
+    (shutdown_tx, handle)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn internal_listener_skips_api_key_auth() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let internal_port = reserve_port();
+    let accounts_db = create_test_accounts_db("internal-test-key");
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        internal: Some(InternalListenerConfig {
+            listen: format!("127.0.0.1:{internal_port}"),
+            rps_limit: 3,
+            limits: ListenerConfig::default(),
+        }),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+    wait_for_port(internal_port).await;
+
+    let client = Client::new();
+
+    // The public listener still requires a key
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/?status=200"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // The internal listener serves the same routes without one, up to its own limit
+    let url = format!("http://127.0.0.1:{internal_port}/?status=200");
+    for _ in 0..3 {
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let limited = client.get(&url).send().await.unwrap();
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let counts = recorded_status_counts(&metrics, INTERNAL_KEY, 4).await;
+    assert_eq!(counts.get(&StatusCode::OK.as_u16()), Some(&3));
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn debug_headers_require_debug_token() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "debug-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        debug_headers: DebugHeadersConfig {
+            enabled: true,
+            token: Some("support-token".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/?status=200");
+
+    let resp = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get(SERVICE_HEADER).is_none());
+
+    let resp = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .header("x-lb-debug", "wrong-token")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get(SERVICE_HEADER).is_none());
+
+    let resp = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .header("x-lb-debug", "support-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[SERVICE_HEADER], "root");
+    assert_eq!(
+        resp.headers()[UPSTREAM_HEADER],
+        up_addr.to_string().as_str()
+    );
+    assert_eq!(resp.headers()[LIMIT_SOURCE_HEADER], "plan");
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+/// Read one HTTP/1.1 response with a Content-Length body, returning its header block.
+async fn read_response(stream: &mut TcpStream) -> String {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before the response completed");
+        buf.extend_from_slice(&chunk[..n]);
+        let text = String::from_utf8_lossy(&buf).to_string();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let head = text[..end].to_ascii_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |v| v.trim().parse().unwrap());
+            if buf.len() >= end + 4 + length {
+                return head;
+            }
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn downstream_connections_close_after_max_requests() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "keepalive-test-key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        listener: ListenerConfig {
+            keepalive_timeout_secs: Some(30),
+            max_requests_per_connection: Some(2),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+    let request = format!(
+        "GET /?status=200 HTTP/1.1\r\nHost: localhost\r\n{API_KEY_HEADER}: {api_key}\r\n\r\n"
+    );
+    for _ in 0..2 {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let head = read_response(&mut stream).await;
+        assert!(head.starts_with("http/1.1 200"), "{head}");
+    }
+
+    // The second request was the last one the connection serves
+    let mut rest = [0u8; 16];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut rest))
+        .await
+        .expect("connection left open")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn internal_listener_enforces_its_own_limits() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let internal_port = reserve_port();
+    let accounts_db = create_test_accounts_db("internal-test-key");
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        internal: Some(InternalListenerConfig {
+            // Per-IP counters are shared by the tests; IPv6 clients keep these apart
+            listen: format!("[::]:{internal_port}"),
+            rps_limit: 10,
+            limits: ListenerConfig {
+                max_uri_length: Some(32),
+                max_requests_per_connection: Some(1),
+                ..Default::default()
+            },
+        }),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+    wait_for_port(internal_port).await;
+
+    let mut stream = TcpStream::connect(("::1", internal_port)).await.unwrap();
+    stream
+        .write_all(b"GET /?status=200 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_response(&mut stream).await;
+    assert!(head.starts_with("http/1.1 200"), "{head}");
+
+    // The connection served its one request
+    let mut rest = [0u8; 16];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut rest))
+        .await
+        .expect("connection left open")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+
+    let long = format!(
+        "http://[::1]:{internal_port}/?status=200&pad={}",
+        "x".repeat(64)
+    );
+    let resp = Client::new().get(long).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::URI_TOO_LONG);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connections_are_limited_from_accept_to_close() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "connection-limit-key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{}"
+      port: {}
+"#,
+        up_addr.ip(),
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        listener: ListenerConfig {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+    // wait_for_port's own connection is released once closed
+    sleep(Duration::from_millis(200)).await;
+
+    // A connection that never sends a request header holds the client's only slot
+    let idle = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    let mut refused = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+    let mut rest = [0u8; 16];
+    let n = tokio::time::timeout(Duration::from_secs(5), refused.read(&mut rest))
+        .await
+        .expect("connection over the limit left open")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+
+    // Closing it frees the slot right away
+    drop(idle);
+    sleep(Duration::from_millis(200)).await;
+    let mut stream = TcpStream::connect(("127.0.0.1", lb_port)).await.unwrap();
+    let request = format!(
+        "GET /?status=200 HTTP/1.1\r\nHost: localhost\r\n{API_KEY_HEADER}: {api_key}\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let head = read_response(&mut stream).await;
+    assert!(head.starts_with("http/1.1 200"), "{head}");
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    assert_eq!(metrics.counter("connections_rejected_per_ip"), 1);
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unmatched_paths_get_configured_404_without_auth() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let accounts_db = create_test_accounts_db("route-miss-key");
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: /status
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+route_miss:
+  body: "nothing here"
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    for path in ["/wp-admin/login.php", "/wp-admin/setup.php", "/"] {
+        let resp = client
+            .get(format!("http://127.0.0.1:{lb_port}{path}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.text().await.unwrap(), "nothing here");
+    }
+
+    let misses = metrics.labeled_counter(ROUTE_MISSES_COUNTER);
+    assert_eq!(misses.get("/wp-admin"), Some(&2));
+    assert_eq!(misses.get("/"), Some(&1));
+    // Misses are answered before authentication
+    assert!(metrics.snapshot(MISSING_API_KEY).is_empty());
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn services_in_maintenance_are_answered_until_reloaded() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "maintenance-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let config_content = |maintenance: bool| {
+        format!(
+            r#"
+services:
+  status:
+    path: /status
+    maintenance: {maintenance}
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+maintenance:
+  retry_after_secs: 120
+  body: "back soon"
+"#,
+            ip = up_addr.ip(),
+            port = up_addr.port()
+        )
+    };
+    let config_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(config_file.path(), config_content(true)).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    // Answered before authentication
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "120");
+    assert_eq!(resp.text().await.unwrap(), "back soon");
+    // Counted once the recorder has caught up
+    let mut answered = None;
+    for _ in 0..40 {
+        answered = metrics
+            .labeled_counter(MAINTENANCE_RESPONSES_COUNTER)
+            .get("status")
+            .copied();
+        if answered.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(answered, Some(1));
+
+    // Lifted by a reload of the config, without a restart
+    std::fs::write(config_file.path(), config_content(false)).unwrap();
+    let mut status = StatusCode::SERVICE_UNAVAILABLE;
+    for _ in 0..60 {
+        status = client
+            .get(&url)
+            .header(API_KEY_HEADER, api_key)
+            .send()
+            .await
+            .unwrap()
+            .status();
+        if status == StatusCode::OK {
+            break;
+        }
+        sleep(Duration::from_millis(250)).await;
+    }
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tenant_keys_are_limited_to_allowed_services() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "partner_test_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+  status: /status
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        tenants: vec![TenantConfig {
+            name: "partner".to_string(),
+            token_prefix: "partner".to_string(),
+            accounts_db: None,
+            default_rps_limit: None,
+            allowed_services: vec!["status".to_string()],
+        }],
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/status?status=200"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/?status=200"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tagged_requests_select_service_and_rate_limit() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "tagging_test_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+  canary: /canary
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+  - service: canary
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+tagging:
+  rules:
+    - when: header.x-canary == "1"
+      tag: canary
+    - when: path ^= /status && query.batch
+      tag: batch
+  rps_limits: {{ batch: 1 }}
+  services: {{ canary: canary }}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/?status=200"))
+        .header(API_KEY_HEADER, api_key)
+        .header("x-canary", "1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The batch tag allows one request per second on top of the key's own limit, sent at
+    // once so the pair cannot straddle a rate window reset
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200&batch=1");
+    let mut requests = tokio::task::JoinSet::new();
+    for _ in 0..2 {
+        let request = client.get(&url).header(API_KEY_HEADER, api_key).send();
+        requests.spawn(async move { request.await.unwrap().status() });
+    }
+    let mut statuses = requests.join_all().await;
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let routed = metrics.labeled_counter(ROUTED_REQUESTS_COUNTER);
+    assert_eq!(routed.get("service=canary"), Some(&1));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn static_cache_serves_repeated_requests_at_the_edge() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "static_cache_key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  docs:
+    path: /status
+    static_cache:
+      ttl_secs: 3600
+backends:
+  - service: docs
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    let first = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(first.text().await.unwrap(), "status 200");
+
+    let cached = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cached.status(), StatusCode::OK);
+    let etag = cached.headers()["etag"].to_str().unwrap().to_string();
+    assert_eq!(cached.text().await.unwrap(), "status 200");
+
+    let revalidated = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .header("If-None-Match", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(revalidated.headers()["etag"], etag.as_str());
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let hits = metrics.labeled_counter(STATIC_CACHE_HITS_COUNTER);
+    assert_eq!(hits.get("docs"), Some(&2));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn diagnostic_headers_hide_topology_when_minimal() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "diagnostic_headers_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  docs:
+    path: /status
+    static_cache:
+      ttl_secs: 3600
+backends:
+  - service: docs
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        diagnostic_headers: DiagnosticHeadersConfig {
+            verbosity: DiagnosticVerbosity::Minimal,
+        },
+        node_id: Some("lb-1".to_string()),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    let first = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(first.headers()[CACHE_HEADER], "MISS");
+    assert_eq!(first.headers()[NODE_HEADER], "lb-1");
+    assert!(first.headers().get(UPSTREAM_HEADER).is_none());
+    assert!(first.headers().get(SERVICE_HEADER).is_none());
+
+    let cached = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cached.headers()[CACHE_HEADER], "HIT");
+    assert_eq!(cached.headers()[NODE_HEADER], "lb-1");
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn openapi_spec_rejects_undocumented_operations() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "openapi_key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    use std::io::Write;
+    let mut spec_file = tempfile::NamedTempFile::new().unwrap();
+    spec_file
+        .write_all(
+            br#"{"openapi": "3.0.3", "paths": {"/status": {"get": {"parameters": [
+                {"name": "status", "in": "query", "required": true}]}}}}"#,
+        )
+        .unwrap();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status:
+    path: /status
+    openapi: "{spec}"
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        spec = spec_file.path().display(),
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let base = format!("http://127.0.0.1:{lb_port}");
+    let resp = client
+        .get(format!("{base}/status?status=200"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client
+        .get(format!("{base}/status/v2"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = client.get(format!("{base}/status")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "missing required parameter 'status'");
+    let resp = client.post(format!("{base}/status")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let rejections = metrics.labeled_counter(REJECTIONS_COUNTER);
+    assert_eq!(rejections.get("status GET /status"), Some(&1));
+    assert_eq!(rejections.get("status GET /status/v2"), Some(&1));
+    assert_eq!(rejections.get("status POST /status"), Some(&1));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saturated_service_sheds_requests_with_retry_after() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "load_shedding_key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status:
+    path: /status
+    load_shedding:
+      max_in_flight: 1
+      retry_after_secs: 3
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let slow = tokio::spawn({
+        let client = client.clone();
+        async move {
+            client
+                .get(format!("http://127.0.0.1:{lb_port}/status?latency_ms=1000"))
+                .header(API_KEY_HEADER, api_key)
+                .send()
+                .await
+                .unwrap()
+                .status()
+        }
+    });
+    sleep(Duration::from_millis(300)).await;
+
+    // The slow request saturates the service
+    let shed = client
+        .get(format!("http://127.0.0.1:{lb_port}/status"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(shed.headers()["retry-after"], "3");
+    assert_eq!(slow.await.unwrap(), StatusCode::OK);
+
+    let served = client
+        .get(format!("http://127.0.0.1:{lb_port}/status"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(served.status(), StatusCode::OK);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let shed = metrics.labeled_counter(SHED_REQUESTS_COUNTER);
+    assert_eq!(shed.get("status"), Some(&1));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ipv6_upstream_behind_dual_stack_listener() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server_on("[::1]:0").await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let internal_port = reserve_port();
+    let accounts_db = create_test_accounts_db("ipv6-test-key");
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "[::1]"
+      port: {}
+"#,
+        up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        internal: Some(InternalListenerConfig {
+            listen: format!("[::]:{internal_port}"),
+            rps_limit: 10,
+            limits: ListenerConfig::default(),
+        }),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+    wait_for_port(internal_port).await;
+
+    // Clients of both families reach the IPv6 upstream through one listener
+    let client = Client::new();
+    for host in ["127.0.0.1", "[::1]"] {
+        let resp = client
+            .get(format!("http://{host}:{internal_port}/?status=200"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK, "{host}");
+    }
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn static_backend_alternates_between_endpoints() {
+    let (up_a, a_shutdown, a_handle) = spawn_upstream_server().await;
+    let (up_b, b_shutdown, b_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let internal_port = reserve_port();
+    let accounts_db = create_test_accounts_db("static-backend-key");
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    backend:
+      type: static
+      endpoints: ["{up_a}", "{up_b}"]
+"#
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        internal: Some(InternalListenerConfig {
+            listen: format!("127.0.0.1:{internal_port}"),
+            rps_limit: 10,
+            limits: ListenerConfig::default(),
+        }),
+        diagnostic_headers: DiagnosticHeadersConfig {
+            verbosity: DiagnosticVerbosity::Full,
+        },
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+    wait_for_port(internal_port).await;
+
+    let client = Client::new();
+    let mut upstreams = Vec::new();
+    for _ in 0..4 {
+        let resp = client
+            .get(format!("http://127.0.0.1:{internal_port}/?status=200"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        upstreams.push(
+            resp.headers()[UPSTREAM_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+    }
+    // Each endpoint takes every other request
+    assert_ne!(upstreams[0], upstreams[1]);
+    assert_eq!(upstreams[..2], upstreams[2..]);
+    assert!(upstreams.contains(&up_a.to_string()));
+    assert!(upstreams.contains(&up_b.to_string()));
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = a_shutdown.send(());
+    let _ = b_shutdown.send(());
+    a_handle.await.unwrap();
+    b_handle.await.unwrap();
+}
+
+/// Answers requests without a tenant header and names the service in responses.
+struct TenantHeaderHook;
+
+impl RequestHook for TenantHeaderHook {
+    fn name(&self) -> &str {
+        "tenant-header"
+    }
+
+    fn on_request(
+        &self,
+        _ctx: &HookContext<'_>,
+        request: &mut pingora::http::RequestHeader,
+    ) -> HookAction {
+        if request.headers.contains_key("x-tenant") {
+            return HookAction::Continue;
+        }
+        HookAction::Respond {
+            status: 400,
+            body: serde_json::json!({ "error": "x-tenant required" }),
+        }
+    }
+
+    fn on_response(&self, ctx: &HookContext<'_>, response: &mut pingora::http::ResponseHeader) {
+        let service = ctx.service.unwrap_or("-").to_string();
+        response.insert_header("x-hook-service", service).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_hooks_answer_and_annotate_requests() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "request_hooks_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: /status
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let (hooks_tx, hooks_rx) = std::sync::mpsc::channel();
+    let (lb_shutdown, shutdown_rx) = oneshot::channel();
+    let lb_metrics = metrics.clone();
+    let lb_handle = thread::spawn(move || {
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
+        hooks_tx.send(server.hooks()).unwrap();
+        server
+            .bootstrap(
+                server_conf,
+                std::path::Path::new("."),
+                &format!("127.0.0.1:{lb_port}"),
+                lb_metrics,
+            )
+            .expect("bootstrap server");
+        server.run(Shutdown::on(shutdown_rx));
+    });
+    let hooks = hooks_rx.recv().unwrap();
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    let get = |tenant: Option<&str>| {
+        let mut request = client.get(&url).header(API_KEY_HEADER, api_key);
+        if let Some(tenant) = tenant {
+            request = request.header("x-tenant", tenant);
+        }
+        request.send()
+    };
+    assert_eq!(get(None).await.unwrap().status(), StatusCode::OK);
+
+    // Installed while the proxy runs
+    hooks.replace(vec![Arc::new(TenantHeaderHook)]);
+    let answered = get(None).await.unwrap();
+    assert_eq!(answered.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        answered.text().await.unwrap(),
+        r#"{"error":"x-tenant required"}"#
+    );
+    let proxied = get(Some("acme")).await.unwrap();
+    assert_eq!(proxied.status(), StatusCode::OK);
+    assert_eq!(proxied.headers()["x-hook-service"], "status");
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let answers = metrics.labeled_counter(HOOK_RESPONSES_COUNTER);
+    assert_eq!(answers.get("tenant-header"), Some(&1));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keys_are_revoked_over_grpc() {
+    use load_balancer::grpc::{AckStatus, Change, ControlPlaneClient, PushRequest};
+
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let admin_port = reserve_port();
+    let grpc_port = reserve_port();
+    let api_key = "grpc_revoked_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: /status
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        admin: Some(AdminConfig {
+            listen: format!("127.0.0.1:{admin_port}"),
+            token: Some("secret".to_string()),
+            requests_per_minute: 0,
+            control_plane: false,
+            control_plane_grpc: Some(format!("127.0.0.1:{grpc_port}")),
+        }),
+        ..Default::default()
+    };
+    let (lb_shutdown, shutdown_rx) = oneshot::channel();
+    let lb_metrics = metrics.clone();
+    let lb_handle = thread::spawn(move || {
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
+        server
+            .bootstrap(
+                server_conf,
+                std::path::Path::new("."),
+                &format!("127.0.0.1:{lb_port}"),
+                lb_metrics,
+            )
+            .expect("bootstrap server");
+        server.run(Shutdown::on(shutdown_rx));
+    });
+    wait_for_port(lb_port).await;
+    wait_for_port(grpc_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    let get = || client.get(&url).header(API_KEY_HEADER, api_key).send();
+    assert_eq!(get().await.unwrap().status(), StatusCode::OK);
+
+    let mut control = ControlPlaneClient::connect(format!("http://127.0.0.1:{grpc_port}"))
+        .await
+        .unwrap();
+    let fingerprint = api_key::fingerprint(
+        API_KEY_PREFIX,
+        "00000000-0000-0000-0000-000000000001".parse().unwrap(),
+    );
+    let push = || {
+        let mut request = tonic::Request::new(PushRequest {
+            version: 1,
+            change: Some(Change::RevokeKey(fingerprint.clone())),
+        });
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        request
+    };
+    let unauthenticated = control
+        .push(PushRequest {
+            version: 1,
+            change: Some(Change::RevokeKey(fingerprint.clone())),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(unauthenticated.code(), tonic::Code::Unauthenticated);
+    let ack = control.push(push()).await.unwrap().into_inner();
+    assert_eq!(ack.status(), AckStatus::Applied);
+    let ack = control.push(push()).await.unwrap().into_inner();
+    assert_eq!(ack.status(), AckStatus::Duplicate);
+
+    // Limited as an unknown key from now on, to fewer requests than its plan allowed
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        statuses.push(get().await.unwrap().status());
+    }
+    assert!(
+        statuses.contains(&StatusCode::TOO_MANY_REQUESTS),
+        "{statuses:?}"
+    );
+    // The accounts DB is left to the control plane
+    let conn = Connection::open(accounts_db.path()).unwrap();
+    let active: bool = conn
+        .query_row("SELECT is_active FROM APIKeys", [], |row| row.get(0))
+        .unwrap();
+    assert!(active);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+/// A hook module answering `on_request` and `on_response` with fixed JSON; empty for no
+/// answer.
+fn hook_module(on_request: &str, on_response: &str) -> Vec<u8> {
+    let escape = |s: &str| -> String { s.bytes().map(|b| format!("\\{b:02x}")).collect() };
+    wat::parse_str(format!(
+        r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "{request}")
+            (data (i32.const 1024) "{response}")
+            (func (export "alloc") (param i32) (result i32) i32.const 4096)
+            (func (export "on_request") (param i32 i32) (result i64) i64.const {request_len})
+            (func (export "on_response") (param i32 i32) (result i64)
+                i64.const {response_packed}))"#,
+        request = escape(on_request),
+        response = escape(on_response),
+        request_len = on_request.len(),
+        response_packed = (1024u64 << 32) | on_response.len() as u64,
+    ))
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wasm_hooks_from_the_config_are_swapped_on_reload() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "wasm_hooks_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let dir = tempfile::tempdir().unwrap();
+    let module_path = dir.path().join("gate.wasm");
+    std::fs::write(
+        &module_path,
+        hook_module(
+            r#"{"respond":{"status":403,"body":{"error":"blocked"}}}"#,
+            "",
+        ),
+    )
+    .unwrap();
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: /status
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+hooks:
+  - name: gate
+    wasm: {module}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port(),
+        module = module_path.display()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let (lb_shutdown, shutdown_rx) = oneshot::channel();
+    let lb_metrics = metrics.clone();
+    let lb_handle = thread::spawn(move || {
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
+        server
+            .bootstrap(
+                server_conf,
+                std::path::Path::new("."),
+                &format!("127.0.0.1:{lb_port}"),
+                lb_metrics,
+            )
+            .expect("bootstrap server");
+        server.run(Shutdown::on(shutdown_rx));
+    });
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{lb_port}/status?status=200");
+    let answered = client
+        .get(&url)
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(answered.status(), StatusCode::FORBIDDEN);
+    assert_eq!(answered.text().await.unwrap(), r#"{"error":"blocked"}"#);
+
+    // A changed module is picked up by the next reload, without a restart
+    std::fs::write(
+        &module_path,
+        hook_module("", r#"{"set_headers":{"x-hook":"seen"}}"#),
+    )
+    .unwrap();
+    let mut proxied = None;
+    for _ in 0..60 {
+        let response = client
+            .get(&url)
+            .header(API_KEY_HEADER, api_key)
+            .send()
+            .await
+            .unwrap();
+        if response.status() == StatusCode::OK {
+            proxied = Some(response);
+            break;
+        }
+        sleep(Duration::from_millis(250)).await;
+    }
+    let proxied = proxied.expect("module reloaded");
+    assert_eq!(proxied.headers()["x-hook"], "seen");
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let answers = metrics.labeled_counter(HOOK_RESPONSES_COUNTER);
+    assert!(answers.get("gate").is_some_and(|&n| n >= 1));
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+/// Limits from a quota service, allowing one request per second to every key.
+struct RemoteQuota;
+
+#[async_trait::async_trait]
+impl AsyncRatelimit for RemoteQuota {
+    async fn limit_for_key(&self, _api_key: &str) -> Limit {
+        // The round trip to the service
+        sleep(Duration::from_millis(5)).await;
+        Limit {
+            quota: 1,
+            per_seconds: 1,
+            burst: None,
+            source: LimitSource::Override,
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn key_limits_can_come_from_an_async_source() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "remote_quota_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: /status
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let (lb_shutdown, shutdown_rx) = oneshot::channel();
+    let lb_metrics = metrics.clone();
+    let lb_handle = thread::spawn(move || {
+        let mut server = Server::new(None, &RuntimeConfig::default()).expect("create server");
+        server.set_limits(Arc::new(RemoteQuota));
+        server
+            .bootstrap(
+                server_conf,
+                std::path::Path::new("."),
+                &format!("127.0.0.1:{lb_port}"),
+                lb_metrics,
+            )
+            .expect("bootstrap server");
+        server.run(Shutdown::on(shutdown_rx));
+    });
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let get = |path: &str| {
+        client
+            .get(format!("http://127.0.0.1:{lb_port}{path}"))
+            .header(API_KEY_HEADER, api_key)
+            .send()
+    };
+    let status: serde_json::Value = get("/v1/ratelimit").await.unwrap().json().await.unwrap();
+    assert_eq!(status["limit"]["quota"], 1);
+    // The accounts DB plan allows 5 requests per second; the quota service one
+    assert_eq!(
+        get("/status?status=200").await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        get("/status?status=200").await.unwrap().status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_requests_are_retried_on_other_endpoints() {
+    let (up_good, good_shutdown, good_handle) = spawn_upstream_server().await;
+    // Answers every request with 503
+    let unavailable = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let up_unavailable = unavailable.local_addr().unwrap();
+    let app = Router::new().route("/", get(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+    let unavailable_handle = tokio::spawn(async move {
+        let _ = axum::serve(unavailable, app).await;
+    });
+    // Refuses connections
+    let up_closed = format!("127.0.0.1:{}", reserve_port());
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "retry_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+backends:
+  - service: root
+    retries: 2
+    retry_on: [connect_failure, 503]
+    backend:
+      type: static
+      endpoints: ["{up_good}", "{up_unavailable}", "{up_closed}"]
+"#
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    // Within the key's 5 requests per second
+    for _ in 0..5 {
+        let resp = client
+            .get(format!("http://127.0.0.1:{lb_port}/"))
+            .header(API_KEY_HEADER, api_key)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await.unwrap(), "status 200");
+    }
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let retries = metrics.labeled_counter(UPSTREAM_RETRIES_COUNTER);
+    assert!(retries.get("root").is_some_and(|n| *n >= 2), "{retries:?}");
+    let _ = good_shutdown.send(());
+    good_handle.await.unwrap();
+    unavailable_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_requests_fall_back_to_fallback_backends_and_responses() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+    let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let up_fallback = fallback.local_addr().unwrap();
+    let app = Router::new().route("/", get(|| async { "fallback" }));
+    let fallback_handle = tokio::spawn(async move {
+        let _ = axum::serve(fallback, app).await;
+    });
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "fallback_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  root: /
+  status:
+    path: /status
+    fallback_response:
+      status: 503
+      body: degraded
+backends:
+  - service: root
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+  - service: root
+    fallback: true
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {fallback_port}
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port(),
+        fallback_port = up_fallback.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+
+    let server_conf = ServerConfig {
+        backend: config_file.path().to_str().unwrap().to_string(),
+        accounts_db: accounts_db.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer_with_conf(lb_port, server_conf, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let get = |path: &str| {
+        client
+            .get(format!("http://127.0.0.1:{lb_port}{path}"))
+            .header(API_KEY_HEADER, api_key)
+            .send()
+    };
+    let resp = get("/?status=200").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "status 200");
+    // A 5xx of the primary backend is answered by the fallback backend
+    let resp = get("/?status=503").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await.unwrap(), "fallback");
+
+    // A service without fallback backends answers with its fallback response
+    let resp = get("/status?status=500").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.text().await.unwrap(), "degraded");
+    // Client errors pass
+    let resp = get("/status?status=404").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let requests = metrics.labeled_counter(FALLBACK_REQUESTS_COUNTER);
+    assert_eq!(requests.get("root"), Some(&1), "{requests:?}");
+    let responses = metrics.labeled_counter(FALLBACK_RESPONSES_COUNTER);
+    assert_eq!(responses.get("status"), Some(&1), "{responses:?}");
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+    fallback_handle.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backend_read_timeout_cuts_slow_upstreams_short() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "read_timeout_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: /status
+backends:
+  - service: status
+    connect_timeout_ms: 1000
+    read_timeout_ms: 200
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port()
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let get = |latency_ms: u64| {
+        client
+            .get(format!(
+                "http://127.0.0.1:{lb_port}/status?status=200&latency_ms={latency_ms}"
+            ))
+            .header(API_KEY_HEADER, api_key)
+            .send()
+    };
+    assert_eq!(get(0).await.unwrap().status(), StatusCode::OK);
+    let started = std::time::Instant::now();
+    assert_eq!(get(2000).await.unwrap().status(), StatusCode::BAD_GATEWAY);
+    assert!(started.elapsed() < Duration::from_millis(1500));
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn service_timeout_and_retry_budget_bound_requests() {
+    let (up_addr, up_shutdown, up_handle) = spawn_upstream_server().await;
+    // Refuse connections
+    let closed: Vec<String> = (0..3)
+        .map(|_| format!("127.0.0.1:{}", reserve_port()))
+        .collect();
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "service_timeout_key";
+    let accounts_db = create_test_accounts_db(api_key);
+
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  status: {{ path: /status, timeout_ms: 300 }}
+  flaky: {{ path: /flaky, retry_budget: 1 }}
+backends:
+  - service: status
+    backend:
+      type: basic
+      ip: "{ip}"
+      port: {port}
+  - service: flaky
+    retries: 3
+    retry_on: [connect_failure]
+    backend:
+      type: static
+      endpoints: ["{closed_a}", "{closed_b}", "{closed_c}"]
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port(),
+        closed_a = closed[0],
+        closed_b = closed[1],
+        closed_c = closed[2],
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let started = std::time::Instant::now();
+    let resp = client
+        .get(format!(
+            "http://127.0.0.1:{lb_port}/status?status=200&latency_ms=2000"
+        ))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(started.elapsed() < Duration::from_millis(1500));
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "service timeout exceeded");
+    assert_eq!(body["service"], "status");
+    assert_eq!(body["timeout_ms"], 300);
+
+    // One retry of the three the backend allows
+    let resp = client
+        .get(format!("http://127.0.0.1:{lb_port}/flaky"))
+        .header(API_KEY_HEADER, api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let timeouts = metrics.labeled_counter(UPSTREAM_TIMEOUTS_COUNTER);
+    assert_eq!(
+        timeouts.get(&format!("service=status,upstream={up_addr}")),
+        Some(&1),
+        "{timeouts:?}"
+    );
+    let retries = metrics.labeled_counter(UPSTREAM_RETRIES_COUNTER);
+    assert_eq!(retries.get("flaky"), Some(&1), "{retries:?}");
+    let _ = up_shutdown.send(());
+    up_handle.await.unwrap();
+}
+
+/// Upstream answering every request with 200 over TLS, with the certificate of
+/// `test_data/upstream-tls.pem` for `upstream.test`.
+async fn spawn_tls_upstream() -> SocketAddr {
+    use pingora::tls::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+    use pingora::tls::tokio_ssl::SslStream;
+    use std::pin::Pin;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let test_data = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data");
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor
+        .set_private_key_file(format!("{test_data}/upstream-tls.key"), SslFiletype::PEM)
+        .unwrap();
+    acceptor
+        .set_certificate_chain_file(format!("{test_data}/upstream-tls.pem"))
+        .unwrap();
+    let acceptor = Arc::new(acceptor.build());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = SslStream::new(ssl, tcp).unwrap();
+                if Pin::new(&mut stream).accept().await.is_err() {
+                    return;
+                }
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(n) if n > 0 => head.extend_from_slice(&buf[..n]),
+                        _ => return,
+                    }
+                }
+                let response =
+                    "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upstream_tls_certificates_are_verified_and_pinned() {
+    let up_addr = spawn_tls_upstream().await;
+
+    let metrics = Arc::new(Metrics::default());
+    let lb_port = reserve_port();
+    let api_key = "upstream-tls-key";
+    let accounts_db = create_test_accounts_db(api_key);
+    let accounts_db_path = accounts_db.path().to_str().unwrap().to_string();
+
+    // SHA-256 of the DER of test_data/upstream-tls.pem
+    let pin = "30791d4e064864660cc77d37183d94a68202c1e8d878c3fbc908c6a55fcafed3";
+    let ca_file = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/upstream-tls.pem");
+    let mut config_file = tempfile::NamedTempFile::new().unwrap();
+    let config_content = format!(
+        r#"
+services:
+  pinned: /pinned
+  repinned: /repinned
+  untrusted: /untrusted
+backends:
+  - service: pinned
+    tls: {{ sni: upstream.test, ca_file: "{ca_file}", cert_sha256: ["{pin}"] }}
+    backend: {{ type: basic, ip: "{ip}", port: {port} }}
+  - service: repinned
+    tls: {{ sni: upstream.test, ca_file: "{ca_file}", cert_sha256: ["{other}"] }}
+    backend: {{ type: basic, ip: "{ip}", port: {port} }}
+  - service: untrusted
+    tls: {{ sni: upstream.test }}
+    backend: {{ type: basic, ip: "{ip}", port: {port} }}
+"#,
+        ip = up_addr.ip(),
+        port = up_addr.port(),
+        other = "00".repeat(32),
+    );
+    use std::io::Write;
+    config_file.write_all(config_content.as_bytes()).unwrap();
+    let config_path = config_file.path().to_str().unwrap().to_string();
+
+    let (lb_shutdown, lb_handle) =
+        spawn_load_balancer(lb_port, config_path, accounts_db_path, metrics.clone());
+    wait_for_port(lb_port).await;
+
+    let client = Client::new();
+    let get = |path: &str| {
+        client
+            .get(format!("http://127.0.0.1:{lb_port}{path}"))
+            .header(API_KEY_HEADER, api_key)
+            .send()
+    };
+    let resp = get("/pinned").await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "ok");
+
+    // A certificate matching no pin, or not issued by a trusted CA, fails the connection
+    assert_eq!(get("/repinned").await.unwrap().status(), 502);
+    assert_eq!(get("/untrusted").await.unwrap().status(), 502);
+
+    let _ = lb_shutdown.send(());
+    let _ = lb_handle.join();
+    let mismatches = metrics.labeled_counter("upstream_tls_mismatch");
+    assert_eq!(mismatches.get("pinned"), None);
+    assert_eq!(mismatches["repinned"], 1);
+    assert_eq!(mismatches["untrusted"], 1);
+}